@@ -0,0 +1,456 @@
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+use crate::event::{InFlightRequest, RequestEvent, Throughput, TokenComposition};
+use crate::index::IndexEntry;
+use crate::keys::encode_hex;
+use crate::pricing::PriceTable;
+use crate::record::Totals;
+use crate::stats::{Histogram, Percentiles};
+
+/// Hex characters kept from a hashed repository or recording name
+const HASH_LEN: usize = 12;
+
+/// Name of a report over the archive index rather than a recording
+const ARCHIVE_NAME: &str = "archive";
+
+/// How much an aggregate report may reveal about where traffic came from
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AggregateOptions {
+    /// Report `claude-sonnet` rather than `claude-3-5-sonnet-20241022`
+    pub model_families: bool,
+    /// Replace repository and recording names with a short hash
+    pub hash_names: bool,
+}
+
+/// One request reduced to what an aggregate report is allowed to see.
+/// There is nowhere to put message text, so no report can contain any.
+#[derive(Debug, Clone)]
+pub struct UsageRecord {
+    pub timestamp: DateTime<Utc>,
+    pub provider: String,
+    pub model: String,
+    /// `None` for a request that failed before a response was parsed
+    pub tokens: Option<u64>,
+    /// Dollars at list price, unset for unpriced models
+    pub cost_usd: Option<f64>,
+    pub repo: Option<String>,
+    /// Output tokens per second and first-to-last token time of a stream
+    /// long enough to measure
+    pub stream: Option<(u64, u64)>,
+    pub composition: TokenComposition,
+}
+
+impl UsageRecord {
+    /// What the archive index kept of a request. The index has no
+    /// repository or token breakdown, so neither is reported.
+    pub fn from_index(entry: &IndexEntry, prices: &PriceTable) -> Self {
+        Self {
+            timestamp: entry.timestamp,
+            provider: entry.provider.clone(),
+            model: entry.model.clone().unwrap_or_default(),
+            tokens: entry.tokens.filter(|_| !entry.failed()),
+            cost_usd: entry.cost(prices),
+            repo: None,
+            stream: entry.throughput.as_ref().and_then(stream),
+            composition: TokenComposition::default(),
+        }
+    }
+}
+
+/// Output tokens per second and duration of a stream long enough to measure
+fn stream(throughput: &Throughput) -> Option<(u64, u64)> {
+    if !throughput.is_significant() {
+        return None;
+    }
+    let rate = throughput.tokens_per_sec()?.round() as u64;
+    Some((rate, throughput.duration_ms))
+}
+
+impl From<&RequestEvent> for UsageRecord {
+    fn from(event: &RequestEvent) -> Self {
+        Self {
+            timestamp: event.timestamp,
+            provider: event.provider.clone(),
+            model: event.model.clone(),
+            tokens: Some(event.tokens as u64),
+            cost_usd: event.cost_usd,
+            repo: event.repo.as_ref().map(|repo| repo.name()),
+            stream: event.throughput.as_ref().and_then(stream),
+            composition: event.composition(),
+        }
+    }
+}
+
+impl From<&InFlightRequest> for UsageRecord {
+    fn from(request: &InFlightRequest) -> Self {
+        Self {
+            timestamp: request.started_at,
+            provider: request.provider.clone(),
+            model: request.model.clone().unwrap_or_default(),
+            tokens: None,
+            cost_usd: None,
+            repo: None,
+            stream: None,
+            composition: TokenComposition::default(),
+        }
+    }
+}
+
+/// Counts, tokens and timings only, safe to share outside the machine
+#[derive(Debug, Clone, Serialize)]
+pub struct AggregateReport {
+    /// Recording name, hashed with `hash_names`
+    pub name: String,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: DateTime<Utc>,
+    pub requests: usize,
+    pub failed: usize,
+    pub total_tokens: u64,
+    /// Dollars at list price over the priced requests
+    pub cost_usd: f64,
+    /// Completed requests whose model has no price, left out of `cost_usd`
+    pub unpriced: usize,
+    /// Tokens by bucket, whatever the dashboard gauge counts
+    pub composition: TokenComposition,
+    pub tokens: Option<Percentiles>,
+    pub by_provider: BTreeMap<String, Totals>,
+    pub by_model: BTreeMap<String, Totals>,
+    /// Keyed by repository name, or its hash with `hash_names`
+    pub by_repo: BTreeMap<String, Totals>,
+    /// Completed requests per UTC hour, keyed like `2025-03-01T12:00Z`
+    pub by_hour: BTreeMap<String, Totals>,
+    /// Output tokens per second by model, from streamed responses
+    pub throughput: BTreeMap<String, Percentiles>,
+    /// First to last streamed token in milliseconds, by model
+    pub stream_ms: BTreeMap<String, Percentiles>,
+}
+
+impl AggregateReport {
+    pub fn build<'a>(
+        name: &str,
+        started_at: DateTime<Utc>,
+        ended_at: DateTime<Utc>,
+        records: impl IntoIterator<Item = &'a UsageRecord>,
+        options: AggregateOptions,
+    ) -> Self {
+        let label = |name: &str| {
+            if options.hash_names {
+                hash_name(name)
+            } else {
+                name.to_string()
+            }
+        };
+        let mut report = Self {
+            name: label(name),
+            started_at,
+            ended_at,
+            requests: 0,
+            failed: 0,
+            total_tokens: 0,
+            cost_usd: 0.0,
+            unpriced: 0,
+            composition: TokenComposition::default(),
+            tokens: None,
+            by_provider: BTreeMap::new(),
+            by_model: BTreeMap::new(),
+            by_repo: BTreeMap::new(),
+            by_hour: BTreeMap::new(),
+            throughput: BTreeMap::new(),
+            stream_ms: BTreeMap::new(),
+        };
+        let mut histogram = Histogram::new();
+        let mut speeds: BTreeMap<String, (Histogram, Histogram)> = BTreeMap::new();
+
+        for record in records {
+            let Some(tokens) = record.tokens else {
+                report.failed += 1;
+                continue;
+            };
+            let model = if options.model_families {
+                model_family(&record.model)
+            } else {
+                record.model.clone()
+            };
+            report.requests += 1;
+            report.total_tokens += tokens;
+            match record.cost_usd {
+                Some(cost) => report.cost_usd += cost,
+                None => report.unpriced += 1,
+            }
+            report.composition += record.composition;
+            histogram.record(tokens);
+            if let Some((rate, duration_ms)) = record.stream {
+                let (rates, durations) = speeds.entry(model.clone()).or_default();
+                rates.record(rate);
+                durations.record(duration_ms);
+            }
+            let hour = record
+                .timestamp
+                .duration_trunc(TimeDelta::hours(1))
+                .unwrap_or(record.timestamp)
+                .format("%Y-%m-%dT%H:%MZ")
+                .to_string();
+            let repo = record
+                .repo
+                .as_deref()
+                .map(|repo| report.by_repo.entry(label(repo)).or_default());
+            for totals in [
+                report
+                    .by_provider
+                    .entry(record.provider.clone())
+                    .or_default(),
+                report.by_model.entry(model).or_default(),
+                report.by_hour.entry(hour).or_default(),
+            ]
+            .into_iter()
+            .chain(repo)
+            {
+                totals.requests += 1;
+                totals.tokens += tokens;
+                totals.cost_usd += record.cost_usd.unwrap_or(0.0);
+            }
+        }
+
+        report.tokens = histogram.percentiles();
+        for (model, (rates, durations)) in speeds {
+            if let Some(rates) = rates.percentiles() {
+                report.throughput.insert(model.clone(), rates);
+            }
+            if let Some(durations) = durations.percentiles() {
+                report.stream_ms.insert(model, durations);
+            }
+        }
+        report
+    }
+
+    /// Report over archive index entries, from the first one to the last
+    pub fn from_index(
+        entries: &[IndexEntry],
+        prices: &PriceTable,
+        options: AggregateOptions,
+    ) -> Self {
+        let records: Vec<UsageRecord> = entries
+            .iter()
+            .map(|entry| UsageRecord::from_index(entry, prices))
+            .collect();
+        let now = Utc::now();
+        let started_at = records.iter().map(|r| r.timestamp).min().unwrap_or(now);
+        let ended_at = records.iter().map(|r| r.timestamp).max().unwrap_or(now);
+        Self::build(ARCHIVE_NAME, started_at, ended_at, &records, options)
+    }
+}
+
+/// Model name without dates, version numbers or release tags, e.g.
+/// `claude-3-5-sonnet-20241022` and `claude-sonnet-4-5` both become
+/// `claude-sonnet`
+pub fn model_family(model: &str) -> String {
+    let model = unprefixed(model);
+    let model = model.split([':', '@']).next().unwrap_or(model);
+    let parts: Vec<&str> = model
+        .split('-')
+        .filter(|part| {
+            !part.is_empty()
+                && !part.chars().all(|c| c.is_ascii_digit() || c == '.')
+                && !matches!(*part, "latest" | "preview" | "exp")
+        })
+        .collect();
+    if parts.is_empty() {
+        model.to_ascii_lowercase()
+    } else {
+        parts.join("-").to_ascii_lowercase()
+    }
+}
+
+/// Whether `served` is the model asked for as `requested`: the same name
+/// once vendor prefixes, case and the `latest` alias are set aside, and the
+/// same date or version suffix unless only one of them has one
+pub fn same_model(requested: &str, served: &str) -> bool {
+    let (requested_name, requested_snapshot) = snapshot(requested);
+    let (served_name, served_snapshot) = snapshot(served);
+    requested_name == served_name
+        && match (requested_snapshot, served_snapshot) {
+            (Some(requested), Some(served)) => requested == served,
+            _ => true,
+        }
+}
+
+/// Model name without a vendor prefix such as `models/` or `anthropic/`
+pub fn unprefixed(model: &str) -> &str {
+    model.rsplit('/').next().unwrap_or(model)
+}
+
+/// Lowercase name and dated snapshot of a model, e.g. `gpt-4o` and
+/// `2024-08-06` for `gpt-4o-2024-08-06`. Aliases such as `latest` have none.
+fn snapshot(model: &str) -> (String, Option<String>) {
+    let model = unprefixed(model).to_ascii_lowercase();
+    // Vertex names snapshots `claude-3-haiku@20240307`, Ollama tags `llama3:latest`
+    if let Some((name, tag)) = model.split_once('@') {
+        let tag = Some(tag).filter(|tag| *tag != "latest");
+        return (name.to_string(), tag.map(str::to_string));
+    }
+    let model = model.strip_suffix(":latest").unwrap_or(&model);
+    let mut parts: Vec<&str> = model.split('-').collect();
+    let digits = |part: &str, len| part.len() == len && part.bytes().all(|b| b.is_ascii_digit());
+    let n = parts.len();
+    let snapshot = if n > 1 && parts[n - 1] == "latest" {
+        parts.pop();
+        None
+    } else if n > 3 && digits(parts[n - 3], 4) && digits(parts[n - 2], 2) && digits(parts[n - 1], 2)
+    {
+        Some(parts.split_off(n - 3).join("-"))
+    } else if n > 1 && [3, 4, 8].iter().any(|&len| digits(parts[n - 1], len)) {
+        parts.pop().map(str::to_string)
+    } else {
+        None
+    };
+    (parts.join("-"), snapshot)
+}
+
+/// Stable short hash, so the same repository groups together across reports
+/// without naming it
+fn hash_name(name: &str) -> String {
+    let mut hash = encode_hex(&Sha256::digest(name.as_bytes()));
+    hash.truncate(HASH_LEN);
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SESSION: &str = include_str!("../tests/fixtures/aggregate_session.jsonl");
+
+    fn records() -> Vec<UsageRecord> {
+        SESSION
+            .lines()
+            .map(|line| UsageRecord::from(&serde_json::from_str::<RequestEvent>(line).unwrap()))
+            .collect()
+    }
+
+    fn report(options: AggregateOptions) -> AggregateReport {
+        let records = records();
+        AggregateReport::build(
+            "acme-launch-review",
+            records[0].timestamp,
+            records[records.len() - 1].timestamp,
+            &records,
+            options,
+        )
+    }
+
+    #[test]
+    fn test_report_has_no_message_text() {
+        let json = serde_json::to_string(&report(AggregateOptions {
+            model_families: true,
+            hash_names: true,
+        }))
+        .unwrap();
+        for secret in [
+            "Project Nightingale",
+            "quarterly revenue",
+            "sk-live-",
+            "renameCustomerTable",
+            "You are a careful reviewer",
+            "acme-launch-review",
+            "acme-payments",
+            "/home/dana",
+            "feature/launch",
+        ] {
+            assert!(!json.contains(secret), "{:?} leaked into {}", secret, json);
+        }
+    }
+
+    #[test]
+    fn test_report_totals() {
+        let plain = report(AggregateOptions::default());
+        assert_eq!((plain.requests, plain.total_tokens), (3, 5200));
+        assert_eq!(plain.by_model["claude-3-5-sonnet-20241022"].requests, 2);
+        assert_eq!(plain.by_repo["acme-payments"].tokens, 4700);
+        assert_eq!(plain.by_hour["2025-03-01T12:00Z"].requests, 2);
+        assert_eq!(plain.by_hour["2025-03-01T13:00Z"].tokens, 500);
+        assert_eq!(plain.throughput["claude-3-5-sonnet-20241022"].p50, 50);
+
+        let hashed = report(AggregateOptions {
+            model_families: true,
+            hash_names: true,
+        });
+        assert_eq!(hashed.by_model["claude-sonnet"].tokens, 4700);
+        assert_eq!(hashed.by_repo[&hash_name("acme-payments")].requests, 2);
+        assert_eq!(hashed.name, hash_name("acme-launch-review"));
+    }
+
+    #[test]
+    fn test_report_from_index() {
+        let mut entries: Vec<IndexEntry> = SESSION
+            .lines()
+            .map(|line| IndexEntry::from(&serde_json::from_str::<RequestEvent>(line).unwrap()))
+            .collect();
+        entries[0].cost_usd = Some(0.5);
+        entries[2].model = Some("in-house-7b".to_string());
+        let mut failed = entries[1].clone();
+        failed.status = Some(529);
+        entries.push(failed);
+        let prices = PriceTable::new(&crate::config::Config::default().pricing);
+
+        let report = AggregateReport::from_index(
+            &entries,
+            &prices,
+            AggregateOptions {
+                model_families: true,
+                hash_names: true,
+            },
+        );
+        assert_eq!((report.requests, report.failed, report.unpriced), (3, 1, 1));
+        assert_eq!(report.started_at, entries[0].timestamp);
+        // As recorded, then 2,500 tokens at $3/Mtok
+        assert!((report.cost_usd - 0.5075).abs() < 1e-9);
+        assert!((report.by_model["claude-sonnet"].cost_usd - 0.5075).abs() < 1e-9);
+        assert_eq!(report.by_model["in-house-7b"].cost_usd, 0.0);
+        assert_eq!(report.name, hash_name("archive"));
+
+        let json = serde_json::to_string(&report).unwrap();
+        for secret in ["Project Nightingale", "quarterly revenue", "acme-payments"] {
+            assert!(!json.contains(secret), "{:?} leaked into {}", secret, json);
+        }
+    }
+
+    #[test]
+    fn test_model_family() {
+        assert_eq!(model_family("claude-3-5-sonnet-20241022"), "claude-sonnet");
+        assert_eq!(model_family("claude-sonnet-4-5"), "claude-sonnet");
+        assert_eq!(model_family("gpt-4o-2024-08-06"), "gpt-4o");
+        assert_eq!(model_family("gemini-1.5-pro-002"), "gemini-pro");
+        assert_eq!(model_family("models/gemini-2.0-flash-exp"), "gemini-flash");
+        assert_eq!(model_family("claude-3-haiku@20240307"), "claude-haiku");
+    }
+
+    #[test]
+    fn test_same_model() {
+        for (requested, served) in [
+            ("claude-3-5-sonnet", "claude-3-5-sonnet-20241022"),
+            ("claude-3-5-sonnet-latest", "claude-3-5-sonnet-20241022"),
+            ("claude-3-5-sonnet-20241022", "claude-3-5-sonnet-20241022"),
+            ("GPT-4o", "gpt-4o-2024-08-06"),
+            ("gpt-3.5-turbo", "gpt-3.5-turbo-0125"),
+            ("models/gemini-1.5-pro", "gemini-1.5-pro-002"),
+            ("anthropic/claude-3-5-sonnet", "claude-3-5-sonnet-20241022"),
+            ("claude-3-haiku@20240307", "claude-3-haiku-20240307"),
+            ("llama3", "llama3:latest"),
+        ] {
+            assert!(same_model(requested, served), "{} served as {}", requested, served);
+        }
+        for (requested, served) in [
+            ("claude-3-5-sonnet-20240620", "claude-3-5-sonnet-20241022"),
+            ("claude-3-opus-latest", "claude-3-5-sonnet-20241022"),
+            ("gpt-4o", "gpt-4o-mini-2024-07-18"),
+            ("gpt-4", "gpt-4o"),
+            ("claude-sonnet-4-5", "claude-sonnet-4-0"),
+            ("llama3:8b", "llama3:70b"),
+        ] {
+            assert!(!same_model(requested, served), "{} served as {}", requested, served);
+        }
+    }
+}
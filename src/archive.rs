@@ -1,14 +1,116 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
 use tokio::fs;
+use tokio::io::AsyncWriteExt;
 use tokio::sync::mpsc;
 
-use crate::config::ArchiveConfig;
-use crate::event::RequestEvent;
+use crate::config::{ArchiveConfig, MarkdownArchiveConfig, RenderMode, SinkConfig};
+use crate::context;
+use crate::event::{Marker, RequestEvent, RequestFailure, TOOL_USE_ROLE};
+use crate::index::{self, IndexEntry, INDEX_FILE};
+use crate::export::{Block, Conversation};
+use crate::metrics::ArchiveMetrics;
+use crate::parser::{extract_text_from_value, parse_request, tool_definition_names};
+use crate::pricing::format_cost;
+use crate::redact::Redactor;
+use crate::text::truncate;
 
-/// Async task that writes prompts to disk
+/// Timestamp prefix of every archive filename
+const TIMESTAMP_FORMAT: &str = "%Y%m%d_%H%M%S%.3f";
+const TIMESTAMP_LEN: usize = "20240101_000000.000".len();
+/// Widest first line shown for a collapsed tool result
+const TOOL_SUMMARY_WIDTH: usize = 80;
+/// `sherlock mark` markers, one JSON object per line, in the archive root
+pub const MARKERS_FILE: &str = "markers.jsonl";
+
+/// Start of the names archive files are written under before being renamed
+/// into place; readers skip them
+pub const IN_PROGRESS_PREFIX: &str = ".";
+
+/// Events the archive didn't get to before quitting, under `~/.sherlock`
+pub const PENDING_FILE: &str = "pending_events.jsonl";
+
+/// Directory in the archive root holding the events whose markdown is still
+/// to be rendered, one `<base name>.json` each
+pub const RENDER_QUEUE_DIR: &str = ".render";
+/// How often the background renderer checks whether the writer caught up
+const RENDER_IDLE_POLL: std::time::Duration = std::time::Duration::from_millis(50);
+
+/// Boxed future returned by `ArchiveSink` methods
+pub type SinkFuture<'a, T = usize> = Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>;
+
+/// A destination for archived requests. Each sink fails on its own: an error
+/// is counted against it and the remaining sinks still get the request.
+pub trait ArchiveSink: Send {
+    /// Label used in metrics and logs, e.g. "markdown"
+    fn name(&self) -> &str;
+
+    /// Archive one request, returning the bytes written
+    fn write<'a>(&'a mut self, event: &'a RequestEvent) -> SinkFuture<'a>;
+
+    /// Archive a `sherlock mark` marker, returning the bytes written. Sinks
+    /// with nowhere to put one skip it.
+    fn mark<'a>(&'a mut self, _marker: &'a Marker) -> SinkFuture<'a> {
+        Box::pin(async { Ok(0) })
+    }
+
+    /// Push out anything buffered
+    fn flush(&mut self) -> SinkFuture<'_, ()> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Called once when the archive writer stops
+    fn close(&mut self) -> SinkFuture<'_, ()> {
+        self.flush()
+    }
+}
+
+/// What the archive writer is sent
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "entry", rename_all = "lowercase")]
+pub enum ArchiveEntry {
+    Request(Box<RequestEvent>),
+    Marker(Marker),
+    /// Only indexed, for reliability stats
+    Failure(RequestFailure),
+    /// Push out whatever the sinks buffer, as asked with `Control::Flush`
+    Flush,
+}
+
+impl From<RequestEvent> for ArchiveEntry {
+    fn from(event: RequestEvent) -> Self {
+        ArchiveEntry::Request(Box::new(event))
+    }
+}
+
+/// Async task that writes prompts to every configured sink. Events left in
+/// `pending` by the last run are archived first, and events still queued
+/// past the shutdown deadline are saved there. Requests are redacted with
+/// `redactor` before any sink sees them.
 pub async fn archive_writer(
-    mut rx: mpsc::Receiver<RequestEvent>,
+    rx: mpsc::Receiver<ArchiveEntry>,
     config: ArchiveConfig,
+    redactor: Arc<Redactor>,
+    metrics: Arc<ArchiveMetrics>,
+    pending: Option<PathBuf>,
+) -> Result<()> {
+    let result = run_writer(rx, config, &redactor, &metrics, pending.as_deref()).await;
+    metrics.set_finished();
+    result
+}
+
+async fn run_writer(
+    mut rx: mpsc::Receiver<ArchiveEntry>,
+    config: ArchiveConfig,
+    redactor: &Redactor,
+    metrics: &ArchiveMetrics,
+    pending: Option<&Path>,
 ) -> Result<()> {
     if !config.enabled {
         tracing::info!("Prompt archiving disabled");
@@ -19,68 +121,1005 @@ pub async fn archive_writer(
 
     // Ensure directory exists
     fs::create_dir_all(&config.directory).await?;
+    let root = fs::canonicalize(&config.directory).await?;
 
     tracing::info!("Archiving prompts to {:?}", config.directory);
 
-    while let Some(event) = rx.recv().await {
-        if let Err(e) = save_prompt(&event, &config).await {
-            tracing::error!("Failed to save prompt: {}", e);
+    let (queue, queued) = mpsc::unbounded_channel();
+    let earlier = if deferred_markdown(&config) {
+        pending_renders(&root).unwrap_or_else(|e| {
+            tracing::error!("Failed to read the markdown render queue: {:#}", e);
+            Vec::new()
+        })
+    } else {
+        Vec::new()
+    };
+    let mut sinks = sinks_with_queue(&config, &root, Some(queue));
+    if let Some(path) = pending {
+        if let Err(e) = replay_pending(path, &root, &mut sinks, redactor, metrics).await {
+            tracing::error!("Failed to replay {:?}: {:#}", path, e);
         }
     }
-
+    tokio::join!(
+        write_to_sinks(rx, sinks, redactor, metrics, Some(&root), pending),
+        render_queued(queued, earlier, &config, metrics),
+    );
     Ok(())
 }
 
-async fn save_prompt(event: &RequestEvent, config: &ArchiveConfig) -> Result<()> {
-    let timestamp = event.timestamp.format("%Y%m%d_%H%M%S%.3f");
-    let base_name = format!("{}_{}", timestamp, event.provider);
+/// Render deferred markdown whenever the writer has nothing waiting,
+/// starting with what earlier runs left queued. Stops once the writer does;
+/// whatever is left stays queued for the next run.
+async fn render_queued(
+    mut queued: mpsc::UnboundedReceiver<PathBuf>,
+    earlier: Vec<PathBuf>,
+    config: &ArchiveConfig,
+    metrics: &ArchiveMetrics,
+) {
+    let mut earlier = earlier.into_iter();
+    loop {
+        let snapshot = match earlier.next() {
+            Some(snapshot) => snapshot,
+            None => match queued.recv().await {
+                Some(snapshot) => snapshot,
+                None => break,
+            },
+        };
+        while metrics.backlog() > 0 && !queued.is_closed() {
+            tokio::time::sleep(RENDER_IDLE_POLL).await;
+        }
+        if queued.is_closed() {
+            break;
+        }
+        if let Err(e) = render_snapshot(&snapshot, config).await {
+            tracing::error!("Failed to render {:?}: {:#}", snapshot, e);
+            metrics.record_failure("markdown", format!("{:#}", e));
+        }
+    }
+}
 
-    for format in &config.format {
-        let path = match format.as_str() {
-            "markdown" | "md" => {
-                let path = config.directory.join(format!("{}.md", base_name));
-                let content = format_markdown(event);
-                fs::write(&path, content).await?;
-                path
+/// Feed every event to `sinks` and the index under `index`, then close the
+/// sinks once the channel closes. Past the shutdown deadline the rest of the
+/// queue goes to `pending`.
+async fn write_to_sinks(
+    mut rx: mpsc::Receiver<ArchiveEntry>,
+    mut sinks: Vec<Box<dyn ArchiveSink>>,
+    redactor: &Redactor,
+    metrics: &ArchiveMetrics,
+    index: Option<&Path>,
+    pending: Option<&Path>,
+) {
+    while let Some(entry) = rx.recv().await {
+        if metrics.past_deadline() {
+            let mut rest = vec![entry];
+            while let Ok(entry) = rx.try_recv() {
+                rest.push(entry);
+            }
+            metrics.set_backlog(0);
+            match pending {
+                Some(path) => match save_pending(path, &rest).await {
+                    Ok(()) => tracing::info!("Saved {} events to {:?}", rest.len(), path),
+                    Err(e) => tracing::error!("Lost {} unarchived events: {:#}", rest.len(), e),
+                },
+                None => tracing::warn!("Dropped {} unarchived events at shutdown", rest.len()),
+            }
+            break;
+        }
+        metrics.set_backlog(rx.len());
+        match entry {
+            ArchiveEntry::Request(event) => {
+                save_prompt(&event, &mut sinks, redactor, metrics).await;
+                save_index(index, &IndexEntry::from(event.as_ref()), metrics).await;
             }
-            "json" => {
-                let path = config.directory.join(format!("{}.json", base_name));
-                let content = serde_json::to_string_pretty(&event.raw_body)?;
-                fs::write(&path, content).await?;
-                path
+            ArchiveEntry::Marker(marker) => save_marker(&marker, &mut sinks, metrics).await,
+            ArchiveEntry::Failure(failure) => {
+                save_index(index, &IndexEntry::from(&failure), metrics).await
             }
-            _ => {
-                tracing::warn!("Unknown archive format: {}", format);
+            ArchiveEntry::Flush => {
+                flush_sinks(&mut sinks, metrics).await;
+                tracing::info!("Flushed archive sinks");
+            }
+        }
+    }
+    for sink in &mut sinks {
+        if let Err(e) = sink.close().await {
+            tracing::error!("Failed to close {} archive: {:#}", sink.name(), e);
+            metrics.record_failure(sink.name(), format!("{:#}", e));
+        }
+    }
+}
+
+async fn flush_sinks(sinks: &mut [Box<dyn ArchiveSink>], metrics: &ArchiveMetrics) {
+    for sink in sinks {
+        if let Err(e) = sink.flush().await {
+            tracing::error!("Failed to flush {} archive: {:#}", sink.name(), e);
+            metrics.record_failure(sink.name(), format!("{:#}", e));
+        }
+    }
+}
+
+/// Append `entries` to the recovery file, one JSON line each
+async fn save_pending(path: &Path, entries: &[ArchiveEntry]) -> Result<()> {
+    let mut lines = String::new();
+    for entry in entries {
+        lines.push_str(&serde_json::to_string(entry)?);
+        lines.push('\n');
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    file.write_all(lines.as_bytes()).await?;
+    file.flush().await?;
+    Ok(())
+}
+
+/// Add `entry` to the index under `root`, counting failures under "index"
+async fn save_index(root: Option<&Path>, entry: &IndexEntry, metrics: &ArchiveMetrics) {
+    let Some(root) = root else {
+        return;
+    };
+    if let Err(e) = index::append(root, entry).await {
+        tracing::error!("Failed to index request: {:#}", e);
+        metrics.record_failure("index", format!("{:#}", e));
+    }
+}
+
+/// Archive what the last run saved to `path` on quitting, then remove it.
+/// Requests already archived under `root` (by timestamp and request id),
+/// failures already indexed, markers already in the markers file, and
+/// repeated lines are skipped, so nothing is written twice.
+async fn replay_pending(
+    path: &Path,
+    root: &Path,
+    sinks: &mut [Box<dyn ArchiveSink>],
+    redactor: &Redactor,
+    metrics: &ArchiveMetrics,
+) -> Result<usize> {
+    let content = match fs::read_to_string(path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+
+    let mut archived = HashSet::new();
+    let mut dir = fs::read_dir(root).await?;
+    while let Some(entry) = dir.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with(IN_PROGRESS_PREFIX) {
+            continue;
+        }
+        // `<date>_<time>_<id>_<provider>.<ext>`, the first three the stamp
+        let parts: Vec<&str> = name.splitn(4, '_').collect();
+        if parts.len() == 4 {
+            archived.insert(parts[..3].join("_"));
+        }
+    }
+    let mut failures: HashSet<(DateTime<Utc>, u64)> = index::read_index(root)?
+        .into_iter()
+        .filter(|entry| entry.error.is_some())
+        .map(|entry| (entry.timestamp, entry.id))
+        .collect();
+    let mut markers: Vec<Marker> = fs::read_to_string(root.join(MARKERS_FILE))
+        .await
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect();
+
+    let mut replayed = 0;
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        let entry = match serde_json::from_str(line) {
+            Ok(entry) => entry,
+            Err(e) => {
+                tracing::warn!("Skipping unreadable pending event: {}", e);
                 continue;
             }
         };
+        match entry {
+            ArchiveEntry::Request(event) => {
+                if !archived.insert(entry_name(&event).0) {
+                    continue;
+                }
+                save_prompt(&event, sinks, redactor, metrics).await;
+                save_index(Some(root), &IndexEntry::from(event.as_ref()), metrics).await;
+            }
+            ArchiveEntry::Failure(failure) => {
+                if !failures.insert((failure.timestamp, failure.id)) {
+                    continue;
+                }
+                save_index(Some(root), &IndexEntry::from(&failure), metrics).await;
+            }
+            ArchiveEntry::Marker(marker) => {
+                if markers.contains(&marker) {
+                    continue;
+                }
+                save_marker(&marker, sinks, metrics).await;
+                markers.push(marker);
+            }
+            ArchiveEntry::Flush => continue,
+        }
+        replayed += 1;
+    }
+    fs::remove_file(path).await?;
+    tracing::info!("Archived {} events saved at the last shutdown", replayed);
+    Ok(replayed)
+}
+
+/// The sinks `config` asks for, writing under the canonical `root`. With no
+/// renderer to defer to, markdown is rendered as requests are archived.
+pub fn build_sinks(config: &ArchiveConfig, root: &Path) -> Vec<Box<dyn ArchiveSink>> {
+    sinks_with_queue(config, root, None)
+}
+
+/// `build_sinks`, deferring markdown to the renderer on the other end of
+/// `queue` if the config asks to
+fn sinks_with_queue(
+    config: &ArchiveConfig,
+    root: &Path,
+    queue: Option<mpsc::UnboundedSender<PathBuf>>,
+) -> Vec<Box<dyn ArchiveSink>> {
+    config
+        .sinks
+        .iter()
+        .map(|sink| -> Box<dyn ArchiveSink> {
+            match sink {
+                SinkConfig::Markdown { render } => Box::new(MarkdownSink {
+                    root: root.to_path_buf(),
+                    limits: config.markdown.clone(),
+                    link_json: links_json(config),
+                    queue: queue.clone().filter(|_| *render == RenderMode::Deferred),
+                }),
+                SinkConfig::Json => Box::new(JsonSink {
+                    root: root.to_path_buf(),
+                }),
+            }
+        })
+        .collect()
+}
+
+/// Write `event`, redacted, to every sink, counting successes and failures
+/// per sink
+pub async fn save_prompt(
+    event: &RequestEvent,
+    sinks: &mut [Box<dyn ArchiveSink>],
+    redactor: &Redactor,
+    metrics: &ArchiveMetrics,
+) {
+    let event = redactor.redacted(event);
+    for sink in sinks {
+        match sink.write(&event).await {
+            Ok(bytes) => metrics.record_write(sink.name(), bytes),
+            Err(e) => {
+                tracing::error!("Failed to archive prompt to {}: {:#}", sink.name(), e);
+                metrics.record_failure(sink.name(), format!("{:#}", e));
+            }
+        }
+    }
+}
 
-        tracing::debug!("Saved prompt to {:?}", path);
+/// Write `marker` to every sink that keeps markers. Only failures are
+/// counted, so the archived request counts stay requests.
+async fn save_marker(
+    marker: &Marker,
+    sinks: &mut [Box<dyn ArchiveSink>],
+    metrics: &ArchiveMetrics,
+) {
+    for sink in sinks {
+        if let Err(e) = sink.mark(marker).await {
+            tracing::error!("Failed to archive marker to {}: {:#}", sink.name(), e);
+            metrics.record_failure(sink.name(), format!("{:#}", e));
+        }
     }
+}
 
-    Ok(())
+/// A readable markdown file per request
+struct MarkdownSink {
+    root: PathBuf,
+    limits: MarkdownArchiveConfig,
+    /// A JSON sink archives the raw body alongside
+    link_json: bool,
+    /// Renderer to queue the event for under `RENDER_QUEUE_DIR`, when
+    /// rendering is deferred
+    queue: Option<mpsc::UnboundedSender<PathBuf>>,
+}
+
+impl ArchiveSink for MarkdownSink {
+    fn name(&self) -> &str {
+        "markdown"
+    }
+
+    fn write<'a>(&'a mut self, event: &'a RequestEvent) -> SinkFuture<'a> {
+        Box::pin(async move {
+            let (stamp, base_name) = entry_name(event);
+            let path = archive_path(&self.root, &base_name, &stamp, "md");
+            let Some(queue) = &self.queue else {
+                let content = render_markdown(event, &self.limits, self.link_json, &path);
+                return write_file(&path, content).await;
+            };
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            let snapshot = self
+                .root
+                .join(RENDER_QUEUE_DIR)
+                .join(format!("{}.json", stem));
+            fs::create_dir_all(self.root.join(RENDER_QUEUE_DIR)).await?;
+            let bytes = write_file(&snapshot, serde_json::to_string(event)?).await?;
+            // Once the renderer stops, the next run's picks it up from the index
+            let _ = queue.send(snapshot);
+            Ok(bytes)
+        })
+    }
+}
+
+/// Whether markdown links each request's raw JSON; truncated markdown points
+/// at it for the full content
+fn links_json(config: &ArchiveConfig) -> bool {
+    config.sinks.contains(&SinkConfig::Json)
+}
+
+fn deferred_markdown(config: &ArchiveConfig) -> bool {
+    config.sinks.contains(&SinkConfig::Markdown {
+        render: RenderMode::Deferred,
+    })
+}
+
+/// The markdown archived at `path` for `event`, the same whether rendered
+/// as it's archived or from the render queue later
+fn render_markdown(
+    event: &RequestEvent,
+    limits: &MarkdownArchiveConfig,
+    link_json: bool,
+    path: &Path,
+) -> String {
+    let json_path = link_json.then(|| path.with_extension("json"));
+    format_markdown(event, limits, json_path.as_deref())
+}
+
+/// Render a queued event's markdown into the archive root, then take it off
+/// the queue
+async fn render_snapshot(snapshot: &Path, config: &ArchiveConfig) -> Result<PathBuf> {
+    let content = fs::read(snapshot)
+        .await
+        .with_context(|| format!("Failed to read {}", snapshot.display()))?;
+    let event: RequestEvent = serde_json::from_slice(&content)
+        .with_context(|| format!("Failed to parse {}", snapshot.display()))?;
+    let root = snapshot
+        .parent()
+        .and_then(Path::parent)
+        .with_context(|| format!("{} is not in a render queue", snapshot.display()))?;
+    let stem = snapshot.file_stem().unwrap_or_default().to_string_lossy();
+    let path = root.join(format!("{}.md", stem));
+    let (limits, link_json, md_path) = (config.markdown.clone(), links_json(config), path.clone());
+    let content =
+        tokio::task::spawn_blocking(move || render_markdown(&event, &limits, link_json, &md_path))
+            .await?;
+    write_file(&path, content).await?;
+    fs::remove_file(snapshot).await?;
+    Ok(path)
+}
+
+/// Queued events of requests in the index under `root` that still have no
+/// markdown, oldest first
+pub fn pending_renders(root: &Path) -> Result<Vec<PathBuf>> {
+    let queue = root.join(RENDER_QUEUE_DIR);
+    if !queue.is_dir() {
+        return Ok(Vec::new());
+    }
+    Ok(index::read_index(root)?
+        .into_iter()
+        .filter(|entry| entry.error.is_none())
+        .map(|entry| name_parts(entry.timestamp, entry.id, &entry.provider).1)
+        .filter(|stem| !root.join(format!("{}.md", stem)).exists())
+        .map(|stem| queue.join(format!("{}.json", stem)))
+        .filter(|snapshot| snapshot.is_file())
+        .collect())
+}
+
+/// What `sherlock render` did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rendered {
+    Now(PathBuf),
+    /// The markdown already existed
+    Already(PathBuf),
+}
+
+/// Render the deferred markdown of one archived request now. `entry` is a
+/// request id (the newest request with it), or an archive file name or
+/// path of any of the request's files.
+pub async fn render_entry(config: &ArchiveConfig, entry: &str) -> Result<Rendered> {
+    let root = &config.directory;
+    let stem = match entry.parse::<u64>() {
+        Ok(id) => index::read_index(root)?
+            .into_iter()
+            .rev()
+            .find(|indexed| indexed.id == id && indexed.error.is_none())
+            .map(|indexed| name_parts(indexed.timestamp, indexed.id, &indexed.provider).1)
+            .with_context(|| format!("No archived request with id {} in {:?}", id, root))?,
+        Err(_) => {
+            let name = Path::new(entry)
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy();
+            match name.rsplit_once('.') {
+                Some((stem, "md" | "json")) => stem.to_string(),
+                _ => name.into_owned(),
+            }
+        }
+    };
+    let path = root.join(format!("{}.md", stem));
+    if path.is_file() {
+        return Ok(Rendered::Already(path));
+    }
+    let snapshot = root.join(RENDER_QUEUE_DIR).join(format!("{}.json", stem));
+    if !snapshot.is_file() {
+        anyhow::bail!("No markdown queued for {:?} in {:?}", entry, root);
+    }
+    render_snapshot(&snapshot, config).await.map(Rendered::Now)
+}
+
+/// The raw request body per request
+struct JsonSink {
+    root: PathBuf,
+}
+
+impl ArchiveSink for JsonSink {
+    fn name(&self) -> &str {
+        "json"
+    }
+
+    fn write<'a>(&'a mut self, event: &'a RequestEvent) -> SinkFuture<'a> {
+        Box::pin(async move {
+            let (stamp, base_name) = entry_name(event);
+            let content = serde_json::to_string_pretty(&event.raw_body)?;
+            write_file(&archive_path(&self.root, &base_name, &stamp, "json"), content).await
+        })
+    }
+
+    fn mark<'a>(&'a mut self, marker: &'a Marker) -> SinkFuture<'a> {
+        Box::pin(async move {
+            let path = self.root.join(MARKERS_FILE);
+            let mut line = serde_json::to_string(marker)?;
+            line.push('\n');
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .await
+                .with_context(|| format!("Failed to open {}", path.display()))?;
+            file.write_all(line.as_bytes()).await?;
+            file.flush().await?;
+            Ok(line.len())
+        })
+    }
+}
+
+/// Timestamp stamp and base filename shared by every file of one request.
+/// The request id keeps requests from the same millisecond apart, and in
+/// arrival order when sorted by name.
+fn entry_name(event: &RequestEvent) -> (String, String) {
+    name_parts(event.timestamp, event.id, &event.provider)
+}
+
+fn name_parts(timestamp: DateTime<Utc>, id: u64, provider: &str) -> (String, String) {
+    let stamp = format!("{}_{:08}", timestamp.format(TIMESTAMP_FORMAT), id);
+    let base_name = format!("{}_{}", stamp, sanitize_component(provider));
+    (stamp, base_name)
+}
+
+/// Write `content` under a temporary name and rename it into place, so
+/// nothing reading the archive meanwhile finds a half-written file
+async fn write_file(path: &Path, content: String) -> Result<usize> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp = path.with_file_name(format!("{}{}.tmp", IN_PROGRESS_PREFIX, name));
+    fs::write(&temp, &content)
+        .await
+        .with_context(|| format!("Failed to write {}", temp.display()))?;
+    fs::rename(&temp, path)
+        .await
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    tracing::debug!("Saved prompt to {:?}", path);
+    Ok(content.len())
+}
+
+/// File extension a sink writes, for checking the archive index
+fn sink_extension(sink: &SinkConfig) -> Option<&'static str> {
+    match sink {
+        SinkConfig::Markdown { .. } => Some("md"),
+        SinkConfig::Json => Some("json"),
+    }
+}
+
+/// Summary of the archive directory for `sherlock archive status`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ArchiveStatus {
+    pub directory: PathBuf,
+    pub files: usize,
+    pub bytes: u64,
+    /// Whether `bytes` was extrapolated from a sample of files
+    pub bytes_estimated: bool,
+    pub oldest: Option<NaiveDateTime>,
+    pub newest: Option<NaiveDateTime>,
+    /// Distinct archived requests (files sharing a base name)
+    pub requests: usize,
+    /// Requests missing one of the configured formats
+    pub missing: usize,
+    /// Files that don't follow the archive naming scheme
+    pub orphaned: usize,
+}
+
+/// Scan the archive directory. Only names are read for every file; sizes
+/// come from at most `sample_limit` files and are extrapolated beyond that,
+/// so the scan stays fast on huge archives.
+pub fn archive_status(
+    dir: &Path,
+    sinks: &[SinkConfig],
+    sample_limit: usize,
+) -> Result<ArchiveStatus> {
+    let expected: Vec<&str> = sinks.iter().filter_map(sink_extension).collect();
+
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.starts_with(IN_PROGRESS_PREFIX) {
+            files.push((entry.path(), name));
+        }
+    }
+
+    let mut requests: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    let mut orphaned = 0;
+    let mut oldest: Option<NaiveDateTime> = None;
+    let mut newest: Option<NaiveDateTime> = None;
+
+    for (_, name) in &files {
+        let archived = name.rsplit_once('.').and_then(|(stem, ext)| {
+            let timestamp = name
+                .get(..TIMESTAMP_LEN)
+                .and_then(|t| NaiveDateTime::parse_from_str(t, TIMESTAMP_FORMAT).ok())?;
+            matches!(ext, "md" | "json").then_some((stem, ext, timestamp))
+        });
+        let Some((stem, ext, timestamp)) = archived else {
+            if name != MARKERS_FILE && name != INDEX_FILE {
+                orphaned += 1;
+            }
+            continue;
+        };
+        requests.entry(stem).or_default().push(ext);
+        oldest = Some(oldest.map_or(timestamp, |t| t.min(timestamp)));
+        newest = Some(newest.map_or(timestamp, |t| t.max(timestamp)));
+    }
+
+    let missing = requests
+        .values()
+        .filter(|exts| expected.iter().any(|ext| !exts.contains(ext)))
+        .count();
+
+    let stride = files.len().div_ceil(sample_limit.max(1)).max(1);
+    let (sampled, sampled_bytes) = files
+        .iter()
+        .step_by(stride)
+        .filter_map(|(path, _)| std::fs::metadata(path).ok())
+        .fold((0u64, 0u64), |(n, bytes), meta| (n + 1, bytes + meta.len()));
+    let bytes = (sampled_bytes * files.len() as u64)
+        .checked_div(sampled)
+        .unwrap_or(0);
+
+    Ok(ArchiveStatus {
+        directory: dir.to_path_buf(),
+        files: files.len(),
+        bytes,
+        bytes_estimated: stride > 1,
+        oldest,
+        newest,
+        requests: requests.len(),
+        missing,
+        orphaned,
+    })
+}
+
+/// Archived JSON request bodies, newest first
+pub fn archived_requests(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut names: Vec<String> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| {
+            name.ends_with(".json")
+                && !name.starts_with(IN_PROGRESS_PREFIX)
+                && name
+                    .get(..TIMESTAMP_LEN)
+                    .is_some_and(|t| NaiveDateTime::parse_from_str(t, TIMESTAMP_FORMAT).is_ok())
+        })
+        .collect();
+    names.sort_unstable_by(|a, b| b.cmp(a));
+    Ok(names.into_iter().map(|name| dir.join(name)).collect())
+}
+
+/// Rebuild the event of an archived JSON request from its body and its
+/// `<date>_<time>_<id>_<provider>.json` file name
+pub fn load_archived_request(path: &Path) -> Result<RequestEvent> {
+    let name = path.file_stem().and_then(|s| s.to_str()).unwrap_or_default();
+    let timestamp = name
+        .get(..TIMESTAMP_LEN)
+        .and_then(|t| NaiveDateTime::parse_from_str(t, TIMESTAMP_FORMAT).ok())
+        .with_context(|| format!("{} is not an archived request", path.display()))?;
+    let rest = name[TIMESTAMP_LEN..].trim_start_matches('_');
+    // Files from before request ids were part of the name have none
+    let (id, provider) = match rest.split_once('_') {
+        Some((id, provider)) => match id.parse() {
+            Ok(id) => (id, provider),
+            Err(_) => (0, rest),
+        },
+        None => (0, rest),
+    };
+    let body = std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut event = parse_request(&body, "", provider)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    event.timestamp = timestamp.and_utc();
+    event.id = id;
+    Ok(event)
+}
+
+impl std::fmt::Display for ArchiveStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let date = |d: Option<NaiveDateTime>| {
+            d.map(|d| d.format("%Y-%m-%d %H:%M:%S").to_string())
+                .unwrap_or_else(|| "-".to_string())
+        };
+
+        writeln!(f, "Archive: {}", self.directory.display())?;
+        writeln!(f, "  Files:    {} ({} requests)", self.files, self.requests)?;
+        writeln!(
+            f,
+            "  Size:     {}{}",
+            format_bytes(self.bytes),
+            if self.bytes_estimated { " (estimated)" } else { "" }
+        )?;
+        writeln!(f, "  Oldest:   {}", date(self.oldest))?;
+        writeln!(f, "  Newest:   {}", date(self.newest))?;
+        if self.missing == 0 && self.orphaned == 0 {
+            writeln!(f, "  Index:    ok")
+        } else {
+            writeln!(
+                f,
+                "  Index:    {} requests missing a format, {} orphaned files",
+                self.missing, self.orphaned
+            )
+        }
+    }
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Longest filename component taken from request-derived strings
+const MAX_COMPONENT_LEN: usize = 64;
+
+/// Make a request-derived string (provider, model, ...) safe to use as part of a filename.
+///
+/// Path separators, control and bidi-override characters, and characters
+/// Windows rejects are replaced, dot-only names and Windows device names are
+/// neutralised, and the result is capped at `MAX_COMPONENT_LEN` characters.
+pub fn sanitize_component(raw: &str) -> String {
+    let cleaned: String = raw
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() || is_bidi_control(c) => '_',
+            c => c,
+        })
+        .take(MAX_COMPONENT_LEN)
+        .collect();
+
+    let trimmed = cleaned.trim_matches(|c: char| c == '.' || c == ' ');
+    if trimmed.is_empty() {
+        return "unknown".to_string();
+    }
+
+    let stem = trimmed.split('.').next().unwrap_or(trimmed).to_ascii_uppercase();
+    if is_windows_reserved(&stem) {
+        return format!("_{}", trimmed);
+    }
+
+    trimmed.to_string()
+}
+
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
+fn is_windows_reserved(stem: &str) -> bool {
+    match stem {
+        "CON" | "PRN" | "AUX" | "NUL" => true,
+        _ => {
+            let bytes = stem.as_bytes();
+            bytes.len() == 4
+                && (stem.starts_with("COM") || stem.starts_with("LPT"))
+                && (b'1'..=b'9').contains(&bytes[3])
+        }
+    }
+}
+
+/// Resolve `<base_name>.<ext>` under the archive root, substituting a
+/// `<stamp>_request` name if the result would not stay directly inside it
+fn archive_path(root: &Path, base_name: &str, stamp: &str, ext: &str) -> PathBuf {
+    let file_name = format!("{}.{}", base_name, ext);
+    match confine_to_root(root, &file_name) {
+        Some(path) => path,
+        None => {
+            tracing::warn!(
+                "Refusing archive filename {:?} outside {:?}, using fallback",
+                file_name,
+                root
+            );
+            root.join(format!("{}_request.{}", stamp, ext))
+        }
+    }
 }
 
-fn format_markdown(event: &RequestEvent) -> String {
+/// `root.join(file_name)` if that is a direct child of the (canonical) root
+/// and doesn't resolve elsewhere through an existing symlink
+fn confine_to_root(root: &Path, file_name: &str) -> Option<PathBuf> {
+    let candidate = root.join(file_name);
+    if candidate.parent() != Some(root) || candidate.file_name()? != file_name {
+        return None;
+    }
+    if let Ok(resolved) = candidate.canonicalize() {
+        if !resolved.starts_with(root) {
+            return None;
+        }
+    }
+    Some(candidate)
+}
+
+/// Render `event` as markdown within the `limits`; `raw_json` is where the
+/// untruncated request was archived, if it was
+fn format_markdown(
+    event: &RequestEvent,
+    limits: &MarkdownArchiveConfig,
+    raw_json: Option<&Path>,
+) -> String {
     let mut md = String::new();
 
     // Header
-    md.push_str(&format!("# {} Request\n\n", capitalize(&event.provider)));
+    let tag = if event.manual { " [manual]" } else { "" };
+    md.push_str(&format!(
+        "# {} Request{}\n\n",
+        capitalize(&event.provider),
+        tag
+    ));
     md.push_str(&format!("- **Timestamp:** {}\n", event.timestamp));
     md.push_str(&format!("- **Model:** {}\n", event.model));
+    if let Some(served) = &event.served_model {
+        let note = match event.substituted_model() {
+            Some(_) => ", a different model",
+            None => "",
+        };
+        md.push_str(&format!("- **Served by:** {}{}\n", served, note));
+    }
+    if let Some(tier) = event.tier() {
+        let note = match event.tier_mismatch() {
+            Some(requested) => format!(", {} requested", requested),
+            None => String::new(),
+        };
+        md.push_str(&format!("- **Service tier:** {}{}\n", tier, note));
+    }
     md.push_str(&format!("- **Tokens:** {}\n", event.tokens));
+    let tools = tool_definition_names(&event.raw_body);
+    if !tools.is_empty() {
+        md.push_str(&format!(
+            "- **Tool definitions:** {} ({} tokens): {}\n",
+            tools.len(),
+            event.tool_definition_tokens(),
+            tools.join(", ")
+        ));
+    }
+    if let Some(cost) = event.cost_usd {
+        md.push_str(&format!("- **Cost:** {} (list price)\n", format_cost(Some(cost))));
+    }
+    if let Some(response) = event.response {
+        md.push_str(&format!("- **Status:** {}\n", response.status));
+    }
+    if let Some(chaos) = &event.chaos {
+        md.push_str(&format!("- **Chaos:** {}, injected by sherlock\n", chaos));
+    }
+    if let Some(latency_ms) = event.latency_ms {
+        md.push_str(&format!("- **Latency:** {} ms\n", latency_ms));
+    }
+    if let Some(repo) = &event.repo {
+        md.push_str(&format!("- **Repo:** {}\n", repo.label()));
+    }
+    if let Some(overlay) = &event.overlay {
+        md.push_str(&format!("- **Overlay:** {}\n", overlay.display()));
+    }
+    if let Some(recording) = &event.recording {
+        md.push_str(&format!("- **Recording:** {}\n", recording));
+    }
+    if let Some(failover) = &event.failover {
+        md.push_str(&format!(
+            "- **Failover:** {}\n",
+            failover.attempted.join(" → ")
+        ));
+    }
+    if !event.policy_matches.is_empty() {
+        md.push_str(&format!(
+            "- **Policy matches:** {}\n",
+            crate::policy::summarize(&event.policy_matches)
+        ));
+    }
+    if let Some(clamp) = &event.output_clamp {
+        md.push_str(&format!(
+            "- **Clamped:** {} {} → {}\n",
+            clamp.field, clamp.requested, clamp.limit
+        ));
+    }
+    if let Some(overflow) = &event.context_overflow {
+        md.push_str(&format!("- **Context:** {}\n", overflow.describe()));
+    }
+    if event.imported {
+        md.push_str("- **Imported:** yes\n");
+    }
     md.push_str(&format!("- **Path:** {}\n\n", event.path));
 
+    // Before the messages, so the advice survives truncation
+    if let Some(overflow) = &event.context_overflow {
+        md.push_str(&context::advise(event, overflow).to_markdown(overflow));
+    }
+
     // Messages
     md.push_str("## Messages\n\n");
 
-    for msg in &event.messages {
+    let full_content = match raw_json.and_then(Path::file_name) {
+        Some(name) => format!("full content in {}", name.to_string_lossy()),
+        None => "full content in the raw request".to_string(),
+    };
+    let collapsed = limits
+        .collapse_tool_results
+        .then(|| collapsed_messages(event))
+        .flatten();
+    for (i, msg) in event.messages.iter().enumerate() {
         md.push_str(&format!("### {}\n\n", capitalize(&msg.role)));
-        md.push_str(&msg.content);
+        let content = collapsed.as_ref().map_or(msg.content.as_str(), |c| c[i].as_str());
+        match limits.max_message_bytes {
+            Some(max) if content.len() > max => {
+                md.push_str(&truncate_markdown(content, max));
+                md.push_str(&format!(
+                    "\n\n*[Message truncated to {} of {}; {}]*",
+                    format_bytes(max as u64),
+                    format_bytes(content.len() as u64),
+                    full_content
+                ));
+            }
+            _ => md.push_str(content),
+        }
         md.push_str("\n\n");
     }
 
-    md
+    match limits.max_total_bytes {
+        Some(max) if md.len() > max => {
+            let mut cut = truncate_markdown(&md, max);
+            cut.push_str(&format!(
+                "\n\n---\n\n*[Document truncated at {} of {}; {}]*\n",
+                format_bytes(max as u64),
+                format_bytes(md.len() as u64),
+                full_content
+            ));
+            cut
+        }
+        _ => md,
+    }
+}
+
+/// Message texts with every tool result reduced to one line, or `None` when
+/// the body's messages don't line up with the parsed ones
+fn collapsed_messages(event: &RequestEvent) -> Option<Vec<String>> {
+    let conversation = Conversation::from_request_body(&event.raw_body);
+    let mut messages = event.messages.iter().peekable();
+    let mut collapsed = Vec::with_capacity(event.messages.len());
+    for turn in &conversation.turns {
+        if messages.next()?.role != turn.role {
+            return None;
+        }
+        // Anthropic tool calls follow their message as messages of their own
+        let mut calls = 0;
+        while messages.next_if(|m| m.role == TOOL_USE_ROLE).is_some() {
+            calls += 1;
+        }
+        let (tool_calls, blocks): (Vec<_>, Vec<_>) = turn
+            .blocks
+            .iter()
+            .partition(|block| calls > 0 && matches!(block, Block::ToolCall { .. }));
+        if calls > 0 && tool_calls.len() != calls {
+            return None;
+        }
+        let texts = blocks.into_iter().map(collapsed_block).filter(|b| !b.is_empty());
+        collapsed.push(texts.collect::<Vec<_>>().join("\n"));
+        collapsed.extend(tool_calls.into_iter().map(collapsed_block));
+    }
+    messages.next().is_none().then_some(collapsed)
+}
+
+/// A content block as a collapsed message shows it
+fn collapsed_block(block: &Block) -> String {
+    match block {
+        Block::Text(text) => text.clone(),
+        Block::ToolCall { name, input } => format!("*Tool call* `{}` {}", name, input),
+        Block::ToolResult {
+            id,
+            content,
+            is_error,
+        } => {
+            let first_line = content.lines().find(|l| !l.trim().is_empty()).unwrap_or("");
+            format!(
+                "*Tool {}* `{}`: {} ({})",
+                if *is_error { "error" } else { "result" },
+                id,
+                truncate(first_line.trim(), TOOL_SUMMARY_WIDTH),
+                format_bytes(content.len() as u64)
+            )
+        }
+        Block::Other { json, .. } => extract_text_from_value(json),
+        Block::Step(calls) => calls
+            .iter()
+            .map(|call| format!("*Tool call* `{}` {}", call.name, call.input))
+            .collect::<Vec<_>>()
+            .join("\n"),
+    }
+}
+
+/// Cut `text` to at most `max` bytes on a char boundary, closing a fenced
+/// code block left open by the cut
+fn truncate_markdown(text: &str, max: usize) -> String {
+    let mut end = max.min(text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    let mut cut = text[..end].to_string();
+
+    let mut open_fence: Option<String> = None;
+    for line in cut.lines() {
+        let line = line.trim_start();
+        let marker: String = match line.chars().next() {
+            Some(c @ ('`' | '~')) => line.chars().take_while(|&m| m == c).collect(),
+            _ => continue,
+        };
+        if marker.len() < 3 {
+            continue;
+        }
+        match &open_fence {
+            None => open_fence = Some(marker),
+            Some(open)
+                if marker.starts_with(open.as_str())
+                    && line[marker.len()..].trim().is_empty() =>
+            {
+                open_fence = None
+            }
+            Some(_) => {}
+        }
+    }
+    if let Some(fence) = open_fence {
+        if !cut.ends_with('\n') {
+            cut.push('\n');
+        }
+        cut.push_str(&fence);
+    }
+    cut
 }
 
 fn capitalize(s: &str) -> String {
@@ -100,6 +1139,7 @@ mod tests {
     fn test_format_markdown() {
         let event = RequestEvent {
             timestamp: Utc::now(),
+            id: 0,
             provider: "anthropic".to_string(),
             model: "claude-3".to_string(),
             tokens: 100,
@@ -107,16 +1147,685 @@ mod tests {
                 crate::event::Message {
                     role: "user".to_string(),
                     content: "Hello!".to_string(),
+                    unknown_parts: vec![],
                 },
             ],
-            raw_body: serde_json::json!({}),
+            raw_body: serde_json::json!({"tools": [
+                {"name": "bash", "input_schema": {"type": "object"}},
+                {"name": "read_file", "input_schema": {"type": "object"}}
+            ]}),
             path: "/v1/messages".to_string(),
+            api_version: None,
+            streaming: false,
+            key: None,
+            client_aborted: false,
+            policy_matches: BTreeMap::new(),
+            failover: None,
+            recording: None,
+            repo: None,
+            overlay: None,
+            unarchived: false,
+            output_clamp: None,
+            chaos: None,
+            imported: false,
+            self_test: false,
+            manual: false,
+            throughput: None,
+            composition: Some(crate::event::TokenComposition {
+                tools: 40,
+                conversation: 60,
+                ..Default::default()
+            }),
+            response: None,
+            output: None,
+            latency_ms: Some(1830),
+            timings: None,
+            context_overflow: None,
+            served_model: None,
+            service_tier: None,
+            served_tier: None,
+            cost_usd: Some(0.0123),
+            response_text: None,
+            auth: Default::default(),
+            cached_content: None,
+            gemini_usage: None,
         };
 
-        let md = format_markdown(&event);
+        let md = format_markdown(&event, &MarkdownArchiveConfig::default(), None);
         assert!(md.contains("# Anthropic Request"));
         assert!(md.contains("**Model:** claude-3"));
+        assert!(md.contains("**Latency:** 1830 ms"));
+        assert!(md.contains("**Cost:** $0.01 (list price)"));
+        assert!(md.contains("**Tool definitions:** 2 (40 tokens): bash, read_file"));
+        assert!(!md.contains("input_schema"));
         assert!(md.contains("### User"));
         assert!(md.contains("Hello!"));
     }
+
+    #[test]
+    fn test_markdown_limits() {
+        let body = serde_json::json!({
+            "model": "claude-3",
+            "messages": [
+                {"role": "user", "content": "Run it"},
+                {"role": "assistant", "content": [
+                    {"type": "tool_use", "id": "t1", "name": "bash", "input": {"cmd": "ls"}}
+                ]},
+                {"role": "user", "content": [
+                    {
+                        "type": "tool_result",
+                        "tool_use_id": "t1",
+                        "content": "a.rs\nb.rs\n".repeat(500)
+                    }
+                ]},
+                {"role": "assistant", "content": "Here:\n```rust\nfn main() {}\n// ééééé\n```"}
+            ]
+        });
+        let event = crate::parser::parse_request(
+            &serde_json::to_vec(&body).unwrap(),
+            "/v1/messages",
+            "anthropic",
+        )
+        .unwrap();
+        let raw = Path::new("/archive/x_anthropic.json");
+
+        // Defaults keep every message whole
+        let full = format_markdown(&event, &MarkdownArchiveConfig::default(), Some(raw));
+        assert!(full.contains(&"a.rs\nb.rs\n".repeat(500).trim_end().to_string()));
+
+        let limits = MarkdownArchiveConfig {
+            max_message_bytes: Some(34),
+            collapse_tool_results: true,
+            max_total_bytes: None,
+        };
+        let md = format_markdown(&event, &limits, Some(raw));
+        assert!(md.contains("*Tool result* `t1`: a.rs (4.9 KB)"), "{}", md);
+        assert!(md.contains("*Tool call* `bash` {\"cmd\":\"ls\"}"));
+        // Cut inside the code block and a two-byte char, the fence closed again
+        let expected = "Here:\n```rust\nfn main() {}\n// éé\n```\n\n\
+                        *[Message truncated to 34 B of 44 B; full content in x_anthropic.json]*";
+        assert!(md.contains(expected), "{}", md);
+
+        let limits = MarkdownArchiveConfig {
+            max_total_bytes: Some(200),
+            ..MarkdownArchiveConfig::default()
+        };
+        let md = format_markdown(&event, &limits, None);
+        assert!(md.len() < 300);
+        assert!(md.contains("\n---\n\n*[Document truncated at 200 B of "), "{}", md);
+        assert!(md.ends_with(" KB; full content in the raw request]*\n"));
+    }
+
+    #[test]
+    fn test_truncate_markdown_fences() {
+        assert_eq!(truncate_markdown("plain text", 5), "plain");
+        // A shorter fence doesn't close a longer one
+        assert_eq!(
+            truncate_markdown("~~~~\ncode\n~~~\nmore", 18),
+            "~~~~\ncode\n~~~\nmore\n~~~~"
+        );
+        assert_eq!(truncate_markdown("```\na\n```\ntext", 12), "```\na\n```\nte");
+        assert_eq!(truncate_markdown("x\n```py\nprint()", 10), "x\n```py\npr\n```");
+        assert_eq!(truncate_markdown("日本語", 4), "日");
+    }
+
+    #[test]
+    fn test_sanitize_component() {
+        assert_eq!(sanitize_component("anthropic"), "anthropic");
+        assert_eq!(sanitize_component("claude-3.5-sonnet"), "claude-3.5-sonnet");
+        assert_eq!(
+            sanitize_component("../../../../etc/cron.d/x"),
+            "_.._.._.._etc_cron.d_x"
+        );
+        assert_eq!(sanitize_component(".."), "unknown");
+        assert_eq!(sanitize_component(" ... "), "unknown");
+        assert_eq!(sanitize_component("a\\b\0c\nd"), "a_b_c_d");
+        // Right-to-left override used to disguise an extension
+        assert_eq!(sanitize_component("invoice\u{202E}fdp.exe"), "invoice_fdp.exe");
+        assert_eq!(sanitize_component("CON"), "_CON");
+        assert_eq!(sanitize_component("nul.txt"), "_nul.txt");
+        assert_eq!(sanitize_component("com7"), "_com7");
+        assert_eq!(sanitize_component("console"), "console");
+        assert_eq!(sanitize_component(&"é".repeat(200)).chars().count(), MAX_COMPONENT_LEN);
+    }
+
+    #[test]
+    fn test_archive_path_stays_in_root() {
+        let root = std::env::temp_dir()
+            .canonicalize()
+            .unwrap()
+            .join(format!("sherlock-archive-test-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+
+        let ok = archive_path(&root, "20240101_000000.000_anthropic", "20240101_000000.000", "md");
+        assert_eq!(ok, root.join("20240101_000000.000_anthropic.md"));
+
+        let escaped = archive_path(&root, "../../etc/passwd", "20240101_000000.000", "json");
+        assert_eq!(escaped, root.join("20240101_000000.000_request.json"));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    fn temp_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir()
+            .canonicalize()
+            .unwrap()
+            .join(format!("sherlock-archive-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    /// Records what it's given, or fails every write
+    struct TestSink {
+        name: &'static str,
+        written: Arc<std::sync::Mutex<Vec<u64>>>,
+        fail: bool,
+        closed: Arc<std::sync::atomic::AtomicBool>,
+    }
+
+    impl TestSink {
+        fn new(name: &'static str, fail: bool) -> Self {
+            Self {
+                name,
+                written: Arc::default(),
+                fail,
+                closed: Arc::default(),
+            }
+        }
+    }
+
+    impl ArchiveSink for TestSink {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn write<'a>(&'a mut self, event: &'a RequestEvent) -> SinkFuture<'a> {
+            Box::pin(async move {
+                if self.fail {
+                    anyhow::bail!("{} is unavailable", self.name);
+                }
+                self.written.lock().unwrap().push(event.id);
+                Ok(10)
+            })
+        }
+
+        fn close(&mut self) -> SinkFuture<'_, ()> {
+            self.closed.store(true, std::sync::atomic::Ordering::Relaxed);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    fn test_event(id: u64) -> RequestEvent {
+        let mut event = crate::parser::minimal_event(b"{}", "/v1/messages", "anthropic");
+        event.id = id;
+        event
+    }
+
+    #[tokio::test]
+    async fn test_failing_sink_does_not_stop_others() {
+        let first = TestSink::new("first", false);
+        let broken = TestSink::new("broken", true);
+        let last = TestSink::new("last", false);
+        let (written, closed) = (Arc::clone(&last.written), Arc::clone(&last.closed));
+        let sinks: Vec<Box<dyn ArchiveSink>> =
+            vec![Box::new(first), Box::new(broken), Box::new(last)];
+
+        let (tx, rx) = mpsc::channel(8);
+        for id in [1, 2, 3] {
+            tx.send(test_event(id).into()).await.unwrap();
+        }
+        drop(tx);
+        let metrics = ArchiveMetrics::default();
+        write_to_sinks(rx, sinks, &Redactor::default(), &metrics, None, None).await;
+
+        assert_eq!(*written.lock().unwrap(), [1, 2, 3]);
+        assert!(closed.load(std::sync::atomic::Ordering::Relaxed));
+        let snapshot = metrics.snapshot();
+        assert_eq!((snapshot.formats["first"].written, snapshot.formats["first"].bytes), (3, 30));
+        assert_eq!(snapshot.formats["broken"].failures, 3);
+        assert_eq!(snapshot.formats["last"].written, 3);
+        assert_eq!(snapshot.last_error.as_deref(), Some("broken is unavailable"));
+    }
+
+    #[tokio::test]
+    async fn test_save_prompt_metrics() {
+        let root = temp_root("metrics");
+        let config = ArchiveConfig::default();
+        let metrics = ArchiveMetrics::default();
+        let event = RequestEvent {
+            timestamp: Utc::now(),
+            id: 0,
+            provider: "anthropic".to_string(),
+            model: "claude-3".to_string(),
+            tokens: 1,
+            messages: vec![],
+            raw_body: serde_json::json!({"model": "claude-3"}),
+            path: "/v1/messages".to_string(),
+            api_version: None,
+            streaming: false,
+            key: None,
+            client_aborted: false,
+            policy_matches: BTreeMap::new(),
+            failover: None,
+            recording: None,
+            repo: None,
+            overlay: None,
+            unarchived: false,
+            output_clamp: None,
+            chaos: None,
+            imported: false,
+            self_test: false,
+            manual: false,
+            throughput: None,
+            composition: None,
+            response: None,
+            output: None,
+            latency_ms: None,
+            timings: None,
+            context_overflow: None,
+            served_model: None,
+            service_tier: None,
+            served_tier: None,
+            cost_usd: None,
+            response_text: None,
+            auth: Default::default(),
+            cached_content: None,
+            gemini_usage: None,
+        };
+
+        let mut sinks = build_sinks(&config, &root);
+        let test_sink = TestSink::new("test", false);
+        let written = Arc::clone(&test_sink.written);
+        sinks.push(Box::new(test_sink));
+        save_prompt(&event, &mut sinks, &Redactor::default(), &metrics).await;
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.formats["markdown"].written, 1);
+        assert_eq!(snapshot.formats["json"].written, 1);
+        assert!(snapshot.formats["json"].bytes > 0);
+        assert_eq!(snapshot.last_error, None);
+        assert_eq!(*written.lock().unwrap(), [0]);
+
+        std::fs::remove_dir_all(&root).unwrap();
+        save_prompt(&event, &mut sinks, &Redactor::default(), &metrics).await;
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.formats["markdown"].failures, 1);
+        assert_eq!(snapshot.formats["json"].failures, 1);
+        assert_eq!(snapshot.formats["test"].written, 2);
+        assert!(snapshot.last_error.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_same_millisecond_requests_kept_apart() {
+        let root = temp_root("collide");
+        let config = ArchiveConfig {
+            sinks: vec![SinkConfig::Json],
+            ..ArchiveConfig::default()
+        };
+        let mut sinks = build_sinks(&config, &root);
+        let metrics = ArchiveMetrics::default();
+        let mut event = test_event(0);
+        for id in [12, 9] {
+            event.id = id;
+            event.raw_body = serde_json::json!({"id": id});
+            save_prompt(&event, &mut sinks, &Redactor::default(), &metrics).await;
+        }
+
+        // Both survive, and name order follows request order
+        let files = archived_requests(&root).unwrap();
+        let bodies: Vec<String> = files
+            .iter()
+            .map(|path| std::fs::read_to_string(path).unwrap())
+            .collect();
+        assert_eq!(files.len(), 2);
+        assert!(bodies[0].contains("12") && bodies[1].contains('9'));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_archive_status() {
+        let root = temp_root("status");
+        let write = |name: &str, size: usize| {
+            std::fs::write(root.join(name), "x".repeat(size)).unwrap();
+        };
+        write("20240101_090000.000_anthropic.md", 100);
+        write("20240101_090000.000_anthropic.json", 100);
+        write("20240315_120000.500_openai.md", 100);
+        write("notes.txt", 100);
+        write(".DS_Store", 7);
+
+        let sinks = ArchiveConfig::default().sinks;
+        let status = archive_status(&root, &sinks, 100).unwrap();
+        assert_eq!(status.files, 4);
+        assert_eq!(status.requests, 2);
+        assert_eq!(status.missing, 1);
+        assert_eq!(status.orphaned, 1);
+        assert_eq!(status.bytes, 400);
+        assert!(!status.bytes_estimated);
+        assert_eq!(status.oldest.unwrap().to_string(), "2024-01-01 09:00:00");
+        assert_eq!(status.newest.unwrap().to_string(), "2024-03-15 12:00:00.500");
+
+        // Sizes from a sample are extrapolated to the whole archive
+        let sampled = archive_status(&root, &sinks, 2).unwrap();
+        assert!(sampled.bytes_estimated);
+        assert_eq!(sampled.bytes, 400);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_reading_while_archiving() {
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+        let root = temp_root("concurrent");
+        let config = ArchiveConfig {
+            sinks: vec![SinkConfig::Json],
+            ..ArchiveConfig::default()
+        };
+        let sinks = build_sinks(&config, &root);
+        let (tx, rx) = mpsc::channel(64);
+        let writer = tokio::spawn({
+            let root = root.clone();
+            async move {
+                let metrics = ArchiveMetrics::default();
+                write_to_sinks(rx, sinks, &Redactor::default(), &metrics, Some(&root), None).await;
+            }
+        });
+
+        // `sherlock stats` and `sherlock view` style readers, over and over
+        let sent = Arc::new(AtomicUsize::new(0));
+        let done = Arc::new(AtomicBool::new(false));
+        let reader = tokio::task::spawn_blocking({
+            let (root, sent, done) = (root.clone(), Arc::clone(&sent), Arc::clone(&done));
+            move || {
+                let (mut reads, mut seen) = (0, 0);
+                while !done.load(Ordering::Relaxed) {
+                    let indexed = index::read_index(&root).unwrap().len();
+                    assert!(indexed >= seen, "index shrank from {} to {}", seen, indexed);
+                    assert!(indexed <= sent.load(Ordering::Relaxed));
+                    seen = indexed;
+                    // The newest files are the ones that may be mid-write
+                    for path in archived_requests(&root).unwrap().iter().take(20) {
+                        match load_archived_request(path) {
+                            Ok(event) => assert_eq!(event.model, "claude-3"),
+                            // Pruned or moved away between listing and reading
+                            Err(_) if !path.exists() => {}
+                            Err(e) => panic!("read a partial file: {:#}", e),
+                        }
+                    }
+                    reads += 1;
+                }
+                reads
+            }
+        });
+
+        let content = "x".repeat(64 * 1024);
+        let started = std::time::Instant::now();
+        while started.elapsed() < std::time::Duration::from_secs(3) {
+            let mut event = test_event(sent.load(Ordering::Relaxed) as u64);
+            event.timestamp = Utc::now();
+            event.raw_body = serde_json::json!({
+                "model": "claude-3",
+                "messages": [{"role": "user", "content": content}]
+            });
+            sent.fetch_add(1, Ordering::Relaxed);
+            tx.send(event.into()).await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        }
+        drop(tx);
+        writer.await.unwrap();
+        done.store(true, Ordering::Relaxed);
+        assert!(reader.await.unwrap() > 10);
+
+        let total = sent.load(Ordering::Relaxed);
+        assert_eq!(index::read_index(&root).unwrap().len(), total);
+        assert_eq!(archived_requests(&root).unwrap().len(), total);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    fn deferred_config(root: &Path) -> ArchiveConfig {
+        ArchiveConfig {
+            directory: root.to_path_buf(),
+            sinks: vec![
+                SinkConfig::Markdown {
+                    render: RenderMode::Deferred,
+                },
+                SinkConfig::Json,
+            ],
+            markdown: MarkdownArchiveConfig {
+                max_message_bytes: Some(1000),
+                ..MarkdownArchiveConfig::default()
+            },
+            ..ArchiveConfig::default()
+        }
+    }
+
+    fn long_event(id: u64) -> RequestEvent {
+        let body = serde_json::json!({
+            "model": "claude-3",
+            "system": "Be brief.",
+            "messages": [
+                {"role": "user", "content": "y".repeat(20_000)},
+                {"role": "assistant", "content": "Done."}
+            ]
+        });
+        let mut event =
+            parse_request(body.to_string().as_bytes(), "/v1/messages", "anthropic").unwrap();
+        event.id = id;
+        event
+    }
+
+    #[tokio::test]
+    async fn test_deferred_markdown_matches_eager() {
+        let eager_root = temp_root("eager");
+        let config = deferred_config(&temp_root("deferred"));
+        let root = config.directory.clone();
+        let event = long_event(3);
+        let metrics = ArchiveMetrics::default();
+        let eager = ArchiveConfig {
+            sinks: ArchiveConfig::default().sinks,
+            ..config.clone()
+        };
+        save_prompt(
+            &event,
+            &mut build_sinks(&eager, &eager_root),
+            &Redactor::default(),
+            &metrics,
+        )
+        .await;
+
+        let metrics = Arc::new(ArchiveMetrics::default());
+        let (tx, rx) = mpsc::channel(8);
+        let writer = tokio::spawn(archive_writer(
+            rx,
+            config,
+            Arc::default(),
+            Arc::clone(&metrics),
+            None,
+        ));
+        tx.send(event.clone().into()).await.unwrap();
+        let name = format!("{}.md", entry_name(&event).1);
+        let started = std::time::Instant::now();
+        while !root.join(&name).exists() {
+            assert!(started.elapsed() < std::time::Duration::from_secs(5));
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+        drop(tx);
+        writer.await.unwrap().unwrap();
+
+        let rendered = std::fs::read(root.join(&name)).unwrap();
+        assert!(String::from_utf8_lossy(&rendered).contains(".json"));
+        assert_eq!(rendered, std::fs::read(eager_root.join(&name)).unwrap());
+        assert!(pending_renders(&root).unwrap().is_empty());
+        assert_eq!(metrics.snapshot().formats["markdown"].failures, 0);
+        std::fs::remove_dir_all(&root).unwrap();
+        std::fs::remove_dir_all(&eager_root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_render_queue_survives_restart() {
+        let eager_root = temp_root("eager-restart");
+        let config = deferred_config(&temp_root("restart"));
+        let root = config.directory.clone();
+        let metrics = ArchiveMetrics::default();
+        // The renderer was gone before it got to either request
+        let (queue, queued) = mpsc::unbounded_channel();
+        drop(queued);
+        let mut sinks = sinks_with_queue(&config, &root, Some(queue));
+        let events = [long_event(1), long_event(2)];
+        for event in &events {
+            save_prompt(event, &mut sinks, &Redactor::default(), &metrics).await;
+            save_index(Some(&root), &IndexEntry::from(event), &metrics).await;
+        }
+        assert_eq!(metrics.snapshot().formats["markdown"].written, 2);
+        let (_, base_name) = entry_name(&events[0]);
+        assert!(!root.join(format!("{}.md", base_name)).exists());
+        let pending = pending_renders(&root).unwrap();
+        assert_eq!(pending.len(), 2);
+        assert!(pending[0].ends_with(format!("{}/{}.json", RENDER_QUEUE_DIR, base_name)));
+
+        // By request id, or by any of the request's file names
+        let Rendered::Now(path) = render_entry(&config, "1").await.unwrap() else {
+            panic!("request 1 was already rendered");
+        };
+        let json = format!("{}.json", entry_name(&events[1]).1);
+        let Rendered::Now(second) = render_entry(&config, &json).await.unwrap() else {
+            panic!("request 2 was already rendered");
+        };
+        assert!(pending_renders(&root).unwrap().is_empty());
+        assert_eq!(
+            render_entry(&config, "1").await.unwrap(),
+            Rendered::Already(path.clone())
+        );
+        assert!(render_entry(&config, "9").await.is_err());
+
+        save_prompt(
+            &events[0],
+            &mut build_sinks(&config, &eager_root),
+            &Redactor::default(),
+            &metrics,
+        )
+        .await;
+        let eager = std::fs::read(eager_root.join(path.file_name().unwrap())).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), eager);
+        assert!(second.exists());
+        std::fs::remove_dir_all(&root).unwrap();
+        std::fs::remove_dir_all(&eager_root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_markers_file() {
+        let root = temp_root("markers");
+        let sinks: Vec<Box<dyn ArchiveSink>> = vec![Box::new(JsonSink { root: root.clone() })];
+        let marker = |label: &str| Marker {
+            label: label.to_string(),
+            timestamp: Utc::now(),
+            tag: None,
+            session: None,
+        };
+        let (tx, rx) = mpsc::channel(8);
+        tx.send(ArchiveEntry::Marker(marker("started refactor"))).await.unwrap();
+        tx.send(test_event(1).into()).await.unwrap();
+        tx.send(ArchiveEntry::Marker(marker("ran tests"))).await.unwrap();
+        drop(tx);
+        let metrics = ArchiveMetrics::default();
+        write_to_sinks(rx, sinks, &Redactor::default(), &metrics, None, None).await;
+
+        let markers = std::fs::read_to_string(root.join(MARKERS_FILE)).unwrap();
+        let labels: Vec<String> = markers
+            .lines()
+            .map(|line| serde_json::from_str::<Marker>(line).unwrap().label)
+            .collect();
+        assert_eq!(labels, ["started refactor", "ran tests"]);
+        // Markers aren't counted as archived requests, nor as stray files
+        assert_eq!(metrics.snapshot().formats["json"].written, 1);
+        let status = archive_status(&root, &[SinkConfig::Json], 100).unwrap();
+        assert_eq!((status.requests, status.orphaned), (1, 0));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_flush_recovery() {
+        let root = temp_root("flush");
+        let pending = root.join("state").join(PENDING_FILE);
+        let archive = root.join("archive");
+        let config = ArchiveConfig {
+            directory: archive.clone(),
+            sinks: vec![SinkConfig::Json],
+            ..ArchiveConfig::default()
+        };
+        std::fs::create_dir_all(&archive).unwrap();
+        let metrics = Arc::new(ArchiveMetrics::default());
+        let (tx, rx) = mpsc::channel(16);
+        let writer = tokio::spawn(archive_writer(
+            rx,
+            config.clone(),
+            Arc::default(),
+            Arc::clone(&metrics),
+            Some(pending.clone()),
+        ));
+
+        let events: Vec<RequestEvent> = (1..=6).map(test_event).collect();
+        for event in &events[..2] {
+            tx.send(event.clone().into()).await.unwrap();
+        }
+        while archived_requests(&archive).unwrap().len() < 2 {
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+        // The flush runs out of time with four requests, a marker and a failure queued
+        metrics.begin_shutdown(std::time::Instant::now());
+        for event in &events[2..] {
+            tx.send(event.clone().into()).await.unwrap();
+        }
+        let marker = Marker {
+            label: "quit".to_string(),
+            timestamp: Utc::now(),
+            tag: None,
+            session: None,
+        };
+        tx.send(ArchiveEntry::Marker(marker)).await.unwrap();
+        let failure = RequestFailure {
+            timestamp: Utc::now(),
+            id: 7,
+            provider: "anthropic".to_string(),
+            model: None,
+            latency_ms: 900,
+            error: "upstream error: connection reset".to_string(),
+        };
+        tx.send(ArchiveEntry::Failure(failure)).await.unwrap();
+        drop(tx);
+        writer.await.unwrap().unwrap();
+        assert!(metrics.is_finished());
+        assert_eq!(archived_requests(&archive).unwrap().len(), 2);
+        let saved = std::fs::read_to_string(&pending).unwrap();
+        assert_eq!(saved.lines().count(), 6);
+
+        // An already archived request and a repeated line aren't written twice
+        let lines: Vec<&str> = saved.lines().collect();
+        let archived = serde_json::to_string(&ArchiveEntry::from(events[0].clone())).unwrap();
+        let extra = format!("{}\n{}\n{}\n", archived, lines[0], lines[5]);
+        std::fs::write(&pending, saved.clone() + &extra).unwrap();
+
+        let metrics = Arc::new(ArchiveMetrics::default());
+        let (tx, rx) = mpsc::channel(1);
+        drop(tx);
+        archive_writer(
+            rx,
+            config,
+            Arc::default(),
+            Arc::clone(&metrics),
+            Some(pending.clone()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(archived_requests(&archive).unwrap().len(), 6);
+        assert_eq!(metrics.snapshot().formats["json"].written, 4);
+        let markers = std::fs::read_to_string(archive.join(MARKERS_FILE)).unwrap();
+        assert_eq!(markers.lines().count(), 1);
+        let indexed = index::read_index(&archive).unwrap();
+        assert_eq!(indexed.len(), 7);
+        assert_eq!(indexed.iter().filter(|entry| entry.error.is_some()).count(), 1);
+        assert!(!pending.exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
 }
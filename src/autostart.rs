@@ -0,0 +1,289 @@
+//! Tools `sherlock start` launches once the proxy is listening, from the
+//! `autostart` config. Each runs under a supervisor task that reports its
+//! state to the dashboard, restarts it after a failure when asked to, and
+//! stops it when sherlock quits.
+
+use anyhow::{Context, Result};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::{Child, Command};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+
+use crate::config::{expand_tilde, AutostartTool, Config};
+use crate::event::ProxyEvent;
+use crate::launch;
+use crate::proxy::SessionInfo;
+use crate::repo::RepoInfo;
+
+/// Pause before starting a failed tool again
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Where an autostarted tool is at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ToolState {
+    /// Running as `pid`, after `restarts` restarts
+    Running { pid: Option<u32>, restarts: u32 },
+    /// Exited with `code`, or `None` when a signal ended it
+    Exited { code: Option<i32> },
+    /// The command couldn't be started
+    Failed(String),
+}
+
+impl ToolState {
+    /// Whether the tool ended in a way worth pointing out
+    pub fn is_failure(&self) -> bool {
+        match self {
+            ToolState::Running { .. } => false,
+            ToolState::Exited { code } => *code != Some(0),
+            ToolState::Failed(_) => true,
+        }
+    }
+}
+
+impl fmt::Display for ToolState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ToolState::Running { restarts: 0, .. } => write!(f, "running"),
+            ToolState::Running { restarts, .. } => write!(f, "running, restart {}", restarts),
+            ToolState::Exited { code: Some(code) } => write!(f, "exited {}", code),
+            ToolState::Exited { code: None } => write!(f, "killed"),
+            ToolState::Failed(error) => write!(f, "failed: {}", error),
+        }
+    }
+}
+
+/// An autostarted tool's new state, for the dashboard's Tools strip
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ToolStatus {
+    pub tag: String,
+    pub state: ToolState,
+}
+
+/// The supervisors of the autostarted tools
+pub struct Tools {
+    /// Set once sherlock quits: `Some(true)` to stop the tools, `Some(false)`
+    /// to leave them running
+    stop: watch::Sender<Option<bool>>,
+    supervisors: Vec<JoinHandle<()>>,
+}
+
+impl Tools {
+    /// Launch every tool in `config.autostart` against the proxy listening
+    /// on `proxy_addr`, logging each to `log_dir`. With `repo_info` set,
+    /// their requests are tagged with the repository they run in.
+    pub fn start(
+        config: &Config,
+        proxy_addr: &str,
+        log_dir: &Path,
+        repo_info: bool,
+        events: mpsc::Sender<ProxyEvent>,
+    ) -> Result<Self> {
+        let (stop, stopped) = watch::channel(None);
+        if !config.autostart.is_empty() {
+            std::fs::create_dir_all(log_dir)
+                .with_context(|| format!("Failed to create {:?}", log_dir))?;
+        }
+        let supervisors = config
+            .autostart
+            .iter()
+            .map(|tool| {
+                let launch = Launch::new(tool, config, proxy_addr, log_dir, repo_info);
+                tokio::spawn(supervise(launch, stopped.clone(), events.clone()))
+            })
+            .collect();
+        Ok(Self { stop, supervisors })
+    }
+
+    /// Stop the tools, or with `kill` unset leave them running on their own,
+    /// and wait for the supervisors to finish
+    pub async fn stop(self, kill: bool) {
+        let _ = self.stop.send(Some(kill));
+        for supervisor in self.supervisors {
+            let _ = supervisor.await;
+        }
+    }
+}
+
+/// Everything needed to start one tool, again if it has to be restarted
+struct Launch {
+    tag: String,
+    tool: AutostartTool,
+    cwd: Option<PathBuf>,
+    env: Vec<(String, String)>,
+    log: PathBuf,
+}
+
+impl Launch {
+    fn new(
+        tool: &AutostartTool,
+        config: &Config,
+        proxy_addr: &str,
+        log_dir: &Path,
+        repo_info: bool,
+    ) -> Self {
+        let tag = tool.tag();
+        let cwd = tool.cwd.as_deref().map(expand_tilde);
+        let mut proxy_url = format!("http://{}", proxy_addr);
+        let dir = cwd.clone().or_else(|| std::env::current_dir().ok());
+        let session = SessionInfo {
+            repo: dir
+                .filter(|_| repo_info)
+                .and_then(|dir| RepoInfo::detect(&dir)),
+            overlay: None,
+        };
+        if session != SessionInfo::default() {
+            proxy_url.push_str(&session.to_path());
+        }
+        // Validated on load, so the provider is known
+        let provider = &config.providers[&tool.provider];
+        let format = provider.body_format(&tool.provider);
+        let env = launch::env_vars(provider, format, &config.run, &[])
+            .into_iter()
+            .map(|name| (name, proxy_url.clone()))
+            .collect();
+        Self {
+            log: log_dir.join(format!("{}.log", tag)),
+            tag,
+            tool: tool.clone(),
+            cwd,
+            env,
+        }
+    }
+
+    /// Start the tool with its output appended to its log; the terminal
+    /// belongs to the dashboard
+    fn spawn(&self) -> Result<Child> {
+        let log = std::fs::File::options()
+            .create(true)
+            .append(true)
+            .open(&self.log)
+            .with_context(|| format!("Failed to open {:?}", self.log))?;
+        let mut cmd = Command::new(&self.tool.command);
+        cmd.args(&self.tool.args)
+            .envs(self.env.iter().map(|(name, value)| (name, value)))
+            .stdin(Stdio::null())
+            .stdout(log.try_clone()?)
+            .stderr(log);
+        if let Some(cwd) = &self.cwd {
+            cmd.current_dir(cwd);
+        }
+        // Its own process group, so a Ctrl-C at sherlock's terminal doesn't
+        // reach a tool meant to outlive it
+        #[cfg(unix)]
+        cmd.process_group(0);
+        cmd.spawn()
+            .with_context(|| format!("Failed to run {:?}", self.tool.command))
+    }
+}
+
+/// Run one tool until it exits for good or sherlock quits
+async fn supervise(
+    launch: Launch,
+    mut stop: watch::Receiver<Option<bool>>,
+    events: mpsc::Sender<ProxyEvent>,
+) {
+    let report = |state: ToolState| {
+        let status = ToolStatus {
+            tag: launch.tag.clone(),
+            state,
+        };
+        let events = events.clone();
+        async move {
+            let _ = events.send(ProxyEvent::Tool(status)).await;
+        }
+    };
+    let mut restarts = 0;
+    loop {
+        let mut child = match launch.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                tracing::error!("Couldn't start {}: {:#}", launch.tag, e);
+                report(ToolState::Failed(format!("{:#}", e))).await;
+                return;
+            }
+        };
+        let pid = child.id();
+        tracing::info!(
+            "Started {} (pid {}), logging to {:?}",
+            launch.tag,
+            pid.unwrap_or_default(),
+            launch.log
+        );
+        report(ToolState::Running { pid, restarts }).await;
+
+        let status = tokio::select! {
+            status = child.wait() => status,
+            _ = stop.changed() => {
+                if *stop.borrow() == Some(true) {
+                    terminate(&launch.tag, child).await;
+                }
+                return;
+            }
+        };
+        let code = match status {
+            Ok(status) => status.code(),
+            Err(e) => {
+                tracing::error!("Lost track of {}: {}", launch.tag, e);
+                None
+            }
+        };
+        let state = ToolState::Exited { code };
+        if !state.is_failure() {
+            tracing::info!("{} exited with code 0", launch.tag);
+            report(state).await;
+            return;
+        }
+        tracing::warn!("{} {}", launch.tag, state);
+        report(state).await;
+        if restarts >= launch.tool.restarts {
+            return;
+        }
+        restarts += 1;
+        tracing::info!(
+            "Restarting {} ({} of {})",
+            launch.tag,
+            restarts,
+            launch.tool.restarts
+        );
+        tokio::select! {
+            _ = tokio::time::sleep(RESTART_DELAY) => {}
+            _ = stop.changed() => return,
+        }
+    }
+}
+
+async fn terminate(tag: &str, mut child: Child) {
+    if let Err(e) = child.start_kill() {
+        tracing::warn!("Couldn't stop {}: {}", tag, e);
+        return;
+    }
+    let _ = child.wait().await;
+    tracing::info!("Stopped {}", tag);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_display() {
+        let running = |restarts| ToolState::Running {
+            pid: Some(42),
+            restarts,
+        };
+        assert_eq!(running(0).to_string(), "running");
+        assert_eq!(running(2).to_string(), "running, restart 2");
+        assert!(!running(0).is_failure());
+
+        let exited = |code| ToolState::Exited { code };
+        assert_eq!(exited(Some(0)).to_string(), "exited 0");
+        assert!(!exited(Some(0)).is_failure());
+        assert_eq!(exited(Some(3)).to_string(), "exited 3");
+        assert!(exited(Some(3)).is_failure());
+        assert_eq!(exited(None).to_string(), "killed");
+        assert!(ToolState::Failed("not found".to_string()).is_failure());
+    }
+}
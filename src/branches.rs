@@ -0,0 +1,317 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+
+use crate::archive::{archived_requests, load_archived_request};
+use crate::caching::conversation;
+use crate::delta::message_hashes;
+use crate::event::RequestEvent;
+use crate::watermark::Peak;
+
+/// Requests of one conversation arranged by where their histories part ways.
+/// Requests that only append stay on their branch; editing an earlier
+/// message and resending starts a new branch at that message.
+#[derive(Debug, Default)]
+pub struct ConversationTree {
+    /// Branch that first reached each message prefix, keyed by a hash chained
+    /// over the prefix's messages
+    prefixes: HashMap<u64, usize>,
+    branches: Vec<Branch>,
+    requests: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Branch {
+    /// Branch this one forked from; unset for the first
+    pub parent: Option<usize>,
+    /// Messages shared with the parent before the histories part ways
+    pub forked_at: usize,
+    /// Messages in the branch's longest request
+    pub len: usize,
+    /// Requests on the branch, numbered in the order they were added
+    pub requests: Vec<usize>,
+}
+
+impl ConversationTree {
+    /// Add the next request given one hash per message, returning its branch.
+    /// Each call costs a hash per message and a binary search over them.
+    pub fn insert(&mut self, messages: &[u64]) -> usize {
+        let mut chain = 0;
+        let prefixes: Vec<u64> = messages
+            .iter()
+            .map(|message| {
+                let mut hasher = DefaultHasher::new();
+                (chain, message).hash(&mut hasher);
+                chain = hasher.finish();
+                chain
+            })
+            .collect();
+        // Every prefix of an added request is kept, so the known ones come first
+        let shared = prefixes.partition_point(|prefix| self.prefixes.contains_key(prefix));
+        let owner = match shared {
+            0 => 0,
+            shared => self.prefixes[&prefixes[shared - 1]],
+        };
+        if self.branches.is_empty() {
+            self.branches.push(Branch {
+                parent: None,
+                forked_at: 0,
+                len: 0,
+                requests: Vec::new(),
+            });
+        }
+
+        // Resending an earlier state, or carrying on from the branch's tip
+        let branch = if shared == messages.len() || self.branches[owner].len == shared {
+            owner
+        } else {
+            self.branches.push(Branch {
+                parent: Some(owner),
+                forked_at: shared,
+                len: shared,
+                requests: Vec::new(),
+            });
+            self.branches.len() - 1
+        };
+        for prefix in &prefixes[shared..] {
+            self.prefixes.insert(*prefix, branch);
+        }
+        let entry = &mut self.branches[branch];
+        entry.len = entry.len.max(messages.len());
+        entry.requests.push(self.requests);
+        self.requests += 1;
+        branch
+    }
+
+    pub fn branches(&self) -> &[Branch] {
+        &self.branches
+    }
+
+    /// Rows from `row` for each request, branches after their parent's
+    /// requests as an ASCII tree. With a single branch this is just the rows.
+    pub fn render(&self, row: impl Fn(usize) -> String) -> String {
+        let mut children = vec![Vec::new(); self.branches.len()];
+        for (index, branch) in self.branches.iter().enumerate() {
+            if let Some(parent) = branch.parent {
+                children[parent].push(index);
+            }
+        }
+        let mut out = String::new();
+        if !self.branches.is_empty() {
+            self.render_branch(0, "", &children, &row, &mut out);
+        }
+        out
+    }
+
+    fn render_branch(
+        &self,
+        branch: usize,
+        indent: &str,
+        children: &[Vec<usize>],
+        row: &impl Fn(usize) -> String,
+        out: &mut String,
+    ) {
+        for request in &self.branches[branch].requests {
+            out.push_str(&format!("{}{}\n", indent, row(*request)));
+        }
+        for (i, child) in children[branch].iter().enumerate() {
+            let last = i + 1 == children[branch].len();
+            out.push_str(&format!(
+                "{}{}forked at message {}\n",
+                indent,
+                if last { "└─ " } else { "├─ " },
+                self.branches[*child].forked_at + 1
+            ));
+            let indent = format!("{}{}", indent, if last { "   " } else { "│  " });
+            self.render_branch(*child, &indent, children, row, out);
+        }
+    }
+}
+
+/// An archived request as listed by `sherlock conversations`
+#[derive(Debug, Clone, Serialize)]
+pub struct ArchivedRequest {
+    /// Archive file name without the extension
+    pub file: String,
+    pub timestamp: DateTime<Utc>,
+    pub messages: usize,
+    pub tokens: usize,
+}
+
+/// Archived requests of one conversation and their branches
+#[derive(Debug, Serialize)]
+pub struct ConversationView {
+    pub label: String,
+    pub provider: String,
+    pub model: String,
+    pub requests: Vec<ArchivedRequest>,
+    /// Indexes into `requests`, the first branch being the original
+    pub branches: Vec<Branch>,
+    /// The largest request, against the model's context window
+    pub peak: Peak,
+    #[serde(skip)]
+    tree: ConversationTree,
+}
+
+impl ConversationView {
+    fn new(event: &RequestEvent, label: String) -> Self {
+        Self {
+            label,
+            provider: event.provider.clone(),
+            model: event.model.clone(),
+            requests: Vec::new(),
+            branches: Vec::new(),
+            peak: Peak::new(0, &event.model),
+            tree: ConversationTree::default(),
+        }
+    }
+
+    fn add(&mut self, file: String, event: &RequestEvent) {
+        self.tree.insert(&message_hashes(event).1);
+        self.requests.push(ArchivedRequest {
+            file,
+            timestamp: event.timestamp,
+            messages: event.messages.len(),
+            tokens: event.tokens,
+        });
+        if event.tokens as u64 > self.peak.tokens {
+            self.peak = Peak::of(event);
+        }
+    }
+
+    fn row(&self, index: usize) -> String {
+        let request = &self.requests[index];
+        format!(
+            "{}  {:>3} msg  {:>7} tok  {}",
+            request.timestamp.format("%Y-%m-%d %H:%M:%S"),
+            request.messages,
+            request.tokens,
+            request.file
+        )
+    }
+}
+
+impl fmt::Display for ConversationView {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "\"{}\"  {} {}, {} requests, {}",
+            self.label,
+            self.provider,
+            self.model,
+            self.requests.len(),
+            self.peak
+        )?;
+        if self.branches.len() > 1 {
+            write!(f, ", {} branches", self.branches.len())?;
+        }
+        writeln!(f)?;
+        let rows = self.tree.render(|index| self.row(index));
+        for line in rows.lines() {
+            writeln!(f, "  {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Conversations among the newest `last` archived requests, most recently
+/// active first
+pub fn archived_conversations(dir: &Path, last: usize) -> Result<Vec<ConversationView>> {
+    let mut paths = archived_requests(dir)?;
+    paths.truncate(last);
+    let mut views: Vec<ConversationView> = Vec::new();
+    let mut keys: HashMap<u64, usize> = HashMap::new();
+    for path in paths.iter().rev() {
+        let event = match load_archived_request(path) {
+            Ok(event) => event,
+            Err(e) => {
+                tracing::warn!("Skipping {:?}: {:#}", path, e);
+                continue;
+            }
+        };
+        let Some((key, label)) = conversation(&event) else {
+            continue;
+        };
+        let index = *keys.entry(key).or_insert_with(|| {
+            views.push(ConversationView::new(&event, label));
+            views.len() - 1
+        });
+        let file = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        views[index].add(file, &event);
+    }
+    for view in &mut views {
+        view.branches = view.tree.branches().to_vec();
+    }
+    views.sort_by_key(|view| std::cmp::Reverse(view.requests.last().map(|r| r.timestamp)));
+    Ok(views)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(requests: &[&[u64]]) -> ConversationTree {
+        let mut tree = ConversationTree::default();
+        for messages in requests {
+            tree.insert(messages);
+        }
+        tree
+    }
+
+    #[test]
+    fn test_no_fork_is_linear() {
+        let tree = tree(&[&[1], &[1, 2, 3], &[1, 2, 3], &[1, 2, 3, 4, 5]]);
+        assert_eq!(tree.branches().len(), 1);
+        assert_eq!(tree.branches()[0].len, 5);
+        let linear: String = (0..4).map(|i| format!("request {}\n", i)).collect();
+        assert_eq!(tree.render(|i| format!("request {}", i)), linear);
+    }
+
+    #[test]
+    fn test_multi_level_forks() {
+        let tree = tree(&[
+            &[1, 2, 3],
+            &[1, 2, 3, 4, 5],
+            // Third message edited
+            &[1, 2, 9],
+            &[1, 2, 9, 4, 5],
+            // First answer edited on the original branch
+            &[1, 2, 3, 4, 7],
+            // The edited branch forks again
+            &[1, 2, 9, 4, 8],
+            // An earlier state resent stays where it was
+            &[1, 2],
+        ]);
+        let branches = tree.branches();
+        assert_eq!(branches.len(), 4);
+        assert_eq!(branches[0].requests, vec![0, 1, 6]);
+        assert_eq!(
+            (
+                branches[1].parent,
+                branches[1].forked_at,
+                &branches[1].requests
+            ),
+            (Some(0), 2, &vec![2, 3])
+        );
+        assert_eq!((branches[2].parent, branches[2].forked_at), (Some(0), 4));
+        assert_eq!((branches[3].parent, branches[3].forked_at), (Some(1), 4));
+        assert_eq!(
+            tree.render(|i| format!("request {}", i)),
+            "request 0\nrequest 1\nrequest 6\n\
+             ├─ forked at message 3\n\
+             │  request 2\n\
+             │  request 3\n\
+             │  └─ forked at message 5\n\
+             │     request 5\n\
+             └─ forked at message 5\n   request 4\n"
+        );
+    }
+}
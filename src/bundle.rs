@@ -0,0 +1,670 @@
+//! `sherlock bundle`: one `.tar.gz` with what it takes to look into a bug
+//! report, i.e. the effective config, the build, recent archived traffic and
+//! the state files under `~/.sherlock`. Prompt text is replaced with
+//! pseudonyms unless asked otherwise, and API keys are redacted either way.
+//!
+//! The traffic goes in as `events.jsonl` in the `sherlock record` format, so
+//! `sherlock view --file report.tar.gz` replays it like a recording.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::archive::{archived_requests, format_bytes, load_archived_request, MARKERS_FILE};
+use crate::config::{Config, RedactionConfig};
+use crate::event::{InFlightRequest, Marker, RequestEvent};
+use crate::index::read_index;
+use crate::record::Entry;
+use crate::redact::Redactor;
+use crate::replay::{parse_events, Step};
+
+/// Layout of the bundle. Bump it when an older `--inspect` could no longer
+/// read what is written.
+pub const BUNDLE_FORMAT: u32 = 1;
+
+const MANIFEST: &str = "manifest.json";
+const CONFIG: &str = "config.json";
+const EVENTS: &str = "events.jsonl";
+
+/// Files under `~/.sherlock` copied into `state/` when present. The key salt
+/// is left out: with it, fingerprints could be checked against known keys.
+const STATE_FILES: [&str; 3] = [
+    crate::models::MODELS_FILE,
+    crate::update::CACHE_FILE,
+    crate::instance::LOCK_FILE,
+];
+
+/// Body fields whose strings say how a request is built rather than what it
+/// says, so they stay when the rest is pseudonymized
+const KEPT_FIELDS: [&str; 7] = [
+    "model",
+    "role",
+    "type",
+    "name",
+    "id",
+    "tool_use_id",
+    "stop_reason",
+];
+
+/// Options for `sherlock bundle`
+pub struct BundleOptions {
+    pub out: PathBuf,
+    /// How far back archived traffic is included
+    pub last: Duration,
+    pub anonymize: bool,
+    /// Replace an existing file at `out`
+    pub force: bool,
+}
+
+/// `manifest.json`: what the bundle holds and what wrote it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format: u32,
+    pub version: String,
+    /// e.g. "linux-x86_64"
+    pub target: String,
+    pub created_at: DateTime<Utc>,
+    pub anonymized: bool,
+    /// Start of the archived traffic included
+    pub since: DateTime<Utc>,
+    pub requests: usize,
+    pub failures: usize,
+    pub markers: usize,
+}
+
+/// Write a bundle of `config`, the archive's traffic over `options.last` and
+/// the state files in `sherlock_dir`
+pub fn create(config: &Config, sherlock_dir: &Path, options: &BundleOptions) -> Result<Manifest> {
+    if options.out.exists() && !options.force {
+        anyhow::bail!(
+            "{} already exists; pass --force to replace it",
+            options.out.display()
+        );
+    }
+    let created_at = Utc::now();
+    let since = created_at - options.last;
+    let redactor = Redactor::new(&RedactionConfig {
+        enabled: true,
+        ..config.redaction.clone()
+    })?;
+    let pseudonyms = options.anonymize.then(|| Pseudonymizer {
+        salt: created_at.timestamp_nanos_opt().unwrap_or_default() as u64,
+    });
+
+    let mut entries = collect(&config.archive.directory, since)?;
+    entries.sort_by_key(|entry| entry.order_key());
+    let mut manifest = Manifest {
+        format: BUNDLE_FORMAT,
+        version: crate::update::CURRENT.to_string(),
+        target: format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH),
+        created_at,
+        anonymized: options.anonymize,
+        since,
+        requests: 0,
+        failures: 0,
+        markers: 0,
+    };
+    let mut events = String::new();
+    for (index, mut entry) in entries.into_iter().enumerate() {
+        match &mut entry {
+            Entry::Completed(event) => {
+                manifest.requests += 1;
+                redactor.redact_event(event);
+                if let Some(pseudonyms) = &pseudonyms {
+                    pseudonyms.event(event);
+                }
+            }
+            Entry::Failed { error, .. } => {
+                manifest.failures += 1;
+                *error = redactor.redact(error).into_owned();
+            }
+            Entry::Marker(marker) => {
+                manifest.markers += 1;
+                marker.label = redactor.redact(&marker.label).into_owned();
+                if let Some(pseudonyms) = &pseudonyms {
+                    pseudonyms.marker(marker);
+                }
+            }
+        }
+        events.push_str(&serde_json::to_string(&entry.line(index + 1, None)?)?);
+        events.push('\n');
+    }
+
+    let mut files = vec![
+        (MANIFEST.to_string(), serde_json::to_vec_pretty(&manifest)?),
+        (
+            CONFIG.to_string(),
+            serde_json::to_vec_pretty(&stripped_config(config, &redactor)?)?,
+        ),
+        (EVENTS.to_string(), events.into_bytes()),
+    ];
+    for name in STATE_FILES {
+        if let Ok(content) = std::fs::read(sherlock_dir.join(name)) {
+            files.push((format!("state/{}", name), content));
+        }
+    }
+    write_archive(&options.out, &files, created_at)?;
+    Ok(manifest)
+}
+
+/// Archived requests from `since` on, and the failures and markers logged since
+fn collect(dir: &Path, since: DateTime<Utc>) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    if !dir.exists() {
+        return Ok(entries);
+    }
+    // Newest first, so the first one too old ends the window
+    for path in archived_requests(dir)? {
+        match load_archived_request(&path) {
+            Ok(event) if event.timestamp < since => break,
+            Ok(event) => entries.push(Entry::Completed(Box::new(event))),
+            Err(e) => tracing::warn!("Skipping {:?}: {:#}", path, e),
+        }
+    }
+    for entry in read_index(dir)? {
+        let Some(error) = entry.error else {
+            continue;
+        };
+        if entry.timestamp >= since {
+            let request = InFlightRequest {
+                id: entry.id,
+                provider: entry.provider,
+                model: entry.model,
+                started_at: entry.timestamp,
+                upload: None,
+            };
+            entries.push(Entry::Failed { request, error });
+        }
+    }
+    let markers = std::fs::read_to_string(dir.join(MARKERS_FILE)).unwrap_or_default();
+    entries.extend(
+        markers
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Marker>(line).ok())
+            .filter(|marker| marker.timestamp >= since)
+            .map(Entry::Marker),
+    );
+    Ok(entries)
+}
+
+/// `config` as JSON with keys redacted, and credentials and query strings
+/// taken out of URLs, e.g. a token in `update_url`
+fn stripped_config(config: &Config, redactor: &Redactor) -> Result<Value> {
+    let mut value = serde_json::to_value(config)?;
+    strip_secrets(&mut value, redactor);
+    Ok(value)
+}
+
+fn strip_secrets(value: &mut Value, redactor: &Redactor) {
+    match value {
+        Value::String(text) => {
+            if let Ok(mut url) = reqwest::Url::parse(text) {
+                if url.has_host() && (url.password().is_some() || url.query().is_some()) {
+                    let _ = url.set_username("");
+                    let _ = url.set_password(None);
+                    url.set_query(None);
+                    *text = url.to_string();
+                }
+            }
+            *text = redactor.redact(text).into_owned();
+        }
+        Value::Array(items) => items
+            .iter_mut()
+            .for_each(|item| strip_secrets(item, redactor)),
+        Value::Object(fields) => fields
+            .values_mut()
+            .for_each(|field| strip_secrets(field, redactor)),
+        _ => {}
+    }
+}
+
+/// Stands in for prompt text. The same text gets the same pseudonym within
+/// a bundle, so conversations still group and repeats still show, but the
+/// salt differs per bundle so pseudonyms can't be matched across reports.
+struct Pseudonymizer {
+    salt: u64,
+}
+
+impl Pseudonymizer {
+    /// e.g. "[text 1f3a9c02, 48 chars]"; empty text stays empty
+    fn text(&self, text: &str) -> String {
+        if text.is_empty() {
+            return String::new();
+        }
+        let mut hasher = DefaultHasher::new();
+        self.salt.hash(&mut hasher);
+        text.hash(&mut hasher);
+        format!(
+            "[text {:08x}, {} chars]",
+            hasher.finish() as u32,
+            text.chars().count()
+        )
+    }
+
+    /// Replace what the event says, keeping its shape: roles, tool names,
+    /// models, sizes and timings
+    fn event(&self, event: &mut RequestEvent) {
+        for message in &mut event.messages {
+            message.content = match message.tool_name() {
+                Some(name) => {
+                    let arguments = &message.content[name.len()..];
+                    let arguments = arguments
+                        .strip_prefix('(')
+                        .and_then(|a| a.strip_suffix(')'))
+                        .unwrap_or(arguments);
+                    format!("{}({})", name, self.text(arguments))
+                }
+                None => self.text(&message.content),
+            };
+            for part in &mut message.unknown_parts {
+                self.value(&mut part.json, false);
+            }
+        }
+        self.value(&mut event.raw_body, false);
+        if let Some(key) = &mut event.key {
+            key.last4.clear();
+        }
+        if let Some(repo) = &mut event.repo {
+            repo.root = PathBuf::from(self.text(&repo.root.to_string_lossy()));
+            repo.branch = repo.branch.as_deref().map(|branch| self.text(branch));
+            repo.head = None;
+        }
+        event.overlay = None;
+        event.cached_content = event.cached_content.as_deref().map(|c| self.text(c));
+    }
+
+    fn marker(&self, marker: &mut Marker) {
+        marker.label = self.text(&marker.label);
+        marker.session = marker.session.as_deref().map(|session| self.text(session));
+    }
+
+    /// Every string in `value` but those of `KEPT_FIELDS`
+    fn value(&self, value: &mut Value, kept: bool) {
+        match value {
+            Value::String(text) if !kept => *text = self.text(text),
+            Value::Array(items) => items.iter_mut().for_each(|item| self.value(item, kept)),
+            Value::Object(fields) => {
+                for (name, field) in fields.iter_mut() {
+                    self.value(field, KEPT_FIELDS.contains(&name.as_str()));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn write_archive(out: &Path, files: &[(String, Vec<u8>)], mtime: DateTime<Utc>) -> Result<()> {
+    let file = std::fs::File::create(out)
+        .with_context(|| format!("Failed to create {}", out.display()))?;
+    let mut tar = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+    for (name, content) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(mtime.timestamp().max(0) as u64);
+        header.set_cksum();
+        tar.append_data(&mut header, name, content.as_slice())?;
+    }
+    tar.into_inner()?.finish()?;
+    Ok(())
+}
+
+/// Every file in the bundle at `path`, by name
+fn read_archive(path: &Path) -> Result<BTreeMap<String, Vec<u8>>> {
+    let file =
+        std::fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut tar = tar::Archive::new(GzDecoder::new(file));
+    let mut files = BTreeMap::new();
+    for entry in tar
+        .entries()
+        .with_context(|| format!("{} is not a .tar.gz", path.display()))?
+    {
+        let mut entry = entry.with_context(|| format!("{} is not a .tar.gz", path.display()))?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content)?;
+        files.insert(name, content);
+    }
+    Ok(files)
+}
+
+/// Whether `path` names a bundle rather than a recording's `events.jsonl`
+pub fn is_bundle(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    name.ends_with(".tar.gz") || name.ends_with(".tgz")
+}
+
+/// A checked bundle, as `sherlock bundle --inspect` reports it
+#[derive(Debug)]
+pub struct Inspection {
+    pub path: PathBuf,
+    pub manifest: Manifest,
+    /// Every file with its size, by name
+    pub files: BTreeMap<String, u64>,
+}
+
+/// Read the bundle at `path`, checking that its manifest, config and events
+/// are there, parse, and agree with each other
+pub fn inspect(path: &Path) -> Result<Inspection> {
+    let files = read_archive(path)?;
+    let manifest = manifest(path, &files)?;
+    let config = files
+        .get(CONFIG)
+        .with_context(|| format!("{} has no {}", path.display(), CONFIG))?;
+    serde_json::from_slice::<Value>(config)
+        .with_context(|| format!("{} in {} is not JSON", CONFIG, path.display()))?;
+    let steps = events(path, &files)?;
+    if steps.len() != manifest.requests + manifest.failures + manifest.markers {
+        anyhow::bail!(
+            "{} holds {} events, but its manifest lists {}",
+            path.display(),
+            steps.len(),
+            manifest.requests + manifest.failures + manifest.markers
+        );
+    }
+    Ok(Inspection {
+        path: path.to_path_buf(),
+        manifest,
+        files: files
+            .iter()
+            .map(|(name, content)| (name.clone(), content.len() as u64))
+            .collect(),
+    })
+}
+
+/// The bundle's traffic as replay steps, for `sherlock view`
+pub fn steps(path: &Path) -> Result<Vec<Step>> {
+    let files = read_archive(path)?;
+    manifest(path, &files)?;
+    events(path, &files)
+}
+
+fn manifest(path: &Path, files: &BTreeMap<String, Vec<u8>>) -> Result<Manifest> {
+    let content = files.get(MANIFEST).with_context(|| {
+        format!(
+            "{} is not a sherlock bundle: no {}",
+            path.display(),
+            MANIFEST
+        )
+    })?;
+    let manifest: Manifest = serde_json::from_slice(content)
+        .with_context(|| format!("{} in {} is invalid", MANIFEST, path.display()))?;
+    if manifest.format > BUNDLE_FORMAT {
+        anyhow::bail!(
+            "{} is bundle format {}, newer than this sherlock reads ({}); upgrade to open it",
+            path.display(),
+            manifest.format,
+            BUNDLE_FORMAT
+        );
+    }
+    Ok(manifest)
+}
+
+fn events(path: &Path, files: &BTreeMap<String, Vec<u8>>) -> Result<Vec<Step>> {
+    let content = files
+        .get(EVENTS)
+        .with_context(|| format!("{} has no {}", path.display(), EVENTS))?;
+    let content = std::str::from_utf8(content)
+        .with_context(|| format!("{} in {} is not UTF-8", EVENTS, path.display()))?;
+    parse_events(content, &format!("{}:{}", path.display(), EVENTS))
+}
+
+/// e.g.
+/// ```text
+/// report.tar.gz: sherlock 0.6.0 on linux-x86_64, created 2024-06-01 12:00 UTC
+///   anonymized; traffic since 2024-06-01 10:00 UTC
+///   12 requests, 1 failures, 2 markers
+///   config.json                 1.2 KB
+/// ```
+impl fmt::Display for Inspection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let manifest = &self.manifest;
+        writeln!(
+            f,
+            "{}: sherlock {} on {}, created {}",
+            self.path.display(),
+            manifest.version,
+            manifest.target,
+            manifest.created_at.format("%Y-%m-%d %H:%M UTC")
+        )?;
+        writeln!(
+            f,
+            "  {}; traffic since {}",
+            match manifest.anonymized {
+                true => "anonymized",
+                false => "not anonymized",
+            },
+            manifest.since.format("%Y-%m-%d %H:%M UTC")
+        )?;
+        writeln!(
+            f,
+            "  {} requests, {} failures, {} markers",
+            manifest.requests, manifest.failures, manifest.markers
+        )?;
+        for (name, size) in &self.files {
+            writeln!(f, "  {:<26} {:>10}", name, format_bytes(*size))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::{archive_writer, ArchiveEntry};
+    use crate::event::RequestFailure;
+    use crate::metrics::ArchiveMetrics;
+    use crate::parser::parse_request;
+    use std::sync::Arc;
+    use tokio::sync::mpsc;
+
+    const KEY: &str = "sk-ant-REDACTED";
+    const TOKEN: &str = "eyJhbGciOiJIUzI1NiJ9.c2VjcmV0LXNlc3Npb24";
+    const PROMPT: &str = "Summarize the Q3 acquisition memo for Globex";
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("sherlock-bundle-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// An archive and state directory full of things that must not leak:
+    /// a key and a bearer token pasted into prompts, an auth header kept in
+    /// body metadata, and prompt text
+    async fn secret_laden(dir: &Path) -> Config {
+        let mut config = Config::default();
+        config.archive.directory = dir.join("prompts");
+        config.redaction.enabled = false;
+        config.update_url = format!(
+            "https://user:{}@updates.example.com/latest?token={}",
+            KEY, TOKEN
+        );
+
+        let body = serde_json::json!({
+            "model": "claude-sonnet-4-5",
+            "metadata": {"headers": {"authorization": format!("Bearer {}", TOKEN)}},
+            "system": format!("Deploy with {}", KEY),
+            "messages": [
+                {"role": "user", "content": PROMPT},
+                {"role": "assistant", "content": [
+                    {"type": "tool_use", "id": "toolu_1", "name": "Read",
+                     "input": {"file_path": "/home/alice/globex/memo.txt"}}
+                ]},
+                {"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "toolu_1",
+                     "content": format!("x-api-key: {}", KEY)}
+                ]}
+            ]
+        });
+        let mut event =
+            parse_request(body.to_string().as_bytes(), "/v1/messages", "anthropic").unwrap();
+        event.timestamp = Utc::now() - chrono::TimeDelta::minutes(5);
+        event.id = 7;
+        let mut old = event.clone();
+        old.timestamp = Utc::now() - chrono::TimeDelta::days(3);
+        old.id = 3;
+        let failure = RequestFailure {
+            timestamp: Utc::now() - chrono::TimeDelta::minutes(4),
+            id: 8,
+            provider: "anthropic".to_string(),
+            model: Some("claude-sonnet-4-5".to_string()),
+            latency_ms: 120,
+            error: format!("401 for key {}", KEY),
+        };
+        let marker = Marker {
+            label: "globex memo".to_string(),
+            timestamp: Utc::now() - chrono::TimeDelta::minutes(6),
+            tag: None,
+            session: Some("globex".to_string()),
+        };
+
+        let (tx, rx) = mpsc::channel(10);
+        let writer = tokio::spawn(archive_writer(
+            rx,
+            config.archive.clone(),
+            Arc::new(Redactor::new(&config.redaction).unwrap()),
+            Arc::new(ArchiveMetrics::default()),
+            None,
+        ));
+        for entry in [
+            ArchiveEntry::Request(Box::new(old)),
+            ArchiveEntry::Request(Box::new(event)),
+            ArchiveEntry::Failure(failure),
+            ArchiveEntry::Marker(marker),
+        ] {
+            tx.send(entry).await.unwrap();
+        }
+        drop(tx);
+        writer.await.unwrap().unwrap();
+
+        std::fs::write(dir.join("models.json"), "{}").unwrap();
+        std::fs::write(dir.join("key_salt"), "00112233").unwrap();
+        config
+    }
+
+    fn bundle_text(path: &Path) -> String {
+        read_archive(path)
+            .unwrap()
+            .values()
+            .map(|content| String::from_utf8_lossy(content).into_owned())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_anonymized_bundle_leaks_nothing() {
+        let dir = temp_dir("anonymized");
+        let config = secret_laden(&dir).await;
+        let out = dir.join("report.tar.gz");
+        let options = BundleOptions {
+            out: out.clone(),
+            last: Duration::from_secs(2 * 60 * 60),
+            anonymize: true,
+            force: false,
+        };
+        let manifest = create(&config, &dir, &options).unwrap();
+        assert_eq!(
+            (manifest.requests, manifest.failures, manifest.markers),
+            (1, 1, 1)
+        );
+
+        let text = bundle_text(&out);
+        for secret in [KEY, TOKEN, PROMPT, "globex", "Globex", "alice", "00112233"] {
+            assert!(!text.contains(secret), "{} leaked", secret);
+        }
+        // The shape of the traffic is still there
+        assert!(text.contains("claude-sonnet-4-5"));
+        assert!(text.contains("\"tool_use_id\":\"toolu_1\""));
+        assert!(text.contains("https://updates.example.com/latest"));
+
+        let inspection = inspect(&out).unwrap();
+        assert!(inspection.manifest.anonymized);
+        assert_eq!(
+            inspection.files.keys().collect::<Vec<_>>(),
+            [CONFIG, EVENTS, MANIFEST, "state/models.json"]
+        );
+        assert!(inspection
+            .to_string()
+            .contains("  1 requests, 1 failures, 1 markers\n"));
+
+        // It replays like a recording
+        assert_eq!(steps(&out).unwrap().len(), 3);
+
+        // Refuses to overwrite
+        assert!(create(&config, &dir, &options).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_plain_bundle_still_redacts_keys() {
+        let dir = temp_dir("plain");
+        let config = secret_laden(&dir).await;
+        let out = dir.join("report.tgz");
+        let options = BundleOptions {
+            out: out.clone(),
+            last: Duration::from_secs(24 * 60 * 60),
+            anonymize: false,
+            force: false,
+        };
+        create(&config, &dir, &options).unwrap();
+
+        let text = bundle_text(&out);
+        assert!(text.contains(PROMPT));
+        for secret in [KEY, TOKEN, "00112233"] {
+            assert!(!text.contains(secret), "{} leaked", secret);
+        }
+        assert!(is_bundle(&out));
+        assert!(!inspect(&out).unwrap().manifest.anonymized);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_inspect_rejects_what_is_not_a_bundle() {
+        let dir = temp_dir("invalid");
+        let plain = dir.join("events.tar.gz");
+        std::fs::write(&plain, "not gzip").unwrap();
+        assert!(inspect(&plain).is_err());
+
+        let missing = dir.join("missing.tar.gz");
+        write_archive(
+            &missing,
+            &[(CONFIG.to_string(), b"{}".to_vec())],
+            Utc::now(),
+        )
+        .unwrap();
+        let error = inspect(&missing).unwrap_err().to_string();
+        assert!(error.contains("not a sherlock bundle"), "{}", error);
+
+        let newer = dir.join("newer.tar.gz");
+        let manifest = serde_json::json!({
+            "format": BUNDLE_FORMAT + 1, "version": "9.0.0", "target": "linux-x86_64",
+            "created_at": Utc::now(), "anonymized": true, "since": Utc::now(),
+            "requests": 0, "failures": 0, "markers": 0
+        });
+        write_archive(
+            &newer,
+            &[(MANIFEST.to_string(), manifest.to_string().into_bytes())],
+            Utc::now(),
+        )
+        .unwrap();
+        let error = inspect(&newer).unwrap_err().to_string();
+        assert!(
+            error.contains("newer than this sherlock reads"),
+            "{}",
+            error
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,459 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+use crate::event::RequestEvent;
+use crate::language;
+use crate::metrics::ProxyMetrics;
+use crate::parser::{count_tokens, detect_body_format};
+use crate::text::truncate;
+
+/// Anthropic won't cache a prefix shorter than this, so smaller gaps aren't worth a hint
+const MIN_CACHEABLE_TOKENS: usize = 1024;
+/// Conversations remembered at once; the least recently seen is dropped first
+const MAX_CONVERSATIONS: usize = 64;
+/// Part token counts remembered before the cache starts over
+const MAX_CACHED_COUNTS: usize = 20_000;
+const LABEL_WIDTH: usize = 40;
+
+/// One cacheable unit of a request body: a tool definition, system block or
+/// message content block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Part {
+    /// Hash of the part without its `cache_control`, so moving a breakpoint
+    /// doesn't change the prefix
+    hash: u64,
+    tokens: usize,
+    breakpoint: bool,
+}
+
+/// How much of a request repeats the previous request in its conversation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrefixReuse {
+    /// Tokens in the prefix shared with the previous request
+    pub reusable_tokens: usize,
+    /// Tokens of that prefix up to its last cache breakpoint
+    pub cached_tokens: usize,
+    /// Breakpoints anywhere in the request
+    pub breakpoints: usize,
+}
+
+impl PrefixReuse {
+    /// Reusable tokens sent at full price on every request
+    pub fn missed_tokens(&self) -> usize {
+        self.reusable_tokens - self.cached_tokens
+    }
+
+    /// One-line advice, when the gap is big enough to cache
+    pub fn hint(&self) -> Option<String> {
+        let missed = self.missed_tokens();
+        if missed < MIN_CACHEABLE_TOKENS {
+            return None;
+        }
+        let reason = if self.breakpoints == 0 {
+            "no cache breakpoints set".to_string()
+        } else {
+            format!(
+                "breakpoints cover ~{} of ~{} reused",
+                approx(self.cached_tokens),
+                approx(self.reusable_tokens)
+            )
+        };
+        Some(format!(
+            "potential cache savings ~{} tok/request — {}",
+            approx(missed),
+            reason
+        ))
+    }
+}
+
+/// Token counts rounded for hints, e.g. 38k
+fn approx(tokens: usize) -> String {
+    if tokens < 1000 {
+        tokens.to_string()
+    } else {
+        format!("{}k", (tokens + 500) / 1000)
+    }
+}
+
+/// Prefix reuse over a session
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct CacheSummary {
+    /// Follow-up requests compared against their conversation's previous one
+    pub requests: u64,
+    /// Those leaving at least `MIN_CACHEABLE_TOKENS` reusable tokens uncached
+    pub missed_requests: u64,
+    pub missed_tokens: u64,
+}
+
+impl CacheSummary {
+    pub fn record(&mut self, reuse: &PrefixReuse) {
+        self.requests += 1;
+        if reuse.hint().is_some() {
+            self.missed_requests += 1;
+            self.missed_tokens += reuse.missed_tokens() as u64;
+        }
+    }
+
+    /// Average uncached reusable tokens over the requests that missed
+    pub fn missed_per_request(&self) -> u64 {
+        self.missed_tokens
+            .checked_div(self.missed_requests)
+            .unwrap_or(0)
+    }
+
+    /// Short summary for the dashboard, once anything was missed
+    pub fn describe(&self) -> Option<String> {
+        (self.missed_requests > 0).then(|| {
+            format!(
+                "cache: ~{} tok/req uncached on {} of {} requests",
+                approx(self.missed_per_request() as usize),
+                self.missed_requests,
+                self.requests
+            )
+        })
+    }
+}
+
+/// Something kept per conversation, for the `MAX_CONVERSATIONS` seen most
+/// recently; the least recently seen is dropped first
+#[derive(Debug)]
+pub struct RecentConversations<T> {
+    /// Each value with when its conversation was last seen
+    entries: HashMap<u64, (T, u64)>,
+    seen: u64,
+}
+
+impl<T> Default for RecentConversations<T> {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+            seen: 0,
+        }
+    }
+}
+
+impl<T> RecentConversations<T> {
+    /// Set the value of conversation `key`, marking it seen, and return the
+    /// one it replaces
+    pub fn insert(&mut self, key: u64, value: T) -> Option<T> {
+        let previous = self.remove(key);
+        self.make_room();
+        self.seen += 1;
+        self.entries.insert(key, (value, self.seen));
+        previous
+    }
+
+    /// The value of conversation `key`, marked seen, inserting `default()`
+    /// when it has none
+    pub fn get_or_insert_with(&mut self, key: u64, default: impl FnOnce() -> T) -> &mut T {
+        if !self.entries.contains_key(&key) {
+            self.make_room();
+        }
+        self.seen += 1;
+        let entry = self.entries.entry(key).or_insert_with(|| (default(), 0));
+        entry.1 = self.seen;
+        &mut entry.0
+    }
+
+    pub fn get(&self, key: u64) -> Option<&T> {
+        self.entries.get(&key).map(|(value, _)| value)
+    }
+
+    pub fn remove(&mut self, key: u64) -> Option<T> {
+        self.entries.remove(&key).map(|(value, _)| value)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Drop the least recently seen conversation when one more wouldn't fit
+    fn make_room(&mut self) {
+        if self.entries.len() < MAX_CONVERSATIONS {
+            return;
+        }
+        let oldest = self
+            .entries
+            .iter()
+            .min_by_key(|(_, (_, last_seen))| *last_seen)
+            .map(|(key, _)| *key);
+        if let Some(oldest) = oldest {
+            self.entries.remove(&oldest);
+        }
+    }
+}
+
+/// Compares each request with the previous request in its conversation
+#[derive(Debug, Default)]
+pub struct PrefixTracker {
+    /// Parts of each conversation's previous request
+    conversations: RecentConversations<Vec<Part>>,
+    /// Token count per part hash, since most parts repeat request after request
+    token_counts: HashMap<u64, usize>,
+}
+
+impl PrefixTracker {
+    /// Record `event`, returning the conversation's label and prefix reuse
+    /// when an earlier request of the same conversation was seen
+    pub fn observe(&mut self, event: &RequestEvent) -> Option<(String, PrefixReuse)> {
+        if event.imported || event.self_test {
+            return None;
+        }
+        // Only Anthropic takes explicit breakpoints
+        if detect_body_format(&event.raw_body) != Some("anthropic") {
+            return None;
+        }
+        let (key, label) = conversation(event)?;
+        let parts = self.parts(&event.raw_body);
+
+        let previous = self.conversations.insert(key, parts.clone());
+        Some((label, prefix_reuse(&previous?, &parts)))
+    }
+
+    /// Parts in the order Anthropic builds its cache prefix: tools, system, messages
+    fn parts(&mut self, body: &Value) -> Vec<Part> {
+        if self.token_counts.len() > MAX_CACHED_COUNTS {
+            self.token_counts.clear();
+        }
+        let mut parts = Vec::new();
+        let blocks = |value: Option<&Value>| -> Vec<Value> {
+            match value {
+                Some(Value::Array(items)) => items.clone(),
+                Some(Value::String(text)) => {
+                    vec![serde_json::json!({"type": "text", "text": text})]
+                }
+                _ => vec![],
+            }
+        };
+
+        for tool in blocks(body.get("tools")) {
+            parts.push(self.part("tool", &tool));
+        }
+        for block in blocks(body.get("system")) {
+            parts.push(self.part("system", &block));
+        }
+        for message in body
+            .get("messages")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+        {
+            let role = message
+                .get("role")
+                .and_then(Value::as_str)
+                .unwrap_or("user");
+            for block in blocks(message.get("content")) {
+                parts.push(self.part(role, &block));
+            }
+        }
+        parts
+    }
+
+    fn part(&mut self, role: &str, block: &Value) -> Part {
+        let breakpoint = block.get("cache_control").is_some();
+        let mut stripped = block.clone();
+        if let Some(object) = stripped.as_object_mut() {
+            object.remove("cache_control");
+        }
+        let mut hasher = DefaultHasher::new();
+        role.hash(&mut hasher);
+        stripped.to_string().hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let tokens = *self.token_counts.entry(hash).or_insert_with(|| {
+            match stripped.get("text").and_then(Value::as_str) {
+                Some(text) => count_tokens(text),
+                None => count_tokens(&stripped.to_string()),
+            }
+        });
+        Part {
+            hash,
+            tokens,
+            breakpoint,
+        }
+    }
+}
+
+/// Requests belong together when they share provider, model and opening
+/// user message; returns the key and a label from that message
+pub fn conversation(event: &RequestEvent) -> Option<(u64, String)> {
+    let first = event.messages.iter().find(|m| m.role == "user")?;
+    let mut hasher = DefaultHasher::new();
+    (&event.provider, &event.model, &first.content).hash(&mut hasher);
+    let label = first
+        .content
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    Some((hasher.finish(), truncate(&label, LABEL_WIDTH)))
+}
+
+fn prefix_reuse(previous: &[Part], current: &[Part]) -> PrefixReuse {
+    let shared = previous
+        .iter()
+        .zip(current)
+        .take_while(|(a, b)| a.hash == b.hash)
+        .count();
+    let reusable_tokens = current[..shared].iter().map(|p| p.tokens).sum();
+    let cached_tokens = current[..shared]
+        .iter()
+        .rposition(|p| p.breakpoint)
+        .map_or(0, |last| current[..=last].iter().map(|p| p.tokens).sum());
+    PrefixReuse {
+        reusable_tokens,
+        cached_tokens,
+        breakpoints: current.iter().filter(|p| p.breakpoint).count(),
+    }
+}
+
+/// Analyze completed requests as they arrive, away from the proxy and the
+/// dashboard's render loop, and publish the results on `metrics`: prefix
+/// reuse and the session's language mix
+pub async fn run(mut rx: mpsc::Receiver<RequestEvent>, metrics: Arc<ProxyMetrics>) {
+    let mut tracker = PrefixTracker::default();
+    while let Some(event) = rx.recv().await {
+        let analyzed = tokio::task::spawn_blocking(move || {
+            let reuse = tracker.observe(&event);
+            let languages = (!event.self_test).then(|| language::classify(&event));
+            (tracker, reuse, languages)
+        })
+        .await;
+        let Ok((returned, reuse, languages)) = analyzed else {
+            tracing::warn!("Cache prefix analysis panicked; stopping it");
+            return;
+        };
+        tracker = returned;
+        if let Some((label, reuse)) = reuse {
+            metrics.record_prefix_reuse(&label, &reuse);
+        }
+        if let Some(languages) = languages {
+            metrics.record_languages(&languages);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_request;
+
+    fn event(body: Value) -> RequestEvent {
+        let body = serde_json::to_vec(&body).unwrap();
+        parse_request(&body, "/v1/messages", "anthropic").unwrap()
+    }
+
+    fn long_text(word: &str) -> String {
+        vec![word; 3000].join(" ")
+    }
+
+    #[test]
+    fn test_uncached_prefix_hint() {
+        let mut tracker = PrefixTracker::default();
+        let system = long_text("rules");
+        let first = serde_json::json!({
+            "model": "claude-3-5-sonnet",
+            "system": system,
+            "messages": [{"role": "user", "content": "fix the build"}]
+        });
+        assert!(tracker.observe(&event(first)).is_none());
+
+        let second = serde_json::json!({
+            "model": "claude-3-5-sonnet",
+            "system": system,
+            "messages": [
+                {"role": "user", "content": "fix the build"},
+                {"role": "assistant", "content": "Done"},
+                {"role": "user", "content": "now run the tests"}
+            ]
+        });
+        let (label, reuse) = tracker.observe(&event(second)).unwrap();
+        assert_eq!(label, "fix the build");
+        assert_eq!(reuse.cached_tokens, 0);
+        assert!(reuse.reusable_tokens > 3000);
+        let hint = reuse.hint().unwrap();
+        assert!(
+            hint.starts_with("potential cache savings ~3k tok/request"),
+            "{}",
+            hint
+        );
+        assert!(hint.ends_with("no cache breakpoints set"));
+
+        // Another conversation starts from scratch
+        let other = serde_json::json!({
+            "model": "claude-3-5-sonnet",
+            "system": system,
+            "messages": [{"role": "user", "content": "write docs"}]
+        });
+        assert!(tracker.observe(&event(other)).is_none());
+    }
+
+    #[test]
+    fn test_breakpoints_count_as_cached() {
+        let mut tracker = PrefixTracker::default();
+        let system = long_text("rules");
+        let body = |turns: Vec<Value>| {
+            serde_json::json!({
+                "model": "claude-3-5-sonnet",
+                "system": [{
+                    "type": "text",
+                    "text": system,
+                    "cache_control": {"type": "ephemeral"}
+                }],
+                "messages": turns
+            })
+        };
+        let opening = serde_json::json!({"role": "user", "content": "fix the build"});
+        tracker.observe(&event(body(vec![opening.clone()])));
+
+        let reply = serde_json::json!({"role": "assistant", "content": long_text("log")});
+        let (_, reuse) = tracker
+            .observe(&event(body(vec![opening.clone(), reply.clone()])))
+            .unwrap();
+        assert_eq!(reuse.breakpoints, 1);
+        assert!(reuse.cached_tokens >= 3000);
+        assert!(reuse.hint().is_none());
+
+        // The long reply is reused but sits after the only breakpoint
+        let next = serde_json::json!({"role": "user", "content": "and the tests"});
+        let (_, reuse) = tracker
+            .observe(&event(body(vec![opening, reply, next])))
+            .unwrap();
+        let hint = reuse.hint().unwrap();
+        assert!(
+            hint.ends_with("breakpoints cover ~3k of ~6k reused"),
+            "{}",
+            hint
+        );
+
+        let mut summary = CacheSummary::default();
+        summary.record(&reuse);
+        summary.record(&PrefixReuse {
+            reusable_tokens: 10,
+            cached_tokens: 0,
+            breakpoints: 0,
+        });
+        assert_eq!(summary.requests, 2);
+        assert_eq!(summary.missed_requests, 1);
+        assert_eq!(summary.missed_per_request(), reuse.missed_tokens() as u64);
+    }
+
+    #[test]
+    fn test_recent_conversations_drop_least_recently_seen() {
+        let mut recent = RecentConversations::default();
+        for key in 0..MAX_CONVERSATIONS as u64 {
+            recent.insert(key, key);
+        }
+        // Seeing the oldest again leaves the second oldest to go
+        *recent.get_or_insert_with(0, || 99) += 1;
+        recent.insert(1000, 1000);
+        assert_eq!(recent.get(0), Some(&1));
+        assert_eq!(recent.get(1), None);
+        assert_eq!(recent.insert(1000, 1001), Some(1000));
+        assert_eq!(recent.get(2), Some(&2));
+    }
+}
@@ -0,0 +1,281 @@
+//! Synthetic faults injected into proxied traffic under `sherlock start
+//! --enable-chaos`, for testing how clients cope with a misbehaving API:
+//! error statuses instead of forwarding, added latency, and event streams
+//! cut short. Requests are picked by a seeded generator, so the same seed
+//! and the same requests in the same order fault the same way.
+
+use anyhow::Result;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::config::{ChaosConfig, ChaosFault, ChaosRule};
+
+/// Response header naming the fault injected into a request
+pub const CHAOS_HEADER: &str = "x-sherlock-chaos";
+
+/// A fault picked for one request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Injected {
+    /// Answered with this status without forwarding
+    Status(u16),
+    /// Held this long before forwarding
+    Delay(Duration),
+    /// Response stream cut off after this many events
+    Truncate { after_events: usize },
+}
+
+impl fmt::Display for Injected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Injected::Status(status) => write!(f, "status {}", status),
+            Injected::Delay(delay) => write!(f, "delayed {:.1}s", delay.as_secs_f64()),
+            Injected::Truncate { after_events } => {
+                write!(f, "stream cut after {} events", after_events)
+            }
+        }
+    }
+}
+
+/// The chaos rules in effect, with the generator picking their requests
+#[derive(Debug)]
+pub struct Chaos {
+    rules: Vec<ChaosRule>,
+    seed: u64,
+    state: Mutex<u64>,
+}
+
+impl Chaos {
+    /// Rules from `config`, seeded by its seed or else a random one
+    pub fn new(config: &ChaosConfig) -> Result<Self> {
+        let seed = match config.seed {
+            Some(seed) => seed,
+            None => {
+                let mut bytes = [0; 8];
+                getrandom::getrandom(&mut bytes)
+                    .map_err(|e| anyhow::anyhow!("Failed to seed chaos rules: {}", e))?;
+                u64::from_le_bytes(bytes)
+            }
+        };
+        Ok(Self {
+            rules: config.rules.clone(),
+            seed,
+            state: Mutex::new(seed),
+        })
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// The rules in brief, e.g. "10% of anthropic: status 429; 5%: latency
+    /// 2000-5000ms"
+    pub fn describe(&self) -> String {
+        self.rules
+            .iter()
+            .map(|rule| {
+                let scope = match &rule.provider {
+                    Some(provider) => format!("{}% of {}", rule.percent, provider),
+                    None => format!("{}%", rule.percent),
+                };
+                let fault = match rule.fault {
+                    ChaosFault::Status { status } => format!("status {}", status),
+                    ChaosFault::Latency { min_ms, max_ms } => {
+                        format!("latency {}-{}ms", min_ms, max_ms)
+                    }
+                    ChaosFault::Truncate { after_events } => {
+                        format!("cut streams after {} events", after_events)
+                    }
+                };
+                format!("{}: {}", scope, fault)
+            })
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
+    /// The fault for a request to `provider`, if a rule fires. Each rule
+    /// that applies draws once, in order, and the first to fire wins;
+    /// truncation only applies to requests that stream.
+    pub fn pick(&self, provider: &str, streaming: bool) -> Option<Injected> {
+        let mut state = self.state.lock().unwrap();
+        for rule in &self.rules {
+            if rule.provider.as_deref().is_some_and(|p| p != provider) {
+                continue;
+            }
+            if matches!(rule.fault, ChaosFault::Truncate { .. }) && !streaming {
+                continue;
+            }
+            if unit(&mut state) * 100.0 >= rule.percent {
+                continue;
+            }
+            return Some(match rule.fault {
+                ChaosFault::Status { status } => Injected::Status(status),
+                ChaosFault::Latency { min_ms, max_ms } => {
+                    let span = (max_ms - min_ms) as f64;
+                    let ms = min_ms + (unit(&mut state) * span).round() as u64;
+                    Injected::Delay(Duration::from_millis(ms))
+                }
+                ChaosFault::Truncate { after_events } => Injected::Truncate { after_events },
+            });
+        }
+        None
+    }
+}
+
+/// Next draw in [0, 1) from a splitmix64 sequence
+fn unit(state: &mut u64) -> f64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Finds where a server-sent event stream reaches its nth event, across
+/// the chunks it arrives in
+#[derive(Debug)]
+pub struct EventCutter {
+    remaining: usize,
+    /// The last byte seen, carriage returns aside, ended a line
+    line_ended: bool,
+}
+
+impl EventCutter {
+    pub fn new(after_events: usize) -> Self {
+        Self {
+            remaining: after_events,
+            line_ended: false,
+        }
+    }
+
+    /// How much of `chunk` to relay before cutting, or `None` to relay it
+    /// all and keep going
+    pub fn cut(&mut self, chunk: &[u8]) -> Option<usize> {
+        if self.remaining == 0 {
+            return Some(0);
+        }
+        for (i, byte) in chunk.iter().enumerate() {
+            match byte {
+                b'\r' => {}
+                b'\n' if self.line_ended => {
+                    self.line_ended = false;
+                    self.remaining -= 1;
+                    if self.remaining == 0 {
+                        return Some(i + 1);
+                    }
+                }
+                b'\n' => self.line_ended = true,
+                _ => self.line_ended = false,
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chaos(seed: u64, rules: Vec<ChaosRule>) -> Chaos {
+        Chaos::new(&ChaosConfig {
+            seed: Some(seed),
+            rules,
+        })
+        .unwrap()
+    }
+
+    fn rule(provider: Option<&str>, percent: f64, fault: ChaosFault) -> ChaosRule {
+        ChaosRule {
+            provider: provider.map(str::to_string),
+            percent,
+            fault,
+        }
+    }
+
+    #[test]
+    fn test_seeded_picks_repeat() {
+        let rules = vec![
+            rule(Some("anthropic"), 10.0, ChaosFault::Status { status: 429 }),
+            rule(
+                None,
+                5.0,
+                ChaosFault::Latency {
+                    min_ms: 2000,
+                    max_ms: 5000,
+                },
+            ),
+        ];
+        let run = |seed| {
+            let chaos = chaos(seed, rules.clone());
+            (0..1000)
+                .map(|_| chaos.pick("anthropic", false))
+                .collect::<Vec<_>>()
+        };
+        let picks = run(42);
+        assert_eq!(picks, run(42));
+        assert_ne!(picks, run(43));
+
+        let statuses = picks
+            .iter()
+            .filter(|p| matches!(p, Some(Injected::Status(429))))
+            .count();
+        let delays: Vec<Duration> = picks
+            .iter()
+            .filter_map(|p| match p {
+                Some(Injected::Delay(delay)) => Some(*delay),
+                _ => None,
+            })
+            .collect();
+        // Roughly 10%, then 5% of the other 90%
+        assert!((70..=130).contains(&statuses), "{}", statuses);
+        assert!((25..=70).contains(&delays.len()), "{}", delays.len());
+        assert!(delays
+            .iter()
+            .all(|delay| (2000..=5000).contains(&(delay.as_millis() as u64))));
+    }
+
+    #[test]
+    fn test_rules_scoped_by_provider_and_streaming() {
+        let chaos = chaos(
+            1,
+            vec![
+                rule(Some("openai"), 100.0, ChaosFault::Status { status: 503 }),
+                rule(None, 100.0, ChaosFault::Truncate { after_events: 2 }),
+            ],
+        );
+        assert_eq!(chaos.pick("openai", true), Some(Injected::Status(503)));
+        assert_eq!(chaos.pick("anthropic", false), None);
+        assert_eq!(
+            chaos.pick("anthropic", true),
+            Some(Injected::Truncate { after_events: 2 })
+        );
+        assert_eq!(
+            chaos.describe(),
+            "100% of openai: status 503; 100%: cut streams after 2 events"
+        );
+        let never = self::chaos(1, vec![rule(None, 0.0, ChaosFault::Status { status: 500 })]);
+        assert!((0..100).all(|_| never.pick("anthropic", false).is_none()));
+    }
+
+    #[test]
+    fn test_injected_display() {
+        assert_eq!(Injected::Status(429).to_string(), "status 429");
+        let delay = Injected::Delay(Duration::from_millis(3240));
+        assert_eq!(delay.to_string(), "delayed 3.2s");
+        let cut = Injected::Truncate { after_events: 3 };
+        assert_eq!(cut.to_string(), "stream cut after 3 events");
+    }
+
+    #[test]
+    fn test_event_cutter() {
+        let mut cutter = EventCutter::new(2);
+        assert_eq!(cutter.cut(b"data: 1\n"), None);
+        assert_eq!(cutter.cut(b"\ndata: 2\r\n"), None);
+        assert_eq!(cutter.cut(b"\r\ndata: 3\n\n"), Some(2));
+
+        let mut cutter = EventCutter::new(1);
+        assert_eq!(cutter.cut(b"event: a\ndata: 1\n\nevent: b\n\n"), Some(18));
+        assert_eq!(EventCutter::new(0).cut(b"data: 1\n\n"), Some(0));
+    }
+}
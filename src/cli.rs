@@ -1,14 +1,29 @@
-use clap::{Parser, Subcommand};
+use chrono::NaiveDate;
+use clap::{Parser, Subcommand, ValueEnum};
 use std::path::PathBuf;
 
 #[derive(Parser)]
 #[command(name = "sherlock", about = "LLM traffic inspector and token usage tracker")]
 #[command(version, author)]
 pub struct Cli {
-    /// Path to config file
-    #[arg(short, long, default_value = "~/.sherlock/config.json")]
+    /// Path to config file, JSON or TOML by its extension. Without it,
+    /// ~/.sherlock/config.toml is read when there is no config.json.
+    #[arg(short, long, default_value = crate::config::DEFAULT_PATH)]
     pub config: PathBuf,
 
+    /// Upgrade an outdated config file to the current schema (keeps a .bak copy)
+    #[arg(long, global = true)]
+    pub migrate_config: bool,
+
+    /// Don't tag requests with the current git repository, branch and commit
+    #[arg(long, global = true)]
+    pub no_repo_info: bool,
+
+    /// Run tools against the configured port even when no proxy is listening,
+    /// rather than starting one for the session
+    #[arg(long, global = true)]
+    pub no_proxy: bool,
+
     #[command(subcommand)]
     pub command: Command,
 }
@@ -16,6 +31,10 @@ pub struct Cli {
 #[derive(Subcommand)]
 pub enum Command {
     /// Start the proxy server and dashboard
+    ///
+    /// On unix, a running instance also takes signals: SIGUSR1 flushes the
+    /// archive and logs a stats snapshot, SIGUSR2 pauses or resumes
+    /// archiving, and SIGHUP reloads the config file.
     Start {
         /// Override proxy port
         #[arg(short, long)]
@@ -24,6 +43,28 @@ pub enum Command {
         /// Override token limit for fuel gauge
         #[arg(short, long)]
         limit: Option<u64>,
+
+        /// Break down token distribution by git repository instead of API key
+        #[arg(long)]
+        by_repo: bool,
+
+        /// Start even if another instance is running, archiving to a subdirectory of its own
+        #[arg(long)]
+        force: bool,
+
+        /// Run without the dashboard, logging a line per request instead, e.g.
+        /// under a service manager
+        #[arg(long, alias = "no-dashboard")]
+        headless: bool,
+
+        /// Inject the faults the config's chaos rules describe into proxied traffic
+        #[arg(long)]
+        enable_chaos: bool,
+
+        /// Log how long each phase of every request takes, and print their
+        /// percentiles on shutdown
+        #[arg(long)]
+        trace_requests: bool,
     },
 
     /// Run Claude Code through the proxy
@@ -60,8 +101,447 @@ pub enum Command {
         #[arg(short = 'P', long)]
         provider: String,
 
+        /// Also point this variable at the proxy (repeatable)
+        #[arg(long = "env-var", value_name = "NAME")]
+        env_vars: Vec<String>,
+
         /// Command and arguments to run
         #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         command: Vec<String>,
     },
+
+    /// Run the proxy headlessly and save all traffic as a self-contained bundle
+    Record {
+        /// Output directory for the bundle
+        #[arg(short, long)]
+        out: PathBuf,
+
+        /// Stop after this long, e.g. 90s, 15m, 2h (default: until Ctrl-C)
+        #[arg(short, long, value_parser = crate::record::parse_duration)]
+        duration: Option<std::time::Duration>,
+
+        /// Name tagged on every captured request (default: the output directory name)
+        #[arg(short, long)]
+        name: Option<String>,
+
+        /// Override proxy port
+        #[arg(short, long)]
+        port: Option<u16>,
+
+        /// Record into a non-empty output directory
+        #[arg(long)]
+        force: bool,
+
+        /// Write only aggregate counts, tokens and timings; no message text
+        #[arg(long)]
+        aggregates_only: bool,
+
+        /// Group models into families (claude-sonnet, gpt-4o) in the aggregates
+        #[arg(long, requires = "aggregates_only")]
+        model_families: bool,
+
+        /// Replace repository and recording names with hashes in the aggregates
+        #[arg(long, requires = "aggregates_only")]
+        hash_names: bool,
+    },
+
+    /// Add a labeled marker to the running proxy's request timeline
+    Mark {
+        /// What you're doing, e.g. "started refactor"
+        label: String,
+
+        /// Short category shown next to the label
+        #[arg(short, long)]
+        tag: Option<String>,
+
+        /// Session the marker applies to (default: the current git repository)
+        #[arg(long)]
+        session: Option<String>,
+
+        /// Proxy port (default: the running instance's, else the configured one)
+        #[arg(short, long)]
+        port: Option<u16>,
+    },
+
+    /// Print a one-line summary of the running session for a status bar, e.g.
+    /// tmux's status-right, or of today's archived requests with none running
+    Statusline {
+        /// Proxy port (default: the running instance's)
+        #[arg(short, long)]
+        port: Option<u16>,
+    },
+
+    /// Run a request body through the parser and print what it extracted
+    Parse {
+        /// Provider whose request format to parse (anthropic, openai, gemini)
+        #[arg(short = 'P', long)]
+        provider: String,
+
+        /// Request path (defaults to the provider's configured path pattern)
+        #[arg(long)]
+        path: Option<String>,
+
+        /// Request body file (default: read stdin)
+        input: Option<PathBuf>,
+
+        /// Print machine-readable JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// List every model seen in traffic, with when it first appeared
+    Models {
+        /// Print machine-readable JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// List archived conversations, with edited and resent prompts as branches
+    Conversations {
+        /// Only read the newest N archived requests
+        #[arg(long, default_value_t = 1000)]
+        last: usize,
+
+        /// Print machine-readable JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Write a condensed summary of an archived conversation for resuming it in another tool
+    Handoff {
+        /// Archived request to hand off, as a path or archive file name
+        /// (default: the most recent conversation)
+        #[arg(long)]
+        conversation: Option<String>,
+
+        /// Output path (default: print to stdout)
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+
+        /// Token budget for the document (default: handoff.budget_tokens)
+        #[arg(long)]
+        budget: Option<usize>,
+
+        /// Summarize the middle of the session with handoff.summary_model
+        #[arg(long)]
+        llm: bool,
+    },
+
+    /// Add another tool's history to the prompt archive
+    Import {
+        /// What kind of history PATH holds
+        #[arg(short, long, value_enum)]
+        format: ImportFormat,
+
+        /// A file, or a directory searched recursively for matching files
+        path: PathBuf,
+    },
+
+    /// Inspect the prompt archive
+    Archive {
+        #[command(subcommand)]
+        command: ArchiveCommand,
+    },
+
+    /// Render the markdown of an archived request whose rendering is deferred
+    Render {
+        /// Request id, or an archive file name or path of the request
+        entry: String,
+    },
+
+    /// Summarize archived requests per provider from the archive index
+    Stats {
+        /// Show the share of requests answered successfully within slo.latency_ms,
+        /// error rates and the worst hour of the day
+        #[arg(long)]
+        reliability: bool,
+
+        /// Show approximate tokens per programming language, the rest as prose
+        #[arg(long, conflicts_with = "reliability")]
+        by_language: bool,
+
+        /// Read the archived request files instead of the index, for tokens
+        /// per provider, model and day and the largest requests
+        #[arg(long, conflicts_with_all = ["reliability", "by_language"])]
+        archive: bool,
+
+        /// Project today's and this month's cost from the index, going by
+        /// the hours of the day past requests came in
+        #[arg(long, conflicts_with_all = ["reliability", "by_language", "archive"])]
+        projection: bool,
+
+        /// Report today's usage against the daily goals and how many of the
+        /// last 30 days stayed under them
+        #[arg(
+            long,
+            conflicts_with_all = ["reliability", "by_language", "archive", "projection"]
+        )]
+        goals: bool,
+
+        /// Chart request tokens, output tokens and latency per power of two
+        /// under the summary
+        #[arg(
+            long,
+            conflicts_with_all = ["reliability", "by_language", "archive", "projection", "goals"]
+        )]
+        histogram: bool,
+
+        /// Show output tokens per second per model, for streamed responses
+        /// long enough to measure
+        #[arg(
+            long,
+            conflicts_with_all = [
+                "reliability",
+                "by_language",
+                "archive",
+                "projection",
+                "goals",
+                "histogram"
+            ]
+        )]
+        throughput: bool,
+
+        /// Print only aggregate counts, tokens, costs and timings as JSON,
+        /// safe to share
+        #[arg(
+            long,
+            conflicts_with_all = [
+                "reliability",
+                "by_language",
+                "archive",
+                "projection",
+                "goals",
+                "histogram",
+                "throughput"
+            ]
+        )]
+        aggregates_only: bool,
+
+        /// Group models into families (claude-sonnet, gpt-4o) in the aggregates
+        #[arg(long, requires = "aggregates_only")]
+        model_families: bool,
+
+        /// Replace the report's name with a hash in the aggregates
+        #[arg(long, requires = "aggregates_only")]
+        hash_names: bool,
+
+        /// Only count requests from this local day on, e.g. 2024-06-01
+        #[arg(long)]
+        since: Option<NaiveDate>,
+
+        /// Only count requests to this provider
+        #[arg(long)]
+        provider: Option<String>,
+
+        /// Only count requests matching a filter expression, as in the
+        /// dashboard; text and role: terms need --archive
+        #[arg(long)]
+        filter: Option<String>,
+
+        #[arg(long, value_enum, default_value_t = StatsFormat::Table)]
+        format: StatsFormat,
+
+        /// Print machine-readable JSON, the same as --format json
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Report how much archived content repeats across requests, and the
+    /// most repeated messages and tool definitions
+    DedupeReport {
+        /// Print machine-readable JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Answer ad-hoc questions from the archive index, e.g.
+    /// `--select "model, sum(tokens)" --where provider=anthropic --group-by model`
+    Query {
+        /// Columns: provider, model, served_model, error, date, tokens, status,
+        /// latency_ms, response_ms, or count(), sum, avg, min, max or p95 of a
+        /// numeric one
+        #[arg(long, default_value = "count()")]
+        select: String,
+
+        /// Filter expression, as in the dashboard (text terms aside)
+        #[arg(long = "where")]
+        filter: Option<String>,
+
+        /// Fields to total the aggregates per
+        #[arg(long)]
+        group_by: Option<String>,
+
+        /// Selected column to sort by, then asc or desc
+        #[arg(long)]
+        order_by: Option<String>,
+
+        /// Rows to print at most
+        #[arg(long)]
+        limit: Option<usize>,
+
+        #[arg(long, value_enum, default_value_t = QueryFormat::Table)]
+        format: QueryFormat,
+    },
+
+    /// Find text in the messages of archived requests, newest first
+    Search {
+        /// Text to find, whatever its case
+        text: String,
+
+        /// Only search requests matching a filter expression, as in the dashboard
+        #[arg(long)]
+        filter: Option<String>,
+
+        /// Matches to print at most
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+
+        /// Print machine-readable JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Write the archive index entries as JSON lines, one per request
+    Export {
+        /// Only export requests matching a filter expression, as in the
+        /// dashboard (text terms aside)
+        #[arg(long)]
+        filter: Option<String>,
+
+        /// Where to write them, instead of standard output
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Write only aggregate counts, tokens, costs and timings as JSON,
+        /// instead of the entries
+        #[arg(long)]
+        aggregates_only: bool,
+
+        /// Group models into families (claude-sonnet, gpt-4o) in the aggregates
+        #[arg(long, requires = "aggregates_only")]
+        model_families: bool,
+
+        /// Replace the report's name with a hash in the aggregates
+        #[arg(long, requires = "aggregates_only")]
+        hash_names: bool,
+    },
+
+    /// Step through an archived session in the dashboard, without starting the proxy
+    View {
+        /// Local day of the archive to replay, e.g. 2024-06-01 (default: today)
+        #[arg(long, conflicts_with = "file")]
+        date: Option<NaiveDate>,
+
+        /// events.jsonl from a `sherlock record` bundle, or a `sherlock bundle`
+        /// .tar.gz, to replay instead
+        #[arg(long)]
+        file: Option<PathBuf>,
+    },
+
+    /// Package the config, version, recent archived traffic and state files
+    /// into a .tar.gz to attach to a bug report
+    Bundle {
+        /// Where to write the bundle, e.g. report.tar.gz
+        #[arg(short, long, required_unless_present = "inspect")]
+        out: Option<PathBuf>,
+
+        /// How much archived traffic to include, e.g. 90m, 2h, 1d
+        #[arg(long, default_value = "24h", value_parser = crate::record::parse_duration)]
+        last: std::time::Duration,
+
+        /// Replace prompt text, repository names and marker labels with
+        /// pseudonyms (the default)
+        #[arg(long, conflicts_with = "no_anonymize")]
+        anonymize: bool,
+
+        /// Keep prompt text as archived; API keys are redacted regardless
+        #[arg(long)]
+        no_anonymize: bool,
+
+        /// Replace an existing file at --out
+        #[arg(long)]
+        force: bool,
+
+        /// Check a bundle and summarize what it holds, instead of writing one
+        #[arg(long, conflicts_with_all = ["out", "anonymize", "no_anonymize", "force"])]
+        inspect: Option<PathBuf>,
+    },
+
+    /// Export an archived JSON request as a standalone conversation file
+    ExportConversation {
+        /// Archived request body (the .json file in the prompt archive)
+        input: PathBuf,
+
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t = ExportFormat::Html)]
+        format: ExportFormat,
+
+        /// Output path (defaults to the input path with the format's extension)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Show each run of tool calls as one step with a table of the calls,
+        /// instead of a turn per call and result
+        #[arg(long)]
+        collapse_steps: bool,
+    },
+
+    /// Inspect the settings in effect
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Print the config a tool session started here would use, with the
+    /// settings the project's .sherlock.json or .sherlock.toml replaces
+    Show {
+        /// Print machine-readable JSON
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ArchiveCommand {
+    /// Show archive size, date range and index health
+    Status {
+        /// Print machine-readable JSON
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    Html,
+    Markdown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum QueryFormat {
+    /// Aligned columns
+    Table,
+    Csv,
+    /// An array with one object per row
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum StatsFormat {
+    /// Aligned columns
+    Table,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ImportFormat {
+    /// Claude Code session transcripts (~/.claude/projects/*/*.jsonl)
+    ClaudeCode,
+    /// OpenAI usage export CSV
+    OpenaiUsage,
+    /// events.jsonl from a `sherlock record` bundle
+    SherlockJsonl,
 }
@@ -1,28 +1,279 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
 use std::path::{Path, PathBuf};
 
+use crate::event::TokenComposition;
+use crate::parser::Encoding;
+use crate::policy::PolicyScanner;
+use crate::redact::Redactor;
+use crate::tls;
+
+/// Current config schema version. Bump together with a new entry in `MIGRATIONS`.
+pub const CONFIG_VERSION: u32 = 6;
+
+/// Config file read unless `--config` names another
+pub const DEFAULT_PATH: &str = "~/.sherlock/config.json";
+
+/// Read in place of `DEFAULT_PATH` when only it exists
+const DEFAULT_TOML_PATH: &str = "~/.sherlock/config.toml";
+
+/// Latest release as reported by GitHub, read by the opt-in update check
+const DEFAULT_UPDATE_URL: &str = "https://api.github.com/repos/Camil-H/sherlock/releases/latest";
+
+/// Environment variables read over the config file, see `Config::with_env`
+const ENV_PROXY_PORT: &str = "SHERLOCK_PROXY_PORT";
+const ENV_BIND_ADDRESS: &str = "SHERLOCK_BIND_ADDRESS";
+const ENV_TOKEN_LIMIT: &str = "SHERLOCK_TOKEN_LIMIT";
+const ENV_ARCHIVE_DIR: &str = "SHERLOCK_ARCHIVE_DIR";
+const ENV_ARCHIVE_ENABLED: &str = "SHERLOCK_ARCHIVE_ENABLED";
+
+/// Upgrades a raw config document by one schema version
+type Migration = fn(&mut Map<String, Value>);
+
+/// Schema migrations; `MIGRATIONS[n]` upgrades a version `n` document to `n + 1`
+const MIGRATIONS: &[Migration] = &[
+    // v0 -> v1: introduce the `version` field, no layout changes
+    |_| {},
+    // v1 -> v2: `archive.format` names become structured `archive.sinks`
+    |obj| {
+        let Some(archive) = obj.get_mut("archive").and_then(Value::as_object_mut) else {
+            return;
+        };
+        let Some(Value::Array(formats)) = archive.remove("format") else {
+            return;
+        };
+        let sinks = formats
+            .iter()
+            .filter_map(|format| match format.as_str() {
+                Some("markdown" | "md") => Some(serde_json::json!({"type": "markdown"})),
+                Some("json") => Some(serde_json::json!({"type": "json"})),
+                _ => {
+                    tracing::warn!("Dropping unknown archive format {}", format);
+                    None
+                }
+            })
+            .collect();
+        archive.entry("sinks").or_insert(Value::Array(sinks));
+    },
+    // v2 -> v3: the default OpenAI provider also takes Responses API requests
+    |obj| {
+        let Some(openai) = obj
+            .get_mut("providers")
+            .and_then(|providers| providers.get_mut("openai"))
+            .and_then(Value::as_object_mut)
+        else {
+            return;
+        };
+        if openai.get("path_pattern").and_then(Value::as_str) == Some(OPENAI_CHAT_PATH) {
+            openai
+                .entry("extra_path_patterns")
+                .or_insert(serde_json::json!([OPENAI_RESPONSES_PATH]));
+        }
+    },
+    // v3 -> v4: local Ollama joins the default providers
+    |obj| {
+        let Some(providers) = obj.get_mut("providers").and_then(Value::as_object_mut) else {
+            return;
+        };
+        if let Ok(ollama) = serde_json::to_value(ollama_provider()) {
+            providers.entry("ollama").or_insert(ollama);
+        }
+    },
+    // v4 -> v5: Azure OpenAI joins the default providers
+    |obj| {
+        let Some(providers) = obj.get_mut("providers").and_then(Value::as_object_mut) else {
+            return;
+        };
+        if let Ok(azure) = serde_json::to_value(azure_provider()) {
+            providers.entry("azure").or_insert(azure);
+        }
+    },
+    // v5 -> v6: `path_pattern` and `extra_path_patterns` become one `path_patterns` list
+    |obj| {
+        let Some(providers) = obj.get_mut("providers").and_then(Value::as_object_mut) else {
+            return;
+        };
+        for provider in providers.values_mut().filter_map(Value::as_object_mut) {
+            let mut patterns: Vec<Value> = provider.remove("path_pattern").into_iter().collect();
+            if let Some(Value::Array(extra)) = provider.remove("extra_path_patterns") {
+                patterns.extend(extra);
+            }
+            if !patterns.is_empty() {
+                provider
+                    .entry("path_patterns")
+                    .or_insert(Value::Array(patterns));
+            }
+        }
+    },
+];
+
+const OPENAI_CHAT_PATH: &str = "/v1/chat/completions";
+const OPENAI_RESPONSES_PATH: &str = "/v1/responses";
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
+    pub version: u32,
     pub proxy: ProxyConfig,
     pub dashboard: DashboardConfig,
+    #[serde(serialize_with = "serialize_sorted")]
     pub providers: HashMap<String, ProviderConfig>,
     pub archive: ArchiveConfig,
+    pub goals: GoalsConfig,
+    pub policy: PolicyConfig,
+    pub redaction: RedactionConfig,
+    pub handoff: HandoffConfig,
+    pub slo: SloConfig,
+    pub run: RunConfig,
+    /// Tools `sherlock start` launches once the proxy is listening
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub autostart: Vec<AutostartTool>,
+    /// Stop the autostarted tools when sherlock quits, instead of leaving
+    /// them running
+    pub autostart_kill_on_exit: bool,
+    /// Faults to inject into proxied traffic; only applied under
+    /// `sherlock start --enable-chaos`
+    #[serde(skip_serializing_if = "ChaosConfig::is_empty")]
+    pub chaos: ChaosConfig,
+    /// Program every request's messages pass through before the dashboard
+    /// or archive sees them; read at start only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scrubber: Option<ScrubberConfig>,
+    /// Dollar rates by model name prefix; the longest prefix of a model wins
+    pub pricing: PricingConfig,
+    /// Check at most once a day whether a newer release is out
+    pub update_check: bool,
+    /// Where the update check reads the latest version: plain text, or JSON
+    /// with a `tag_name` or `version` field
+    pub update_url: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ProxyConfig {
     pub port: u16,
     pub bind_address: String,
+    /// Connections beyond this many get a 503 instead of being served
+    pub max_connections: usize,
+    /// Forward requests on unknown paths to the one provider whose request
+    /// format the body has. Off by default: such requests are only recorded.
+    pub shape_based_routing: bool,
+    /// Provider that requests on paths no provider claims are passed through
+    /// to, unparsed and unrecorded, e.g. model listings and health checks.
+    /// Without one they get a 400.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_provider: Option<String>,
+    /// Round-trip a synthetic request through the proxy on `sherlock start`
+    pub self_test: bool,
+    /// Keep each request's credential headers in memory, never on disk, so
+    /// it can be resent from the request detail view
+    pub capture_auth_headers: bool,
+    /// Cap on request bytes sent upstream per second, shared by all
+    /// connections. Picked up from the config file without a restart.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_upload_bytes_per_sec: Option<u64>,
+    /// Cap on response bytes relayed to clients per second
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_download_bytes_per_sec: Option<u64>,
+    /// How long quitting waits for requests under way to finish
+    pub shutdown_grace_secs: u64,
+    /// Request bodies at least this large are read one message at a time
+    /// instead of as a whole, and only a shortened copy of them is kept.
+    /// `0` reads every body whole.
+    #[serde(deserialize_with = "zero_if_null")]
+    pub streaming_parse_bytes: usize,
+    /// Longest string a streamed body's kept copy holds in full; longer
+    /// ones are cut to this many bytes
+    pub streamed_body_max_string_bytes: usize,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct DashboardConfig {
     pub token_limit: u64,
     pub max_log_entries: usize,
     pub refresh_rate_hz: u32,
     pub prompt_preview_length: usize,
+    /// Drop in-flight requests that haven't completed after this long
+    pub in_flight_timeout_secs: u64,
+    pub layout: LayoutMode,
+    /// Show which API key each request used (toggle with 'k')
+    pub show_key_column: bool,
+    /// Show output tokens per second of streamed responses (toggle with 't')
+    pub show_throughput_column: bool,
+    /// Show how each request changed from the previous one in its
+    /// conversation, e.g. "+2 msg, +3.1k" (toggle with 'd')
+    pub show_change_column: bool,
+    /// Break down the distribution panel by git repository instead of API key
+    pub group_by_repo: bool,
+    /// What the context gauge counts (toggle with '1', '2' and '3')
+    pub token_scope: TokenScope,
+    /// Keep the terminal title on a summary such as "sherlock · 142k tok ·
+    /// $3.20" while the dashboard runs
+    pub set_terminal_title: bool,
+    /// Percents of a model's context window that raise a notice, once per
+    /// conversation, when its largest request so far reaches them
+    pub peak_alert_thresholds: Vec<u8>,
+}
+
+/// Token buckets counted toward the context gauge besides user and assistant
+/// messages. Stats and exports always report every bucket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TokenScope {
+    pub system: bool,
+    /// Tool definitions sent with each request
+    pub tools: bool,
+    pub tool_results: bool,
+}
+
+impl Default for TokenScope {
+    fn default() -> Self {
+        Self {
+            system: true,
+            tools: true,
+            tool_results: true,
+        }
+    }
+}
+
+impl TokenScope {
+    /// The part of `composition` this scope counts
+    pub fn counted(&self, composition: &TokenComposition) -> u64 {
+        composition.conversation
+            + if self.system { composition.system } else { 0 }
+            + if self.tools { composition.tools } else { 0 }
+            + if self.tool_results { composition.tool_results } else { 0 }
+    }
+
+    /// e.g. "user+assistant+system", or "user+assistant only"
+    pub fn label(&self) -> String {
+        let included: Vec<&str> = [
+            (self.system, "system"),
+            (self.tools, "tools"),
+            (self.tool_results, "tool results"),
+        ]
+        .into_iter()
+        .filter_map(|(on, name)| on.then_some(name))
+        .collect();
+        if included.is_empty() {
+            "user+assistant only".to_string()
+        } else {
+            format!("user+assistant+{}", included.join("+"))
+        }
+    }
+}
+
+/// Dashboard layout selection; `auto` switches to compact in short terminals
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LayoutMode {
+    #[default]
+    Auto,
+    Full,
+    Compact,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -30,14 +281,718 @@ pub struct ProviderConfig {
     pub host: String,
     pub base_url: String,
     pub env_vars: Vec<String>,
-    pub path_pattern: String,
+    /// Requests whose path contains one of these reach this provider. The
+    /// first is the provider's main endpoint. A single string, as older
+    /// configs have under `path_pattern`, is read as a list of one.
+    #[serde(alias = "path_pattern", deserialize_with = "one_or_more")]
+    pub path_patterns: Vec<String>,
+    /// Request body format (anthropic, openai, gemini) for shape-based
+    /// routing; defaults to the provider name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    /// Providers to retry against, in order, when this one fails
+    #[serde(default)]
+    pub fallbacks: Vec<String>,
+    /// Upstream statuses that move on to the next fallback (connect errors always do)
+    #[serde(default = "default_failover_statuses")]
+    pub failover_statuses: Vec<u16>,
+    /// Trust and client certificate settings for the upstream connection
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsConfig>,
+    /// Encoding for token estimates, e.g. "o200k_base", instead of the one
+    /// the request's model uses
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokenizer: Option<Encoding>,
+    /// Model behind each deployment named in the request path, for Azure
+    /// OpenAI; an unlisted deployment is reported under its own name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub deployment_models: BTreeMap<String, String>,
+    /// Environment variable holding a key for requests that arrive without
+    /// one, such as those resent from the request detail view
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key_env: Option<String>,
+}
+
+impl ProviderConfig {
+    pub fn matches_path(&self, path: &str) -> bool {
+        self.path_match(path).is_some()
+    }
+
+    /// Length of the longest pattern `path` contains, so the most specific
+    /// of several matching providers can win
+    pub fn path_match(&self, path: &str) -> Option<usize> {
+        self.path_patterns
+            .iter()
+            .filter(|pattern| path.contains(pattern.as_str()))
+            .map(String::len)
+            .max()
+    }
+
+    /// Path a request body is taken to be for when none is given
+    pub fn main_path(&self) -> &str {
+        self.path_patterns.first().map_or("/", String::as_str)
+    }
+
+    pub fn body_format<'a>(&'a self, name: &'a str) -> &'a str {
+        self.format.as_deref().unwrap_or(name)
+    }
+
+    /// Header carrying the key `var` finds under `api_key_env`, in the way
+    /// `name`'s request format expects it; unset when there is no such key
+    pub fn injected_auth(
+        &self,
+        name: &str,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Option<(&'static str, String)> {
+        let key = var(self.api_key_env.as_ref()?)?;
+        let key = key.trim();
+        if key.is_empty() {
+            return None;
+        }
+        Some(match self.body_format(name) {
+            "anthropic" => ("x-api-key", key.to_string()),
+            "gemini" => ("x-goog-api-key", key.to_string()),
+            _ if self.host.ends_with(".openai.azure.com") => ("api-key", key.to_string()),
+            _ => ("authorization", format!("Bearer {}", key)),
+        })
+    }
+}
+
+/// Upstream TLS settings for gateways with a private CA or mTLS
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// PEM bundle of extra root certificates to trust
+    pub ca_bundle: Option<PathBuf>,
+    /// PEM client certificate presented to the upstream, with `client_key`
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    /// Accept any upstream certificate. Only for debugging.
+    pub insecure_skip_verify: bool,
+}
+
+/// Write map entries by key so saved configs come out the same every run
+fn serialize_sorted<S, V>(map: &HashMap<String, V>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+    V: Serialize,
+{
+    map.iter().collect::<BTreeMap<_, _>>().serialize(serializer)
+}
+
+/// A list that may be written as a single string
+fn one_or_more<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMore {
+        One(String),
+        More(Vec<String>),
+    }
+    Ok(match OneOrMore::deserialize(deserializer)? {
+        OneOrMore::One(pattern) => vec![pattern],
+        OneOrMore::More(patterns) => patterns,
+    })
+}
+
+/// A size where `0` means off. TOML has no null, so `0` stands in for the
+/// `null` older JSON configs wrote.
+fn zero_if_null<'de, D>(deserializer: D) -> Result<usize, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(Option::<usize>::deserialize(deserializer)?.unwrap_or(0))
+}
+
+fn default_failover_statuses() -> Vec<u16> {
+    vec![529, 503]
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ArchiveConfig {
     pub enabled: bool,
     pub directory: PathBuf,
-    pub format: Vec<String>,
+    /// Where each request is written; one failing sink doesn't stop the others
+    pub sinks: Vec<SinkConfig>,
+    pub markdown: MarkdownArchiveConfig,
+    /// How long quitting waits for the archive to catch up. Whatever is left
+    /// is saved to `~/.sherlock/pending_events.jsonl` and archived next start.
+    pub shutdown_flush_timeout_secs: u64,
+}
+
+/// One archive destination
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SinkConfig {
+    /// A readable markdown file per request, within `archive.markdown`'s limits
+    Markdown {
+        #[serde(default, skip_serializing_if = "RenderMode::is_eager")]
+        render: RenderMode,
+    },
+    /// The raw request body per request, always written straight away
+    Json,
+}
+
+/// When a sink renders a request's file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RenderMode {
+    /// As the request is archived
+    #[default]
+    Eager,
+    /// In the background once the archive is idle, or by `sherlock render`.
+    /// The request waits in the archive's render queue until then.
+    Deferred,
+}
+
+impl RenderMode {
+    fn is_eager(&self) -> bool {
+        *self == RenderMode::Eager
+    }
+}
+
+/// Size limits for archived markdown; the defaults keep everything
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MarkdownArchiveConfig {
+    /// Longest message kept, in bytes; longer ones are cut with a note
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_message_bytes: Option<usize>,
+    /// Show each tool result as a one-line summary
+    pub collapse_tool_results: bool,
+    /// Longest document kept, in bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_total_bytes: Option<usize>,
+}
+
+/// Optional daily usage goals shown against today's progress
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GoalsConfig {
+    pub daily_tokens: Option<u64>,
+    /// Dollars a day, at the `pricing` rates
+    pub daily_cost_usd: Option<f64>,
+}
+
+/// Patterns that flag (or block) prompts before they are forwarded
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PolicyConfig {
+    pub scan_patterns: Vec<ScanPattern>,
+    /// Highest output token limit (`max_tokens` and its per-provider
+    /// equivalents) a request may ask for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_output_tokens: Option<u32>,
+    /// What happens to requests over `max_output_tokens`
+    pub max_output_tokens_action: OutputCapAction,
+}
+
+/// Credentials blanked out of prompts before they are shown, logged or
+/// archived. Upstream still gets the request as it was sent.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RedactionConfig {
+    pub enabled: bool,
+    /// Regexes whose matches become `[REDACTED:<n>]`, `n` the number of
+    /// characters replaced
+    pub patterns: Vec<String>,
+}
+
+impl Default for RedactionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            patterns: [
+                // Anthropic, then OpenAI's `sk-` and `sk-proj-` keys
+                r"sk-ant-[A-Za-z0-9_-]{20,}",
+                r"sk-[A-Za-z0-9_-]{20,}",
+                // Google API keys
+                r"AIza[0-9A-Za-z_-]{35}",
+                r"(?i)\bbearer\s+[A-Za-z0-9._~+/=-]{16,}",
+            ]
+            .map(str::to_string)
+            .to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScanPattern {
+    /// Reported instead of the matched text
+    pub name: String,
+    pub regex: String,
+    #[serde(default)]
+    pub action: PolicyAction,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyAction {
+    /// Flag the request but forward it
+    #[default]
+    Warn,
+    /// Reject the request with a policy error instead of forwarding it
+    Block,
+}
+
+/// Settings for `sherlock handoff`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HandoffConfig {
+    /// Largest handoff document, in tokens
+    pub budget_tokens: usize,
+    /// Provider `handoff --llm` sends the summary request to (anthropic or openai)
+    pub summary_provider: String,
+    /// Cheap model used by `handoff --llm`; unset disables it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary_model: Option<String>,
+}
+
+impl Default for HandoffConfig {
+    fn default() -> Self {
+        Self {
+            budget_tokens: 4_000,
+            summary_provider: "anthropic".to_string(),
+            summary_model: None,
+        }
+    }
+}
+
+/// Provider reliability objective for the reliability panel and
+/// `sherlock stats --reliability`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SloConfig {
+    /// A request only counts as good when its response started within this
+    pub latency_ms: u64,
+    /// Longer window reported next to the last 24 hours, e.g. "7d"
+    pub window: String,
+}
+
+impl Default for SloConfig {
+    fn default() -> Self {
+        Self {
+            latency_ms: 10_000,
+            window: "7d".to_string(),
+        }
+    }
+}
+
+impl SloConfig {
+    pub fn window(&self) -> Result<chrono::TimeDelta> {
+        let window = crate::record::parse_duration(&self.window).map_err(anyhow::Error::msg)?;
+        Ok(chrono::TimeDelta::from_std(window)?)
+    }
+}
+
+/// How `sherlock run` points tools at the proxy
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RunConfig {
+    /// Also set every known alias of a provider's base URL variable, since
+    /// tools disagree on its name
+    pub set_env_var_aliases: bool,
+    /// Base URL variables tools are known to read, per request format. A
+    /// format listed here replaces the built-in list; one left out keeps it.
+    #[serde(serialize_with = "serialize_sorted")]
+    pub env_var_aliases: HashMap<String, Vec<String>>,
+}
+
+impl Default for RunConfig {
+    fn default() -> Self {
+        Self {
+            set_env_var_aliases: true,
+            env_var_aliases: ["anthropic", "openai", "gemini"]
+                .into_iter()
+                .map(|format| (format.to_string(), default_env_var_aliases(format)))
+                .collect(),
+        }
+    }
+}
+
+impl RunConfig {
+    /// Known base URL variables for providers speaking `format`
+    pub fn aliases(&self, format: &str) -> Vec<String> {
+        self.env_var_aliases
+            .get(format)
+            .cloned()
+            .unwrap_or_else(|| default_env_var_aliases(format))
+    }
+}
+
+/// A tool started alongside the proxy, pointed at it like `sherlock run`
+/// would. Its output goes to `~/.sherlock/logs/<tag>.log`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AutostartTool {
+    pub provider: String,
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
+    /// Name in the dashboard and of the log file; the command's file name
+    /// by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    /// Times to start the tool again after it exits with a failure
+    #[serde(default)]
+    pub restarts: u32,
+}
+
+impl AutostartTool {
+    pub fn tag(&self) -> String {
+        self.tag.clone().unwrap_or_else(|| {
+            Path::new(&self.command)
+                .file_name()
+                .map_or(self.command.clone(), |name| {
+                    name.to_string_lossy().into_owned()
+                })
+        })
+    }
+}
+
+/// Synthetic faults for testing how clients cope with a misbehaving API
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChaosConfig {
+    /// Seed for picking the requests to fault, so a run can be repeated;
+    /// a random one, logged at start, when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    /// Tried in order for each request; the first to fire applies
+    pub rules: Vec<ChaosRule>,
+}
+
+impl ChaosConfig {
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+}
+
+/// Apply `fault` to `percent` of the requests to `provider`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChaosRule {
+    /// Every provider when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    pub percent: f64,
+    pub fault: ChaosFault,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ChaosFault {
+    /// Answer with this error status instead of forwarding
+    Status { status: u16 },
+    /// Hold the request for between `min_ms` and `max_ms` before forwarding
+    Latency { min_ms: u64, max_ms: u64 },
+    /// Cut a streamed response off after this many events; requests that
+    /// don't stream are left alone
+    Truncate { after_events: usize },
+}
+
+/// An outside program that rewrites prompts before sherlock shows or stores
+/// them. It reads one JSON array of messages per line on stdin and answers
+/// each with the scrubbed array on one line of stdout, staying up between
+/// requests.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScrubberConfig {
+    pub command: String,
+    pub args: Vec<String>,
+    /// Longest wait for an answer, counting the wait for a free process
+    pub timeout_ms: u64,
+    /// Largest array sent to the program, and largest answer taken back
+    pub max_bytes: usize,
+    /// Copies of the program kept running for requests under way at once
+    pub processes: usize,
+    /// What a request keeps when the program fails, times out or answers
+    /// with something other than an array of messages
+    pub on_failure: ScrubFailure,
+}
+
+impl Default for ScrubberConfig {
+    fn default() -> Self {
+        Self {
+            command: String::new(),
+            args: Vec::new(),
+            timeout_ms: 1000,
+            max_bytes: 8 * 1024 * 1024,
+            processes: 4,
+            on_failure: ScrubFailure::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScrubFailure {
+    /// Keep the messages as the client sent them, with a warning
+    PassThrough,
+    /// Keep the request's metadata but none of its prompt text
+    #[default]
+    DropContent,
+}
+
+/// Built-in base URL variables per request format, the most common first
+pub fn default_env_var_aliases(format: &str) -> Vec<String> {
+    let names: &[&str] = match format {
+        "anthropic" => &["ANTHROPIC_BASE_URL", "ANTHROPIC_API_URL", "ANTHROPIC_API_BASE"],
+        "openai" => &[
+            "OPENAI_BASE_URL",
+            "OPENAI_API_BASE",
+            "OPENAI_API_BASE_URL",
+            "OPENAI_API_HOST",
+        ],
+        "gemini" => &[
+            "GOOGLE_GEMINI_BASE_URL",
+            "GEMINI_API_BASE_URL",
+            "GEMINI_BASEURL",
+            "GEMINI_BASE_URL",
+        ],
+        _ => &[],
+    };
+    names.iter().map(|name| name.to_string()).collect()
+}
+
+/// Dollar rates by model name prefix, and the models each request's cost
+/// is compared against in the detail view
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PricingConfig {
+    /// Models the detail view prices each request on, listing the cheapest
+    /// three; models without a rate below are shown without a cost
+    #[serde(default = "default_compare_models")]
+    pub compare_models: Vec<String>,
+    /// Rates by model name prefix. Any rates replace the defaults; a section
+    /// without rates keeps them.
+    #[serde(flatten, deserialize_with = "default_pricing_if_empty")]
+    pub models: BTreeMap<String, ModelPrice>,
+}
+
+impl Default for PricingConfig {
+    fn default() -> Self {
+        Self {
+            compare_models: default_compare_models(),
+            models: default_pricing(),
+        }
+    }
+}
+
+/// What a model costs, in dollars per million tokens
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelPrice {
+    pub input_per_mtok: f64,
+    pub output_per_mtok: f64,
+    /// Rate for prompt tokens read from a cache, e.g. Gemini's cached
+    /// content; unset means the input rate
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cached_input_per_mtok: Option<f64>,
+    /// Rates of service tiers priced differently, e.g. "priority" or "flex";
+    /// other tiers pay the rates above
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tiers: BTreeMap<String, TierPrice>,
+}
+
+/// What a model costs on one service tier, in dollars per million tokens
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TierPrice {
+    pub input_per_mtok: f64,
+    pub output_per_mtok: f64,
+}
+
+impl ModelPrice {
+    /// Rates on `tier`, falling back to the standard ones
+    pub fn rates(&self, tier: Option<&str>) -> TierPrice {
+        tier.and_then(|tier| self.tiers.get(&tier.to_ascii_lowercase()))
+            .copied()
+            .unwrap_or(TierPrice {
+                input_per_mtok: self.input_per_mtok,
+                output_per_mtok: self.output_per_mtok,
+            })
+    }
+}
+
+/// The rates given, or the defaults when there are none, e.g. in a section
+/// that only sets `compare_models`
+fn default_pricing_if_empty<'de, D>(
+    deserializer: D,
+) -> Result<BTreeMap<String, ModelPrice>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let models = BTreeMap::deserialize(deserializer)?;
+    Ok(if models.is_empty() {
+        default_pricing()
+    } else {
+        models
+    })
+}
+
+/// A capable and a cheap model from each of the common providers
+fn default_compare_models() -> Vec<String> {
+    [
+        "claude-sonnet-4",
+        "claude-haiku-4-5",
+        "gpt-5",
+        "gpt-5-mini",
+        "gemini-2.5-pro",
+        "gemini-2.5-flash",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect()
+}
+
+/// List prices of common models; dated snapshots match by prefix
+fn default_pricing() -> BTreeMap<String, ModelPrice> {
+    [
+        ("claude-3-haiku", 0.25, 1.25),
+        ("claude-3-5-haiku", 0.8, 4.0),
+        ("claude-haiku-4-5", 1.0, 5.0),
+        ("claude-3-5-sonnet", 3.0, 15.0),
+        ("claude-3-7-sonnet", 3.0, 15.0),
+        ("claude-sonnet-4", 3.0, 15.0),
+        ("claude-3-opus", 15.0, 75.0),
+        ("claude-opus-4", 15.0, 75.0),
+        ("claude-opus-4-5", 5.0, 25.0),
+        ("gpt-4o", 2.5, 10.0),
+        ("gpt-4o-mini", 0.15, 0.6),
+        ("gpt-4.1", 2.0, 8.0),
+        ("gpt-4.1-mini", 0.4, 1.6),
+        ("gpt-4.1-nano", 0.1, 0.4),
+        ("gpt-5", 1.25, 10.0),
+        ("gpt-5-mini", 0.25, 2.0),
+        ("gpt-5-nano", 0.05, 0.4),
+        ("o1", 15.0, 60.0),
+        ("o3", 2.0, 8.0),
+        ("o3-mini", 1.1, 4.4),
+        ("o4-mini", 1.1, 4.4),
+        ("gemini-1.5-flash", 0.075, 0.3),
+        ("gemini-1.5-pro", 1.25, 5.0),
+        ("gemini-2.0-flash", 0.1, 0.4),
+        ("gemini-2.5-flash", 0.3, 2.5),
+        ("gemini-2.5-pro", 1.25, 10.0),
+    ]
+    .into_iter()
+    .map(|(prefix, input, output)| {
+        let tiers = DEFAULT_TIER_PRICES
+            .iter()
+            .filter(|(model, ..)| *model == prefix)
+            .map(|&(_, tier, input, output)| {
+                let price = TierPrice {
+                    input_per_mtok: input,
+                    output_per_mtok: output,
+                };
+                (tier.to_string(), price)
+            })
+            .collect();
+        let cached = DEFAULT_CACHED_INPUT_PRICES
+            .iter()
+            .find(|(model, _)| *model == prefix)
+            .map(|&(_, cached)| cached);
+        let price = ModelPrice {
+            input_per_mtok: input,
+            output_per_mtok: output,
+            cached_input_per_mtok: cached,
+            tiers,
+        };
+        (prefix.to_string(), price)
+    })
+    .collect()
+}
+
+/// Gemini's list prices for cached prompt tokens
+const DEFAULT_CACHED_INPUT_PRICES: &[(&str, f64)] = &[
+    ("gemini-1.5-flash", 0.01875),
+    ("gemini-1.5-pro", 0.3125),
+    ("gemini-2.0-flash", 0.025),
+    ("gemini-2.5-flash", 0.075),
+    ("gemini-2.5-pro", 0.31),
+];
+
+/// OpenAI's list prices for priority and flex processing
+const DEFAULT_TIER_PRICES: &[(&str, &str, f64, f64)] = &[
+    ("gpt-4o", "priority", 4.25, 17.0),
+    ("gpt-4o-mini", "priority", 0.25, 1.0),
+    ("gpt-4.1", "priority", 3.5, 14.0),
+    ("gpt-5", "priority", 2.5, 20.0),
+    ("gpt-5", "flex", 0.625, 5.0),
+    ("gpt-5-mini", "priority", 0.45, 3.6),
+    ("gpt-5-mini", "flex", 0.125, 1.0),
+    ("gpt-5-nano", "flex", 0.025, 0.2),
+    ("o3", "priority", 3.5, 14.0),
+    ("o3", "flex", 1.0, 4.0),
+    ("o4-mini", "priority", 2.0, 8.0),
+    ("o4-mini", "flex", 0.55, 2.2),
+];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputCapAction {
+    /// Lower the limit to the cap in the forwarded body
+    #[default]
+    Clamp,
+    /// Reject the request with a policy error instead of forwarding it
+    Block,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            port: 8080,
+            bind_address: "127.0.0.1".to_string(),
+            max_connections: 256,
+            shape_based_routing: false,
+            default_provider: None,
+            self_test: true,
+            capture_auth_headers: false,
+            max_upload_bytes_per_sec: None,
+            max_download_bytes_per_sec: None,
+            shutdown_grace_secs: 10,
+            streaming_parse_bytes: 4 * 1024 * 1024,
+            streamed_body_max_string_bytes: 4096,
+        }
+    }
+}
+
+impl Default for DashboardConfig {
+    fn default() -> Self {
+        Self {
+            token_limit: 200_000,
+            max_log_entries: 100,
+            refresh_rate_hz: 4,
+            prompt_preview_length: 200,
+            in_flight_timeout_secs: 600,
+            layout: LayoutMode::Auto,
+            show_key_column: false,
+            show_throughput_column: false,
+            show_change_column: false,
+            group_by_repo: false,
+            token_scope: TokenScope::default(),
+            set_terminal_title: false,
+            peak_alert_thresholds: vec![80, 90],
+        }
+    }
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            directory: PathBuf::from("~/.sherlock/prompts"),
+            sinks: vec![
+                SinkConfig::Markdown {
+                    render: RenderMode::Eager,
+                },
+                SinkConfig::Json,
+            ],
+            markdown: MarkdownArchiveConfig::default(),
+            shutdown_flush_timeout_secs: 10,
+        }
+    }
 }
 
 impl Default for Config {
@@ -50,7 +1005,14 @@ impl Default for Config {
                 host: "api.anthropic.com".to_string(),
                 base_url: "https://api.anthropic.com".to_string(),
                 env_vars: vec!["ANTHROPIC_BASE_URL".to_string()],
-                path_pattern: "/v1/messages".to_string(),
+                path_patterns: vec!["/v1/messages".to_string()],
+                format: None,
+                fallbacks: Vec::new(),
+                failover_statuses: default_failover_statuses(),
+                tls: None,
+                tokenizer: None,
+                deployment_models: BTreeMap::new(),
+                api_key_env: None,
             },
         );
 
@@ -60,7 +1022,17 @@ impl Default for Config {
                 host: "api.openai.com".to_string(),
                 base_url: "https://api.openai.com".to_string(),
                 env_vars: vec!["OPENAI_BASE_URL".to_string()],
-                path_pattern: "/v1/chat/completions".to_string(),
+                path_patterns: vec![
+                    OPENAI_CHAT_PATH.to_string(),
+                    OPENAI_RESPONSES_PATH.to_string(),
+                ],
+                format: None,
+                fallbacks: Vec::new(),
+                failover_statuses: default_failover_statuses(),
+                tls: None,
+                tokenizer: None,
+                deployment_models: BTreeMap::new(),
+                api_key_env: None,
             },
         );
 
@@ -74,50 +1046,338 @@ impl Default for Config {
                     "GEMINI_API_BASE_URL".to_string(),
                     "GEMINI_BASEURL".to_string(),
                 ],
-                path_pattern: "generateContent".to_string(),
+                path_patterns: vec!["generateContent".to_string()],
+                format: None,
+                fallbacks: Vec::new(),
+                failover_statuses: default_failover_statuses(),
+                tls: None,
+                tokenizer: None,
+                deployment_models: BTreeMap::new(),
+                api_key_env: None,
             },
         );
 
+        providers.insert("ollama".to_string(), ollama_provider());
+        providers.insert("azure".to_string(), azure_provider());
+
         Self {
-            proxy: ProxyConfig {
-                port: 8080,
-                bind_address: "127.0.0.1".to_string(),
-            },
-            dashboard: DashboardConfig {
-                token_limit: 200_000,
-                max_log_entries: 100,
-                refresh_rate_hz: 4,
-                prompt_preview_length: 200,
-            },
+            version: CONFIG_VERSION,
+            proxy: ProxyConfig::default(),
+            dashboard: DashboardConfig::default(),
             providers,
-            archive: ArchiveConfig {
-                enabled: true,
-                directory: PathBuf::from("~/.sherlock/prompts"),
-                format: vec!["markdown".to_string(), "json".to_string()],
-            },
+            archive: ArchiveConfig::default(),
+            goals: GoalsConfig::default(),
+            policy: PolicyConfig::default(),
+            redaction: RedactionConfig::default(),
+            handoff: HandoffConfig::default(),
+            slo: SloConfig::default(),
+            run: RunConfig::default(),
+            autostart: Vec::new(),
+            autostart_kill_on_exit: true,
+            chaos: ChaosConfig::default(),
+            scrubber: None,
+            pricing: PricingConfig::default(),
+            update_check: false,
+            update_url: DEFAULT_UPDATE_URL.to_string(),
         }
     }
 }
 
+/// A local Ollama server, for both its chat and single-prompt endpoints
+fn ollama_provider() -> ProviderConfig {
+    ProviderConfig {
+        host: "localhost:11434".to_string(),
+        base_url: "http://localhost:11434".to_string(),
+        env_vars: vec!["OLLAMA_HOST".to_string()],
+        path_patterns: vec!["/api/chat".to_string(), "/api/generate".to_string()],
+        format: None,
+        fallbacks: Vec::new(),
+        failover_statuses: default_failover_statuses(),
+        tls: None,
+        tokenizer: None,
+        deployment_models: BTreeMap::new(),
+        api_key_env: None,
+    }
+}
+
+/// Azure OpenAI, which names the deployment in the path and takes OpenAI
+/// request bodies. `base_url` is a placeholder for the resource's endpoint.
+fn azure_provider() -> ProviderConfig {
+    ProviderConfig {
+        host: "your-resource.openai.azure.com".to_string(),
+        base_url: "https://your-resource.openai.azure.com".to_string(),
+        env_vars: vec!["AZURE_OPENAI_ENDPOINT".to_string()],
+        path_patterns: vec!["/openai/deployments/".to_string()],
+        format: Some("openai".to_string()),
+        fallbacks: Vec::new(),
+        failover_statuses: default_failover_statuses(),
+        tls: None,
+        tokenizer: None,
+        deployment_models: BTreeMap::new(),
+        api_key_env: None,
+    }
+}
+
 impl Config {
-    pub fn load(path: &Path) -> Result<Self> {
-        let expanded_path = expand_tilde(path);
+    /// Load the config file, upgrading older schema versions in memory.
+    ///
+    /// With `migrate` set, an outdated file is rewritten at the current version
+    /// after copying the original to `<file>.bak`.
+    pub fn load(path: &Path, migrate: bool) -> Result<Self> {
+        let expanded_path = resolve_path(path);
 
-        if expanded_path.exists() {
-            let content = std::fs::read_to_string(&expanded_path)?;
-            let mut config: Config = serde_json::from_str(&content)?;
-            // Expand tilde in archive directory
-            config.archive.directory = expand_tilde(&config.archive.directory);
-            Ok(config)
-        } else {
+        if !expanded_path.exists() {
             tracing::info!(
                 "Config file not found at {:?}, using defaults",
                 expanded_path
             );
-            let mut config = Config::default();
+            if migrate {
+                Config::save_default(path)?;
+            }
+            let mut config = Config::default().with_env(|name| std::env::var(name).ok())?;
             config.archive.directory = expand_tilde(&config.archive.directory);
-            Ok(config)
+            return Ok(config);
         }
+
+        let content = std::fs::read_to_string(&expanded_path)?;
+        let mut value = parse_document(&expanded_path, &content)?;
+        let from_version = Config::migrate(&mut value)?;
+
+        let (config, unknown) = Config::from_value(value)?;
+        config.validate(&expanded_path)?;
+        if !unknown.is_empty() {
+            tracing::warn!(
+                "Ignoring unknown config fields in {:?}: {}",
+                expanded_path,
+                unknown.join(", ")
+            );
+        }
+
+        if from_version < CONFIG_VERSION {
+            if migrate {
+                let backup = match is_toml(&expanded_path) {
+                    true => expanded_path.with_extension("toml.bak"),
+                    false => expanded_path.with_extension("json.bak"),
+                };
+                std::fs::copy(&expanded_path, &backup)?;
+                config.save(&expanded_path)?;
+                tracing::info!(
+                    "Migrated config from version {} to {} (backup at {:?})",
+                    from_version,
+                    CONFIG_VERSION,
+                    backup
+                );
+            } else {
+                tracing::warn!(
+                    "Config file {:?} is at version {}, current is {}; run with --migrate-config to upgrade it",
+                    expanded_path,
+                    from_version,
+                    CONFIG_VERSION
+                );
+            }
+        }
+
+        // After any migration, so the environment never ends up in the file
+        let mut config = config.with_env(|name| std::env::var(name).ok())?;
+        // Expand tilde in archive directory
+        config.archive.directory = expand_tilde(&config.archive.directory);
+        Ok(config)
+    }
+
+    /// Apply the `SHERLOCK_*` variables `var` finds over the file's
+    /// settings; the command line's `--port` and `--limit` still win
+    pub fn with_env(mut self, var: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        fn parse<T: std::str::FromStr>(name: &str, value: &str) -> Result<T>
+        where
+            T::Err: std::error::Error + Send + Sync + 'static,
+        {
+            value
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid {}={:?}", name, value))
+        }
+
+        if let Some(port) = var(ENV_PROXY_PORT) {
+            self.proxy.port = parse(ENV_PROXY_PORT, &port)?;
+        }
+        if let Some(address) = var(ENV_BIND_ADDRESS) {
+            if address.trim().is_empty() {
+                anyhow::bail!("Invalid {}: it is empty", ENV_BIND_ADDRESS);
+            }
+            self.proxy.bind_address = address.trim().to_string();
+        }
+        if let Some(limit) = var(ENV_TOKEN_LIMIT) {
+            self.dashboard.token_limit = parse(ENV_TOKEN_LIMIT, &limit)?;
+        }
+        if let Some(dir) = var(ENV_ARCHIVE_DIR) {
+            if dir.is_empty() {
+                anyhow::bail!("Invalid {}: it is empty", ENV_ARCHIVE_DIR);
+            }
+            self.archive.directory = PathBuf::from(dir);
+        }
+        if let Some(enabled) = var(ENV_ARCHIVE_ENABLED) {
+            self.archive.enabled = match enabled.trim().to_ascii_lowercase().as_str() {
+                "1" | "true" | "yes" | "on" => true,
+                "0" | "false" | "no" | "off" => false,
+                _ => anyhow::bail!(
+                    "Invalid {}={:?}: expected true or false",
+                    ENV_ARCHIVE_ENABLED,
+                    enabled
+                ),
+            };
+        }
+        Ok(self)
+    }
+
+    /// Upgrade a raw config document in place to `CONFIG_VERSION`,
+    /// returning the version it started at
+    pub fn migrate(value: &mut Value) -> Result<u32> {
+        migrate_with(value, MIGRATIONS)
+    }
+
+    /// Check the settings serde can't: policy and redaction patterns,
+    /// fallbacks, the SLO window, the default provider, provider paths, TLS
+    /// files, autostarted tools, chaos rules, the scrubber and the peak alert
+    /// thresholds. `source` names the file in errors.
+    pub fn validate(&self, source: &Path) -> Result<()> {
+        PolicyScanner::new(&self.policy)
+            .with_context(|| format!("Invalid policy in {:?}", source))?;
+        Redactor::new(&self.redaction)
+            .with_context(|| format!("Invalid redaction in {:?}", source))?;
+        self.validate_fallbacks()
+            .with_context(|| format!("Invalid provider fallbacks in {:?}", source))?;
+        self.slo
+            .window()
+            .with_context(|| format!("Invalid slo.window in {:?}", source))?;
+        if let Some(default) = &self.proxy.default_provider {
+            if !self.providers.contains_key(default) {
+                anyhow::bail!(
+                    "proxy.default_provider names unknown provider {:?} in {:?}",
+                    default,
+                    source
+                );
+            }
+        }
+        for (name, provider) in &self.providers {
+            if provider.path_patterns.is_empty() {
+                anyhow::bail!("Provider {} has no path_patterns in {:?}", name, source);
+            }
+            if let Some(tls) = &provider.tls {
+                tls::validate(tls).with_context(|| {
+                    format!("Invalid TLS settings for {} in {:?}", name, source)
+                })?;
+            }
+        }
+        self.validate_autostart()
+            .with_context(|| format!("Invalid autostart in {:?}", source))?;
+        self.validate_chaos()
+            .with_context(|| format!("Invalid chaos rules in {:?}", source))?;
+        self.validate_scrubber()
+            .with_context(|| format!("Invalid scrubber in {:?}", source))?;
+        if let Some(threshold) = self
+            .dashboard
+            .peak_alert_thresholds
+            .iter()
+            .find(|threshold| !(1..=100).contains(*threshold))
+        {
+            anyhow::bail!(
+                "dashboard.peak_alert_thresholds has {}, outside 1 to 100, in {:?}",
+                threshold,
+                source
+            );
+        }
+        Ok(())
+    }
+
+    /// Autostarted tools need a known provider and a tag of their own, since
+    /// the tag names their log file
+    fn validate_autostart(&self) -> Result<()> {
+        let mut tags = Vec::new();
+        for tool in &self.autostart {
+            if !self.providers.contains_key(&tool.provider) {
+                anyhow::bail!("{} uses unknown provider {:?}", tool.command, tool.provider);
+            }
+            let tag = tool.tag();
+            if tag.is_empty() || tag.contains(['/', '\\']) || tag.starts_with('.') {
+                anyhow::bail!("{:?} can't name a log file", tag);
+            }
+            if tags.contains(&tag) {
+                anyhow::bail!("more than one tool is tagged {:?}", tag);
+            }
+            tags.push(tag);
+        }
+        Ok(())
+    }
+
+    fn validate_chaos(&self) -> Result<()> {
+        for rule in &self.chaos.rules {
+            if let Some(provider) = &rule.provider {
+                if !self.providers.contains_key(provider) {
+                    anyhow::bail!("unknown provider {:?}", provider);
+                }
+            }
+            if !(0.0..=100.0).contains(&rule.percent) {
+                anyhow::bail!("percent {} is outside 0 to 100", rule.percent);
+            }
+            match rule.fault {
+                ChaosFault::Status { status } if !(400..=599).contains(&status) => {
+                    anyhow::bail!("status {} is not an error status", status);
+                }
+                ChaosFault::Latency { min_ms, max_ms } if min_ms > max_ms => {
+                    anyhow::bail!("min_ms {} is over max_ms {}", min_ms, max_ms);
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_scrubber(&self) -> Result<()> {
+        let Some(scrubber) = &self.scrubber else {
+            return Ok(());
+        };
+        if scrubber.command.trim().is_empty() {
+            anyhow::bail!("command is empty");
+        }
+        if scrubber.timeout_ms == 0 || scrubber.max_bytes == 0 || scrubber.processes == 0 {
+            anyhow::bail!("timeout_ms, max_bytes and processes must be over 0");
+        }
+        Ok(())
+    }
+
+    /// Deserialize a config document, collecting the paths of fields sherlock doesn't know
+    pub fn from_value(value: Value) -> Result<(Self, Vec<String>)> {
+        let mut unknown = Vec::new();
+        let config = serde_ignored::deserialize(value, |path| unknown.push(path.to_string()))?;
+        Ok((config, unknown))
+    }
+
+    /// Fallbacks must name other known providers speaking the same API format.
+    /// Providers with the same main path are treated as the same format,
+    /// since bodies are forwarded untranslated.
+    fn validate_fallbacks(&self) -> Result<()> {
+        for (name, provider) in &self.providers {
+            for fallback in &provider.fallbacks {
+                let Some(target) = self.providers.get(fallback) else {
+                    anyhow::bail!("{} falls back to unknown provider {:?}", name, fallback);
+                };
+                if fallback == name {
+                    anyhow::bail!("{} lists itself as a fallback", name);
+                }
+                let (from, to) = (provider.body_format(name), target.body_format(fallback));
+                if from != to {
+                    anyhow::bail!(
+                        "{} can't fall back to {}: cross-format failover is not supported \
+                         ({} to {}; set `format` on a gateway)",
+                        name,
+                        fallback,
+                        from,
+                        to
+                    );
+                }
+            }
+        }
+        Ok(())
     }
 
     pub fn with_overrides(mut self, port: Option<u16>, limit: Option<u64>) -> Self {
@@ -130,7 +1390,8 @@ impl Config {
         self
     }
 
-    /// Save the default config to the specified path
+    /// Save the default config to the specified path, as TOML with comments
+    /// for a `.toml` path and as JSON otherwise
     pub fn save_default(path: &Path) -> Result<()> {
         let expanded_path = expand_tilde(path);
 
@@ -138,21 +1399,130 @@ impl Config {
             std::fs::create_dir_all(parent)?;
         }
 
-        let config = Config::default();
-        let content = serde_json::to_string_pretty(&config)?;
-        std::fs::write(&expanded_path, content)?;
+        Config::default().save(&expanded_path)?;
 
         tracing::info!("Saved default config to {:?}", expanded_path);
         Ok(())
     }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        let content = match is_toml(path) {
+            true => documented_toml(&toml::to_string_pretty(self)?),
+            false => serde_json::to_string_pretty(self)?,
+        };
+        std::fs::write(path, content)?;
+        Ok(())
+    }
+}
+
+/// `path` with `~` expanded; for the default path, `~/.sherlock/config.toml`
+/// instead when there is no `config.json` but there is that
+pub fn resolve_path(path: &Path) -> PathBuf {
+    let expanded = expand_tilde(path);
+    if !expanded.exists() && expanded == expand_tilde(Path::new(DEFAULT_PATH)) {
+        let toml = expand_tilde(Path::new(DEFAULT_TOML_PATH));
+        if toml.exists() {
+            return toml;
+        }
+    }
+    expanded
+}
+
+fn is_toml(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "toml")
+}
+
+/// A config file's contents as a JSON document, read as TOML or JSON by the
+/// file's extension
+fn parse_document(path: &Path, content: &str) -> Result<Value> {
+    match is_toml(path) {
+        true => toml::from_str(content).with_context(|| format!("Invalid TOML in {:?}", path)),
+        false => {
+            serde_json::from_str(content).with_context(|| format!("Invalid JSON in {:?}", path))
+        }
+    }
+}
+
+/// Comment written above a top-level table of a saved TOML config
+fn toml_section_doc(section: &str) -> Option<&'static str> {
+    Some(match section {
+        "proxy" => "Where the proxy listens, and how it forwards and parses requests",
+        "dashboard" => "What the dashboard shows and how often it redraws",
+        "providers" => {
+            "Upstream APIs by name; a request goes to the provider whose path_patterns it matches"
+        }
+        "archive" => "Where prompts are saved, and in which formats",
+        "goals" => "Daily token and cost goals, for the dashboard and `sherlock stats --goals`",
+        "policy" => "Content patterns to flag or block, and the cap on output tokens",
+        "redaction" => "Patterns blanked out of prompts before they are shown or archived",
+        "handoff" => "Size and model of `sherlock handoff` summaries",
+        "slo" => "Latency objective and window for the reliability panel",
+        "run" => "Base URL variables set for tools run through sherlock",
+        "autostart" => "Tools `sherlock start` launches once the proxy is listening",
+        "chaos" => "Faults injected under `sherlock start --enable-chaos`",
+        "scrubber" => "Program every request's messages pass through; read at start only",
+        "pricing" => {
+            "Dollar rates by model name prefix, and the models the detail view compares costs against"
+        }
+        _ => return None,
+    })
+}
+
+/// `toml` with a header, and each top-level table preceded by what it is for
+fn documented_toml(toml: &str) -> String {
+    let mut out = String::from(
+        "# sherlock config. Settings left out take their defaults; see the README\n\
+         # for what each one does.\n\n",
+    );
+    let mut section = "";
+    for line in toml.lines() {
+        if let Some(header) = line.strip_prefix('[') {
+            let name = header
+                .trim_start_matches('[')
+                .split(['.', ']'])
+                .next()
+                .unwrap_or_default();
+            if name != section {
+                section = name;
+                if let Some(doc) = toml_section_doc(name) {
+                    out.push_str(&format!("# {}\n", doc));
+                }
+            }
+        }
+        out.push_str(line);
+        out.push('\n');
+    }
+    out
+}
+
+fn migrate_with(value: &mut Value, migrations: &[Migration]) -> Result<u32> {
+    let obj = value
+        .as_object_mut()
+        .ok_or_else(|| anyhow::anyhow!("Config file must contain a JSON object"))?;
+
+    let version = obj.get("version").and_then(Value::as_u64).unwrap_or(0) as usize;
+    if version > migrations.len() {
+        anyhow::bail!(
+            "Config version {} is newer than this sherlock supports ({})",
+            version,
+            migrations.len()
+        );
+    }
+
+    for migration in &migrations[version..] {
+        migration(obj);
+    }
+    obj.insert("version".to_string(), Value::from(migrations.len()));
+
+    Ok(version as u32)
 }
 
 /// Expand ~ to home directory
 pub fn expand_tilde(path: &Path) -> PathBuf {
     if let Some(path_str) = path.to_str() {
-        if path_str.starts_with("~/") {
+        if let Some(rest) = path_str.strip_prefix("~/") {
             if let Some(home) = dirs::home_dir() {
-                return home.join(&path_str[2..]);
+                return home.join(rest);
             }
         } else if path_str == "~" {
             if let Some(home) = dirs::home_dir() {
@@ -183,4 +1553,553 @@ mod tests {
         assert_eq!(config.proxy.port, 9090);
         assert_eq!(config.dashboard.token_limit, 100_000);
     }
+
+    #[test]
+    fn test_with_env() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| value.to_string())
+            }
+        };
+        let config = Config::default()
+            .with_env(env(&[
+                ("SHERLOCK_PROXY_PORT", "9000"),
+                ("SHERLOCK_BIND_ADDRESS", "0.0.0.0"),
+                ("SHERLOCK_TOKEN_LIMIT", " 50000 "),
+                ("SHERLOCK_ARCHIVE_DIR", "/data/prompts"),
+                ("SHERLOCK_ARCHIVE_ENABLED", "False"),
+            ]))
+            .unwrap();
+        assert_eq!(config.proxy.port, 9000);
+        assert_eq!(config.proxy.bind_address, "0.0.0.0");
+        assert_eq!(config.dashboard.token_limit, 50_000);
+        assert_eq!(config.archive.directory, Path::new("/data/prompts"));
+        assert!(!config.archive.enabled);
+
+        // The command line still wins
+        let config = config.with_overrides(Some(9090), None);
+        assert_eq!(config.proxy.port, 9090);
+        assert_eq!(config.dashboard.token_limit, 50_000);
+
+        // Unset variables leave the file's settings alone
+        let config = Config::default().with_env(env(&[])).unwrap();
+        assert_eq!(config.proxy.port, 8080);
+        assert!(config.archive.enabled);
+
+        for (name, value) in [
+            ("SHERLOCK_PROXY_PORT", "70000"),
+            ("SHERLOCK_BIND_ADDRESS", ""),
+            ("SHERLOCK_TOKEN_LIMIT", "lots"),
+            ("SHERLOCK_ARCHIVE_DIR", ""),
+            ("SHERLOCK_ARCHIVE_ENABLED", "maybe"),
+        ] {
+            let err = Config::default()
+                .with_env(|var| (var == name).then(|| value.to_string()))
+                .unwrap_err();
+            assert!(format!("{:#}", err).contains(name), "{:#}", err);
+        }
+    }
+
+    #[test]
+    fn test_migrations_match_version() {
+        assert_eq!(MIGRATIONS.len(), CONFIG_VERSION as usize);
+    }
+
+    #[test]
+    fn test_load_v0_file() {
+        // A pre-versioning file, including a partial section
+        let mut value = serde_json::json!({
+            "proxy": {"port": 9000, "bind_address": "0.0.0.0"},
+            "dashboard": {"token_limit": 50000},
+            "archive": {"enabled": false, "directory": "/tmp/prompts", "format": ["json"]}
+        });
+
+        assert_eq!(Config::migrate(&mut value).unwrap(), 0);
+        let (config, unknown) = Config::from_value(value).unwrap();
+        assert!(unknown.is_empty());
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.proxy.port, 9000);
+        assert_eq!(config.dashboard.token_limit, 50000);
+        assert_eq!(config.dashboard.max_log_entries, 100);
+        assert!(config.providers.contains_key("anthropic"));
+        assert_eq!(config.archive.sinks, [SinkConfig::Json]);
+    }
+
+    #[test]
+    fn test_v2_openai_takes_responses_path() {
+        let provider = |path: &str| {
+            serde_json::json!({
+                "host": "api.openai.com",
+                "base_url": "https://api.openai.com",
+                "env_vars": [],
+                "path_pattern": path
+            })
+        };
+        let mut value = serde_json::json!({"version": 2, "providers": {
+            "openai": provider("/v1/chat/completions"),
+            "anthropic": provider("/v1/messages")
+        }});
+        assert_eq!(Config::migrate(&mut value).unwrap(), 2);
+        let (config, _) = Config::from_value(value).unwrap();
+        assert!(config.providers["openai"].matches_path("/v1/responses"));
+        assert!(config.providers["openai"].matches_path("/v1/chat/completions"));
+        assert!(!config.providers["anthropic"].matches_path("/v1/responses"));
+
+        // A provider moved to another path is left as configured
+        let mut value = serde_json::json!({"version": 2, "providers": {
+            "openai": provider("/openai/deployments/gpt-4o/chat/completions")
+        }});
+        Config::migrate(&mut value).unwrap();
+        let openai = &value["providers"]["openai"];
+        assert_eq!(
+            openai["path_patterns"],
+            serde_json::json!(["/openai/deployments/gpt-4o/chat/completions"])
+        );
+    }
+
+    #[test]
+    fn test_v3_gains_ollama() {
+        let mut value = serde_json::json!({"version": 3, "providers": {
+            "anthropic": serde_json::to_value(&Config::default().providers["anthropic"]).unwrap()
+        }});
+        Config::migrate(&mut value).unwrap();
+        let (config, _) = Config::from_value(value).unwrap();
+        let ollama = &config.providers["ollama"];
+        assert_eq!(ollama.base_url, "http://localhost:11434");
+        assert!(ollama.matches_path("/api/generate"));
+
+        // One already configured is kept as it is
+        let mut value = serde_json::json!({"version": 3, "providers": {
+            "ollama": {"host": "gpu-box:11434", "base_url": "http://gpu-box:11434",
+                       "env_vars": [], "path_pattern": "/api/chat"}
+        }});
+        Config::migrate(&mut value).unwrap();
+        assert_eq!(value["providers"]["ollama"]["host"], "gpu-box:11434");
+    }
+
+    #[test]
+    fn test_v4_gains_azure() {
+        let mut value = serde_json::json!({"version": 4, "providers": {}});
+        Config::migrate(&mut value).unwrap();
+        let (config, _) = Config::from_value(value).unwrap();
+        let azure = &config.providers["azure"];
+        assert_eq!(azure.body_format("azure"), "openai");
+        assert!(azure.matches_path("/openai/deployments/gpt4o-prod/chat/completions"));
+        assert!(!azure.matches_path("/v1/chat/completions"));
+    }
+
+    #[test]
+    fn test_v5_lists_path_patterns() {
+        let mut value = serde_json::json!({"version": 5, "providers": {
+            "openai": {"host": "api.openai.com", "base_url": "https://api.openai.com",
+                       "env_vars": [], "path_pattern": "/v1/chat/completions",
+                       "extra_path_patterns": ["/v1/responses", "/v1/embeddings"]},
+            "anthropic": {"host": "api.anthropic.com", "base_url": "https://api.anthropic.com",
+                          "env_vars": [], "path_pattern": "/v1/messages"}
+        }});
+        Config::migrate(&mut value).unwrap();
+        let (config, unknown) = Config::from_value(value).unwrap();
+        assert!(unknown.is_empty(), "{:?}", unknown);
+        assert_eq!(
+            config.providers["openai"].path_patterns,
+            ["/v1/chat/completions", "/v1/responses", "/v1/embeddings"]
+        );
+        assert_eq!(
+            config.providers["anthropic"].path_patterns,
+            ["/v1/messages"]
+        );
+        assert_eq!(
+            config.providers["openai"].main_path(),
+            "/v1/chat/completions"
+        );
+
+        // Read straight, a single `path_pattern` is still a list of one
+        let value = serde_json::json!({"version": CONFIG_VERSION, "providers": {
+            "gateway": {"host": "llm.internal", "base_url": "https://llm.internal",
+                        "env_vars": [], "path_pattern": "/llm/v1/chat"}
+        }});
+        let (config, _) = Config::from_value(value).unwrap();
+        assert_eq!(config.providers["gateway"].path_patterns, ["/llm/v1/chat"]);
+    }
+
+    #[test]
+    fn test_hypothetical_schema_bumps() {
+        let migrations: &[Migration] = &[
+            |_| {},
+            // v1 -> v2: dashboard.token_limit moves to limits.tokens
+            |obj| {
+                let limit = obj
+                    .get_mut("dashboard")
+                    .and_then(|d| d.as_object_mut())
+                    .and_then(|d| d.remove("token_limit"));
+                if let Some(limit) = limit {
+                    obj.insert("limits".to_string(), serde_json::json!({ "tokens": limit }));
+                }
+            },
+            // v2 -> v3: new field filled with its default
+            |obj| {
+                obj.entry("update_check").or_insert(Value::Bool(false));
+            },
+        ];
+
+        let mut value = serde_json::json!({"dashboard": {"token_limit": 1234}});
+        assert_eq!(migrate_with(&mut value, migrations).unwrap(), 0);
+        assert_eq!(value["version"], 3);
+        assert_eq!(value["limits"]["tokens"], 1234);
+        assert!(value["dashboard"].get("token_limit").is_none());
+        assert_eq!(value["update_check"], false);
+
+        // Already current documents pass through untouched
+        let mut current = value.clone();
+        assert_eq!(migrate_with(&mut current, migrations).unwrap(), 3);
+        assert_eq!(current, value);
+
+        let mut future = serde_json::json!({"version": 4});
+        assert!(migrate_with(&mut future, migrations).is_err());
+    }
+
+    #[test]
+    fn test_validate_fallbacks() {
+        let mut config = Config::default();
+        // A gateway on its own path takes the same request bodies
+        let backup = ProviderConfig {
+            host: "gateway.internal".to_string(),
+            base_url: "https://gateway.internal".to_string(),
+            path_patterns: vec!["/anthropic/v1/messages".to_string()],
+            format: Some("anthropic".to_string()),
+            ..config.providers["anthropic"].clone()
+        };
+        config.providers.insert("anthropic-backup".to_string(), backup);
+        let lookalike = ProviderConfig {
+            format: Some("openai".to_string()),
+            ..config.providers["anthropic"].clone()
+        };
+        config.providers.insert("lookalike".to_string(), lookalike);
+
+        let set_fallbacks = |config: &mut Config, fallbacks: &[&str]| {
+            config.providers.get_mut("anthropic").unwrap().fallbacks =
+                fallbacks.iter().map(|f| f.to_string()).collect();
+        };
+
+        set_fallbacks(&mut config, &["anthropic-backup"]);
+        config.validate_fallbacks().unwrap();
+
+        set_fallbacks(&mut config, &["bedrock"]);
+        assert!(config.validate_fallbacks().unwrap_err().to_string().contains("unknown"));
+
+        set_fallbacks(&mut config, &["anthropic"]);
+        assert!(config.validate_fallbacks().unwrap_err().to_string().contains("itself"));
+
+        set_fallbacks(&mut config, &["openai"]);
+        assert!(config.validate_fallbacks().unwrap_err().to_string().contains("cross-format"));
+
+        // The same path is no use with a body of another format
+        set_fallbacks(&mut config, &["lookalike"]);
+        assert!(config.validate_fallbacks().unwrap_err().to_string().contains("cross-format"));
+    }
+
+    #[test]
+    fn test_validate_default_provider() {
+        let mut config = Config::default();
+        config.proxy.default_provider = Some("anthropic".to_string());
+        config.validate(Path::new("config.json")).unwrap();
+        config.proxy.default_provider = Some("bedrock".to_string());
+        let err = config.validate(Path::new("config.json")).unwrap_err();
+        assert!(err.to_string().contains("unknown provider"), "{}", err);
+    }
+
+    #[test]
+    fn test_injected_auth() {
+        let mut providers = Config::default().providers;
+        let var = |name: &str| (name == "TEAM_KEY").then(|| " sk-team \n".to_string());
+        let mut auth = |name: &str| {
+            let provider = providers.get_mut(name).unwrap();
+            assert_eq!(provider.injected_auth(name, var), None);
+            provider.api_key_env = Some("TEAM_KEY".to_string());
+            provider.injected_auth(name, var)
+        };
+        assert_eq!(
+            auth("anthropic"),
+            Some(("x-api-key", "sk-team".to_string()))
+        );
+        assert_eq!(
+            auth("gemini"),
+            Some(("x-goog-api-key", "sk-team".to_string()))
+        );
+        assert_eq!(auth("azure"), Some(("api-key", "sk-team".to_string())));
+        assert_eq!(
+            auth("ollama"),
+            Some(("authorization", "Bearer sk-team".to_string()))
+        );
+        providers.get_mut("openai").unwrap().api_key_env = Some("UNSET".to_string());
+        assert_eq!(providers["openai"].injected_auth("openai", var), None);
+    }
+
+    #[test]
+    fn test_validate_peak_alert_thresholds() {
+        let mut config = Config::default();
+        assert_eq!(config.dashboard.peak_alert_thresholds, [80, 90]);
+        config.dashboard.peak_alert_thresholds = vec![100, 50];
+        config.validate(Path::new("config.json")).unwrap();
+        config.dashboard.peak_alert_thresholds = vec![90, 120];
+        let err = config.validate(Path::new("config.json")).unwrap_err();
+        assert!(err.to_string().contains("120"), "{}", err);
+    }
+
+    #[test]
+    fn test_validate_autostart() {
+        let value = serde_json::json!({
+            "version": CONFIG_VERSION,
+            "autostart": [
+                {"provider": "anthropic", "command": "/usr/local/bin/claude", "args": ["-p", "hi"]},
+                {"provider": "openai", "command": "codex", "tag": "review", "restarts": 2}
+            ]
+        });
+        let (mut config, unknown) = Config::from_value(value).unwrap();
+        assert!(unknown.is_empty(), "{:?}", unknown);
+        config.validate_autostart().unwrap();
+        assert_eq!(config.autostart[0].tag(), "claude");
+        assert_eq!(config.autostart[1].tag(), "review");
+        assert!(config.autostart_kill_on_exit);
+
+        config.autostart[1].tag = Some("claude".to_string());
+        let err = config.validate_autostart().unwrap_err().to_string();
+        assert!(err.contains("more than one"), "{}", err);
+        config.autostart[1].tag = Some("../claude".to_string());
+        assert!(config.validate_autostart().is_err());
+        config.autostart[1].tag = None;
+        config.autostart[1].provider = "bedrock".to_string();
+        let err = config.validate_autostart().unwrap_err().to_string();
+        assert!(err.contains("unknown provider"), "{}", err);
+    }
+
+    #[test]
+    fn test_validate_scrubber() {
+        let value = serde_json::json!({
+            "version": CONFIG_VERSION,
+            "scrubber": {"command": "/usr/local/bin/scrub", "on_failure": "pass_through"}
+        });
+        let (mut config, unknown) = Config::from_value(value).unwrap();
+        assert!(unknown.is_empty(), "{:?}", unknown);
+        config.validate_scrubber().unwrap();
+        let scrubber = config.scrubber.as_mut().unwrap();
+        assert_eq!(scrubber.on_failure, ScrubFailure::PassThrough);
+        assert_eq!(scrubber.timeout_ms, 1000);
+        assert!(Config::default().scrubber.is_none());
+
+        scrubber.processes = 0;
+        assert!(config.validate_scrubber().is_err());
+        let scrubber = config.scrubber.as_mut().unwrap();
+        scrubber.processes = 1;
+        scrubber.command = " ".to_string();
+        let err = config.validate_scrubber().unwrap_err().to_string();
+        assert!(err.contains("command"), "{}", err);
+    }
+
+    #[test]
+    fn test_validate_chaos() {
+        let value = serde_json::json!({
+            "version": CONFIG_VERSION,
+            "chaos": {
+                "seed": 7,
+                "rules": [
+                    {"provider": "anthropic", "percent": 10, "fault": {"type": "status", "status": 429}},
+                    {"percent": 5, "fault": {"type": "latency", "min_ms": 2000, "max_ms": 5000}},
+                    {"percent": 1, "fault": {"type": "truncate", "after_events": 3}}
+                ]
+            }
+        });
+        let (mut config, unknown) = Config::from_value(value).unwrap();
+        assert!(unknown.is_empty(), "{:?}", unknown);
+        config.validate_chaos().unwrap();
+        assert_eq!(config.chaos.seed, Some(7));
+        assert_eq!(
+            config.chaos.rules[2].fault,
+            ChaosFault::Truncate { after_events: 3 }
+        );
+        assert!(Config::default().chaos.is_empty());
+
+        config.chaos.rules[0].fault = ChaosFault::Status { status: 200 };
+        let err = config.validate_chaos().unwrap_err().to_string();
+        assert!(err.contains("not an error status"), "{}", err);
+        config.chaos.rules[0].fault = ChaosFault::Latency {
+            min_ms: 10,
+            max_ms: 5,
+        };
+        assert!(config.validate_chaos().is_err());
+        config.chaos.rules[0].fault = ChaosFault::Status { status: 503 };
+        config.chaos.rules[0].percent = 120.0;
+        assert!(config.validate_chaos().is_err());
+        config.chaos.rules[0].percent = 10.0;
+        config.chaos.rules[0].provider = Some("bedrock".to_string());
+        let err = config.validate_chaos().unwrap_err().to_string();
+        assert!(err.contains("unknown provider"), "{}", err);
+    }
+
+    /// A config setting the nested maps, lists and optional tables too
+    fn custom_config(dir: &Path) -> Config {
+        let mut config = Config::default();
+        config.proxy.port = 9100;
+        // Off, which must survive TOML's lack of null
+        config.proxy.streaming_parse_bytes = 0;
+        config.archive.directory = dir.join("prompts");
+        let mut gateway = Config::default().providers["openai"].clone();
+        gateway.host = "gateway.internal:8443".to_string();
+        gateway.format = Some("openai".to_string());
+        gateway.fallbacks = vec!["openai".to_string()];
+        gateway.tls = Some(TlsConfig {
+            insecure_skip_verify: true,
+            ..TlsConfig::default()
+        });
+        gateway
+            .deployment_models
+            .insert("prod-gpt".to_string(), "gpt-4o".to_string());
+        config.providers.insert("gateway".to_string(), gateway);
+        config.pricing.compare_models = vec!["gpt-5".to_string(), "in-house-7b".to_string()];
+        config.pricing.models.insert(
+            "gpt-5".to_string(),
+            ModelPrice {
+                input_per_mtok: 1.25,
+                output_per_mtok: 10.0,
+                cached_input_per_mtok: None,
+                tiers: BTreeMap::from([(
+                    "priority".to_string(),
+                    TierPrice {
+                        input_per_mtok: 2.5,
+                        output_per_mtok: 20.0,
+                    },
+                )]),
+            },
+        );
+        config.autostart.push(AutostartTool {
+            provider: "gateway".to_string(),
+            command: "aider".to_string(),
+            args: vec!["--yes".to_string()],
+            cwd: None,
+            tag: None,
+            restarts: 1,
+        });
+        config.chaos.rules.push(ChaosRule {
+            provider: Some("gateway".to_string()),
+            percent: 12.5,
+            fault: ChaosFault::Latency {
+                min_ms: 100,
+                max_ms: 900,
+            },
+        });
+        config.scrubber = Some(ScrubberConfig {
+            command: "scrub".to_string(),
+            ..ScrubberConfig::default()
+        });
+        config
+    }
+
+    #[test]
+    fn test_round_trips_json_and_toml() {
+        let dir = std::env::temp_dir().join(format!("sherlock-config-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = custom_config(&dir);
+        let expected = serde_json::to_value(&config).unwrap();
+
+        for name in ["config.json", "config.toml"] {
+            let path = dir.join(name);
+            config.save(&path).unwrap();
+            let loaded = Config::load(&path, false).unwrap();
+            assert_eq!(serde_json::to_value(&loaded).unwrap(), expected, "{}", name);
+            assert_eq!(
+                loaded.providers["gateway"].deployment_models["prod-gpt"],
+                "gpt-4o"
+            );
+            assert_eq!(loaded.proxy.streaming_parse_bytes, 0, "{}", name);
+        }
+
+        // Older JSON configs turned streaming off with null
+        let path = dir.join("config.json");
+        std::fs::write(&path, r#"{"proxy": {"streaming_parse_bytes": null}}"#).unwrap();
+        let loaded = Config::load(&path, false).unwrap();
+        assert_eq!(loaded.proxy.streaming_parse_bytes, 0);
+
+        // TOML gets comments, above the first of each section's tables only
+        let toml = std::fs::read_to_string(dir.join("config.toml")).unwrap();
+        assert!(toml.starts_with("# sherlock config."), "{}", toml);
+        for section in [
+            "# Upstream APIs by name; a request goes to the provider whose path_patterns it matches\n\
+             [providers.anthropic]\n",
+            "\n\n[providers.gateway]\n",
+            "# Faults injected under `sherlock start --enable-chaos`\n[[chaos.rules]]\n",
+        ] {
+            assert!(toml.contains(section), "{}", toml);
+        }
+
+        // Errors name the format
+        std::fs::write(dir.join("broken.toml"), "[proxy\nport = 1").unwrap();
+        let err = Config::load(&dir.join("broken.toml"), false).unwrap_err();
+        assert!(err.to_string().starts_with("Invalid TOML"), "{:#}", err);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_pricing_without_rates() {
+        let dir =
+            std::env::temp_dir().join(format!("sherlock-config-pricing-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+
+        // Setting only the comparison keeps the default rates
+        std::fs::write(&path, "[pricing]\ncompare_models = [\"gpt-5-mini\"]\n").unwrap();
+        let config = Config::load(&path, false).unwrap();
+        assert_eq!(config.pricing.compare_models, ["gpt-5-mini"]);
+        assert_eq!(config.pricing.models, default_pricing());
+
+        // Any rates replace them
+        let toml = "[pricing.in-house-7b]\ninput_per_mtok = 0.1\noutput_per_mtok = 0.2\n";
+        std::fs::write(&path, toml).unwrap();
+        let config = Config::load(&path, false).unwrap();
+        assert_eq!(config.pricing.compare_models, default_compare_models());
+        assert_eq!(
+            config.pricing.models.keys().collect::<Vec<_>>(),
+            ["in-house-7b"]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_migrates_toml_file() {
+        let dir = std::env::temp_dir().join(format!("sherlock-config-toml-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        let v0 = "[proxy]\nport = 9000\n\n[archive]\nformat = [\"json\"]\n";
+        std::fs::write(&path, v0).unwrap();
+
+        let config = Config::load(&path, true).unwrap();
+        assert_eq!(config.proxy.port, 9000);
+        assert_eq!(config.archive.sinks, [SinkConfig::Json]);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("config.toml.bak")).unwrap(),
+            v0
+        );
+        let upgraded: Value = toml::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(upgraded["version"], CONFIG_VERSION);
+        assert_eq!(upgraded["archive"]["sinks"][0]["type"], "json");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_unknown_fields_reported() {
+        let value = serde_json::json!({
+            "version": CONFIG_VERSION,
+            "proxy": {"port": 8081, "listen_port": 1},
+            "colour_scheme": "dark"
+        });
+
+        let (config, unknown) = Config::from_value(value).unwrap();
+        assert_eq!(config.proxy.port, 8081);
+        assert!(unknown.contains(&"proxy.listen_port".to_string()));
+        assert!(unknown.contains(&"colour_scheme".to_string()));
+    }
 }
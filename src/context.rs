@@ -0,0 +1,433 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp::Reverse;
+use std::collections::VecDeque;
+
+use crate::dashboard::format_number;
+use crate::event::{RequestEvent, TokenComposition};
+use crate::export::{Block, Conversation};
+use crate::parser::count_tokens;
+
+/// Context windows by model id prefix; the longest matching prefix wins
+const CONTEXT_LIMITS: &[(&str, u64)] = &[
+    ("claude-", 200_000),
+    ("gpt-5", 400_000),
+    ("gpt-4.1", 1_047_576),
+    ("gpt-4o", 128_000),
+    ("gpt-4-turbo", 128_000),
+    ("gpt-4", 8_192),
+    ("gpt-3.5-turbo", 16_385),
+    ("o1-mini", 128_000),
+    ("o1", 200_000),
+    ("o3", 200_000),
+    ("o4-mini", 200_000),
+    ("gemini-1.5-pro", 2_097_152),
+    ("gemini-1.5-flash", 1_048_576),
+    ("gemini-2", 1_048_576),
+];
+
+/// Most candidates listed per kind in the advice
+const MAX_CANDIDATES: usize = 5;
+
+/// Rough cost of one image; providers scale it with resolution
+const IMAGE_TOKENS: u64 = 1_600;
+
+/// Bytes per token assumed for attached documents
+const BYTES_PER_TOKEN: u64 = 4;
+
+/// A request too large for its model's context window, either predicted
+/// from sherlock's count or reported by the provider
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContextOverflow {
+    /// Tokens in the request, the provider's figure when its error gave one
+    pub tokens: Option<u64>,
+    pub limit: Option<u64>,
+    /// The provider answered with a context length error
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub rejected: bool,
+}
+
+impl ContextOverflow {
+    /// e.g. "212,345 of 200,000 tokens, rejected by the provider"
+    pub fn describe(&self) -> String {
+        let size = match (self.tokens, self.limit) {
+            (Some(tokens), Some(limit)) => {
+                format!(
+                    "{} of {} tokens",
+                    format_number(tokens),
+                    format_number(limit)
+                )
+            }
+            (Some(tokens), None) => format!("{} tokens", format_number(tokens)),
+            (None, Some(limit)) => format!("over {} tokens", format_number(limit)),
+            (None, None) => "over the context window".to_string(),
+        };
+        if self.rejected {
+            format!("{}, rejected by the provider", size)
+        } else {
+            size
+        }
+    }
+
+    /// Tokens to shed before the request fits, when both sides are known
+    pub fn excess(&self) -> Option<u64> {
+        Some(self.tokens?.saturating_sub(self.limit?))
+    }
+}
+
+/// Context window of `model`, if it is one sherlock knows
+pub fn context_limit(model: &str) -> Option<u64> {
+    let model = model.to_lowercase();
+    let model = model.strip_prefix("models/").unwrap_or(&model);
+    CONTEXT_LIMITS
+        .iter()
+        .filter(|(prefix, _)| model.starts_with(prefix))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, limit)| *limit)
+}
+
+/// Tokens the request takes up in the context window: messages, system
+/// prompt and tool definitions
+pub fn counted_tokens(event: &RequestEvent) -> u64 {
+    event.composition().total()
+}
+
+/// The overflow sherlock can predict before forwarding `event`
+pub fn check(event: &RequestEvent) -> Option<ContextOverflow> {
+    let limit = context_limit(&event.model)?;
+    let tokens = counted_tokens(event);
+    (tokens > limit).then_some(ContextOverflow {
+        tokens: Some(tokens),
+        limit: Some(limit),
+        rejected: false,
+    })
+}
+
+/// A provider's context length error, from an error response body. The
+/// body's shape decides the provider, so failover responses are read too.
+pub fn parse_error(body: &Value) -> Option<ContextOverflow> {
+    let error = body.get("error")?;
+    let message = error.get("message").and_then(Value::as_str).unwrap_or("");
+    let kind = |field: &str| error.get(field).and_then(Value::as_str);
+    let (tokens, limit) = if message.starts_with("prompt is too long") {
+        // Anthropic: "prompt is too long: 212345 tokens > 200000 maximum"
+        (
+            number_after(message, "too long"),
+            number_after(message, ">"),
+        )
+    } else if message.contains("exceed context limit") {
+        // Anthropic, counting max_tokens: "... exceed context limit: 195201 + 8192 > 200000"
+        (
+            number_after(message, "context limit"),
+            number_after(message, ">"),
+        )
+    } else if kind("code") == Some("context_length_exceeded")
+        || message.contains("maximum context length")
+    {
+        (
+            number_after(message, "resulted in"),
+            number_after(message, "maximum context length is"),
+        )
+    } else if kind("status") == Some("INVALID_ARGUMENT")
+        && message.contains("exceeds the maximum number of tokens")
+    {
+        (
+            number_after(message, "input token count"),
+            number_after(message, "tokens allowed"),
+        )
+    } else {
+        return None;
+    };
+    Some(ContextOverflow {
+        tokens,
+        limit,
+        rejected: true,
+    })
+}
+
+/// The first number after `phrase` in `message`
+fn number_after(message: &str, phrase: &str) -> Option<u64> {
+    let (_, rest) = message.split_once(phrase)?;
+    let rest = rest.trim_start_matches(|c: char| !c.is_ascii_digit());
+    let end = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    rest[..end].parse().ok()
+}
+
+/// Part of a request that could be cut to make it fit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    /// e.g. "turn 12, read_file result"
+    pub label: String,
+    pub tokens: u64,
+}
+
+/// What an oversized request is made of and which parts would free the most.
+/// Informational only; nothing is changed in the forwarded request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Advice {
+    pub composition: TokenComposition,
+    /// Tokens to shed, when the limit is known
+    pub excess: Option<u64>,
+    /// Largest tool results first
+    pub tool_results: Vec<Candidate>,
+    /// Oldest turns first, leaving out the latest one
+    pub history: Vec<Candidate>,
+    /// Oldest turns that would have to go to fit, if dropping them is enough
+    pub turns_to_fit: Option<usize>,
+    pub attachments: Vec<Candidate>,
+}
+
+/// Where `event` could be trimmed to get under `overflow`'s limit
+pub fn advise(event: &RequestEvent, overflow: &ContextOverflow) -> Advice {
+    let conversation = Conversation::from_request_body(&event.raw_body);
+    let mut tool_results = Vec::new();
+    let mut attachments = Vec::new();
+    let mut turn_tokens = Vec::new();
+    // Tool results answer tool calls in order, so names are matched up the same way
+    let mut calls: VecDeque<&str> = VecDeque::new();
+    for (i, turn) in conversation.turns.iter().enumerate() {
+        let mut tokens = 0;
+        for block in &turn.blocks {
+            tokens += match block {
+                Block::Text(text) => count_tokens(text) as u64,
+                Block::ToolCall { name, input } => {
+                    calls.push_back(name);
+                    count_tokens(&input.to_string()) as u64
+                }
+                Block::ToolResult { id, content, .. } => {
+                    let tokens = count_tokens(content) as u64;
+                    let name = calls.pop_front().unwrap_or(id);
+                    tool_results.push(Candidate {
+                        label: format!("turn {}, {} result", i + 1, name),
+                        tokens,
+                    });
+                    tokens
+                }
+                Block::Other { kind, json } => match attachment_tokens(kind, json) {
+                    Some(tokens) => {
+                        attachments.push(Candidate {
+                            label: format!("turn {}, {}", i + 1, kind),
+                            tokens,
+                        });
+                        tokens
+                    }
+                    None => 0,
+                },
+                // Bodies are never collapsed into steps
+                Block::Step(_) => 0,
+            };
+        }
+        turn_tokens.push((format!("turn {} ({})", i + 1, turn.role), tokens));
+    }
+    tool_results.sort_by_key(|candidate| Reverse(candidate.tokens));
+    tool_results.truncate(MAX_CANDIDATES);
+    attachments.sort_by_key(|candidate| Reverse(candidate.tokens));
+    attachments.truncate(MAX_CANDIDATES);
+
+    // The latest turn is the prompt being asked, so it never counts as history
+    turn_tokens.pop();
+    let excess = overflow.excess();
+    let turns_to_fit = excess.and_then(|excess| {
+        let mut freed = 0;
+        turn_tokens.iter().position(|(_, tokens)| {
+            freed += tokens;
+            freed >= excess
+        })
+    });
+    let history = turn_tokens
+        .into_iter()
+        .take(MAX_CANDIDATES)
+        .map(|(label, tokens)| Candidate { label, tokens })
+        .collect();
+
+    Advice {
+        composition: event.composition(),
+        excess,
+        tool_results,
+        history,
+        turns_to_fit: turns_to_fit.map(|i| i + 1),
+        attachments,
+    }
+}
+
+/// Estimated tokens of an image or document block, `None` for other blocks
+fn attachment_tokens(kind: &str, json: &Value) -> Option<u64> {
+    // Gemini parts carry their payload under inlineData or fileData
+    let media = json
+        .get("inlineData")
+        .or_else(|| json.get("inline_data"))
+        .or_else(|| json.get("fileData"));
+    let mime = media
+        .and_then(|media| media.get("mimeType").or_else(|| media.get("mime_type")))
+        .or_else(|| json.pointer("/source/media_type"))
+        .and_then(Value::as_str)
+        .unwrap_or("");
+    if kind.contains("image") || mime.starts_with("image/") {
+        return Some(IMAGE_TOKENS);
+    }
+    if !matches!(kind, "document" | "file" | "input_file") && media.is_none() {
+        return None;
+    }
+    if json.pointer("/source/type").and_then(Value::as_str) == Some("text") {
+        let text = json
+            .pointer("/source/data")
+            .and_then(Value::as_str)
+            .unwrap_or("");
+        return Some(count_tokens(text) as u64);
+    }
+    // Base64 payloads decode to three bytes per four characters
+    let data = media
+        .and_then(|media| media.get("data"))
+        .or_else(|| json.pointer("/source/data"))
+        .or_else(|| json.pointer("/file/file_data"))
+        .and_then(Value::as_str)
+        .unwrap_or("");
+    Some(data.len() as u64 * 3 / 4 / BYTES_PER_TOKEN)
+}
+
+impl Advice {
+    /// The advice as a markdown section
+    pub fn to_markdown(&self, overflow: &ContextOverflow) -> String {
+        let mut md = String::from("## Context limit\n\n");
+        md.push_str(&format!(
+            "Over the context window: {}.",
+            overflow.describe()
+        ));
+        if let Some(excess) = self.excess {
+            md.push_str(&format!(
+                " About {} tokens would have to go.",
+                format_number(excess)
+            ));
+        }
+        md.push_str("\n\n");
+        let composition = &self.composition;
+        md.push_str(&format!(
+            "- **System prompt:** {}\n- **Tool definitions:** {}\n- **Tool results:** {}\n\
+             - **Conversation:** {}\n\n",
+            format_number(composition.system),
+            format_number(composition.tools),
+            format_number(composition.tool_results),
+            format_number(composition.conversation)
+        ));
+        for (title, candidates) in [
+            ("Largest tool results", &self.tool_results),
+            ("Oldest turns", &self.history),
+            ("Attachments (estimated)", &self.attachments),
+        ] {
+            if candidates.is_empty() {
+                continue;
+            }
+            md.push_str(&format!("### {}\n\n", title));
+            for candidate in candidates {
+                md.push_str(&format!(
+                    "- {}: {} tokens\n",
+                    candidate.label,
+                    format_number(candidate.tokens)
+                ));
+            }
+            md.push('\n');
+        }
+        if let Some(turns) = self.turns_to_fit {
+            md.push_str(&format!(
+                "Dropping the oldest {} turns would make it fit.\n\n",
+                turns
+            ));
+        }
+        md
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_request;
+
+    const ERRORS: &str = include_str!("../tests/fixtures/context_errors.json");
+
+    #[test]
+    fn test_provider_context_errors() {
+        let cases: Vec<Value> = serde_json::from_str(ERRORS).unwrap();
+        for case in &cases {
+            let name = case["name"].as_str().unwrap();
+            let parsed = parse_error(&case["body"]);
+            let expected = &case["expected"];
+            if expected.is_null() {
+                assert_eq!(parsed, None, "{}", name);
+                continue;
+            }
+            let parsed = parsed.unwrap_or_else(|| panic!("{} not recognised", name));
+            assert!(parsed.rejected, "{}", name);
+            assert_eq!(parsed.tokens, expected["tokens"].as_u64(), "{}", name);
+            assert_eq!(parsed.limit, expected["limit"].as_u64(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_limits_and_check() {
+        assert_eq!(context_limit("claude-sonnet-4-5-20250929"), Some(200_000));
+        assert_eq!(context_limit("gpt-4o-mini"), Some(128_000));
+        assert_eq!(context_limit("gpt-4-0613"), Some(8_192));
+        assert_eq!(context_limit("o1-mini-2024-09-12"), Some(128_000));
+        assert_eq!(context_limit("models/gemini-2.5-pro"), Some(1_048_576));
+        assert_eq!(context_limit("llama3"), None);
+
+        let body = serde_json::json!({
+            "model": "gpt-4",
+            "messages": [{"role": "user", "content": "word ".repeat(9_000)}],
+        });
+        let mut event = parse_request(
+            body.to_string().as_bytes(),
+            "/v1/chat/completions",
+            "openai",
+        )
+        .unwrap();
+        let overflow = check(&event).unwrap();
+        assert_eq!(overflow.limit, Some(8_192));
+        assert!(overflow.excess().unwrap() > 0);
+        event.model = "gpt-4o".to_string();
+        assert_eq!(check(&event), None);
+    }
+
+    #[test]
+    fn test_advice() {
+        let body = serde_json::json!({
+            "model": "claude-sonnet-4-5",
+            "messages": [
+                {"role": "user", "content": "List the files"},
+                {"role": "assistant", "content": [
+                    {"type": "tool_use", "id": "t1", "name": "ls", "input": {}},
+                    {"type": "tool_use", "id": "t2", "name": "read_file",
+                     "input": {"path": "big.log"}}
+                ]},
+                {"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "t1", "content": "a.rs b.rs"},
+                    {"type": "tool_result", "tool_use_id": "t2",
+                     "content": "line of log output\n".repeat(500)},
+                    {"type": "image", "source": {"type": "base64",
+                     "media_type": "image/png", "data": "iVBORw0KGgo="}}
+                ]},
+                {"role": "user", "content": "Why is it failing?"}
+            ]
+        });
+        let event =
+            parse_request(body.to_string().as_bytes(), "/v1/messages", "anthropic").unwrap();
+        let overflow = ContextOverflow {
+            tokens: Some(201_000),
+            limit: Some(200_000),
+            rejected: true,
+        };
+        let advice = advise(&event, &overflow);
+        assert_eq!(advice.excess, Some(1_000));
+        assert_eq!(advice.tool_results[0].label, "turn 3, read_file result");
+        assert_eq!(advice.tool_results[1].label, "turn 3, ls result");
+        assert_eq!(advice.attachments[0].tokens, IMAGE_TOKENS);
+        assert_eq!(advice.history.len(), 3);
+        assert_eq!(advice.turns_to_fit, Some(3));
+
+        let md = advice.to_markdown(&overflow);
+        assert!(md.contains("201,000 of 200,000 tokens, rejected by the provider"));
+        assert!(md.contains("### Largest tool results\n\n- turn 3, read_file result"));
+    }
+}
@@ -0,0 +1,65 @@
+//! Actions a running instance can be asked for while it runs. Dashboard
+//! keys and, on unix, signals both go through `Dashboard::control`, so a
+//! headless instance under a service manager can be driven too.
+
+use std::fmt;
+#[cfg(unix)]
+use tokio::sync::mpsc;
+
+/// An action asked of a running instance
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Control {
+    /// Flush the archive's files and log a stats snapshot (SIGUSR1)
+    Flush,
+    /// Pause archiving, or resume it (SIGUSR2)
+    ToggleArchive,
+    /// Re-read the config file now (SIGHUP)
+    Reload,
+}
+
+impl fmt::Display for Control {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Control::Flush => "flush",
+            Control::ToggleArchive => "archive toggle",
+            Control::Reload => "reload",
+        })
+    }
+}
+
+/// SIGUSR1, SIGUSR2 and SIGHUP, listened for from the moment they're
+/// installed so none sent during startup ends the process
+#[cfg(unix)]
+pub struct Signals {
+    flush: tokio::signal::unix::Signal,
+    toggle_archive: tokio::signal::unix::Signal,
+    reload: tokio::signal::unix::Signal,
+}
+
+#[cfg(unix)]
+impl Signals {
+    pub fn install() -> std::io::Result<Self> {
+        use tokio::signal::unix::{signal, SignalKind};
+        Ok(Self {
+            flush: signal(SignalKind::user_defined1())?,
+            toggle_archive: signal(SignalKind::user_defined2())?,
+            reload: signal(SignalKind::hangup())?,
+        })
+    }
+
+    /// Send the control each signal asks for until `controls` closes
+    pub async fn forward(mut self, controls: mpsc::Sender<Control>) {
+        loop {
+            let control = tokio::select! {
+                Some(()) = self.flush.recv() => Control::Flush,
+                Some(()) = self.toggle_archive.recv() => Control::ToggleArchive,
+                Some(()) = self.reload.recv() => Control::Reload,
+                else => break,
+            };
+            tracing::debug!("Received signal for {}", control);
+            if controls.send(control).await.is_err() {
+                break;
+            }
+        }
+    }
+}
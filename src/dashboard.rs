@@ -1,108 +1,1167 @@
 use anyhow::Result;
 use crossterm::{
-    event::{self, Event, KeyCode, KeyEventKind},
+    cursor,
+    event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use ratatui::{
     backend::CrosstermBackend,
+    buffer::Buffer,
     layout::{Constraint, Layout, Rect},
     style::{Color, Modifier, Style},
     text::{Line, Span},
-    widgets::{Block, Borders, Gauge, Paragraph, Row, Table, Wrap},
+    widgets::{
+        Block, Borders, Cell as TableCell, Gauge, Paragraph, Row, StatefulWidget, Table,
+        TableState, Wrap,
+    },
     Frame, Terminal,
 };
-use std::collections::VecDeque;
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::io::{self, Stdout};
+use std::sync::{Arc, Mutex, Once, PoisonError};
+use std::thread::ThreadId;
 use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
 
-use crate::config::DashboardConfig;
-use crate::event::{RequestEvent, RequestInfo};
+use crate::archive::{format_bytes, ArchiveEntry};
+use crate::autostart::{ToolState, ToolStatus};
+use crate::config::{DashboardConfig, GoalsConfig, LayoutMode, SloConfig, TokenScope};
+use crate::control::Control;
+use crate::delta::DeltaTracker;
+use crate::detail::DetailView;
+use crate::event::{
+    capitalize, InFlightRequest, OutputTokens, ProxyEvent, RequestEvent, RequestFailure,
+    RequestInfo, TokenComposition, UploadProgress,
+};
+use crate::filter::Filter;
+use crate::goals::{GoalTracker, GOAL_WINDOW_DAYS};
+use crate::language;
+use crate::metrics::{ArchiveMetrics, ProxyMetrics};
+use crate::models::ModelRegistry;
+use crate::parser::SchemaDrift;
+use crate::pricing::{format_cost, PriceTable};
+use crate::projection::SpendTracker;
+use crate::proxy::ShutdownHandle;
+use crate::reliability::{ReliabilityReport, Sample};
+use crate::resend::{self, Draft, Resender};
+use crate::runtime::Reloader;
+use crate::search::{Search, SearchScope};
+use crate::self_test;
+use crate::stats::{ranked, Histogram, ModelStats, SessionStats};
+use crate::statusline::{self, Status, TerminalTitle};
+use crate::text::{display_width, truncate, truncate_middle};
+use crate::update;
+use crate::watermark::Watermarks;
 
 pub struct Dashboard {
     config: DashboardConfig,
-    total_tokens: u64,
+    /// Token buckets of every counted request, so scope toggles need no recount
+    tokens: TokenComposition,
+    /// Output tokens of the responses so far
+    output_tokens: u64,
+    /// Dollars spent on priced requests this session; unset until one completes
+    cost_usd: Option<f64>,
+    /// Requests this session whose model has no price
+    unpriced: usize,
+    /// Prices the detail view compares each request's cost against
+    prices: PriceTable,
     requests: VecDeque<RequestInfo>,
+    in_flight: Vec<InFlightRequest>,
     last_prompt: String,
     last_provider: String,
+    stats: SessionStats,
+    /// Requests and tokens per model and per provider over the whole
+    /// session, however many have left the log
+    by_model: HashMap<String, ModelStats>,
+    by_provider: HashMap<String, ModelStats>,
+    spend: SpendTracker,
+    show_spend: bool,
+    show_reliability: bool,
+    show_models: bool,
+    slo: SloConfig,
+    goals: GoalTracker,
+    metrics: Arc<ProxyMetrics>,
+    archive_metrics: Arc<ArchiveMetrics>,
+    /// Providers whose schema drift notice has already been shown
+    drift_notified: BTreeSet<String>,
+    /// Conversations whose caching hint has already been shown
+    cache_notified: BTreeSet<String>,
+    notice: Option<(String, Instant)>,
+    show_keys: bool,
+    show_throughput: bool,
+    show_changes: bool,
+    /// Previous request per conversation, for the change column
+    deltas: DeltaTracker,
+    /// Largest request per conversation, this session and since the start
+    peaks: Watermarks,
+    /// Key labels seen this session per provider, by fingerprint
+    keys_by_provider: BTreeMap<String, BTreeMap<String, String>>,
+    /// Request log rows scrolled past, 0 keeps the newest entries in view
+    scroll: usize,
+    /// Highlighted request log row. On the newest row (0) the view follows new
+    /// entries; anywhere else the selection and view stay on the same rows.
+    selected: usize,
+    /// Request log rows that fit on screen, as of the last render
+    viewport: Cell<usize>,
+    models: ModelRegistry,
+    models_saved: Instant,
+    model_width: ModelWidth,
+    /// Widest model cell seen this session, for the `Full` preset
+    longest_model: usize,
+    /// Columns the request table is scrolled right by
+    hscroll: usize,
+    /// Furthest the table could scroll right, as of the last render
+    hscroll_max: Cell<usize>,
+    /// The self-test request reached the dashboard as sent
+    self_test_seen: bool,
+    self_test_error: Option<String>,
+    /// Newer release reported by the update check
+    update_available: Option<String>,
+    /// Autostarted tools and their latest state, in start order
+    tools: Vec<ToolStatus>,
+    /// Only completed requests matching this are listed
+    filter: Option<Filter>,
+    /// Filter or search being typed after '/'
+    filter_input: Option<String>,
+    /// What typing after '/' searches; unset while it edits the filter
+    search_scope: Option<SearchScope>,
+    /// Search results, listed in place of the request log
+    search: Option<Search>,
+    /// Request opened with Enter, shown over everything else
+    detail: Option<DetailView>,
+    /// Sends requests edited from the detail view; unset when replaying
+    resender: Option<Resender>,
+    /// Request to open in the editor once the terminal is handed over
+    editing: Option<Draft>,
+    /// A resent request is on its way, and its response opens in the detail view
+    awaiting_manual: bool,
+    /// Position in the session when replaying an archive rather than live traffic
+    replay: Option<String>,
+    /// Chaos rules injecting faults into the traffic, in brief
+    chaos: Option<String>,
+    /// Completed requests go to the archive; paused with `a` or SIGUSR2
+    archiving: bool,
+    /// Controls asked for from outside, such as by signals
+    controls: Option<mpsc::Receiver<Control>>,
+    /// Applies `Control::Reload`
+    reloader: Option<Reloader>,
+    /// Set while the terminal title follows the session, see
+    /// `DashboardConfig::set_terminal_title`
+    title: Option<TerminalTitle>,
+    /// Log a line per request, with no dashboard to list them
+    log_requests: bool,
 }
 
 impl Dashboard {
-    pub fn new(config: DashboardConfig) -> Self {
+    pub fn new(
+        config: DashboardConfig,
+        goals: &GoalsConfig,
+        metrics: Arc<ProxyMetrics>,
+        archive_metrics: Arc<ArchiveMetrics>,
+        models: ModelRegistry,
+        slo: SloConfig,
+    ) -> Self {
         Self {
+            show_keys: config.show_key_column,
+            show_throughput: config.show_throughput_column,
+            show_changes: config.show_change_column,
+            deltas: DeltaTracker::default(),
+            peaks: Watermarks::new(&config.peak_alert_thresholds),
             config,
-            total_tokens: 0,
+            tokens: TokenComposition::default(),
+            output_tokens: 0,
+            cost_usd: None,
+            unpriced: 0,
+            prices: PriceTable::default(),
             requests: VecDeque::new(),
+            in_flight: Vec::new(),
             last_prompt: String::new(),
             last_provider: String::new(),
+            stats: SessionStats::default(),
+            by_model: HashMap::new(),
+            by_provider: HashMap::new(),
+            spend: SpendTracker::new(chrono::Local::now().naive_local()),
+            show_spend: false,
+            show_reliability: false,
+            show_models: false,
+            slo,
+            goals: GoalTracker::new(goals),
+            metrics,
+            archive_metrics,
+            drift_notified: BTreeSet::new(),
+            cache_notified: BTreeSet::new(),
+            notice: None,
+            keys_by_provider: BTreeMap::new(),
+            scroll: 0,
+            selected: 0,
+            viewport: Cell::new(0),
+            models,
+            models_saved: Instant::now(),
+            model_width: ModelWidth::Fit,
+            longest_model: 0,
+            hscroll: 0,
+            hscroll_max: Cell::new(0),
+            self_test_seen: false,
+            self_test_error: None,
+            update_available: None,
+            tools: Vec::new(),
+            filter: None,
+            filter_input: None,
+            search_scope: None,
+            search: None,
+            detail: None,
+            resender: None,
+            editing: None,
+            awaiting_manual: false,
+            replay: None,
+            chaos: None,
+            archiving: true,
+            controls: None,
+            reloader: None,
+            title: None,
+            log_requests: false,
         }
     }
 
+    /// Take controls from `controls` while running, reloading the config
+    /// with `reloader` when asked
+    pub fn set_controls(&mut self, controls: mpsc::Receiver<Control>, reloader: Option<Reloader>) {
+        self.controls = Some(controls);
+        self.reloader = reloader;
+    }
+
+    /// Show the header's REPLAY badge with `status`, e.g. "12/340 14:03:22 ▶ 10x"
+    pub fn set_replay_status(&mut self, status: String) {
+        self.replay = Some(status);
+    }
+
+    /// Compare each opened request's cost against `prices`' comparison models
+    pub fn set_prices(&mut self, prices: PriceTable) {
+        self.prices = prices;
+    }
+
+    /// Let `E` in the detail view edit the request and send it again
+    pub fn set_resender(&mut self, resender: Resender) {
+        self.resender = Some(resender);
+    }
+
+    /// Keep the header's CHAOS banner up, naming the `rules` in effect
+    pub fn set_chaos(&mut self, rules: String) {
+        self.chaos = Some(rules);
+    }
+
+    /// A filter is being typed or a request is open, so every key is the
+    /// dashboard's
+    pub fn captures_keys(&self) -> bool {
+        self.filter_input.is_some() || self.detail.is_some()
+    }
+
+    /// Forget every request and failure seen so far, keeping what is shown
+    /// and how, so the session can be rebuilt from an earlier point
+    pub fn reset_session(&mut self, goals: &GoalsConfig) {
+        self.tokens = TokenComposition::default();
+        self.output_tokens = 0;
+        self.cost_usd = None;
+        self.unpriced = 0;
+        self.requests.clear();
+        self.in_flight.clear();
+        self.last_prompt.clear();
+        self.last_provider.clear();
+        self.stats = SessionStats::default();
+        self.by_model.clear();
+        self.by_provider.clear();
+        self.spend = SpendTracker::new(chrono::Local::now().naive_local());
+        self.goals = GoalTracker::new(goals);
+        self.deltas = DeltaTracker::default();
+        self.peaks.reset_session();
+        self.keys_by_provider.clear();
+        self.notice = None;
+        self.scroll = 0;
+        self.selected = 0;
+        self.longest_model = 0;
+    }
+
     pub async fn run(
         mut self,
-        mut event_rx: mpsc::Receiver<RequestEvent>,
-        archive_tx: mpsc::Sender<RequestEvent>,
+        event_rx: mpsc::Receiver<ProxyEvent>,
+        archive_tx: mpsc::Sender<ArchiveEntry>,
+        cache_tx: mpsc::Sender<RequestEvent>,
+        proxy: ShutdownHandle,
+        shutdown: ShutdownTimeouts,
+    ) -> Result<()> {
+        let mut screen = Screen::Terminal(setup_terminal()?);
+        let term = std::env::var("TERM").ok();
+        if self.config.set_terminal_title && statusline::supports_title(term.as_deref()) {
+            self.title = Some(TerminalTitle::save(&mut io::stdout())?);
+        }
+        let result = self
+            .run_in(&mut screen, event_rx, archive_tx, cache_tx, proxy, shutdown)
+            .await;
+        // Errors leave the terminal usable too, then get reported
+        if let Screen::Terminal(terminal) = &mut screen {
+            restore_terminal(terminal)?;
+        }
+        if let Some(title) = self.title.take() {
+            title.restore(&mut io::stdout())?;
+        }
+        result
+    }
+
+    /// Like `run`, with nothing drawn and no keys read, for running as a
+    /// service. Controls still apply, and SIGINT or SIGTERM quits.
+    pub async fn run_headless(
+        mut self,
+        event_rx: mpsc::Receiver<ProxyEvent>,
+        archive_tx: mpsc::Sender<ArchiveEntry>,
+        cache_tx: mpsc::Sender<RequestEvent>,
+        proxy: ShutdownHandle,
+        shutdown: ShutdownTimeouts,
     ) -> Result<()> {
-        let mut terminal = setup_terminal()?;
+        let mut screen = Screen::Headless;
+        self.log_requests = true;
+        self.run_in(&mut screen, event_rx, archive_tx, cache_tx, proxy, shutdown)
+            .await
+    }
 
+    async fn run_in(
+        &mut self,
+        screen: &mut Screen,
+        mut event_rx: mpsc::Receiver<ProxyEvent>,
+        archive_tx: mpsc::Sender<ArchiveEntry>,
+        cache_tx: mpsc::Sender<RequestEvent>,
+        proxy: ShutdownHandle,
+        shutdown: ShutdownTimeouts,
+    ) -> Result<()> {
         let tick_rate = Duration::from_millis(1000 / self.config.refresh_rate_hz as u64);
         let mut last_tick = Instant::now();
+        let mut signal = std::pin::pin!(shutdown_signal());
+        // Closed straight away when nothing sends controls
+        let mut controls = self.controls.take().unwrap_or_else(|| mpsc::channel(1).1);
 
         loop {
             // Draw UI
-            terminal.draw(|f| self.render(f))?;
+            screen.draw(|f| self.render(f))?;
 
             // Handle events with timeout
             let timeout = tick_rate.saturating_sub(last_tick.elapsed());
 
             tokio::select! {
                 // Check for new events from proxy
-                Some(req_event) = event_rx.recv() => {
-                    self.add_request(&req_event);
-                    // Forward to archive writer
-                    let _ = archive_tx.send(req_event).await;
+                Some(proxy_event) = event_rx.recv() => {
+                    self.forward(proxy_event, &archive_tx, &cache_tx).await;
                 }
 
+                Some(control) = controls.recv() => self.control(control, &archive_tx),
+
                 // Check for keyboard input
                 _ = tokio::time::sleep(timeout) => {
-                    if event::poll(Duration::ZERO)? {
+                    if screen.reads_keys() && event::poll(Duration::ZERO)? {
                         if let Event::Key(key) = event::read()? {
-                            if key.kind == KeyEventKind::Press {
-                                match key.code {
-                                    KeyCode::Char('q') | KeyCode::Esc => {
-                                        break;
-                                    }
-                                    KeyCode::Char('c') if key.modifiers.contains(crossterm::event::KeyModifiers::CONTROL) => {
-                                        break;
-                                    }
-                                    _ => {}
-                                }
+                            if key.kind == KeyEventKind::Press && self.handle_key(key) {
+                                break;
+                            }
+                            if let Some(draft) = self.editing.take() {
+                                self.edit_and_resend(screen, draft)?;
                             }
                         }
                     }
                 }
+
+                // `kill` or a Ctrl-C that didn't come through the terminal
+                _ = &mut signal => break,
             }
 
             if last_tick.elapsed() >= tick_rate {
                 last_tick = Instant::now();
+                self.poll_search();
+                self.expire_in_flight(chrono::Utc::now());
+                self.check_schema_drift(Instant::now());
+                self.check_cache_hints(Instant::now());
+                self.publish_status();
+                if self.models_saved.elapsed() >= MODELS_SAVE_INTERVAL {
+                    self.save_models();
+                }
+            }
+        }
+
+        proxy.shutdown();
+        self.finish_requests(
+            screen,
+            &mut event_rx,
+            &archive_tx,
+            &cache_tx,
+            shutdown.grace,
+        )
+        .await?;
+        self.save_models();
+        drop(archive_tx);
+        drop(cache_tx);
+        let flushed = self.flush_archive(screen, shutdown.archive_flush).await;
+        tracing::info!("Session complete: {}", self.stats_summary());
+        flushed
+    }
+
+    /// Apply a control, whether from a key or a signal, and log what it did
+    pub fn control(&mut self, control: Control, archive_tx: &mpsc::Sender<ArchiveEntry>) {
+        match control {
+            Control::Flush => {
+                tracing::info!("Session stats: {}", self.stats_summary());
+                if archive_tx.try_send(ArchiveEntry::Flush).is_err() {
+                    tracing::warn!("Archive queue is full; not flushing it");
+                }
+                self.notice = Some(("archive flushed".to_string(), Instant::now()));
+            }
+            Control::ToggleArchive => self.toggle_archiving(),
+            Control::Reload => {
+                let notice = match self.reloader.as_ref().map(Reloader::reload) {
+                    Some(Ok(())) => "config reloaded".to_string(),
+                    Some(Err(e)) => {
+                        tracing::warn!("Ignoring config reload: {:#}", e);
+                        format!("config not reloaded: {:#}", e)
+                    }
+                    None => {
+                        tracing::info!("No config file to reload");
+                        "no config file to reload".to_string()
+                    }
+                };
+                self.notice = Some((notice, Instant::now()));
+            }
+        }
+    }
+
+    fn toggle_archiving(&mut self) {
+        self.archiving = !self.archiving;
+        let state = if self.archiving { "resumed" } else { "paused" };
+        tracing::info!("Archiving {}", state);
+        self.notice = Some((format!("archiving {}", state), Instant::now()));
+    }
+
+    /// Requests, tokens and archive writes so far, for the log
+    fn stats_summary(&self) -> String {
+        let requests: usize = self.by_provider.values().map(|stats| stats.requests).sum();
+        let archive = self.archive_metrics.snapshot();
+        let mut written = archive
+            .formats
+            .iter()
+            .map(|(format, counters)| format!("{} {}", counters.written, format))
+            .collect::<Vec<_>>()
+            .join(", ");
+        if written.is_empty() {
+            written = "nothing".to_string();
+        }
+        let mut summary = format!(
+            "{} requests, {} input and {} output tokens, {} in flight; archived {}, {} pending",
+            requests,
+            format_number(self.tokens.total()),
+            format_number(self.output_tokens),
+            self.in_flight.len(),
+            written,
+            archive.backlog
+        );
+        if let Some(peak) = self.peaks.session() {
+            summary.push_str(&format!("; {} ({})", peak, peak.model));
+        }
+        if let Some(lifetime) = self
+            .peaks
+            .lifetime()
+            .filter(|l| Some(*l) != self.peaks.session())
+        {
+            summary.push_str(&format!(", lifetime {}", lifetime));
+        }
+        summary
+    }
+
+    /// Session totals, for `sherlock statusline` and the terminal title
+    fn status(&self) -> Status {
+        Status {
+            requests: self.by_provider.values().map(|stats| stats.requests).sum(),
+            input_tokens: self.tokens.total(),
+            output_tokens: self.output_tokens,
+            cost_usd: self.cost_usd,
+            archive: Some(self.archive_metrics.snapshot()),
+            ..Status::default()
+        }
+    }
+
+    fn publish_status(&mut self) {
+        let status = self.status();
+        if let Some(title) = &mut self.title {
+            if let Err(e) = title.update(&mut io::stdout(), &status.line(), Instant::now()) {
+                tracing::debug!("Couldn't set the terminal title: {}", e);
+            }
+        }
+        self.metrics.set_status(status);
+    }
+
+    /// Apply a proxy event and pass what it completed on to the archive
+    /// writer and the caching analysis
+    async fn forward(
+        &mut self,
+        proxy_event: ProxyEvent,
+        archive_tx: &mpsc::Sender<ArchiveEntry>,
+        cache_tx: &mpsc::Sender<RequestEvent>,
+    ) {
+        if let ProxyEvent::Marker(marker) = &proxy_event {
+            if self.archiving {
+                let _ = archive_tx.send(ArchiveEntry::Marker(marker.clone())).await;
+            }
+        }
+        if let ProxyEvent::Failed {
+            event: Some(event), ..
+        } = &proxy_event
+        {
+            // The request made it upstream, so it's archived like any other
+            if self.archiving && !event.unarchived {
+                let _ = archive_tx.send(ArchiveEntry::Request(event.clone())).await;
+            }
+        }
+        if let Some(failure) = self.failure(&proxy_event) {
+            if self.log_requests {
+                tracing::warn!("{}", failure_line(&failure));
+            }
+            if self.archiving {
+                let _ = archive_tx.send(ArchiveEntry::Failure(failure)).await;
+            }
+        }
+        if let Some(req_event) = self.handle_event(proxy_event) {
+            if self.log_requests {
+                tracing::info!("{}", request_line(&req_event));
+            }
+            // Caching advice is best effort; skip requests while it catches up
+            let _ = cache_tx.try_send(req_event.clone());
+            // Forward to archive writer
+            if self.archiving && !req_event.unarchived {
+                let _ = archive_tx.send(req_event.into()).await;
+            }
+        }
+    }
+
+    /// Once the proxy has stopped accepting, keep taking events until the
+    /// connections still open have finished or `grace` runs out, so requests
+    /// under way at quit are archived too
+    async fn finish_requests(
+        &mut self,
+        screen: &mut Screen,
+        event_rx: &mut mpsc::Receiver<ProxyEvent>,
+        archive_tx: &mpsc::Sender<ArchiveEntry>,
+        cache_tx: &mpsc::Sender<RequestEvent>,
+        grace: Duration,
+    ) -> Result<()> {
+        let deadline = Instant::now() + grace;
+        loop {
+            let open = self.metrics.health().open_connections;
+            if open == 0 || Instant::now() >= deadline {
+                break;
+            }
+            screen.status(&format!("Waiting for {} open connections…", open))?;
+            tokio::select! {
+                Some(proxy_event) = event_rx.recv() => {
+                    self.forward(proxy_event, archive_tx, cache_tx).await;
+                }
+                _ = tokio::time::sleep(FLUSH_POLL) => {}
+            }
+        }
+        // Requests finish just before their connection closes
+        while let Ok(proxy_event) = event_rx.try_recv() {
+            self.forward(proxy_event, archive_tx, cache_tx).await;
+        }
+        Ok(())
+    }
+
+    /// Show the archive catching up after quitting, until the writer is done
+    /// or `timeout` passes and it saves the rest for the next start
+    async fn flush_archive(&self, screen: &mut Screen, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        self.archive_metrics.begin_shutdown(deadline);
+        // The writer checks the deadline between events, then saves the queue
+        while !self.archive_metrics.is_finished() && Instant::now() < deadline + FLUSH_GRACE {
+            let pending = self.archive_metrics.backlog();
+            screen.status(&format!("Flushing archive: {} pending…", pending))?;
+            tokio::time::sleep(FLUSH_POLL).await;
+        }
+        Ok(())
+    }
+
+    /// Apply a key press, returning true when the dashboard should quit.
+    /// Keys behave the same in every layout.
+    pub fn handle_key(&mut self, key: KeyEvent) -> bool {
+        if self.filter_input.is_some() {
+            return self.handle_filter_key(key);
+        }
+        if let Some(detail) = &mut self.detail {
+            if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+                return true;
+            }
+            if key.code == KeyCode::Char('E') {
+                match &self.resender {
+                    Some(_) => self.editing = Some(detail.draft()),
+                    None => {
+                        self.notice =
+                            Some(("can't resend from a replay".to_string(), Instant::now()))
+                    }
+                }
+                return false;
+            }
+            if detail.handle_key(key) {
+                self.detail = None;
+            }
+            return false;
+        }
+        if self.search.is_some() && self.handle_search_key(key) {
+            return false;
+        }
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => true,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => true,
+            KeyCode::Char('s') => {
+                self.show_spend = !self.show_spend;
+                false
+            }
+            KeyCode::Char('r') => {
+                self.show_reliability = !self.show_reliability;
+                false
+            }
+            KeyCode::Char('m') => {
+                self.show_models = !self.show_models;
+                false
+            }
+            KeyCode::Char('k') => {
+                self.show_keys = !self.show_keys;
+                false
+            }
+            KeyCode::Char('t') => {
+                self.show_throughput = !self.show_throughput;
+                false
+            }
+            KeyCode::Char('d') => {
+                self.show_changes = !self.show_changes;
+                false
+            }
+            KeyCode::Char('/') => {
+                let current = match &self.search {
+                    Some(search) => Some(search.query.clone()),
+                    None => self.filter.as_ref().map(|f| f.source().to_string()),
+                };
+                self.search_scope = self.search.as_ref().map(|search| search.scope);
+                self.filter_input = Some(current.unwrap_or_default());
+                false
+            }
+            KeyCode::Char(c @ '1'..='3') => {
+                let scope = &mut self.config.token_scope;
+                let bucket = match c {
+                    '1' => &mut scope.system,
+                    '2' => &mut scope.tools,
+                    _ => &mut scope.tool_results,
+                };
+                *bucket = !*bucket;
+                self.notice = Some((format!("counting: {}", scope.label()), Instant::now()));
+                false
+            }
+            KeyCode::Char('a') => {
+                self.toggle_archiving();
+                false
+            }
+            KeyCode::Char('w') => {
+                self.model_width = self.model_width.next();
+                self.notice = Some((
+                    format!("model column: {}", self.model_width.label()),
+                    Instant::now(),
+                ));
+                false
+            }
+            KeyCode::Left => {
+                self.hscroll_by(-HSCROLL_STEP);
+                false
+            }
+            KeyCode::Right => {
+                self.hscroll_by(HSCROLL_STEP);
+                false
+            }
+            KeyCode::Up => {
+                self.select_by(-1);
+                false
+            }
+            KeyCode::Down => {
+                self.select_by(1);
+                false
+            }
+            KeyCode::PageUp => {
+                let page = self.viewport.get().max(1) as isize;
+                self.scroll_by(-page);
+                self.select_by(-page);
+                false
+            }
+            KeyCode::PageDown => {
+                let page = self.viewport.get().max(1) as isize;
+                self.scroll_by(page);
+                self.select_by(page);
+                false
+            }
+            KeyCode::Home => {
+                self.select_by(isize::MIN);
+                false
+            }
+            KeyCode::End => {
+                self.select_by(isize::MAX);
+                false
+            }
+            KeyCode::Enter => {
+                self.open_detail();
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// Edit the filter line; Enter applies it (empty clears it), Esc discards the edit.
+    /// Tab turns it into a search, of prompts and then of responses too, which
+    /// starts over with every edit.
+    fn handle_filter_key(&mut self, key: KeyEvent) -> bool {
+        let Some(input) = &mut self.filter_input else {
+            return false;
+        };
+        match key.code {
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return true,
+            KeyCode::Char(c) => input.push(c),
+            KeyCode::Backspace => {
+                input.pop();
+            }
+            KeyCode::Tab => {
+                self.search_scope = match self.search_scope {
+                    None => Some(SearchScope::Prompts),
+                    Some(SearchScope::Prompts) => Some(SearchScope::Everything),
+                    Some(SearchScope::Everything) => None,
+                };
+            }
+            KeyCode::Esc => {
+                self.filter_input = None;
+                self.search = None;
+                return false;
+            }
+            KeyCode::Enter if self.search_scope.is_some() => {
+                if input.trim().is_empty() {
+                    self.search = None;
+                }
+                self.filter_input = None;
+                return false;
+            }
+            KeyCode::Enter => match Filter::parse(input) {
+                Ok(filter) => {
+                    self.filter = (!filter.is_empty()).then_some(filter);
+                    self.filter_input = None;
+                    self.scroll = 0;
+                    self.selected = 0;
+                }
+                Err(e) => self.notice = Some((format!("filter: {}", e), Instant::now())),
+            },
+            _ => return false,
+        }
+        self.restart_search();
+        false
+    }
+
+    /// Search the retained requests for what's typed after '/', replacing
+    /// (and so stopping) any search still running
+    fn restart_search(&mut self) {
+        self.search = match (self.search_scope, &self.filter_input) {
+            (Some(scope), Some(query)) => {
+                let rows = self.requests.iter().filter(|r| r.detail.is_some()).cloned();
+                Some(Search::start(query, scope, rows.collect()))
+            }
+            _ => None,
+        };
+    }
+
+    /// Move through search results and open them; false for keys the
+    /// results leave to the dashboard
+    fn handle_search_key(&mut self, key: KeyEvent) -> bool {
+        let Some(search) = &mut self.search else {
+            return false;
+        };
+        let page = self.viewport.get().max(1) as isize;
+        match key.code {
+            KeyCode::Up => search.select_by(-1),
+            KeyCode::Down => search.select_by(1),
+            KeyCode::PageUp => search.select_by(-page),
+            KeyCode::PageDown => search.select_by(page),
+            KeyCode::Home => search.select_by(isize::MIN),
+            KeyCode::End => search.select_by(isize::MAX),
+            KeyCode::Enter => {
+                let hit = search.selected_hit();
+                self.detail = hit.and_then(|hit| {
+                    let view = DetailView::open_at(hit, &search.query)?;
+                    let view = view.with_cache_hint(cache_hint(&self.metrics, &hit.info));
+                    Some(view.with_prices(&self.prices))
+                });
             }
+            KeyCode::Esc => self.search = None,
+            _ => return false,
         }
+        true
+    }
 
-        restore_terminal(&mut terminal)?;
+    /// Hand the terminal to `$EDITOR` for `draft`, then send what was saved
+    /// through the proxy
+    fn edit_and_resend(&mut self, screen: &mut Screen, draft: Draft) -> Result<()> {
+        let Some(resender) = &self.resender else {
+            return Ok(());
+        };
+        let editor = std::env::var("EDITOR").unwrap_or_else(|_| "vi".to_string());
+        let edited =
+            screen.suspended(|| resend::edit(&draft.body, &editor, &std::env::temp_dir()))?;
+        let prepared = edited.and_then(|edited| {
+            edited
+                .map(|edited| resender.prepare(&draft, &edited))
+                .transpose()
+        });
+        let notice = match prepared {
+            Ok(Some(request)) => {
+                self.awaiting_manual = true;
+                tokio::spawn(async move {
+                    // Read to the end, so the proxy sees the response finish
+                    let result = match request.send().await {
+                        Ok(response) => response.bytes().await.map(drop),
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = result {
+                        tracing::warn!("Resending the request failed: {}", e);
+                    }
+                });
+                "resent; its response opens here".to_string()
+            }
+            Ok(None) => "emptied in the editor, so not resent".to_string(),
+            Err(e) => format!("not resent: {:#}", e),
+        };
+        self.notice = Some((notice, Instant::now()));
         Ok(())
     }
 
+    /// Take the hits a running search has found since the last tick
+    pub fn poll_search(&mut self) {
+        if let Some(search) = &mut self.search {
+            search.poll();
+        }
+    }
+
+    /// The line being typed after '/', and what it does
+    fn input_line(&self, input: &str) -> Line<'_> {
+        let mode = match self.search_scope {
+            Some(scope) => format!("search {}", scope.label()),
+            None => "filter".to_string(),
+        };
+        Line::from(vec![
+            Span::styled(format!(" /{}_ ", input), Style::default().fg(Color::Cyan)),
+            Span::styled(
+                format!("{} · Tab changes ", mode),
+                Style::default().fg(Color::DarkGray),
+            ),
+        ])
+    }
+
+    /// Open the selected row in the detail view
+    fn open_detail(&mut self) {
+        let Some(row) = self.selection().checked_sub(self.in_flight.len()) else {
+            self.notice = Some((
+                "still in flight, details show once it completes".to_string(),
+                Instant::now(),
+            ));
+            return;
+        };
+        let detail = self.shown_requests().nth(row).map(|info| {
+            let view = DetailView::open(info)?;
+            let view = view.with_cache_hint(cache_hint(&self.metrics, info));
+            Some(view.with_prices(&self.prices))
+        });
+        match detail {
+            Some(Some(detail)) => self.detail = Some(detail),
+            Some(None) => {
+                self.notice = Some(("no request details for this row".to_string(), Instant::now()))
+            }
+            None => {}
+        }
+    }
+
+    /// The failure `event` reports for an in-flight request, for the archive index
+    fn failure(&self, event: &ProxyEvent) -> Option<RequestFailure> {
+        let ProxyEvent::Failed { id, error, .. } = event else {
+            return None;
+        };
+        let request = self.in_flight.iter().find(|r| r.id == *id)?;
+        RequestFailure::upstream(request, error, chrono::Utc::now())
+    }
+
+    /// Apply a proxy lifecycle event, returning the completed request for archiving
+    pub fn handle_event(&mut self, event: ProxyEvent) -> Option<RequestEvent> {
+        match event {
+            ProxyEvent::Uploading(request) | ProxyEvent::Started(request) => {
+                if request.provider == self_test::PROVIDER {
+                    return None;
+                }
+                let model = request.model.as_deref().unwrap_or("...");
+                self.longest_model = self.longest_model.max(2 + display_width(model));
+                // A finished upload becomes the forwarded request in place
+                match self.in_flight.iter_mut().find(|r| r.id == request.id) {
+                    Some(row) => *row = request,
+                    None => {
+                        self.in_flight.push(request);
+                        self.row_inserted(0);
+                    }
+                }
+                None
+            }
+            ProxyEvent::Completed { id, event } => {
+                let selected = self.take_in_flight(id).is_some_and(|(_, selected)| selected);
+                let event = *event?;
+                if event.self_test {
+                    self.self_test_seen = self_test::is_expected(&event);
+                    return None;
+                }
+                self.add_request(&event);
+                self.follow_completed(selected);
+                if event.manual && std::mem::take(&mut self.awaiting_manual) {
+                    self.detail = self.requests.front().and_then(|info| {
+                        let view = DetailView::open(info)?;
+                        let view = view.with_cache_hint(cache_hint(&self.metrics, info));
+                        Some(view.with_prices(&self.prices))
+                    });
+                }
+                Some(event)
+            }
+            ProxyEvent::Failed { id, error, .. } => {
+                if let Some((request, selected)) = self.take_in_flight(id) {
+                    let failure = RequestFailure::upstream(&request, &error, chrono::Utc::now());
+                    if let Some(failure) = failure {
+                        self.stats.record_outcome(Sample::from(&failure));
+                    }
+                    self.push_row(RequestInfo::failed(&request, error));
+                    self.follow_completed(selected);
+                }
+                None
+            }
+            ProxyEvent::SelfTest(result) => {
+                let result = result.and_then(|()| {
+                    if self.self_test_seen {
+                        Ok(())
+                    } else {
+                        Err("the proxy never reported the request".to_string())
+                    }
+                });
+                match result {
+                    Ok(()) => tracing::info!("Self-test passed"),
+                    Err(error) => {
+                        tracing::error!("Self-test failed: {}", error);
+                        self.self_test_error = Some(error);
+                    }
+                }
+                None
+            }
+            ProxyEvent::Marker(marker) => {
+                self.push_row(RequestInfo::from(&marker));
+                None
+            }
+            ProxyEvent::UpdateAvailable(latest) => {
+                tracing::info!("sherlock {} is available (running {})", latest, update::CURRENT);
+                self.update_available = Some(latest);
+                None
+            }
+            ProxyEvent::Tool(status) => {
+                match self.tools.iter_mut().find(|tool| tool.tag == status.tag) {
+                    Some(tool) => *tool = status,
+                    None => self.tools.push(status),
+                }
+                None
+            }
+        }
+    }
+
+    /// Turn requests that never completed (client gone, proxy error) into error rows
+    fn expire_in_flight(&mut self, now: chrono::DateTime<chrono::Utc>) {
+        let timeout = chrono::Duration::seconds(self.config.in_flight_timeout_secs as i64);
+        let expired: Vec<u64> = self
+            .in_flight
+            .iter()
+            .filter(|r| now - r.started_at > timeout)
+            .map(|r| r.id)
+            .collect();
+
+        for id in expired {
+            if let Some((request, selected)) = self.take_in_flight(id) {
+                self.push_row(RequestInfo::failed(&request, "timed out".to_string()));
+                self.follow_completed(selected);
+            }
+        }
+    }
+
+    /// Take request `id` out of the in-flight rows, and whether its row was
+    /// selected
+    fn take_in_flight(&mut self, id: u64) -> Option<(InFlightRequest, bool)> {
+        let pos = self.in_flight.iter().position(|r| r.id == id)?;
+        // In-flight rows are listed newest first
+        let row = self.in_flight.len() - 1 - pos;
+        let selected = self.selected > 0 && self.selected == row;
+        self.row_removed(row);
+        Some((self.in_flight.remove(pos), selected))
+    }
+
+    /// Move a selection off a finished in-flight row onto the completed row
+    /// it became, which sits just below the remaining in-flight ones
+    fn follow_completed(&mut self, selected: bool) {
+        if selected && self.requests.front().is_some_and(|r| self.shows(r)) {
+            self.selected = self.in_flight.len();
+        }
+    }
+
+    /// Show a one-time notice the first time a provider sends something unknown
+    fn check_schema_drift(&mut self, now: Instant) {
+        for (provider, counts) in self.metrics.schema_drift() {
+            if self.drift_notified.insert(provider.clone()) {
+                self.notice = Some((drift_notice(&provider, &counts.seen), now));
+            }
+        }
+        if self
+            .notice
+            .as_ref()
+            .is_some_and(|(_, shown)| now.duration_since(*shown) > NOTICE_DURATION)
+        {
+            self.notice = None;
+        }
+    }
+
+    /// Show a conversation's prompt caching hint the first time it appears
+    fn check_cache_hints(&mut self, now: Instant) {
+        for (conversation, hint) in self.metrics.cache_hints() {
+            if self.cache_notified.insert(conversation.clone()) {
+                self.notice = Some((format!("\"{}\": {}", conversation, hint), now));
+            }
+        }
+    }
+
+    /// Persist request counts for the known models registry
+    fn save_models(&mut self) {
+        if let Err(e) = self.models.save() {
+            tracing::warn!("Failed to save model registry: {:#}", e);
+        }
+        self.models_saved = Instant::now();
+    }
+
     fn add_request(&mut self, event: &RequestEvent) {
-        self.total_tokens += event.tokens as u64;
+        self.tokens += event.composition();
+        self.output_tokens += event.output.map_or(0, |output| output.tokens);
+        // A replay starts with an empty registry, so every model would look new
+        if event.model != "unknown"
+            && self.replay.is_none()
+            && self
+                .models
+                .record(&event.provider, &event.model, event.timestamp)
+        {
+            self.notice = Some((format!("new model seen: {}", event.model), Instant::now()));
+            self.save_models();
+        }
+        if let Some(served) = event.substituted_model() {
+            self.notice = Some((
+                format!("{} served {} for {}", capitalize(&event.provider), served, event.model),
+                Instant::now(),
+            ));
+        }
+        if let (Some(requested), Some(served)) = (event.tier_mismatch(), &event.served_tier) {
+            self.notice = Some((
+                format!(
+                    "{} served the {} tier for a {} request",
+                    capitalize(&event.provider),
+                    served,
+                    requested
+                ),
+                Instant::now(),
+            ));
+        }
+        if let Some(alert) = self.peaks.observe(event) {
+            tracing::warn!("{}", alert);
+            self.notice = Some((alert.to_string(), Instant::now()));
+        }
+        if let Some(overflow) = &event.context_overflow {
+            self.notice = Some((
+                format!("{} over its context window: {}", event.model, overflow.describe()),
+                Instant::now(),
+            ));
+        }
         self.last_provider = event.provider.clone();
+        self.stats
+            .record_request(event.tokens, event.key.as_ref(), event.repo.as_ref());
+        if let Some(sample) = Sample::of(event) {
+            self.stats.record_outcome(sample);
+        }
+        if let Some(throughput) = &event.throughput {
+            self.stats.record_throughput(&event.model, throughput);
+        }
+        if let Some(latency_ms) = event.latency_ms {
+            self.stats.record_latency(event.streaming, latency_ms);
+        }
+        if let Some(output) = event.output {
+            self.stats.output_tokens.record(output.tokens);
+        }
+        self.by_model.entry(event.model.clone()).or_default().record(event);
+        self.by_provider
+            .entry(event.provider.clone())
+            .or_default()
+            .record(event);
+        self.track_key(event);
+        match event.cost_usd {
+            Some(cost) => *self.cost_usd.get_or_insert(0.0) += cost,
+            None => self.unpriced += 1,
+        }
+        if let Some(cost) = event.cost_usd {
+            self.spend.record(
+                event.timestamp.with_timezone(&chrono::Local).naive_local(),
+                cost,
+            );
+        }
+        self.goals.record(
+            event.timestamp,
+            &chrono::Local,
+            event.tokens as u64,
+            event.cost_usd,
+        );
 
         if let Some(prompt) = event.last_user_message() {
             self.last_prompt = prompt.to_string();
         }
 
-        let info = RequestInfo::from(event);
+        let mut info = RequestInfo::from(event);
+        info.change = self.deltas.observe(event).map(|delta| delta.to_string());
+        self.push_row(info);
+    }
+
+    /// Raise a notice when a provider is used with more than one key in a session
+    fn track_key(&mut self, event: &RequestEvent) {
+        let Some(key) = &event.key else {
+            return;
+        };
+        let keys = self.keys_by_provider.entry(event.provider.clone()).or_default();
+        if keys.insert(key.fingerprint.clone(), key.label()).is_none() && keys.len() > 1 {
+            let labels: Vec<&str> = keys.values().map(String::as_str).collect();
+            self.notice = Some((
+                format!(
+                    "{} used {} different API keys this session: {}",
+                    event.provider,
+                    keys.len(),
+                    labels.join(", ")
+                ),
+                Instant::now(),
+            ));
+        }
+    }
+
+    fn push_row(&mut self, info: RequestInfo) {
+        let width = display_width(&model_prefix(&info)) + display_width(&info.model);
+        self.longest_model = self.longest_model.max(width);
+        let shown = self.shows(&info);
         self.requests.push_front(info);
+        // Completed rows follow the in-flight ones
+        if shown {
+            self.row_inserted(self.in_flight.len());
+        }
 
         // Keep only max_log_entries
         while self.requests.len() > self.config.max_log_entries {
@@ -110,19 +1169,90 @@ impl Dashboard {
         }
     }
 
-    fn render(&self, frame: &mut Frame) {
+    pub fn render(&self, frame: &mut Frame) {
+        match choose_layout(self.config.layout, frame.area()) {
+            LayoutKind::Full => self.render_full(frame),
+            LayoutKind::Compact => self.render_compact(frame),
+        }
+        if let Some(detail) = &self.detail {
+            detail.render(frame, frame.area());
+        }
+    }
+
+    fn render_full(&self, frame: &mut Frame) {
+        let spend_height = if self.show_spend { 3 } else { 0 };
+        let groups = if self.config.group_by_repo {
+            self.stats.by_repo.len()
+        } else {
+            self.stats.by_key.len()
+        };
+        let stats_height = 4
+            + groups.min(MAX_GROUP_ROWS) as u16
+            + self.stats.throughput.len().min(MAX_GROUP_ROWS) as u16
+            + self.sampled_histograms().count() as u16;
+        let reliability = self
+            .show_reliability
+            .then(|| ReliabilityReport::build(&self.stats.outcomes, &self.slo, chrono::Utc::now()));
+        let reliability_height = reliability
+            .as_ref()
+            .map_or(0, |report| 3 + report.providers.len().clamp(1, MAX_GROUP_ROWS) as u16);
+        let models = self.show_models.then(|| self.models_panel());
+        let models_height = models.as_ref().map_or(0, |(_, rows)| 3 + *rows as u16);
+        let tools_height = if self.tools.is_empty() { 0 } else { 3 };
         let chunks = Layout::vertical([
-            Constraint::Length(3),  // Header
-            Constraint::Length(5),  // Fuel gauge
-            Constraint::Min(10),    // Request log
-            Constraint::Length(6),  // Last prompt
+            Constraint::Length(3),                  // Header
+            Constraint::Length(tools_height),       // Autostarted tools
+            Constraint::Length(5),                  // Fuel gauge
+            Constraint::Length(spend_height),       // Spend projection
+            Constraint::Length(reliability_height), // Provider reliability
+            Constraint::Length(models_height),      // Per-model breakdown
+            Constraint::Length(stats_height),       // Distribution stats
+            Constraint::Min(10),                    // Request log
+            Constraint::Length(6),                  // Last prompt
         ])
         .split(frame.area());
 
         frame.render_widget(self.header(), chunks[0]);
-        frame.render_widget(self.fuel_gauge(), chunks[1]);
-        frame.render_widget(self.request_table(chunks[2]), chunks[2]);
-        frame.render_widget(self.prompt_panel(), chunks[3]);
+        if !self.tools.is_empty() {
+            frame.render_widget(self.tools_panel(), chunks[1]);
+        }
+        frame.render_widget(self.fuel_gauge(), chunks[2]);
+        if self.show_spend {
+            frame.render_widget(self.spend_panel(), chunks[3]);
+        }
+        if let Some(report) = reliability {
+            frame.render_widget(self.reliability_panel(report), chunks[4]);
+        }
+        if let Some((panel, _)) = models {
+            frame.render_widget(panel, chunks[5]);
+        }
+        frame.render_widget(self.stats_panel(), chunks[6]);
+        match &self.search {
+            Some(search) => self.render_search(search, frame, chunks[7]),
+            None => self.render_request_log(frame, chunks[7]),
+        }
+        frame.render_widget(self.prompt_panel(), chunks[8]);
+    }
+
+    /// One header line and a borderless request table, for small panes
+    fn render_compact(&self, frame: &mut Frame) {
+        let chunks = Layout::vertical([Constraint::Length(1), Constraint::Min(0)])
+            .split(frame.area());
+
+        let area = chunks[1];
+        let (model_width, table_width) = self.table_width(COMPACT_COLUMNS, area.width);
+        let offset = self.hscroll_offset(table_width, area.width);
+
+        frame.render_widget(
+            self.compact_header(hscroll_indicator(offset, table_width, area.width)),
+            chunks[0],
+        );
+        if let Some(search) = &self.search {
+            self.render_search(search, frame, area);
+            return;
+        }
+        let (table, mut state) = self.compact_table(area, model_width);
+        render_scrolled(table, &mut state, area, frame.buffer_mut(), table_width, offset);
     }
 
     fn header(&self) -> Paragraph<'_> {
@@ -135,80 +1265,1021 @@ impl Dashboard {
             )
         };
 
-        Paragraph::new(Line::from(vec![Span::styled(
+        let mut spans = vec![Span::styled(
             title,
             Style::default()
                 .fg(Color::Cyan)
                 .add_modifier(Modifier::BOLD),
-        )]))
-        .block(Block::default().borders(Borders::ALL))
-        .alignment(ratatui::layout::Alignment::Center)
+        )];
+        spans.extend(self.replay_status());
+        spans.extend(self.chaos_status());
+        spans.extend(self.proxy_status());
+        spans.extend(self.shaping_status());
+        spans.extend(self.self_test_status());
+        spans.extend(self.update_status());
+        spans.extend(self.peak_status());
+
+        Paragraph::new(Line::from(spans))
+            .block(Block::default().borders(Borders::ALL))
+            .alignment(ratatui::layout::Alignment::Center)
     }
 
-    fn fuel_gauge(&self) -> Gauge<'_> {
-        let percentage =
-            (self.total_tokens as f64 / self.config.token_limit as f64 * 100.0).min(100.0);
+    /// One line with each autostarted tool and its state
+    fn tools_panel(&self) -> Paragraph<'_> {
+        let mut spans = Vec::new();
+        for (i, tool) in self.tools.iter().enumerate() {
+            if i > 0 {
+                spans.push(Span::raw("  "));
+            }
+            let color = match &tool.state {
+                ToolState::Running { .. } => Color::Green,
+                state if state.is_failure() => Color::Red,
+                _ => Color::DarkGray,
+            };
+            spans.push(Span::styled(
+                tool.tag.as_str(),
+                Style::default().add_modifier(Modifier::BOLD),
+            ));
+            spans.push(Span::styled(
+                format!(" {}", tool.state),
+                Style::default().fg(color),
+            ));
+        }
+        Paragraph::new(Line::from(spans))
+            .block(Block::default().title(" Tools ").borders(Borders::ALL))
+    }
 
-        let color = if percentage < 50.0 {
-            Color::Green
-        } else if percentage < 80.0 {
-            Color::Yellow
-        } else {
-            Color::Red
-        };
+    /// Badge marking a replay, with where in the session it is
+    fn replay_status(&self) -> Option<Span<'_>> {
+        let status = self.replay.as_ref()?;
+        Some(Span::styled(
+            format!(" REPLAY {}", status),
+            Style::default()
+                .fg(Color::Black)
+                .bg(Color::Magenta)
+                .add_modifier(Modifier::BOLD),
+        ))
+    }
 
-        let label = format!(
-            "{} / {} tokens ({:.1}%)",
-            format_number(self.total_tokens),
-            format_number(self.config.token_limit),
-            percentage
-        );
+    /// Banner kept up while chaos mode injects faults
+    fn chaos_status(&self) -> Option<Span<'_>> {
+        let rules = self.chaos.as_ref()?;
+        Some(Span::styled(
+            format!(" CHAOS {}", rules),
+            Style::default()
+                .fg(Color::Black)
+                .bg(Color::Red)
+                .add_modifier(Modifier::BOLD),
+        ))
+    }
 
-        Gauge::default()
-            .block(
-                Block::default()
-                    .title(" Context Usage ")
-                    .borders(Borders::ALL),
-            )
+    /// Warning shown while the proxy is failing to accept or shedding connections
+    fn proxy_status(&self) -> Option<Span<'_>> {
+        let health = self.metrics.health();
+        if !health.degraded {
+            return None;
+        }
+        Some(Span::styled(
+            format!(
+                " PROXY DEGRADED ({} accept errors, {} shed)",
+                health.accept_errors, health.shed_connections
+            ),
+            Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+        ))
+    }
+
+    /// Throughput against the bandwidth limits, while any are set
+    fn shaping_status(&self) -> Option<Span<'_>> {
+        let status = self.metrics.shaping().status()?;
+        Some(Span::styled(
+            format!(" {}", status),
+            Style::default().fg(Color::Yellow),
+        ))
+    }
+
+    /// The session's largest request against its model's context window
+    fn peak_status(&self) -> Option<Span<'_>> {
+        let peak = self.peaks.session()?;
+        let color = match peak.percent() {
+            Some(percent) if percent >= 80.0 => Color::Red,
+            Some(percent) if percent >= 50.0 => Color::Yellow,
+            _ => Color::DarkGray,
+        };
+        Some(Span::styled(
+            format!(" {}", peak),
+            Style::default().fg(color),
+        ))
+    }
+
+    /// Quiet notice that a newer release is out
+    fn update_status(&self) -> Option<Span<'_>> {
+        let latest = self.update_available.as_ref()?;
+        Some(Span::styled(
+            format!(" {} available", latest),
+            Style::default().fg(Color::DarkGray),
+        ))
+    }
+
+    /// Warning kept up for the whole session when the startup self-test failed
+    fn self_test_status(&self) -> Option<Span<'_>> {
+        let error = self.self_test_error.as_ref()?;
+        Some(Span::styled(
+            format!(" self-test failed: {}", error),
+            Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+        ))
+    }
+
+    /// Tokens so far within the gauge's counting scope, plus the output
+    fn counted_tokens(&self) -> u64 {
+        self.config.token_scope.counted(&self.tokens) + self.output_tokens
+    }
+
+    /// Context usage percentage and its gauge color
+    fn usage(&self) -> (f64, Color) {
+        let percentage =
+            (self.counted_tokens() as f64 / self.config.token_limit as f64 * 100.0).min(100.0);
+
+        let color = if percentage < 50.0 {
+            Color::Green
+        } else if percentage < 80.0 {
+            Color::Yellow
+        } else {
+            Color::Red
+        };
+        (percentage, color)
+    }
+
+    fn compact_header(&self, hscroll: Option<String>) -> Paragraph<'_> {
+        let (percentage, color) = self.usage();
+
+        let mut spans = vec![Span::styled(
+            "SHERLOCK",
+            Style::default()
+                .fg(Color::Cyan)
+                .add_modifier(Modifier::BOLD),
+        )];
+        if !self.last_provider.is_empty() {
+            spans.push(Span::raw(format!(" {}", self.last_provider.to_uppercase())));
+        }
+        spans.extend(self.replay_status());
+        spans.extend(self.chaos_status());
+        spans.extend(self.proxy_status());
+        spans.extend(self.shaping_status());
+        spans.extend(self.self_test_status());
+        spans.extend(self.update_status());
+        spans.push(Span::raw(format!(
+            " | {} / {} tokens ",
+            format_number(self.counted_tokens()),
+            format_number(self.config.token_limit)
+        )));
+        spans.push(Span::styled(
+            format!("{:.1}%", percentage),
+            Style::default().fg(color).add_modifier(Modifier::BOLD),
+        ));
+        if let Some(peak) = self.peaks.session() {
+            spans.push(Span::raw(format!(" | {}", peak)));
+        }
+        if let Some(cost) = self.session_cost() {
+            spans.push(Span::raw(format!(" | {}", cost)));
+        }
+        if self.config.token_scope != TokenScope::default() {
+            spans.push(Span::styled(
+                format!(" counting: {}", self.config.token_scope.label()),
+                Style::default().fg(Color::DarkGray),
+            ));
+        }
+        if self.show_spend {
+            let p = self.spend.projection(chrono::Local::now().naive_local());
+            spans.push(Span::raw(format!(
+                " | month end ~{}",
+                format_cost(Some(p.month_end))
+            )));
+        }
+        if let Some(goal) = self.goal_title() {
+            spans.extend(goal.spans);
+        }
+        if let Some(input) = &self.filter_input {
+            spans.extend(self.input_line(input).spans);
+        } else if let Some(filter) = &self.filter {
+            spans.push(Span::raw(format!(" | filter: {}", filter.source())));
+        }
+        if let Some(hscroll) = hscroll {
+            spans.push(Span::styled(
+                format!(" {}", hscroll),
+                Style::default().fg(Color::DarkGray),
+            ));
+        }
+        if let Some((notice, _)) = &self.notice {
+            spans.push(Span::styled(
+                format!(" {}", notice),
+                Style::default().fg(Color::Yellow),
+            ));
+        }
+
+        Paragraph::new(Line::from(spans))
+    }
+
+    /// e.g. "$1.27", or "$1.27 + 3 unpriced"; unset until a priced request
+    /// completes
+    fn session_cost(&self) -> Option<String> {
+        let cost = format_cost(Some(self.cost_usd?));
+        Some(match self.unpriced {
+            0 => cost,
+            unpriced => format!("{} + {} unpriced", cost, unpriced),
+        })
+    }
+
+    fn fuel_gauge(&self) -> Gauge<'_> {
+        let (percentage, color) = self.usage();
+
+        let mut label = format!(
+            "{} / {} tokens ({:.1}%)",
+            format_number(self.counted_tokens()),
+            format_number(self.config.token_limit),
+            percentage
+        );
+        if let Some(cost) = self.session_cost() {
+            label.push_str(&format!(" · {}", cost));
+        }
+
+        let scope = format!(" Context Usage — counting: {} ", self.config.token_scope.label());
+        let mut block = Block::default().title(scope).borders(Borders::ALL);
+        if let Some(goal) = self.goal_title() {
+            block = block.title(goal);
+        }
+
+        Gauge::default()
+            .block(block)
             .gauge_style(Style::default().fg(color))
             .percent(percentage as u16)
             .label(label)
     }
 
-    fn request_table(&self, _area: Rect) -> Table<'_> {
-        let header = Row::new(vec!["Time", "Provider", "Model", "Tokens"])
-            .style(Style::default().add_modifier(Modifier::BOLD))
-            .bottom_margin(1);
+    /// In-flight requests first, then completed ones, newest first
+    /// Rows for the visible window of the request log (in-flight first, then
+    /// completed, newest first). Only `viewport` rows are built per frame, so
+    /// render cost doesn't grow with `max_log_entries`.
+    fn request_rows(&self, viewport: usize, model_width: u16) -> Vec<Row<'_>> {
+        let offset = self.scroll_offset(viewport);
+        let in_flight_skip = offset.min(self.in_flight.len());
+        let in_flight_take = viewport.min(self.in_flight.len() - in_flight_skip);
+        let now = chrono::Utc::now();
+        // Latency, status and the optional columns come from the completed request
+        let with_key = |cells: Vec<String>, info: Option<&RequestInfo>| {
+            let mut cells: Vec<TableCell> = cells.into_iter().map(TableCell::from).collect();
+            cells.push(TableCell::from(info.map_or(String::new(), |r| format_cost(r.cost_usd))));
+            let streaming = info.is_some_and(|r| r.streaming);
+            cells.push(latency_cell(info.and_then(|r| r.latency_ms), streaming));
+            cells.push(status_cell(info.and_then(|r| r.status)));
+            if self.show_changes {
+                let change = info.and_then(|r| r.change.clone());
+                cells.push(TableCell::from(change.unwrap_or_default()));
+            }
+            if self.show_throughput {
+                let throughput = info.and_then(|r| r.throughput).map(|t| format!("{:.0}", t));
+                cells.push(TableCell::from(throughput.unwrap_or_default()));
+            }
+            if self.show_keys {
+                let key = info.and_then(|r| r.key.clone());
+                cells.push(TableCell::from(key.unwrap_or_default()));
+            }
+            cells
+        };
+        let in_flight_rows = self.in_flight.iter().rev().skip(in_flight_skip).take(in_flight_take);
+        let in_flight_rows = in_flight_rows.map(|r| {
+            let elapsed = (now - r.started_at).to_std().unwrap_or_default();
+            let spinner = SPINNER_FRAMES[(elapsed.as_millis() / 100) as usize % SPINNER_FRAMES.len()];
+            let model = match &r.upload {
+                Some(upload) => model_cell("↑ ", &describe_upload(upload), model_width),
+                None => model_cell(
+                    &format!("{} ", spinner),
+                    r.model.as_deref().unwrap_or("..."),
+                    model_width,
+                ),
+            };
+            Row::new(with_key(
+                vec![
+                    r.started_at.format("%H:%M:%S").to_string(),
+                    capitalize(&r.provider),
+                    model,
+                    format!("{:.1}s", elapsed.as_secs_f64()),
+                    String::new(),
+                ],
+                None,
+            ))
+            .style(Style::default().fg(Color::Cyan))
+        });
 
-        let rows: Vec<Row> = self
-            .requests
-            .iter()
-            .map(|r| {
-                Row::new(vec![
+        let completed_rows = self
+            .shown_requests()
+            .skip(offset - in_flight_skip)
+            .take(viewport - in_flight_take);
+        let completed_rows = completed_rows.map(|r| match &r.error {
+            _ if r.marker.is_some() => Row::new(with_key(
+                vec![
+                    r.time.clone(),
+                    "───".to_string(),
+                    marker_rule(r.marker.as_deref().unwrap_or_default(), model_width),
+                    "───".to_string(),
+                    "───".to_string(),
+                ],
+                None,
+            ))
+            .style(Style::default().fg(Color::DarkGray)),
+            Some(error) => Row::new(with_key(
+                vec![
+                    r.time.clone(),
+                    r.provider.clone(),
+                    model_cell(&model_prefix(r), &r.model, model_width),
+                    truncate(error, 12),
+                    String::new(),
+                ],
+                Some(r),
+            ))
+            .style(Style::default().fg(Color::Red)),
+            None if r.chaos => Row::new(with_key(
+                vec![
+                    r.time.clone(),
+                    r.provider.clone(),
+                    model_cell(&model_prefix(r), &r.model, model_width),
+                    format_number(r.tokens as u64),
+                    output_cell(r.output),
+                ],
+                Some(r),
+            ))
+            .style(Style::default().fg(Color::LightRed).add_modifier(Modifier::BOLD)),
+            None if r.over_context => Row::new(with_key(
+                vec![
+                    r.time.clone(),
+                    r.provider.clone(),
+                    model_cell(&model_prefix(r), &r.model, model_width),
+                    format_number(r.tokens as u64),
+                    output_cell(r.output),
+                ],
+                Some(r),
+            ))
+            .style(Style::default().fg(Color::Red).add_modifier(Modifier::BOLD)),
+            None if r.aborted => Row::new(with_key(
+                vec![
+                    r.time.clone(),
+                    r.provider.clone(),
+                    model_cell(&model_prefix(r), &r.model, model_width),
+                    format_number(r.tokens as u64),
+                    output_cell(r.output),
+                ],
+                Some(r),
+            ))
+            .style(Style::default().fg(Color::Yellow)),
+            None if r.failover.is_some() => Row::new(with_key(
+                vec![
+                    r.time.clone(),
+                    r.provider.clone(),
+                    model_cell(&model_prefix(r), &r.model, model_width),
+                    format_number(r.tokens as u64),
+                    output_cell(r.output),
+                ],
+                Some(r),
+            ))
+            .style(Style::default().fg(Color::Blue)),
+            None if r.served_model.is_some() || r.tier_mismatch.is_some() => Row::new(with_key(
+                vec![
+                    r.time.clone(),
+                    r.provider.clone(),
+                    model_cell(&model_prefix(r), &r.model, model_width),
+                    format_number(r.tokens as u64),
+                    output_cell(r.output),
+                ],
+                Some(r),
+            ))
+            .style(Style::default().fg(Color::LightYellow)),
+            None if r.clamped => Row::new(with_key(
+                vec![
+                    r.time.clone(),
+                    r.provider.clone(),
+                    model_cell(&model_prefix(r), &r.model, model_width),
+                    format_number(r.tokens as u64),
+                    output_cell(r.output),
+                ],
+                Some(r),
+            ))
+            .style(Style::default().fg(Color::LightRed)),
+            None if r.flagged => Row::new(with_key(
+                vec![
+                    r.time.clone(),
+                    r.provider.clone(),
+                    model_cell(&model_prefix(r), &r.model, model_width),
+                    format_number(r.tokens as u64),
+                    output_cell(r.output),
+                ],
+                Some(r),
+            ))
+            .style(Style::default().fg(Color::Magenta)),
+            None => Row::new(with_key(
+                vec![
                     r.time.clone(),
                     r.provider.clone(),
-                    truncate(&r.model, 30),
+                    model_cell(&model_prefix(r), &r.model, model_width),
                     format_number(r.tokens as u64),
+                    output_cell(r.output),
+                ],
+                Some(r),
+            )),
+        });
+
+        in_flight_rows.chain(completed_rows).collect()
+    }
+
+    /// Completed requests passing the filter, newest first
+    fn shown_requests(&self) -> impl Iterator<Item = &RequestInfo> {
+        self.requests.iter().filter(|r| self.shows(r))
+    }
+
+    fn shows(&self, request: &RequestInfo) -> bool {
+        self.filter.as_ref().is_none_or(|f| f.matches(request))
+    }
+
+    /// Entries in the request log, in-flight and completed
+    fn log_len(&self) -> usize {
+        let shown = match self.filter {
+            Some(_) => self.shown_requests().count(),
+            None => self.requests.len(),
+        };
+        self.in_flight.len() + shown
+    }
+
+    /// The selected row, which may have dropped off the end of the log
+    fn selection(&self) -> usize {
+        self.selected.min(self.log_len().saturating_sub(1))
+    }
+
+    /// Scroll position clamped so the last page stays full and the selection
+    /// stays in view
+    fn scroll_offset(&self, viewport: usize) -> usize {
+        let selected = self.selection();
+        let first = selected.saturating_sub(viewport.saturating_sub(1));
+        self.scroll.clamp(first, selected).min(self.log_len().saturating_sub(viewport))
+    }
+
+    fn scroll_by(&mut self, delta: isize) {
+        let max = self.log_len().saturating_sub(self.viewport.get());
+        self.scroll = self.scroll.min(max).saturating_add_signed(delta).min(max);
+    }
+
+    /// Move the selection `delta` rows, scrolling only as far as it takes to
+    /// keep it in view
+    fn select_by(&mut self, delta: isize) {
+        let last = self.log_len().saturating_sub(1);
+        self.selected = self.selection().saturating_add_signed(delta).min(last);
+        self.scroll = self.scroll_offset(self.viewport.get());
+    }
+
+    /// A row went into the log at position `at`. Unless the selection is on
+    /// the newest row, it and the view stay on the rows they were showing.
+    fn row_inserted(&mut self, at: usize) {
+        if self.selected == 0 {
+            return;
+        }
+        if self.selected >= at {
+            self.selected += 1;
+        }
+        if self.scroll >= at {
+            self.scroll += 1;
+        }
+    }
+
+    /// The row at position `at` left the log; a selection on it passes to
+    /// the row after it
+    fn row_removed(&mut self, at: usize) {
+        if self.selected > at {
+            self.selected -= 1;
+        }
+        if self.scroll > at {
+            self.scroll -= 1;
+        }
+    }
+
+    /// Position of the selection among the `viewport` rows shown from `offset`
+    fn table_state(&self, offset: usize) -> TableState {
+        TableState::default().with_selected(self.selection().checked_sub(offset))
+    }
+
+    fn hscroll_by(&mut self, delta: isize) {
+        let max = self.hscroll_max.get();
+        self.hscroll = self.hscroll.min(max).saturating_add_signed(delta).min(max);
+    }
+
+    /// Horizontal scroll position for a table `width` columns wide shown in
+    /// `view` columns, remembering the limit for the arrow keys
+    fn hscroll_offset(&self, width: u16, view: u16) -> u16 {
+        let max = width.saturating_sub(view);
+        self.hscroll_max.set(max as usize);
+        self.hscroll.min(max as usize) as u16
+    }
+
+    fn render_request_log(&self, frame: &mut Frame, area: Rect) {
+        let header = self.table_header().bottom_margin(1);
+        let mut block = Block::default().borders(Borders::ALL);
+        let inner = block.inner(area);
+
+        // The header and its margin
+        let viewport = inner.height.saturating_sub(2) as usize;
+        self.viewport.set(viewport);
+        let (model_width, table_width) = self.table_width(FULL_COLUMNS, inner.width);
+        let hscroll = self.hscroll_offset(table_width, inner.width);
+        let rows = self.request_rows(viewport, model_width);
+
+        let offset = self.scroll_offset(viewport);
+        let total = self.log_len();
+        let mut count = if total > viewport {
+            format!(
+                "showing {}-{} of {}",
+                offset + 1,
+                (offset + viewport).min(total),
+                total
+            )
+        } else {
+            (total - self.in_flight.len()).to_string()
+        };
+        if let Some(filter) = &self.filter {
+            if total <= viewport {
+                count.push_str(&format!(" of {}", self.requests.len()));
+            }
+            count.push_str(&format!(" matching {}", filter.source()));
+        }
+        let mut title = if self.in_flight.is_empty() {
+            format!(" Request Log ({}", count)
+        } else {
+            format!(" Request Log ({}, {} in flight", count, self.in_flight.len())
+        };
+        title.push_str(") ");
+        if let Some(indicator) = hscroll_indicator(hscroll, table_width, inner.width) {
+            title.push_str(&format!("{} ", indicator));
+        }
+
+        block = block.title(title);
+        if let Some(archive) = self.archive_title() {
+            block = block.title(archive);
+        }
+        if let Some(input) = &self.filter_input {
+            block = block.title_bottom(self.input_line(input));
+        }
+        if let Some((notice, _)) = &self.notice {
+            block = block.title_bottom(Span::styled(
+                format!(" {} ", notice),
+                Style::default().fg(Color::Yellow),
+            ));
+        }
+
+        let table = Table::new(rows, self.column_widths(FULL_COLUMNS, model_width))
+            .header(header)
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+
+        frame.render_widget(block, area);
+        let mut state = self.table_state(offset);
+        render_scrolled(table, &mut state, inner, frame.buffer_mut(), table_width, hscroll);
+    }
+
+    /// One row per request the search matched, with a snippet of the first match
+    fn render_search(&self, search: &Search, frame: &mut Frame, area: Rect) {
+        let searching = if search.finished() { "" } else { ", searching…" };
+        let mut block = Block::default().borders(Borders::ALL).title(format!(
+            " Search {}: \"{}\" ({} request{}{}) ",
+            search.scope.label(),
+            search.query,
+            search.hits.len(),
+            if search.hits.len() == 1 { "" } else { "s" },
+            searching
+        ));
+        block = match &self.filter_input {
+            Some(input) => block.title_bottom(self.input_line(input)),
+            None => block.title_bottom(Span::styled(
+                " ↑/↓ select · Enter open · / edit · Esc close ",
+                Style::default().fg(Color::DarkGray),
+            )),
+        };
+        self.viewport.set(block.inner(area).height.saturating_sub(1) as usize);
+
+        let rows = search.hits.iter().map(|hit| {
+            let (snippet, found) = &hit.snippet;
+            let snippet = Line::from(vec![
+                Span::raw(&snippet[..found.start]),
+                Span::styled(
+                    &snippet[found.clone()],
+                    Style::default().fg(Color::Black).bg(Color::Yellow),
+                ),
+                Span::raw(&snippet[found.end..]),
+            ]);
+            Row::new(vec![
+                TableCell::from(hit.info.time.as_str()),
+                TableCell::from(hit.info.provider.as_str()),
+                TableCell::from(truncate_middle(&hit.info.model, 24)),
+                TableCell::from(hit.role.as_str()),
+                TableCell::from(hit.count.to_string()),
+                TableCell::from(snippet),
+            ])
+        });
+        let header = Row::new(["Time", "Provider", "Model", "In", "Hits", "Match"])
+            .style(Style::default().add_modifier(Modifier::BOLD));
+        let widths = [
+            Constraint::Length(8),
+            Constraint::Length(10),
+            Constraint::Length(24),
+            Constraint::Length(9),
+            Constraint::Length(4),
+            Constraint::Min(10),
+        ];
+        let table = Table::new(rows, widths)
+            .header(header)
+            .block(block)
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        let selected = search.selected_hit().map(|_| search.selected.min(search.hits.len() - 1));
+        let mut state = TableState::default().with_selected(selected);
+        frame.render_stateful_widget(table, area, &mut state);
+    }
+
+    fn table_header(&self) -> Row<'static> {
+        let mut titles = vec![
+            "Time", "Provider", "Model", "Tokens", "Out", "Cost", "Latency", "Status",
+        ];
+        if self.show_changes {
+            titles.push("Change");
+        }
+        if self.show_throughput {
+            titles.push("Tok/s");
+        }
+        if self.show_keys {
+            titles.push("Key");
+        }
+        Row::new(titles).style(Style::default().add_modifier(Modifier::BOLD))
+    }
+
+    /// Time, provider, token, output, cost, latency and status widths around
+    /// the model column
+    fn column_widths(
+        &self,
+        [time, provider, tokens, output, cost, latency, status]: [u16; 7],
+        model: u16,
+    ) -> Vec<Constraint> {
+        let mut widths = vec![
+            Constraint::Length(time),
+            Constraint::Length(provider),
+            Constraint::Length(model),
+            Constraint::Length(tokens),
+            Constraint::Length(output),
+            Constraint::Length(cost),
+            Constraint::Length(latency),
+            Constraint::Length(status),
+        ];
+        widths.extend(self.optional_columns().map(Constraint::Length));
+        widths
+    }
+
+    /// Widths of the change, throughput and key columns, when shown
+    fn optional_columns(&self) -> impl Iterator<Item = u16> {
+        [
+            (self.show_changes, 20),
+            (self.show_throughput, 6),
+            (self.show_keys, 8),
+        ]
+            .into_iter()
+            .filter_map(|(shown, width)| shown.then_some(width))
+    }
+
+    /// Model column width under the current preset, and the width of the
+    /// whole table, which may exceed `available` and scroll sideways
+    fn table_width(&self, columns: [u16; 7], available: u16) -> (u16, u16) {
+        let spacing = 7 + self.optional_columns().count() as u16;
+        let fixed = columns.iter().sum::<u16>() + self.optional_columns().sum::<u16>() + spacing;
+        let model = match self.model_width {
+            ModelWidth::Fit => available.saturating_sub(fixed),
+            ModelWidth::Narrow => 24,
+            ModelWidth::Wide => 48,
+            ModelWidth::Full => self.longest_model.min(u16::MAX as usize / 2) as u16,
+        }
+        .max(MIN_MODEL_WIDTH);
+        (model, fixed + model)
+    }
+
+    /// Borderless request table, with the selected row among those shown; the
+    /// last prompt follows the newest completed row
+    fn compact_table(&self, area: Rect, model_width: u16) -> (Table<'_>, TableState) {
+        let header = self.table_header();
+
+        let viewport = area.height.saturating_sub(1) as usize;
+        self.viewport.set(viewport);
+        let show_prompt = !self.requests.is_empty()
+            && self.filter.is_none()
+            && !self.last_prompt.is_empty()
+            && self.scroll_offset(viewport.saturating_sub(1)) == 0;
+
+        let viewport = viewport - usize::from(show_prompt && viewport > 0);
+        let mut rows = self.request_rows(viewport, model_width);
+        let mut state = self.table_state(self.scroll_offset(viewport));
+        if show_prompt && rows.len() > self.in_flight.len() {
+            if let Some(row) = state.selected().filter(|row| *row > self.in_flight.len()) {
+                state.select(Some(row + 1));
+            }
+            let prompt = self.last_prompt.split_whitespace().collect::<Vec<_>>().join(" ");
+            rows.insert(
+                self.in_flight.len() + 1,
+                Row::new(vec![
+                    String::new(),
+                    String::new(),
+                    format!("↳ {}", truncate(&prompt, self.config.prompt_preview_length)),
+                    String::new(),
+                    String::new(),
+                    String::new(),
+                    String::new(),
+                    String::new(),
                 ])
+                .style(Style::default().fg(Color::DarkGray)),
+            );
+        }
+
+        let table = Table::new(rows, self.column_widths(COMPACT_COLUMNS, model_width))
+            .header(header)
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        (table, state)
+    }
+
+    /// Archive writer progress, red once any write has failed
+    fn archive_title(&self) -> Option<Line<'_>> {
+        let archive = self.archive_metrics.snapshot();
+        let written: u64 = archive.formats.values().map(|c| c.written).sum();
+        let failures: u64 = archive.formats.values().map(|c| c.failures).sum();
+        if written == 0 && failures == 0 {
+            return None;
+        }
+
+        let mut spans = vec![Span::styled(
+            format!(" archived {} · backlog {} ", written, archive.backlog),
+            Style::default().fg(Color::DarkGray),
+        )];
+        if let Some(error) = archive.last_error.filter(|_| failures > 0) {
+            spans.push(Span::styled(
+                format!("{} failed: {} ", failures, truncate(&error, 40)),
+                Style::default().fg(Color::Red),
+            ));
+        }
+        Some(Line::from(spans).right_aligned())
+    }
+
+    /// Subtle daily goal indicator for the gauge border
+    fn goal_title(&self) -> Option<Line<'_>> {
+        let today = chrono::Local::now().date_naive();
+        let progress = self.goals.progress(today)?;
+        let streak = self.goals.streak(today, GOAL_WINDOW_DAYS)?;
+
+        let color = if progress.over() {
+            Color::Red
+        } else {
+            Color::DarkGray
+        };
+        let text = format!(" today {}, {}d streak ", progress, streak.current);
+        Some(Line::from(Span::styled(text, Style::default().fg(color))).right_aligned())
+    }
+
+    fn spend_panel(&self) -> Paragraph<'_> {
+        let p = self.spend.projection(chrono::Local::now().naive_local());
+        let model = if p.weighted { "hourly" } else { "linear" };
+
+        let mut text = format!(
+            "Today ~{} | Month to date {} | Month end ~{} ({})",
+            format_cost(Some(p.today)),
+            format_cost(Some(p.month_to_date)),
+            format_cost(Some(p.month_end)),
+            model
+        );
+        if self.unpriced > 0 {
+            text.push_str(&format!(" | {} unpriced not counted", self.unpriced));
+        }
+
+        Paragraph::new(text).block(
+            Block::default()
+                .title(" Spend (estimate) ")
+                .borders(Borders::ALL),
+        )
+    }
+
+    /// Per provider share of good requests this session, toggled with `r`
+    fn reliability_panel(&self, report: ReliabilityReport) -> Table<'_> {
+        let header = Row::new(vec![
+            String::new(),
+            "24h".to_string(),
+            report.window.clone(),
+            format!("errors ({})", report.window),
+            "worst hour".to_string(),
+        ])
+        .style(Style::default().add_modifier(Modifier::BOLD));
+        let rows = report.providers.iter().take(MAX_GROUP_ROWS).map(|provider| {
+            let worst = provider.worst_hour.as_ref().map(|worst| worst.to_string());
+            Row::new(vec![
+                capitalize(&provider.provider),
+                provider.day.describe(),
+                provider.window.describe(),
+                provider.window.problems(),
+                worst.unwrap_or_else(|| "-".to_string()),
+            ])
+        });
+        let title = format!(
+            " Reliability (ok within {:.1}s) ",
+            report.latency_ms as f64 / 1000.0
+        );
+        Table::new(
+            rows,
+            [
+                Constraint::Length(12),
+                Constraint::Length(16),
+                Constraint::Length(16),
+                Constraint::Length(28),
+                Constraint::Length(26),
+            ],
+        )
+        .header(header)
+        .block(Block::default().title(title).borders(Borders::ALL))
+    }
+
+    /// Tokens and requests per provider, then per model, most tokens first,
+    /// and how many rows that is
+    fn models_panel(&self) -> (Table<'_>, usize) {
+        let header = Row::new(vec!["", "Requests", "Tokens", "Output", "Share"])
+            .style(Style::default().add_modifier(Modifier::BOLD));
+        let total: u64 = self.by_provider.values().map(|stats| stats.tokens).sum();
+        let row = |name: String, stats: ModelStats| {
+            let share = match total {
+                0 => "-".to_string(),
+                total => format!("{:.0}%", stats.tokens as f64 * 100.0 / total as f64),
+            };
+            Row::new(vec![
+                name,
+                format_number(stats.requests as u64),
+                format_number(stats.tokens),
+                format_number(stats.output_tokens),
+                share,
+            ])
+        };
+
+        let mut rows: Vec<Row> = ranked(&self.by_provider)
+            .into_iter()
+            .take(MAX_GROUP_ROWS)
+            .map(|(provider, stats)| {
+                row(capitalize(provider), stats).style(Style::default().fg(Color::Cyan))
             })
             .collect();
+        let models = ranked(&self.by_model);
+        let hidden = models.len().saturating_sub(MAX_MODEL_ROWS);
+        rows.extend(
+            models
+                .into_iter()
+                .take(MAX_MODEL_ROWS)
+                .map(|(model, stats)| row(format!("  {}", model), stats)),
+        );
+        if rows.is_empty() {
+            let empty = Row::new(vec!["No requests yet"]);
+            rows.push(empty.style(Style::default().fg(Color::DarkGray)));
+        }
 
-        Table::new(
+        let mut block = Block::default().title(" Models ").borders(Borders::ALL);
+        if hidden > 0 {
+            block = block.title(Line::from(format!(" +{} more ", hidden)).right_aligned());
+        }
+        let count = rows.len();
+        let table = Table::new(
             rows,
             [
+                Constraint::Min(20),
                 Constraint::Length(10),
                 Constraint::Length(12),
-                Constraint::Min(20),
                 Constraint::Length(12),
+                Constraint::Length(6),
             ],
         )
         .header(header)
-        .block(
-            Block::default()
-                .title(format!(" Request Log ({}) ", self.requests.len()))
-                .borders(Borders::ALL),
+        .block(block);
+        (table, count)
+    }
+
+    /// Output token and latency (in ms) rows of the distribution panel, for
+    /// those with samples
+    fn sampled_histograms(&self) -> impl Iterator<Item = (&'static str, &Histogram)> {
+        [
+            ("Output", &self.stats.output_tokens),
+            ("Latency stream", &self.stats.streaming_latency),
+            ("Latency sync", &self.stats.sync_latency),
+        ]
+        .into_iter()
+        .filter(|(_, hist)| hist.percentiles().is_some())
+    }
+
+    fn stats_panel(&self) -> Table<'_> {
+        let header = Row::new(vec!["", "p50", "p90", "p99"])
+            .style(Style::default().add_modifier(Modifier::BOLD));
+
+        let percentile_row = |label: String, hist: &Histogram| {
+            match hist.percentiles() {
+                Some(p) => Row::new(vec![
+                    label,
+                    format_number(p.p50),
+                    format_number(p.p90),
+                    format_number(p.p99),
+                ]),
+                None => Row::new(vec![label, "-".into(), "-".into(), "-".into()]),
+            }
+        };
+
+        let mut rows = vec![percentile_row("Tokens".to_string(), &self.stats.request_tokens)];
+        if self.config.group_by_repo {
+            rows.extend(
+                self.stats
+                    .by_repo
+                    .iter()
+                    .take(MAX_GROUP_ROWS)
+                    .map(|(repo, hist)| percentile_row(format!("Repo {}", repo), hist)),
+            );
+        } else {
+            rows.extend(
+                self.stats
+                    .by_key
+                    .values()
+                    .take(MAX_GROUP_ROWS)
+                    .map(|key| percentile_row(format!("Key {}", key.label), &key.request_tokens)),
+            );
+        }
+        rows.extend(
+            self.stats
+                .throughput
+                .iter()
+                .take(MAX_GROUP_ROWS)
+                .map(|(model, hist)| {
+                    percentile_row(format!("{} t/s", truncate_middle(model, 12)), hist)
+                }),
+        );
+        rows.extend(
+            self.sampled_histograms()
+                .map(|(label, hist)| percentile_row(label.to_string(), hist)),
+        );
+
+        let mut block = Block::default()
+            .title(" Distribution ")
+            .borders(Borders::ALL);
+
+        let parse_errors = self.metrics.parse_errors();
+        if !parse_errors.is_empty() {
+            let summary = parse_errors
+                .iter()
+                .map(|(kind, count)| format!("{} {}", kind, count))
+                .collect::<Vec<_>>()
+                .join(", ");
+            block = block.title(
+                Line::from(Span::styled(
+                    format!(" parse errors: {} ", summary),
+                    Style::default().fg(Color::Yellow),
+                ))
+                .right_aligned(),
+            );
+        }
+
+        if let Some(summary) = self.metrics.cache_summary().describe() {
+            block = block.title_bottom(
+                Line::from(Span::styled(
+                    format!(" {} ", summary),
+                    Style::default().fg(Color::Yellow),
+                ))
+                .right_aligned(),
+            );
+        }
+
+        if let Some(mix) = language::describe(&self.metrics.languages(), 4) {
+            block = block.title_bottom(Line::from(format!(" {} ", mix)).left_aligned());
+        }
+
+        let policy_matches = self.metrics.policy_matches();
+        if !policy_matches.is_empty() {
+            let summary = policy_matches
+                .iter()
+                .map(|(name, count)| format!("{} {}", name, count))
+                .collect::<Vec<_>>()
+                .join(", ");
+            block = block.title_bottom(
+                Line::from(Span::styled(
+                    format!(" policy: {} ", summary),
+                    Style::default().fg(Color::Magenta),
+                ))
+                .right_aligned(),
+            );
+        }
+
+        Table::new(
+            rows,
+            [
+                Constraint::Length(16),
+                Constraint::Length(12),
+                Constraint::Length(12),
+                Constraint::Length(12),
+            ],
         )
-        .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+        .header(header)
+        .block(block)
     }
 
     fn prompt_panel(&self) -> Paragraph<'_> {
@@ -229,44 +2300,399 @@ impl Dashboard {
     }
 }
 
-fn setup_terminal() -> Result<Terminal<CrosstermBackend<Stdout>>> {
-    enable_raw_mode()?;
-    let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen)?;
-    let backend = CrosstermBackend::new(stdout);
-    let terminal = Terminal::new(backend)?;
-    Ok(terminal)
+/// Most per-key or per-repo rows shown in the distribution panel
+const MAX_GROUP_ROWS: usize = 4;
+
+/// Most models listed in the models panel, below the providers
+const MAX_MODEL_ROWS: usize = 6;
+
+/// How long quitting waits for open connections, then for the archive writer
+#[derive(Debug, Clone, Copy)]
+pub struct ShutdownTimeouts {
+    pub grace: Duration,
+    pub archive_flush: Duration,
 }
 
-fn restore_terminal(terminal: &mut Terminal<CrosstermBackend<Stdout>>) -> Result<()> {
-    disable_raw_mode()?;
-    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
-    terminal.show_cursor()?;
-    Ok(())
+/// A completed request as logged headless, e.g. "14:03:22 anthropic
+/// claude-sonnet-4-5: 12,480 tokens in, 312 out, 200 in 4.2s"
+fn request_line(event: &RequestEvent) -> String {
+    let mut line = format!(
+        "{} {} {}: {} tokens in",
+        event
+            .timestamp
+            .with_timezone(&chrono::Local)
+            .format("%H:%M:%S"),
+        event.provider,
+        event.model,
+        format_number(event.tokens as u64)
+    );
+    if let Some(output) = event.output {
+        line.push_str(&format!(", {} out", format_number(output.tokens)));
+    }
+    if let Some(response) = event.response {
+        line.push_str(&format!(", {}", response.status));
+    }
+    if let Some(ms) = event.latency_ms {
+        line.push_str(&format!(" in {:.1}s", ms as f64 / 1000.0));
+    }
+    line
 }
 
-fn truncate(s: &str, max_len: usize) -> String {
-    // Single-pass truncation: collect up to max_len char boundaries
-    let trunc_at = max_len.saturating_sub(3);
-    let mut trunc_byte_idx = 0;
-    let mut end_byte_idx = 0;
+/// A failed request as logged headless, e.g. "14:03:22 openai gpt-4o
+/// failed after 1.2s: connection reset"
+fn failure_line(failure: &RequestFailure) -> String {
+    format!(
+        "{} {} {} failed after {:.1}s: {}",
+        failure
+            .timestamp
+            .with_timezone(&chrono::Local)
+            .format("%H:%M:%S"),
+        failure.provider,
+        failure.model.as_deref().unwrap_or("unknown model"),
+        failure.latency_ms as f64 / 1000.0,
+        failure.error
+    )
+}
 
-    for (i, (byte_idx, _)) in s.char_indices().enumerate() {
-        if i == trunc_at {
-            trunc_byte_idx = byte_idx;
-        }
-        if i == max_len {
-            // String exceeds max_len, truncate
-            return format!("{}...", &s[..trunc_byte_idx]);
+/// Where the dashboard draws: the terminal, or nowhere when headless
+enum Screen {
+    Terminal(Terminal<CrosstermBackend<Stdout>>),
+    Headless,
+}
+
+impl Screen {
+    fn draw(&mut self, render: impl FnOnce(&mut Frame)) -> Result<()> {
+        if let Screen::Terminal(terminal) = self {
+            terminal.draw(render)?;
         }
-        end_byte_idx = byte_idx;
+        Ok(())
     }
-    // Check if we need the last character's end
-    let _ = end_byte_idx;
-    s.to_string()
-}
 
-fn format_number(n: u64) -> String {
+    /// One centered line in place of the dashboard, e.g. while quitting
+    fn status(&mut self, text: &str) -> Result<()> {
+        match self {
+            Screen::Terminal(terminal) => {
+                terminal.draw(|f| {
+                    let area = f.area();
+                    let row = Rect::new(area.x, area.y + area.height / 2, area.width, 1);
+                    f.render_widget(Paragraph::new(text).centered(), row);
+                })?;
+            }
+            Screen::Headless => tracing::debug!("{}", text),
+        }
+        Ok(())
+    }
+
+    fn reads_keys(&self) -> bool {
+        matches!(self, Screen::Terminal(_))
+    }
+
+    /// Run `f` with the terminal back in its normal state, e.g. for an editor
+    fn suspended<T>(&mut self, f: impl FnOnce() -> T) -> Result<T> {
+        let Screen::Terminal(terminal) = self else {
+            return Ok(f());
+        };
+        disable_raw_mode()?;
+        execute!(terminal.backend_mut(), LeaveAlternateScreen, cursor::Show)?;
+        let result = f();
+        enable_raw_mode()?;
+        execute!(terminal.backend_mut(), EnterAlternateScreen)?;
+        terminal.clear()?;
+        Ok(result)
+    }
+}
+
+/// SIGINT or SIGTERM, so stopping sherlock from outside the terminal
+/// quits as cleanly as `q`
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+            return;
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// How long a notice stays on screen
+const NOTICE_DURATION: Duration = Duration::from_secs(30);
+
+/// The caching hint for `info`'s conversation, while it is leaving reusable
+/// tokens uncached
+fn cache_hint(metrics: &ProxyMetrics, info: &RequestInfo) -> Option<String> {
+    let conversation = info.detail.as_ref()?.conversation.as_ref()?;
+    metrics.cache_hints().remove(conversation)
+}
+
+/// How often request counts are flushed to the model registry
+const MODELS_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often the quit screen redraws, and how long past the flush deadline
+/// it waits for the writer to save what's left
+const FLUSH_POLL: Duration = Duration::from_millis(100);
+const FLUSH_GRACE: Duration = Duration::from_secs(2);
+
+/// Time, provider, token, output, cost, latency and status column widths in each layout
+const FULL_COLUMNS: [u16; 7] = [10, 12, 12, 8, 8, 8, 6];
+const COMPACT_COLUMNS: [u16; 7] = [8, 10, 10, 7, 7, 7, 6];
+
+/// Latencies drawn in yellow, and in red, from these on
+const SLOW_LATENCY_MS: u64 = 5_000;
+const VERY_SLOW_LATENCY_MS: u64 = 15_000;
+
+/// The model column never shrinks below this; narrower panes scroll instead
+const MIN_MODEL_WIDTH: u16 = 20;
+
+/// Columns moved per left/right key press
+const HSCROLL_STEP: isize = 4;
+
+/// Model column width presets, cycled with `w`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ModelWidth {
+    /// Whatever the other columns leave free
+    Fit,
+    Narrow,
+    Wide,
+    /// The longest model seen, never truncated
+    Full,
+}
+
+impl ModelWidth {
+    fn next(self) -> Self {
+        match self {
+            ModelWidth::Fit => ModelWidth::Narrow,
+            ModelWidth::Narrow => ModelWidth::Wide,
+            ModelWidth::Wide => ModelWidth::Full,
+            ModelWidth::Full => ModelWidth::Fit,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            ModelWidth::Fit => "fit",
+            ModelWidth::Narrow => "narrow",
+            ModelWidth::Wide => "wide",
+            ModelWidth::Full => "full",
+        }
+    }
+}
+
+/// Marker shown before a model name, e.g. "✗ aborted ", "↪ openai " or
+/// "[manual] "
+fn model_prefix(info: &RequestInfo) -> String {
+    match (&info.error, &info.failover) {
+        (Some(_), _) => "✗ ".to_string(),
+        (None, _) if info.manual => "[manual] ".to_string(),
+        (None, _) if info.chaos => "☢ ".to_string(),
+        (None, _) if info.over_context => "⚠ ".to_string(),
+        (None, _) if info.aborted => "✗ aborted ".to_string(),
+        (None, Some(failover)) => format!("↪ {} ", failover),
+        (None, None) if info.served_model.is_some() || info.tier_mismatch.is_some() => {
+            "≠ ".to_string()
+        }
+        (None, None) if info.clamped => "✂ ".to_string(),
+        (None, None) if info.flagged => "⚑ ".to_string(),
+        (None, None) => String::new(),
+    }
+}
+
+/// `prefix` then the model, shortened in the middle to fit `width` columns
+fn model_cell(prefix: &str, model: &str, width: u16) -> String {
+    let room = (width as usize).saturating_sub(display_width(prefix));
+    format!("{}{}", prefix, truncate_middle(model, room))
+}
+
+/// e.g. "3.2 MB multipart/form-data" for a request body still arriving
+fn describe_upload(upload: &UploadProgress) -> String {
+    match &upload.content_type {
+        Some(content_type) => format!("{} {}", format_bytes(upload.bytes), content_type),
+        None => format!("{} {} {}", format_bytes(upload.bytes), upload.method, upload.path),
+    }
+}
+
+/// e.g. "1.4s", or "S 0.3s" to the first byte of a stream; yellow when slow
+/// and red when very slow
+fn latency_cell(latency_ms: Option<u64>, streaming: bool) -> TableCell<'static> {
+    let Some(ms) = latency_ms else {
+        return TableCell::from("");
+    };
+    let marker = if streaming { "S " } else { "" };
+    let cell = TableCell::from(format!("{}{:.1}s", marker, ms as f64 / 1000.0));
+    match ms {
+        ms if ms >= VERY_SLOW_LATENCY_MS => cell.style(Style::default().fg(Color::Red)),
+        ms if ms >= SLOW_LATENCY_MS => cell.style(Style::default().fg(Color::Yellow)),
+        _ => cell,
+    }
+}
+
+/// e.g. "529", red for client and server errors
+fn status_cell(status: Option<u16>) -> TableCell<'static> {
+    match status {
+        Some(status) if status >= 400 => {
+            TableCell::from(status.to_string()).style(Style::default().fg(Color::Red))
+        }
+        Some(status) => TableCell::from(status.to_string()),
+        None => TableCell::from(""),
+    }
+}
+
+/// Output tokens, with "~" when sherlock counted them itself
+fn output_cell(output: Option<OutputTokens>) -> String {
+    match output {
+        Some(output) if output.estimated => format!("~{}", format_number(output.tokens)),
+        Some(output) => format_number(output.tokens),
+        None => String::new(),
+    }
+}
+
+/// A marker label drawn as a horizontal rule across the model column
+fn marker_rule(label: &str, width: u16) -> String {
+    let label = format!("── {} ", truncate(label, (width as usize).saturating_sub(4)));
+    let fill = (width as usize).saturating_sub(display_width(&label));
+    format!("{}{}", label, "─".repeat(fill))
+}
+
+/// e.g. "◀ cols 5-84 of 120 ▶" while a table is wider than its view
+fn hscroll_indicator(offset: u16, width: u16, view: u16) -> Option<String> {
+    if width <= view {
+        return None;
+    }
+    Some(format!(
+        "{}cols {}-{} of {}{}",
+        if offset > 0 { "◀ " } else { "" },
+        offset + 1,
+        offset + view,
+        width,
+        if offset + view < width { " ▶" } else { "" }
+    ))
+}
+
+/// Render `table` at its full `width`, showing the part of it that starts
+/// `offset` columns in when it's wider than `area`
+fn render_scrolled(
+    table: Table,
+    state: &mut TableState,
+    area: Rect,
+    buf: &mut Buffer,
+    width: u16,
+    offset: u16,
+) {
+    if area.is_empty() {
+        return;
+    }
+    if width <= area.width {
+        table.render(area, buf, state);
+        return;
+    }
+
+    let mut scratch = Buffer::empty(Rect::new(0, 0, width, area.height));
+    table.render(scratch.area, &mut scratch, state);
+    for y in 0..area.height {
+        for x in 0..area.width {
+            buf[(area.x + x, area.y + y)] = scratch[(offset + x, y)].clone();
+        }
+        // A double-width character cut by the right edge would spill over
+        let last = &mut buf[(area.right() - 1, area.y + y)];
+        if display_width(last.symbol()) > 1 {
+            last.set_symbol(" ");
+        }
+    }
+}
+
+/// e.g. "seen 2 unknown content types from anthropic: server_tool_use, …"
+fn drift_notice(provider: &str, drift: &SchemaDrift) -> String {
+    let plural = |n: usize, what: &str| {
+        format!("{} unknown {}{}", n, what, if n == 1 { "" } else { "s" })
+    };
+
+    let mut counts = Vec::new();
+    if !drift.content_types.is_empty() {
+        counts.push(plural(drift.content_types.len(), "content type"));
+    }
+    if !drift.fields.is_empty() {
+        counts.push(plural(drift.fields.len(), "field"));
+    }
+
+    let names: Vec<&str> = drift
+        .content_types
+        .iter()
+        .chain(&drift.fields)
+        .map(String::as_str)
+        .collect();
+    let mut list = names.iter().take(3).copied().collect::<Vec<_>>().join(", ");
+    if names.len() > 3 {
+        list.push_str(", …");
+    }
+
+    format!("seen {} from {}: {}", counts.join(" and "), provider, list)
+}
+
+/// Terminals shorter than this get the compact layout in `auto` mode
+const COMPACT_HEIGHT_THRESHOLD: u16 = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LayoutKind {
+    Full,
+    Compact,
+}
+
+fn choose_layout(mode: LayoutMode, area: Rect) -> LayoutKind {
+    match mode {
+        LayoutMode::Full => LayoutKind::Full,
+        LayoutMode::Compact => LayoutKind::Compact,
+        LayoutMode::Auto if area.height < COMPACT_HEIGHT_THRESHOLD => LayoutKind::Compact,
+        LayoutMode::Auto => LayoutKind::Full,
+    }
+}
+
+const SPINNER_FRAMES: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
+/// Thread drawing the TUI while it is up, whose panics must restore the
+/// terminal; panics in other threads leave it alone
+static TUI_THREAD: Mutex<Option<ThreadId>> = Mutex::new(None);
+
+/// Chain a panic hook that leaves raw mode and the alternate screen before
+/// the previous hook prints the message and backtrace
+fn install_panic_hook() {
+    static INSTALLED: Once = Once::new();
+    INSTALLED.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            let tui_thread = *TUI_THREAD.lock().unwrap_or_else(PoisonError::into_inner);
+            if tui_thread == Some(std::thread::current().id()) {
+                let _ = disable_raw_mode();
+                let _ = execute!(io::stdout(), LeaveAlternateScreen, cursor::Show);
+            }
+            previous(info);
+        }));
+    });
+}
+
+pub fn setup_terminal() -> Result<Terminal<CrosstermBackend<Stdout>>> {
+    install_panic_hook();
+    *TUI_THREAD.lock().unwrap_or_else(PoisonError::into_inner) = Some(std::thread::current().id());
+    enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    execute!(stdout, EnterAlternateScreen)?;
+    let backend = CrosstermBackend::new(stdout);
+    let terminal = Terminal::new(backend)?;
+    Ok(terminal)
+}
+
+pub fn restore_terminal(terminal: &mut Terminal<CrosstermBackend<Stdout>>) -> Result<()> {
+    *TUI_THREAD.lock().unwrap_or_else(PoisonError::into_inner) = None;
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+    Ok(())
+}
+
+pub fn format_number(n: u64) -> String {
     let s = n.to_string();
     let mut result = String::new();
     for (i, c) in s.chars().rev().enumerate() {
@@ -281,6 +2707,9 @@ fn format_number(n: u64) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::caching::PrefixReuse;
+    use crate::context::ContextOverflow;
+    use crate::parser::parse_request;
 
     #[test]
     fn test_format_number() {
@@ -290,9 +2719,1166 @@ mod tests {
         assert_eq!(format_number(1234567), "1,234,567");
     }
 
+    fn started(id: u64, started_at: chrono::DateTime<chrono::Utc>) -> ProxyEvent {
+        ProxyEvent::Started(InFlightRequest {
+            id,
+            provider: "anthropic".to_string(),
+            model: Some("claude-3".to_string()),
+            started_at,
+            upload: None,
+        })
+    }
+
+    #[test]
+    fn test_in_flight_lifecycle() {
+        let mut dashboard = Dashboard::new(
+            DashboardConfig::default(),
+            &GoalsConfig::default(),
+            Arc::new(ProxyMetrics::default()),
+            Arc::new(ArchiveMetrics::default()),
+            ModelRegistry::default(),
+            SloConfig::default(),
+        );
+        let now = chrono::Utc::now();
+
+        assert!(dashboard.handle_event(started(1, now)).is_none());
+        assert!(dashboard.handle_event(started(2, now)).is_none());
+        assert_eq!(dashboard.in_flight.len(), 2);
+
+        let event = RequestEvent {
+            timestamp: now,
+            id: 0,
+            provider: "anthropic".to_string(),
+            model: "claude-3".to_string(),
+            tokens: 42,
+            messages: vec![],
+            raw_body: serde_json::json!({}),
+            path: "/v1/messages".to_string(),
+            api_version: None,
+            streaming: false,
+            key: None,
+            client_aborted: true,
+            policy_matches: BTreeMap::new(),
+            failover: None,
+            recording: None,
+            repo: None,
+            overlay: None,
+            unarchived: false,
+            output_clamp: None,
+            chaos: None,
+            imported: false,
+            self_test: false,
+            manual: false,
+            throughput: None,
+            composition: None,
+            response: None,
+            output: None,
+            latency_ms: None,
+            timings: None,
+            context_overflow: None,
+            served_model: None,
+            service_tier: None,
+            served_tier: None,
+            cost_usd: None,
+            response_text: None,
+            auth: Default::default(),
+            cached_content: None,
+            gemini_usage: None,
+        };
+        let archived = dashboard.handle_event(ProxyEvent::Completed {
+            id: 2,
+            event: Some(Box::new(event)),
+        });
+        assert_eq!(archived.map(|e| e.tokens), Some(42));
+        assert_eq!(dashboard.in_flight.len(), 1);
+        assert_eq!(dashboard.in_flight[0].id, 1);
+
+        dashboard.handle_event(ProxyEvent::Failed {
+            id: 1,
+            error: "upstream error".to_string(),
+            event: None,
+        });
+        assert!(dashboard.in_flight.is_empty());
+        assert_eq!(dashboard.requests.len(), 2);
+        assert_eq!(dashboard.requests[0].error.as_deref(), Some("upstream error"));
+        // It never got an answer, so it shows the gateway error the client saw
+        assert_eq!(dashboard.requests[0].status, Some(502));
+        assert!(dashboard.requests[1].aborted);
+        assert_eq!(dashboard.counted_tokens(), 42);
+
+        // An upload keeps one row until it's forwarded or given up on
+        let uploading = |id, bytes| {
+            ProxyEvent::Uploading(InFlightRequest {
+                id,
+                provider: "anthropic".to_string(),
+                model: None,
+                started_at: now,
+                upload: Some(UploadProgress {
+                    method: "POST".to_string(),
+                    path: "/v1/files".to_string(),
+                    content_type: Some("multipart/form-data".to_string()),
+                    bytes,
+                }),
+            })
+        };
+        dashboard.handle_event(uploading(3, 1024));
+        dashboard.handle_event(uploading(3, 3 << 20));
+        dashboard.handle_event(uploading(4, 1024));
+        assert_eq!(dashboard.in_flight.len(), 2);
+        assert_eq!(
+            describe_upload(dashboard.in_flight[0].upload.as_ref().unwrap()),
+            "3.0 MB multipart/form-data"
+        );
+        dashboard.handle_event(started(3, now));
+        assert!(dashboard.in_flight[0].upload.is_none());
+        dashboard.handle_event(ProxyEvent::Failed {
+            id: 4,
+            error: "upload aborted".to_string(),
+            event: None,
+        });
+        assert_eq!(dashboard.in_flight.len(), 1);
+        assert_eq!(dashboard.requests.len(), 3);
+        // The provider never saw it, so it doesn't count against reliability
+        assert_eq!(dashboard.stats.outcomes.len(), 1);
+    }
+
+    #[test]
+    fn test_token_scope_toggles() {
+        let mut dashboard = Dashboard::new(
+            DashboardConfig::default(),
+            &GoalsConfig::default(),
+            Arc::new(ProxyMetrics::default()),
+            Arc::new(ArchiveMetrics::default()),
+            ModelRegistry::default(),
+            SloConfig::default(),
+        );
+        let mut event = crate::parser::minimal_event(b"hi", "/v1/messages", "anthropic");
+        event.tokens = 1800;
+        event.composition = Some(TokenComposition {
+            system: 1000,
+            tools: 300,
+            tool_results: 200,
+            conversation: 300,
+        });
+        dashboard.handle_event(ProxyEvent::Completed {
+            id: 1,
+            event: Some(Box::new(event)),
+        });
+        assert_eq!(dashboard.counted_tokens(), 1800);
+
+        let key = |c| KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE);
+        dashboard.handle_key(key('2'));
+        assert_eq!(dashboard.counted_tokens(), 1500);
+        dashboard.handle_key(key('1'));
+        dashboard.handle_key(key('3'));
+        assert_eq!(dashboard.counted_tokens(), 300);
+        assert_eq!(dashboard.config.token_scope.label(), "user+assistant only");
+
+        let mut terminal = Terminal::new(ratatui::backend::TestBackend::new(100, 40)).unwrap();
+        terminal.draw(|f| dashboard.render(f)).unwrap();
+        let screen: String = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect();
+        assert!(screen.contains("Context Usage — counting: user+assistant only"), "{}", screen);
+    }
+
+    #[test]
+    fn test_self_test_request_hidden_and_checked() {
+        let mut dashboard = Dashboard::new(
+            DashboardConfig::default(),
+            &GoalsConfig::default(),
+            Arc::new(ProxyMetrics::default()),
+            Arc::new(ArchiveMetrics::default()),
+            ModelRegistry::default(),
+            SloConfig::default(),
+        );
+        let body = serde_json::json!({
+            "model": "claude-3",
+            "messages": [{"role": "user", "content": "ping"}]
+        });
+        let body = serde_json::to_vec(&body).unwrap();
+        let mut event = parse_request(&body, "/v1/messages", self_test::PROVIDER).unwrap();
+        event.self_test = true;
+
+        let archived = dashboard.handle_event(ProxyEvent::Completed {
+            id: 1,
+            event: Some(Box::new(event)),
+        });
+        assert!(archived.is_none());
+        assert!(dashboard.requests.is_empty());
+        assert_eq!(dashboard.counted_tokens(), 0);
+
+        // The round trip worked, but the request didn't arrive as it was sent
+        dashboard.handle_event(ProxyEvent::SelfTest(Ok(())));
+        assert!(dashboard.self_test_status().is_some());
+    }
+
+    #[test]
+    fn test_orphaned_in_flight_expire() {
+        let mut dashboard = Dashboard::new(
+            DashboardConfig::default(),
+            &GoalsConfig::default(),
+            Arc::new(ProxyMetrics::default()),
+            Arc::new(ArchiveMetrics::default()),
+            ModelRegistry::default(),
+            SloConfig::default(),
+        );
+        let now = chrono::Utc::now();
+        let timeout = dashboard.config.in_flight_timeout_secs as i64;
+
+        dashboard.handle_event(started(1, now - chrono::Duration::seconds(timeout + 1)));
+        dashboard.handle_event(started(2, now - chrono::Duration::seconds(timeout - 1)));
+        dashboard.expire_in_flight(now);
+
+        assert_eq!(dashboard.in_flight.len(), 1);
+        assert_eq!(dashboard.in_flight[0].id, 2);
+        assert_eq!(dashboard.requests[0].error.as_deref(), Some("timed out"));
+    }
+
+    #[test]
+    fn test_choose_layout() {
+        let cases = [
+            // (width, height, auto layout)
+            (100, 12, LayoutKind::Compact),
+            (80, 23, LayoutKind::Compact),
+            (80, 24, LayoutKind::Full),
+            (200, 60, LayoutKind::Full),
+            (40, 50, LayoutKind::Full),
+            (300, 5, LayoutKind::Compact),
+            (0, 0, LayoutKind::Compact),
+        ];
+        for (width, height, expected) in cases {
+            let area = Rect::new(0, 0, width, height);
+            assert_eq!(choose_layout(LayoutMode::Auto, area), expected, "{}x{}", width, height);
+            assert_eq!(choose_layout(LayoutMode::Full, area), LayoutKind::Full);
+            assert_eq!(choose_layout(LayoutMode::Compact, area), LayoutKind::Compact);
+        }
+    }
+
     #[test]
-    fn test_truncate() {
-        assert_eq!(truncate("hello", 10), "hello");
-        assert_eq!(truncate("hello world", 8), "hello...");
+    fn test_compact_render_and_keys() {
+        use ratatui::backend::TestBackend;
+
+        let mut dashboard = Dashboard::new(
+            DashboardConfig::default(),
+            &GoalsConfig::default(),
+            Arc::new(ProxyMetrics::default()),
+            Arc::new(ArchiveMetrics::default()),
+            ModelRegistry::default(),
+            SloConfig::default(),
+        );
+        dashboard.last_prompt = "fix the\nbuild".to_string();
+        dashboard.add_request(&RequestEvent {
+            timestamp: chrono::Utc::now(),
+            id: 0,
+            provider: "anthropic".to_string(),
+            model: "claude-3".to_string(),
+            tokens: 42,
+            messages: vec![],
+            raw_body: serde_json::json!({}),
+            path: "/v1/messages".to_string(),
+            api_version: None,
+            streaming: false,
+            key: None,
+            client_aborted: false,
+            policy_matches: BTreeMap::new(),
+            failover: None,
+            recording: None,
+            repo: None,
+            overlay: None,
+            unarchived: false,
+            output_clamp: None,
+            chaos: None,
+            imported: false,
+            self_test: false,
+            manual: false,
+            throughput: None,
+            composition: None,
+            response: None,
+            output: None,
+            latency_ms: None,
+            timings: None,
+            context_overflow: None,
+            served_model: None,
+            service_tier: None,
+            served_tier: None,
+            cost_usd: Some(0.5),
+            response_text: None,
+            auth: Default::default(),
+            cached_content: None,
+            gemini_usage: None,
+        });
+
+        let key = |c| KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE);
+        assert!(!dashboard.handle_key(key('s')));
+        assert!(dashboard.show_spend);
+
+        let mut terminal = Terminal::new(TestBackend::new(100, 12)).unwrap();
+        terminal.draw(|f| dashboard.render(f)).unwrap();
+        let screen: Vec<String> = terminal
+            .backend()
+            .buffer()
+            .content()
+            .chunks(100)
+            .map(|line| line.iter().map(|cell| cell.symbol()).collect())
+            .collect();
+
+        assert!(screen[0].starts_with(
+            "SHERLOCK ANTHROPIC | 42 / 200,000 tokens 0.0% | peak 42 / 200k | $0.50 | month end ~$"
+        ));
+        assert!(screen[2].contains("claude-3"));
+        assert!(screen[3].contains("↳ fix the build"));
+        assert!(!screen.iter().any(|line| line.contains("Last Prompt")));
+
+        assert!(dashboard.handle_key(key('q')));
+        assert!(dashboard.handle_key(KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL)));
+    }
+
+    #[test]
+    fn test_marker_row() {
+        use ratatui::backend::TestBackend;
+
+        let mut dashboard = Dashboard::new(
+            DashboardConfig::default(),
+            &GoalsConfig::default(),
+            Arc::new(ProxyMetrics::default()),
+            Arc::new(ArchiveMetrics::default()),
+            ModelRegistry::default(),
+            SloConfig::default(),
+        );
+        let archived = dashboard.handle_event(ProxyEvent::Marker(crate::event::Marker {
+            label: "started refactor".to_string(),
+            timestamp: chrono::Utc::now(),
+            tag: Some("infra".to_string()),
+            session: None,
+        }));
+        assert!(archived.is_none());
+        assert_eq!(dashboard.counted_tokens(), 0);
+
+        let mut terminal = Terminal::new(TestBackend::new(110, 40)).unwrap();
+        terminal.draw(|f| dashboard.render(f)).unwrap();
+        let screen: String = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect();
+        assert!(screen.contains("── started refactor [infra] ──"), "{}", screen);
+    }
+
+    #[test]
+    fn test_controls_match_keys() {
+        let mut dashboard = Dashboard::new(
+            DashboardConfig::default(),
+            &GoalsConfig::default(),
+            Arc::new(ProxyMetrics::default()),
+            Arc::new(ArchiveMetrics::default()),
+            ModelRegistry::default(),
+            SloConfig::default(),
+        );
+        let (archive_tx, mut archive_rx) = mpsc::channel(4);
+
+        // `a` and SIGUSR2 flip the same switch
+        dashboard.handle_key(KeyEvent::new(KeyCode::Char('a'), KeyModifiers::NONE));
+        assert!(!dashboard.archiving);
+        dashboard.control(Control::ToggleArchive, &archive_tx);
+        assert!(dashboard.archiving);
+
+        dashboard.control(Control::Flush, &archive_tx);
+        assert!(matches!(archive_rx.try_recv(), Ok(ArchiveEntry::Flush)));
+
+        // Without a config file there's nothing to reload, which isn't fatal
+        dashboard.control(Control::Reload, &archive_tx);
+        let (notice, _) = dashboard.notice.as_ref().unwrap();
+        assert_eq!(notice, "no config file to reload");
+    }
+
+    #[test]
+    fn test_tools_strip() {
+        use crate::autostart::{ToolState, ToolStatus};
+        use ratatui::backend::TestBackend;
+
+        let mut dashboard = Dashboard::new(
+            DashboardConfig::default(),
+            &GoalsConfig::default(),
+            Arc::new(ProxyMetrics::default()),
+            Arc::new(ArchiveMetrics::default()),
+            ModelRegistry::default(),
+            SloConfig::default(),
+        );
+        let status = |tag: &str, state| {
+            ProxyEvent::Tool(ToolStatus {
+                tag: tag.to_string(),
+                state,
+            })
+        };
+        let running = |restarts| ToolState::Running {
+            pid: Some(7),
+            restarts,
+        };
+        dashboard.handle_event(status("claude", running(0)));
+        dashboard.handle_event(status("worker", running(0)));
+        dashboard.handle_event(status("worker", ToolState::Exited { code: Some(1) }));
+        dashboard.handle_event(status("worker", running(1)));
+        dashboard.handle_event(status("claude", ToolState::Exited { code: Some(0) }));
+        assert_eq!(dashboard.tools.len(), 2);
+
+        let mut terminal = Terminal::new(TestBackend::new(100, 50)).unwrap();
+        terminal.draw(|f| dashboard.render(f)).unwrap();
+        let screen: String = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect();
+        assert!(
+            screen.contains("claude exited 0  worker running, restart 1"),
+            "{}",
+            screen
+        );
+    }
+
+    #[test]
+    fn test_models_panel_outlives_the_log() {
+        use ratatui::backend::TestBackend;
+
+        let config = DashboardConfig {
+            max_log_entries: 2,
+            ..DashboardConfig::default()
+        };
+        let mut dashboard = Dashboard::new(
+            config,
+            &GoalsConfig::default(),
+            Arc::new(ProxyMetrics::default()),
+            Arc::new(ArchiveMetrics::default()),
+            ModelRegistry::default(),
+            SloConfig::default(),
+        );
+        let requests = [
+            ("anthropic", "claude-3-5-sonnet", 30_000),
+            ("anthropic", "claude-3-5-haiku", 2_000),
+            ("anthropic", "claude-3-5-sonnet", 40_000),
+            ("gemini", "gemini-2.5-pro", 50_000),
+            ("anthropic", "claude-3-5-haiku", 3_000),
+        ];
+        for (id, (provider, model, tokens)) in requests.into_iter().enumerate() {
+            let mut event = crate::parser::minimal_event(b"hi", "/v1/messages", provider);
+            event.model = model.to_string();
+            event.tokens = tokens;
+            dashboard.handle_event(ProxyEvent::Completed {
+                id: id as u64,
+                event: Some(Box::new(event)),
+            });
+        }
+        assert_eq!(dashboard.requests.len(), 2);
+        let sonnet = dashboard.by_model["claude-3-5-sonnet"];
+        assert_eq!((sonnet.requests, sonnet.tokens), (2, 70_000));
+        assert_eq!(dashboard.by_provider["anthropic"].tokens, 75_000);
+
+        let key = |c| KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE);
+        dashboard.handle_key(key('m'));
+        let mut terminal = Terminal::new(TestBackend::new(100, 50)).unwrap();
+        terminal.draw(|f| dashboard.render(f)).unwrap();
+        let screen: Vec<String> = terminal
+            .backend()
+            .buffer()
+            .content()
+            .chunks(100)
+            .map(|line| line.iter().map(|cell| cell.symbol()).collect())
+            .collect();
+        let row = |name: &str| {
+            screen
+                .iter()
+                .position(|line| line.contains(name))
+                .unwrap_or_else(|| panic!("no {} row in\n{}", name, screen.join("\n")))
+        };
+        // Providers first, then models, each with the most tokens on top
+        let order = [
+            row("Anthropic"),
+            row("Gemini "),
+            row("  claude-3-5-sonnet"),
+            row("  gemini-2.5-pro"),
+            row("  claude-3-5-haiku"),
+        ];
+        assert!(order.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", order);
+        assert!(screen[row("Anthropic")].contains("75,000"));
+        assert!(screen[row("  claude-3-5-haiku")].contains("4%"));
+
+        // A replay starting over clears them
+        dashboard.reset_session(&GoalsConfig::default());
+        assert!(dashboard.by_model.is_empty() && dashboard.by_provider.is_empty());
+    }
+
+    #[test]
+    fn test_filter_keys() {
+        let mut dashboard = Dashboard::new(
+            DashboardConfig::default(),
+            &GoalsConfig::default(),
+            Arc::new(ProxyMetrics::default()),
+            Arc::new(ArchiveMetrics::default()),
+            ModelRegistry::default(),
+            SloConfig::default(),
+        );
+        for (provider, model) in [("anthropic", "claude-3"), ("openai", "gpt-4o")] {
+            let body = serde_json::json!({
+                "model": model,
+                "messages": [{"role": "user", "content": "hello"}]
+            });
+            let body = serde_json::to_vec(&body).unwrap();
+            dashboard.add_request(&parse_request(&body, "/v1/messages", provider).unwrap());
+        }
+        let press = |dashboard: &mut Dashboard, code| {
+            dashboard.handle_key(KeyEvent::new(code, KeyModifiers::NONE))
+        };
+        let type_text = |dashboard: &mut Dashboard, text: &str| {
+            for c in text.chars() {
+                press(dashboard, KeyCode::Char(c));
+            }
+        };
+
+        press(&mut dashboard, KeyCode::Char('/'));
+        // 'q' is text while typing a filter, not quit
+        type_text(&mut dashboard, "model~gpq");
+        press(&mut dashboard, KeyCode::Backspace);
+        type_text(&mut dashboard, "t");
+        assert!(!press(&mut dashboard, KeyCode::Enter));
+        assert!(dashboard.filter_input.is_none());
+        assert_eq!(dashboard.log_len(), 1);
+        assert_eq!(dashboard.shown_requests().next().unwrap().model, "gpt-4o");
+
+        // A bad filter stays open for editing and keeps the old one applied
+        press(&mut dashboard, KeyCode::Char('/'));
+        type_text(&mut dashboard, " tokens>x");
+        press(&mut dashboard, KeyCode::Enter);
+        assert_eq!(dashboard.filter_input.as_deref(), Some("model~gpt tokens>x"));
+        let (notice, _) = dashboard.notice.as_ref().unwrap();
+        assert!(notice.contains("column 18"), "{}", notice);
+        press(&mut dashboard, KeyCode::Esc);
+        assert_eq!(dashboard.log_len(), 1);
+
+        press(&mut dashboard, KeyCode::Char('/'));
+        for _ in 0.."model~gpt".len() {
+            press(&mut dashboard, KeyCode::Backspace);
+        }
+        press(&mut dashboard, KeyCode::Enter);
+        assert!(dashboard.filter.is_none());
+        assert_eq!(dashboard.log_len(), 2);
+    }
+
+    #[test]
+    fn test_enter_opens_selected_request() {
+        use ratatui::backend::TestBackend;
+
+        let mut dashboard = Dashboard::new(
+            DashboardConfig::default(),
+            &GoalsConfig::default(),
+            Arc::new(ProxyMetrics::default()),
+            Arc::new(ArchiveMetrics::default()),
+            ModelRegistry::default(),
+            SloConfig::default(),
+        );
+        for prompt in ["first question", "second question"] {
+            let body = serde_json::json!({
+                "model": "claude-3",
+                "messages": [{"role": "user", "content": prompt}]
+            });
+            let body = serde_json::to_vec(&body).unwrap();
+            dashboard.add_request(&parse_request(&body, "/v1/messages", "anthropic").unwrap());
+        }
+        dashboard.handle_event(started(1, chrono::Utc::now()));
+        let press = |dashboard: &mut Dashboard, code| {
+            dashboard.handle_key(KeyEvent::new(code, KeyModifiers::NONE))
+        };
+
+        // The in-flight row has nothing to show yet
+        press(&mut dashboard, KeyCode::Enter);
+        assert!(!dashboard.captures_keys());
+        press(&mut dashboard, KeyCode::Down);
+        press(&mut dashboard, KeyCode::Down);
+        press(&mut dashboard, KeyCode::Enter);
+        assert!(dashboard.captures_keys());
+
+        let mut terminal = Terminal::new(TestBackend::new(80, 24)).unwrap();
+        terminal.draw(|f| dashboard.render(f)).unwrap();
+        let buffer = terminal.backend().buffer();
+        let text: String = buffer.content().iter().map(|cell| cell.symbol()).collect();
+        assert!(text.contains("first question"));
+        assert!(!text.contains("second question"));
+
+        // Keys belong to the open request until it closes
+        assert!(!press(&mut dashboard, KeyCode::Char('q')));
+        assert!(!dashboard.captures_keys());
+        assert_eq!(dashboard.selected, 2);
+    }
+
+    #[test]
+    fn test_edit_and_resend_keys() {
+        let mut dashboard = Dashboard::new(
+            DashboardConfig::default(),
+            &GoalsConfig::default(),
+            Arc::new(ProxyMetrics::default()),
+            Arc::new(ArchiveMetrics::default()),
+            ModelRegistry::default(),
+            SloConfig::default(),
+        );
+        let body = serde_json::json!({
+            "model": "claude-3",
+            "messages": [{"role": "user", "content": "first question"}]
+        });
+        let body = serde_json::to_vec(&body).unwrap();
+        let event = parse_request(&body, "/v1/messages", "anthropic").unwrap();
+        dashboard.add_request(&event);
+        let press = |dashboard: &mut Dashboard, code| {
+            dashboard.handle_key(KeyEvent::new(code, KeyModifiers::NONE))
+        };
+
+        // A replay has no proxy to send through
+        press(&mut dashboard, KeyCode::Enter);
+        press(&mut dashboard, KeyCode::Char('E'));
+        assert!(dashboard.editing.is_none());
+        assert_eq!(
+            dashboard.notice.as_ref().unwrap().0,
+            "can't resend from a replay"
+        );
+
+        let config = crate::config::Config::default();
+        dashboard.set_resender(Resender::new(&config.proxy, config.providers.clone()));
+        press(&mut dashboard, KeyCode::Char('E'));
+        let draft = dashboard.editing.take().unwrap();
+        assert_eq!(
+            (draft.provider.as_str(), draft.path.as_str()),
+            ("anthropic", "/v1/messages")
+        );
+        assert!(draft.body.contains("first question"));
+        press(&mut dashboard, KeyCode::Esc);
+        assert!(!dashboard.captures_keys());
+
+        // The resent request's response opens in place of the log
+        dashboard.awaiting_manual = true;
+        let mut resent = event.clone();
+        resent.manual = true;
+        dashboard.handle_event(ProxyEvent::Completed {
+            id: 1,
+            event: Some(Box::new(resent)),
+        });
+        assert!(dashboard.captures_keys());
+        assert!(!dashboard.awaiting_manual);
+        assert_eq!(model_prefix(&dashboard.requests[0]), "[manual] ");
+        assert_eq!(model_prefix(&dashboard.requests[1]), "");
+    }
+
+    #[test]
+    fn test_search_prompts_and_responses() {
+        use ratatui::backend::TestBackend;
+
+        let mut dashboard = Dashboard::new(
+            DashboardConfig::default(),
+            &GoalsConfig::default(),
+            Arc::new(ProxyMetrics::default()),
+            Arc::new(ArchiveMetrics::default()),
+            ModelRegistry::default(),
+            SloConfig::default(),
+        );
+        for (prompt, response) in [
+            ("Where is parse_body defined?", "In parser.rs"),
+            ("Thanks", "Shall I rename Parse_Body?"),
+            ("Unrelated", "Nothing to see"),
+        ] {
+            let body = serde_json::json!({
+                "model": "claude-3",
+                "messages": [{"role": "user", "content": prompt}]
+            });
+            let body = serde_json::to_vec(&body).unwrap();
+            let mut event = parse_request(&body, "/v1/messages", "anthropic").unwrap();
+            event.response_text = Some(response.to_string());
+            dashboard.add_request(&event);
+        }
+        let press = |dashboard: &mut Dashboard, code| {
+            dashboard.handle_key(KeyEvent::new(code, KeyModifiers::NONE))
+        };
+        let search = |dashboard: &mut Dashboard| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while !dashboard.search.as_ref().unwrap().finished() {
+                assert!(Instant::now() < deadline);
+                dashboard.poll_search();
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            let search = dashboard.search.as_ref().unwrap();
+            search.hits.iter().map(|hit| hit.role.clone()).collect::<Vec<_>>()
+        };
+
+        // Tab turns the filter line into a search of prompts, then of responses too
+        press(&mut dashboard, KeyCode::Char('/'));
+        press(&mut dashboard, KeyCode::Tab);
+        for c in "PARSE_BODY".chars() {
+            press(&mut dashboard, KeyCode::Char(c));
+        }
+        assert_eq!(search(&mut dashboard), ["user"]);
+        press(&mut dashboard, KeyCode::Tab);
+        assert_eq!(search(&mut dashboard), ["response", "user"]);
+        press(&mut dashboard, KeyCode::Enter);
+        assert!(dashboard.filter_input.is_none());
+
+        let mut terminal = Terminal::new(TestBackend::new(100, 40)).unwrap();
+        terminal.draw(|f| dashboard.render(f)).unwrap();
+        let buffer = terminal.backend().buffer();
+        let text: String = buffer.content().iter().map(|cell| cell.symbol()).collect();
+        assert!(text.contains("Search prompts + responses: \"PARSE_BODY\" (2 requests)"));
+        assert!(text.contains("Shall I rename Parse_Body?"));
+
+        // Enter opens the selected match; Esc closes it, then the results
+        press(&mut dashboard, KeyCode::Enter);
+        assert!(dashboard.detail.is_some());
+        press(&mut dashboard, KeyCode::Esc);
+        assert!(dashboard.detail.is_none() && dashboard.search.is_some());
+        assert!(!press(&mut dashboard, KeyCode::Esc));
+        assert!(dashboard.search.is_none());
+
+        // Back to a filter, the search stops
+        press(&mut dashboard, KeyCode::Char('/'));
+        press(&mut dashboard, KeyCode::Tab);
+        press(&mut dashboard, KeyCode::Char('x'));
+        assert!(dashboard.search.is_some());
+        press(&mut dashboard, KeyCode::Tab);
+        press(&mut dashboard, KeyCode::Tab);
+        assert!(dashboard.search.is_none());
+    }
+
+    #[test]
+    fn test_schema_drift_notice_once_per_provider() {
+        let metrics = Arc::new(ProxyMetrics::default());
+        let mut dashboard = Dashboard::new(
+            DashboardConfig::default(),
+            &GoalsConfig::default(),
+            Arc::clone(&metrics),
+            Arc::new(ArchiveMetrics::default()),
+            ModelRegistry::default(),
+            SloConfig::default(),
+        );
+        let start = Instant::now();
+        dashboard.check_schema_drift(start);
+        assert!(dashboard.notice.is_none());
+
+        let drift = SchemaDrift {
+            content_types: BTreeSet::from(["server_tool_use".to_string()]),
+            fields: BTreeSet::from(["container".to_string()]),
+        };
+        metrics.record_schema_drift("anthropic", &drift);
+        dashboard.check_schema_drift(start);
+        assert_eq!(
+            dashboard.notice.as_ref().map(|(n, _)| n.as_str()),
+            Some("seen 1 unknown content type and 1 unknown field from anthropic: server_tool_use, container")
+        );
+
+        // Expires, and more drift from the same provider doesn't bring it back
+        metrics.record_schema_drift("anthropic", &drift);
+        dashboard.check_schema_drift(start + NOTICE_DURATION + Duration::from_secs(1));
+        assert!(dashboard.notice.is_none());
+    }
+
+    #[test]
+    fn test_cache_hint_notice_once_per_conversation() {
+        let metrics = Arc::new(ProxyMetrics::default());
+        let mut dashboard = Dashboard::new(
+            DashboardConfig::default(),
+            &GoalsConfig::default(),
+            Arc::clone(&metrics),
+            Arc::new(ArchiveMetrics::default()),
+            ModelRegistry::default(),
+            SloConfig::default(),
+        );
+        let reuse = PrefixReuse {
+            reusable_tokens: 38_000,
+            cached_tokens: 0,
+            breakpoints: 0,
+        };
+        metrics.record_prefix_reuse("fix the build", &reuse);
+        dashboard.check_cache_hints(Instant::now());
+        assert_eq!(
+            dashboard.notice.take().map(|(n, _)| n),
+            Some(
+                "\"fix the build\": potential cache savings ~38k tok/request — \
+                 no cache breakpoints set"
+                    .to_string()
+            )
+        );
+
+        metrics.record_prefix_reuse("fix the build", &reuse);
+        dashboard.check_cache_hints(Instant::now());
+        assert!(dashboard.notice.is_none());
+        assert_eq!(
+            metrics.cache_summary().describe().as_deref(),
+            Some("cache: ~38k tok/req uncached on 2 of 2 requests")
+        );
+
+        // The detail view of a request in the conversation repeats the hint
+        let body = serde_json::json!({
+            "model": "claude-sonnet-4-5",
+            "messages": [{"role": "user", "content": "fix the build"}]
+        });
+        let event =
+            parse_request(body.to_string().as_bytes(), "/v1/messages", "anthropic").unwrap();
+        let hint = cache_hint(&metrics, &RequestInfo::from(&event));
+        assert!(hint.is_some_and(|hint| hint.starts_with("potential cache savings ~38k")));
+    }
+
+    #[test]
+    fn test_peak_alert_and_header() {
+        let mut dashboard = Dashboard::new(
+            DashboardConfig::default(),
+            &GoalsConfig::default(),
+            Arc::new(ProxyMetrics::default()),
+            Arc::new(ArchiveMetrics::default()),
+            ModelRegistry::default(),
+            SloConfig::default(),
+        );
+        let mut event = parse_request(
+            br#"{"model":"claude-sonnet-4-5","messages":[{"role":"user","content":"fix it"}]}"#,
+            "/v1/messages",
+            "anthropic",
+        )
+        .unwrap();
+        event.tokens = 187_000;
+        dashboard.add_request(&event);
+        assert_eq!(
+            dashboard.notice.as_ref().map(|(n, _)| n.as_str()),
+            Some("\"fix it\" reached 90% of claude-sonnet-4-5's context (peak 187k / 200k)")
+        );
+        event.tokens = 20_000;
+        dashboard.add_request(&event);
+        assert!(dashboard
+            .stats_summary()
+            .ends_with("; peak 187k / 200k (claude-sonnet-4-5)"));
+
+        let mut terminal = Terminal::new(ratatui::backend::TestBackend::new(120, 40)).unwrap();
+        terminal.draw(|f| dashboard.render(f)).unwrap();
+        let screen: String = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect();
+        assert!(screen.contains("peak 187k / 200k"));
+
+        // A replay starting over keeps the lifetime peak for the summary
+        dashboard.reset_session(&GoalsConfig::default());
+        dashboard.add_request(&event);
+        assert!(dashboard
+            .stats_summary()
+            .ends_with("; peak 20k / 200k (claude-sonnet-4-5), lifetime peak 187k / 200k"));
+    }
+
+    #[test]
+    fn test_new_model_notice() {
+        let mut dashboard = Dashboard::new(
+            DashboardConfig::default(),
+            &GoalsConfig::default(),
+            Arc::new(ProxyMetrics::default()),
+            Arc::new(ArchiveMetrics::default()),
+            ModelRegistry::default(),
+            SloConfig::default(),
+        );
+        let mut event = parse_request(
+            br#"{"model":"claude-sonnet-4-5","messages":[{"role":"user","content":"hi"}]}"#,
+            "/v1/messages",
+            "anthropic",
+        )
+        .unwrap();
+
+        dashboard.add_request(&event);
+        assert_eq!(
+            dashboard.notice.as_ref().map(|(n, _)| n.as_str()),
+            Some("new model seen: claude-sonnet-4-5")
+        );
+
+        dashboard.notice = None;
+        dashboard.add_request(&event);
+        assert!(dashboard.notice.is_none());
+        assert_eq!(dashboard.models.entries()["claude-sonnet-4-5"].requests, 2);
+
+        // Bodies that couldn't be parsed don't register a model
+        event.model = "unknown".to_string();
+        dashboard.add_request(&event);
+        assert!(dashboard.notice.is_none());
+
+        // Requests over their context window are called out, whatever else happened
+        event.model = "gpt-4".to_string();
+        event.context_overflow = Some(ContextOverflow {
+            tokens: Some(9_001),
+            limit: Some(8_192),
+            rejected: true,
+        });
+        dashboard.add_request(&event);
+        assert_eq!(
+            dashboard.notice.as_ref().map(|(n, _)| n.as_str()),
+            Some("gpt-4 over its context window: 9,001 of 8,192 tokens, rejected by the provider")
+        );
+        assert_eq!(model_prefix(&RequestInfo::from(&event)), "⚠ ");
+
+        // So are responses from a different model than requested, but not
+        // ones that only resolve an alias
+        event.context_overflow = None;
+        event.model = "claude-3-5-sonnet-latest".to_string();
+        event.served_model = Some("claude-3-5-sonnet-20241022".to_string());
+        dashboard.add_request(&event);
+        dashboard.notice = None;
+        dashboard.add_request(&event);
+        assert!(dashboard.notice.is_none());
+        event.served_model = Some("claude-3-5-haiku-20241022".to_string());
+        dashboard.add_request(&event);
+        assert_eq!(
+            dashboard.notice.as_ref().map(|(n, _)| n.as_str()),
+            Some("Anthropic served claude-3-5-haiku-20241022 for claude-3-5-sonnet-latest")
+        );
+        assert_eq!(model_prefix(&RequestInfo::from(&event)), "≠ ");
+    }
+
+    #[test]
+    fn test_large_log_builds_visible_rows_only() {
+        use ratatui::backend::TestBackend;
+
+        let config = DashboardConfig {
+            max_log_entries: 10_000,
+            ..DashboardConfig::default()
+        };
+        let mut dashboard = Dashboard::new(
+            config,
+            &GoalsConfig::default(),
+            Arc::new(ProxyMetrics::default()),
+            Arc::new(ArchiveMetrics::default()),
+            ModelRegistry::default(),
+            SloConfig::default(),
+        );
+        let now = chrono::Utc::now();
+        for i in 0..10_000 {
+            dashboard.push_row(RequestInfo {
+                time: "12:00:00".to_string(),
+                timestamp: now,
+                provider: "Anthropic".to_string(),
+                model: format!("model-{}", i),
+                tokens: i,
+                error: None,
+                key: None,
+                aborted: false,
+                flagged: false,
+                failover: None,
+                clamped: false,
+                manual: false,
+                chaos: false,
+                over_context: false,
+                served_model: None,
+                tier_mismatch: None,
+                output: None,
+                cost_usd: None,
+                latency_ms: None,
+                streaming: false,
+                status: None,
+                throughput: None,
+                prompt: None,
+                change: None,
+                marker: None,
+                detail: None,
+            });
+        }
+        dashboard.handle_event(started(1, now));
+
+        let mut terminal = Terminal::new(TestBackend::new(100, 40)).unwrap();
+        let mut screen = |dashboard: &Dashboard| -> String {
+            terminal.draw(|f| dashboard.render(f)).unwrap();
+            let buffer = terminal.backend().buffer();
+            buffer.content().iter().map(|cell| cell.symbol()).collect()
+        };
+
+        let text = screen(&dashboard);
+        let viewport = dashboard.viewport.get();
+        assert!(viewport > 0 && viewport < 40);
+        assert_eq!(dashboard.request_rows(viewport, 30).len(), viewport);
+        assert!(text.contains("model-9999"));
+
+        assert!(text.contains(&format!("showing 1-{} of 10001, 1 in flight", viewport)));
+
+        // The selection walks the logical list, in-flight row first, and
+        // scrolls once it passes the bottom row
+        for _ in 0..viewport {
+            dashboard.handle_key(KeyEvent::new(KeyCode::Down, KeyModifiers::NONE));
+        }
+        assert_eq!((dashboard.selected, dashboard.scroll), (viewport, 1));
+        assert_eq!(dashboard.request_rows(viewport, 30).len(), viewport);
+        let text = screen(&dashboard);
+        assert!(!text.contains("claude-3"));
+        assert!(text.contains("model-9999"));
+        assert!(text.contains(&format!("showing 2-{} of 10001", viewport + 1)));
+
+        // New entries move neither the selection nor the view off their rows
+        dashboard.handle_event(started(3, now));
+        assert_eq!((dashboard.selected, dashboard.scroll), (viewport + 1, 2));
+        dashboard.push_row(RequestInfo::failed(
+            &InFlightRequest {
+                id: 2,
+                provider: "openai".to_string(),
+                model: Some("gpt-4o".to_string()),
+                started_at: now,
+                upload: None,
+            },
+            "boom".to_string(),
+        ));
+        assert!(!screen(&dashboard).contains("gpt-4o"));
+        assert_eq!((dashboard.selected, dashboard.scroll), (viewport + 2, 3));
+        dashboard.handle_key(KeyEvent::new(KeyCode::Up, KeyModifiers::NONE));
+        assert_eq!(dashboard.scroll, 3);
+
+        dashboard.handle_key(KeyEvent::new(KeyCode::End, KeyModifiers::NONE));
+        let text = screen(&dashboard);
+        assert!(text.contains("model-1 "));
+        assert_eq!(dashboard.request_rows(viewport, 30).len(), viewport);
+
+        dashboard.handle_key(KeyEvent::new(KeyCode::Home, KeyModifiers::NONE));
+        assert!(screen(&dashboard).contains("gpt-4o"));
+
+        // On the newest row the view follows new entries
+        dashboard.handle_event(started(4, now));
+        assert_eq!((dashboard.selected, dashboard.scroll), (0, 0));
+        assert!(screen(&dashboard).contains("showing 1-"));
+    }
+
+    #[test]
+    fn test_model_width_presets_and_hscroll() {
+        use ratatui::backend::TestBackend;
+
+        let mut dashboard = Dashboard::new(
+            DashboardConfig::default(),
+            &GoalsConfig::default(),
+            Arc::new(ProxyMetrics::default()),
+            Arc::new(ArchiveMetrics::default()),
+            ModelRegistry::default(),
+            SloConfig::default(),
+        );
+        let model = "anthropic/claude-3.5-sonnet-20241022:beta-extended-thinking";
+        dashboard.push_row(RequestInfo {
+            time: "12:00:00".to_string(),
+            timestamp: chrono::Utc::now(),
+            provider: "Openrouter".to_string(),
+            model: model.to_string(),
+            tokens: 42,
+            error: None,
+            key: None,
+            aborted: false,
+            flagged: false,
+            failover: None,
+            clamped: false,
+            manual: false,
+            chaos: false,
+            over_context: false,
+            served_model: None,
+            tier_mismatch: None,
+            output: None,
+            cost_usd: None,
+            latency_ms: None,
+            streaming: false,
+            status: None,
+            throughput: None,
+            prompt: None,
+            change: None,
+            marker: None,
+            detail: None,
+        });
+
+        let mut terminal = Terminal::new(TestBackend::new(94, 40)).unwrap();
+        let mut screen = |dashboard: &Dashboard| -> String {
+            terminal.draw(|f| dashboard.render(f)).unwrap();
+            let buffer = terminal.backend().buffer();
+            buffer.content().iter().map(|cell| cell.symbol()).collect()
+        };
+        let key = |code| KeyEvent::new(code, KeyModifiers::NONE);
+
+        // Fit: the model column takes what's left and keeps both ends visible
+        let text = screen(&dashboard);
+        assert!(text.contains("anthropic/…d-thinking"));
+        assert!(!text.contains("cols "));
+
+        // Narrow is wider than what's left, so the table scrolls sideways
+        dashboard.handle_key(key(KeyCode::Char('w')));
+        assert_eq!(dashboard.model_width, ModelWidth::Narrow);
+        assert!(screen(&dashboard).contains("cols 1-92 of 95 ▶"));
+        dashboard.handle_key(key(KeyCode::Right));
+        dashboard.handle_key(key(KeyCode::Right));
+        assert!(screen(&dashboard).contains("◀ cols 4-95 of 95 "));
+        dashboard.handle_key(key(KeyCode::Left));
+        assert_eq!(dashboard.hscroll, 0);
+
+        // Full shows the whole name once scrolled to the end
+        dashboard.handle_key(key(KeyCode::Char('w')));
+        dashboard.handle_key(key(KeyCode::Char('w')));
+        assert_eq!(dashboard.model_width, ModelWidth::Full);
+        screen(&dashboard);
+        for _ in 0..20 {
+            dashboard.handle_key(key(KeyCode::Right));
+        }
+        let text = screen(&dashboard);
+        assert!(text.contains("extended-thinking"));
+        assert!(!text.contains("…"));
+
+        dashboard.handle_key(key(KeyCode::Char('w')));
+        assert_eq!(dashboard.model_width, ModelWidth::Fit);
+        assert!(!screen(&dashboard).contains("cols "));
+    }
+
+    #[test]
+    fn test_headless_request_lines() {
+        let body = serde_json::json!({
+            "model": "claude-sonnet-4-5",
+            "messages": [{"role": "user", "content": "Fix the build"}]
+        });
+        let body = serde_json::to_vec(&body).unwrap();
+        let mut event = parse_request(&body, "/v1/messages", "anthropic").unwrap();
+        let time = event
+            .timestamp
+            .with_timezone(&chrono::Local)
+            .format("%H:%M:%S")
+            .to_string();
+        event.tokens = 12_480;
+        assert_eq!(
+            request_line(&event),
+            format!("{} anthropic claude-sonnet-4-5: 12,480 tokens in", time)
+        );
+        event.output = Some(OutputTokens {
+            tokens: 312,
+            estimated: false,
+        });
+        event.response = Some(crate::event::ResponseInfo {
+            status: 200,
+            latency_ms: 900,
+        });
+        event.latency_ms = Some(4_200);
+        assert_eq!(
+            request_line(&event),
+            format!(
+                "{} anthropic claude-sonnet-4-5: 12,480 tokens in, 312 out, 200 in 4.2s",
+                time
+            )
+        );
+
+        let failure = RequestFailure {
+            timestamp: event.timestamp,
+            id: 2,
+            provider: "openai".to_string(),
+            model: None,
+            latency_ms: 1_240,
+            error: "connection reset".to_string(),
+        };
+        assert_eq!(
+            failure_line(&failure),
+            format!(
+                "{} openai unknown model failed after 1.2s: connection reset",
+                time
+            )
+        );
     }
 }
@@ -0,0 +1,477 @@
+//! How much of the prompt archive repeats content seen elsewhere in it, for
+//! `sherlock dedupe-report`. Every message and each request's tool
+//! definitions count as a blob, hashed by content. The scan reads the
+//! archive twice so memory stays bounded: a bloom filter over every blob
+//! finds the ones that may repeat, then only those are counted exactly.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+use crate::archive::{archived_requests, format_bytes, load_archived_request};
+use crate::dashboard::format_number;
+use crate::event::{RequestEvent, TokenComposition};
+use crate::text::truncate;
+
+/// Most repeated blobs listed in a report
+const TOP_BLOBS: usize = 10;
+
+/// Blobs the bloom filter is sized for per archived request
+const BLOBS_PER_REQUEST: usize = 256;
+
+/// Largest bloom filter, in 64-bit words (32 MiB); past that, more blobs
+/// only mean more false candidates to count exactly
+const MAX_BLOOM_WORDS: usize = 1 << 22;
+
+/// Blob kind of a request's tool definitions
+const TOOLS: &str = "tools";
+
+/// Which pass of the scan is reading the archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pass {
+    /// Finding blobs that may repeat
+    Candidates,
+    /// Counting them
+    Counting,
+}
+
+/// How far the scan has got through the archive's requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub pass: Pass,
+    pub done: usize,
+    pub total: usize,
+}
+
+/// Unique and repeated content across the whole archive
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DedupeReport {
+    pub directory: PathBuf,
+    pub requests: usize,
+    /// Files that couldn't be read or parsed
+    pub skipped: usize,
+    /// Tokens by bucket over all requests, as the dashboard gauge counts them
+    pub composition: TokenComposition,
+    /// Tokens of each blob's first appearance, plus what no blob holds
+    pub unique_tokens: u64,
+    /// Tokens of every later appearance
+    pub duplicated_tokens: u64,
+    pub content_bytes: u64,
+    /// What a blob store keeping each distinct blob once would save
+    pub duplicated_bytes: u64,
+    /// Blobs appearing most often, most first
+    pub top: Vec<RepeatedBlob>,
+    /// Blobs the bloom filter passed on to be counted exactly, repeated or not
+    pub candidates: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RepeatedBlob {
+    /// Role of the message it first appeared as, or "tools"
+    pub kind: String,
+    /// Start of its text
+    pub preview: String,
+    /// Tokens of its first appearance
+    pub tokens: u64,
+    pub bytes: u64,
+    pub occurrences: usize,
+}
+
+/// One appearance of a blob in a request
+struct Blob<'a> {
+    hash: u64,
+    kind: &'a str,
+    text: &'a str,
+    tokens: u64,
+}
+
+/// Scan the archive in `dir`, calling `progress` after each request read
+pub fn dedupe_report(dir: &Path, mut progress: impl FnMut(Progress)) -> Result<DedupeReport> {
+    let paths = archived_requests(dir)
+        .with_context(|| format!("Failed to read the archive in {:?}", dir))?;
+    let total = paths.len();
+    let mut report = DedupeReport {
+        directory: dir.to_path_buf(),
+        ..DedupeReport::default()
+    };
+
+    let mut bloom = Bloom::new(total.saturating_mul(BLOBS_PER_REQUEST));
+    let mut candidates: HashSet<u64> = HashSet::new();
+    for (done, path) in paths.iter().enumerate() {
+        if let Some(event) = load(path) {
+            let tools = tool_definitions(&event);
+            for blob in blobs(&event, &tools) {
+                if bloom.insert(blob.hash) {
+                    candidates.insert(blob.hash);
+                }
+            }
+        }
+        progress(Progress {
+            pass: Pass::Candidates,
+            done: done + 1,
+            total,
+        });
+    }
+
+    let mut tallies: HashMap<u64, RepeatedBlob> = HashMap::with_capacity(candidates.len());
+    for (done, path) in paths.iter().enumerate() {
+        match load(path) {
+            Some(event) => {
+                report.requests += 1;
+                report.composition += event.composition();
+                let tools = tool_definitions(&event);
+                let blobs = blobs(&event, &tools);
+                let attributed: u64 = blobs.iter().map(|blob| blob.tokens).sum();
+                report.unique_tokens += event.composition().total() - attributed;
+                for blob in blobs {
+                    let bytes = blob.text.len() as u64;
+                    report.content_bytes += bytes;
+                    if !candidates.contains(&blob.hash) {
+                        report.unique_tokens += blob.tokens;
+                        continue;
+                    }
+                    match tallies.get_mut(&blob.hash) {
+                        Some(tally) => {
+                            tally.occurrences += 1;
+                            report.duplicated_tokens += blob.tokens;
+                            report.duplicated_bytes += bytes;
+                        }
+                        None => {
+                            report.unique_tokens += blob.tokens;
+                            tallies.insert(blob.hash, blob.first_seen());
+                        }
+                    }
+                }
+            }
+            None => report.skipped += 1,
+        }
+        progress(Progress {
+            pass: Pass::Counting,
+            done: done + 1,
+            total,
+        });
+    }
+
+    report.candidates = tallies.len();
+    let mut repeated: Vec<RepeatedBlob> = tallies
+        .into_values()
+        .filter(|blob| blob.occurrences > 1)
+        .collect();
+    repeated.sort_by(|a, b| {
+        (b.occurrences, b.bytes, &a.preview).cmp(&(a.occurrences, a.bytes, &b.preview))
+    });
+    repeated.truncate(TOP_BLOBS);
+    report.top = repeated;
+    Ok(report)
+}
+
+fn load(path: &Path) -> Option<RequestEvent> {
+    match load_archived_request(path) {
+        Ok(event) => Some(event),
+        Err(e) => {
+            tracing::warn!("Skipping {:?}: {:#}", path, e);
+            None
+        }
+    }
+}
+
+/// The request's tool and function definitions as JSON, empty without any
+fn tool_definitions(event: &RequestEvent) -> String {
+    ["tools", "functions"]
+        .iter()
+        .filter_map(|field| event.raw_body.get(field))
+        .map(|definitions| definitions.to_string())
+        .collect()
+}
+
+/// The blobs of a request. Its tool definitions keep their own token count;
+/// the system prompt's tokens are split between the system messages by
+/// length, and the rest between the other messages, so a request's blobs
+/// add up to its composition less any bucket with no text to hold it.
+fn blobs<'a>(event: &'a RequestEvent, tools: &'a str) -> Vec<Blob<'a>> {
+    let composition = event.composition();
+    let mut blobs: Vec<Blob> = Vec::new();
+    if !tools.is_empty() {
+        blobs.push(Blob {
+            hash: hash(tools),
+            kind: TOOLS,
+            text: tools,
+            tokens: composition.tools,
+        });
+    }
+    let (system, rest): (Vec<_>, Vec<_>) = event
+        .messages
+        .iter()
+        .filter(|m| !m.content.is_empty())
+        .partition(|m| m.role == "system" || m.role == "developer");
+    let conversation = composition.tool_results + composition.conversation;
+    for (messages, tokens) in [(system, composition.system), (rest, conversation)] {
+        let first = blobs.len();
+        let chars: u64 = messages.iter().map(|m| m.content.len() as u64).sum();
+        for message in messages {
+            blobs.push(Blob {
+                hash: hash(&message.content),
+                kind: &message.role,
+                text: &message.content,
+                tokens: tokens * message.content.len() as u64 / chars,
+            });
+        }
+        // Rounding goes to the longest
+        let split: u64 = blobs[first..].iter().map(|blob| blob.tokens).sum();
+        if let Some(longest) = blobs[first..].iter_mut().max_by_key(|blob| blob.text.len()) {
+            longest.tokens += tokens - split;
+        }
+    }
+    blobs
+}
+
+fn hash(text: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    text.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl Blob<'_> {
+    /// The blob's tally, as of its first appearance
+    fn first_seen(&self) -> RepeatedBlob {
+        let first_line = self.text.lines().find(|line| !line.trim().is_empty());
+        RepeatedBlob {
+            kind: self.kind.to_string(),
+            preview: truncate(first_line.unwrap_or_default().trim(), 60),
+            tokens: self.tokens,
+            bytes: self.text.len() as u64,
+            occurrences: 1,
+        }
+    }
+}
+
+/// Bloom filter over 64-bit hashes, at about 10 bits per item for a 1%
+/// false positive rate
+struct Bloom {
+    words: Vec<u64>,
+}
+
+/// Bit positions probed per item
+const BLOOM_PROBES: u64 = 7;
+
+impl Bloom {
+    fn new(items: usize) -> Self {
+        let words = (items.saturating_mul(10) / 64).clamp(1024, MAX_BLOOM_WORDS);
+        Self {
+            words: vec![0; words],
+        }
+    }
+
+    /// Add `hash`, returning whether it may have been added before
+    fn insert(&mut self, hash: u64) -> bool {
+        let bits = self.words.len() as u64 * 64;
+        // Double hashing: probes step through the filter by an odd stride
+        let stride = hash.rotate_left(32) | 1;
+        let mut seen = true;
+        for probe in 0..BLOOM_PROBES {
+            let bit = hash.wrapping_add(probe.wrapping_mul(stride)) % bits;
+            let (word, mask) = ((bit / 64) as usize, 1 << (bit % 64));
+            seen &= self.words[word] & mask != 0;
+            self.words[word] |= mask;
+        }
+        seen
+    }
+}
+
+impl fmt::Display for DedupeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.unique_tokens + self.duplicated_tokens;
+        let share = |part: u64, whole: u64| (part * 100).checked_div(whole).unwrap_or(0);
+        writeln!(
+            f,
+            "Archive: {} ({} requests)",
+            self.directory.display(),
+            format_number(self.requests as u64)
+        )?;
+        if self.skipped > 0 {
+            writeln!(f, "  Skipped:     {} unreadable files", self.skipped)?;
+        }
+        writeln!(
+            f,
+            "  Tokens:      {} total, {} unique, {} duplicated ({}%)",
+            format_number(total),
+            format_number(self.unique_tokens),
+            format_number(self.duplicated_tokens),
+            share(self.duplicated_tokens, total)
+        )?;
+        let composition = &self.composition;
+        writeln!(
+            f,
+            "  By source:   {} system, {} tools, {} tool results, {} conversation",
+            format_number(composition.system),
+            format_number(composition.tools),
+            format_number(composition.tool_results),
+            format_number(composition.conversation)
+        )?;
+        writeln!(
+            f,
+            "  Blob store:  would save {} of {} content ({}%)",
+            format_bytes(self.duplicated_bytes),
+            format_bytes(self.content_bytes),
+            share(self.duplicated_bytes, self.content_bytes)
+        )?;
+        if self.top.is_empty() {
+            return writeln!(f, "\nNo repeated content");
+        }
+        writeln!(f, "\nMost repeated:")?;
+        for blob in &self.top {
+            writeln!(
+                f,
+                "  {:>6}×  {:>9} tok  {:>9}  {:<10}  {}",
+                blob.occurrences,
+                format_number(blob.tokens),
+                format_bytes(blob.bytes),
+                blob.kind,
+                blob.preview
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregate::{AggregateOptions, AggregateReport, UsageRecord};
+    use crate::parser::parse_request;
+    use serde_json::{json, Value};
+
+    const SYSTEM: &str = "You are a careful coding assistant.\nFollow the style guide.";
+
+    fn body(turns: &[&str], tools: bool) -> Value {
+        let messages: Vec<Value> = turns
+            .iter()
+            .enumerate()
+            .map(|(i, text)| {
+                let role = if i % 2 == 0 { "user" } else { "assistant" };
+                json!({"role": role, "content": text})
+            })
+            .collect();
+        let mut body =
+            json!({"model": "claude-sonnet-4-5", "system": SYSTEM, "messages": messages});
+        if tools {
+            body["tools"] = json!([{
+                "name": "read_file",
+                "description": "Read a file from the workspace",
+                "input_schema": {"type": "object", "properties": {"path": {"type": "string"}}}
+            }]);
+        }
+        body
+    }
+
+    #[test]
+    fn test_report_reconciles_with_composition() {
+        let dir = std::env::temp_dir().join(format!("sherlock-dedupe-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let big_file = "fn main() {}\n".repeat(200);
+        let bodies = [
+            body(&["Fix the build"], true),
+            body(&["Fix the build", "Done.", &big_file], true),
+            body(&["Fix the build", "Done.", &big_file, "Thanks"], true),
+            body(&["Write docs"], false),
+            body(&[&big_file], false),
+        ];
+        for (i, body) in bodies.iter().enumerate() {
+            let name = format!("20250101_1000{:02}.000_{:08}_anthropic.json", i, i + 1);
+            std::fs::write(dir.join(name), body.to_string()).unwrap();
+        }
+        std::fs::write(dir.join("20250101_100010.000_00000009_anthropic.json"), "{").unwrap();
+
+        let mut calls = Vec::new();
+        let report = dedupe_report(&dir, |progress| calls.push(progress)).unwrap();
+        assert_eq!(calls.len(), 12);
+        assert_eq!(
+            calls.last(),
+            Some(&Progress {
+                pass: Pass::Counting,
+                done: 6,
+                total: 6
+            })
+        );
+        assert_eq!((report.requests, report.skipped), (5, 1));
+
+        // The same totals the live gauge reaches over the same requests
+        let records: Vec<UsageRecord> = bodies
+            .iter()
+            .map(|body| {
+                let event = parse_request(body.to_string().as_bytes(), "", "anthropic").unwrap();
+                UsageRecord::from(&event)
+            })
+            .collect();
+        let now = chrono::Utc::now();
+        let live = AggregateReport::build("", now, now, &records, AggregateOptions::default());
+        assert_eq!(report.composition, live.composition);
+        assert_eq!(
+            report.unique_tokens + report.duplicated_tokens,
+            live.total_tokens
+        );
+
+        // The system prompt in all five, the big file in three, "Fix the
+        // build" and the tools in three, "Done." in two
+        let occurrences: Vec<(&str, usize)> = report
+            .top
+            .iter()
+            .map(|blob| (blob.kind.as_str(), blob.occurrences))
+            .collect();
+        assert_eq!(
+            occurrences,
+            [
+                ("system", 5),
+                ("user", 3),
+                ("tools", 3),
+                ("user", 3),
+                ("assistant", 2)
+            ]
+        );
+        assert_eq!(report.top[0].preview, "You are a careful coding assistant.");
+        assert_eq!(report.top[0].tokens * 5, live.composition.system);
+        assert_eq!(report.top[1].preview, "fn main() {}");
+        assert_eq!(report.top[1].bytes, big_file.len() as u64);
+        assert!(report.duplicated_tokens > 2 * report.top[0].tokens);
+        let repeated_bytes: u64 = report
+            .top
+            .iter()
+            .map(|blob| blob.bytes * (blob.occurrences as u64 - 1))
+            .sum();
+        assert_eq!(report.duplicated_bytes, repeated_bytes);
+        assert!(report.to_string().contains("Most repeated:"));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_bloom_has_no_false_negatives() {
+        let mut bloom = Bloom::new(10_000);
+        let false_positives = (0..10_000u64)
+            .filter(|i| bloom.insert(hash(&i.to_string())))
+            .count();
+        assert!(false_positives < 200, "{}", false_positives);
+        assert!((0..10_000u64).all(|i| bloom.insert(hash(&i.to_string()))));
+    }
+
+    #[test]
+    fn test_blobs_add_up_to_the_request() {
+        let event = parse_request(
+            body(&["a", "bb", "ccc"], true).to_string().as_bytes(),
+            "",
+            "anthropic",
+        )
+        .unwrap();
+        let tools = tool_definitions(&event);
+        let blobs = blobs(&event, &tools);
+        assert_eq!(blobs.len(), 5);
+        assert_eq!(blobs[0].kind, TOOLS);
+        assert_eq!(blobs[0].tokens, event.composition().tools);
+        let total: u64 = blobs.iter().map(|blob| blob.tokens).sum();
+        assert_eq!(total, event.composition().total());
+    }
+}
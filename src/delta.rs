@@ -0,0 +1,262 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use crate::branches::ConversationTree;
+use crate::caching::{conversation, RecentConversations};
+use crate::event::{Message, RequestEvent};
+
+/// How a request differs from the previous request in its conversation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestDelta {
+    /// Messages after the part shared with the previous request
+    pub appended: usize,
+    /// Messages of the previous request no longer present, e.g. an edited
+    /// last turn or a compacted history
+    pub removed: usize,
+    pub system_changed: bool,
+    pub tokens: i64,
+    /// Branches the conversation has split into by editing earlier messages
+    pub branches: usize,
+}
+
+/// e.g. "+2 msg, +3.1k", "⑂2 -1 +1 msg, +40" or "sys, +1 msg, -38.0k"
+impl std::fmt::Display for RequestDelta {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.branches > 1 {
+            write!(f, "⑂{} ", self.branches)?;
+        }
+        if self.system_changed {
+            write!(f, "sys, ")?;
+        }
+        match (self.removed, self.appended) {
+            (0, 0) => {}
+            (0, appended) => write!(f, "+{} msg, ", appended)?,
+            (removed, 0) => write!(f, "-{} msg, ", removed)?,
+            (removed, appended) => write!(f, "-{} +{} msg, ", removed, appended)?,
+        }
+        if self.tokens.abs() < 1000 {
+            write!(f, "{:+}", self.tokens)
+        } else {
+            write!(f, "{:+.1}k", self.tokens as f64 / 1000.0)
+        }
+    }
+}
+
+/// Compares each request with the previous request in its conversation,
+/// remembering a hash per message so appends only hash the new ones
+#[derive(Debug, Default)]
+pub struct DeltaTracker {
+    conversations: RecentConversations<Seen>,
+}
+
+#[derive(Debug)]
+struct Seen {
+    system: u64,
+    /// One hash per message after the leading system messages
+    messages: Vec<u64>,
+    tokens: usize,
+    tree: ConversationTree,
+}
+
+impl DeltaTracker {
+    /// Record `event`, returning how it changed when an earlier request of
+    /// the same conversation was seen
+    pub fn observe(&mut self, event: &RequestEvent) -> Option<RequestDelta> {
+        if event.imported || event.self_test {
+            return None;
+        }
+        let (key, _) = conversation(event)?;
+        let system_len = event
+            .messages
+            .iter()
+            .take_while(|m| m.role == "system")
+            .count();
+        let (system, messages) = event.messages.split_at(system_len);
+        let system = hash_messages(system);
+
+        let mut delta = None;
+        let mut tree = ConversationTree::default();
+        let hashes = match self.conversations.remove(key) {
+            Some(mut previous) => {
+                let previous_len = previous.messages.len();
+                let (hashes, shared) = if extends(&previous.messages, messages) {
+                    previous
+                        .messages
+                        .extend(messages[previous_len..].iter().map(hash_message));
+                    (previous.messages, previous_len)
+                } else {
+                    // Edited or compacted: find where the two part ways
+                    let hashes: Vec<u64> = messages.iter().map(hash_message).collect();
+                    let shared = previous
+                        .messages
+                        .iter()
+                        .zip(&hashes)
+                        .take_while(|(a, b)| a == b)
+                        .count();
+                    (hashes, shared)
+                };
+                tree = previous.tree;
+                tree.insert(&hashes);
+                delta = Some(RequestDelta {
+                    appended: messages.len() - shared,
+                    removed: previous_len - shared,
+                    system_changed: previous.system != system,
+                    tokens: event.tokens as i64 - previous.tokens as i64,
+                    branches: tree.branches().len(),
+                });
+                hashes
+            }
+            None => {
+                let hashes: Vec<u64> = messages.iter().map(hash_message).collect();
+                tree.insert(&hashes);
+                hashes
+            }
+        };
+
+        self.conversations.insert(
+            key,
+            Seen {
+                system,
+                messages: hashes,
+                tokens: event.tokens,
+                tree,
+            },
+        );
+        delta
+    }
+}
+
+/// The previous request's last message is still in place, so everything
+/// after it is new. Only that one message is compared.
+fn extends(previous: &[u64], messages: &[Message]) -> bool {
+    match previous.last() {
+        None => true,
+        Some(last) => messages
+            .get(previous.len() - 1)
+            .is_some_and(|message| hash_message(message) == *last),
+    }
+}
+
+/// Hash of the leading system messages, and one per message after them
+pub fn message_hashes(event: &RequestEvent) -> (u64, Vec<u64>) {
+    let system_len = event
+        .messages
+        .iter()
+        .take_while(|m| m.role == "system")
+        .count();
+    let (system, messages) = event.messages.split_at(system_len);
+    (
+        hash_messages(system),
+        messages.iter().map(hash_message).collect(),
+    )
+}
+
+fn hash_message(message: &Message) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (&message.role, &message.content).hash(&mut hasher);
+    hasher.finish()
+}
+
+fn hash_messages(messages: &[Message]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for message in messages {
+        (&message.role, &message.content).hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_request;
+
+    fn event(system: &str, turns: &[&str]) -> RequestEvent {
+        let messages: Vec<_> = turns
+            .iter()
+            .enumerate()
+            .map(|(i, text)| {
+                let role = if i % 2 == 0 { "user" } else { "assistant" };
+                serde_json::json!({"role": role, "content": text})
+            })
+            .collect();
+        let body = serde_json::json!({
+            "model": "claude-3-5-sonnet",
+            "system": system,
+            "messages": messages
+        });
+        let body = serde_json::to_vec(&body).unwrap();
+        parse_request(&body, "/v1/messages", "anthropic").unwrap()
+    }
+
+    #[test]
+    fn test_append() {
+        let mut tracker = DeltaTracker::default();
+        let first = event("be brief", &["fix the build"]);
+        assert_eq!(tracker.observe(&first), None);
+
+        let long_answer = vec!["done"; 3000].join(" ");
+        let second = event(
+            "be brief",
+            &["fix the build", &long_answer, "now run tests"],
+        );
+        let delta = tracker.observe(&second).unwrap();
+        assert_eq!((delta.appended, delta.removed), (2, 0));
+        assert!(!delta.system_changed);
+        assert_eq!(delta.tokens, (second.tokens - first.tokens) as i64);
+        assert!(delta.to_string().starts_with("+2 msg, +3."), "{}", delta);
+
+        // A new system prompt is called out; unrelated conversations aren't compared
+        let third = event(
+            "be thorough",
+            &[
+                "fix the build",
+                &long_answer,
+                "now run tests",
+                "ok",
+                "thanks",
+            ],
+        );
+        let delta = tracker.observe(&third).unwrap();
+        assert!(delta.system_changed);
+        assert!(delta.to_string().starts_with("sys, +2 msg, "), "{}", delta);
+        assert_eq!(tracker.observe(&event("be brief", &["write docs"])), None);
+    }
+
+    #[test]
+    fn test_edit_and_resend() {
+        let mut tracker = DeltaTracker::default();
+        tracker.observe(&event("", &["fix the build", "which one?", "the rust one"]));
+        let edited = event("", &["fix the build", "which one?", "the cargo build"]);
+        let delta = tracker.observe(&edited).unwrap();
+        assert_eq!((delta.appended, delta.removed, delta.branches), (1, 1, 2));
+        assert_eq!(delta.to_string(), format!("⑂2 -1 +1 msg, {:+}", delta.tokens));
+
+        // Resending unchanged is an empty delta on the same branch
+        let delta = tracker.observe(&edited).unwrap();
+        assert_eq!(delta.to_string(), "⑂2 +0");
+    }
+
+    #[test]
+    fn test_compaction() {
+        let mut tracker = DeltaTracker::default();
+        let history: Vec<String> = (0..20).map(|i| vec!["step"; 100 + i].join(" ")).collect();
+        let mut turns = vec!["fix the build"];
+        turns.extend(history.iter().map(String::as_str));
+        let before = event("", &turns);
+        tracker.observe(&before);
+
+        let after = event(
+            "",
+            &["fix the build", "summary of the work so far", "continue"],
+        );
+        let delta = tracker.observe(&after).unwrap();
+        assert_eq!((delta.appended, delta.removed), (2, 20));
+        assert!(delta.tokens < -1000);
+        // The compacted history parts ways with the old one like an edit does
+        assert!(
+            delta.to_string().starts_with("⑂2 -20 +2 msg, -2."),
+            "{}",
+            delta
+        );
+    }
+}
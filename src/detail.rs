@@ -0,0 +1,638 @@
+use crossterm::event::{KeyCode, KeyEvent};
+use ratatui::{
+    layout::Rect,
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Borders, Clear, Paragraph},
+    Frame,
+};
+use std::cell::{Cell, RefCell};
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::context::{Advice, ContextOverflow};
+use crate::dashboard::format_number;
+use crate::event::{RequestDetail, RequestInfo};
+use crate::pricing::{format_cost, Alternative, PriceTable};
+use crate::resend::Draft;
+use crate::search::{self, Part, SearchHit, SearchScope};
+use crate::text::wrap_ranges;
+
+/// Full-screen view of one request from the log: its model, path, token
+/// count, every message it sent and the response
+pub struct DetailView {
+    info: RequestInfo,
+    detail: Arc<RequestDetail>,
+    /// Search text highlighted throughout, lowercase
+    highlight: Option<String>,
+    /// Prompt caching advice for the request's conversation
+    cache_hint: Option<String>,
+    /// What the request would have cost on the `pricing.compare_models`
+    alternatives: Vec<Alternative>,
+    /// Match to bring into view on the first render: its part and offset
+    target: Cell<Option<(Part, usize)>>,
+    /// First line shown; set while rendering when jumping to `target`
+    scroll: Cell<usize>,
+    /// Lines wrapped to the width they were last drawn at, so long
+    /// conversations are only wrapped again on resize
+    lines: RefCell<Option<(u16, Vec<Line<'static>>)>>,
+    /// Lines that fit on screen, as of the last render
+    height: Cell<usize>,
+}
+
+impl DetailView {
+    /// A view of the request on `info`'s row, if its messages were kept
+    pub fn open(info: &RequestInfo) -> Option<Self> {
+        Some(Self {
+            detail: Arc::clone(info.detail.as_ref()?),
+            info: info.clone(),
+            highlight: None,
+            cache_hint: None,
+            alternatives: Vec::new(),
+            target: Cell::new(None),
+            scroll: Cell::new(0),
+            lines: RefCell::new(None),
+            height: Cell::new(0),
+        })
+    }
+
+    /// A view of the request `hit` found, scrolled to the match, with every
+    /// occurrence of `query` highlighted
+    pub fn open_at(hit: &SearchHit, query: &str) -> Option<Self> {
+        let mut view = Self::open(&hit.info)?;
+        view.highlight = Some(query.to_lowercase());
+        view.target.set(Some((hit.part, hit.range.start)));
+        Some(view)
+    }
+
+    /// Show `hint` as the caching advice for the request's conversation
+    pub fn with_cache_hint(mut self, hint: Option<String>) -> Self {
+        self.cache_hint = hint;
+        self
+    }
+
+    /// Price the request on `prices`' comparison models, at the model that
+    /// served it and the tokens it took
+    pub fn with_prices(mut self, prices: &PriceTable) -> Self {
+        let model = self
+            .info
+            .served_model
+            .as_deref()
+            .unwrap_or(&self.info.model);
+        let output = self.info.output.map_or(0, |output| output.tokens);
+        self.alternatives = prices.alternatives(model, self.info.tokens as u64, output);
+        self
+    }
+
+    /// The request as sent, to edit and resend
+    pub fn draft(&self) -> Draft {
+        Draft {
+            provider: self.detail.provider.clone(),
+            path: self.detail.path.clone(),
+            body: serde_json::to_string_pretty(&self.detail.raw_body)
+                .expect("JSON values serialize"),
+            api_version: self.detail.api_version.clone(),
+            auth: self.detail.auth.clone(),
+        }
+    }
+
+    /// Apply a key press, returning true when the view should close
+    pub fn handle_key(&mut self, key: KeyEvent) -> bool {
+        let page = self.height.get().max(1) as isize;
+        match key.code {
+            KeyCode::Esc | KeyCode::Enter | KeyCode::Char('q') => return true,
+            KeyCode::Char('j') | KeyCode::Down => self.scroll_by(1),
+            KeyCode::Char('k') | KeyCode::Up => self.scroll_by(-1),
+            KeyCode::Char(' ') | KeyCode::PageDown => self.scroll_by(page),
+            KeyCode::PageUp => self.scroll_by(-page),
+            KeyCode::Char('g') | KeyCode::Home => self.scroll_by(isize::MIN),
+            KeyCode::Char('G') | KeyCode::End => self.scroll_by(isize::MAX),
+            _ => {}
+        }
+        false
+    }
+
+    /// Furthest the view can scroll, as of the last render
+    fn max_scroll(&self) -> usize {
+        let lines = self.lines.borrow();
+        let count = lines.as_ref().map_or(0, |(_, lines)| lines.len());
+        count.saturating_sub(self.height.get())
+    }
+
+    fn scroll_by(&mut self, delta: isize) {
+        let max = self.max_scroll();
+        let scroll = self
+            .scroll
+            .get()
+            .min(max)
+            .saturating_add_signed(delta)
+            .min(max);
+        self.scroll.set(scroll);
+    }
+
+    pub fn render(&self, frame: &mut Frame, area: Rect) {
+        let mut block = Block::default()
+            .borders(Borders::ALL)
+            .title(format!(" Request {} ", self.info.time))
+            .title_bottom(Span::styled(
+                " j/k scroll · PgUp/PgDn page · E edit and resend · Esc close ",
+                Style::default().fg(Color::DarkGray),
+            ));
+        let inner = block.inner(area);
+        self.height.set(inner.height as usize);
+
+        let mut cache = self.lines.borrow_mut();
+        let lines = match cache.take() {
+            Some((width, lines)) if width == inner.width => lines,
+            _ => {
+                let (lines, target) = self.build_lines(inner.width as usize);
+                // A line of context above the match
+                if let Some(line) = target.filter(|_| self.target.take().is_some()) {
+                    self.scroll.set(line.saturating_sub(1));
+                }
+                lines
+            }
+        };
+        let height = inner.height as usize;
+        let scroll = self.scroll.get().min(lines.len().saturating_sub(height));
+        if lines.len() > height {
+            block = block.title(
+                Line::from(format!(
+                    " lines {}-{} of {} ",
+                    scroll + 1,
+                    (scroll + height).min(lines.len()),
+                    lines.len()
+                ))
+                .right_aligned(),
+            );
+        }
+        let shown: Vec<Line> = lines.iter().skip(scroll).take(height).cloned().collect();
+        *cache = Some((inner.width, lines));
+
+        frame.render_widget(Clear, area);
+        frame.render_widget(Paragraph::new(shown).block(block), area);
+    }
+
+    /// The request's summary, then its messages and response wrapped to
+    /// `width`, each under a header naming its role. Also the line `target`
+    /// is on, if there is one.
+    fn build_lines(&self, width: usize) -> (Vec<Line<'static>>, Option<usize>) {
+        let field = |name: &str, value: String| {
+            Line::from(vec![
+                Span::styled(
+                    format!("{}: ", name),
+                    Style::default().add_modifier(Modifier::BOLD),
+                ),
+                Span::raw(value),
+            ])
+        };
+        let mut model = field("Model", self.info.model.clone());
+        if let Some(tier) = &self.detail.service_tier {
+            let (badge, color) = match &self.info.tier_mismatch {
+                Some(requested) => {
+                    let badge = format!("tier: {}, requested {}", tier, requested);
+                    (badge, Color::Yellow)
+                }
+                None => (format!("tier: {}", tier), Color::Cyan),
+            };
+            model.push_span(Span::raw("  "));
+            model.push_span(Span::styled(
+                format!(" {} ", badge),
+                Style::default().fg(Color::Black).bg(color),
+            ));
+        }
+        let mut tokens = format_number(self.info.tokens as u64);
+        let tool_tokens = self.detail.tool_definition_tokens;
+        if tool_tokens > 0 {
+            tokens.push_str(&format!(
+                " (messages: {}, tools: {})",
+                format_number((self.info.tokens as u64).saturating_sub(tool_tokens)),
+                format_number(tool_tokens)
+            ));
+        }
+        let mut lines = vec![
+            field("Provider", self.info.provider.clone()),
+            model,
+            field("Path", self.detail.path.clone()),
+            field("Tokens", tokens),
+        ];
+        let usage = self.detail.gemini_usage;
+        if let Some(name) = &self.detail.cached_content {
+            let size = match usage {
+                Some(usage) => format!("{} tokens", format_number(usage.cached)),
+                None => "size unknown until answered".to_string(),
+            };
+            lines.push(field("Cached context", format!("{} ({})", name, size)));
+        }
+        if let Some(usage) = usage {
+            let mut prompt = format!(
+                "{} prompt ({} cached, {} new)",
+                format_number(usage.prompt),
+                format_number(usage.cached),
+                format_number(usage.prompt.saturating_sub(usage.cached))
+            );
+            if usage.tool_use_prompt > 0 {
+                prompt.push_str(&format!(
+                    " + {} tool use",
+                    format_number(usage.tool_use_prompt)
+                ));
+            }
+            lines.push(field(
+                "Gemini usage",
+                format!(
+                    "{}, {} output, {} thinking, {} total",
+                    prompt,
+                    format_number(usage.candidates),
+                    format_number(usage.thoughts),
+                    format_number(usage.total)
+                ),
+            ));
+        }
+        if let Some(throughput) = self.detail.throughput {
+            let rate = throughput
+                .tokens_per_sec()
+                .map_or("-".to_string(), |rate| format!("{:.0} tokens/s", rate));
+            let mut text = format!(
+                "{} ({} tokens over {:.1}s)",
+                rate,
+                format_number(throughput.output_tokens),
+                throughput.duration_ms as f64 / 1000.0
+            );
+            if !throughput.is_significant() {
+                text.push_str(", too short to count per model");
+            }
+            lines.push(field("Throughput", text));
+        }
+        let tools = self.detail.tool_names();
+        if !tools.is_empty() {
+            lines.push(field("Tools", tools.join(", ")));
+        }
+        if let Some(hint) = &self.cache_hint {
+            let mut caching = field("Caching", String::new());
+            caching.spans[1] = Span::styled(hint.clone(), Style::default().fg(Color::Yellow));
+            lines.push(caching);
+        }
+        if !self.alternatives.is_empty() {
+            let costs: Vec<String> = self.alternatives.iter().map(describe).collect();
+            lines.push(field("What if", costs.join(", ")));
+        }
+        if let Some((overflow, advice)) = &self.detail.context {
+            lines.extend(advice_lines(overflow, advice));
+        }
+        if self.detail.messages.is_empty() && self.detail.response.is_none() {
+            lines.push(Line::default());
+            lines.push(Line::styled(
+                "No messages in this request",
+                Style::default().fg(Color::DarkGray),
+            ));
+        }
+        let mut target_line = None;
+        for (part, role, content) in search::parts(&self.detail, SearchScope::Everything) {
+            lines.push(Line::default());
+            lines.push(Line::styled(
+                format!("── {} ──", role),
+                Style::default()
+                    .fg(role_color(role))
+                    .add_modifier(Modifier::BOLD),
+            ));
+            // Tabs would render as nothing; one space keeps the byte offsets
+            let content = content.replace('\t', " ");
+            let wrapped = wrap_ranges(&content, width);
+            let found = match &self.highlight {
+                Some(query) => search::find_all(&content, query),
+                None => Vec::new(),
+            };
+            if let Some((_, offset)) = self.target.get().filter(|(target, _)| *target == part) {
+                target_line = Some(lines.len() + search::line_of(&wrapped, offset));
+            }
+            let highlights = search::line_highlights(&wrapped, &found);
+            for (range, highlights) in wrapped.into_iter().zip(highlights) {
+                lines.push(highlighted(&content[range], &highlights));
+            }
+            if let Part::Message(i) = part {
+                for block in &self.detail.messages[i].unknown_parts {
+                    lines.push(Line::styled(
+                        format!("[{} block]", block.kind),
+                        Style::default().fg(Color::DarkGray),
+                    ));
+                }
+            }
+        }
+        (lines, target_line)
+    }
+}
+
+/// e.g. "gpt-5-mini $0.05 (92% cheaper)", or "in-house-7b -" without a price
+fn describe(alternative: &Alternative) -> String {
+    let cost = format!(
+        "{} {}",
+        alternative.model,
+        format_cost(alternative.cost_usd)
+    );
+    match alternative.savings_percent {
+        Some(savings) if savings >= 0.0 => format!("{} ({:.0}% cheaper)", cost, savings),
+        Some(savings) => format!("{} ({:.0}% dearer)", cost, -savings),
+        None => cost,
+    }
+}
+
+/// What an oversized request is made of and what could be trimmed, as in
+/// the markdown archive's "Context limit" section
+fn advice_lines(overflow: &ContextOverflow, advice: &Advice) -> Vec<Line<'static>> {
+    let mut summary = overflow.describe();
+    if let Some(excess) = advice.excess {
+        summary.push_str(&format!(", about {} to trim", format_number(excess)));
+    }
+    let composition = &advice.composition;
+    let mut lines = vec![
+        Line::from(vec![
+            Span::styled("Context: ", Style::default().add_modifier(Modifier::BOLD)),
+            Span::styled(
+                summary,
+                Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+            ),
+        ]),
+        Line::raw(format!(
+            "  system prompt {}, tool definitions {}, tool results {}, conversation {}",
+            format_number(composition.system),
+            format_number(composition.tools),
+            format_number(composition.tool_results),
+            format_number(composition.conversation)
+        )),
+    ];
+    for (title, candidates) in [
+        ("Largest tool results", &advice.tool_results),
+        ("Oldest turns", &advice.history),
+        ("Attachments (estimated)", &advice.attachments),
+    ] {
+        if candidates.is_empty() {
+            continue;
+        }
+        lines.push(Line::raw(format!("  {}:", title)));
+        for candidate in candidates {
+            lines.push(Line::raw(format!(
+                "    {}: {} tokens",
+                candidate.label,
+                format_number(candidate.tokens)
+            )));
+        }
+    }
+    if let Some(turns) = advice.turns_to_fit {
+        lines.push(Line::raw(format!(
+            "  Dropping the oldest {} turns would make it fit",
+            turns
+        )));
+    }
+    lines
+}
+
+/// `text` with the byte `ranges` of it marked
+fn highlighted(text: &str, ranges: &[Range<usize>]) -> Line<'static> {
+    let mut spans = Vec::new();
+    let mut at = 0;
+    for range in ranges {
+        if range.start > at {
+            spans.push(Span::raw(text[at..range.start].to_string()));
+        }
+        spans.push(Span::styled(
+            text[range.clone()].to_string(),
+            Style::default().fg(Color::Black).bg(Color::Yellow),
+        ));
+        at = range.end;
+    }
+    if at < text.len() || spans.is_empty() {
+        spans.push(Span::raw(text[at..].to_string()));
+    }
+    Line::from(spans)
+}
+
+fn role_color(role: &str) -> Color {
+    match role {
+        "user" => Color::Cyan,
+        "assistant" | "model" | "response" => Color::Green,
+        "system" | "developer" => Color::Yellow,
+        _ => Color::Magenta,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::{InFlightRequest, Throughput};
+    use crate::parser::parse_request;
+    use crossterm::event::KeyModifiers;
+    use ratatui::{backend::TestBackend, Terminal};
+
+    fn screen(terminal: &mut Terminal<TestBackend>, view: &DetailView) -> String {
+        terminal.draw(|f| view.render(f, f.area())).unwrap();
+        let buffer = terminal.backend().buffer();
+        let width = buffer.area.width as usize;
+        let symbols: Vec<&str> = buffer.content().iter().map(|cell| cell.symbol()).collect();
+        symbols
+            .chunks(width)
+            .map(|row| row.concat())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn test_detail_view() {
+        let body = serde_json::json!({
+            "model": "claude-sonnet-4-5",
+            "messages": [
+                {"role": "user", "content": "Read the log"},
+                {"role": "assistant", "content": "Reading it now."},
+                {"role": "user", "content": format!("{}the end", "word ".repeat(200))},
+            ]
+        });
+        let event =
+            parse_request(body.to_string().as_bytes(), "/v1/messages", "anthropic").unwrap();
+        let mut view = DetailView::open(&RequestInfo::from(&event)).unwrap();
+
+        let mut terminal = Terminal::new(TestBackend::new(40, 12)).unwrap();
+        let text = screen(&mut terminal, &view);
+        assert!(text.contains("Model: claude-sonnet-4-5"));
+        assert!(text.contains("Path: /v1/messages"));
+        assert!(text.contains("── user ──"));
+        assert!(text.contains("lines 1-10 of "));
+        assert!(!text.contains("the end"));
+
+        // Long messages wrap, and scrolling stops at the last line
+        let key = |code| KeyEvent::new(code, KeyModifiers::NONE);
+        assert!(!view.handle_key(key(KeyCode::Char('j'))));
+        assert!(screen(&mut terminal, &view).contains("lines 2-11 of "));
+        view.handle_key(key(KeyCode::Char('G')));
+        let max = view.max_scroll();
+        assert_eq!(view.scroll.get(), max);
+        view.handle_key(key(KeyCode::Char('j')));
+        assert_eq!(view.scroll.get(), max);
+        let text = screen(&mut terminal, &view);
+        assert!(text.contains("the end"));
+        assert!(text.lines().all(|line| !line.contains("wordword")));
+        view.handle_key(key(KeyCode::Char('k')));
+        assert_eq!(view.scroll.get(), max - 1);
+        assert!(view.handle_key(key(KeyCode::Esc)));
+
+        // Rows without a request behind them have nothing to show
+        let failed = RequestInfo::failed(
+            &InFlightRequest {
+                id: 1,
+                provider: "openai".to_string(),
+                model: None,
+                started_at: chrono::Utc::now(),
+                upload: None,
+            },
+            "boom".to_string(),
+        );
+        assert!(DetailView::open(&failed).is_none());
+    }
+
+    #[test]
+    fn test_tier_badge() {
+        let body = r#"{"model":"gpt-5","service_tier":"priority","messages":[]}"#;
+        let mut event = parse_request(body.as_bytes(), "/v1/chat/completions", "openai").unwrap();
+        let mut terminal = Terminal::new(TestBackend::new(60, 8)).unwrap();
+        let view = DetailView::open(&RequestInfo::from(&event)).unwrap();
+        assert!(screen(&mut terminal, &view).contains("Model: gpt-5   tier: priority "));
+
+        event.served_tier = Some("default".to_string());
+        let view = DetailView::open(&RequestInfo::from(&event)).unwrap();
+        let text = screen(&mut terminal, &view);
+        assert!(text.contains("tier: default, requested priority"));
+    }
+
+    #[test]
+    fn test_throughput() {
+        let body = r#"{"model":"claude-sonnet-4-5","messages":[]}"#;
+        let mut event = parse_request(body.as_bytes(), "/v1/messages", "anthropic").unwrap();
+        let mut terminal = Terminal::new(TestBackend::new(80, 8)).unwrap();
+        let view = DetailView::open(&RequestInfo::from(&event)).unwrap();
+        assert!(!screen(&mut terminal, &view).contains("Throughput"));
+
+        event.throughput = Some(Throughput {
+            output_tokens: 1204,
+            duration_ms: 25_080,
+        });
+        let view = DetailView::open(&RequestInfo::from(&event)).unwrap();
+        let text = screen(&mut terminal, &view);
+        assert!(text.contains("Throughput: 48 tokens/s (1,204 tokens over 25.1s) "));
+
+        event.throughput = Some(Throughput {
+            output_tokens: 12,
+            duration_ms: 200,
+        });
+        let view = DetailView::open(&RequestInfo::from(&event)).unwrap();
+        let text = screen(&mut terminal, &view);
+        assert!(text.contains(
+            "Throughput: 60 tokens/s (12 tokens over 0.2s), too short to count per model"
+        ));
+    }
+
+    #[test]
+    fn test_caching_hint() {
+        let body = r#"{"model":"claude-sonnet-4-5","messages":[{"role":"user","content":"hi"}]}"#;
+        let event = parse_request(body.as_bytes(), "/v1/messages", "anthropic").unwrap();
+        let mut terminal = Terminal::new(TestBackend::new(100, 8)).unwrap();
+        let view = DetailView::open(&RequestInfo::from(&event)).unwrap();
+        assert!(!screen(&mut terminal, &view).contains("Caching:"));
+
+        let hint = "potential cache savings ~38k tok/request — no cache breakpoints set";
+        let view = DetailView::open(&RequestInfo::from(&event))
+            .unwrap()
+            .with_cache_hint(Some(hint.to_string()));
+        let text = screen(&mut terminal, &view);
+        assert!(text.contains(&format!("Caching: {}", hint)));
+    }
+
+    #[test]
+    fn test_what_if() {
+        let body = r#"{"model":"claude-sonnet-4-5","messages":[{"role":"user","content":"hi"}]}"#;
+        let mut event = parse_request(body.as_bytes(), "/v1/messages", "anthropic").unwrap();
+        event.tokens = 200_000;
+        let mut terminal = Terminal::new(TestBackend::new(160, 8)).unwrap();
+        let mut pricing = crate::config::Config::default().pricing;
+        let view = DetailView::open(&RequestInfo::from(&event))
+            .unwrap()
+            .with_prices(&PriceTable::new(&pricing));
+        let text = screen(&mut terminal, &view);
+        assert!(text.contains(
+            "What if: gpt-5-mini $0.05 (92% cheaper), gemini-2.5-flash $0.06 (90% cheaper), \
+             claude-haiku-4-5 $0.20 (67% cheaper) "
+        ));
+
+        // Models without a price are listed without a cost
+        pricing.compare_models = vec!["claude-opus-4-1".to_string(), "in-house-7b".to_string()];
+        let view = DetailView::open(&RequestInfo::from(&event))
+            .unwrap()
+            .with_prices(&PriceTable::new(&pricing));
+        let text = screen(&mut terminal, &view);
+        assert!(text.contains("What if: claude-opus-4-1 $3.00 (400% dearer), in-house-7b - "));
+
+        pricing.compare_models.clear();
+        let view = DetailView::open(&RequestInfo::from(&event))
+            .unwrap()
+            .with_prices(&PriceTable::new(&pricing));
+        assert!(!screen(&mut terminal, &view).contains("What if"));
+    }
+
+    #[test]
+    fn test_context_advice() {
+        let body = serde_json::json!({
+            "model": "claude-sonnet-4-5",
+            "messages": [
+                {"role": "user", "content": "word ".repeat(3000)},
+                {"role": "assistant", "content": "Noted."},
+                {"role": "user", "content": "Now summarize it"},
+            ]
+        });
+        let mut event =
+            parse_request(body.to_string().as_bytes(), "/v1/messages", "anthropic").unwrap();
+        let mut terminal = Terminal::new(TestBackend::new(100, 20)).unwrap();
+        let view = DetailView::open(&RequestInfo::from(&event)).unwrap();
+        assert!(!screen(&mut terminal, &view).contains("Context:"));
+
+        event.context_overflow = Some(ContextOverflow {
+            tokens: Some(203_000),
+            limit: Some(200_000),
+            rejected: true,
+        });
+        let view = DetailView::open(&RequestInfo::from(&event)).unwrap();
+        let text = screen(&mut terminal, &view);
+        assert!(text.contains(
+            "Context: 203,000 of 200,000 tokens, rejected by the provider, about 3,000 to trim"
+        ));
+        assert!(text.contains("  system prompt 0, tool definitions 0, tool results 0"));
+        assert!(text.contains("  Oldest turns:"));
+        assert!(text.contains("    turn 1 (user): 3,001 tokens"));
+        assert!(text.contains("  Dropping the oldest 1 turns would make it fit"));
+    }
+
+    #[test]
+    fn test_gemini_breakdown() {
+        let body = r#"{"cachedContent":"cachedContents/4d2kq8x1v9rz",
+            "contents":[{"role":"user","parts":[{"text":"Summarize it"}]}]}"#;
+        let path = "/v1beta/models/gemini-2.5-pro:generateContent";
+        let mut event = parse_request(body.as_bytes(), path, "gemini").unwrap();
+        let mut terminal = Terminal::new(TestBackend::new(100, 8)).unwrap();
+        let view = DetailView::open(&RequestInfo::from(&event)).unwrap();
+        let text = screen(&mut terminal, &view);
+        let unknown = "Cached context: cachedContents/4d2kq8x1v9rz (size unknown until answered)";
+        assert!(text.contains(unknown));
+
+        event.gemini_usage = Some(crate::event::GeminiUsage {
+            prompt: 9120,
+            cached: 8704,
+            candidates: 210,
+            thoughts: 572,
+            tool_use_prompt: 0,
+            total: 9902,
+        });
+        let view = DetailView::open(&RequestInfo::from(&event)).unwrap();
+        let text = screen(&mut terminal, &view);
+        assert!(text.contains("Cached context: cachedContents/4d2kq8x1v9rz (8,704 tokens)"));
+        assert!(text.contains(
+            "Gemini usage: 9,120 prompt (8,704 cached, 416 new), 210 output, 572 thinking, \
+             9,902 total"
+        ));
+    }
+}
@@ -0,0 +1,203 @@
+//! A proxy run in-process by `sherlock claude` and the other tool commands
+//! when no `sherlock start` is listening, so the tool's requests still reach
+//! the provider and the archive. There is no dashboard: completed requests,
+//! failures and markers go straight to the archive writer.
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::archive::{archive_writer, ArchiveEntry, PENDING_FILE};
+use crate::config::Config;
+use crate::dashboard::ShutdownTimeouts;
+use crate::event::{ProxyEvent, RequestFailure};
+use crate::keys::KeyFingerprinter;
+use crate::metrics::{ArchiveMetrics, ProxyMetrics};
+use crate::policy::PolicyScanner;
+use crate::pricing::PriceTable;
+use crate::proxy::{ProxyServer, ShutdownHandle};
+use crate::redact::Redactor;
+use crate::scrub::Scrubber;
+
+/// Longest wait for something on the proxy port to accept a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Extra time for the archive writer to save what is left once its
+/// deadline passes
+const FLUSH_GRACE: Duration = Duration::from_secs(1);
+
+/// Whether anything accepts connections on `bind_address:port`
+pub async fn listening(bind_address: &str, port: u16) -> bool {
+    let connect = tokio::net::TcpStream::connect((bind_address, port));
+    matches!(
+        tokio::time::timeout(CONNECT_TIMEOUT, connect).await,
+        Ok(Ok(_))
+    )
+}
+
+/// A proxy and archive writer serving one tool session
+pub struct EmbeddedProxy {
+    shutdown: ShutdownHandle,
+    proxy: JoinHandle<()>,
+    forward: JoinHandle<()>,
+    archive: JoinHandle<Result<()>>,
+    archive_metrics: Arc<ArchiveMetrics>,
+    timeouts: ShutdownTimeouts,
+}
+
+impl EmbeddedProxy {
+    /// Listen on the configured port and archive under the configured
+    /// directory, as `sherlock start --headless` would. `sherlock_dir` holds
+    /// the key fingerprints and the events left over at the last shutdown.
+    pub async fn start(config: &Config, sherlock_dir: &Path) -> Result<Self> {
+        let (event_tx, event_rx) = mpsc::channel(1000);
+        let (archive_tx, archive_rx) = mpsc::channel(100);
+        let redactor = Arc::new(Redactor::new(&config.redaction)?);
+        let proxy = ProxyServer::new(
+            config.proxy.clone(),
+            config.providers.clone(),
+            event_tx,
+            Arc::new(ProxyMetrics::default()),
+            Arc::new(KeyFingerprinter::load_or_create(sherlock_dir)?),
+            Arc::new(PolicyScanner::new(&config.policy)?),
+            Arc::new(PriceTable::new(&config.pricing)),
+        )?
+        .with_redactor(Arc::clone(&redactor));
+        let proxy = match &config.scrubber {
+            Some(scrubber) => proxy.with_scrubber(Scrubber::start(scrubber)?),
+            None => proxy,
+        };
+        let listener = proxy.bind().await?;
+        let shutdown = proxy.shutdown_handle();
+
+        let archive_metrics = Arc::new(ArchiveMetrics::default());
+        let archive = tokio::spawn(archive_writer(
+            archive_rx,
+            config.archive.clone(),
+            redactor,
+            Arc::clone(&archive_metrics),
+            Some(sherlock_dir.join(PENDING_FILE)),
+        ));
+        Ok(Self {
+            shutdown,
+            proxy: tokio::spawn(proxy.serve(listener)),
+            forward: tokio::spawn(forward(event_rx, archive_tx)),
+            archive,
+            archive_metrics,
+            timeouts: ShutdownTimeouts {
+                grace: Duration::from_secs(config.proxy.shutdown_grace_secs),
+                archive_flush: Duration::from_secs(config.archive.shutdown_flush_timeout_secs),
+            },
+        })
+    }
+
+    /// Stop taking connections, give requests under way the shutdown grace
+    /// period to finish, then wait for the archive to catch up. Events still
+    /// queued past `archive.shutdown_flush_timeout_secs` are saved for the
+    /// next start.
+    pub async fn stop(mut self) {
+        self.shutdown.shutdown();
+        let _ = (&mut self.proxy).await;
+        // Open connections keep the event channel open until they finish
+        if tokio::time::timeout(self.timeouts.grace, &mut self.forward)
+            .await
+            .is_err()
+        {
+            tracing::warn!("Stopped waiting for requests still under way");
+            self.forward.abort();
+        }
+        let deadline = Instant::now() + self.timeouts.archive_flush;
+        self.archive_metrics.begin_shutdown(deadline);
+        let wait = self.timeouts.archive_flush + FLUSH_GRACE;
+        match tokio::time::timeout(wait, &mut self.archive).await {
+            Ok(Ok(Err(e))) => tracing::error!("Archive writer error: {:#}", e),
+            Ok(_) => {}
+            Err(_) => self.archive.abort(),
+        }
+    }
+}
+
+/// Pass what the proxy reports on to the archive, the way the dashboard
+/// does, until every connection has closed
+async fn forward(mut event_rx: mpsc::Receiver<ProxyEvent>, archive_tx: mpsc::Sender<ArchiveEntry>) {
+    let mut in_flight = HashMap::new();
+    while let Some(event) = event_rx.recv().await {
+        let entries: Vec<ArchiveEntry> = match event {
+            ProxyEvent::Uploading(request) | ProxyEvent::Started(request) => {
+                in_flight.insert(request.id, request);
+                vec![]
+            }
+            ProxyEvent::Completed { id, event } => {
+                in_flight.remove(&id);
+                event
+                    .filter(|event| !event.unarchived)
+                    .map(ArchiveEntry::Request)
+                    .into_iter()
+                    .collect()
+            }
+            ProxyEvent::Failed { id, error, event } => {
+                let failure = in_flight.remove(&id).and_then(|request| {
+                    RequestFailure::upstream(&request, &error, chrono::Utc::now())
+                });
+                event
+                    .filter(|event| !event.unarchived)
+                    .map(ArchiveEntry::Request)
+                    .into_iter()
+                    .chain(failure.map(ArchiveEntry::Failure))
+                    .collect()
+            }
+            ProxyEvent::Marker(marker) => vec![ArchiveEntry::Marker(marker)],
+            _ => vec![],
+        };
+        for entry in entries {
+            let _ = archive_tx.send(entry).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_serves_and_archives_until_stopped() {
+        let dir = std::env::temp_dir().join(format!("sherlock-embedded-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Nothing listens on a port just freed
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        assert!(!listening("127.0.0.1", port).await);
+
+        let mut config = Config::default();
+        config.proxy.port = port;
+        config.archive.directory = dir.join("prompts");
+        let proxy = EmbeddedProxy::start(&config, &dir).await.unwrap();
+        assert!(listening("127.0.0.1", port).await);
+
+        // A marker goes through the proxy's own API to the archive
+        let resp = reqwest::Client::new()
+            .post(format!("http://127.0.0.1:{}/__sherlock/api/mark", port))
+            .json(&serde_json::json!({"label": "embedded"}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+
+        proxy.stop().await;
+        assert!(!listening("127.0.0.1", port).await);
+        let markers =
+            std::fs::read_to_string(dir.join("prompts").join(crate::archive::MARKERS_FILE))
+                .unwrap();
+        assert!(markers.contains("\"embedded\""), "{}", markers);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,11 +1,26 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::aggregate::same_model;
+use crate::autostart::ToolStatus;
+use crate::caching;
+use crate::context::{self, Advice, ContextOverflow};
+use crate::keys::{CapturedAuth, KeyFingerprint};
+use crate::phases::RequestTimings;
+use crate::repo::RepoInfo;
 
 /// Event emitted when a request is intercepted by the proxy
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RequestEvent {
     /// Timestamp when the request was intercepted
     pub timestamp: DateTime<Utc>,
+    /// Proxy request id, increasing in arrival order within one proxy run.
+    /// Breaks ties between requests intercepted in the same millisecond.
+    #[serde(default)]
+    pub id: u64,
     /// Provider name (anthropic, openai, gemini)
     pub provider: String,
     /// Model identifier
@@ -18,13 +33,419 @@ pub struct RequestEvent {
     pub raw_body: serde_json::Value,
     /// API endpoint path
     pub path: String,
+    /// API version the client asked for (`anthropic-version`, `OpenAI-Beta`
+    /// or Azure's `api-version` query parameter)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_version: Option<String>,
+    /// The client asked for the response as an event stream
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub streaming: bool,
+    /// Which credential the request used; the key itself is never stored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<KeyFingerprint>,
+    /// The client disconnected before the response finished
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub client_aborted: bool,
+    /// Content policy pattern names and how often each matched
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub policy_matches: BTreeMap<String, usize>,
+    /// Set when the request was retried against fallback providers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failover: Option<Failover>,
+    /// Name of the `sherlock record` session that captured the request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recording: Option<String>,
+    /// Git repository the client tool was launched from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repo: Option<RepoInfo>,
+    /// Project overlay (`.sherlock.json`) the client tool was launched under
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub overlay: Option<PathBuf>,
+    /// Kept out of the archive by the session's overlay
+    #[serde(skip)]
+    pub unarchived: bool,
+    /// Output token limit lowered by `policy.max_output_tokens` before forwarding
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_clamp: Option<TokenClamp>,
+    /// Fault injected by chaos mode, e.g. "status 429"; such requests say
+    /// nothing about the provider and are left out of reliability stats
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chaos: Option<String>,
+    /// Converted from another tool's history by `sherlock import` rather than
+    /// seen by the proxy, so it never counts toward the live gauges
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub imported: bool,
+    /// Synthetic request sent by the startup self-test; never shown or counted
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub self_test: bool,
+    /// Edited and resent from the request detail view, tagged `[manual]`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub manual: bool,
+    /// Output speed, for streamed responses the proxy could observe
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub throughput: Option<Throughput>,
+    /// `tokens` split by where they come from, plus the tool definitions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub composition: Option<TokenComposition>,
+    /// How the upstream answered, once it did
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<ResponseInfo>,
+    /// Tokens the upstream generated in reply, once the response finished
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<OutputTokens>,
+    /// From forwarding the request until the response body finished, or
+    /// until its first byte for event streams
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    /// Time the proxy spent on each phase of the request, as its phase
+    /// spans record it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timings: Option<RequestTimings>,
+    /// Set when the request doesn't fit its model's context window
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context_overflow: Option<ContextOverflow>,
+    /// Model the response names, when it isn't literally the one requested
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub served_model: Option<String>,
+    /// `service_tier` the request asked for, e.g. "priority" or "auto"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service_tier: Option<String>,
+    /// Service tier the response reports it was processed on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub served_tier: Option<String>,
+    /// Dollars at list price per the `pricing` config; unset for unpriced models
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cost_usd: Option<f64>,
+    /// Gemini `cachedContents/...` resource the request builds on. Its
+    /// tokens aren't in the body, so `tokens` leaves them out until
+    /// `gemini_usage` reports them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cached_content: Option<String>,
+    /// Gemini's own count of the request and response, once answered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gemini_usage: Option<GeminiUsage>,
+    /// What the response generated, for searching from the dashboard. Only
+    /// kept in memory; archives hold the request as sent.
+    #[serde(skip)]
+    pub response_text: Option<String>,
+    /// Credential headers, with `proxy.capture_auth_headers` on, for
+    /// resending the request; never archived
+    #[serde(skip)]
+    pub auth: CapturedAuth,
+}
+
+/// Providers tried for a request whose first choice failed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Failover {
+    /// Every provider tried, in order, including the one that served it
+    pub attempted: Vec<String>,
+    pub served_by: String,
+}
+
+impl RequestEvent {
+    /// Total order for sorting and grouping: timestamp, then request id
+    pub fn order_key(&self) -> (DateTime<Utc>, u64) {
+        (self.timestamp, self.id)
+    }
+
+    /// Token buckets of the request; all conversation when it wasn't parsed
+    pub fn composition(&self) -> TokenComposition {
+        self.composition
+            .unwrap_or_else(|| TokenComposition::conversation_only(self.tokens as u64))
+    }
+
+    /// Tokens of the tool definitions sent with the request
+    pub fn tool_definition_tokens(&self) -> u64 {
+        self.composition().tools
+    }
+
+    /// The model that answered, when it isn't the requested one under
+    /// another name or without a date suffix
+    pub fn substituted_model(&self) -> Option<&str> {
+        let served = self.served_model.as_deref()?;
+        (!same_model(&self.model, served)).then_some(served)
+    }
+
+    /// Tier the request is billed at: the reported one, else the requested one
+    pub fn tier(&self) -> Option<&str> {
+        self.served_tier.as_deref().or(self.service_tier.as_deref())
+    }
+
+    /// The requested tier, when the response reports another one, e.g.
+    /// priority asked for but standard served. Requests leaving the choice
+    /// to the provider ("auto") never mismatch.
+    pub fn tier_mismatch(&self) -> Option<&str> {
+        let requested = self.service_tier.as_deref()?;
+        let expected = match requested {
+            "auto" => return None,
+            // Anthropic's way of opting out of priority capacity
+            "standard_only" => "standard",
+            tier => tier,
+        };
+        let served = self.served_tier.as_deref()?;
+        (!expected.eq_ignore_ascii_case(served)).then_some(requested)
+    }
+}
+
+/// Estimated tokens by where they come from, adding up to
+/// `RequestEvent::tokens`. Archives from before tool definitions were
+/// counted in `tokens` leave them out of it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TokenComposition {
+    pub system: u64,
+    pub tools: u64,
+    pub tool_results: u64,
+    /// User and assistant messages
+    pub conversation: u64,
+}
+
+impl TokenComposition {
+    pub fn conversation_only(tokens: u64) -> Self {
+        Self {
+            conversation: tokens,
+            ..Self::default()
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.system + self.tools + self.tool_results + self.conversation
+    }
+}
+
+impl std::ops::AddAssign for TokenComposition {
+    fn add_assign(&mut self, other: Self) {
+        self.system += other.system;
+        self.tools += other.tools;
+        self.tool_results += other.tool_results;
+        self.conversation += other.conversation;
+    }
 }
 
+/// Upstream status and how long it took to answer, for reliability stats
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResponseInfo {
+    pub status: u16,
+    /// From forwarding the request to the response headers
+    pub latency_ms: u64,
+}
+
+/// Error prefix of requests sherlock refused itself; those never reached
+/// the provider, so they don't count against its reliability
+pub const BLOCKED_PREFIX: &str = "blocked: ";
+
+/// A forwarded request that failed before its response completed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RequestFailure {
+    pub timestamp: DateTime<Utc>,
+    pub id: u64,
+    pub provider: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// From forwarding the request to the failure
+    pub latency_ms: u64,
+    pub error: String,
+}
+
+impl RequestFailure {
+    /// The failure of `request` at `now`, unless sherlock blocked it or it
+    /// never got past the upload
+    pub fn upstream(request: &InFlightRequest, error: &str, now: DateTime<Utc>) -> Option<Self> {
+        if error.starts_with(BLOCKED_PREFIX) || request.upload.is_some() {
+            return None;
+        }
+        Some(Self {
+            timestamp: request.started_at,
+            id: request.id,
+            provider: request.provider.clone(),
+            model: request.model.clone(),
+            latency_ms: (now - request.started_at).num_milliseconds().max(0) as u64,
+            error: error.to_string(),
+        })
+    }
+}
+
+/// Responses shorter than this say more about latency than model speed
+const MIN_THROUGHPUT_TOKENS: u64 = 32;
+const MIN_THROUGHPUT_MS: u64 = 500;
+
+/// How fast a streamed response produced its output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Throughput {
+    /// Output tokens counted over the stream's content deltas
+    pub output_tokens: u64,
+    /// Time from the first to the last content delta
+    pub duration_ms: u64,
+}
+
+impl Throughput {
+    pub fn tokens_per_sec(&self) -> Option<f64> {
+        (self.duration_ms > 0).then(|| self.output_tokens as f64 * 1000.0 / self.duration_ms as f64)
+    }
+
+    /// Long enough to count toward per-model aggregates
+    pub fn is_significant(&self) -> bool {
+        self.output_tokens >= MIN_THROUGHPUT_TOKENS && self.duration_ms >= MIN_THROUGHPUT_MS
+    }
+}
+
+/// Size of a response, from the provider's usage block when it sent one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutputTokens {
+    pub tokens: u64,
+    /// Counted by sherlock over the response text, for lack of usage
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub estimated: bool,
+}
+
+/// A Gemini response's `usageMetadata`. Streams report it cumulatively,
+/// so each count is the largest seen.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GeminiUsage {
+    /// `promptTokenCount`, cached context included
+    pub prompt: u64,
+    /// `cachedContentTokenCount`: the part of `prompt` read from a cache,
+    /// explicit `cachedContent` or implicit
+    pub cached: u64,
+    /// `candidatesTokenCount`
+    pub candidates: u64,
+    /// `thoughtsTokenCount`
+    pub thoughts: u64,
+    /// `toolUsePromptTokenCount`: results of tools Gemini ran itself
+    pub tool_use_prompt: u64,
+    /// `totalTokenCount`
+    pub total: u64,
+}
+
+impl GeminiUsage {
+    /// Prompt tokens read fresh rather than from a cache
+    pub fn uncached(&self) -> u64 {
+        self.prompt.saturating_sub(self.cached) + self.tool_use_prompt
+    }
+}
+
+/// An output token limit over the configured cap
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenClamp {
+    /// Where the limit sits in the body, e.g. `generationConfig.maxOutputTokens`
+    pub field: String,
+    pub requested: u64,
+    pub limit: u64,
+}
+
+/// Lifecycle notifications sent from the proxy to the dashboard
+#[derive(Debug, Clone)]
+pub enum ProxyEvent {
+    /// A request body is still arriving from the client. Sent once the first
+    /// chunk is in but not the rest, then a few times a second with the bytes
+    /// so far, until `Started` (or `Failed` if the client gives up).
+    Uploading(InFlightRequest),
+    /// A request was intercepted and is being forwarded upstream
+    Started(InFlightRequest),
+    /// The upstream response finished relaying. `event` is `None` when the
+    /// request body couldn't be parsed.
+    Completed {
+        id: u64,
+        event: Option<Box<RequestEvent>>,
+    },
+    /// Forwarding failed before the response completed. `event` is the
+    /// request as parsed, once it reached the upstream, so it's still archived.
+    Failed {
+        id: u64,
+        error: String,
+        event: Option<Box<RequestEvent>>,
+    },
+    /// The startup self-test finished its round trip; the dashboard still
+    /// checks that the self-test request itself came through
+    SelfTest(Result<(), String>),
+    /// The update check found this newer release
+    UpdateAvailable(String),
+    /// `sherlock mark` labeled a point in the session
+    Marker(Marker),
+    /// An autostarted tool started, exited or couldn't be started
+    Tool(ToolStatus),
+}
+
+/// A labeled point in time from `sherlock mark`, shown among the requests
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Marker {
+    pub label: String,
+    pub timestamp: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    /// Repository name of the session the marker applies to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session: Option<String>,
+}
+
+impl Marker {
+    /// e.g. "started refactor [infra] (sherlock)"
+    pub fn describe(&self) -> String {
+        let mut text = self.label.clone();
+        if let Some(tag) = &self.tag {
+            text.push_str(&format!(" [{}]", tag));
+        }
+        if let Some(session) = &self.session {
+            text.push_str(&format!(" ({})", session));
+        }
+        text
+    }
+}
+
+/// A request whose response hasn't finished yet
+#[derive(Debug, Clone)]
+pub struct InFlightRequest {
+    /// Per-process request id used to correlate later events
+    pub id: u64,
+    pub provider: String,
+    /// Model identifier, if the body could be parsed
+    pub model: Option<String>,
+    pub started_at: DateTime<Utc>,
+    /// Set while the request body is still arriving
+    pub upload: Option<UploadProgress>,
+}
+
+/// A request body still arriving, e.g. a large file upload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UploadProgress {
+    pub method: String,
+    pub path: String,
+    pub content_type: Option<String>,
+    /// Bytes received so far
+    pub bytes: u64,
+}
+
+/// Role of the message a tool call in an Anthropic, OpenAI Responses or
+/// Ollama request reads as
+pub const TOOL_USE_ROLE: &str = "tool_use";
+
 /// A normalized message from any provider
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
     pub role: String,
     pub content: String,
+    /// Content blocks of types the parser doesn't model
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unknown_parts: Vec<UnknownPart>,
+}
+
+impl Message {
+    /// The tool a `tool_use` message calls
+    pub fn tool_name(&self) -> Option<&str> {
+        if self.role != TOOL_USE_ROLE {
+            return None;
+        }
+        self.content.split('(').next()
+    }
+}
+
+/// A content block of an unrecognized `type`, kept verbatim
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UnknownPart {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub json: serde_json::Value,
 }
 
 /// Simplified request info for dashboard display
@@ -32,26 +453,229 @@ pub struct Message {
 pub struct RequestInfo {
     /// Time in HH:MM:SS format
     pub time: String,
+    pub timestamp: DateTime<Utc>,
     /// Provider name (capitalized)
     pub provider: String,
     /// Model name
     pub model: String,
     /// Token count
     pub tokens: usize,
+    /// Why the request failed, if it did
+    pub error: Option<String>,
+    /// Short label of the credential used, e.g. "…a1b2"
+    pub key: Option<String>,
+    /// The client hung up before the response finished
+    pub aborted: bool,
+    /// A content policy pattern matched the prompt
+    pub flagged: bool,
+    /// Fallback provider that served the request, if failover happened
+    pub failover: Option<String>,
+    /// The output token limit was lowered by the output token cap
+    pub clamped: bool,
+    /// See `RequestEvent::manual`
+    pub manual: bool,
+    /// Chaos mode injected a fault, see `RequestEvent::chaos`
+    pub chaos: bool,
+    /// Too large for the model's context window, predicted or reported
+    pub over_context: bool,
+    /// A different model than requested answered, see `RequestEvent::substituted_model`
+    pub served_model: Option<String>,
+    /// Requested service tier the response didn't honor, see
+    /// `RequestEvent::tier_mismatch`
+    pub tier_mismatch: Option<String>,
+    /// Tokens the upstream generated in reply
+    pub output: Option<OutputTokens>,
+    /// Dollars at list price, see `RequestEvent::cost_usd`
+    pub cost_usd: Option<f64>,
+    /// Upstream call duration, see `RequestEvent::latency_ms`
+    pub latency_ms: Option<u64>,
+    /// See `RequestEvent::streaming`
+    pub streaming: bool,
+    /// Upstream HTTP status, or the one sherlock answered with when the
+    /// upstream never did
+    pub status: Option<u16>,
+    /// Output tokens per second of a streamed response
+    pub throughput: Option<f64>,
+    /// Last user message, for text terms in the dashboard filter
+    pub prompt: Option<String>,
+    /// Change from the previous request in the conversation, e.g. "+2 msg, +3.1k"
+    pub change: Option<String>,
+    /// Set on a `sherlock mark` row rather than a request
+    pub marker: Option<String>,
+    /// What the dashboard's detail view shows; only completed requests have it
+    pub detail: Option<Arc<RequestDetail>>,
+}
+
+/// The parts of a request kept with its dashboard row for the detail view
+#[derive(Debug)]
+pub struct RequestDetail {
+    /// Provider name as configured, lowercase
+    pub provider: String,
+    pub path: String,
+    /// Body as sent, to edit and resend
+    pub raw_body: serde_json::Value,
+    /// See `RequestEvent::api_version`
+    pub api_version: Option<String>,
+    /// See `RequestEvent::auth`
+    pub auth: CapturedAuth,
+    pub messages: Vec<Message>,
+    /// The response's text, when it was captured
+    pub response: Option<String>,
+    /// See `RequestEvent::tier`
+    pub service_tier: Option<String>,
+    /// See `RequestEvent::tool_definition_tokens`
+    pub tool_definition_tokens: u64,
+    pub cached_content: Option<String>,
+    pub gemini_usage: Option<GeminiUsage>,
+    pub throughput: Option<Throughput>,
+    /// Label of the request's conversation, see `caching::conversation`
+    pub conversation: Option<String>,
+    /// Over its model's context window, and what could be trimmed
+    pub context: Option<(ContextOverflow, Advice)>,
+}
+
+impl RequestDetail {
+    /// Names of the tools the conversation called, first call first, each once
+    pub fn tool_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = Vec::new();
+        for name in self.messages.iter().filter_map(Message::tool_name) {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        names
+    }
 }
 
 impl From<&RequestEvent> for RequestInfo {
     fn from(event: &RequestEvent) -> Self {
         Self {
             time: event.timestamp.format("%H:%M:%S").to_string(),
+            timestamp: event.timestamp,
             provider: capitalize(&event.provider),
             model: event.model.clone(),
             tokens: event.tokens,
+            error: None,
+            key: event.key.as_ref().map(KeyFingerprint::label),
+            aborted: event.client_aborted,
+            flagged: !event.policy_matches.is_empty(),
+            failover: event.failover.as_ref().map(|f| f.served_by.clone()),
+            clamped: event.output_clamp.is_some(),
+            manual: event.manual,
+            chaos: event.chaos.is_some(),
+            over_context: event.context_overflow.is_some(),
+            served_model: event.substituted_model().map(str::to_string),
+            tier_mismatch: event.tier_mismatch().map(str::to_string),
+            output: event.output,
+            cost_usd: event.cost_usd,
+            latency_ms: event.latency_ms,
+            streaming: event.streaming,
+            status: event.response.map(|response| response.status),
+            throughput: event.throughput.as_ref().and_then(Throughput::tokens_per_sec),
+            prompt: event.last_user_message().map(str::to_string),
+            change: None,
+            marker: None,
+            detail: Some(Arc::new(RequestDetail {
+                provider: event.provider.clone(),
+                path: event.path.clone(),
+                raw_body: event.raw_body.clone(),
+                api_version: event.api_version.clone(),
+                auth: event.auth.clone(),
+                messages: event.messages.clone(),
+                response: event.response_text.clone(),
+                service_tier: event.tier().map(str::to_string),
+                tool_definition_tokens: event.tool_definition_tokens(),
+                cached_content: event.cached_content.clone(),
+                gemini_usage: event.gemini_usage,
+                throughput: event.throughput,
+                conversation: caching::conversation(event).map(|(_, label)| label),
+                context: event
+                    .context_overflow
+                    .map(|overflow| (overflow, context::advise(event, &overflow))),
+            })),
         }
     }
 }
 
-fn capitalize(s: &str) -> String {
+impl From<&Marker> for RequestInfo {
+    fn from(marker: &Marker) -> Self {
+        Self {
+            time: marker.timestamp.format("%H:%M:%S").to_string(),
+            timestamp: marker.timestamp,
+            provider: String::new(),
+            model: String::new(),
+            tokens: 0,
+            error: None,
+            key: None,
+            aborted: false,
+            flagged: false,
+            failover: None,
+            clamped: false,
+            manual: false,
+            chaos: false,
+            over_context: false,
+            served_model: None,
+            tier_mismatch: None,
+            output: None,
+            cost_usd: None,
+            latency_ms: None,
+            streaming: false,
+            status: None,
+            throughput: None,
+            prompt: None,
+            change: None,
+            marker: Some(marker.describe()),
+            detail: None,
+        }
+    }
+}
+
+impl RequestInfo {
+    /// Row for a request that never completed
+    pub fn failed(request: &InFlightRequest, error: String) -> Self {
+        let status = failure_status(&error);
+        Self {
+            time: request.started_at.format("%H:%M:%S").to_string(),
+            timestamp: request.started_at,
+            provider: capitalize(&request.provider),
+            model: request.model.clone().unwrap_or_else(|| "unknown".to_string()),
+            tokens: 0,
+            error: Some(error),
+            key: None,
+            aborted: false,
+            flagged: false,
+            failover: None,
+            clamped: false,
+            manual: false,
+            chaos: false,
+            over_context: false,
+            served_model: None,
+            tier_mismatch: None,
+            output: None,
+            cost_usd: None,
+            latency_ms: None,
+            streaming: false,
+            status: Some(status),
+            throughput: None,
+            prompt: None,
+            change: None,
+            marker: None,
+            detail: None,
+        }
+    }
+}
+
+/// What the client was answered with for a request that failed: sherlock's
+/// own refusal, or a bad gateway when the upstream never answered
+fn failure_status(error: &str) -> u16 {
+    if error.starts_with(BLOCKED_PREFIX) {
+        403
+    } else {
+        502
+    }
+}
+
+pub fn capitalize(s: &str) -> String {
     let mut chars = s.chars();
     match chars.next() {
         None => String::new(),
@@ -74,6 +698,82 @@ impl RequestEvent {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_service_tiers() {
+        let cases: Vec<serde_json::Value> =
+            serde_json::from_str(include_str!("../tests/fixtures/service_tiers.json")).unwrap();
+        for case in cases {
+            let provider = case["provider"].as_str().unwrap();
+            let path = match provider {
+                "anthropic" => "/v1/messages",
+                "openai" => "/v1/chat/completions",
+                _ => "/v1beta/models/gemini-2.5-pro:generateContent",
+            };
+            let body = case["request"].to_string();
+            let mut event = crate::parser::parse_request(body.as_bytes(), path, provider).unwrap();
+            let (is_event_stream, response) = match case["stream"].as_str() {
+                Some(stream) => (true, stream.to_string()),
+                None => (false, case["response"].to_string()),
+            };
+            let mut tap = crate::usage::UsageTap::new(is_event_stream);
+            tap.observe(response.as_bytes());
+            event.served_tier = tap.finish().service_tier;
+
+            let tier = |key: &str| case[key].as_str();
+            assert_eq!(event.service_tier.as_deref(), tier("requested"), "{}", body);
+            assert_eq!(event.served_tier.as_deref(), tier("served"), "{}", body);
+            assert_eq!(event.tier_mismatch(), tier("mismatch"), "{}", body);
+            let info = RequestInfo::from(&event);
+            assert_eq!(info.tier_mismatch.as_deref(), tier("mismatch"));
+            let shown = info.detail.unwrap().service_tier.clone();
+            assert_eq!(shown.as_deref(), tier("served").or(tier("requested")));
+        }
+    }
+
+    #[test]
+    fn test_gemini_usage() {
+        let cases: Vec<serde_json::Value> =
+            serde_json::from_str(include_str!("../tests/fixtures/gemini_usage.json")).unwrap();
+        let prices = crate::pricing::PriceTable::new(&crate::config::Config::default().pricing);
+        for case in cases {
+            let name = case["name"].as_str().unwrap();
+            let path = case["path"].as_str().unwrap();
+            let body = case["request"].to_string();
+            let mut event = crate::parser::parse_request(body.as_bytes(), path, "gemini").unwrap();
+            assert_eq!(
+                event.cached_content.as_deref(),
+                case["cached_content"].as_str(),
+                "{}",
+                name
+            );
+
+            // As the proxy fills it in once the response is done
+            let (is_event_stream, response) = match case["stream"].as_str() {
+                Some(stream) => (true, stream.to_string()),
+                None => (false, case["response"].to_string()),
+            };
+            let mut tap = crate::usage::UsageTap::new(is_event_stream);
+            tap.observe(response.as_bytes());
+            let usage = tap.finish();
+            event.output = usage.output;
+            event.gemini_usage = usage.gemini;
+            let expected: GeminiUsage = serde_json::from_value(case["usage"].clone()).unwrap();
+            assert_eq!(event.gemini_usage, Some(expected), "{}", name);
+            let output = event.output.map(|output| output.tokens);
+            assert_eq!(output, case["output"].as_u64(), "{}", name);
+
+            // Cached tokens are charged at the cached rate, fresh ones in full
+            let cost = prices.cost(&event).unwrap();
+            let listed = case["cost"].as_f64().unwrap();
+            assert!((cost - listed).abs() < 1e-9, "{}: {}", name, cost);
+
+            let info = RequestInfo::from(&event);
+            let detail = info.detail.unwrap();
+            assert_eq!(detail.cached_content, event.cached_content);
+            assert_eq!(detail.gemini_usage, Some(expected));
+        }
+    }
+
     #[test]
     fn test_capitalize() {
         assert_eq!(capitalize("anthropic"), "Anthropic");
@@ -85,6 +785,7 @@ mod tests {
     fn test_last_user_message() {
         let event = RequestEvent {
             timestamp: Utc::now(),
+            id: 0,
             provider: "anthropic".to_string(),
             model: "claude-3".to_string(),
             tokens: 100,
@@ -92,18 +793,51 @@ mod tests {
                 Message {
                     role: "user".to_string(),
                     content: "First".to_string(),
+                    unknown_parts: vec![],
                 },
                 Message {
                     role: "assistant".to_string(),
                     content: "Response".to_string(),
+                    unknown_parts: vec![],
                 },
                 Message {
                     role: "user".to_string(),
                     content: "Second".to_string(),
+                    unknown_parts: vec![],
                 },
             ],
             raw_body: serde_json::json!({}),
             path: "/v1/messages".to_string(),
+            api_version: None,
+            streaming: false,
+            key: None,
+            client_aborted: false,
+            policy_matches: BTreeMap::new(),
+            failover: None,
+            recording: None,
+            repo: None,
+            overlay: None,
+            unarchived: false,
+            output_clamp: None,
+            chaos: None,
+            imported: false,
+            self_test: false,
+            manual: false,
+            throughput: None,
+            composition: None,
+            response: None,
+            output: None,
+            latency_ms: None,
+            timings: None,
+            context_overflow: None,
+            served_model: None,
+            service_tier: None,
+            served_tier: None,
+            cost_usd: None,
+            response_text: None,
+            auth: Default::default(),
+            cached_content: None,
+            gemini_usage: None,
         };
 
         assert_eq!(event.last_user_message(), Some("Second"));
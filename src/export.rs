@@ -0,0 +1,1027 @@
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+use crate::cli::ExportFormat;
+use crate::event::capitalize;
+use crate::parser::{count_tokens, extract_text_from_value};
+use crate::pricing::{format_cost, PriceTable};
+use crate::repo::RepoInfo;
+use crate::text::truncate;
+
+const HTML_TEMPLATE: &str = include_str!("templates/conversation.html");
+
+/// A conversation rebuilt from an archived request body
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conversation {
+    pub provider: String,
+    pub model: String,
+    pub turns: Vec<Turn>,
+    /// Repository the request came from, when known. Archived request bodies
+    /// don't carry it, so this is only set for conversations built from events.
+    pub repo: Option<RepoInfo>,
+    /// Dollars at list price per the `pricing` config: what the request cost
+    /// when built from an event, else an estimate of its input. Unset for
+    /// unpriced models.
+    pub cost_usd: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Turn {
+    pub role: String,
+    pub blocks: Vec<Block>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Block {
+    Text(String),
+    ToolCall { name: String, input: Value },
+    ToolResult { id: String, content: String, is_error: bool },
+    /// Anything else (images, thinking, ...), shown as collapsed JSON
+    Other { kind: String, json: Value },
+    /// Tool calls made one after another and what they returned, in place
+    /// of their turns; only in conversations with collapsed steps
+    Step(Vec<StepCall>),
+}
+
+/// One tool call of a step and its result, once one came back
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepCall {
+    pub name: String,
+    pub input: Value,
+    pub result: Option<StepResult>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepResult {
+    pub content: String,
+    pub is_error: bool,
+}
+
+/// Arguments shown for a call in a step's table, when it has them, ahead
+/// of any others
+const KEY_ARGUMENTS: &[&str] = &["command", "file_path", "path", "pattern", "url", "query"];
+/// Arguments shown per call in a step's table
+const STEP_ARGUMENTS: usize = 2;
+/// Widest argument value shown in a step's table
+const STEP_ARGUMENT_WIDTH: usize = 60;
+
+/// Export an archived JSON request body as a standalone file, returning
+/// where it was written. `collapse_steps` folds tool call loops into steps.
+pub fn export_conversation(
+    input: &Path,
+    format: ExportFormat,
+    output: Option<&Path>,
+    collapse_steps: bool,
+    prices: &PriceTable,
+) -> Result<PathBuf> {
+    let raw = std::fs::read_to_string(input)
+        .with_context(|| format!("Failed to read {}", input.display()))?;
+    let body: Value = serde_json::from_str(&raw)
+        .with_context(|| format!("{} is not an archived JSON request", input.display()))?;
+    let mut conversation = Conversation::from_request_body(&body);
+    conversation.cost_usd = prices.estimate(&conversation.model, conversation.tokens() as u64, 0);
+    if collapse_steps {
+        conversation = conversation.collapse_steps();
+    }
+
+    let (content, ext) = match format {
+        ExportFormat::Html => (render_html(&conversation), "html"),
+        ExportFormat::Markdown => (render_markdown(&conversation), "md"),
+    };
+
+    let output = output
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| input.with_extension(ext));
+    std::fs::write(&output, content)
+        .with_context(|| format!("Failed to write {}", output.display()))?;
+    Ok(output)
+}
+
+impl Conversation {
+    /// Rebuild the message history from a request body of any supported provider
+    pub fn from_request_body(body: &Value) -> Self {
+        let provider = detect_provider(body);
+        let turns = match provider {
+            "gemini" => gemini_turns(body),
+            "openai" => openai_turns(body),
+            _ => anthropic_turns(body),
+        };
+        Self {
+            provider: provider.to_string(),
+            model: body
+                .get("model")
+                .and_then(Value::as_str)
+                .unwrap_or("unknown")
+                .to_string(),
+            turns,
+            repo: None,
+            cost_usd: None,
+        }
+    }
+
+    pub fn tool_calls(&self) -> usize {
+        self.blocks()
+            .map(|block| match block {
+                Block::ToolCall { .. } => 1,
+                Block::Step(calls) => calls.len(),
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// The conversation with each tool loop folded into one assistant turn:
+    /// the text the loop started with, then a step listing every call and
+    /// its result. A loop goes on while the assistant answers results with
+    /// nothing but more tool calls, and ends at the next turn with anything
+    /// else in it. No text is dropped, only the turns that carried results.
+    pub fn collapse_steps(mut self) -> Self {
+        let mut turns = Vec::new();
+        let mut rest = std::mem::take(&mut self.turns).into_iter().peekable();
+        while let Some(turn) = rest.next() {
+            let opens_step = turn.role == "assistant"
+                && turn
+                    .blocks
+                    .iter()
+                    .any(|b| matches!(b, Block::ToolCall { .. }));
+            if !opens_step {
+                turns.push(turn);
+                continue;
+            }
+
+            let mut blocks = Vec::new();
+            let mut calls = Vec::new();
+            add_calls(turn.blocks, &mut blocks, &mut calls);
+            // What a results turn held besides results, kept as its own turn
+            let mut left_over = None;
+            loop {
+                while let Some(results) = rest.next_if(carries_results) {
+                    let other = add_results(results.blocks, &mut calls);
+                    if !other.is_empty() {
+                        left_over = Some(Turn {
+                            role: results.role,
+                            blocks: other,
+                        });
+                        break;
+                    }
+                }
+                if left_over.is_some() {
+                    break;
+                }
+                let Some(next) = rest.next_if(|turn| {
+                    turn.role == "assistant"
+                        && !turn.blocks.is_empty()
+                        && turn
+                            .blocks
+                            .iter()
+                            .all(|b| matches!(b, Block::ToolCall { .. }))
+                }) else {
+                    break;
+                };
+                add_calls(next.blocks, &mut blocks, &mut calls);
+            }
+
+            blocks.push(Block::Step(calls));
+            turns.push(Turn {
+                role: turn.role,
+                blocks,
+            });
+            turns.extend(left_over);
+        }
+        self.turns = turns;
+        self
+    }
+
+    /// Approximate token count of everything in the conversation
+    fn tokens(&self) -> usize {
+        self.blocks()
+            .map(|block| match block {
+                Block::Text(text) => count_tokens(text),
+                Block::ToolCall { name, input } => {
+                    count_tokens(name) + count_tokens(&input.to_string())
+                }
+                Block::ToolResult { content, .. } => count_tokens(content),
+                Block::Other { json, .. } => count_tokens(&extract_text_from_value(json)),
+                Block::Step(calls) => calls
+                    .iter()
+                    .map(|call| {
+                        let result = call.result.as_ref().map(|r| count_tokens(&r.content));
+                        count_tokens(&call.name)
+                            + count_tokens(&call.input.to_string())
+                            + result.unwrap_or(0)
+                    })
+                    .sum(),
+            })
+            .sum()
+    }
+
+    fn blocks(&self) -> impl Iterator<Item = &Block> {
+        self.turns.iter().flat_map(|t| &t.blocks)
+    }
+}
+
+/// Move `blocks`' tool calls to `calls`, and the rest to `kept`
+fn add_calls(blocks: Vec<Block>, kept: &mut Vec<Block>, calls: &mut Vec<StepCall>) {
+    for block in blocks {
+        match block {
+            Block::ToolCall { name, input } => calls.push(StepCall {
+                name,
+                input,
+                result: None,
+            }),
+            block => kept.push(block),
+        }
+    }
+}
+
+/// Whether `turn` answers tool calls: a user (or OpenAI tool) turn with
+/// results in it
+fn carries_results(turn: &Turn) -> bool {
+    turn.role != "assistant"
+        && turn
+            .blocks
+            .iter()
+            .any(|b| matches!(b, Block::ToolResult { .. }))
+}
+
+/// Give each result to the earliest call still without one, returning the
+/// blocks that aren't results, and results no call is waiting for
+fn add_results(blocks: Vec<Block>, calls: &mut [StepCall]) -> Vec<Block> {
+    let mut other = Vec::new();
+    for block in blocks {
+        let waiting = calls.iter_mut().find(|call| call.result.is_none());
+        match (block, waiting) {
+            (
+                Block::ToolResult {
+                    content, is_error, ..
+                },
+                Some(call),
+            ) => call.result = Some(StepResult { content, is_error }),
+            (block, _) => other.push(block),
+        }
+    }
+    other
+}
+
+/// A call's arguments for a step's table: the key ones, then others, as
+/// `name: value`; nested values are left to the full export
+fn step_arguments(input: &Value) -> String {
+    let Value::Object(fields) = input else {
+        return match input {
+            Value::Null => String::new(),
+            input => truncate(&input.to_string(), STEP_ARGUMENT_WIDTH),
+        };
+    };
+    let key = KEY_ARGUMENTS
+        .iter()
+        .filter_map(|name| fields.get_key_value(*name));
+    let others = fields
+        .iter()
+        .filter(|(name, _)| !KEY_ARGUMENTS.contains(&name.as_str()));
+    key.chain(others)
+        .filter_map(|(name, value)| {
+            let value = match value {
+                Value::String(text) => text.clone(),
+                Value::Number(_) | Value::Bool(_) => value.to_string(),
+                _ => return None,
+            };
+            let value = value.split_whitespace().collect::<Vec<_>>().join(" ");
+            Some(format!(
+                "{}: {}",
+                name,
+                truncate(&value, STEP_ARGUMENT_WIDTH)
+            ))
+        })
+        .take(STEP_ARGUMENTS)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// A call's outcome for a step's table: its status, with the exit code
+/// when the result starts with one, and the result's length
+fn step_outcome(result: Option<&StepResult>) -> String {
+    let Some(result) = result else {
+        return "no result".to_string();
+    };
+    let exit = result
+        .content
+        .strip_prefix("Exit code ")
+        .and_then(|rest| rest.split_whitespace().next())
+        .filter(|code| code.parse::<i32>().is_ok());
+    let status = match (exit, result.is_error) {
+        (Some(code), _) => format!("exit {}", code),
+        (None, true) => "error".to_string(),
+        (None, false) => "ok".to_string(),
+    };
+    let lines = result.content.lines().count();
+    format!(
+        "{}, {} line{}",
+        status,
+        lines,
+        if lines == 1 { "" } else { "s" }
+    )
+}
+
+/// Guess the request schema from its shape, since archived bodies don't record it
+fn detect_provider(body: &Value) -> &'static str {
+    if body.get("contents").is_some() {
+        return "gemini";
+    }
+    if body.get("system").is_some() {
+        return "anthropic";
+    }
+    let messages = body
+        .get("messages")
+        .and_then(Value::as_array)
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    let openai_only = messages.iter().any(|m| {
+        m.get("tool_calls").is_some()
+            || matches!(
+                m.get("role").and_then(Value::as_str),
+                Some("system" | "developer" | "tool")
+            )
+    });
+    if openai_only {
+        "openai"
+    } else {
+        "anthropic"
+    }
+}
+
+fn anthropic_turns(body: &Value) -> Vec<Turn> {
+    let mut turns = Vec::new();
+
+    if let Some(system) = body.get("system") {
+        let text = extract_text_from_value(system);
+        if !text.is_empty() {
+            turns.push(Turn {
+                role: "system".to_string(),
+                blocks: vec![Block::Text(text)],
+            });
+        }
+    }
+
+    for msg in body
+        .get("messages")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+    {
+        let blocks = match msg.get("content") {
+            Some(Value::Array(content)) => content.iter().map(anthropic_block).collect(),
+            Some(content) => vec![Block::Text(extract_text_from_value(content))],
+            None => vec![],
+        };
+        turns.push(Turn {
+            role: role(msg),
+            blocks,
+        });
+    }
+
+    turns
+}
+
+fn anthropic_block(block: &Value) -> Block {
+    match block.get("type").and_then(Value::as_str) {
+        Some("text") => Block::Text(str_field(block, "text")),
+        Some("tool_use") => Block::ToolCall {
+            name: str_field(block, "name"),
+            input: block.get("input").cloned().unwrap_or(Value::Null),
+        },
+        Some("tool_result") => Block::ToolResult {
+            id: str_field(block, "tool_use_id"),
+            content: block
+                .get("content")
+                .map(extract_text_from_value)
+                .unwrap_or_default(),
+            is_error: block
+                .get("is_error")
+                .and_then(Value::as_bool)
+                .unwrap_or(false),
+        },
+        kind => Block::Other {
+            kind: kind.unwrap_or("unknown").to_string(),
+            json: block.clone(),
+        },
+    }
+}
+
+fn openai_turns(body: &Value) -> Vec<Turn> {
+    let mut turns = Vec::new();
+
+    for msg in body
+        .get("messages")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+    {
+        let role = role(msg);
+        let content = msg.get("content").unwrap_or(&Value::Null);
+
+        let mut blocks = Vec::new();
+        if role == "tool" {
+            blocks.push(Block::ToolResult {
+                id: str_field(msg, "tool_call_id"),
+                content: extract_text_from_value(content),
+                is_error: false,
+            });
+        } else {
+            match content {
+                Value::Array(parts) => {
+                    for part in parts {
+                        blocks.push(match part.get("type").and_then(Value::as_str) {
+                            Some("text") => Block::Text(str_field(part, "text")),
+                            kind => Block::Other {
+                                kind: kind.unwrap_or("unknown").to_string(),
+                                json: part.clone(),
+                            },
+                        });
+                    }
+                }
+                Value::Null => {}
+                content => blocks.push(Block::Text(extract_text_from_value(content))),
+            }
+        }
+
+        for call in msg
+            .get("tool_calls")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+        {
+            let function = call.get("function").unwrap_or(&Value::Null);
+            let arguments = str_field(function, "arguments");
+            blocks.push(Block::ToolCall {
+                name: str_field(function, "name"),
+                input: serde_json::from_str(&arguments).unwrap_or(Value::String(arguments)),
+            });
+        }
+
+        turns.push(Turn { role, blocks });
+    }
+
+    turns
+}
+
+fn gemini_turns(body: &Value) -> Vec<Turn> {
+    let mut turns = Vec::new();
+
+    if let Some(system) = body.get("systemInstruction") {
+        let text = extract_text_from_value(system);
+        if !text.is_empty() {
+            turns.push(Turn {
+                role: "system".to_string(),
+                blocks: vec![Block::Text(text)],
+            });
+        }
+    }
+
+    for content in body
+        .get("contents")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+    {
+        let role = match content.get("role").and_then(Value::as_str) {
+            Some("model") => "assistant".to_string(),
+            Some(role) => role.to_string(),
+            None => "user".to_string(),
+        };
+        let blocks = content
+            .get("parts")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .map(|part| {
+                if let Some(text) = part.get("text").and_then(Value::as_str) {
+                    Block::Text(text.to_string())
+                } else if let Some(call) = part.get("functionCall") {
+                    Block::ToolCall {
+                        name: str_field(call, "name"),
+                        input: call.get("args").cloned().unwrap_or(Value::Null),
+                    }
+                } else if let Some(response) = part.get("functionResponse") {
+                    Block::ToolResult {
+                        id: str_field(response, "name"),
+                        content: serde_json::to_string_pretty(
+                            response.get("response").unwrap_or(&Value::Null),
+                        )
+                        .unwrap_or_default(),
+                        is_error: false,
+                    }
+                } else {
+                    Block::Other {
+                        kind: "part".to_string(),
+                        json: part.clone(),
+                    }
+                }
+            })
+            .collect();
+        turns.push(Turn { role, blocks });
+    }
+
+    turns
+}
+
+fn role(msg: &Value) -> String {
+    msg.get("role")
+        .and_then(Value::as_str)
+        .unwrap_or("unknown")
+        .to_string()
+}
+
+fn str_field(value: &Value, key: &str) -> String {
+    value
+        .get(key)
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Render a self-contained HTML page: inline styles, no scripts, no external requests
+pub fn render_html(conversation: &Conversation) -> String {
+    let title = format!(
+        "{} conversation: {}",
+        capitalize(&conversation.provider),
+        conversation.model
+    );
+
+    let summary = format!(
+        "<dl class=\"summary\">\n{}{}{}{}{}{}</dl>",
+        summary_item("Model", &conversation.model),
+        summary_item("Messages", &conversation.turns.len().to_string()),
+        summary_item("Tool calls", &conversation.tool_calls().to_string()),
+        summary_item("Tokens", &format!("~{}", conversation.tokens())),
+        summary_item("Cost", &format_cost(conversation.cost_usd)),
+        conversation
+            .repo
+            .as_ref()
+            .map(|repo| summary_item("Repo", &repo.label()))
+            .unwrap_or_default(),
+    );
+
+    let mut body = String::new();
+    for turn in &conversation.turns {
+        body.push_str(&format!(
+            "<section class=\"turn {}\">\n<div class=\"role\">{}</div>\n",
+            escape_html(&turn.role),
+            escape_html(&turn.role)
+        ));
+        for block in &turn.blocks {
+            body.push_str(&render_block(block));
+        }
+        body.push_str("</section>\n");
+    }
+
+    HTML_TEMPLATE
+        .replace("{{title}}", &escape_html(&title))
+        .replace("{{summary}}", &summary)
+        .replace("{{body}}", body.trim_end())
+}
+
+fn summary_item(label: &str, value: &str) -> String {
+    format!(
+        "<div><dt>{}</dt><dd>{}</dd></div>\n",
+        escape_html(label),
+        escape_html(value)
+    )
+}
+
+fn render_block(block: &Block) -> String {
+    match block {
+        Block::Text(text) => render_text(text),
+        Block::ToolCall { name, input } => format!(
+            "<details>\n<summary>tool call: {}</summary>\n<pre><code>{}</code></pre>\n</details>\n",
+            escape_html(name),
+            highlight(&pretty(input), "json")
+        ),
+        Block::ToolResult {
+            id,
+            content,
+            is_error,
+        } => format!(
+            "<details{}>\n<summary>tool result{}: {}</summary>\n<pre><code>{}</code></pre>\n</details>\n",
+            if *is_error { " class=\"error\"" } else { "" },
+            if *is_error { " (error)" } else { "" },
+            escape_html(id),
+            escape_html(content)
+        ),
+        Block::Other { kind, json } => format!(
+            "<details>\n<summary>{} block</summary>\n<pre><code>{}</code></pre>\n</details>\n",
+            escape_html(kind),
+            highlight(&pretty(json), "json")
+        ),
+        Block::Step(calls) => {
+            let rows: String = calls
+                .iter()
+                .map(|call| {
+                    let failed = call.result.as_ref().is_some_and(|r| r.is_error);
+                    format!(
+                        "<tr{}><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                        if failed { " class=\"error\"" } else { "" },
+                        escape_html(&call.name),
+                        escape_html(&step_arguments(&call.input)),
+                        escape_html(&step_outcome(call.result.as_ref()))
+                    )
+                })
+                .collect();
+            format!(
+                "<table class=\"step\">\n<thead><tr><th>Tool</th><th>Arguments</th><th>Result</th></tr></thead>\n\
+                 <tbody>\n{}</tbody>\n</table>\n",
+                rows
+            )
+        }
+    }
+}
+
+/// Prose as pre-wrapped text, fenced code blocks as highlighted `<pre>`
+fn render_text(text: &str) -> String {
+    let mut html = String::new();
+    for (i, segment) in text.split("```").enumerate() {
+        if i % 2 == 0 {
+            let prose = segment.trim_matches('\n');
+            if !prose.is_empty() {
+                html.push_str(&format!("<div class=\"text\">{}</div>\n", escape_html(prose)));
+            }
+        } else {
+            let (lang, code) = segment.split_once('\n').unwrap_or(("", segment));
+            let lang = lang.trim();
+            if !lang.is_empty() {
+                html.push_str(&format!("<div class=\"code-lang\">{}</div>\n", escape_html(lang)));
+            }
+            html.push_str(&format!(
+                "<pre><code>{}</code></pre>\n",
+                highlight(code.trim_end_matches('\n'), lang)
+            ));
+        }
+    }
+    html
+}
+
+/// Render the conversation as Markdown, tool calls and results as fenced blocks
+pub fn render_markdown(conversation: &Conversation) -> String {
+    let mut md = format!(
+        "# {} conversation: {}\n\n- **Messages:** {}\n- **Tool calls:** {}\n- **Tokens:** ~{}\n\
+         - **Cost:** {}\n",
+        capitalize(&conversation.provider),
+        conversation.model,
+        conversation.turns.len(),
+        conversation.tool_calls(),
+        conversation.tokens(),
+        format_cost(conversation.cost_usd)
+    );
+    if let Some(repo) = &conversation.repo {
+        md.push_str(&format!("- **Repo:** {}\n", repo.label()));
+    }
+
+    for turn in &conversation.turns {
+        md.push_str(&format!("\n## {}\n", capitalize(&turn.role)));
+        for block in &turn.blocks {
+            md.push('\n');
+            match block {
+                Block::Text(text) => {
+                    md.push_str(text.trim_end());
+                    md.push('\n');
+                }
+                Block::ToolCall { name, input } => {
+                    md.push_str(&format!("**Tool call:** `{}`\n\n", name));
+                    md.push_str(&fenced(&pretty(input), "json"));
+                }
+                Block::ToolResult {
+                    id,
+                    content,
+                    is_error,
+                } => {
+                    let label = if *is_error { "Tool error" } else { "Tool result" };
+                    md.push_str(&format!("**{}:** `{}`\n\n", label, id));
+                    md.push_str(&fenced(content, ""));
+                }
+                Block::Other { kind, json } => {
+                    md.push_str(&format!("**{} block**\n\n", kind));
+                    md.push_str(&fenced(&pretty(json), "json"));
+                }
+                Block::Step(calls) => {
+                    md.push_str(&format!(
+                        "**Step:** {} tool call{}\n\n| Tool | Arguments | Result |\n| --- | --- | --- |\n",
+                        calls.len(),
+                        if calls.len() == 1 { "" } else { "s" }
+                    ));
+                    for call in calls {
+                        md.push_str(&format!(
+                            "| `{}` | {} | {} |\n",
+                            call.name,
+                            table_cell(&step_arguments(&call.input)),
+                            step_outcome(call.result.as_ref())
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    md
+}
+
+/// `text` safe inside a Markdown table cell
+fn table_cell(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('|', "\\|")
+}
+
+/// Code fence longer than any backtick run inside `content`
+fn fenced(content: &str, lang: &str) -> String {
+    let longest = content
+        .split(|c| c != '`')
+        .map(str::len)
+        .max()
+        .unwrap_or(0);
+    let fence = "`".repeat(longest.max(2) + 1);
+    format!("{}{}\n{}\n{}\n", fence, lang, content.trim_end_matches('\n'), fence)
+}
+
+fn pretty(value: &Value) -> String {
+    serde_json::to_string_pretty(value).unwrap_or_default()
+}
+
+pub fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+const KEYWORDS: &[&str] = &[
+    "as", "async", "await", "break", "class", "const", "continue", "def", "else", "enum", "except",
+    "export", "false", "finally", "fn", "for", "from", "func", "function", "if", "impl", "import",
+    "in", "let", "match", "mod", "mut", "new", "null", "pub", "return", "self", "static", "struct",
+    "trait", "true", "try", "type", "use", "var", "while", "None", "True", "False",
+];
+
+/// Minimal keyword/string/number/comment highlighter. Output is HTML-escaped.
+fn highlight(code: &str, lang: &str) -> String {
+    let hash_comments = matches!(
+        lang,
+        "python" | "py" | "sh" | "bash" | "shell" | "ruby" | "rb" | "yaml" | "yml" | "toml"
+    );
+    let single_quote_strings = !matches!(lang, "rust" | "rs");
+
+    let chars: Vec<char> = code.chars().collect();
+    let mut out = String::with_capacity(code.len());
+    let mut i = 0;
+
+    let span = |out: &mut String, class: &str, text: &[char]| {
+        let text: String = text.iter().collect();
+        out.push_str(&format!("<span class=\"{}\">{}</span>", class, escape_html(&text)));
+    };
+
+    while i < chars.len() {
+        let c = chars[i];
+        let line_comment = (c == '/' && chars.get(i + 1) == Some(&'/')) || (c == '#' && hash_comments);
+
+        if line_comment {
+            let end = chars[i..]
+                .iter()
+                .position(|&c| c == '\n')
+                .map_or(chars.len(), |p| i + p);
+            span(&mut out, "hl-com", &chars[i..end]);
+            i = end;
+        } else if c == '"' || (c == '\'' && single_quote_strings) {
+            let mut end = i + 1;
+            while end < chars.len() && chars[end] != c && chars[end] != '\n' {
+                end += if chars[end] == '\\' { 2 } else { 1 };
+            }
+            let end = (end + 1).min(chars.len());
+            span(&mut out, "hl-str", &chars[i..end]);
+            i = end;
+        } else if c.is_ascii_digit() {
+            let end = chars[i..]
+                .iter()
+                .position(|c| !(c.is_ascii_alphanumeric() || *c == '.' || *c == '_'))
+                .map_or(chars.len(), |p| i + p);
+            span(&mut out, "hl-num", &chars[i..end]);
+            i = end;
+        } else if c.is_alphabetic() || c == '_' {
+            let end = chars[i..]
+                .iter()
+                .position(|c| !(c.is_alphanumeric() || *c == '_'))
+                .map_or(chars.len(), |p| i + p);
+            let word: String = chars[i..end].iter().collect();
+            if KEYWORDS.contains(&word.as_str()) {
+                span(&mut out, "hl-kw", &chars[i..end]);
+            } else {
+                out.push_str(&escape_html(&word));
+            }
+            i = end;
+        } else {
+            out.push_str(&escape_html(&c.to_string()));
+            i += 1;
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &str = include_str!("../tests/fixtures/conversation_anthropic.json");
+    const GOLDEN: &str = "tests/fixtures/conversation_anthropic.html";
+    const SESSION: &str = include_str!("../tests/fixtures/claude_code_session.json");
+
+    /// Compare `rendered` with the golden file, rewriting it instead when
+    /// `SHERLOCK_UPDATE_GOLDEN` is set
+    fn assert_golden(rendered: &str, golden: &str) {
+        let golden = Path::new(env!("CARGO_MANIFEST_DIR")).join(golden);
+        if std::env::var_os("SHERLOCK_UPDATE_GOLDEN").is_some() {
+            std::fs::write(&golden, rendered).unwrap();
+        }
+        assert_eq!(rendered, std::fs::read_to_string(&golden).unwrap());
+    }
+
+    #[test]
+    fn test_html_matches_golden() {
+        let body: Value = serde_json::from_str(FIXTURE).unwrap();
+        let mut conversation = Conversation::from_request_body(&body);
+        conversation.cost_usd = Some(0.0042);
+        assert_golden(&render_html(&conversation), GOLDEN);
+    }
+
+    #[test]
+    fn test_export_estimates_input_cost() {
+        let dir = std::env::temp_dir().join(format!("sherlock-export-cost-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("request.json");
+        std::fs::write(&input, FIXTURE).unwrap();
+        let prices = PriceTable::new(&crate::config::Config::default().pricing);
+
+        let path =
+            export_conversation(&input, ExportFormat::Markdown, None, false, &prices).unwrap();
+        let md = std::fs::read_to_string(&path).unwrap();
+        // ~84 input tokens at $3/Mtok for claude-sonnet-4
+        assert!(md.contains("- **Tokens:** ~84\n- **Cost:** $0.0003\n"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_collapsed_steps_match_golden() {
+        let body: Value = serde_json::from_str(SESSION).unwrap();
+        let full = Conversation::from_request_body(&body);
+        let collapsed = full.clone().collapse_steps();
+        assert_golden(
+            &render_markdown(&full),
+            "tests/fixtures/conversation_session.md",
+        );
+        assert_golden(
+            &render_markdown(&collapsed),
+            "tests/fixtures/conversation_session_collapsed.md",
+        );
+
+        // Two loops, then a reply, then a loop cut short by the user's text
+        let roles: Vec<_> = collapsed.turns.iter().map(|t| t.role.as_str()).collect();
+        assert_eq!(
+            roles,
+            [
+                "system",
+                "user",
+                "assistant",
+                "assistant",
+                "assistant",
+                "user",
+                "assistant",
+                "user"
+            ]
+        );
+        assert_eq!(collapsed.tool_calls(), full.tool_calls());
+        assert_eq!(collapsed.tokens(), full.tokens());
+
+        // Every bit of text is still there, in the same order
+        let texts = |c: &Conversation| -> Vec<String> {
+            c.blocks()
+                .filter_map(|b| match b {
+                    Block::Text(text) => Some(text.clone()),
+                    _ => None,
+                })
+                .collect()
+        };
+        assert_eq!(texts(&collapsed), texts(&full));
+
+        let html = render_html(&collapsed);
+        assert!(html.contains("<table class=\"step\">"));
+        assert!(html.contains(
+            "<tr class=\"error\"><td>Bash</td><td>command: cargo test parser, \
+             description: Run parser tests</td><td>exit 101, 5 lines</td></tr>"
+        ));
+    }
+
+    #[test]
+    fn test_step_table_cells() {
+        let command = serde_json::json!({"command": "a | b\n  c", "timeout": 5, "env": {"A": "1"}});
+        assert_eq!(step_arguments(&command), "command: a | b c, timeout: 5");
+        assert_eq!(
+            table_cell(&step_arguments(&command)),
+            "command: a \\| b c, timeout: 5"
+        );
+        let long = serde_json::json!({"query": "x".repeat(100)});
+        assert!(step_arguments(&long).chars().count() <= "query: ".len() + STEP_ARGUMENT_WIDTH);
+
+        let result = |content: &str, is_error| StepResult {
+            content: content.to_string(),
+            is_error,
+        };
+        assert_eq!(step_outcome(None), "no result");
+        assert_eq!(step_outcome(Some(&result("done", false))), "ok, 1 line");
+        assert_eq!(step_outcome(Some(&result("", true))), "error, 0 lines");
+        assert_eq!(
+            step_outcome(Some(&result("Exit code 2\nno such file", true))),
+            "exit 2, 2 lines"
+        );
+    }
+
+    #[test]
+    fn test_script_renders_as_text() {
+        let body: Value = serde_json::from_str(FIXTURE).unwrap();
+        let html = render_html(&Conversation::from_request_body(&body));
+
+        assert!(!html.contains("<script"));
+        assert!(!html.contains("src=\"http"));
+        assert!(html.contains("&lt;script&gt;"));
+    }
+
+    #[test]
+    fn test_openai_tool_calls() {
+        let body = serde_json::json!({
+            "model": "gpt-4o",
+            "messages": [
+                {"role": "user", "content": "What's in src?"},
+                {"role": "assistant", "content": null, "tool_calls": [{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": {"name": "list_dir", "arguments": "{\"path\":\"src\"}"}
+                }]},
+                {"role": "tool", "tool_call_id": "call_1", "content": "main.rs"}
+            ]
+        });
+        let conversation = Conversation::from_request_body(&body);
+
+        assert_eq!(conversation.provider, "openai");
+        assert_eq!(
+            conversation.turns[1].blocks,
+            vec![Block::ToolCall {
+                name: "list_dir".to_string(),
+                input: serde_json::json!({"path": "src"}),
+            }]
+        );
+        assert_eq!(
+            conversation.turns[2].blocks,
+            vec![Block::ToolResult {
+                id: "call_1".to_string(),
+                content: "main.rs".to_string(),
+                is_error: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_markdown_fences_nested_backticks() {
+        let body = serde_json::json!({
+            "model": "claude-3",
+            "messages": [
+                {"role": "user", "content": "Explain this"},
+                {"role": "assistant", "content": [
+                    {"type": "tool_use", "id": "t1", "name": "read", "input": {"path": "a.md"}}
+                ]},
+                {"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "t1", "content": "```rust\nfn main() {}\n```"}
+                ]}
+            ]
+        });
+        let md = render_markdown(&Conversation::from_request_body(&body));
+
+        assert!(md.starts_with("# Anthropic conversation: claude-3\n"));
+        assert!(md.contains("## User\n\nExplain this\n"));
+        assert!(md.contains("**Tool call:** `read`\n\n```json\n{\n  \"path\": \"a.md\"\n}\n```\n"));
+        assert!(md.contains("````\n```rust\nfn main() {}\n```\n````\n"));
+    }
+
+    #[test]
+    fn test_highlight() {
+        assert_eq!(
+            highlight("let x = \"<b>\"; // 42", "rust"),
+            "<span class=\"hl-kw\">let</span> x = <span class=\"hl-str\">&quot;&lt;b&gt;&quot;</span>; \
+             <span class=\"hl-com\">// 42</span>"
+        );
+        assert_eq!(
+            highlight("# it's 1", "python"),
+            "<span class=\"hl-com\"># it&#39;s 1</span>"
+        );
+    }
+}
@@ -0,0 +1,732 @@
+use chrono::{DateTime, Duration, Local, NaiveDate, TimeZone, Utc};
+use std::fmt;
+
+use crate::event::{RequestEvent, RequestInfo};
+use crate::index::IndexEntry;
+
+/// Fields that take comparisons, for error messages
+const FIELDS: &str = "provider, model or tokens";
+const DATES: &str = "YYYY-MM-DD, today, yesterday, 7d or 12h";
+
+/// Anything a filter can be evaluated against
+pub trait Subject {
+    fn provider(&self) -> &str;
+    fn model(&self) -> &str;
+    fn tokens(&self) -> u64;
+    fn timestamp(&self) -> DateTime<Utc>;
+    /// Message texts with their roles
+    fn messages(&self) -> Vec<(&str, &str)>;
+}
+
+impl Subject for RequestEvent {
+    fn provider(&self) -> &str {
+        &self.provider
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn tokens(&self) -> u64 {
+        self.tokens as u64
+    }
+
+    fn timestamp(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+
+    fn messages(&self) -> Vec<(&str, &str)> {
+        self.messages
+            .iter()
+            .map(|m| (m.role.as_str(), m.content.as_str()))
+            .collect()
+    }
+}
+
+/// Dashboard rows only keep the last user prompt, so text terms search that
+impl Subject for RequestInfo {
+    fn provider(&self) -> &str {
+        &self.provider
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn tokens(&self) -> u64 {
+        self.tokens as u64
+    }
+
+    fn timestamp(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+
+    fn messages(&self) -> Vec<(&str, &str)> {
+        self.prompt.iter().map(|p| ("user", p.as_str())).collect()
+    }
+}
+
+/// The index keeps no messages, so text and role terms never match it
+impl Subject for IndexEntry {
+    fn provider(&self) -> &str {
+        &self.provider
+    }
+
+    fn model(&self) -> &str {
+        self.model.as_deref().unwrap_or("unknown")
+    }
+
+    fn tokens(&self) -> u64 {
+        self.tokens.unwrap_or(0)
+    }
+
+    fn timestamp(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+
+    fn messages(&self) -> Vec<(&str, &str)> {
+        Vec::new()
+    }
+}
+
+/// A parsed filter expression such as
+/// `provider=anthropic model~sonnet tokens>50000 since:7d role:user "failing test"`.
+///
+/// Terms separated by spaces must all match; `OR` binds looser than that, a
+/// leading `-` negates a term or group and parentheses group. `role:` terms
+/// also limit text terms to messages with those roles.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    source: String,
+    expr: Option<Expr>,
+    roles: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    All(Vec<Expr>),
+    Any(Vec<Expr>),
+    Not(Box<Expr>),
+    Term(Term),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Term {
+    /// Lowercased word or phrase to find in message text
+    Text(String),
+    Role(String),
+    Since(DateTime<Utc>),
+    /// Exclusive upper bound
+    Until(DateTime<Utc>),
+    Provider(Op, String),
+    Model(Op, String),
+    Tokens(Op, u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Contains,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+impl Op {
+    fn symbol(self) -> &'static str {
+        match self {
+            Op::Eq => "=",
+            Op::Ne => "!=",
+            Op::Contains => "~",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+            Op::Lt => "<",
+            Op::Le => "<=",
+        }
+    }
+}
+
+/// Why a filter didn't parse, and which characters of it are at fault
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterError {
+    pub message: String,
+    /// Character offset of the offending token
+    pub start: usize,
+}
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at column {}", self.message, self.start + 1)
+    }
+}
+
+impl std::error::Error for FilterError {}
+
+fn error(start: usize, message: impl Into<String>) -> FilterError {
+    FilterError {
+        message: message.into(),
+        start,
+    }
+}
+
+impl Filter {
+    /// Parse `input`, resolving relative dates against the local clock
+    pub fn parse(input: &str) -> Result<Self, FilterError> {
+        Self::parse_at(input, Local::now())
+    }
+
+    /// Parse `input` with `today`, `7d` and plain dates relative to `now` and its time zone
+    pub fn parse_at<Tz: TimeZone>(input: &str, now: DateTime<Tz>) -> Result<Self, FilterError> {
+        let mut parser = Parser {
+            tokens: tokenize(input)?,
+            pos: 0,
+            end: input.chars().count(),
+            now,
+            roles: Vec::new(),
+        };
+        let expr = if parser.tokens.is_empty() {
+            None
+        } else {
+            let expr = parser.any()?;
+            if let Some(token) = parser.tokens.get(parser.pos) {
+                return Err(error(token.start, "unmatched )"));
+            }
+            Some(expr)
+        };
+        Ok(Self {
+            source: input.trim().to_string(),
+            expr,
+            roles: parser.roles,
+        })
+    }
+
+    /// The expression as typed
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Has no terms, so matches everything
+    pub fn is_empty(&self) -> bool {
+        self.expr.is_none()
+    }
+
+    /// Has text or `role:` terms, which need message contents
+    pub fn searches_messages(&self) -> bool {
+        self.expr.as_ref().is_some_and(Expr::searches_messages)
+    }
+
+    pub fn matches<S: Subject + ?Sized>(&self, subject: &S) -> bool {
+        self.expr
+            .as_ref()
+            .is_none_or(|expr| expr.eval(subject, &self.roles))
+    }
+}
+
+impl Expr {
+    fn searches_messages(&self) -> bool {
+        match self {
+            Expr::All(exprs) | Expr::Any(exprs) => exprs.iter().any(Expr::searches_messages),
+            Expr::Not(expr) => expr.searches_messages(),
+            Expr::Term(term) => matches!(term, Term::Text(_) | Term::Role(_)),
+        }
+    }
+
+    fn eval<S: Subject + ?Sized>(&self, subject: &S, roles: &[String]) -> bool {
+        match self {
+            Expr::All(exprs) => exprs.iter().all(|e| e.eval(subject, roles)),
+            Expr::Any(exprs) => exprs.iter().any(|e| e.eval(subject, roles)),
+            Expr::Not(expr) => !expr.eval(subject, roles),
+            Expr::Term(term) => term.eval(subject, roles),
+        }
+    }
+}
+
+impl Term {
+    fn eval<S: Subject + ?Sized>(&self, subject: &S, roles: &[String]) -> bool {
+        match self {
+            Term::Text(text) => subject.messages().iter().any(|(role, content)| {
+                (roles.is_empty() || roles.iter().any(|r| r.eq_ignore_ascii_case(role)))
+                    && content.to_lowercase().contains(text)
+            }),
+            Term::Role(wanted) => subject
+                .messages()
+                .iter()
+                .any(|(role, _)| wanted.eq_ignore_ascii_case(role)),
+            Term::Since(bound) => subject.timestamp() >= *bound,
+            Term::Until(bound) => subject.timestamp() < *bound,
+            Term::Provider(op, value) => compare_text(subject.provider(), *op, value),
+            Term::Model(op, value) => compare_text(subject.model(), *op, value),
+            Term::Tokens(op, value) => {
+                let tokens = subject.tokens();
+                match op {
+                    Op::Eq => tokens == *value,
+                    Op::Ne => tokens != *value,
+                    Op::Gt => tokens > *value,
+                    Op::Ge => tokens >= *value,
+                    Op::Lt => tokens < *value,
+                    Op::Le => tokens <= *value,
+                    Op::Contains => unreachable!("rejected by the parser"),
+                }
+            }
+        }
+    }
+}
+
+/// Case-insensitive `=`, `!=` and `~`; `value` is already lowercase
+fn compare_text(actual: &str, op: Op, value: &str) -> bool {
+    let actual = actual.to_lowercase();
+    match op {
+        Op::Eq => actual == value,
+        Op::Ne => actual != value,
+        _ => actual.contains(value),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Or,
+    Not,
+    /// A term with its quotes removed; `literal` is the character index
+    /// where quoting began, so operators after it are plain text
+    Word {
+        text: String,
+        literal: usize,
+    },
+}
+
+#[derive(Debug, Clone)]
+struct Spanned {
+    token: Token,
+    start: usize,
+}
+
+fn tokenize(input: &str) -> Result<Vec<Spanned>, FilterError> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i].is_whitespace() {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        let token = match chars[i] {
+            '(' => {
+                i += 1;
+                Token::Open
+            }
+            ')' => {
+                i += 1;
+                Token::Close
+            }
+            '-' if chars.get(i + 1).is_some_and(|c| !c.is_whitespace()) => {
+                i += 1;
+                Token::Not
+            }
+            _ => {
+                let mut text = String::new();
+                let mut literal = None;
+                while i < chars.len() && !chars[i].is_whitespace() && !matches!(chars[i], '(' | ')')
+                {
+                    if chars[i] == '"' {
+                        let open = i;
+                        literal.get_or_insert(text.chars().count());
+                        i += 1;
+                        while i < chars.len() && chars[i] != '"' {
+                            text.push(chars[i]);
+                            i += 1;
+                        }
+                        if i == chars.len() {
+                            return Err(error(open, "unterminated quote"));
+                        }
+                    } else {
+                        text.push(chars[i]);
+                    }
+                    i += 1;
+                }
+                match literal {
+                    None if text == "OR" => Token::Or,
+                    _ => Token::Word {
+                        literal: literal.unwrap_or(usize::MAX),
+                        text,
+                    },
+                }
+            }
+        };
+        tokens.push(Spanned { token, start });
+    }
+    Ok(tokens)
+}
+
+struct Parser<Tz: TimeZone> {
+    tokens: Vec<Spanned>,
+    pos: usize,
+    /// Length of the input, where errors about a missing term point
+    end: usize,
+    now: DateTime<Tz>,
+    roles: Vec<String>,
+}
+
+impl<Tz: TimeZone> Parser<Tz> {
+    /// Terms joined by `OR`
+    fn any(&mut self) -> Result<Expr, FilterError> {
+        let mut alternatives = vec![self.all()?];
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            alternatives.push(self.all()?);
+        }
+        Ok(if alternatives.len() == 1 {
+            alternatives.remove(0)
+        } else {
+            Expr::Any(alternatives)
+        })
+    }
+
+    /// Adjacent terms, up to the next `OR` or `)`
+    fn all(&mut self) -> Result<Expr, FilterError> {
+        let mut terms = Vec::new();
+        while !matches!(self.peek(), None | Some(Token::Or | Token::Close)) {
+            terms.push(self.unary()?);
+        }
+        if terms.is_empty() {
+            return Err(self.missing_term());
+        }
+        Ok(if terms.len() == 1 {
+            terms.remove(0)
+        } else {
+            Expr::All(terms)
+        })
+    }
+
+    fn unary(&mut self) -> Result<Expr, FilterError> {
+        let Some(token) = self.tokens.get(self.pos).cloned() else {
+            return Err(self.missing_term());
+        };
+        self.pos += 1;
+        match token.token {
+            Token::Not => Ok(Expr::Not(Box::new(self.unary()?))),
+            Token::Open => {
+                let inner = self.any()?;
+                if self.peek() != Some(&Token::Close) {
+                    return Err(error(token.start, "unclosed ("));
+                }
+                self.pos += 1;
+                Ok(inner)
+            }
+            Token::Word { text, literal } => {
+                Ok(Expr::Term(self.term(&text, literal, token.start)?))
+            }
+            Token::Or | Token::Close => {
+                self.pos -= 1;
+                Err(self.missing_term())
+            }
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|t| &t.token)
+    }
+
+    fn missing_term(&self) -> FilterError {
+        match self.tokens.get(self.pos) {
+            None => error(self.end, "expected a term at the end"),
+            Some(t) if t.token == Token::Or => error(t.start, "OR needs a term on both sides"),
+            Some(t) => error(t.start, "expected a term before )"),
+        }
+    }
+
+    /// One word: text to search for, `field<op>value` or `keyword:value`
+    fn term(&mut self, text: &str, literal: usize, start: usize) -> Result<Term, FilterError> {
+        let chars: Vec<char> = text.chars().collect();
+        let operator = chars
+            .iter()
+            .take(literal)
+            .position(|c| matches!(c, '=' | '!' | '~' | '<' | '>' | ':'));
+        let Some(at) = operator else {
+            return Ok(Term::Text(text.to_lowercase()));
+        };
+
+        let name: String = chars[..at].iter().collect::<String>().to_lowercase();
+        let next = chars.get(at + 1).copied();
+        let op = match (chars[at], next) {
+            (':', _) => None,
+            ('!', Some('=')) => Some(Op::Ne),
+            ('>', Some('=')) => Some(Op::Ge),
+            ('<', Some('=')) => Some(Op::Le),
+            ('=', _) => Some(Op::Eq),
+            ('~', _) => Some(Op::Contains),
+            ('>', _) => Some(Op::Gt),
+            ('<', _) => Some(Op::Lt),
+            _ => return Err(error(start + at, "expected != after !")),
+        };
+        let op_len = op.map_or(1, |op| op.symbol().chars().count());
+        let value: String = chars[at + op_len..].iter().collect();
+        let value_start = start + at + op_len;
+
+        if name.is_empty() {
+            return Err(error(start, "missing field name"));
+        }
+        if value.is_empty() {
+            return Err(error(start, format!("missing value after {}", name)));
+        }
+
+        let Some(op) = op else {
+            return match name.as_str() {
+                "role" => {
+                    let role = value.to_lowercase();
+                    self.roles.push(role.clone());
+                    Ok(Term::Role(role))
+                }
+                "since" | "until" => {
+                    let until = name == "until";
+                    let bound = self.date(&value, until).ok_or_else(|| {
+                        error(
+                            value_start,
+                            format!("expected {}, found \"{}\"", DATES, value),
+                        )
+                    })?;
+                    Ok(if until {
+                        Term::Until(bound)
+                    } else {
+                        Term::Since(bound)
+                    })
+                }
+                _ => Err(error(
+                    start,
+                    format!(
+                        "unknown keyword \"{}:\" (expected since, until or role; \
+                         quote the term to search for it)",
+                        name
+                    ),
+                )),
+            };
+        };
+
+        match name.as_str() {
+            "provider" | "model" => {
+                if !matches!(op, Op::Eq | Op::Ne | Op::Contains) {
+                    return Err(error(
+                        start + at,
+                        format!("{} only works on tokens", op.symbol()),
+                    ));
+                }
+                let value = value.to_lowercase();
+                Ok(match name.as_str() {
+                    "provider" => Term::Provider(op, value),
+                    _ => Term::Model(op, value),
+                })
+            }
+            "tokens" => {
+                if op == Op::Contains {
+                    return Err(error(start + at, "~ only works on provider and model"));
+                }
+                let count = value.replace('_', "").parse().map_err(|_| {
+                    error(
+                        value_start,
+                        format!("expected a number, found \"{}\"", value),
+                    )
+                })?;
+                Ok(Term::Tokens(op, count))
+            }
+            _ => Err(error(
+                start,
+                format!("unknown field \"{}\" (expected {})", name, FIELDS),
+            )),
+        }
+    }
+
+    /// Start of a `since:` range, or the exclusive end of an `until:` one
+    fn date(&self, value: &str, until: bool) -> Option<DateTime<Utc>> {
+        let value = value.to_lowercase();
+        let today = self.now.date_naive();
+        let day = match value.as_str() {
+            "today" => Some(today),
+            "yesterday" => today.pred_opt(),
+            _ => NaiveDate::parse_from_str(&value, "%Y-%m-%d").ok(),
+        };
+        if let Some(day) = day {
+            let day = if until { day.succ_opt()? } else { day };
+            return self
+                .now
+                .timezone()
+                .from_local_datetime(&day.and_hms_opt(0, 0, 0)?)
+                .earliest()
+                .map(|t| t.with_timezone(&Utc));
+        }
+
+        let (count, unit) = value.split_at(value.len().saturating_sub(1));
+        let count: i64 = count.parse().ok()?;
+        let ago = match unit {
+            "d" => Duration::try_days(count)?,
+            "h" => Duration::try_hours(count)?,
+            _ => return None,
+        };
+        Some(self.now.with_timezone(&Utc) - ago)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::Message;
+    use crate::parser::parse_request;
+
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 6, 10, 15, 30, 0).unwrap()
+    }
+
+    fn parse(input: &str) -> Filter {
+        Filter::parse_at(input, now()).unwrap()
+    }
+
+    fn parse_err(input: &str) -> FilterError {
+        Filter::parse_at(input, now()).unwrap_err()
+    }
+
+    fn event(provider: &str, model: &str, tokens: usize, days_ago: i64) -> RequestEvent {
+        let body = serde_json::json!({
+            "model": model,
+            "messages": [
+                {"role": "user", "content": "The failing test is in parser.rs"},
+                {"role": "assistant", "content": "Let me look at the Sonnet output"}
+            ]
+        });
+        let body = serde_json::to_vec(&body).unwrap();
+        let mut event = parse_request(&body, "/v1/messages", provider).unwrap();
+        event.tokens = tokens;
+        event.timestamp = now() - Duration::days(days_ago);
+        event
+    }
+
+    #[test]
+    fn test_fields_and_keywords() {
+        let sonnet = event("anthropic", "claude-3-5-sonnet", 60_000, 3);
+        let gpt = event("openai", "gpt-4o", 1_000, 0);
+
+        let filter = parse(r#"provider=anthropic model~sonnet tokens>50000 since:2024-06-01"#);
+        assert!(filter.matches(&sonnet));
+        assert!(!filter.matches(&gpt));
+
+        assert!(parse("provider=ANTHROPIC").matches(&sonnet));
+        assert!(parse("provider!=openai").matches(&sonnet));
+        assert!(parse("tokens<=1000 tokens>=1_000 tokens=1000").matches(&gpt));
+        assert!(!parse("tokens<1000").matches(&gpt));
+        assert!(parse("model~4O").matches(&gpt));
+
+        assert!(parse("since:today").matches(&gpt));
+        assert!(!parse("since:today").matches(&sonnet));
+        assert!(parse("since:7d until:yesterday").matches(&sonnet));
+        assert!(!parse("until:2024-06-06").matches(&sonnet));
+        assert!(parse("until:2024-06-06").matches(&event("anthropic", "m", 0, 4)));
+        assert!(parse("since:72h").matches(&sonnet));
+        assert!(!parse("since:71h").matches(&sonnet));
+
+        assert!(parse("").is_empty());
+        assert!(parse("   ").matches(&sonnet));
+    }
+
+    #[test]
+    fn test_text_terms_and_roles() {
+        let event = event("anthropic", "claude-3-5-sonnet", 10, 0);
+
+        assert!(parse(r#""failing test""#).matches(&event));
+        assert!(parse("FAILING parser.rs").matches(&event));
+        assert!(!parse(r#""test failing""#).matches(&event));
+        assert!(parse(r#"role:user "failing test""#).matches(&event));
+        // Roles scope text terms: the user never mentioned Sonnet
+        assert!(!parse("role:user sonnet").matches(&event));
+        assert!(parse("role:assistant sonnet").matches(&event));
+        assert!(!parse("role:system").matches(&event));
+        // A quoted operator is text, not a comparison
+        assert!(!parse(r#""tokens>5""#).matches(&event));
+        assert!(parse(r#"model="claude-3-5-sonnet""#).matches(&event));
+
+        let row = RequestInfo::from(&event);
+        assert!(parse("provider=anthropic failing").matches(&row));
+        // Rows only keep the last user prompt
+        assert!(!parse("role:assistant sonnet").matches(&row));
+    }
+
+    #[test]
+    fn test_precedence() {
+        let event = event("anthropic", "claude-3-5-sonnet", 10, 0);
+        // AND binds tighter than OR: (openai AND gpt) OR sonnet
+        assert!(parse("provider=openai model~gpt OR model~sonnet").matches(&event));
+        // ... but not the other way round: openai AND (gpt OR sonnet)
+        assert!(!parse("provider=openai (model~gpt OR model~sonnet)").matches(&event));
+        assert!(parse("provider=openai OR provider=gemini OR tokens=10").matches(&event));
+        // Negation binds tightest
+        assert!(!parse("-provider=anthropic OR provider=openai").matches(&event));
+        assert!(parse("-(provider=openai OR tokens>100)").matches(&event));
+        assert!(parse("--provider=anthropic").matches(&event));
+        assert!(!parse("-failing").matches(&event));
+
+        assert_eq!(
+            parse("a b OR c").expr,
+            Some(Expr::Any(vec![
+                Expr::All(vec![
+                    Expr::Term(Term::Text("a".into())),
+                    Expr::Term(Term::Text("b".into())),
+                ]),
+                Expr::Term(Term::Text("c".into())),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_errors_point_at_token() {
+        let cases = [
+            ("provider=x modle~sonnet", 11, "unknown field \"modle\""),
+            ("tokens>lots", 7, "expected a number, found \"lots\""),
+            ("tokens~5", 6, "~ only works on provider and model"),
+            ("model>5", 5, "> only works on tokens"),
+            ("since:lastweek", 6, "expected YYYY-MM-DD"),
+            ("since:2024-13-01", 6, "expected YYYY-MM-DD"),
+            ("http://example.com", 0, "unknown keyword \"http:\""),
+            ("a \"unfinished", 2, "unterminated quote"),
+            ("(a OR b", 0, "unclosed ("),
+            ("a b)", 3, "unmatched )"),
+            ("OR a", 0, "OR needs a term on both sides"),
+            ("a OR", 4, "expected a term at the end"),
+            ("a ()", 3, "expected a term before )"),
+            ("model=", 0, "missing value after model"),
+            ("=x", 0, "missing field name"),
+            ("model!x", 5, "expected != after !"),
+        ];
+        for (input, column, message) in cases {
+            let err = parse_err(input);
+            assert!(
+                err.message.starts_with(message),
+                "{}: {}",
+                input,
+                err.message
+            );
+            assert_eq!(err.start, column, "{}: {}", input, err.message);
+        }
+        assert_eq!(
+            parse_err("tokens>lots").to_string(),
+            "expected a number, found \"lots\" at column 8"
+        );
+    }
+
+    #[test]
+    fn test_row_subject() {
+        let mut info = RequestInfo::from(&event("openai", "gpt-4o", 5, 0));
+        info.prompt = None;
+        assert!(parse("provider=openai").matches(&info));
+        assert!(!parse("failing").matches(&info));
+        let message = Message {
+            role: "user".to_string(),
+            content: "hi".to_string(),
+            unknown_parts: vec![],
+        };
+        assert!(parse("role:user").matches(&RequestEvent {
+            messages: vec![message],
+            ..event("openai", "gpt-4o", 5, 0)
+        }));
+    }
+}
@@ -0,0 +1,343 @@
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::config::GoalsConfig;
+use crate::dashboard::format_number;
+use crate::index::IndexEntry;
+use crate::pricing::{format_cost, PriceTable};
+
+/// Days `sherlock stats --goals` and the dashboard look back over
+pub const GOAL_WINDOW_DAYS: u32 = 30;
+
+/// Per-day usage tracked against the configured daily goals.
+///
+/// Days are local calendar days in whatever timezone the caller passes,
+/// so a request just before midnight and one just after land on different days.
+#[derive(Debug, Clone)]
+pub struct GoalTracker {
+    daily_tokens: Option<u64>,
+    daily_cost_usd: Option<f64>,
+    days: BTreeMap<NaiveDate, DayUsage>,
+}
+
+/// What one day used; unpriced requests add no cost
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct DayUsage {
+    pub tokens: u64,
+    pub cost_usd: f64,
+}
+
+/// A day's usage against the goals that are set
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Progress {
+    pub used: DayUsage,
+    pub daily_tokens: Option<u64>,
+    pub daily_cost_usd: Option<f64>,
+}
+
+/// How many recent days stayed within the goal
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Streak {
+    /// Days in the window that stayed under the goal
+    pub days_under: u32,
+    /// Days in the window
+    pub days_total: u32,
+    /// Consecutive days under the goal, counting back from the most recent
+    pub current: u32,
+    /// Most consecutive days under the goal anywhere in the window
+    pub longest: u32,
+}
+
+impl Progress {
+    /// Whether the day went over any of the goals
+    pub fn over(&self) -> bool {
+        self.daily_tokens
+            .is_some_and(|goal| self.used.tokens > goal)
+            || self
+                .daily_cost_usd
+                .is_some_and(|goal| self.used.cost_usd > goal)
+    }
+}
+
+impl GoalTracker {
+    pub fn new(config: &GoalsConfig) -> Self {
+        Self {
+            daily_tokens: config.daily_tokens,
+            daily_cost_usd: config.daily_cost_usd,
+            days: BTreeMap::new(),
+        }
+    }
+
+    pub fn record<Tz: TimeZone>(
+        &mut self,
+        at: DateTime<Utc>,
+        tz: &Tz,
+        tokens: u64,
+        cost_usd: Option<f64>,
+    ) {
+        let day = at.with_timezone(tz).date_naive();
+        let usage = self.days.entry(day).or_default();
+        usage.tokens += tokens;
+        usage.cost_usd += cost_usd.unwrap_or(0.0);
+    }
+
+    /// Usage against the goals for the given day, if any goal is configured
+    pub fn progress(&self, day: NaiveDate) -> Option<Progress> {
+        if self.daily_tokens.is_none() && self.daily_cost_usd.is_none() {
+            return None;
+        }
+        Some(Progress {
+            used: self.days.get(&day).copied().unwrap_or_default(),
+            daily_tokens: self.daily_tokens,
+            daily_cost_usd: self.daily_cost_usd,
+        })
+    }
+
+    /// Streak over the `window` days ending with (and including) `today`.
+    /// Days without any usage count as under the goal.
+    pub fn streak(&self, today: NaiveDate, window: u32) -> Option<Streak> {
+        let mut streak = Streak {
+            days_under: 0,
+            days_total: window,
+            current: 0,
+            longest: 0,
+        };
+        let mut counting_current = true;
+        let mut run = 0;
+
+        for day in today.iter_days().rev().take(window as usize) {
+            let under = !self.progress(day)?.over();
+            if under {
+                streak.days_under += 1;
+                run += 1;
+                streak.longest = streak.longest.max(run);
+            } else {
+                run = 0;
+            }
+            if counting_current && under {
+                streak.current += 1;
+            } else {
+                counting_current = false;
+            }
+        }
+
+        Some(streak)
+    }
+}
+
+/// e.g. "700 / 1,000 tokens, $1.20 / $5.00"
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(goal) = self.daily_tokens {
+            parts.push(format!(
+                "{} / {} tokens",
+                format_number(self.used.tokens),
+                format_number(goal)
+            ));
+        }
+        if let Some(goal) = self.daily_cost_usd {
+            parts.push(format!(
+                "{} / {}",
+                format_cost(Some(self.used.cost_usd)),
+                format_cost(Some(goal))
+            ));
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// Daily goals against the archive index, for `sherlock stats --goals`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GoalReport {
+    pub today: Progress,
+    pub streak: Streak,
+}
+
+impl GoalReport {
+    /// Go over `entries` in `tz`'s local days; unset when no goal is set
+    pub fn build<Tz: TimeZone>(
+        config: &GoalsConfig,
+        entries: &[IndexEntry],
+        prices: &PriceTable,
+        tz: &Tz,
+        now: DateTime<Utc>,
+    ) -> Option<Self> {
+        let mut goals = GoalTracker::new(config);
+        for entry in entries {
+            let tokens = entry.tokens.unwrap_or(0);
+            goals.record(entry.timestamp, tz, tokens, entry.cost(prices));
+        }
+        let today = now.with_timezone(tz).date_naive();
+        Some(Self {
+            today: goals.progress(today)?,
+            streak: goals.streak(today, GOAL_WINDOW_DAYS)?,
+        })
+    }
+}
+
+impl fmt::Display for GoalReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = if self.today.over() { "over" } else { "under" };
+        writeln!(f, "Today: {} ({} goal)", self.today, status)?;
+        writeln!(
+            f,
+            "Last {} days: {} under goal, current streak {} days, longest {} days",
+            self.streak.days_total,
+            self.streak.days_under,
+            self.streak.current,
+            self.streak.longest
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::FixedOffset;
+
+    fn tracker(goal: u64) -> GoalTracker {
+        GoalTracker::new(&GoalsConfig {
+            daily_tokens: Some(goal),
+            daily_cost_usd: None,
+        })
+    }
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    fn date(s: &str) -> NaiveDate {
+        s.parse().unwrap()
+    }
+
+    fn used(progress: Option<Progress>) -> Option<(u64, u64)> {
+        progress.map(|p| (p.used.tokens, p.daily_tokens.unwrap()))
+    }
+
+    #[test]
+    fn test_midnight_rollover() {
+        let tz = FixedOffset::east_opt(0).unwrap();
+        let mut goals = tracker(1000);
+        goals.record(utc("2024-06-01T23:59:59Z"), &tz, 700, None);
+        goals.record(utc("2024-06-02T00:00:01Z"), &tz, 400, None);
+
+        assert_eq!(used(goals.progress(date("2024-06-01"))), Some((700, 1000)));
+        assert_eq!(used(goals.progress(date("2024-06-02"))), Some((400, 1000)));
+    }
+
+    #[test]
+    fn test_timezone_decides_the_day() {
+        // 23:30 UTC is already the next day in UTC+2, still the same day in UTC-5
+        let at = utc("2024-06-01T23:30:00Z");
+        let mut east = tracker(1000);
+        east.record(at, &FixedOffset::east_opt(2 * 3600).unwrap(), 10, None);
+        let mut west = tracker(1000);
+        west.record(at, &FixedOffset::west_opt(5 * 3600).unwrap(), 10, None);
+
+        assert_eq!(used(east.progress(date("2024-06-02"))), Some((10, 1000)));
+        assert_eq!(used(west.progress(date("2024-06-01"))), Some((10, 1000)));
+    }
+
+    #[test]
+    fn test_streak() {
+        let tz = FixedOffset::east_opt(0).unwrap();
+        let mut goals = tracker(1000);
+        goals.record(utc("2024-06-01T12:00:00Z"), &tz, 5000, None); // over
+        goals.record(utc("2024-06-02T12:00:00Z"), &tz, 900, None);
+        goals.record(utc("2024-06-04T12:00:00Z"), &tz, 1000, None); // exactly at goal
+
+        let streak = goals.streak(date("2024-06-04"), 30).unwrap();
+        assert_eq!(streak.current, 3);
+        assert_eq!(streak.days_under, 29);
+        assert_eq!(streak.days_total, 30);
+        // May 6th to 31st
+        assert_eq!(streak.longest, 26);
+    }
+
+    #[test]
+    fn test_cost_goal() {
+        let tz = FixedOffset::east_opt(0).unwrap();
+        let mut goals = GoalTracker::new(&GoalsConfig {
+            daily_tokens: Some(1_000_000),
+            daily_cost_usd: Some(5.0),
+        });
+        goals.record(utc("2024-06-01T09:00:00Z"), &tz, 1000, Some(4.0));
+        goals.record(utc("2024-06-01T10:00:00Z"), &tz, 1000, None);
+        let progress = goals.progress(date("2024-06-01")).unwrap();
+        assert!(!progress.over());
+        assert_eq!(
+            progress.to_string(),
+            "2,000 / 1,000,000 tokens, $4.00 / $5.00"
+        );
+
+        // Under the token goal, but over on cost
+        goals.record(utc("2024-06-01T11:00:00Z"), &tz, 1000, Some(1.5));
+        assert!(goals.progress(date("2024-06-01")).unwrap().over());
+        assert_eq!(goals.streak(date("2024-06-02"), 2).unwrap().current, 1);
+    }
+
+    #[test]
+    fn test_report_from_index() {
+        let entry = |at: &str, tokens, cost_usd| IndexEntry {
+            timestamp: utc(at),
+            id: 0,
+            provider: "anthropic".to_string(),
+            model: Some("claude-3-5-sonnet".to_string()),
+            tokens: Some(tokens),
+            output_tokens: None,
+            served_model: None,
+            service_tier: None,
+            requested_tier: None,
+            cost_usd,
+            unpriced: false,
+            no_context_limit: false,
+            status: Some(200),
+            latency_ms: None,
+            error: None,
+            response_ms: None,
+            throughput: None,
+            languages: Default::default(),
+            chaos: None,
+        };
+        let config = GoalsConfig {
+            daily_tokens: None,
+            daily_cost_usd: Some(1.0),
+        };
+        let prices = PriceTable::new(&crate::config::Config::default().pricing);
+        let entries = [
+            entry("2024-06-10T12:00:00Z", 1000, Some(2.0)),
+            // Indexed without a cost: 500,000 tokens at $3/Mtok
+            entry("2024-06-14T12:00:00Z", 500_000, None),
+            entry("2024-06-15T08:00:00Z", 1000, Some(0.25)),
+        ];
+        let tz = FixedOffset::east_opt(0).unwrap();
+        let now = utc("2024-06-15T12:00:00Z");
+
+        let report = GoalReport::build(&config, &entries, &prices, &tz, now).unwrap();
+        assert_eq!(report.today.used.cost_usd, 0.25);
+        assert_eq!(report.streak.days_under, 28);
+        assert_eq!(report.streak.current, 1);
+        // May 17th to June 9th
+        assert_eq!(report.streak.longest, 24);
+        assert_eq!(
+            report.to_string(),
+            "Today: $0.25 / $1.00 (under goal)\n\
+             Last 30 days: 28 under goal, current streak 1 days, longest 24 days\n"
+        );
+
+        assert_eq!(
+            GoalReport::build(&GoalsConfig::default(), &entries, &prices, &tz, now),
+            None
+        );
+    }
+
+    #[test]
+    fn test_no_goal_configured() {
+        let goals = GoalTracker::new(&GoalsConfig::default());
+        assert_eq!(goals.progress(date("2024-06-01")), None);
+        assert_eq!(goals.streak(date("2024-06-01"), 30), None);
+    }
+}
@@ -0,0 +1,622 @@
+use anyhow::{Context, Result};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+use crate::archive::archived_requests;
+use crate::config::Config;
+use crate::event::capitalize;
+use crate::export::{Block, Conversation};
+use crate::parser::count_tokens;
+use crate::text::truncate;
+
+/// Newest archived requests considered when no conversation is named
+const RECENT_CANDIDATES: usize = 20;
+
+/// Lists are cut to their most recent entries once dropping progress isn't enough
+const SHORT_LIST_LEN: usize = 10;
+
+/// Longest condensed progress item, in characters
+const SENTENCE_LEN: usize = 200;
+
+/// Injected context that isn't part of what the user asked for
+static SYSTEM_REMINDER: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?s)<system-reminder>.*?</system-reminder>").unwrap());
+
+/// Files named in Codex `apply_patch` bodies
+static PATCH_FILE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?m)^\*\*\* (?:Add|Update|Delete) File: (.+)$").unwrap());
+
+/// Tool input keys holding a file path, across Claude Code, Codex and Gemini CLI
+pub const PATH_KEYS: &[&str] = &[
+    "file_path",
+    "path",
+    "notebook_path",
+    "filePath",
+    "absolute_path",
+];
+
+/// Everything a handoff document is built from, pulled out of one conversation
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Handoff {
+    pub provider: String,
+    pub model: String,
+    pub turns: usize,
+    pub tool_calls: usize,
+    /// First thing the user asked for
+    pub task: String,
+    pub files: Vec<FileTouch>,
+    pub commands: Vec<String>,
+    /// Assistant updates between the start and the last one
+    pub progress: Vec<String>,
+    /// Model-written replacement for `progress`, from `handoff --llm`
+    pub summary: Option<String>,
+    /// The last thing the assistant said
+    pub last_state: String,
+    /// A user message the assistant hadn't answered yet
+    pub pending: Option<String>,
+    pub open_questions: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileTouch {
+    pub path: String,
+    pub edited: bool,
+}
+
+/// How much of the progress section survives, from most to least detailed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Detail {
+    Full,
+    Paragraph,
+    Sentence,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    detail: Detail,
+    /// Progress items kept, split between the oldest and newest
+    progress: usize,
+    /// Files and commands kept, newest first
+    list: usize,
+    /// Characters kept of the task and last state
+    text: usize,
+}
+
+impl Handoff {
+    pub fn extract(conversation: &Conversation) -> Self {
+        let mut handoff = Handoff {
+            provider: conversation.provider.clone(),
+            model: conversation.model.clone(),
+            turns: conversation.turns.len(),
+            tool_calls: conversation.tool_calls(),
+            ..Handoff::default()
+        };
+
+        let mut assistant_texts = Vec::new();
+        let mut last_user: Option<(usize, String)> = None;
+        for (index, turn) in conversation.turns.iter().enumerate() {
+            for block in &turn.blocks {
+                match block {
+                    Block::Text(text) => {
+                        let text = clean(text);
+                        if text.is_empty() {
+                            continue;
+                        }
+                        match turn.role.as_str() {
+                            "user" => {
+                                if handoff.task.is_empty() {
+                                    handoff.task = text.clone();
+                                }
+                                last_user = Some((index, text));
+                            }
+                            "assistant" | "model" => assistant_texts.push((index, text)),
+                            _ => {}
+                        }
+                    }
+                    Block::ToolCall { name, input } => handoff.record_tool_call(name, input),
+                    _ => {}
+                }
+            }
+        }
+
+        if let Some((index, text)) = assistant_texts.pop() {
+            handoff.open_questions = questions(&text);
+            handoff.last_state = text;
+            if let Some((user_index, user_text)) = last_user {
+                if user_index > index && user_text != handoff.task {
+                    handoff.pending = Some(user_text);
+                }
+            }
+        }
+        handoff.progress = assistant_texts.into_iter().map(|(_, text)| text).collect();
+        handoff
+    }
+
+    fn record_tool_call(&mut self, name: &str, input: &Value) {
+        let name = name.to_lowercase();
+        let edits = ["edit", "write", "patch", "replace", "create"]
+            .iter()
+            .any(|verb| name.contains(verb));
+
+        for key in PATH_KEYS {
+            if let Some(path) = input.get(*key).and_then(Value::as_str) {
+                self.touch(path, edits);
+            }
+        }
+        for text in strings(input) {
+            for cap in PATCH_FILE.captures_iter(text) {
+                self.touch(cap[1].trim(), true);
+            }
+        }
+
+        let command = match input.get("command") {
+            Some(Value::String(command)) => Some(command.clone()),
+            // Codex runs `["bash", "-lc", "<script>"]`
+            Some(Value::Array(argv)) => {
+                let argv: Vec<&str> = argv.iter().filter_map(Value::as_str).collect();
+                match argv.as_slice() {
+                    [_, "-c" | "-lc", script] => Some(script.to_string()),
+                    ["apply_patch", ..] => None,
+                    argv => Some(argv.join(" ")),
+                }
+            }
+            _ => None,
+        };
+        if let Some(command) = command.filter(|c| !c.trim().is_empty()) {
+            self.commands.retain(|c| c != &command);
+            self.commands.push(command);
+        }
+    }
+
+    /// Files keep their first-seen position; an edit outranks a read
+    fn touch(&mut self, path: &str, edited: bool) {
+        match self.files.iter_mut().find(|f| f.path == path) {
+            Some(file) => file.edited |= edited,
+            None => self.files.push(FileTouch {
+                path: path.to_string(),
+                edited,
+            }),
+        }
+    }
+
+    /// Markdown under `budget` tokens. Progress notes are condensed to their
+    /// first paragraph, then their first sentence, then dropped from the
+    /// middle out; lists and long texts are cut only if that isn't enough.
+    pub fn render(&self, budget: usize) -> String {
+        let mut limits = Limits {
+            detail: Detail::Full,
+            progress: self.progress.len(),
+            list: usize::MAX,
+            text: usize::MAX,
+        };
+        let fits = |limits: Limits| {
+            let doc = self.render_with(limits);
+            (count_tokens(&doc) <= budget).then_some(doc)
+        };
+
+        for detail in [Detail::Full, Detail::Paragraph, Detail::Sentence] {
+            limits.detail = detail;
+            if let Some(doc) = fits(limits) {
+                return doc;
+            }
+        }
+
+        // Most progress items that still fit
+        let (mut low, mut high) = (0, self.progress.len());
+        while low < high {
+            let mid = (low + high).div_ceil(2);
+            if fits(Limits {
+                progress: mid,
+                ..limits
+            })
+            .is_some()
+            {
+                low = mid;
+            } else {
+                high = mid - 1;
+            }
+        }
+        limits.progress = low;
+        if let Some(doc) = fits(limits) {
+            return doc;
+        }
+
+        limits.list = SHORT_LIST_LEN;
+        limits.text = 4_000;
+        loop {
+            let doc = self.render_with(limits);
+            if count_tokens(&doc) <= budget || limits.text <= SENTENCE_LEN {
+                return doc;
+            }
+            limits.text /= 2;
+        }
+    }
+
+    fn render_with(&self, limits: Limits) -> String {
+        let mut md = String::from("# Handoff: continuing an earlier session\n\n");
+        md.push_str(&format!(
+            "This picks up a session with {} ({}) after {} turns and {} tool calls. \
+             Review the state below before continuing.\n\n",
+            capitalize(&self.provider),
+            self.model,
+            self.turns,
+            self.tool_calls
+        ));
+
+        if !self.task.is_empty() {
+            md.push_str("## Original task\n\n");
+            md.push_str(&cut(&self.task, limits.text));
+            md.push_str("\n\n");
+        }
+
+        if !self.files.is_empty() {
+            md.push_str("## Files touched\n\n");
+            let skip = self.files.len().saturating_sub(limits.list);
+            if skip > 0 {
+                md.push_str(&format!("- … {} more\n", skip));
+            }
+            for file in &self.files[skip..] {
+                let what = if file.edited { "edited" } else { "read" };
+                md.push_str(&format!("- `{}` ({})\n", file.path, what));
+            }
+            md.push('\n');
+        }
+
+        if !self.commands.is_empty() {
+            md.push_str("## Commands run\n\n");
+            let skip = self.commands.len().saturating_sub(limits.list);
+            if skip > 0 {
+                md.push_str(&format!("- … {} earlier commands\n", skip));
+            }
+            for command in &self.commands[skip..] {
+                let command = command.lines().collect::<Vec<_>>().join(" && ");
+                md.push_str(&format!("- `{}`\n", truncate(&command, SENTENCE_LEN)));
+            }
+            md.push('\n');
+        }
+
+        if let Some(summary) = &self.summary {
+            md.push_str("## Progress so far\n\n");
+            md.push_str(summary.trim());
+            md.push_str("\n\n");
+        } else if !self.progress.is_empty() {
+            md.push_str("## Progress so far\n\n");
+            let keep = limits.progress.min(self.progress.len());
+            let head = keep.div_ceil(2);
+            let tail = self.progress.len() - (keep - head);
+            for (index, item) in self.progress.iter().enumerate() {
+                if index == head && head < tail {
+                    md.push_str(&format!("- … {} updates omitted\n", tail - head));
+                }
+                if index >= head && index < tail {
+                    continue;
+                }
+                let item = match limits.detail {
+                    Detail::Full => item.clone(),
+                    Detail::Paragraph => first_paragraph(item).to_string(),
+                    Detail::Sentence => truncate(first_sentence(item), SENTENCE_LEN),
+                };
+                md.push_str(&format!("- {}\n", item.replace('\n', "\n  ")));
+            }
+            md.push('\n');
+        }
+
+        if !self.last_state.is_empty() {
+            md.push_str("## Where it stopped\n\n");
+            md.push_str(&cut(&self.last_state, limits.text));
+            md.push_str("\n\n");
+        }
+
+        if let Some(pending) = &self.pending {
+            md.push_str("## Latest request (not yet answered)\n\n");
+            md.push_str(&cut(pending, limits.text));
+            md.push_str("\n\n");
+        }
+
+        if !self.open_questions.is_empty() {
+            md.push_str("## Open questions\n\n");
+            for question in &self.open_questions {
+                md.push_str(&format!("- {}\n", question));
+            }
+            md.push('\n');
+        }
+
+        md.truncate(md.trim_end().len());
+        md.push('\n');
+        md
+    }
+
+    /// Replace the progress list with a summary written by
+    /// `handoff.summary_model`, for conversations too long to condense well
+    pub async fn summarize_progress(&mut self, config: &Config, max_tokens: usize) -> Result<()> {
+        if self.progress.is_empty() {
+            return Ok(());
+        }
+        let handoff = &config.handoff;
+        let model = handoff
+            .summary_model
+            .as_deref()
+            .context("Set handoff.summary_model in the config to use --llm")?;
+        let provider = config
+            .providers
+            .get(&handoff.summary_provider)
+            .with_context(|| format!("Unknown summary provider {:?}", handoff.summary_provider))?;
+        let client = crate::tls::build_client(&handoff.summary_provider, provider.tls.as_ref())?;
+
+        let prompt = format!(
+            "Below are progress updates from an AI coding session, oldest first. \
+             Summarize them as a short bullet list of the key decisions made and the \
+             changes completed, so another assistant can continue the work. \
+             Reply with the list only.\n\n{}",
+            self.progress.join("\n\n---\n\n")
+        );
+        let request = match handoff.summary_provider.as_str() {
+            "anthropic" => client
+                .post(format!("{}/v1/messages", provider.base_url))
+                .header("x-api-key", api_key("ANTHROPIC_API_KEY")?)
+                .header("anthropic-version", "2023-06-01")
+                .json(&serde_json::json!({
+                    "model": model,
+                    "max_tokens": max_tokens,
+                    "messages": [{"role": "user", "content": prompt}],
+                })),
+            "openai" => client
+                .post(format!("{}/v1/chat/completions", provider.base_url))
+                .bearer_auth(api_key("OPENAI_API_KEY")?)
+                .json(&serde_json::json!({
+                    "model": model,
+                    "max_completion_tokens": max_tokens,
+                    "messages": [{"role": "user", "content": prompt}],
+                })),
+            other => anyhow::bail!(
+                "Summaries are only supported via anthropic or openai, not {}",
+                other
+            ),
+        };
+
+        let response: Value = request
+            .send()
+            .await
+            .context("Summary request failed")?
+            .error_for_status()
+            .context("Summary request failed")?
+            .json()
+            .await?;
+        let summary = response
+            .pointer("/content/0/text")
+            .or_else(|| response.pointer("/choices/0/message/content"))
+            .and_then(Value::as_str)
+            .context("Summary response had no text")?;
+        self.summary = Some(summary.to_string());
+        Ok(())
+    }
+}
+
+/// The archived request to hand off: `id` as a path or archive file stem, or
+/// else the longest of the most recent requests, since side requests (titles,
+/// quota checks) made alongside a session carry only a message or two
+pub fn find_conversation(archive_dir: &Path, id: Option<&str>) -> Result<PathBuf> {
+    if let Some(id) = id {
+        let path = PathBuf::from(id);
+        if path.is_file() {
+            return Ok(path);
+        }
+        let path = archive_dir.join(format!("{}.json", id.trim_end_matches(".json")));
+        if path.is_file() {
+            return Ok(path);
+        }
+        anyhow::bail!("No archived conversation {:?} in {:?}", id, archive_dir);
+    }
+
+    archived_requests(archive_dir)?
+        .into_iter()
+        .take(RECENT_CANDIDATES)
+        .map(|path| {
+            let turns = load_conversation(&path).map_or(0, |c| c.turns.len());
+            (path, turns)
+        })
+        .reduce(|best, candidate| {
+            if candidate.1 > best.1 {
+                candidate
+            } else {
+                best
+            }
+        })
+        .map(|(path, _)| path)
+        .with_context(|| format!("No archived conversations in {:?}", archive_dir))
+}
+
+pub fn load_conversation(path: &Path) -> Result<Conversation> {
+    let raw = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let body: Value = serde_json::from_str(&raw)
+        .with_context(|| format!("{} is not an archived JSON request", path.display()))?;
+    Ok(Conversation::from_request_body(&body))
+}
+
+fn api_key(var: &str) -> Result<String> {
+    std::env::var(var).with_context(|| format!("{} must be set to use --llm", var))
+}
+
+fn clean(text: &str) -> String {
+    SYSTEM_REMINDER.replace_all(text, "").trim().to_string()
+}
+
+/// Every string anywhere in a tool input
+fn strings(value: &Value) -> Vec<&str> {
+    match value {
+        Value::String(s) => vec![s.as_str()],
+        Value::Array(items) => items.iter().flat_map(strings).collect(),
+        Value::Object(map) => map.values().flat_map(strings).collect(),
+        _ => vec![],
+    }
+}
+
+/// Sentences ending in a question mark
+fn questions(text: &str) -> Vec<String> {
+    text.lines()
+        .flat_map(|line| line.split_inclusive(['.', '!', '?']))
+        .map(|s| s.trim().trim_start_matches(['-', '*', ' ']))
+        .filter(|s| s.ends_with('?') && s.len() > 1)
+        .map(str::to_string)
+        .collect()
+}
+
+fn first_paragraph(text: &str) -> &str {
+    text.split("\n\n").next().unwrap_or(text).trim()
+}
+
+fn first_sentence(text: &str) -> &str {
+    let line = text.lines().next().unwrap_or(text).trim();
+    match line.find(". ") {
+        Some(end) => &line[..=end],
+        None => line,
+    }
+}
+
+/// At most `max` characters, marking the cut
+fn cut(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text.to_string();
+    }
+    let kept: String = text.chars().take(max).collect();
+    format!("{} […]", kept.trim_end())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session() -> Conversation {
+        let body = serde_json::json!({
+            "model": "claude-sonnet-4-5",
+            "system": "You are a coding agent.",
+            "messages": [
+                {"role": "user", "content": [
+                    {"type": "text", "text": "<system-reminder>ignore me</system-reminder>"},
+                    {"type": "text", "text": "Add retries to the upload client."}
+                ]},
+                {"role": "assistant", "content": [
+                    {"type": "text", "text": "I'll start by reading the client. It lives in src/upload.rs."},
+                    {"type": "tool_use", "id": "t1", "name": "Read", "input": {"file_path": "src/upload.rs"}}
+                ]},
+                {"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "t1", "content": "fn upload() {}"}
+                ]},
+                {"role": "assistant", "content": [
+                    {"type": "text", "text": "Using exponential backoff with three attempts.\n\nDetails follow."},
+                    {"type": "tool_use", "id": "t2", "name": "Edit", "input": {"file_path": "src/upload.rs", "old_string": "a", "new_string": "b"}},
+                    {"type": "tool_use", "id": "t3", "name": "Bash", "input": {"command": "cargo test"}},
+                    {"type": "tool_use", "id": "t4", "name": "Bash", "input": {"command": "cargo test"}}
+                ]},
+                {"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "t4", "content": "ok"}
+                ]},
+                {"role": "assistant", "content": [
+                    {"type": "text", "text": "Retries are in and tests pass. Should the backoff cap be configurable? Done for now."}
+                ]},
+                {"role": "user", "content": "Also log each retry."}
+            ]
+        });
+        Conversation::from_request_body(&body)
+    }
+
+    #[test]
+    fn test_extract() {
+        let handoff = Handoff::extract(&session());
+
+        assert_eq!(handoff.task, "Add retries to the upload client.");
+        assert_eq!(
+            handoff.files,
+            [FileTouch {
+                path: "src/upload.rs".to_string(),
+                edited: true
+            }]
+        );
+        assert_eq!(handoff.commands, ["cargo test"]);
+        assert_eq!(handoff.progress.len(), 2);
+        assert!(handoff.last_state.starts_with("Retries are in"));
+        assert_eq!(handoff.pending.as_deref(), Some("Also log each retry."));
+        assert_eq!(
+            handoff.open_questions,
+            ["Should the backoff cap be configurable?"]
+        );
+
+        let md = handoff.render(10_000);
+        assert!(md.starts_with("# Handoff: continuing an earlier session\n"));
+        assert!(md.contains("## Original task\n\nAdd retries to the upload client.\n"));
+        assert!(md.contains("- `src/upload.rs` (edited)\n"));
+        assert!(md.contains("- `cargo test`\n"));
+        assert!(md.contains("## Latest request (not yet answered)\n\nAlso log each retry.\n"));
+        assert!(!md.contains("ignore me"));
+    }
+
+    #[test]
+    fn test_codex_shell_and_patch() {
+        let mut handoff = Handoff::default();
+        handoff.record_tool_call(
+            "shell",
+            &serde_json::json!({"command": ["bash", "-lc", "rg upload src"]}),
+        );
+        handoff.record_tool_call(
+            "shell",
+            &serde_json::json!({"command": ["apply_patch", "*** Begin Patch\n*** Update File: src/upload.rs\n@@\n*** End Patch"]}),
+        );
+        assert_eq!(handoff.commands, ["rg upload src"]);
+        assert_eq!(handoff.files[0].path, "src/upload.rs");
+        assert!(handoff.files[0].edited);
+    }
+
+    #[test]
+    fn test_render_stays_under_budget() {
+        let mut handoff = Handoff::extract(&session());
+        handoff.progress = (0..200)
+            .map(|i| format!("Step {} done. {}", i, "More detail here. ".repeat(40)))
+            .collect();
+
+        let full = handoff.render(usize::MAX);
+        assert!(full.contains("Step 100 done. More detail"));
+
+        for budget in [8_000, 1_000, 400] {
+            let md = handoff.render(budget);
+            assert!(
+                count_tokens(&md) <= budget,
+                "{} > {}",
+                count_tokens(&md),
+                budget
+            );
+            // The ends of the session survive condensing
+            assert!(md.contains("Add retries to the upload client."));
+            assert!(md.contains("Retries are in and tests pass."));
+        }
+
+        let md = handoff.render(1_000);
+        assert!(md.contains("- Step 0 done.\n"));
+        assert!(md.contains("- Step 199 done.\n"));
+        assert!(md.contains("updates omitted"));
+    }
+
+    #[test]
+    fn test_find_conversation_prefers_longest_recent() {
+        let dir = std::env::temp_dir().join(format!("sherlock-handoff-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, messages: usize| {
+            let messages: Vec<Value> = (0..messages)
+                .map(|_| serde_json::json!({"role": "user", "content": "hi"}))
+                .collect();
+            let body = serde_json::json!({"model": "m", "messages": messages});
+            std::fs::write(dir.join(name), body.to_string()).unwrap();
+        };
+        write("20250101_100000.000_anthropic.json", 12);
+        write("20250101_100001.000_anthropic.json", 1);
+
+        let found = find_conversation(&dir, None).unwrap();
+        assert!(found.ends_with("20250101_100000.000_anthropic.json"));
+        let found = find_conversation(&dir, Some("20250101_100001.000_anthropic")).unwrap();
+        assert!(found.ends_with("20250101_100001.000_anthropic.json"));
+        assert!(find_conversation(&dir, Some("missing")).is_err());
+    }
+}
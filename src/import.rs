@@ -0,0 +1,487 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::archive::{build_sinks, save_prompt};
+use crate::cli::ImportFormat;
+use crate::config::ArchiveConfig;
+use crate::event::RequestEvent;
+use crate::keys::encode_hex;
+use crate::metrics::ArchiveMetrics;
+use crate::models::ModelRegistry;
+use crate::redact::Redactor;
+
+/// Source ids of every imported record, one per line, kept in the archive directory
+const LEDGER_FILE: &str = ".imported";
+
+/// A foreign record converted to an event, with the id that makes re-imports no-ops
+#[derive(Debug)]
+pub struct Imported {
+    pub source_id: String,
+    pub event: RequestEvent,
+}
+
+/// Outcome of `sherlock import`
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    pub files: usize,
+    pub imported: usize,
+    /// Records already in the archive from an earlier import
+    pub duplicates: usize,
+    /// Lines that couldn't be read as a record
+    pub skipped: usize,
+}
+
+impl std::fmt::Display for ImportReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Imported {} requests from {} files ({} already imported, {} unreadable lines skipped)",
+            self.imported, self.files, self.duplicates, self.skipped
+        )
+    }
+}
+
+/// Convert everything under `path` and append it to the archive, skipping
+/// records a previous import already wrote
+pub async fn import(
+    format: ImportFormat,
+    path: &Path,
+    config: &ArchiveConfig,
+    redactor: &Redactor,
+    registry: &mut ModelRegistry,
+) -> Result<ImportReport> {
+    let files = source_files(format, path)?;
+    if files.is_empty() {
+        anyhow::bail!(
+            "No {} files found under {}",
+            format.extension(),
+            path.display()
+        );
+    }
+
+    tokio::fs::create_dir_all(&config.directory).await?;
+    let root = tokio::fs::canonicalize(&config.directory).await?;
+    let ledger_path = root.join(LEDGER_FILE);
+    let mut seen: HashSet<String> = match std::fs::read_to_string(&ledger_path) {
+        Ok(content) => content.lines().map(str::to_string).collect(),
+        Err(_) => HashSet::new(),
+    };
+    let mut ledger = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&ledger_path)
+        .with_context(|| format!("Failed to open {}", ledger_path.display()))?;
+
+    let mut sinks = build_sinks(config, &root);
+
+    let mut report = ImportReport {
+        files: files.len(),
+        ..ImportReport::default()
+    };
+    for file in &files {
+        let content = std::fs::read_to_string(file)
+            .with_context(|| format!("Failed to read {}", file.display()))?;
+        let (records, skipped) = parse(format, &content);
+        report.skipped += skipped;
+
+        for Imported {
+            source_id,
+            mut event,
+        } in records
+        {
+            if !seen.insert(source_id.clone()) {
+                report.duplicates += 1;
+                continue;
+            }
+            // The ledger only grows, so its length never repeats as a filename id
+            event.id = seen.len() as u64;
+            event.imported = true;
+
+            let metrics = ArchiveMetrics::default();
+            save_prompt(&event, &mut sinks, redactor, &metrics).await;
+            if let Some(error) = metrics.snapshot().last_error {
+                anyhow::bail!("Failed to archive {}: {}", source_id, error);
+            }
+            writeln!(ledger, "{}", source_id)?;
+            registry.record(&event.provider, &event.model, event.timestamp);
+            report.imported += 1;
+        }
+    }
+
+    registry.save()?;
+    Ok(report)
+}
+
+impl ImportFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ImportFormat::ClaudeCode | ImportFormat::SherlockJsonl => "jsonl",
+            ImportFormat::OpenaiUsage => "csv",
+        }
+    }
+}
+
+/// `path` itself, or every file with the format's extension below it, sorted
+fn source_files(format: ImportFormat, path: &Path) -> Result<Vec<PathBuf>> {
+    if path.is_file() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let mut files = Vec::new();
+    let mut dirs = vec![path.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries =
+            std::fs::read_dir(&dir).with_context(|| format!("Failed to read {}", dir.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path
+                .extension()
+                .is_some_and(|ext| ext == format.extension())
+            {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Records in one source file, and how many lines were unreadable
+pub fn parse(format: ImportFormat, content: &str) -> (Vec<Imported>, usize) {
+    match format {
+        ImportFormat::ClaudeCode => parse_claude_code(content),
+        ImportFormat::OpenaiUsage => parse_openai_usage(content),
+        ImportFormat::SherlockJsonl => parse_sherlock_jsonl(content),
+    }
+}
+
+/// A Claude Code session transcript (`~/.claude/projects/*/*.jsonl`). Every
+/// assistant message with usage is one API request, sent with the
+/// conversation up to that point.
+fn parse_claude_code(content: &str) -> (Vec<Imported>, usize) {
+    let mut records = Vec::new();
+    let mut skipped = 0;
+    let mut history: Vec<Value> = Vec::new();
+    // Claude Code writes one line per content block of a response
+    let mut last_response: Option<String> = None;
+
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        let Ok(entry) = serde_json::from_str::<Value>(line) else {
+            skipped += 1;
+            continue;
+        };
+        let kind = entry["type"].as_str();
+        let message = &entry["message"];
+        if !matches!(kind, Some("user" | "assistant")) || !message.is_object() {
+            continue;
+        }
+        let content = match &message["content"] {
+            Value::String(text) => vec![json!({"type": "text", "text": text})],
+            Value::Array(blocks) => blocks.clone(),
+            _ => vec![],
+        };
+
+        if kind == Some("user") {
+            history.push(json!({"role": "user", "content": content}));
+            last_response = None;
+            continue;
+        }
+
+        let response_id = message["id"].as_str().map(str::to_string);
+        if response_id.is_some() && response_id == last_response {
+            if let Some(Value::Array(blocks)) = history.last_mut().map(|m| &mut m["content"]) {
+                blocks.extend(content);
+            }
+            continue;
+        }
+
+        let model = message["model"].as_str().unwrap_or("unknown");
+        let usage = &message["usage"];
+        // Claude Code's own error placeholders never reached the API
+        if usage.is_object() && model != "<synthetic>" {
+            let tokens = [
+                "input_tokens",
+                "cache_creation_input_tokens",
+                "cache_read_input_tokens",
+            ]
+            .iter()
+            .filter_map(|field| usage[field].as_u64())
+            .sum::<u64>();
+            let body = json!({"model": model, "messages": history, "usage": usage});
+            let mut event = event_from_body(body, "/v1/messages", "anthropic");
+            event.tokens = tokens as usize;
+            if let Some(timestamp) = timestamp(&entry["timestamp"]) {
+                event.timestamp = timestamp;
+            }
+            let id = entry["requestId"].as_str().or(response_id.as_deref());
+            records.push(Imported {
+                source_id: match id {
+                    Some(id) => format!("claude-code:{}", id),
+                    None => format!("claude-code:{}", content_hash(line)),
+                },
+                event,
+            });
+        }
+
+        history.push(json!({"role": "assistant", "content": content}));
+        last_response = response_id;
+    }
+
+    (records, skipped)
+}
+
+/// An OpenAI usage export CSV. Rows are usage buckets rather than single
+/// requests, so each becomes one event carrying the bucket's input tokens.
+fn parse_openai_usage(content: &str) -> (Vec<Imported>, usize) {
+    let mut lines = content.lines().filter(|line| !line.trim().is_empty());
+    let Some(header) = lines.next() else {
+        return (vec![], 0);
+    };
+    let columns = csv_fields(header);
+    let column = |names: &[&str]| columns.iter().position(|c| names.contains(&c.as_str()));
+    let (Some(model_col), Some(tokens_col)) = (
+        column(&["model", "snapshot_id"]),
+        column(&["input_tokens", "n_context_tokens_total"]),
+    ) else {
+        return (vec![], lines.count() + 1);
+    };
+    let time_col = column(&["start_time_iso", "start_time", "timestamp"]);
+
+    let mut records = Vec::new();
+    let mut skipped = 0;
+    for line in lines {
+        let fields = csv_fields(line);
+        let field = |col: usize| fields.get(col).map(String::as_str).unwrap_or("");
+        let (model, Ok(tokens)) = (field(model_col), field(tokens_col).parse::<u64>()) else {
+            skipped += 1;
+            continue;
+        };
+        if model.is_empty() {
+            skipped += 1;
+            continue;
+        }
+
+        let row: Map<String, Value> = columns
+            .iter()
+            .zip(&fields)
+            .map(|(column, value)| (column.clone(), Value::String(value.clone())))
+            .collect();
+        let mut event = event_from_body(Value::Object(row), "/v1/chat/completions", "openai");
+        event.model = model.to_string();
+        event.tokens = tokens as usize;
+        if let Some(timestamp) = time_col.and_then(|col| timestamp(&json!(field(col)))) {
+            event.timestamp = timestamp;
+        }
+        records.push(Imported {
+            source_id: format!("openai-usage:{}", content_hash(line.trim())),
+            event,
+        });
+    }
+
+    (records, skipped)
+}
+
+/// The `events.jsonl` of a `sherlock record` bundle. Failed requests and
+/// markers are left out.
+fn parse_sherlock_jsonl(content: &str) -> (Vec<Imported>, usize) {
+    let mut records = Vec::new();
+    let mut skipped = 0;
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        let Ok(value) = serde_json::from_str::<Value>(line) else {
+            skipped += 1;
+            continue;
+        };
+        if value["status"] == "failed" || value["status"] == "marker" {
+            continue;
+        }
+        match serde_json::from_value::<RequestEvent>(value) {
+            Ok(event) => records.push(Imported {
+                source_id: format!("sherlock:{}", content_hash(line.trim())),
+                event,
+            }),
+            Err(_) => skipped += 1,
+        }
+    }
+    (records, skipped)
+}
+
+/// Parse a reconstructed request body, keeping it even if the parser rejects it
+fn event_from_body(body: Value, path: &str, provider: &str) -> RequestEvent {
+    let bytes = body.to_string().into_bytes();
+    crate::parser::parse_request(&bytes, path, provider).unwrap_or_else(|_| {
+        let mut event = crate::parser::minimal_event(&bytes, path, provider);
+        event.raw_body = body;
+        event
+    })
+}
+
+/// RFC 3339 strings and Unix seconds, as found in transcripts and usage exports
+fn timestamp(value: &Value) -> Option<DateTime<Utc>> {
+    let text = value.as_str()?;
+    if let Ok(secs) = text.parse::<i64>() {
+        return DateTime::from_timestamp(secs, 0);
+    }
+    DateTime::parse_from_rfc3339(text)
+        .ok()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+fn content_hash(text: &str) -> String {
+    encode_hex(&Sha256::digest(text.as_bytes())[..16])
+}
+
+/// Split one CSV line, honouring double-quoted fields with `""` escapes
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.trim_end_matches('\r').chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> String {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name);
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn test_claude_code_transcript() {
+        let (records, skipped) = parse(
+            ImportFormat::ClaudeCode,
+            &fixture("import_claude_code.jsonl"),
+        );
+        assert_eq!(skipped, 1);
+        // Two API calls; the split response and the synthetic error count once or not at all
+        assert_eq!(records.len(), 2);
+
+        let first = &records[0];
+        assert_eq!(first.source_id, "claude-code:req_011CUa1");
+        assert_eq!(first.event.model, "claude-sonnet-4-5-20250929");
+        assert_eq!(first.event.tokens, 3 + 1_200 + 9_800);
+        assert_eq!(
+            first.event.timestamp.to_rfc3339(),
+            "2025-09-30T10:00:05+00:00"
+        );
+        assert_eq!(first.event.messages.len(), 1);
+
+        // The second request carries the whole conversation, response blocks
+        // merged and its tool call read as a message of its own
+        let second = &records[1].event;
+        assert_eq!(second.tokens, 250 + 11_000);
+        assert_eq!(second.messages.len(), 4);
+        assert_eq!(
+            second.raw_body["messages"][1]["content"]
+                .as_array()
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(second.raw_body["usage"]["output_tokens"], 10);
+    }
+
+    #[test]
+    fn test_openai_usage_csv() {
+        let (records, skipped) = parse(
+            ImportFormat::OpenaiUsage,
+            &fixture("import_openai_usage.csv"),
+        );
+        assert_eq!(skipped, 1);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].event.model, "gpt-4o-2024-08-06");
+        assert_eq!(records[0].event.tokens, 120_000);
+        assert_eq!(
+            records[0].event.timestamp.to_rfc3339(),
+            "2025-01-01T00:00:00+00:00"
+        );
+        assert_eq!(records[0].event.raw_body["num_model_requests"], "37");
+        assert_eq!(records[1].event.model, "o3-mini");
+        assert_eq!(records[1].event.raw_body["project_name"], "Acme, \"R&D\"");
+        assert_ne!(records[0].source_id, records[1].source_id);
+    }
+
+    #[test]
+    fn test_sherlock_bundle_events() {
+        let (records, skipped) =
+            parse(ImportFormat::SherlockJsonl, &fixture("import_events.jsonl"));
+        assert_eq!(skipped, 0);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].event.model, "gpt-4o");
+        assert_eq!(records[0].event.tokens, 512);
+        assert_eq!(records[0].event.recording.as_deref(), Some("nightly"));
+    }
+
+    #[tokio::test]
+    async fn test_reimport_skips_duplicates() {
+        let dir = std::env::temp_dir().join(format!("sherlock-import-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let source = dir.join("session.jsonl");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&source, fixture("import_claude_code.jsonl")).unwrap();
+        let config = ArchiveConfig {
+            directory: dir.join("archive"),
+            ..ArchiveConfig::default()
+        };
+        let mut registry = ModelRegistry::default();
+
+        let report = import(
+            ImportFormat::ClaudeCode,
+            &source,
+            &config,
+            &Redactor::default(),
+            &mut registry,
+        )
+        .await
+        .unwrap();
+        assert_eq!((report.imported, report.duplicates), (2, 0));
+        let again = import(
+            ImportFormat::ClaudeCode,
+            &dir,
+            &config,
+            &Redactor::default(),
+            &mut registry,
+        )
+        .await
+        .unwrap();
+        assert_eq!((again.imported, again.duplicates), (0, 2));
+
+        assert_eq!(
+            crate::archive::archived_requests(&config.directory)
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(registry.entries()["claude-sonnet-4-5-20250929"].requests, 2);
+        let markdown = std::fs::read_dir(&config.directory)
+            .unwrap()
+            .filter_map(|entry| std::fs::read_to_string(entry.unwrap().path()).ok())
+            .find(|content| content.starts_with('#'))
+            .unwrap();
+        assert!(markdown.contains("**Imported:** yes"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
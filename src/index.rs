@@ -0,0 +1,651 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
+
+use crate::aggregate::same_model;
+use crate::context::context_limit;
+use crate::event::{RequestEvent, RequestFailure, Throughput};
+use crate::language::{self, LanguageMix};
+use crate::pricing::PriceTable;
+use crate::reliability::Sample;
+use crate::stats::Distribution;
+use crate::watermark::Peak;
+
+/// One line per archived request and failure, in the archive directory
+pub const INDEX_FILE: &str = "index.jsonl";
+
+/// Schema of index entries. Bump it together with an upgrade step in
+/// `read_index` for files written before.
+pub const INDEX_VERSION: u32 = 1;
+
+/// First line of the index
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    index_version: u32,
+}
+
+/// What the index remembers about a request
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub timestamp: DateTime<Utc>,
+    pub id: u64,
+    pub provider: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokens: Option<u64>,
+    /// Tokens the response generated, once it finished
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_tokens: Option<u64>,
+    /// Model the response names, when it isn't literally `model`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub served_model: Option<String>,
+    /// Service tier that served the request, else the one it asked for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service_tier: Option<String>,
+    /// Tier requested but not served, see `RequestEvent::tier_mismatch`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requested_tier: Option<String>,
+    /// Dollars at list price, see `RequestEvent::cost_usd`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cost_usd: Option<f64>,
+    /// Answered, but no `pricing` entry covered the model when indexed
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub unpriced: bool,
+    /// No context limit was known for the model when indexed
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub no_context_limit: bool,
+    /// Upstream HTTP status; unset when no response arrived
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    /// From forwarding the request to the response headers, or the failure
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Until the response body finished, or its first byte for event streams
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_ms: Option<u64>,
+    /// Output speed of a streamed response, see `RequestEvent::throughput`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub throughput: Option<Throughput>,
+    /// Approximate tokens per programming language; empty for failures and
+    /// entries indexed before languages were
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub languages: LanguageMix,
+    /// Fault chaos mode injected, see `RequestEvent::chaos`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chaos: Option<String>,
+}
+
+impl From<&RequestEvent> for IndexEntry {
+    fn from(event: &RequestEvent) -> Self {
+        Self {
+            timestamp: event.timestamp,
+            id: event.id,
+            provider: event.provider.clone(),
+            model: Some(event.model.clone()),
+            tokens: Some(event.tokens as u64),
+            output_tokens: event.output.map(|output| output.tokens),
+            served_model: event.served_model.clone(),
+            service_tier: event.tier().map(str::to_string),
+            requested_tier: event.tier_mismatch().map(str::to_string),
+            cost_usd: event.cost_usd,
+            // Rejected requests cost nothing, priced or not
+            unpriced: event.cost_usd.is_none()
+                && event.response.is_none_or(|response| response.status < 400),
+            no_context_limit: context_limit(&event.model).is_none(),
+            status: event.response.map(|response| response.status),
+            latency_ms: event.response.map(|response| response.latency_ms),
+            error: None,
+            response_ms: event.latency_ms,
+            throughput: event.throughput,
+            languages: language::classify(event),
+            chaos: event.chaos.clone(),
+        }
+    }
+}
+
+impl From<&RequestFailure> for IndexEntry {
+    fn from(failure: &RequestFailure) -> Self {
+        Self {
+            timestamp: failure.timestamp,
+            id: failure.id,
+            provider: failure.provider.clone(),
+            model: failure.model.clone(),
+            tokens: None,
+            output_tokens: None,
+            served_model: None,
+            service_tier: None,
+            requested_tier: None,
+            cost_usd: None,
+            unpriced: false,
+            no_context_limit: false,
+            status: None,
+            latency_ms: Some(failure.latency_ms),
+            error: Some(failure.error.clone()),
+            response_ms: None,
+            throughput: None,
+            languages: LanguageMix::new(),
+            chaos: None,
+        }
+    }
+}
+
+impl IndexEntry {
+    /// Whether the request failed or the upstream rejected it
+    pub fn failed(&self) -> bool {
+        self.error.is_some() || self.status.is_some_and(|status| status >= 400)
+    }
+
+    /// Dollars the request cost: as recorded, or else priced now from its
+    /// tokens at standard rates, for entries indexed before their model had
+    /// a price. Unset for failed requests and unpriced models.
+    pub fn cost(&self, prices: &PriceTable) -> Option<f64> {
+        if self.failed() {
+            return None;
+        }
+        self.cost_usd.or_else(|| {
+            let model = self.served_model.as_ref().or(self.model.as_ref())?;
+            prices.estimate(model, self.tokens?, self.output_tokens.unwrap_or(0))
+        })
+    }
+
+    /// Reliability outcome, unless nothing was measured (imported requests)
+    /// or chaos mode made it up
+    pub fn sample(&self) -> Option<Sample> {
+        if self.chaos.is_some() {
+            return None;
+        }
+        Some(Sample {
+            timestamp: self.timestamp,
+            provider: self.provider.clone(),
+            status: self.status.filter(|_| self.error.is_none()),
+            latency_ms: self.latency_ms?,
+        })
+    }
+}
+
+/// Append `entry` to the index under `root`, starting the file with its
+/// version header. Lines go out in one write, so readers see each one whole
+/// or cut short at the end of the file.
+pub async fn append(root: &Path, entry: &IndexEntry) -> Result<usize> {
+    let path = root.join(INDEX_FILE);
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .await
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut lines = String::new();
+    if file.metadata().await?.len() == 0 {
+        lines.push_str(&serde_json::to_string(&Header {
+            index_version: INDEX_VERSION,
+        })?);
+        lines.push('\n');
+    }
+    lines.push_str(&serde_json::to_string(entry)?);
+    lines.push('\n');
+    file.write_all(lines.as_bytes()).await?;
+    file.flush().await?;
+    Ok(lines.len())
+}
+
+/// Every entry in the index under `root`; none when there is no index yet
+pub fn read_index(root: &Path) -> Result<Vec<IndexEntry>> {
+    Ok(stream_index(root)?.collect())
+}
+
+/// Like `read_index`, reading one line at a time as the entries are taken.
+///
+/// The archive writer appends whole lines while this reads, so a last line
+/// without its newline is one still being written and is left out, as is an
+/// index whose header isn't complete yet.
+pub fn stream_index(root: &Path) -> Result<impl Iterator<Item = IndexEntry>> {
+    let path = root.join(INDEX_FILE);
+    let mut reader = match std::fs::File::open(&path) {
+        Ok(file) => Some(BufReader::new(file)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    let header = reader.as_mut().and_then(|reader| complete_line(reader, &path));
+    if let Some(line) = header {
+        let header: Header = serde_json::from_str(&line)
+            .with_context(|| format!("{} has no version header", path.display()))?;
+        if header.index_version > INDEX_VERSION {
+            anyhow::bail!(
+                "{} is at version {}, newer than this sherlock understands ({})",
+                path.display(),
+                header.index_version,
+                INDEX_VERSION
+            );
+        }
+    } else {
+        reader = None;
+    }
+    let read_path = path.clone();
+    let lines = std::iter::from_fn(move || {
+        let line = complete_line(reader.as_mut()?, &read_path);
+        // Anything after a partial line would be its tail
+        if line.is_none() {
+            reader = None;
+        }
+        line
+    });
+    Ok(lines
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(move |line| match serde_json::from_str(&line) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                tracing::warn!("Skipping unreadable index entry in {:?}: {}", path, e);
+                None
+            }
+        }))
+}
+
+/// The next line of `reader` if it has been written out in full
+fn complete_line(reader: &mut BufReader<std::fs::File>, path: &Path) -> Option<String> {
+    let mut line = String::new();
+    match reader.read_line(&mut line) {
+        Ok(_) if line.ends_with('\n') => Some(line),
+        Ok(_) => None,
+        Err(e) => {
+            tracing::warn!("Stopped reading {:?}: {}", path, e);
+            None
+        }
+    }
+}
+
+/// Requests, failures and tokens per provider, for `sherlock stats`
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct IndexSummary {
+    pub oldest: Option<DateTime<Utc>>,
+    pub newest: Option<DateTime<Utc>>,
+    pub providers: BTreeMap<String, ProviderTotals>,
+    /// Requests answered under another model name, most frequent first
+    pub served_models: Vec<ServedModel>,
+    /// Requests per service tier, for those that named or reported one
+    pub tiers: BTreeMap<String, TierTotals>,
+    /// The largest request, and its model's context window
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peak: Option<Peak>,
+    /// Models indexed without a price or a context limit
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub missing_metadata: BTreeMap<String, MissingMetadata>,
+    pub percentiles: Distribution,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct ProviderTotals {
+    pub requests: usize,
+    pub failed: usize,
+    pub tokens: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct TierTotals {
+    pub requests: usize,
+    pub tokens: u64,
+    /// Requests that asked for another tier
+    pub mismatched: usize,
+}
+
+/// What sherlock lacked for one model's requests, see `IndexEntry::unpriced`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct MissingMetadata {
+    pub requests: usize,
+    pub unpriced: bool,
+    pub no_context_limit: bool,
+}
+
+/// How often `served` answered requests for `requested`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ServedModel {
+    pub requested: String,
+    pub served: String,
+    pub requests: usize,
+    /// More than an alias or a date suffix apart, see `aggregate::same_model`
+    pub different: bool,
+}
+
+impl IndexSummary {
+    pub fn build(entries: &[IndexEntry]) -> Self {
+        let mut summary = Self {
+            oldest: entries.iter().map(|e| e.timestamp).min(),
+            newest: entries.iter().map(|e| e.timestamp).max(),
+            ..Self::default()
+        };
+        let mut served_models: BTreeMap<(&str, &str), usize> = BTreeMap::new();
+        for entry in entries {
+            if let (Some(requested), Some(served)) = (&entry.model, &entry.served_model) {
+                *served_models.entry((requested, served)).or_default() += 1;
+            }
+            let totals = summary.providers.entry(entry.provider.clone()).or_default();
+            totals.requests += 1;
+            if entry.failed() {
+                totals.failed += 1;
+            }
+            totals.tokens += entry.tokens.unwrap_or(0);
+            let percentiles = &mut summary.percentiles;
+            for (hist, value) in [
+                (&mut percentiles.request_tokens, entry.tokens),
+                (&mut percentiles.output_tokens, entry.output_tokens),
+                (&mut percentiles.latency_ms, entry.response_ms),
+            ] {
+                if let Some(value) = value {
+                    hist.record(value);
+                }
+            }
+            if let (Some(tokens), Some(model)) = (entry.tokens, &entry.model) {
+                if summary
+                    .peak
+                    .as_ref()
+                    .is_none_or(|peak| tokens > peak.tokens)
+                {
+                    summary.peak = Some(Peak::new(tokens, model));
+                }
+            }
+            if entry.unpriced || entry.no_context_limit {
+                let model = entry.model.as_deref().unwrap_or("unknown");
+                let missing = summary
+                    .missing_metadata
+                    .entry(model.to_string())
+                    .or_default();
+                missing.requests += 1;
+                missing.unpriced |= entry.unpriced;
+                missing.no_context_limit |= entry.no_context_limit;
+            }
+            if let Some(tier) = &entry.service_tier {
+                let totals = summary.tiers.entry(tier.to_ascii_lowercase()).or_default();
+                totals.requests += 1;
+                totals.tokens += entry.tokens.unwrap_or(0);
+                totals.mismatched += usize::from(entry.requested_tier.is_some());
+            }
+        }
+        summary.served_models = served_models
+            .into_iter()
+            .map(|((requested, served), requests)| ServedModel {
+                requested: requested.to_string(),
+                served: served.to_string(),
+                requests,
+                different: !same_model(requested, served),
+            })
+            .collect();
+        summary.served_models.sort_by_key(|served| Reverse(served.requests));
+        summary
+    }
+}
+
+impl fmt::Display for IndexSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (Some(oldest), Some(newest)) = (self.oldest, self.newest) else {
+            return writeln!(f, "No indexed requests yet");
+        };
+        writeln!(
+            f,
+            "Indexed requests from {} to {}",
+            oldest.format("%Y-%m-%d %H:%M"),
+            newest.format("%Y-%m-%d %H:%M")
+        )?;
+        for (provider, totals) in &self.providers {
+            writeln!(
+                f,
+                "  {:<12} {} requests, {} failed, {} tokens",
+                provider, totals.requests, totals.failed, totals.tokens
+            )?;
+        }
+        for (tier, totals) in &self.tiers {
+            write!(
+                f,
+                "  tier {:<8} {} requests, {} tokens",
+                tier, totals.requests, totals.tokens
+            )?;
+            match totals.mismatched {
+                0 => writeln!(f)?,
+                n => writeln!(f, ", {} asked for another tier", n)?,
+            }
+        }
+        for served in &self.served_models {
+            writeln!(
+                f,
+                "  requested {}, served {}: {} {}{}",
+                served.requested,
+                served.served,
+                served.requests,
+                if served.requests == 1 { "time" } else { "times" },
+                if served.different { " (different model)" } else { "" }
+            )?;
+        }
+        if let Some(peak) = &self.peak {
+            write!(
+                f,
+                "  largest request {} tokens, {}",
+                peak.tokens, peak.model
+            )?;
+            match peak.percent() {
+                Some(percent) => writeln!(f, " ({:.0}% of its context window)", percent)?,
+                None => writeln!(f)?,
+            }
+        }
+        for (model, missing) in &self.missing_metadata {
+            let what = match (missing.unpriced, missing.no_context_limit) {
+                (true, true) => "pricing entry or context limit",
+                (true, false) => "pricing entry",
+                _ => "context limit",
+            };
+            writeln!(
+                f,
+                "  no {} for {}: {} {}",
+                what,
+                model,
+                missing.requests,
+                if missing.requests == 1 { "request" } else { "requests" }
+            )?;
+        }
+        write!(f, "{}", self.percentiles)
+    }
+}
+
+/// Tokens per language over the index, for `sherlock stats --by-language`
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct LanguageSummary {
+    pub tokens: LanguageMix,
+    /// Requests counted
+    pub requests: usize,
+    /// Requests indexed before languages were, left out
+    pub unclassified: usize,
+}
+
+impl LanguageSummary {
+    pub fn build(entries: &[IndexEntry]) -> Self {
+        let mut summary = Self::default();
+        for entry in entries.iter().filter(|entry| entry.error.is_none()) {
+            if entry.languages.is_empty() && entry.tokens.unwrap_or(0) > 0 {
+                summary.unclassified += 1;
+                continue;
+            }
+            summary.requests += 1;
+            language::merge(&mut summary.tokens, &entry.languages);
+        }
+        summary
+    }
+}
+
+impl fmt::Display for LanguageSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total: u64 = self.tokens.values().sum();
+        if total == 0 {
+            writeln!(f, "No classified requests yet")?;
+        } else {
+            writeln!(f, "Tokens by language over {} requests", self.requests)?;
+            let mut languages: Vec<_> = self.tokens.iter().collect();
+            languages.sort_by(|a, b| b.1.cmp(a.1));
+            for (language, tokens) in languages {
+                writeln!(
+                    f,
+                    "  {:<12} {:>10} tokens  {:>5.1}%",
+                    language,
+                    tokens,
+                    *tokens as f64 * 100.0 / total as f64
+                )?;
+            }
+        }
+        if self.unclassified > 0 {
+            writeln!(
+                f,
+                "{} requests indexed before language tagging aren't counted",
+                self.unclassified
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::ResponseInfo;
+    use std::io::Write;
+
+    #[tokio::test]
+    async fn test_index_round_trip() {
+        let root = std::env::temp_dir().join(format!("sherlock-index-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        assert!(read_index(&root).unwrap().is_empty());
+
+        let mut event = crate::parser::minimal_event(b"hi", "/v1/messages", "anthropic");
+        event.id = 3;
+        event.response = Some(ResponseInfo {
+            status: 529,
+            latency_ms: 420,
+        });
+        let failure = RequestFailure {
+            timestamp: Utc::now(),
+            id: 4,
+            provider: "anthropic".to_string(),
+            model: None,
+            latency_ms: 30_000,
+            error: "upstream error: timed out".to_string(),
+        };
+        append(&root, &IndexEntry::from(&event)).await.unwrap();
+        append(&root, &IndexEntry::from(&failure)).await.unwrap();
+        event.model = "claude-3-5-sonnet".to_string();
+        let alias = "claude-3-5-sonnet-20241022";
+        for served in [alias, alias, "claude-3-opus"] {
+            event.served_model = Some(served.to_string());
+            append(&root, &IndexEntry::from(&event)).await.unwrap();
+        }
+
+        let content = std::fs::read_to_string(root.join(INDEX_FILE)).unwrap();
+        assert!(content.starts_with("{\"index_version\":1}\n"));
+        let entries = read_index(&root).unwrap();
+        assert_eq!(entries.len(), 5);
+        let samples: Vec<Sample> = entries.iter().filter_map(IndexEntry::sample).collect();
+        assert_eq!(samples[0].status, Some(529));
+        assert_eq!((samples[1].status, samples[1].latency_ms), (None, 30_000));
+        let summary = IndexSummary::build(&entries);
+        assert_eq!(summary.providers["anthropic"].failed, 5);
+        let text = summary.to_string();
+        assert!(text.contains(
+            "requested claude-3-5-sonnet, served claude-3-5-sonnet-20241022: 2 times\n"
+        ));
+        assert!(text.contains("served claude-3-opus: 1 time (different model)"));
+        assert!(!text.contains("tier"));
+
+        // Requests are counted per tier, along with the ones that asked for another
+        let mut tiered = IndexEntry::from(&event);
+        tiered.service_tier = Some("priority".to_string());
+        tiered.tokens = Some(1_000);
+        let mut downgraded = tiered.clone();
+        downgraded.service_tier = Some("default".to_string());
+        downgraded.requested_tier = Some("priority".to_string());
+        let text = IndexSummary::build(&[tiered, downgraded.clone(), downgraded]).to_string();
+        assert!(text.contains("  tier priority 1 requests, 1000 tokens\n"));
+        assert!(text.contains("  tier default  2 requests, 2000 tokens, 2 asked for another"));
+
+        // The largest request, against its model's window when known
+        let mut small = IndexEntry::from(&event);
+        small.tokens = Some(1_000);
+        let mut largest = small.clone();
+        largest.tokens = Some(150_000);
+        let summary = IndexSummary::build(&[small.clone(), largest]);
+        assert_eq!(summary.peak.as_ref().map(|peak| peak.tokens), Some(150_000));
+        assert!(summary.to_string().contains(
+            "  largest request 150000 tokens, claude-3-5-sonnet (75% of its context window)\n"
+        ));
+        small.model = Some("llama3".to_string());
+        let text = IndexSummary::build(&[small.clone()]).to_string();
+        assert!(
+            text.contains("  largest request 1000 tokens, llama3\n"),
+            "{}",
+            text
+        );
+
+        // Models sherlock had no price or context limit for are flagged
+        assert!(!text.contains("no pricing entry"));
+        let mut unknown = event.clone();
+        unknown.model = "in-house-7b".to_string();
+        unknown.served_model = None;
+        unknown.response = Some(ResponseInfo {
+            status: 200,
+            latency_ms: 420,
+        });
+        let flagged = IndexEntry::from(&unknown);
+        assert!(flagged.unpriced && flagged.no_context_limit);
+        // Never priced, as if `pricing` had no entry for it
+        unknown.model = "claude-3-5-sonnet".to_string();
+        let unpriced = IndexEntry::from(&unknown);
+        assert!(unpriced.unpriced && !unpriced.no_context_limit);
+        assert!(!IndexEntry::from(&failure).unpriced);
+        let summary = IndexSummary::build(&[flagged.clone(), flagged, unpriced]);
+        assert_eq!(summary.missing_metadata["in-house-7b"].requests, 2);
+        let missing = summary.to_string();
+        assert!(
+            missing.contains("  no pricing entry or context limit for in-house-7b: 2 requests\n")
+        );
+        assert!(missing.contains("  no pricing entry for claude-3-5-sonnet: 1 request\n"));
+
+        // Percentiles for whatever was measured
+        assert!(text.ends_with(
+            "                        p50        p90        p99\n  \
+             tokens              1,000      1,000      1,000\n"
+        ));
+        let mut answered = small;
+        answered.output_tokens = Some(300);
+        answered.response_ms = Some(1_200);
+        let summary = IndexSummary::build(&[answered]);
+        assert!(summary.to_string().ends_with(
+            "  output tokens         300        300        300\n  \
+             latency ms          1,200      1,200      1,200\n"
+        ));
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["percentiles"]["output_tokens"]["p90"], 300);
+
+        // A line still being appended is left out until its newline lands
+        let line = serde_json::to_string(&IndexEntry::from(&failure)).unwrap();
+        let (head, tail) = line.split_at(line.len() / 2);
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(root.join(INDEX_FILE))
+            .unwrap();
+        file.write_all(head.as_bytes()).unwrap();
+        assert_eq!(read_index(&root).unwrap().len(), 5);
+        file.write_all(format!("{}\n", tail).as_bytes()).unwrap();
+        assert_eq!(read_index(&root).unwrap().len(), 6);
+        // So is an index whose header is only partly written
+        std::fs::write(root.join(INDEX_FILE), "{\"index_ver").unwrap();
+        assert!(read_index(&root).unwrap().is_empty());
+
+        // Indexes from a later release aren't misread
+        std::fs::write(root.join(INDEX_FILE), "{\"index_version\":2}\n").unwrap();
+        assert!(read_index(&root).is_err());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,229 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+use crate::event::RequestEvent;
+use crate::parser::{count_tokens, parse_request, schema_drift, ParseError};
+use crate::pricing::{format_cost, PriceTable};
+
+/// Top-level fields holding conversation content rather than parameters
+const CONTENT_FIELDS: &[&str] = &[
+    "model",
+    "messages",
+    "system",
+    "contents",
+    "systemInstruction",
+    "tools",
+];
+
+/// What the parser made of a request body, for `sherlock parse` and the
+/// proxy's estimate endpoint
+#[derive(Debug, Clone, Serialize)]
+pub struct ParseReport {
+    pub provider: String,
+    pub path: String,
+    pub model: String,
+    pub tokens: usize,
+    /// Dollars for the input at the model's standard rate; null when the
+    /// `pricing` config has no entry for it
+    pub input_cost_usd: Option<f64>,
+    pub messages: Vec<MessageSummary>,
+    /// Top-level request settings such as `max_tokens` or `temperature`
+    pub parameters: BTreeMap<String, Value>,
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MessageSummary {
+    pub role: String,
+    pub tokens: usize,
+    /// Content block types the parser doesn't model
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unknown_types: Vec<String>,
+}
+
+/// Run a body through `parse_request` exactly as the proxy would
+pub fn parse_body(
+    body: &[u8],
+    provider: &str,
+    path: &str,
+    prices: &PriceTable,
+) -> Result<ParseReport, ParseError> {
+    Ok(report(&parse_request(body, path, provider)?, prices))
+}
+
+/// Summarize a parsed request
+pub fn report(event: &RequestEvent, prices: &PriceTable) -> ParseReport {
+    let messages: Vec<MessageSummary> = event
+        .messages
+        .iter()
+        .map(|message| MessageSummary {
+            role: message.role.clone(),
+            tokens: count_tokens(&message.content),
+            unknown_types: message
+                .unknown_parts
+                .iter()
+                .map(|part| part.kind.clone())
+                .collect(),
+        })
+        .collect();
+
+    let mut warnings: Vec<String> = messages
+        .iter()
+        .enumerate()
+        .flat_map(|(index, message)| {
+            message.unknown_types.iter().map(move |kind| {
+                format!(
+                    "unknown content block type {:?} in message {}",
+                    kind,
+                    index + 1
+                )
+            })
+        })
+        .collect();
+    warnings.extend(
+        schema_drift(event)
+            .fields
+            .iter()
+            .map(|field| format!("unknown request field {:?}", field)),
+    );
+    let input_cost_usd = prices.estimate(&event.model, event.tokens as u64, 0);
+    if event.model == "unknown" {
+        warnings.push("no model named in the request".to_string());
+    } else if input_cost_usd.is_none() {
+        warnings.push(format!("no pricing entry for {:?}", event.model));
+    }
+    if event.tokens == 0 {
+        warnings.push("no text found to count tokens from".to_string());
+    }
+
+    let parameters = event
+        .raw_body
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter(|(key, _)| !CONTENT_FIELDS.contains(&key.as_str()))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+
+    ParseReport {
+        provider: event.provider.clone(),
+        path: event.path.clone(),
+        model: event.model.clone(),
+        tokens: event.tokens,
+        input_cost_usd,
+        messages,
+        parameters,
+        warnings,
+    }
+}
+
+impl std::fmt::Display for ParseReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Provider:  {}", self.provider)?;
+        writeln!(f, "Path:      {}", self.path)?;
+        writeln!(f, "Model:     {}", self.model)?;
+        writeln!(f, "Tokens:    {}", self.tokens)?;
+        writeln!(f, "Cost:      {} input", format_cost(self.input_cost_usd))?;
+
+        if !self.parameters.is_empty() {
+            writeln!(f, "Parameters:")?;
+            for (key, value) in &self.parameters {
+                writeln!(f, "  {} = {}", key, value)?;
+            }
+        }
+
+        writeln!(f, "Messages ({}):", self.messages.len())?;
+        for (index, message) in self.messages.iter().enumerate() {
+            writeln!(
+                f,
+                "  {:>3}. {:<10} {:>8} tokens",
+                index + 1,
+                message.role,
+                message.tokens
+            )?;
+        }
+
+        if !self.warnings.is_empty() {
+            writeln!(f, "Warnings:")?;
+            for warning in &self.warnings {
+                writeln!(f, "  {}", warning)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_fixture() {
+        let body = include_bytes!("../tests/fixtures/conversation_anthropic.json");
+        let prices = PriceTable::new(&crate::config::Config::default().pricing);
+        let report = parse_body(body, "anthropic", "/v1/messages", &prices).unwrap();
+
+        assert_eq!(report.model, "claude-sonnet-4-20250514");
+        assert_eq!(
+            report.input_cost_usd,
+            prices.estimate(&report.model, report.tokens as u64, 0)
+        );
+        assert!(report.input_cost_usd.is_some());
+        assert!(report.tokens > 0);
+        let roles: Vec<_> = report.messages.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(
+            roles,
+            ["system", "user", "assistant", "tool_use", "user", "assistant"]
+        );
+        assert!(report.messages[0].tokens > 0);
+        assert_eq!(report.parameters["max_tokens"], 8192);
+        assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+
+        let text = report.to_string();
+        assert!(text.contains("Model:     claude-sonnet-4-20250514\n"));
+        assert!(text.contains("Cost:      $0.0"));
+        assert!(text.contains("  max_tokens = 8192\n"));
+        assert!(text.contains("Messages (6):\n"));
+    }
+
+    #[test]
+    fn test_unknown_blocks_and_fields_warn() {
+        let body = br#"{
+            "model": "claude-3",
+            "messages": [{"role": "user", "content": [
+                {"type": "text", "text": "hi"},
+                {"type": "hologram", "data": "..."}
+            ]}],
+            "telepathy": true
+        }"#;
+        let report = parse_body(body, "anthropic", "/v1/messages", &PriceTable::default()).unwrap();
+
+        assert_eq!(report.messages[0].unknown_types, ["hologram"]);
+        assert_eq!(report.input_cost_usd, None);
+        assert_eq!(
+            report.warnings,
+            [
+                "unknown content block type \"hologram\" in message 1",
+                "unknown request field \"telepathy\"",
+                "no pricing entry for \"claude-3\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_failure_names_variant() {
+        let prices = PriceTable::default();
+        let err = parse_body(
+            b"{\"model\": \"gpt-4\"}",
+            "openai",
+            "/v1/chat/completions",
+            &prices,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), "MissingMessages");
+
+        let err = parse_body(b"not json", "anthropic", "/v1/messages", &prices).unwrap_err();
+        assert_eq!(err.kind(), "NotJson");
+    }
+}
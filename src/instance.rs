@@ -0,0 +1,255 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const LOCK_FILE: &str = "instance.lock";
+const REACHABLE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// What `instance.lock` records about the instance holding it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstanceInfo {
+    pub pid: u32,
+    pub port: u16,
+    pub started_at: DateTime<Utc>,
+}
+
+/// Held while this process is the running `sherlock start`. The advisory
+/// lock goes away with the process, so a crash never leaves it held.
+pub struct InstanceLock {
+    file: File,
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        // Cleared while still locked, so nobody reads a half-removed file
+        let _ = self.file.set_len(0);
+    }
+}
+
+pub enum Acquired {
+    /// This process is the instance; `stale` is what a crashed one left behind
+    Locked {
+        lock: InstanceLock,
+        stale: Option<InstanceInfo>,
+    },
+    /// Another live process holds the lock. `None` while it is still
+    /// writing its details.
+    Held(Option<InstanceInfo>),
+}
+
+/// Take `dir/instance.lock` for an instance serving `port`
+pub fn acquire(dir: &Path, port: u16) -> Result<Acquired> {
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(LOCK_FILE);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => return Ok(Acquired::Held(read_info(&mut file))),
+        Err(TryLockError::Error(e)) => {
+            return Err(e).with_context(|| format!("Failed to lock {}", path.display()))
+        }
+    }
+
+    let stale = read_info(&mut file);
+    let info = InstanceInfo {
+        pid: std::process::id(),
+        port,
+        started_at: Utc::now(),
+    };
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(serde_json::to_string(&info)?.as_bytes())?;
+    file.sync_data()?;
+    Ok(Acquired::Locked {
+        lock: InstanceLock { file },
+        stale,
+    })
+}
+
+/// Details of the instance currently holding `dir/instance.lock`, without
+/// taking the lock. A crashed instance's details may still be there.
+pub fn running(dir: &Path) -> Option<InstanceInfo> {
+    read_info(&mut File::open(dir.join(LOCK_FILE)).ok()?)
+}
+
+fn read_info(file: &mut File) -> Option<InstanceInfo> {
+    let mut contents = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut contents).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+/// One line about the instance holding the lock, checking its proxy answers
+pub async fn describe(holder: Option<&InstanceInfo>) -> String {
+    let Some(info) = holder else {
+        return "Another sherlock instance is starting up".to_string();
+    };
+    let connect = tokio::net::TcpStream::connect(("127.0.0.1", info.port));
+    let reachable = matches!(
+        tokio::time::timeout(REACHABLE_TIMEOUT, connect).await,
+        Ok(Ok(_))
+    );
+    format!(
+        "sherlock is already running (pid {}, port {}, started {}, proxy {})",
+        info.pid,
+        info.port,
+        info.started_at
+            .with_timezone(&Local)
+            .format("%Y-%m-%d %H:%M:%S"),
+        if reachable {
+            "reachable"
+        } else {
+            "not answering"
+        }
+    )
+}
+
+/// Archive directory for an instance started with `--force` next to a
+/// running one, so the two never write the same index
+pub fn forced_archive_dir(archive_dir: &Path) -> PathBuf {
+    archive_dir.join(format!("instance-{}", std::process::id()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufRead;
+    use std::process::{Command, Stdio};
+
+    const CHILD_DIR: &str = "SHERLOCK_INSTANCE_LOCK_CHILD";
+    const CHILD_MODE: &str = "SHERLOCK_INSTANCE_LOCK_MODE";
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("sherlock-instance-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn child(dir: &Path, mode: &str) -> Command {
+        let mut command = Command::new(std::env::current_exe().unwrap());
+        command
+            .args([
+                "instance::tests::lock_child",
+                "--exact",
+                "--ignored",
+                "--nocapture",
+            ])
+            .env(CHILD_DIR, dir)
+            .env(CHILD_MODE, mode);
+        command
+    }
+
+    #[test]
+    fn test_lock_released_on_drop() {
+        let dir = temp_dir("drop");
+        let Acquired::Locked { lock, stale } = acquire(&dir, 8080).unwrap() else {
+            panic!("expected the lock");
+        };
+        assert!(stale.is_none());
+        let Acquired::Held(Some(holder)) = acquire(&dir, 9090).unwrap() else {
+            panic!("expected the lock to be held");
+        };
+        assert_eq!((holder.pid, holder.port), (std::process::id(), 8080));
+
+        drop(lock);
+        // A clean exit leaves nothing to reclaim
+        assert!(matches!(
+            acquire(&dir, 9090).unwrap(),
+            Acquired::Locked { stale: None, .. }
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_stale_lock_reclaimed() {
+        let dir = temp_dir("stale");
+        let status = child(&dir, "crash").status().unwrap();
+        assert!(status.success());
+
+        let Acquired::Locked { stale, .. } = acquire(&dir, 8080).unwrap() else {
+            panic!("a lock from an exited process should be reclaimed");
+        };
+        let stale = stale.unwrap();
+        assert_ne!(stale.pid, std::process::id());
+        assert_eq!(stale.port, 4000);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_startup_one_winner() {
+        let dir = temp_dir("race");
+        let mut children: Vec<_> = (0..8)
+            .map(|_| {
+                child(&dir, "hold")
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .spawn()
+                    .unwrap()
+            })
+            .collect();
+
+        // The winner keeps holding until its stdin closes, so every child
+        // reports while it's still held. Output follows libtest's "test ... "
+        // on the same line.
+        let mut stdouts: Vec<_> = children
+            .iter_mut()
+            .map(|child| std::io::BufReader::new(child.stdout.take().unwrap()).lines())
+            .collect();
+        let outcomes: Vec<String> = stdouts
+            .iter_mut()
+            .map(|lines| {
+                lines
+                    .map_while(Result::ok)
+                    .find_map(|line| Some(line.split_once("lock: ")?.1.to_string()))
+                    .unwrap()
+            })
+            .collect();
+        for (child, lines) in children.iter_mut().zip(stdouts) {
+            drop(child.stdin.take());
+            // Read to the end so the child never writes into a closed pipe
+            lines.for_each(drop);
+            assert!(child.wait().unwrap().success());
+        }
+
+        assert_eq!(
+            outcomes.iter().filter(|o| *o == "locked").count(),
+            1,
+            "{:?}",
+            outcomes
+        );
+        assert!(outcomes.iter().all(|o| o == "locked" || o == "held"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[ignore = "run by the tests above in a separate process"]
+    #[test]
+    fn lock_child() {
+        let Some(dir) = std::env::var_os(CHILD_DIR) else {
+            return;
+        };
+        let outcome = acquire(Path::new(&dir), 4000).unwrap();
+        match std::env::var(CHILD_MODE).unwrap().as_str() {
+            // Exit without dropping the lock, like a crash
+            "crash" => std::process::exit(0),
+            _ => {
+                let locked = matches!(outcome, Acquired::Locked { .. });
+                println!("lock: {}", if locked { "locked" } else { "held" });
+                if locked {
+                    std::io::stdin().read_to_end(&mut Vec::new()).unwrap();
+                }
+            }
+        }
+    }
+}
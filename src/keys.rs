@@ -0,0 +1,242 @@
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fmt;
+use std::path::Path;
+
+const SALT_FILE: &str = "key_salt";
+const SALT_LEN: usize = 32;
+
+/// Headers a credential comes in: Anthropic's, Gemini's, Azure's and a
+/// bearer `Authorization`
+pub const CREDENTIAL_HEADERS: [&str; 4] =
+    ["x-api-key", "x-goog-api-key", "api-key", "authorization"];
+
+/// Identifies which credential a request used without keeping the credential
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyFingerprint {
+    /// Truncated HMAC-SHA256 of the key under the per-install salt
+    pub fingerprint: String,
+    /// Last four characters of the key, as shown by provider consoles
+    pub last4: String,
+}
+
+impl KeyFingerprint {
+    /// Short label for display, e.g. "…a1b2"
+    pub fn label(&self) -> String {
+        format!("…{}", self.last4)
+    }
+}
+
+/// A request's credential headers, held in memory only so the request can
+/// be resent; never serialized, and left out of `Debug`
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct CapturedAuth(Vec<(String, String)>);
+
+impl CapturedAuth {
+    pub fn from_headers(headers: &hyper::HeaderMap) -> Self {
+        let captured = CREDENTIAL_HEADERS
+            .iter()
+            .filter_map(|name| {
+                let value = headers.get(*name)?.to_str().ok()?;
+                Some((name.to_string(), value.to_string()))
+            })
+            .collect();
+        Self(captured)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+}
+
+impl fmt::Debug for CapturedAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CapturedAuth({} headers)", self.0.len())
+    }
+}
+
+/// Fingerprints API keys with a random salt kept in the sherlock directory,
+/// so fingerprints stay stable across restarts but can't be matched elsewhere
+pub struct KeyFingerprinter {
+    salt: Vec<u8>,
+}
+
+impl KeyFingerprinter {
+    /// Load the salt from `dir`, generating and saving a new one on first use
+    pub fn load_or_create(dir: &Path) -> Result<Self> {
+        let path = dir.join(SALT_FILE);
+        if let Ok(existing) = std::fs::read_to_string(&path) {
+            let salt = decode_hex(existing.trim())
+                .filter(|salt| salt.len() == SALT_LEN)
+                .with_context(|| format!("Corrupt key salt in {:?}", path))?;
+            return Ok(Self { salt });
+        }
+
+        let mut salt = vec![0u8; SALT_LEN];
+        getrandom::getrandom(&mut salt)
+            .map_err(|e| anyhow::anyhow!("Failed to generate key salt: {}", e))?;
+
+        std::fs::create_dir_all(dir)?;
+        std::fs::write(&path, encode_hex(&salt))
+            .with_context(|| format!("Failed to write key salt to {:?}", path))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+        }
+
+        tracing::info!("Generated API key fingerprint salt at {:?}", path);
+        Ok(Self { salt })
+    }
+
+    pub fn fingerprint(&self, key: &str) -> KeyFingerprint {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.salt).expect("HMAC accepts any key length");
+        mac.update(key.as_bytes());
+        let digest = mac.finalize().into_bytes();
+
+        let chars: Vec<char> = key.chars().collect();
+        KeyFingerprint {
+            fingerprint: encode_hex(&digest[..8]),
+            last4: chars[chars.len().saturating_sub(4)..].iter().collect(),
+        }
+    }
+
+    /// Fingerprint the credential in `x-api-key`, `x-goog-api-key`, Azure's
+    /// `api-key` or a bearer `Authorization`
+    pub fn fingerprint_headers(&self, headers: &hyper::HeaderMap) -> Option<KeyFingerprint> {
+        let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+
+        let key = header("x-api-key")
+            .or_else(|| header("x-goog-api-key"))
+            .or_else(|| header("api-key"))
+            .or_else(|| {
+                header("authorization").map(|auth| auth.strip_prefix("Bearer ").unwrap_or(auth))
+            })?
+            .trim();
+
+        (!key.is_empty()).then(|| self.fingerprint(key))
+    }
+}
+
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "sk-ant-REDACTED";
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("sherlock-keys-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_salt_generated_once() {
+        let dir = temp_dir("salt");
+        KeyFingerprinter::load_or_create(&dir).unwrap();
+        let saved = std::fs::read_to_string(dir.join(SALT_FILE)).unwrap();
+        assert_eq!(decode_hex(&saved).unwrap().len(), SALT_LEN);
+
+        KeyFingerprinter::load_or_create(&dir).unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join(SALT_FILE)).unwrap(), saved);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(dir.join(SALT_FILE))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let other = temp_dir("salt-other");
+        KeyFingerprinter::load_or_create(&other).unwrap();
+        assert_ne!(
+            std::fs::read_to_string(other.join(SALT_FILE)).unwrap(),
+            saved
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_dir_all(&other).unwrap();
+    }
+
+    #[test]
+    fn test_fingerprint_stable_across_restarts() {
+        let dir = temp_dir("stable");
+        let first = KeyFingerprinter::load_or_create(&dir)
+            .unwrap()
+            .fingerprint(KEY);
+        let second = KeyFingerprinter::load_or_create(&dir)
+            .unwrap()
+            .fingerprint(KEY);
+        assert_eq!(first, second);
+        assert_eq!(first.last4, "wxyz");
+        assert_eq!(first.fingerprint.len(), 16);
+
+        let other_key = KeyFingerprinter::load_or_create(&dir)
+            .unwrap()
+            .fingerprint("sk-ant-REDACTED");
+        assert_ne!(first.fingerprint, other_key.fingerprint);
+
+        // A different install can't correlate the same key
+        let other_dir = temp_dir("stable-other");
+        let elsewhere = KeyFingerprinter::load_or_create(&other_dir)
+            .unwrap()
+            .fingerprint(KEY);
+        assert_ne!(first.fingerprint, elsewhere.fingerprint);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_dir_all(&other_dir).unwrap();
+    }
+
+    #[test]
+    fn test_plaintext_never_stored() {
+        let dir = temp_dir("plaintext");
+        let keys = KeyFingerprinter::load_or_create(&dir).unwrap();
+
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert("authorization", format!("Bearer {}", KEY).parse().unwrap());
+        let fingerprint = keys.fingerprint_headers(&headers).unwrap();
+        assert_eq!(fingerprint, keys.fingerprint(KEY));
+
+        let secret = "SECRETSECRETSECRET";
+        let serialized = serde_json::to_string(&fingerprint).unwrap();
+        assert!(!serialized.contains(secret));
+        assert!(!format!("{:?}", fingerprint).contains(secret));
+        assert!(!fingerprint.label().contains(secret));
+        let captured = CapturedAuth::from_headers(&headers);
+        assert_eq!(captured.headers().count(), 1);
+        assert_eq!(format!("{:?}", captured), "CapturedAuth(1 headers)");
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            let contents = std::fs::read_to_string(entry.unwrap().path()).unwrap();
+            assert!(!contents.contains(secret));
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,383 @@
+use serde_json::Value;
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::event::RequestEvent;
+use crate::export::{Block, Conversation};
+use crate::handoff::PATH_KEYS;
+use crate::parser::extract_text_from_value;
+
+/// Bucket for prose and anything the classifier isn't sure about
+pub const PROSE: &str = "prose";
+
+/// Approximate tokens per language, e.g. `{"prose": 1200, "rust": 800}`
+pub type LanguageMix = BTreeMap<String, u64>;
+
+/// Fewest keyword hits before unlabeled code is attributed to a language
+const MIN_KEYWORD_HITS: usize = 3;
+
+/// Fence info strings and file extensions that differ from the language name
+const ALIASES: &[(&str, &str)] = &[
+    ("rs", "rust"),
+    ("py", "python"),
+    ("python3", "python"),
+    ("pyi", "python"),
+    ("js", "javascript"),
+    ("jsx", "javascript"),
+    ("mjs", "javascript"),
+    ("cjs", "javascript"),
+    ("node", "javascript"),
+    ("ts", "typescript"),
+    ("tsx", "typescript"),
+    ("golang", "go"),
+    ("sh", "shell"),
+    ("bash", "shell"),
+    ("zsh", "shell"),
+    ("console", "shell"),
+    ("shellscript", "shell"),
+    ("yml", "yaml"),
+    ("md", "markdown"),
+    ("rb", "ruby"),
+    ("kt", "kotlin"),
+    ("cs", "csharp"),
+    ("c#", "csharp"),
+    ("c++", "cpp"),
+    ("cc", "cpp"),
+    ("cxx", "cpp"),
+    ("hpp", "cpp"),
+    ("h", "c"),
+    ("hs", "haskell"),
+    ("ex", "elixir"),
+    ("exs", "elixir"),
+    ("htm", "html"),
+];
+
+/// Languages recognised by name, in fences or as file extensions
+const LANGUAGES: &[&str] = &[
+    "rust",
+    "python",
+    "javascript",
+    "typescript",
+    "go",
+    "shell",
+    "yaml",
+    "toml",
+    "json",
+    "markdown",
+    "ruby",
+    "kotlin",
+    "java",
+    "swift",
+    "csharp",
+    "cpp",
+    "c",
+    "haskell",
+    "elixir",
+    "html",
+    "css",
+    "sql",
+    "php",
+    "lua",
+    "scala",
+    "zig",
+    "dart",
+    "diff",
+];
+
+/// Telltale snippets for unlabeled code; each counts once however often it occurs
+const KEYWORDS: &[(&str, &[&str])] = &[
+    (
+        "rust",
+        &[
+            "fn ",
+            "let mut ",
+            "impl ",
+            "pub fn ",
+            "use std::",
+            "#[derive",
+            "&mut ",
+            "-> Result<",
+            "Option<",
+            "::new(",
+            "unwrap()",
+            "match ",
+        ],
+    ),
+    (
+        "python",
+        &[
+            "def ",
+            "elif ",
+            "self.",
+            "__init__",
+            "import ",
+            "None",
+            "print(",
+            "    return ",
+            "lambda ",
+            "except ",
+        ],
+    ),
+    (
+        "javascript",
+        &[
+            "const ",
+            "=> ",
+            "function ",
+            "console.log",
+            "require(",
+            "===",
+            "let ",
+            "export ",
+        ],
+    ),
+    (
+        "typescript",
+        &[
+            "interface ",
+            ": string",
+            ": number",
+            "export type ",
+            "as const",
+            ": boolean",
+        ],
+    ),
+    (
+        "go",
+        &[
+            "func ",
+            ":= ",
+            "package ",
+            "fmt.",
+            "err != nil",
+            "go func",
+            "chan ",
+        ],
+    ),
+    (
+        "shell",
+        &[
+            "#!/bin/", "echo ", "$(", "\nfi", "then\n", "&& ", "| grep", "sudo ",
+        ],
+    ),
+];
+
+/// Approximate tokens per language in `event`. Fenced code goes by its info
+/// string, tool results by the extension of the file their tool call named,
+/// and unlabeled code by keywords; the rest counts as prose. Shares are
+/// measured in characters and scaled to the request's token count.
+pub fn classify(event: &RequestEvent) -> LanguageMix {
+    let conversation = Conversation::from_request_body(&event.raw_body);
+    let mut chars: BTreeMap<&'static str, usize> = BTreeMap::new();
+    // Tool results answer tool calls in order, so paths are matched up the same way
+    let mut paths: VecDeque<Option<&'static str>> = VecDeque::new();
+    for block in conversation.turns.iter().flat_map(|turn| &turn.blocks) {
+        match block {
+            Block::Text(text) => split_fences(text, &mut chars),
+            Block::ToolCall { input, .. } => {
+                let language = PATH_KEYS
+                    .iter()
+                    .find_map(|key| input.get(*key).and_then(Value::as_str))
+                    .and_then(from_path);
+                paths.push_back(language);
+                let text = extract_text_from_value(input);
+                let language = language.or_else(|| from_keywords(&text)).unwrap_or(PROSE);
+                *chars.entry(language).or_default() += text.len();
+            }
+            Block::ToolResult { content, .. } => match paths.pop_front().flatten() {
+                Some(language) => *chars.entry(language).or_default() += content.len(),
+                None if content.contains("```") => split_fences(content, &mut chars),
+                None => {
+                    let language = from_keywords(content).unwrap_or(PROSE);
+                    *chars.entry(language).or_default() += content.len();
+                }
+            },
+            Block::Other { .. } | Block::Step(_) => {}
+        }
+    }
+    scale(&chars, event.tokens as u64)
+}
+
+/// Attribute fenced blocks by info string or keywords, and the text around them to prose
+fn split_fences(text: &str, chars: &mut BTreeMap<&'static str, usize>) {
+    let mut fence: Option<(Option<&'static str>, String)> = None;
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        let marker = trimmed.starts_with("```") || trimmed.starts_with("~~~");
+        match fence.as_mut() {
+            None if marker => {
+                let info = trimmed[3..].split_whitespace().next().unwrap_or("");
+                fence = Some((from_name(info), String::new()));
+                *chars.entry(PROSE).or_default() += line.len();
+            }
+            None => *chars.entry(PROSE).or_default() += line.len(),
+            Some((_, code)) if !marker => code.push_str(line),
+            Some(_) => {
+                if let Some((language, code)) = fence.take() {
+                    close_fence(language, &code, chars);
+                }
+                *chars.entry(PROSE).or_default() += line.len();
+            }
+        }
+    }
+    // An unclosed fence still holds code
+    if let Some((language, code)) = fence {
+        close_fence(language, &code, chars);
+    }
+}
+
+fn close_fence(
+    language: Option<&'static str>,
+    code: &str,
+    chars: &mut BTreeMap<&'static str, usize>,
+) {
+    let language = language.or_else(|| from_keywords(code)).unwrap_or(PROSE);
+    *chars.entry(language).or_default() += code.len();
+}
+
+/// Language of a fence info string or file extension, if it names one
+fn from_name(name: &str) -> Option<&'static str> {
+    let name = name.to_lowercase();
+    ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == name)
+        .map(|(_, language)| *language)
+        .or_else(|| {
+            LANGUAGES
+                .iter()
+                .find(|language| **language == name)
+                .copied()
+        })
+}
+
+fn from_path(path: &str) -> Option<&'static str> {
+    let file = path.rsplit(['/', '\\']).next()?;
+    let (_, extension) = file.rsplit_once('.')?;
+    from_name(extension)
+}
+
+/// The language with the most distinct keyword hits, if it has enough and
+/// clearly more than the runner-up
+fn from_keywords(code: &str) -> Option<&'static str> {
+    let mut scores: Vec<(&'static str, usize)> = KEYWORDS
+        .iter()
+        .map(|(language, words)| {
+            let hits = words.iter().filter(|word| code.contains(*word)).count();
+            (*language, hits)
+        })
+        .collect();
+    scores.sort_by_key(|(_, hits)| std::cmp::Reverse(*hits));
+    let (language, best) = scores[0];
+    let runner_up = scores.get(1).map_or(0, |(_, hits)| *hits);
+    (best >= MIN_KEYWORD_HITS && best >= runner_up * 2).then_some(language)
+}
+
+/// Split `tokens` in proportion to `chars`, the rounding left to the largest bucket
+fn scale(chars: &BTreeMap<&'static str, usize>, tokens: u64) -> LanguageMix {
+    let total: usize = chars.values().sum();
+    if total == 0 || tokens == 0 {
+        return LanguageMix::new();
+    }
+    let mut mix: LanguageMix = chars
+        .iter()
+        .filter(|(_, count)| **count > 0)
+        .map(|(language, count)| {
+            let share = tokens as u128 * *count as u128 / total as u128;
+            (language.to_string(), share as u64)
+        })
+        .collect();
+    let assigned: u64 = mix.values().sum();
+    if let Some(largest) = chars.iter().max_by_key(|(_, count)| **count) {
+        *mix.entry(largest.0.to_string()).or_default() += tokens - assigned;
+    }
+    mix
+}
+
+/// e.g. "rust 48% · prose 31% · python 21%", largest first, at most `max` buckets
+pub fn describe(mix: &LanguageMix, max: usize) -> Option<String> {
+    let total: u64 = mix.values().sum();
+    if total == 0 {
+        return None;
+    }
+    let mut shares: Vec<(&String, &u64)> = mix.iter().collect();
+    shares.sort_by(|a, b| b.1.cmp(a.1));
+    let parts: Vec<String> = shares
+        .iter()
+        .take(max)
+        .map(|(language, tokens)| {
+            format!(
+                "{} {:.0}%",
+                language,
+                **tokens as f64 * 100.0 / total as f64
+            )
+        })
+        .collect();
+    Some(parts.join(" · "))
+}
+
+/// Add `other` into `mix`
+pub fn merge(mix: &mut LanguageMix, other: &LanguageMix) {
+    for (language, tokens) in other {
+        *mix.entry(language.clone()).or_default() += tokens;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_request;
+
+    const CORPUS: &str = include_str!("../tests/fixtures/language_corpus.json");
+    /// Allowed difference between expected and measured shares
+    const TOLERANCE: f64 = 0.1;
+
+    #[test]
+    fn test_corpus_proportions() {
+        let cases: Vec<Value> = serde_json::from_str(CORPUS).unwrap();
+        for case in &cases {
+            let name = case["name"].as_str().unwrap();
+            let body = serde_json::to_vec(&case["body"]).unwrap();
+            let event = parse_request(&body, "/v1/messages", "anthropic").unwrap();
+            let mix = classify(&event);
+            assert_eq!(mix.values().sum::<u64>(), event.tokens as u64, "{}", name);
+
+            let expected = case["expected"].as_object().unwrap();
+            for language in mix.keys() {
+                assert!(expected.contains_key(language), "{}: {:?}", name, mix);
+            }
+            for (language, share) in expected {
+                let measured = mix.get(language).copied().unwrap_or(0) as f64 / event.tokens as f64;
+                let share = share.as_f64().unwrap();
+                assert!(
+                    (measured - share).abs() <= TOLERANCE,
+                    "{}: {} is {:.2}, expected {:.2}",
+                    name,
+                    language,
+                    measured,
+                    share
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_names_and_keywords() {
+        assert_eq!(from_name("RS"), Some("rust"));
+        assert_eq!(from_name("text"), None);
+        assert_eq!(from_path("/repo/web/App.tsx"), Some("typescript"));
+        assert_eq!(from_path("Makefile"), None);
+        assert_eq!(
+            from_keywords("func main() {\n\tx := 1\n\tfmt.Println(x)\n}"),
+            Some("go")
+        );
+        // Too little to go on, or too close to call, stays prose
+        assert_eq!(from_keywords("let x = 1;"), None);
+        assert_eq!(
+            from_keywords("def f(): import os; self.x\nfn g() { let mut y = impl }"),
+            None
+        );
+
+        let mix = LanguageMix::from([("rust".to_string(), 75), ("prose".to_string(), 25)]);
+        assert_eq!(describe(&mix, 4).as_deref(), Some("rust 75% · prose 25%"));
+        assert_eq!(describe(&LanguageMix::new(), 4), None);
+    }
+}
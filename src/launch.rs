@@ -0,0 +1,177 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use std::path::Path;
+
+use crate::config::{default_env_var_aliases, ProviderConfig, RunConfig};
+use crate::index;
+
+/// Words in the name of a variable holding a base URL
+const BASE_URL_HINTS: &[&str] = &["BASE", "URL", "HOST", "ENDPOINT"];
+
+/// Words in the name of a variable holding a credential instead
+const SECRET_HINTS: &[&str] = &["KEY", "TOKEN", "SECRET"];
+
+/// Variables `sherlock run` points at the proxy: the provider's own, its
+/// known aliases unless they are turned off, then any asked for by name
+pub fn env_vars(
+    provider: &ProviderConfig,
+    format: &str,
+    run: &RunConfig,
+    extra: &[String],
+) -> Vec<String> {
+    let aliases = if run.set_env_var_aliases {
+        run.aliases(format)
+    } else {
+        Vec::new()
+    };
+    let mut names: Vec<String> = Vec::new();
+    for name in provider.env_vars.iter().chain(&aliases).chain(extra) {
+        if !names.contains(name) {
+            names.push(name.clone());
+        }
+    }
+    names
+}
+
+/// Requests `provider` has indexed since `since`, as a sign that a tool's
+/// traffic is going through the proxy
+pub fn requests_since(root: &Path, provider: &str, since: DateTime<Utc>) -> Result<usize> {
+    Ok(index::stream_index(root)?
+        .filter(|entry| entry.provider == provider && entry.timestamp >= since)
+        .count())
+}
+
+/// The variable `tool` most likely reads its base URL from, when setting
+/// `tried` sent nothing through the proxy.
+///
+/// A base URL variable already in `env` that names the tool or the provider
+/// is the best guess, since the tool reads that one instead; tool-named ones
+/// first. Failing that, a known alias that wasn't set.
+pub fn suggest_env_var(
+    tool: &str,
+    format: &str,
+    run: &RunConfig,
+    tried: &[String],
+    env: &[(String, String)],
+) -> Option<String> {
+    let tool = Path::new(tool)
+        .file_name()
+        .map_or(tool.into(), |name| name.to_string_lossy())
+        .to_ascii_uppercase()
+        .replace('-', "_");
+    let mut known = run.aliases(format);
+    known.extend(default_env_var_aliases(format));
+    let mut prefixes: Vec<String> = known
+        .iter()
+        .filter_map(|name| name.split('_').next())
+        .map(|prefix| format!("{}_", prefix))
+        .collect();
+    prefixes.sort();
+    prefixes.dedup();
+
+    let untried = |name: &&String| !tried.contains(name);
+    let in_env = |prefix: &str| {
+        let mut names: Vec<&String> = env
+            .iter()
+            .filter(|(name, value)| {
+                name.starts_with(prefix) && !value.is_empty() && looks_like_base_url(name)
+            })
+            .map(|(name, _)| name)
+            .filter(untried)
+            .collect();
+        names.sort();
+        names.first().map(|name| name.to_string())
+    };
+    in_env(&format!("{}_", tool))
+        .or_else(|| prefixes.iter().find_map(|prefix| in_env(prefix)))
+        .or_else(|| known.iter().find(untried).cloned())
+}
+
+fn looks_like_base_url(name: &str) -> bool {
+    BASE_URL_HINTS.iter().any(|hint| name.contains(hint))
+        && !SECRET_HINTS.iter().any(|hint| name.contains(hint))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_env_vars_add_aliases_and_extras() {
+        let config = Config::default();
+        let openai = &config.providers["openai"];
+        let mut run = RunConfig::default();
+        let vars = env_vars(
+            openai,
+            "openai",
+            &run,
+            &names(&["MY_LLM_URL", "OPENAI_API_BASE"]),
+        );
+        assert_eq!(
+            vars,
+            [
+                "OPENAI_BASE_URL",
+                "OPENAI_API_BASE",
+                "OPENAI_API_BASE_URL",
+                "OPENAI_API_HOST",
+                "MY_LLM_URL"
+            ]
+        );
+
+        // Config can restrict the table, or turn the aliases off
+        run.env_var_aliases
+            .insert("openai".to_string(), names(&["OPENAI_API_BASE"]));
+        let vars = env_vars(openai, "openai", &run, &[]);
+        assert_eq!(vars, ["OPENAI_BASE_URL", "OPENAI_API_BASE"]);
+        run.set_env_var_aliases = false;
+        assert_eq!(env_vars(openai, "openai", &run, &[]), ["OPENAI_BASE_URL"]);
+        // Formats left out of the table keep the built-in list
+        assert_eq!(
+            run.aliases("anthropic"),
+            default_env_var_aliases("anthropic")
+        );
+    }
+
+    #[test]
+    fn test_suggest_env_var() {
+        let mut run = RunConfig::default();
+        let tried = env_vars(&Config::default().providers["openai"], "openai", &run, &[]);
+        let env = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect()
+        };
+
+        // A variable the tool reads and the user already set wins, the
+        // tool's own before the provider's; keys aren't base URLs
+        let set = env(&[
+            ("OPENAI_API_KEY", "sk-test"),
+            ("OPENAI_ENDPOINT", "https://example.com"),
+            ("SOME_TOOL_API_URL", "https://example.com"),
+        ]);
+        let suggest = |tried: &[String], env: &[(String, String)]| {
+            suggest_env_var("/usr/bin/some-tool", "openai", &run, tried, env)
+        };
+        assert_eq!(suggest(&tried, &set).as_deref(), Some("SOME_TOOL_API_URL"));
+        assert_eq!(
+            suggest(&tried, &set[..2]).as_deref(),
+            Some("OPENAI_ENDPOINT")
+        );
+        // Nothing to go on once every alias was tried
+        assert_eq!(suggest(&tried, &set[..1]), None);
+
+        // With the aliases restricted, one that wasn't set is next
+        run.env_var_aliases.insert("openai".to_string(), Vec::new());
+        let suggest = |tried: &[String]| suggest_env_var("sometool", "openai", &run, tried, &[]);
+        assert_eq!(
+            suggest(&names(&["OPENAI_BASE_URL"])).as_deref(),
+            Some("OPENAI_API_BASE")
+        );
+    }
+}
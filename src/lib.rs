@@ -0,0 +1,60 @@
+pub mod aggregate;
+#[cfg(test)]
+mod alloc_stats;
+pub mod archive;
+pub mod autostart;
+pub mod branches;
+pub mod bundle;
+pub mod caching;
+pub mod chaos;
+pub mod cli;
+pub mod config;
+pub mod context;
+pub mod control;
+pub mod dashboard;
+pub mod dedupe;
+pub mod delta;
+pub mod detail;
+pub mod embedded;
+pub mod event;
+pub mod export;
+pub mod filter;
+pub mod goals;
+pub mod handoff;
+pub mod import;
+pub mod index;
+pub mod inspect;
+pub mod instance;
+pub mod keys;
+pub mod language;
+pub mod launch;
+pub mod metrics;
+pub mod models;
+pub mod overlay;
+pub mod parser;
+pub mod phases;
+pub mod policy;
+pub mod pricing;
+pub mod projection;
+pub mod proxy;
+pub mod query;
+pub mod reader;
+pub mod record;
+pub mod redact;
+pub mod reliability;
+pub mod replay;
+pub mod repo;
+pub mod resend;
+pub mod runtime;
+pub mod scrub;
+pub mod search;
+pub mod self_test;
+pub mod shaping;
+pub mod sse;
+pub mod stats;
+pub mod statusline;
+pub mod text;
+pub mod tls;
+pub mod update;
+pub mod usage;
+pub mod watermark;
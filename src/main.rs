@@ -1,114 +1,1017 @@
-mod archive;
-mod cli;
-mod config;
-mod dashboard;
-mod event;
-mod parser;
-mod proxy;
-
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{Context, Result};
+use clap::{CommandFactory, FromArgMatches};
+use std::io::{IsTerminal, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::mpsc;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-use crate::archive::archive_writer;
-use crate::cli::{Cli, Command};
-use crate::config::Config;
-use crate::dashboard::Dashboard;
-use crate::event::RequestEvent;
-use crate::proxy::ProxyServer;
+use sherlock::aggregate::{AggregateOptions, AggregateReport};
+use sherlock::archive::{
+    archive_status, archive_writer, render_entry, ArchiveEntry, Rendered, PENDING_FILE,
+};
+use sherlock::autostart::Tools;
+use sherlock::bundle::{self, BundleOptions};
+use sherlock::chaos::Chaos;
+use sherlock::cli::{ArchiveCommand, Cli, Command, ConfigCommand, QueryFormat, StatsFormat};
+use sherlock::config::{self, Config};
+use sherlock::control::Control;
+use sherlock::dashboard::{Dashboard, ShutdownTimeouts};
+use sherlock::embedded::EmbeddedProxy;
+use sherlock::event::{Marker, ProxyEvent, RequestEvent};
+use sherlock::filter::Filter;
+use sherlock::goals::GoalReport;
+use sherlock::index::{IndexEntry, IndexSummary, LanguageSummary};
+use sherlock::instance::{forced_archive_dir, Acquired};
+use sherlock::keys::KeyFingerprinter;
+use sherlock::metrics::{ArchiveMetrics, ProxyMetrics};
+use sherlock::models::ModelRegistry;
+use sherlock::overlay::{Overlay, Provenance};
+use sherlock::phases::{self, PhaseLayer};
+use sherlock::policy::PolicyScanner;
+use sherlock::pricing::PriceTable;
+use sherlock::projection::CostProjection;
+use sherlock::proxy::{
+    MarkRequest, ProxyServer, SessionInfo, SessionOverlay, MARK_PATH, STATUS_PATH,
+};
+use sherlock::query::Query;
+use sherlock::reader::{ArchiveStats, StatsFilter};
+use sherlock::record::{run_recording, RecordOptions};
+use sherlock::redact::Redactor;
+use sherlock::reliability::ReliabilityReport;
+use sherlock::repo::RepoInfo;
+use sherlock::resend::Resender;
+use sherlock::scrub::Scrubber;
+use sherlock::stats::ThroughputReport;
+use sherlock::statusline::Status;
+use sherlock::{
+    branches, caching, dedupe, embedded, export, handoff, import, index, inspect, instance, launch,
+    reader, replay, runtime, search, self_test, update,
+};
+
+/// Files whose size is read by `archive status` before it starts sampling
+const STATUS_SAMPLE_LIMIT: usize = 2_000;
+/// Longest `sherlock statusline` waits on the running instance
+const STATUS_TIMEOUT: Duration = Duration::from_millis(500);
+/// Archived requests past which `dedupe-report` shows its progress
+const DEDUPE_PROGRESS_MIN: usize = 500;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize tracing
+    // `--version` also names a newer release found by the last update check
+    let version = update::version_line(sherlock_dir().ok().as_deref());
+    let cli = Cli::from_arg_matches(&Cli::command().version(&*version.leak()).get_matches())?;
+
+    // Initialize tracing. A status bar shows whatever `statusline` prints, so
+    // its logs go to stderr. Traced requests log each phase as it ends.
+    let writer = match cli.command {
+        Command::Statusline { .. } => BoxMakeWriter::new(std::io::stderr),
+        _ => BoxMakeWriter::new(std::io::stdout),
+    };
+    let trace_requests = matches!(
+        cli.command,
+        Command::Start {
+            trace_requests: true,
+            ..
+        }
+    );
+    phases::trace_requests(trace_requests);
+    let phase_layer = trace_requests.then(PhaseLayer::default);
+    let span_events = match trace_requests {
+        true => FmtSpan::CLOSE,
+        false => FmtSpan::NONE,
+    };
     tracing_subscriber::registry()
         .with(tracing_subscriber::EnvFilter::new(
             std::env::var("RUST_LOG").unwrap_or_else(|_| "sherlock=info".into()),
         ))
-        .with(tracing_subscriber::fmt::layer().with_target(false))
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_target(false)
+                .with_span_events(span_events)
+                .with_writer(writer),
+        )
+        .with(phase_layer.clone())
         .init();
 
-    let cli = Cli::parse();
-    let config = Config::load(&cli.config)?;
+    // The file in use, e.g. ~/.sherlock/config.toml when there is no config.json
+    let config_path = config::resolve_path(&cli.config);
+    let config = Config::load(&config_path, cli.migrate_config)?;
 
+    let tool_flags = ToolFlags {
+        repo_info: !cli.no_repo_info,
+        embed_proxy: !cli.no_proxy,
+    };
     match cli.command {
-        Command::Start { port, limit } => {
-            let config = config.with_overrides(port, limit);
-            run_server(config).await?;
+        Command::Start {
+            port,
+            limit,
+            by_repo,
+            force,
+            headless,
+            enable_chaos,
+            ..
+        } => {
+            let mut config = config.with_overrides(port, limit);
+            config.dashboard.group_by_repo |= by_repo;
+            let _lock = match instance::acquire(&sherlock_dir()?, config.proxy.port)? {
+                Acquired::Locked { lock, stale } => {
+                    if let Some(stale) = stale {
+                        tracing::warn!(
+                            "Reclaimed instance lock of exited pid {} (port {})",
+                            stale.pid,
+                            stale.port
+                        );
+                    }
+                    Some(lock)
+                }
+                Acquired::Held(holder) => {
+                    let status = instance::describe(holder.as_ref()).await;
+                    if !force {
+                        anyhow::bail!(
+                            "{}\nUse --force to start another instance with its own \
+                             archive directory",
+                            status
+                        );
+                    }
+                    config.archive.directory = forced_archive_dir(&config.archive.directory);
+                    eprintln!(
+                        "{}; starting anyway, archiving to {}",
+                        status,
+                        config.archive.directory.display()
+                    );
+                    None
+                }
+            };
+            let chaos = match (enable_chaos, config.chaos.is_empty()) {
+                (true, true) => {
+                    anyhow::bail!("--enable-chaos given, but no chaos rules are configured")
+                }
+                (true, false) => Some(Chaos::new(&config.chaos)?),
+                (false, true) => None,
+                (false, false) => {
+                    tracing::info!("Chaos rules are configured but only apply with --enable-chaos");
+                    None
+                }
+            };
+            let result = run_server(config, &config_path, headless, !cli.no_repo_info, chaos).await;
+            if let Some(layer) = phase_layer {
+                print!("{}", layer.report());
+            }
+            result?;
         }
         Command::Claude { args } => {
-            run_tool("anthropic", "claude", args, &config).await?;
+            run_tool("anthropic", "claude", args, &[], &config, tool_flags).await?;
         }
         Command::Happy { args } => {
-            run_tool("anthropic", "happy", args, &config).await?;
+            run_tool("anthropic", "happy", args, &[], &config, tool_flags).await?;
         }
         Command::Gemini { args } => {
-            run_tool("gemini", "gemini", args, &config).await?;
+            run_tool("gemini", "gemini", args, &[], &config, tool_flags).await?;
         }
         Command::Codex { args } => {
-            run_tool("openai", "codex", args, &config).await?;
+            run_tool("openai", "codex", args, &[], &config, tool_flags).await?;
         }
-        Command::Run { provider, command } => {
+        Command::Run {
+            provider,
+            env_vars,
+            command,
+        } => {
             if command.is_empty() {
                 anyhow::bail!("No command specified");
             }
-            run_tool(&provider, &command[0], command[1..].to_vec(), &config).await?;
+            run_tool(
+                &provider,
+                &command[0],
+                command[1..].to_vec(),
+                &env_vars,
+                &config,
+                tool_flags,
+            )
+            .await?;
+        }
+        Command::Record {
+            out,
+            duration,
+            name,
+            port,
+            force,
+            aggregates_only,
+            model_families,
+            hash_names,
+        } => {
+            let name = name.unwrap_or_else(|| {
+                out.file_name()
+                    .map(|n| n.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| "recording".to_string())
+            });
+            let options = RecordOptions {
+                name,
+                out,
+                duration,
+                force,
+                aggregates_only: aggregates_only.then_some(AggregateOptions {
+                    model_families,
+                    hash_names,
+                }),
+            };
+            run_recording(config.with_overrides(port, None), options).await?;
+        }
+        Command::Mark {
+            label,
+            tag,
+            session,
+            port,
+        } => {
+            let session = session.or_else(|| {
+                let dir = std::env::current_dir().ok().filter(|_| !cli.no_repo_info)?;
+                Some(RepoInfo::detect(&dir)?.name())
+            });
+            let port = port
+                .or_else(|| instance::running(&sherlock_dir().ok()?).map(|info| info.port))
+                .unwrap_or(config.proxy.port);
+            let request = MarkRequest {
+                label,
+                tag,
+                session,
+            };
+            let marker = send_marker(&config.proxy.bind_address, port, &request).await?;
+            println!(
+                "Marked {:?} at {}",
+                marker.describe(),
+                marker.timestamp.with_timezone(&chrono::Local).format("%H:%M:%S")
+            );
+        }
+        Command::Statusline { port } => {
+            let port =
+                port.or_else(|| instance::running(&sherlock_dir().ok()?).map(|info| info.port));
+            let status = match port {
+                Some(port) => fetch_status(&config.proxy.bind_address, port).await.ok(),
+                None => None,
+            };
+            let status = match status {
+                Some(status) => status,
+                None => {
+                    let entries = index::stream_index(&config.archive.directory)?;
+                    Status::today(entries, chrono::Local::now().date_naive())
+                }
+            };
+            println!("{}", status.line());
+        }
+        Command::Parse {
+            provider,
+            path,
+            input,
+            json,
+        } => {
+            let body = match input {
+                Some(input) => std::fs::read(&input)
+                    .with_context(|| format!("Failed to read {}", input.display()))?,
+                None => {
+                    let mut body = Vec::new();
+                    std::io::stdin().read_to_end(&mut body)?;
+                    body
+                }
+            };
+            let path = path
+                .or_else(|| config.providers.get(&provider).map(|p| p.main_path().to_string()))
+                .unwrap_or_else(|| "/".to_string());
+            let prices = PriceTable::new(&config.pricing);
+            let report = inspect::parse_body(&body, &provider, &path, &prices)
+                .map_err(|e| anyhow::anyhow!("Parse failed ({}): {}", e.kind(), e))?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                print!("{}", report);
+            }
+        }
+        Command::Models { json } => {
+            let registry = ModelRegistry::load(&sherlock_dir()?);
+            if json {
+                println!("{}", serde_json::to_string_pretty(registry.entries())?);
+            } else {
+                print!("{}", registry);
+            }
+        }
+        Command::Conversations { last, json } => {
+            let views = branches::archived_conversations(&config.archive.directory, last)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&views)?);
+            } else if views.is_empty() {
+                println!("No archived conversations in {:?}", config.archive.directory);
+            } else {
+                for view in views {
+                    println!("{}", view);
+                }
+            }
+        }
+        Command::Handoff {
+            conversation,
+            out,
+            budget,
+            llm,
+        } => {
+            let path =
+                handoff::find_conversation(&config.archive.directory, conversation.as_deref())?;
+            let mut handoff = handoff::Handoff::extract(&handoff::load_conversation(&path)?);
+            let budget = budget.unwrap_or(config.handoff.budget_tokens);
+            if llm {
+                handoff.summarize_progress(&config, budget / 2).await?;
+            }
+            let doc = handoff.render(budget);
+            match out {
+                Some(out) => {
+                    std::fs::write(&out, doc)
+                        .with_context(|| format!("Failed to write {}", out.display()))?;
+                    println!("Wrote handoff for {} to {}", path.display(), out.display());
+                }
+                None => print!("{}", doc),
+            }
+        }
+        Command::Import { format, path } => {
+            let mut registry = ModelRegistry::load(&sherlock_dir()?);
+            let redactor = Redactor::new(&config.redaction)?;
+            let report =
+                import::import(format, &path, &config.archive, &redactor, &mut registry).await?;
+            println!("{}", report);
+        }
+        Command::Archive {
+            command: ArchiveCommand::Status { json },
+        } => {
+            let directory = &config.archive.directory;
+            if !directory.is_dir() {
+                anyhow::bail!("Archive directory {:?} does not exist", directory);
+            }
+            let status = archive_status(directory, &config.archive.sinks, STATUS_SAMPLE_LIMIT)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&status)?);
+            } else {
+                print!("{}", status);
+            }
+        }
+        Command::Render { entry } => match render_entry(&config.archive, &entry).await? {
+            Rendered::Now(path) => println!("Rendered {}", path.display()),
+            Rendered::Already(path) => println!("Already rendered: {}", path.display()),
+        },
+        Command::Stats {
+            reliability,
+            by_language,
+            archive,
+            projection,
+            goals,
+            histogram,
+            throughput,
+            aggregates_only,
+            model_families,
+            hash_names,
+            since,
+            provider,
+            filter,
+            format,
+            json,
+        } => {
+            let json = json || format == StatsFormat::Json;
+            let expr = match filter.as_deref() {
+                Some(filter) if archive => {
+                    Some(Filter::parse(filter).map_err(|e| anyhow::anyhow!("--filter: {}", e))?)
+                }
+                Some(filter) => Some(index_filter(filter, "--filter")?),
+                None => None,
+            };
+            let filter = StatsFilter {
+                since,
+                provider,
+                expr,
+            };
+            let entries = || -> Result<Vec<IndexEntry>> {
+                let mut entries = index::read_index(&config.archive.directory)?;
+                entries.retain(|entry| filter.admits(entry));
+                Ok(entries)
+            };
+            if archive {
+                let stats = ArchiveStats::read(&config.archive.directory, &filter)?;
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&stats)?);
+                } else {
+                    print!("{}", stats);
+                }
+            } else if projection {
+                let prices = PriceTable::new(&config.pricing);
+                let projection =
+                    CostProjection::build(&entries()?, &prices, &chrono::Local, chrono::Utc::now());
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&projection)?);
+                } else {
+                    print!("{}", projection);
+                }
+            } else if goals {
+                let prices = PriceTable::new(&config.pricing);
+                let Some(report) = GoalReport::build(
+                    &config.goals,
+                    &entries()?,
+                    &prices,
+                    &chrono::Local,
+                    chrono::Utc::now(),
+                ) else {
+                    anyhow::bail!(
+                        "No daily goal set; add goals.daily_tokens or goals.daily_cost_usd to {}",
+                        config_path.display()
+                    );
+                };
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&report)?);
+                } else {
+                    print!("{}", report);
+                }
+            } else if aggregates_only {
+                let prices = PriceTable::new(&config.pricing);
+                let options = AggregateOptions {
+                    model_families,
+                    hash_names,
+                };
+                let report = AggregateReport::from_index(&entries()?, &prices, options);
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else if throughput {
+                let report = ThroughputReport::build(&entries()?);
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&report)?);
+                } else {
+                    print!("{}", report);
+                }
+            } else if by_language {
+                let summary = LanguageSummary::build(&entries()?);
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&summary)?);
+                } else {
+                    print!("{}", summary);
+                }
+            } else if reliability {
+                let samples: Vec<_> = entries()?.iter().filter_map(IndexEntry::sample).collect();
+                let report = ReliabilityReport::build(&samples, &config.slo, chrono::Utc::now());
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&report)?);
+                } else {
+                    print!("{}", report);
+                }
+            } else {
+                let summary = IndexSummary::build(&entries()?);
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&summary)?);
+                } else {
+                    print!("{}", summary);
+                    if histogram {
+                        print!("{}", summary.percentiles.chart());
+                    }
+                }
+            }
+        }
+        Command::DedupeReport { json } => {
+            let show = std::io::stderr().is_terminal();
+            let report = dedupe::dedupe_report(&config.archive.directory, |progress| {
+                if !show || progress.total < DEDUPE_PROGRESS_MIN {
+                    return;
+                }
+                if progress.done % 100 == 0 || progress.done == progress.total {
+                    let pass = match progress.pass {
+                        dedupe::Pass::Candidates => 1,
+                        dedupe::Pass::Counting => 2,
+                    };
+                    let mut stderr = std::io::stderr();
+                    let _ = write!(
+                        stderr,
+                        "\rScanning archive, pass {}/2: {}/{} requests",
+                        pass, progress.done, progress.total
+                    );
+                    if pass == 2 && progress.done == progress.total {
+                        let _ = writeln!(stderr);
+                    }
+                }
+            })?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                print!("{}", report);
+            }
+        }
+        Command::Query {
+            select,
+            filter,
+            group_by,
+            order_by,
+            limit,
+            format,
+        } => {
+            let query = Query::parse(&select, group_by.as_deref(), order_by.as_deref(), limit)?;
+            let filter = index_filter(filter.as_deref().unwrap_or_default(), "--where")?;
+            let results = query.run(index::stream_index(&config.archive.directory)?, &filter);
+            match format {
+                QueryFormat::Table => print!("{}", results),
+                QueryFormat::Csv => print!("{}", results.to_csv()),
+                QueryFormat::Json => {
+                    println!("{}", serde_json::to_string_pretty(&results.to_json())?)
+                }
+            }
+        }
+        Command::Search {
+            text,
+            filter,
+            limit,
+            json,
+        } => {
+            let filter = Filter::parse(filter.as_deref().unwrap_or_default())
+                .map_err(|e| anyhow::anyhow!("--filter: {}", e))?;
+            let requests = reader::read_archive(&config.archive.directory)?
+                .filter_map(|request| request.map_err(|e| tracing::warn!("Skipping {:#}", e)).ok());
+            let hits = search::search_archive(requests, &text, &filter, limit);
+            if json {
+                println!("{}", serde_json::to_string_pretty(&hits)?);
+            } else if hits.is_empty() {
+                println!("No archived request mentions {:?}", text);
+            } else {
+                for hit in &hits {
+                    print!("{}", hit);
+                }
+            }
+        }
+        Command::Export {
+            filter,
+            output,
+            aggregates_only,
+            model_families,
+            hash_names,
+        } => {
+            let filter = index_filter(filter.as_deref().unwrap_or_default(), "--filter")?;
+            let mut out: Box<dyn Write> = match &output {
+                Some(path) => Box::new(std::io::BufWriter::new(
+                    std::fs::File::create(path)
+                        .with_context(|| format!("Failed to create {}", path.display()))?,
+                )),
+                None => Box::new(std::io::stdout().lock()),
+            };
+            let entries = index::stream_index(&config.archive.directory)?
+                .filter(|entry| filter.matches(entry));
+            let mut exported = 0;
+            if aggregates_only {
+                let entries: Vec<IndexEntry> = entries.collect();
+                let prices = PriceTable::new(&config.pricing);
+                let options = AggregateOptions {
+                    model_families,
+                    hash_names,
+                };
+                let report = AggregateReport::from_index(&entries, &prices, options);
+                serde_json::to_writer_pretty(&mut out, &report)?;
+                writeln!(out)?;
+                exported = entries.len();
+            } else {
+                for entry in entries {
+                    serde_json::to_writer(&mut out, &entry)?;
+                    writeln!(out)?;
+                    exported += 1;
+                }
+            }
+            out.flush()?;
+            if let Some(path) = output {
+                println!("Exported {} requests to {}", exported, path.display());
+            }
+        }
+        Command::View { date, file } => {
+            let steps = match (file, date) {
+                (Some(file), _) if bundle::is_bundle(&file) => bundle::steps(&file)?,
+                (Some(file), _) => replay::from_recording(&file)?,
+                (None, date) => {
+                    let date = date.unwrap_or_else(|| chrono::Local::now().date_naive());
+                    replay::from_archive(&config.archive.directory, date)?
+                }
+            };
+            if steps.is_empty() {
+                anyhow::bail!("Nothing to replay: no requests, failures or markers found");
+            }
+            let hz = config.dashboard.refresh_rate_hz;
+            let mut dashboard = Dashboard::new(
+                config.dashboard,
+                &config.goals,
+                Arc::default(),
+                Arc::default(),
+                ModelRegistry::default(),
+                config.slo,
+            );
+            dashboard.set_prices(PriceTable::new(&config.pricing));
+            replay::run(dashboard, steps, config.goals, hz)?;
+        }
+        Command::Bundle {
+            out,
+            last,
+            anonymize: _,
+            no_anonymize,
+            force,
+            inspect,
+        } => match inspect {
+            Some(path) => print!("{}", bundle::inspect(&path)?),
+            None => {
+                let options = BundleOptions {
+                    out: out.context("--out is required")?,
+                    last,
+                    anonymize: !no_anonymize,
+                    force,
+                };
+                let manifest = bundle::create(&config, &sherlock_dir()?, &options)?;
+                let anonymized = match manifest.anonymized {
+                    true => ", anonymized",
+                    false => "",
+                };
+                println!(
+                    "Wrote {} with {} requests, {} failures and {} markers{}",
+                    options.out.display(),
+                    manifest.requests,
+                    manifest.failures,
+                    manifest.markers,
+                    anonymized
+                );
+            }
+        },
+        Command::ExportConversation {
+            input,
+            format,
+            output,
+            collapse_steps,
+        } => {
+            let prices = PriceTable::new(&config.pricing);
+            let path = export::export_conversation(
+                &input,
+                format,
+                output.as_deref(),
+                collapse_steps,
+                &prices,
+            )?;
+            println!("Exported conversation to {}", path.display());
+        }
+        Command::Config {
+            command: ConfigCommand::Show { json },
+        } => {
+            let overlay = Overlay::find(&std::env::current_dir()?)?;
+            let provenance = Provenance::new(&config_path, config, overlay.as_ref())?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&provenance)?);
+            } else {
+                print!("{}", provenance);
+            }
         }
     }
 
     Ok(())
 }
 
-async fn run_server(config: Config) -> Result<()> {
+async fn run_server(
+    config: Config,
+    config_path: &Path,
+    headless: bool,
+    repo_info: bool,
+    chaos: Option<Chaos>,
+) -> Result<()> {
     // Create channels for communication
-    let (event_tx, event_rx) = mpsc::channel::<RequestEvent>(1000);
-    let (archive_tx, archive_rx) = mpsc::channel::<RequestEvent>(100);
+    let (event_tx, event_rx) = mpsc::channel::<ProxyEvent>(1000);
+    let (archive_tx, archive_rx) = mpsc::channel::<ArchiveEntry>(100);
+    let (cache_tx, cache_rx) = mpsc::channel::<RequestEvent>(100);
+
+    let (control_tx, control_rx) = mpsc::channel::<Control>(8);
+
+    let metrics = Arc::new(ProxyMetrics::default());
+    let archive_metrics = Arc::new(ArchiveMetrics::default());
+
+    // Listen before binding, so an early signal doesn't kill the process
+    #[cfg(unix)]
+    tokio::spawn(sherlock::control::Signals::install()?.forward(control_tx.clone()));
 
     // Spawn proxy server
     let proxy_config = config.proxy.clone();
-    let providers = config.providers.clone();
-    let proxy = ProxyServer::new(proxy_config, providers, event_tx);
-
-    let proxy_handle = tokio::spawn(async move {
-        if let Err(e) = proxy.run().await {
-            tracing::error!("Proxy server error: {}", e);
+    let mut providers = config.providers.clone();
+    if config.proxy.self_test {
+        providers.insert(
+            self_test::PROVIDER.to_string(),
+            self_test::mock_provider().await?,
+        );
+    }
+    let sherlock_dir = sherlock_dir()?;
+    let keys = Arc::new(KeyFingerprinter::load_or_create(&sherlock_dir)?);
+    let policy = Arc::new(PolicyScanner::new(&config.policy)?);
+    let redactor = Arc::new(Redactor::new(&config.redaction)?);
+    let proxy = ProxyServer::new(
+        proxy_config,
+        providers,
+        event_tx.clone(),
+        Arc::clone(&metrics),
+        keys,
+        policy,
+        Arc::new(PriceTable::new(&config.pricing)),
+    )?
+    .with_redactor(Arc::clone(&redactor));
+    let chaos_rules = chaos.as_ref().map(Chaos::describe);
+    let proxy = match chaos {
+        Some(chaos) => {
+            tracing::warn!(
+                "Chaos mode on (seed {}): {}",
+                chaos.seed(),
+                chaos.describe()
+            );
+            proxy.with_chaos(chaos)
         }
-    });
+        None => proxy,
+    };
+    let proxy = match &config.scrubber {
+        Some(scrubber) => proxy.with_scrubber(Scrubber::start(scrubber)?),
+        None => proxy,
+    };
+
+    let listener = proxy.bind().await?;
+    let proxy_addr = listener.local_addr()?.to_string();
+    let runtime = proxy.runtime();
+    let proxy_shutdown = proxy.shutdown_handle();
+    let proxy_handle = tokio::spawn(proxy.serve(listener));
+    let reloader = runtime::Reloader::new(config_path, runtime, Arc::clone(metrics.shaping()));
+    let reload_handle = tokio::spawn(runtime::watch_config(reloader.clone()));
+
+    let tools = Tools::start(
+        &config,
+        &proxy_addr,
+        &sherlock_dir.join("logs"),
+        repo_info,
+        event_tx.clone(),
+    )?;
+
+    if config.update_check {
+        let update_tx = event_tx.clone();
+        let url = config.update_url.clone();
+        let dir = sherlock_dir.clone();
+        tokio::spawn(async move {
+            if let Some(latest) = update::check(&dir, &url).await {
+                let _ = update_tx.send(ProxyEvent::UpdateAvailable(latest)).await;
+            }
+        });
+    }
+
+    // Check the whole path once the proxy is listening
+    if config.proxy.self_test {
+        let archive_config = config.archive.clone();
+        tokio::spawn(async move {
+            self_test::run(&proxy_addr, &archive_config, event_tx).await;
+        });
+    }
 
     // Spawn archive writer
     let archive_config = config.archive.clone();
-    let archive_handle = tokio::spawn(async move {
-        if let Err(e) = archive_writer(archive_rx, archive_config).await {
+    let writer_metrics = Arc::clone(&archive_metrics);
+    let pending = sherlock_dir.join(PENDING_FILE);
+    let mut archive_handle = tokio::spawn(async move {
+        let writer = archive_writer(
+            archive_rx,
+            archive_config,
+            redactor,
+            writer_metrics,
+            Some(pending),
+        );
+        if let Err(e) = writer.await {
             tracing::error!("Archive writer error: {}", e);
         }
     });
 
+    // Spawn prompt caching analysis
+    let cache_handle = tokio::spawn(caching::run(cache_rx, Arc::clone(&metrics)));
+
     // Run dashboard in main task (needs terminal access)
-    let dashboard = Dashboard::new(config.dashboard);
-    let result = dashboard.run(event_rx, archive_tx).await;
+    let flush_timeout = Duration::from_secs(config.archive.shutdown_flush_timeout_secs);
+    let kill_tools = config.autostart_kill_on_exit;
+    let shutdown = ShutdownTimeouts {
+        grace: Duration::from_secs(config.proxy.shutdown_grace_secs),
+        archive_flush: flush_timeout,
+    };
+    let mut dashboard = Dashboard::new(
+        config.dashboard,
+        &config.goals,
+        metrics,
+        archive_metrics,
+        ModelRegistry::load(&sherlock_dir),
+        config.slo,
+    );
+    dashboard.set_controls(control_rx, Some(reloader));
+    dashboard.set_prices(PriceTable::new(&config.pricing));
+    dashboard.set_resender(Resender::new(&config.proxy, config.providers.clone()));
+    if let Some(rules) = chaos_rules {
+        dashboard.set_chaos(rules);
+    }
+    drop(control_tx);
+    let result = if headless {
+        dashboard
+            .run_headless(event_rx, archive_tx, cache_tx, proxy_shutdown, shutdown)
+            .await
+    } else {
+        dashboard
+            .run(event_rx, archive_tx, cache_tx, proxy_shutdown, shutdown)
+            .await
+    };
 
     // Cleanup
+    tools.stop(kill_tools).await;
     proxy_handle.abort();
+    reload_handle.abort();
+    // The dashboard waited out the flush; this only bounds a failed start
+    let _ = tokio::time::timeout(flush_timeout, &mut archive_handle).await;
     archive_handle.abort();
+    cache_handle.abort();
 
     result
 }
 
+/// Start a proxy for `tool_name`'s session alone, holding the instance lock
+/// while it runs; none when another instance holds the lock, as it may
+/// still be starting up or listen on another port
+async fn start_embedded(
+    config: &Config,
+    tool_name: &str,
+) -> Result<Option<(EmbeddedProxy, instance::InstanceLock)>> {
+    let dir = sherlock_dir()?;
+    match instance::acquire(&dir, config.proxy.port)? {
+        Acquired::Locked { lock, .. } => {
+            tracing::info!(
+                "No proxy on port {}; starting one for this {} session",
+                config.proxy.port,
+                tool_name
+            );
+            let proxy = EmbeddedProxy::start(config, &dir).await?;
+            Ok(Some((proxy, lock)))
+        }
+        Acquired::Held(holder) => {
+            tracing::warn!(
+                "{}; not starting a proxy for {}",
+                instance::describe(holder.as_ref()).await,
+                tool_name
+            );
+            Ok(None)
+        }
+    }
+}
+
+/// Suggest another base URL variable when nothing `tool_name` did reached
+/// the proxy, going by the archive index
+async fn report_no_traffic(
+    provider: &str,
+    tool_name: &str,
+    env_vars: &[String],
+    config: &Config,
+    launched_at: chrono::DateTime<chrono::Utc>,
+) {
+    let root = &config.archive.directory;
+    let mut seen = launch::requests_since(root, provider, launched_at);
+    // The archive writes behind the proxy; give the last requests a moment
+    if matches!(seen, Ok(0)) {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        seen = launch::requests_since(root, provider, launched_at);
+    }
+    match seen {
+        Ok(0) => {}
+        Ok(_) => return,
+        Err(e) => {
+            tracing::debug!("Couldn't check for traffic from {}: {:#}", tool_name, e);
+            return;
+        }
+    }
+    let format = config.providers[provider].body_format(provider);
+    let env: Vec<(String, String)> = std::env::vars().collect();
+    match launch::suggest_env_var(tool_name, format, &config.run, env_vars, &env) {
+        Some(name) => eprintln!(
+            "No traffic seen; {} may use {} — try --env-var {}",
+            tool_name, name, name
+        ),
+        None => eprintln!(
+            "No traffic seen; {} may not take its base URL from {} or any known alias",
+            tool_name,
+            env_vars.join(", ")
+        ),
+    }
+}
+
+/// Post a marker to the proxy listening on `port`
+async fn send_marker(bind_address: &str, port: u16, request: &MarkRequest) -> Result<Marker> {
+    let url = format!("http://{}:{}{}", bind_address, port, MARK_PATH);
+    let response = reqwest::Client::new()
+        .post(&url)
+        .json(request)
+        .send()
+        .await
+        .with_context(|| format!("No sherlock proxy answering on port {}", port))?;
+    let status = response.status();
+    if !status.is_success() {
+        anyhow::bail!("Proxy rejected the marker ({}): {}", status, response.text().await?);
+    }
+    Ok(response.json().await?)
+}
+
+/// The running instance's session totals, given up on quickly so a status
+/// bar never waits on a stuck proxy
+async fn fetch_status(bind_address: &str, port: u16) -> Result<Status> {
+    let url = format!("http://{}:{}{}", bind_address, port, STATUS_PATH);
+    let response = reqwest::Client::new()
+        .get(&url)
+        .timeout(STATUS_TIMEOUT)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(response.json().await?)
+}
+
+/// Parse a filter expression `flag` applies to the archive index, which
+/// keeps no messages to match text and `role:` terms against
+fn index_filter(expr: &str, flag: &str) -> Result<Filter> {
+    let filter = Filter::parse(expr).map_err(|e| anyhow::anyhow!("{}: {}", flag, e))?;
+    if filter.searches_messages() {
+        anyhow::bail!(
+            "{}: the archive index keeps no messages, so text and role: terms \
+             can't be used; filter on provider, model, tokens, since: and until:",
+            flag
+        );
+    }
+    Ok(filter)
+}
+
+/// `~/.sherlock`, home of the key salt and the model registry
+fn sherlock_dir() -> Result<std::path::PathBuf> {
+    Ok(dirs::home_dir()
+        .ok_or_else(|| anyhow::anyhow!("Could not determine home directory"))?
+        .join(".sherlock"))
+}
+
+/// What the global flags ask of a tool command's session
+#[derive(Debug, Clone, Copy)]
+struct ToolFlags {
+    /// Tag requests with the repository the tool runs in
+    repo_info: bool,
+    /// Start a proxy for the session when none is listening
+    embed_proxy: bool,
+}
+
 async fn run_tool(
     provider: &str,
     tool_name: &str,
     args: Vec<String>,
+    extra_env_vars: &[String],
     config: &Config,
+    flags: ToolFlags,
 ) -> Result<()> {
     use std::process::Stdio;
     use tokio::process::Command as TokioCommand;
 
+    // Serve the session in-process when no proxy is listening yet
+    let embedded = match flags.embed_proxy
+        && !embedded::listening(&config.proxy.bind_address, config.proxy.port).await
+    {
+        true => start_embedded(config, tool_name).await?,
+        false => None,
+    };
+
+    // A project overlay applies to this session alone
+    let cwd = std::env::current_dir().ok();
+    let overlay = match &cwd {
+        Some(dir) => Overlay::find(dir)?,
+        None => None,
+    };
+    let merged;
+    let config = match &overlay {
+        Some(overlay) => {
+            tracing::info!("Using project overlay {:?}", overlay.path);
+            merged = overlay.apply(config)?;
+            &merged
+        }
+        None => config,
+    };
+
     let provider_config = config
         .providers
         .get(provider)
         .ok_or_else(|| anyhow::anyhow!("Unknown provider: {}", provider))?;
 
-    let proxy_url = format!("http://{}:{}", config.proxy.bind_address, config.proxy.port);
+    let mut proxy_url = format!("http://{}:{}", config.proxy.bind_address, config.proxy.port);
+
+    // Tag the session's requests with the repository the tool runs in, and
+    // hand the proxy what it applies of the overlay
+    let repo = cwd
+        .filter(|_| flags.repo_info)
+        .and_then(|dir| RepoInfo::detect(&dir));
+    if let Some(repo) = &repo {
+        tracing::info!("Tagging requests with repository {}", repo.label());
+    }
+    let session = SessionInfo {
+        repo,
+        overlay: overlay.map(|overlay| SessionOverlay {
+            archive: config.archive.enabled,
+            policy: overlay.sets("policy").then(|| config.policy.clone()),
+            path: overlay.path,
+        }),
+    };
+    if session != SessionInfo::default() {
+        proxy_url.push_str(&session.to_path());
+    }
 
     let mut cmd = TokioCommand::new(tool_name);
     cmd.args(&args)
@@ -117,18 +1020,31 @@ async fn run_tool(
         .stderr(Stdio::inherit());
 
     // Set environment variables for the provider
-    for env_var in &provider_config.env_vars {
+    let format = provider_config.body_format(provider);
+    let env_vars = launch::env_vars(provider_config, format, &config.run, extra_env_vars);
+    for env_var in &env_vars {
         cmd.env(env_var, &proxy_url);
     }
 
     tracing::info!(
         "Running {} with {} set to {}",
         tool_name,
-        provider_config.env_vars.join(", "),
+        env_vars.join(", "),
         proxy_url
     );
 
-    let status = cmd.status().await?;
+    let launched_at = chrono::Utc::now();
+    let status = cmd.status().await;
+    // Checked while an embedded proxy still holds the instance lock; the
+    // archive is only complete once it has stopped
+    let check_traffic = config.archive.enabled && instance::running(&sherlock_dir()?).is_some();
+    if let Some((proxy, _lock)) = embedded {
+        proxy.stop().await;
+    }
+    let status = status?;
+    if check_traffic {
+        report_no_traffic(provider, tool_name, &env_vars, config, launched_at).await;
+    }
 
     if !status.success() {
         if let Some(code) = status.code() {
@@ -0,0 +1,291 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::caching::{CacheSummary, PrefixReuse};
+use crate::language::{self, LanguageMix};
+use crate::parser::SchemaDrift;
+use crate::shaping::Shaper;
+use crate::statusline::Status;
+
+/// Counters shared between the proxy and the dashboard
+#[derive(Debug, Default)]
+pub struct ProxyMetrics {
+    parse_errors: Mutex<BTreeMap<&'static str, u64>>,
+    schema_drift: Mutex<BTreeMap<String, DriftCounts>>,
+    policy_matches: Mutex<BTreeMap<String, u64>>,
+    cache_summary: Mutex<CacheSummary>,
+    /// Latest prompt caching hint per conversation label
+    cache_hints: Mutex<BTreeMap<String, String>>,
+    /// Approximate tokens per programming language this session
+    languages: Mutex<LanguageMix>,
+    /// The dashboard's session totals, for `sherlock statusline`
+    status: Mutex<Status>,
+    accept_errors: AtomicU64,
+    shed_connections: AtomicU64,
+    open_connections: AtomicUsize,
+    degraded: AtomicBool,
+    shaping: Arc<Shaper>,
+}
+
+/// Archive writer counters shared with the dashboard
+#[derive(Debug, Default)]
+pub struct ArchiveMetrics {
+    formats: Mutex<BTreeMap<String, FormatCounters>>,
+    backlog: AtomicUsize,
+    last_error: Mutex<Option<String>>,
+    /// Set on quit: events still queued after this are saved for next start
+    flush_deadline: Mutex<Option<Instant>>,
+    /// The writer has stopped and closed its sinks
+    finished: AtomicBool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FormatCounters {
+    pub written: u64,
+    pub bytes: u64,
+    pub failures: u64,
+}
+
+/// Point-in-time copy of `ArchiveMetrics`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchiveSnapshot {
+    pub formats: BTreeMap<String, FormatCounters>,
+    /// Events waiting to be written
+    pub backlog: usize,
+    pub last_error: Option<String>,
+}
+
+impl ArchiveMetrics {
+    pub fn record_write(&self, format: &str, bytes: usize) {
+        let mut formats = self.formats.lock().unwrap();
+        let counters = formats.entry(format.to_string()).or_default();
+        counters.written += 1;
+        counters.bytes += bytes as u64;
+    }
+
+    pub fn record_failure(&self, format: &str, error: String) {
+        self.formats
+            .lock()
+            .unwrap()
+            .entry(format.to_string())
+            .or_default()
+            .failures += 1;
+        *self.last_error.lock().unwrap() = Some(error);
+    }
+
+    pub fn set_backlog(&self, len: usize) {
+        self.backlog.store(len, Ordering::Relaxed);
+    }
+
+    pub fn backlog(&self) -> usize {
+        self.backlog.load(Ordering::Relaxed)
+    }
+
+    pub fn begin_shutdown(&self, deadline: Instant) {
+        *self.flush_deadline.lock().unwrap() = Some(deadline);
+    }
+
+    pub fn past_deadline(&self) -> bool {
+        self.flush_deadline
+            .lock()
+            .unwrap()
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    pub fn set_finished(&self) {
+        self.finished.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::Relaxed)
+    }
+
+    pub fn snapshot(&self) -> ArchiveSnapshot {
+        ArchiveSnapshot {
+            formats: self.formats.lock().unwrap().clone(),
+            backlog: self.backlog.load(Ordering::Relaxed),
+            last_error: self.last_error.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// Snapshot of the proxy's connection health
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxyHealth {
+    pub accept_errors: u64,
+    pub shed_connections: u64,
+    pub open_connections: usize,
+    /// Set by an accept error or a shed connection, cleared by the next
+    /// connection that is served normally
+    pub degraded: bool,
+}
+
+/// An open connection slot, released on drop
+pub struct ConnectionGuard {
+    metrics: Arc<ProxyMetrics>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.metrics.open_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Schema drift observed for one provider
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DriftCounts {
+    /// Requests that contained anything unknown
+    pub requests: u64,
+    /// Every unknown content type and field seen so far
+    pub seen: SchemaDrift,
+}
+
+impl ProxyMetrics {
+    /// Count a parse failure by its `ParseError::kind`
+    pub fn record_parse_error(&self, kind: &'static str) {
+        *self.parse_errors.lock().unwrap().entry(kind).or_default() += 1;
+    }
+
+    pub fn record_accept_error(&self) {
+        self.accept_errors.fetch_add(1, Ordering::Relaxed);
+        self.degraded.store(true, Ordering::Relaxed);
+    }
+
+    /// Reserve a connection slot, or `None` (counted as shed) when `cap`
+    /// connections are already open
+    pub fn open_connection(self: &Arc<Self>, cap: usize) -> Option<ConnectionGuard> {
+        let reserved = self
+            .open_connections
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |open| {
+                (open < cap).then_some(open + 1)
+            })
+            .is_ok();
+
+        if reserved {
+            self.degraded.store(false, Ordering::Relaxed);
+            Some(ConnectionGuard {
+                metrics: Arc::clone(self),
+            })
+        } else {
+            self.shed_connections.fetch_add(1, Ordering::Relaxed);
+            self.degraded.store(true, Ordering::Relaxed);
+            None
+        }
+    }
+
+    pub fn health(&self) -> ProxyHealth {
+        ProxyHealth {
+            accept_errors: self.accept_errors.load(Ordering::Relaxed),
+            shed_connections: self.shed_connections.load(Ordering::Relaxed),
+            open_connections: self.open_connections.load(Ordering::Relaxed),
+            degraded: self.degraded.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn record_schema_drift(&self, provider: &str, drift: &SchemaDrift) {
+        let mut schema_drift = self.schema_drift.lock().unwrap();
+        let counts = schema_drift.entry(provider.to_string()).or_default();
+        counts.requests += 1;
+        counts.seen.content_types.extend(drift.content_types.iter().cloned());
+        counts.seen.fields.extend(drift.fields.iter().cloned());
+    }
+
+    /// Schema drift per provider, sorted by provider
+    pub fn schema_drift(&self) -> Vec<(String, DriftCounts)> {
+        self.schema_drift
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(provider, counts)| (provider.clone(), counts.clone()))
+            .collect()
+    }
+
+    /// Add one request's content policy matches to the per-pattern totals
+    pub fn record_policy_matches(&self, matches: &BTreeMap<String, usize>) {
+        let mut totals = self.policy_matches.lock().unwrap();
+        for (name, count) in matches {
+            *totals.entry(name.clone()).or_default() += *count as u64;
+        }
+    }
+
+    pub fn policy_matches(&self) -> BTreeMap<String, u64> {
+        self.policy_matches.lock().unwrap().clone()
+    }
+
+    /// Add one follow-up request's prefix reuse to the session summary
+    pub fn record_prefix_reuse(&self, conversation: &str, reuse: &PrefixReuse) {
+        self.cache_summary.lock().unwrap().record(reuse);
+        let mut hints = self.cache_hints.lock().unwrap();
+        match reuse.hint() {
+            Some(hint) => hints.insert(conversation.to_string(), hint),
+            None => hints.remove(conversation),
+        };
+    }
+
+    /// Add one request's language mix to the session's
+    pub fn record_languages(&self, mix: &LanguageMix) {
+        language::merge(&mut self.languages.lock().unwrap(), mix);
+    }
+
+    pub fn languages(&self) -> LanguageMix {
+        self.languages.lock().unwrap().clone()
+    }
+
+    pub fn set_status(&self, status: Status) {
+        *self.status.lock().unwrap() = status;
+    }
+
+    pub fn status(&self) -> Status {
+        self.status.lock().unwrap().clone()
+    }
+
+    pub fn cache_summary(&self) -> CacheSummary {
+        *self.cache_summary.lock().unwrap()
+    }
+
+    /// Conversations currently leaving reusable tokens uncached, with their hints
+    pub fn cache_hints(&self) -> BTreeMap<String, String> {
+        self.cache_hints.lock().unwrap().clone()
+    }
+
+    /// Bandwidth limits, applied by the proxy and shown by the dashboard
+    pub fn shaping(&self) -> &Arc<Shaper> {
+        &self.shaping
+    }
+
+    /// Parse failure counts per kind, sorted by kind
+    pub fn parse_errors(&self) -> Vec<(&'static str, u64)> {
+        self.parse_errors
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(kind, count)| (*kind, *count))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_cap() {
+        let metrics = Arc::new(ProxyMetrics::default());
+        let first = metrics.open_connection(2).unwrap();
+        let _second = metrics.open_connection(2).unwrap();
+        assert!(metrics.open_connection(2).is_none());
+
+        let health = metrics.health();
+        assert_eq!(health.open_connections, 2);
+        assert_eq!(health.shed_connections, 1);
+        assert!(health.degraded);
+
+        drop(first);
+        assert!(metrics.open_connection(2).is_some());
+        assert!(!metrics.health().degraded);
+        assert_eq!(metrics.health().open_connections, 1);
+    }
+}
@@ -0,0 +1,200 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+pub const MODELS_FILE: &str = "models.json";
+
+/// Least recently seen models are dropped beyond this many entries
+const MAX_MODELS: usize = 1_000;
+
+/// Everything sherlock remembers about one model id
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelEntry {
+    /// Provider the model was first seen through
+    pub provider: String,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub requests: u64,
+}
+
+/// Every distinct model string seen in traffic, kept in `~/.sherlock/models.json`
+#[derive(Debug, Default)]
+pub struct ModelRegistry {
+    /// Where to save; `None` keeps the registry in memory only
+    path: Option<PathBuf>,
+    models: BTreeMap<String, ModelEntry>,
+    dirty: bool,
+}
+
+impl ModelRegistry {
+    /// Load the registry from `dir`. A missing file starts an empty registry;
+    /// an unreadable one is reported and replaced on the next save.
+    pub fn load(dir: &Path) -> Self {
+        let path = dir.join(MODELS_FILE);
+        let models = match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                tracing::warn!("Ignoring corrupt model registry {:?}: {}", path, e);
+                BTreeMap::new()
+            }),
+            Err(_) => BTreeMap::new(),
+        };
+        Self {
+            path: Some(path),
+            models,
+            dirty: false,
+        }
+    }
+
+    /// Count a request for `model`, returning true the first time it is seen
+    pub fn record(&mut self, provider: &str, model: &str, at: DateTime<Utc>) -> bool {
+        self.dirty = true;
+        if let Some(entry) = self.models.get_mut(model) {
+            // Imported history can predate what the proxy has seen
+            entry.first_seen = entry.first_seen.min(at);
+            entry.last_seen = entry.last_seen.max(at);
+            entry.requests += 1;
+            return false;
+        }
+        self.models.insert(
+            model.to_string(),
+            ModelEntry {
+                provider: provider.to_string(),
+                first_seen: at,
+                last_seen: at,
+                requests: 1,
+            },
+        );
+        true
+    }
+
+    pub fn entries(&self) -> &BTreeMap<String, ModelEntry> {
+        &self.models
+    }
+
+    /// Write the registry if it changed, via a temp file and rename so a
+    /// crash never leaves a truncated file behind
+    pub fn save(&mut self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if !self.dirty {
+            return Ok(());
+        }
+
+        if self.models.len() > MAX_MODELS {
+            let mut by_age: Vec<(DateTime<Utc>, String)> = self
+                .models
+                .iter()
+                .map(|(model, entry)| (entry.last_seen, model.clone()))
+                .collect();
+            by_age.sort();
+            for (_, model) in by_age.into_iter().take(self.models.len() - MAX_MODELS) {
+                self.models.remove(&model);
+            }
+        }
+
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(&self.models)?)
+            .with_context(|| format!("Failed to write {:?}", tmp))?;
+        std::fs::rename(&tmp, path).with_context(|| format!("Failed to replace {:?}", path))?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for ModelRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.models.is_empty() {
+            return writeln!(f, "No models seen yet");
+        }
+
+        let width = self.models.keys().map(|m| m.chars().count()).max().unwrap_or(0);
+        writeln!(
+            f,
+            "{:<width$}  {:<10}  {:<16}  {:<16}  {:>8}",
+            "MODEL", "PROVIDER", "FIRST SEEN", "LAST SEEN", "REQUESTS"
+        )?;
+        let mut models: Vec<_> = self.models.iter().collect();
+        models.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.last_seen));
+        for (model, entry) in models {
+            writeln!(
+                f,
+                "{:<width$}  {:<10}  {:<16}  {:<16}  {:>8}",
+                model,
+                entry.provider,
+                entry.first_seen.format("%Y-%m-%d %H:%M"),
+                entry.last_seen.format("%Y-%m-%d %H:%M"),
+                entry.requests
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("sherlock-models-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_record_and_reload() {
+        let dir = temp_dir("reload");
+        let first = Utc::now();
+        let later = first + chrono::Duration::minutes(5);
+
+        let mut registry = ModelRegistry::load(&dir);
+        assert!(registry.record("anthropic", "claude-sonnet-4-5", first));
+        assert!(!registry.record("anthropic", "claude-sonnet-4-5", later));
+        registry.save().unwrap();
+        assert!(!registry.dirty);
+        assert!(!dir.join("models.json.tmp").exists());
+
+        let registry = ModelRegistry::load(&dir);
+        let entry = &registry.entries()["claude-sonnet-4-5"];
+        assert_eq!(entry.provider, "anthropic");
+        assert_eq!(entry.first_seen, first);
+        assert_eq!(entry.last_seen, later);
+        assert_eq!(entry.requests, 2);
+    }
+
+    #[test]
+    fn test_corrupt_file_starts_empty() {
+        let dir = temp_dir("corrupt");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(MODELS_FILE), "{ not json").unwrap();
+
+        let mut registry = ModelRegistry::load(&dir);
+        assert!(registry.entries().is_empty());
+        assert!(registry.record("openai", "gpt-4o", Utc::now()));
+        registry.save().unwrap();
+        assert_eq!(ModelRegistry::load(&dir).entries().len(), 1);
+    }
+
+    #[test]
+    fn test_save_drops_least_recently_seen() {
+        let dir = temp_dir("bounded");
+        let start = Utc::now();
+        let mut registry = ModelRegistry::load(&dir);
+        for i in 0..MAX_MODELS + 5 {
+            let at = start + chrono::Duration::seconds(i as i64);
+            registry.record("openai", &format!("model-{:04}", i), at);
+        }
+        registry.save().unwrap();
+
+        let models = ModelRegistry::load(&dir).models;
+        assert_eq!(models.len(), MAX_MODELS);
+        assert!(!models.contains_key("model-0004"));
+        assert!(models.contains_key("model-0005"));
+    }
+}
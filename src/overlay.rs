@@ -0,0 +1,286 @@
+//! Project overlays: a `.sherlock.json` or `.sherlock.toml` in a project
+//! directory, merged over the global config for the tool sessions started
+//! there
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::config::{expand_tilde, Config};
+
+/// Overlay file names, looked for in this order in each directory
+pub const OVERLAY_FILES: [&str; 2] = [".sherlock.json", ".sherlock.toml"];
+
+/// Settings a project can't change: the proxy's listener, upstreams and
+/// archive are shared by every session, tools are autostarted with the
+/// proxy, and the schema version is the global file's
+const FIXED_SETTINGS: [&str; 7] = [
+    "proxy",
+    "providers",
+    "archive.directory",
+    "autostart",
+    "autostart_kill_on_exit",
+    "chaos",
+    "version",
+];
+
+/// A project overlay file and the settings in it
+#[derive(Debug, Clone)]
+pub struct Overlay {
+    pub path: PathBuf,
+    settings: Map<String, Value>,
+}
+
+impl Overlay {
+    /// The overlay in `dir`, or else in its nearest parent that has one
+    pub fn find(dir: &Path) -> Result<Option<Self>> {
+        for dir in dir.ancestors() {
+            for name in OVERLAY_FILES {
+                let path = dir.join(name);
+                if path.is_file() {
+                    return Self::load(&path).map(Some);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+        let value: Value = if path.extension().is_some_and(|ext| ext == "toml") {
+            toml::from_str(&text).with_context(|| format!("Invalid TOML in {:?}", path))?
+        } else {
+            serde_json::from_str(&text).with_context(|| format!("Invalid JSON in {:?}", path))?
+        };
+        let fixed: Vec<&str> = FIXED_SETTINGS
+            .into_iter()
+            .filter(|setting| {
+                value
+                    .pointer(&format!("/{}", setting.replace('.', "/")))
+                    .is_some()
+            })
+            .collect();
+        if !fixed.is_empty() {
+            anyhow::bail!(
+                "Overlay {:?} can't set {}; only the global config can",
+                path,
+                fixed.join(", ")
+            );
+        }
+        let Value::Object(settings) = value else {
+            anyhow::bail!("Overlay {:?} must contain an object", path);
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            settings,
+        })
+    }
+
+    /// `config` with the overlay merged over it, checked like a config file
+    pub fn apply(&self, config: &Config) -> Result<Config> {
+        let mut value = serde_json::to_value(config)?;
+        merge(&mut value, &self.settings);
+        let (mut merged, unknown) = Config::from_value(value)
+            .with_context(|| format!("Invalid overlay {:?}", self.path))?;
+        merged.validate(&self.path)?;
+        if !unknown.is_empty() {
+            tracing::warn!(
+                "Ignoring unknown overlay fields in {:?}: {}",
+                self.path,
+                unknown.join(", ")
+            );
+        }
+        merged.archive.directory = expand_tilde(&merged.archive.directory);
+        Ok(merged)
+    }
+
+    /// Dotted paths of the settings the overlay replaces, e.g. `archive.enabled`
+    pub fn overridden(&self) -> Vec<String> {
+        let mut paths = Vec::new();
+        leaf_paths(&self.settings, "", &mut paths);
+        paths
+    }
+
+    /// Whether the overlay sets anything in the top-level `section`
+    pub fn sets(&self, section: &str) -> bool {
+        self.settings.contains_key(section)
+    }
+}
+
+/// The settings in effect in a directory and where they come from, as
+/// `sherlock config show` prints them
+#[derive(Debug, Serialize)]
+pub struct Provenance {
+    pub global: PathBuf,
+    pub overlay: Option<PathBuf>,
+    /// Settings the overlay replaces
+    pub overridden: Vec<String>,
+    pub config: Config,
+}
+
+impl Provenance {
+    pub fn new(global: &Path, config: Config, overlay: Option<&Overlay>) -> Result<Self> {
+        Ok(Self {
+            global: expand_tilde(global),
+            overlay: overlay.map(|overlay| overlay.path.clone()),
+            overridden: overlay.map(Overlay::overridden).unwrap_or_default(),
+            config: match overlay {
+                Some(overlay) => overlay.apply(&config)?,
+                None => config,
+            },
+        })
+    }
+}
+
+impl fmt::Display for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Global config: {}", self.global.display())?;
+        match &self.overlay {
+            Some(path) => {
+                writeln!(f, "Overlay:       {}", path.display())?;
+                for field in &self.overridden {
+                    writeln!(f, "  {}", field)?;
+                }
+            }
+            None => writeln!(f, "Overlay:       none")?,
+        }
+        let json = serde_json::to_string_pretty(&self.config).map_err(|_| fmt::Error)?;
+        writeln!(f, "\n{}", json)
+    }
+}
+
+/// Merge `overlay` into `base` field by field; arrays and scalars replace
+/// what was there
+fn merge(base: &mut Value, overlay: &Map<String, Value>) {
+    let Value::Object(base) = base else {
+        *base = Value::Object(overlay.clone());
+        return;
+    };
+    for (key, value) in overlay {
+        match (base.get_mut(key), value) {
+            (Some(existing @ Value::Object(_)), Value::Object(fields)) => merge(existing, fields),
+            _ => {
+                base.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+fn leaf_paths(fields: &Map<String, Value>, prefix: &str, paths: &mut Vec<String>) {
+    for (key, value) in fields {
+        let path = format!("{}{}", prefix, key);
+        match value {
+            Value::Object(nested) if !nested.is_empty() => {
+                leaf_paths(nested, &format!("{}.", path), paths)
+            }
+            _ => paths.push(path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("sherlock-overlay-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_find_walks_up_from_nested_directories() {
+        let root = temp_dir("nested");
+        let project = root.join("client");
+        let nested = project.join("src/deep");
+        std::fs::create_dir_all(&nested).unwrap();
+        assert!(Overlay::find(&nested).unwrap().is_none());
+
+        std::fs::write(
+            project.join(".sherlock.toml"),
+            "[archive]\nenabled = false\n",
+        )
+        .unwrap();
+        let overlay = Overlay::find(&nested).unwrap().unwrap();
+        assert_eq!(overlay.path, project.join(".sherlock.toml"));
+
+        // The nearest overlay wins, and JSON before TOML in one directory
+        std::fs::write(nested.join(".sherlock.toml"), "").unwrap();
+        std::fs::write(nested.join(".sherlock.json"), "{}").unwrap();
+        let overlay = Overlay::find(&nested).unwrap().unwrap();
+        assert_eq!(overlay.path, nested.join(".sherlock.json"));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_apply_merges_over_global() {
+        let dir = temp_dir("apply");
+        let path = dir.join(".sherlock.json");
+        std::fs::write(
+            &path,
+            r#"{
+                "archive": {"enabled": false},
+                "dashboard": {"token_limit": 50000},
+                "policy": {"max_output_tokens": 4096}
+            }"#,
+        )
+        .unwrap();
+        let overlay = Overlay::load(&path).unwrap();
+        let global = Config::default();
+        let merged = overlay.apply(&global).unwrap();
+
+        assert!(!merged.archive.enabled);
+        assert_eq!(merged.dashboard.token_limit, 50000);
+        assert_eq!(merged.policy.max_output_tokens, Some(4096));
+        // Untouched settings in a touched section stay as they were
+        assert_eq!(merged.archive.sinks, global.archive.sinks);
+        assert_eq!(
+            merged.dashboard.max_log_entries,
+            global.dashboard.max_log_entries
+        );
+        assert_eq!(
+            overlay.overridden(),
+            [
+                "archive.enabled",
+                "dashboard.token_limit",
+                "policy.max_output_tokens"
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rejects_fixed_and_invalid_settings() {
+        let dir = temp_dir("fixed");
+        let path = dir.join(".sherlock.json");
+        for fixed in [
+            r#"{"proxy": {"port": 9999}}"#,
+            r#"{"providers": {}}"#,
+            r#"{"version": 1}"#,
+            r#"{"archive": {"directory": "/tmp/elsewhere"}}"#,
+        ] {
+            std::fs::write(&path, fixed).unwrap();
+            let err = Overlay::load(&path).unwrap_err().to_string();
+            assert!(err.contains("can't set"), "{}", err);
+        }
+
+        // Checked with the global config's rules
+        std::fs::write(
+            &path,
+            r#"{"policy": {"scan_patterns": [{"name": "bad", "regex": "("}]}}"#,
+        )
+        .unwrap();
+        let overlay = Overlay::load(&path).unwrap();
+        let err = overlay.apply(&Config::default()).unwrap_err();
+        assert!(format!("{:#}", err).contains("Invalid policy"));
+
+        std::fs::write(&path, "[1, 2]").unwrap();
+        assert!(Overlay::load(&path).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,202 +1,1168 @@
-use anyhow::Result;
 use once_cell::sync::Lazy;
+use serde::de::{DeserializeSeed, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use thiserror::Error;
 use tiktoken_rs::CoreBPE;
 
-use crate::event::{Message, RequestEvent};
+use crate::aggregate::unprefixed;
+use crate::config::ProxyConfig;
+use crate::event::{Message, RequestEvent, TokenComposition, UnknownPart, TOOL_USE_ROLE};
 
-/// Cached tiktoken encoding for cl100k_base (used by Claude and GPT-4)
-static ENCODING: Lazy<CoreBPE> = Lazy::new(|| {
-    tiktoken_rs::cl100k_base().expect("Failed to load cl100k_base encoding")
-});
+/// Bodies larger than this are forwarded but not parsed
+pub const MAX_PARSE_BODY_BYTES: usize = 64 * 1024 * 1024;
 
-/// Count the number of tokens in a text string
+/// Why a request body couldn't be turned into a `RequestEvent`
+#[derive(Debug, Error)]
+pub enum ParseError {
+    #[error("body is not valid UTF-8")]
+    InvalidUtf8,
+    #[error("body is not JSON: {0}")]
+    NotJson(#[from] serde_json::Error),
+    #[error("request has no {0} array")]
+    MissingMessages(&'static str),
+    #[error("endpoint {0} is not a generation request")]
+    UnsupportedEndpoint(String),
+    #[error("body of {size} bytes exceeds the {limit} byte parse limit")]
+    OversizedBody { size: usize, limit: usize },
+}
+
+impl ParseError {
+    /// Stable variant name used for logging and metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ParseError::InvalidUtf8 => "InvalidUtf8",
+            ParseError::NotJson(_) => "NotJson",
+            ParseError::MissingMessages(_) => "MissingMessages",
+            ParseError::UnsupportedEndpoint(_) => "UnsupportedEndpoint",
+            ParseError::OversizedBody { .. } => "OversizedBody",
+        }
+    }
+
+    /// The body itself is broken, rather than a well-formed body that isn't
+    /// a generation request
+    pub fn is_malformed(&self) -> bool {
+        matches!(self, ParseError::InvalidUtf8 | ParseError::NotJson(_))
+    }
+}
+
+type Result<T> = std::result::Result<T, ParseError>;
+
+/// Anthropic content block types the parser knows about
+const ANTHROPIC_CONTENT_TYPES: &[&str] = &[
+    "text",
+    "image",
+    "document",
+    "tool_use",
+    "tool_result",
+    "thinking",
+    "redacted_thinking",
+];
+
+/// Anthropic Messages API request fields
+const ANTHROPIC_FIELDS: &[&str] = &[
+    "model",
+    "messages",
+    "system",
+    "max_tokens",
+    "metadata",
+    "stop_sequences",
+    "stream",
+    "temperature",
+    "top_k",
+    "top_p",
+    "tools",
+    "tool_choice",
+    "thinking",
+    "service_tier",
+];
+
+/// OpenAI chat content part types the parser knows about
+const OPENAI_CONTENT_TYPES: &[&str] = &["text", "image_url", "input_audio", "file", "refusal"];
+
+/// OpenAI Chat Completions request fields
+const OPENAI_FIELDS: &[&str] = &[
+    "model",
+    "messages",
+    "frequency_penalty",
+    "logit_bias",
+    "logprobs",
+    "top_logprobs",
+    "max_tokens",
+    "max_completion_tokens",
+    "n",
+    "presence_penalty",
+    "response_format",
+    "seed",
+    "stop",
+    "stream",
+    "stream_options",
+    "temperature",
+    "top_p",
+    "tools",
+    "tool_choice",
+    "parallel_tool_calls",
+    "functions",
+    "function_call",
+    "user",
+    "reasoning_effort",
+    "store",
+    "metadata",
+    "modalities",
+    "audio",
+    "prediction",
+    "service_tier",
+];
+
+/// OpenAI Responses API input content part types the parser knows about
+const OPENAI_RESPONSES_CONTENT_TYPES: &[&str] = &[
+    "input_text",
+    "input_image",
+    "input_file",
+    "input_audio",
+    "output_text",
+    "refusal",
+];
+
+/// OpenAI Responses API request fields
+const OPENAI_RESPONSES_FIELDS: &[&str] = &[
+    "model",
+    "input",
+    "instructions",
+    "background",
+    "conversation",
+    "include",
+    "max_output_tokens",
+    "max_tool_calls",
+    "metadata",
+    "parallel_tool_calls",
+    "previous_response_id",
+    "prompt",
+    "prompt_cache_key",
+    "reasoning",
+    "safety_identifier",
+    "service_tier",
+    "store",
+    "stream",
+    "stream_options",
+    "temperature",
+    "text",
+    "tool_choice",
+    "tools",
+    "top_logprobs",
+    "top_p",
+    "truncation",
+    "user",
+];
+
+/// Gemini generateContent request fields
+const GEMINI_FIELDS: &[&str] = &[
+    "model",
+    "contents",
+    "systemInstruction",
+    "generationConfig",
+    "safetySettings",
+    "tools",
+    "toolConfig",
+    "cachedContent",
+];
+
+/// Ollama /api/chat request fields
+const OLLAMA_CHAT_FIELDS: &[&str] = &[
+    "model",
+    "messages",
+    "tools",
+    "format",
+    "options",
+    "stream",
+    "keep_alive",
+    "think",
+];
+
+/// Ollama /api/generate request fields
+const OLLAMA_GENERATE_FIELDS: &[&str] = &[
+    "model",
+    "prompt",
+    "suffix",
+    "images",
+    "system",
+    "template",
+    "context",
+    "raw",
+    "format",
+    "options",
+    "stream",
+    "keep_alive",
+    "think",
+];
+
+/// Parts of a request that don't match the schema the parser models
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SchemaDrift {
+    pub content_types: BTreeSet<String>,
+    pub fields: BTreeSet<String>,
+}
+
+impl SchemaDrift {
+    pub fn is_empty(&self) -> bool {
+        self.content_types.is_empty() && self.fields.is_empty()
+    }
+}
+
+/// A tiktoken encoding to count tokens with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Encoding {
+    #[default]
+    #[serde(rename = "cl100k_base")]
+    Cl100k,
+    #[serde(rename = "o200k_base")]
+    O200k,
+}
+
+static CL100K: Lazy<CoreBPE> =
+    Lazy::new(|| tiktoken_rs::cl100k_base().expect("Failed to load cl100k_base encoding"));
+static O200K: Lazy<CoreBPE> =
+    Lazy::new(|| tiktoken_rs::o200k_base().expect("Failed to load o200k_base encoding"));
+
+/// Models tokenized with o200k_base, by name prefix
+const O200K_MODELS: &[&str] = &[
+    "gpt-4o",
+    "chatgpt-4o",
+    "gpt-4.1",
+    "gpt-4.5",
+    "gpt-5",
+    "gpt-oss",
+    "o1",
+    "o3",
+    "o4",
+];
+
+impl Encoding {
+    /// The encoding `model` uses: o200k_base for GPT-4o and later OpenAI
+    /// models, cl100k_base for the rest. Claude's and Gemini's tokenizers
+    /// aren't public, so cl100k_base stands in for them.
+    pub fn for_model(model: &str) -> Self {
+        let model = unprefixed(model).to_ascii_lowercase();
+        if O200K_MODELS.iter().any(|prefix| model.starts_with(prefix)) {
+            Encoding::O200k
+        } else {
+            Encoding::Cl100k
+        }
+    }
+
+    fn bpe(self) -> &'static CoreBPE {
+        match self {
+            Encoding::Cl100k => &CL100K,
+            Encoding::O200k => &O200K,
+        }
+    }
+
+    /// Tokens in `text`
+    pub fn count(self, text: &str) -> usize {
+        let (tokens, counted) = self.count_whole_chunks(text);
+        tokens + self.bpe().encode_ordinary(&text[counted..]).len()
+    }
+
+    /// Tokens in the leading chunks of `text`, and how many bytes those cover
+    fn count_whole_chunks(self, text: &str) -> (usize, usize) {
+        let (mut tokens, mut counted) = (0, 0);
+        while let Some(len) = chunk_len(&text[counted..]) {
+            let chunk = &text[counted..counted + len];
+            tokens += self.bpe().encode_ordinary(chunk).len();
+            counted += len;
+        }
+        (tokens, counted)
+    }
+
+    /// Tokens in `lines`, each ending in a newline, all joined
+    fn count_lines<'a>(self, lines: impl IntoIterator<Item = &'a str>) -> usize {
+        let mut lines = lines.into_iter().peekable();
+        let mut tokens = 0;
+        // Lines are only joined where the tokenizer could run them together
+        let mut joined = String::new();
+        while let Some(line) = lines.next() {
+            joined.push_str(line);
+            joined.push('\n');
+            if lines.peek().is_none_or(|next| starts_apart(next)) {
+                tokens += self.count(&joined);
+                joined.clear();
+            }
+        }
+        tokens
+    }
+}
+
+/// Text is tokenized this many bytes at a time or a little more, so counting
+/// a large body never holds a token for every few bytes of it at once
+const TOKEN_CHUNK_BYTES: usize = 64 * 1024;
+
+/// Count the number of tokens in a text string, with cl100k_base
 pub fn count_tokens(text: &str) -> usize {
-    ENCODING.encode_ordinary(text).len()
+    Encoding::Cl100k.count(text)
+}
+
+/// Where the first chunk of `text` ends, if it's longer than one: after a
+/// newline that `starts_apart`
+fn chunk_len(text: &str) -> Option<usize> {
+    let tail = text.as_bytes().get(TOKEN_CHUNK_BYTES..)?;
+    tail.iter()
+        .enumerate()
+        .filter(|(_, &byte)| byte == b'\n')
+        .map(|(i, _)| TOKEN_CHUNK_BYTES + i + 1)
+        .find(|&end| starts_apart(&text[end..]))
+}
+
+/// Whether text after a newline is tokenized the same on its own: when it
+/// starts with something other than whitespace, maybe indented. Only a
+/// further line break would join the newline to what follows, or with
+/// o200k_base, a slash right after punctuation ending the line.
+fn starts_apart(text: &str) -> bool {
+    let first = text
+        .chars()
+        .find(|&c| !c.is_whitespace() || c == '\r' || c == '\n');
+    first.is_some_and(|c| !c.is_whitespace() && c != '/')
+}
+
+/// How `parse_request_with` reads large bodies
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// Bodies at least this large are read one message at a time, never
+    /// holding the whole JSON tree; `None` reads every body whole
+    pub streaming_threshold: Option<usize>,
+    /// Longest string kept in full in a streamed body's `raw_body`
+    pub max_kept_string: usize,
+    /// Tokenizer to count with instead of the model's own
+    pub encoding: Option<Encoding>,
+    /// Model behind each deployment a path names, as set for the provider
+    pub deployment_models: BTreeMap<String, String>,
+}
+
+impl From<&ProxyConfig> for ParseOptions {
+    fn from(config: &ProxyConfig) -> Self {
+        Self {
+            streaming_threshold: Some(config.streaming_parse_bytes).filter(|&bytes| bytes > 0),
+            max_kept_string: config.streamed_body_max_string_bytes,
+            encoding: None,
+            deployment_models: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self::from(&ProxyConfig::default())
+    }
+}
+
+impl ParseOptions {
+    /// Read every body whole, keeping all of it
+    pub const WHOLE: Self = Self {
+        streaming_threshold: None,
+        max_kept_string: usize::MAX,
+        encoding: None,
+        deployment_models: BTreeMap::new(),
+    };
 }
 
 /// Parse a request body and create a RequestEvent
 pub fn parse_request(body: &[u8], path: &str, provider: &str) -> Result<RequestEvent> {
-    let raw_body: Value = serde_json::from_slice(body)?;
-
-    let (model, messages, total_text) = match provider {
-        "anthropic" => parse_anthropic_request(&raw_body)?,
-        "openai" => parse_openai_request(&raw_body)?,
-        "gemini" => parse_gemini_request(&raw_body)?,
-        _ => {
-            // Generic fallback
-            let model = raw_body
-                .get("model")
-                .and_then(|v| v.as_str())
-                .unwrap_or("unknown")
-                .to_string();
-            let text = extract_text_from_value(&raw_body);
-            (model, vec![], text)
-        }
+    parse_request_with(body, path, provider, &ParseOptions::WHOLE)
+}
+
+/// Parse a request body, streaming through it if it is large enough
+pub fn parse_request_with(
+    body: &[u8],
+    path: &str,
+    provider: &str,
+    options: &ParseOptions,
+) -> Result<RequestEvent> {
+    if body.len() > MAX_PARSE_BODY_BYTES {
+        return Err(ParseError::OversizedBody {
+            size: body.len(),
+            limit: MAX_PARSE_BODY_BYTES,
+        });
+    }
+    if is_unsupported_endpoint(path, provider) {
+        return Err(ParseError::UnsupportedEndpoint(path.to_string()));
+    }
+
+    let text = std::str::from_utf8(body).map_err(|_| ParseError::InvalidUtf8)?;
+    let Some(format) = request_format(provider, path) else {
+        // Generic fallback
+        let raw_body: Value = serde_json::from_str(text)?;
+        let model = raw_body
+            .get("model")
+            .and_then(|v| v.as_str())
+            .unwrap_or("unknown")
+            .to_string();
+        let encoding = options
+            .encoding
+            .unwrap_or_else(|| Encoding::for_model(&model));
+        return Ok(RequestEvent {
+            tokens: encoding.count(&extract_text_from_value(&raw_body)),
+            ..new_event(provider, path, model, raw_body)
+        });
     };
 
-    let tokens = count_tokens(&total_text);
+    // Settled before reading, as a streamed body is counted as it goes
+    let path_model = path_model(provider, path).map(|model| {
+        let deployed = options.deployment_models.get(&model);
+        deployed.cloned().unwrap_or(model)
+    });
+    let encoding = options.encoding.unwrap_or_else(|| {
+        let model = path_model.clone().or_else(|| body_model(text));
+        Encoding::for_model(model.as_deref().unwrap_or(format.default_model))
+    });
+    let streamed = match options.streaming_threshold {
+        Some(threshold) if body.len() >= threshold => {
+            stream_body(text, provider, &format, options.max_kept_string, encoding)?
+        }
+        _ => None,
+    };
+    let (raw_body, reading) = match streamed {
+        Some(streamed) => streamed,
+        None => {
+            let raw_body: Value = serde_json::from_str(text)?;
+            let reading = read_body(&raw_body, provider, &format, encoding);
+            (raw_body, reading)
+        }
+    };
+    let has_messages = match raw_body.get(format.messages) {
+        Some(Value::Array(_)) => true,
+        Some(Value::String(_)) => format.text_input,
+        _ => false,
+    };
+    if !has_messages {
+        return Err(ParseError::MissingMessages(format.messages));
+    }
 
+    let (tool_results, tools) = (reading.tool_results, reading.tools + reading.functions);
+    let (model, messages, tokens) = reading.finish(&format);
+    let model = path_model.unwrap_or(model);
+    let composition = token_composition(&messages, tokens, tool_results, tools, encoding);
     Ok(RequestEvent {
+        tokens,
+        messages,
+        composition: Some(composition),
+        ..new_event(provider, path, model, raw_body)
+    })
+}
+
+/// The `model` field of a JSON body, found without building the rest of it
+fn body_model(text: &str) -> Option<String> {
+    #[derive(Deserialize)]
+    struct Model {
+        model: Option<String>,
+    }
+    serde_json::from_str::<Model>(text).ok()?.model
+}
+
+/// The model a provider names in the request path rather than the body:
+/// Gemini's `/v1beta/models/gemini-1.5-pro:generateContent`, in any API
+/// version and for any method, or the deployment in Azure OpenAI's
+/// `/openai/deployments/gpt4o-prod/chat/completions`
+fn path_model(provider: &str, path: &str) -> Option<String> {
+    let path = percent_decode(path.split('?').next().unwrap_or(path));
+    let model = match provider {
+        "gemini" => {
+            let (_, rest) = path.rsplit_once("models/")?;
+            let (model, _method) = rest.split_once(':')?;
+            model
+        }
+        "openai" | "azure" => {
+            let (_, rest) = path.split_once("/openai/deployments/")?;
+            let (deployment, _endpoint) = rest.split_once('/')?;
+            deployment
+        }
+        _ => return None,
+    };
+    (!model.is_empty() && !model.contains('/')).then(|| model.to_string())
+}
+
+/// `%XX` escapes in a URL path decoded, leaving malformed ones as they are
+fn percent_decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| path.get(i + 1..i + 3))
+            .flatten()
+            .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// A request just read from the client, before anything is counted in it
+fn new_event(provider: &str, path: &str, model: String, raw_body: Value) -> RequestEvent {
+    // Anthropic and OpenAI both name it `service_tier`; Gemini has none
+    let service_tier = raw_body
+        .get("service_tier")
+        .and_then(Value::as_str)
+        .map(str::to_string);
+    let cached_content = raw_body
+        .get("cachedContent")
+        .and_then(Value::as_str)
+        .filter(|_| provider == "gemini")
+        .map(str::to_string);
+    let streaming = requests_stream(&raw_body, path, provider);
+    RequestEvent {
         timestamp: chrono::Utc::now(),
+        id: 0,
         provider: provider.to_string(),
         model,
-        tokens,
-        messages,
+        tokens: 0,
+        messages: vec![],
         raw_body,
         path: path.to_string(),
-    })
+        api_version: None,
+        streaming,
+        key: None,
+        client_aborted: false,
+        policy_matches: BTreeMap::new(),
+        failover: None,
+        recording: None,
+        repo: None,
+        overlay: None,
+        unarchived: false,
+        output_clamp: None,
+        chaos: None,
+        imported: false,
+        self_test: false,
+        manual: false,
+        throughput: None,
+        composition: None,
+        response: None,
+        output: None,
+        latency_ms: None,
+        timings: None,
+        context_overflow: None,
+        served_model: None,
+        service_tier,
+        served_tier: None,
+        cost_usd: None,
+        response_text: None,
+        auth: Default::default(),
+        cached_content,
+        gemini_usage: None,
+    }
 }
 
-/// Parse Anthropic Messages API request
-fn parse_anthropic_request(body: &Value) -> Result<(String, Vec<Message>, String)> {
-    let model = body
-        .get("model")
-        .and_then(|v| v.as_str())
-        .unwrap_or("unknown")
-        .to_string();
+/// Whether the client asked for an event stream: `"stream": true` for
+/// Anthropic and OpenAI, `alt=sse` in the query for Gemini. Ollama streams
+/// unless told `"stream": false`.
+fn requests_stream(body: &Value, path: &str, provider: &str) -> bool {
+    let sse_query = path
+        .split_once('?')
+        .is_some_and(|(_, query)| query.split('&').any(|pair| pair == "alt=sse"));
+    let stream = body.get("stream").and_then(Value::as_bool);
+    match provider {
+        "ollama" => body.is_object() && stream != Some(false),
+        _ => sse_query || stream == Some(true),
+    }
+}
 
-    let mut messages = Vec::new();
-    // Pre-allocate for typical request sizes
-    let mut all_text = String::with_capacity(4096);
+/// Minimal event for a body that isn't JSON, so the request still shows up
+pub fn minimal_event(body: &[u8], path: &str, provider: &str) -> RequestEvent {
+    let text = String::from_utf8_lossy(body);
+    RequestEvent {
+        tokens: count_tokens(&text),
+        ..new_event(
+            provider,
+            path,
+            path_model(provider, path).unwrap_or_else(|| "unknown".to_string()),
+            Value::Null,
+        )
+    }
+}
+
+/// Split a parsed request's `tokens` into system prompt, tool definitions,
+/// tool results and conversation
+fn token_composition(
+    messages: &[Message],
+    tokens: usize,
+    tool_results: usize,
+    tools: usize,
+    encoding: Encoding,
+) -> TokenComposition {
+    let system: usize = messages
+        .iter()
+        .filter(|m| m.role == "system" || m.role == "developer")
+        .map(|m| encoding.count(&m.content))
+        .sum();
+    TokenComposition {
+        system: system as u64,
+        tools: tools as u64,
+        tool_results: tool_results as u64,
+        conversation: tokens.saturating_sub(system + tools + tool_results) as u64,
+    }
+}
+
+/// Unknown content block types and top-level fields in a parsed request.
+/// The unknown parts themselves stay in `raw_body` and `Message::unknown_parts`.
+pub fn schema_drift(event: &RequestEvent) -> SchemaDrift {
+    let known_fields = match event.provider.as_str() {
+        "anthropic" => ANTHROPIC_FIELDS,
+        "openai" if is_responses_path(&event.path) => OPENAI_RESPONSES_FIELDS,
+        "openai" => OPENAI_FIELDS,
+        "gemini" => GEMINI_FIELDS,
+        "ollama" if is_ollama_generate_path(&event.path) => OLLAMA_GENERATE_FIELDS,
+        "ollama" => OLLAMA_CHAT_FIELDS,
+        _ => return SchemaDrift::default(),
+    };
+
+    let content_types = event
+        .messages
+        .iter()
+        .flat_map(|m| &m.unknown_parts)
+        .map(|part| part.kind.clone())
+        .collect();
+    let fields = event
+        .raw_body
+        .as_object()
+        .into_iter()
+        .flat_map(|obj| obj.keys())
+        .filter(|key| !known_fields.contains(&key.as_str()))
+        .cloned()
+        .collect();
+
+    SchemaDrift {
+        content_types,
+        fields,
+    }
+}
+
+/// Typed content blocks whose `type` isn't in `known`
+fn unknown_parts(content: &Value, known: &[&str]) -> Vec<UnknownPart> {
+    let Value::Array(blocks) = content else {
+        return Vec::new();
+    };
+    blocks
+        .iter()
+        .filter_map(|block| {
+            let kind = block.get("type")?.as_str()?;
+            (!known.contains(&kind)).then(|| UnknownPart {
+                kind: kind.to_string(),
+                json: block.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Paths that match a provider pattern but aren't generation requests
+fn is_unsupported_endpoint(path: &str, provider: &str) -> bool {
+    let path = path.split('?').next().unwrap_or(path);
+    match provider {
+        "anthropic" => path.ends_with("/count_tokens") || path.contains("/messages/batches"),
+        // Retrieving, cancelling or counting a stored response
+        "openai" => path.contains("/responses/"),
+        _ => false,
+    }
+}
+
+/// Whether an OpenAI request is for the Responses API rather than Chat Completions
+fn is_responses_path(path: &str) -> bool {
+    let path = path.split('?').next().unwrap_or(path);
+    path.ends_with("/responses")
+}
+
+/// Whether an Ollama request is a single-prompt completion rather than a chat
+fn is_ollama_generate_path(path: &str) -> bool {
+    let path = path.split('?').next().unwrap_or(path);
+    path.ends_with("/api/generate")
+}
+
+/// Where a provider's request body keeps its conversation, and how each
+/// message in it reads
+struct RequestFormat {
+    messages: &'static str,
+    system: Option<&'static str>,
+    /// Used when neither the path nor the body names a model
+    default_model: &'static str,
+    /// The normalized messages one message of the body reads as, itself first
+    message: fn(&Value) -> Vec<Message>,
+    /// Whether the conversation may also be a single string, read as one message
+    text_input: bool,
+}
+
+fn request_format(provider: &str, path: &str) -> Option<RequestFormat> {
+    match provider {
+        "anthropic" => Some(RequestFormat {
+            messages: "messages",
+            system: Some("system"),
+            default_model: "unknown",
+            message: anthropic_messages,
+            text_input: false,
+        }),
+        "openai" if is_responses_path(path) => Some(RequestFormat {
+            messages: "input",
+            system: Some("instructions"),
+            default_model: "unknown",
+            message: |item| vec![openai_responses_item(item)],
+            text_input: true,
+        }),
+        "openai" => Some(RequestFormat {
+            messages: "messages",
+            system: None,
+            default_model: "unknown",
+            message: |msg| vec![openai_message(msg)],
+            text_input: false,
+        }),
+        "gemini" => Some(RequestFormat {
+            messages: "contents",
+            system: Some("systemInstruction"),
+            default_model: "gemini",
+            message: |msg| vec![gemini_message(msg)],
+            text_input: false,
+        }),
+        "ollama" if is_ollama_generate_path(path) => Some(RequestFormat {
+            messages: "prompt",
+            system: Some("system"),
+            default_model: "unknown",
+            message: |prompt| {
+                vec![Message {
+                    role: "user".to_string(),
+                    content: extract_text_from_value(prompt),
+                    unknown_parts: vec![],
+                }]
+            },
+            text_input: true,
+        }),
+        "ollama" => Some(RequestFormat {
+            messages: "messages",
+            system: None,
+            default_model: "unknown",
+            message: ollama_messages,
+            text_input: false,
+        }),
+        _ => None,
+    }
+}
 
-    // Handle system prompt
-    if let Some(system) = body.get("system") {
-        let system_text = extract_text_from_value(system);
-        if !system_text.is_empty() {
-            messages.push(Message {
+/// What the parser takes from a request body, gathered a field and a
+/// message at a time so it works on a whole body or a stream alike
+#[derive(Default)]
+struct Reading {
+    /// What the tokens are counted with
+    encoding: Encoding,
+    model: Option<String>,
+    system: Option<Message>,
+    messages: Vec<Message>,
+    /// Tokens of tool output sent back in the messages
+    tool_results: usize,
+    /// Tokens of the tool definitions
+    tools: usize,
+    /// Tokens of OpenAI's legacy `functions` definitions
+    functions: usize,
+}
+
+impl Reading {
+    /// Take what's needed from a top-level field other than the messages
+    fn read_field(&mut self, format: &RequestFormat, key: &str, value: &Value) {
+        if key == "model" {
+            self.model = value.as_str().map(str::to_string);
+        } else if key == "tools" {
+            self.tools = self.definition_tokens(value);
+        } else if key == "functions" {
+            self.functions = self.definition_tokens(value);
+        } else if Some(key) == format.system {
+            let text = extract_text_from_value(value);
+            self.system = (!text.is_empty()).then(|| Message {
                 role: "system".to_string(),
-                content: system_text.clone(),
+                content: text,
+                unknown_parts: vec![],
             });
-            all_text.push_str(&system_text);
-            all_text.push('\n');
         }
     }
 
-    // Handle messages array
-    if let Some(Value::Array(msgs)) = body.get("messages") {
-        for msg in msgs {
-            let role = msg
-                .get("role")
-                .and_then(|v| v.as_str())
-                .unwrap_or("unknown")
-                .to_string();
-
-            let content = if let Some(content_val) = msg.get("content") {
-                extract_text_from_value(content_val)
-            } else {
-                String::new()
-            };
+    /// Tokens of a tool definitions array, counted as the JSON it's sent as
+    fn definition_tokens(&self, value: &Value) -> usize {
+        match value {
+            Value::Null => 0,
+            tools => self.encoding.count(&tools.to_string()),
+        }
+    }
 
-            if !content.is_empty() {
-                all_text.push_str(&content);
-                all_text.push('\n');
+    fn read_message(&mut self, provider: &str, format: &RequestFormat, msg: &Value) {
+        let messages = (format.message)(msg);
+        let message = &messages[0];
+        self.tool_results += match provider {
+            "anthropic" => msg
+                .get("content")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter(|block| block.get("type").and_then(Value::as_str) == Some("tool_result"))
+                .filter_map(|block| block.get("content"))
+                .map(|content| self.encoding.count(&extract_text_from_value(content)))
+                .sum(),
+            "openai" | "ollama" if message.role == "tool" || message.role == "function" => {
+                self.encoding.count(&message.content)
             }
+            // Function responses aren't part of the Gemini text count
+            _ => 0,
+        };
+        self.messages.extend(messages);
+    }
 
-            messages.push(Message { role, content });
+    /// The model, the system prompt followed by the messages, and the
+    /// tokens in the text of all of them, one per line, plus the tool
+    /// definitions
+    fn finish(self, format: &RequestFormat) -> (String, Vec<Message>, usize) {
+        let model = self
+            .model
+            .unwrap_or_else(|| format.default_model.to_string());
+        let messages: Vec<Message> = self.system.into_iter().chain(self.messages).collect();
+        let texts = messages.iter().filter(|m| !m.content.is_empty());
+        let tokens = self.encoding.count_lines(texts.map(|m| m.content.as_str()));
+        (model, messages, tokens + self.tools + self.functions)
+    }
+}
+
+/// Read a body already parsed whole
+fn read_body(body: &Value, provider: &str, format: &RequestFormat, encoding: Encoding) -> Reading {
+    let mut reading = Reading {
+        encoding,
+        ..Reading::default()
+    };
+    for (key, value) in body.as_object().into_iter().flatten() {
+        if key != format.messages {
+            reading.read_field(format, key, value);
+        }
+    }
+    match body.get(format.messages) {
+        Some(Value::Array(msgs)) => {
+            for msg in msgs {
+                reading.read_message(provider, format, msg);
+            }
         }
+        Some(text @ Value::String(_)) if format.text_input => {
+            reading.read_message(provider, format, text);
+        }
+        _ => {}
     }
+    reading
+}
 
-    Ok((model, messages, all_text))
+/// Read a body straight from its text, holding one message's JSON at a time
+/// and keeping a copy of the body with long strings cut. `None` if the body
+/// isn't shaped as `format` expects, to be read whole instead.
+fn stream_body(
+    text: &str,
+    provider: &str,
+    format: &RequestFormat,
+    max_kept_string: usize,
+    encoding: Encoding,
+) -> Result<Option<(Value, Reading)>> {
+    let mut deserializer = serde_json::Deserializer::from_str(text);
+    let visitor = BodyVisitor {
+        provider,
+        format,
+        max_kept_string,
+        encoding,
+    };
+    let streamed = deserializer
+        .deserialize_map(visitor)
+        .and_then(|streamed| deserializer.end().map(|_| streamed));
+    match streamed {
+        Ok(streamed) => Ok(Some(streamed)),
+        Err(e) if e.is_data() => Ok(None),
+        Err(e) => Err(e.into()),
+    }
 }
 
-/// Parse OpenAI Chat Completions API request
-fn parse_openai_request(body: &Value) -> Result<(String, Vec<Message>, String)> {
-    let model = body
-        .get("model")
-        .and_then(|v| v.as_str())
-        .unwrap_or("unknown")
-        .to_string();
+struct BodyVisitor<'a> {
+    provider: &'a str,
+    format: &'a RequestFormat,
+    max_kept_string: usize,
+    encoding: Encoding,
+}
 
-    let mut messages = Vec::new();
-    // Pre-allocate for typical request sizes
-    let mut all_text = String::with_capacity(4096);
+impl<'de> Visitor<'de> for BodyVisitor<'_> {
+    type Value = (Value, Reading);
 
-    if let Some(Value::Array(msgs)) = body.get("messages") {
-        for msg in msgs {
-            let role = msg
-                .get("role")
-                .and_then(|v| v.as_str())
-                .unwrap_or("unknown")
-                .to_string();
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a request object")
+    }
 
-            let content = if let Some(content_val) = msg.get("content") {
-                extract_text_from_value(content_val)
+    fn visit_map<A: MapAccess<'de>>(
+        self,
+        mut map: A,
+    ) -> std::result::Result<Self::Value, A::Error> {
+        let mut body = serde_json::Map::new();
+        let mut reading = Reading {
+            encoding: self.encoding,
+            ..Reading::default()
+        };
+        while let Some(key) = map.next_key::<String>()? {
+            if key == self.format.messages {
+                // A repeated key replaces the earlier one, as when parsed whole
+                reading.messages.clear();
+                reading.tool_results = 0;
+                let kept = map.next_value_seed(MessagesSeed {
+                    body: &self,
+                    reading: &mut reading,
+                })?;
+                body.insert(key, Value::Array(kept));
             } else {
-                String::new()
-            };
-
-            if !content.is_empty() {
-                all_text.push_str(&content);
-                all_text.push('\n');
+                let value: Value = map.next_value()?;
+                reading.read_field(self.format, &key, &value);
+                body.insert(key, shorten(value, self.max_kept_string));
             }
-
-            messages.push(Message { role, content });
         }
+        Ok((Value::Object(body), reading))
     }
+}
 
-    Ok((model, messages, all_text))
+/// The messages array of a streamed body, read one element at a time
+struct MessagesSeed<'a, 'b> {
+    body: &'a BodyVisitor<'b>,
+    reading: &'a mut Reading,
 }
 
-/// Parse Google Gemini API request
-fn parse_gemini_request(body: &Value) -> Result<(String, Vec<Message>, String)> {
-    // Gemini model is typically in the URL path, not the body
-    let model = body
-        .get("model")
-        .and_then(|v| v.as_str())
-        .unwrap_or("gemini")
-        .to_string();
+impl<'de> DeserializeSeed<'de> for MessagesSeed<'_, '_> {
+    type Value = Vec<Value>;
 
-    let mut messages = Vec::new();
-    // Pre-allocate for typical request sizes
-    let mut all_text = String::with_capacity(4096);
+    fn deserialize<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<Self::Value, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
 
-    // Handle systemInstruction
-    if let Some(system) = body.get("systemInstruction") {
-        let system_text = extract_text_from_value(system);
-        if !system_text.is_empty() {
-            messages.push(Message {
-                role: "system".to_string(),
-                content: system_text.clone(),
-            });
-            all_text.push_str(&system_text);
-            all_text.push('\n');
-        }
-    }
-
-    // Handle contents array
-    if let Some(Value::Array(contents)) = body.get("contents") {
-        for content in contents {
-            let role = content
-                .get("role")
-                .and_then(|v| v.as_str())
-                .unwrap_or("user")
-                .to_string();
-
-            // Gemini uses "parts" array
-            let text = if let Some(Value::Array(parts)) = content.get("parts") {
-                parts
-                    .iter()
-                    .filter_map(|part| part.get("text").and_then(|v| v.as_str()))
-                    .collect::<Vec<_>>()
-                    .join("\n")
-            } else {
-                extract_text_from_value(content)
-            };
+impl<'de> Visitor<'de> for MessagesSeed<'_, '_> {
+    type Value = Vec<Value>;
 
-            if !text.is_empty() {
-                all_text.push_str(&text);
-                all_text.push('\n');
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an array of messages")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(
+        self,
+        mut seq: A,
+    ) -> std::result::Result<Self::Value, A::Error> {
+        let mut kept = Vec::new();
+        while let Some(msg) = seq.next_element::<Value>()? {
+            self.reading
+                .read_message(self.body.provider, self.body.format, &msg);
+            kept.push(shorten(msg, self.body.max_kept_string));
+        }
+        Ok(kept)
+    }
+}
+
+/// `value` with each string longer than `max` bytes cut to its first `max`
+/// bytes and a note of how much was dropped
+fn shorten(value: Value, max: usize) -> Value {
+    match value {
+        Value::String(s) if s.len() > max => {
+            let mut end = max;
+            while !s.is_char_boundary(end) {
+                end -= 1;
             }
+            let note = format!("… [{} bytes cut]", s.len() - end);
+            let mut cut = String::with_capacity(end + note.len());
+            cut.push_str(&s[..end]);
+            cut.push_str(&note);
+            Value::String(cut)
+        }
+        Value::Array(items) => Value::Array(items.into_iter().map(|v| shorten(v, max)).collect()),
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .map(|(key, v)| (key, shorten(v, max)))
+                .collect(),
+        ),
+        other => other,
+    }
+}
 
-            messages.push(Message {
-                role,
-                content: text,
-            });
+/// A message from an Anthropic request, followed by a `tool_use` message
+/// reading `name(arguments)` for each tool call in it
+fn anthropic_messages(msg: &Value) -> Vec<Message> {
+    let Some(blocks) = msg.get("content").and_then(Value::as_array) else {
+        return vec![chat_message(msg, ANTHROPIC_CONTENT_TYPES)];
+    };
+    let is_call = |block: &&Value| block.get("type").and_then(Value::as_str) == Some("tool_use");
+    let content = blocks
+        .iter()
+        .filter(|block| !is_call(block))
+        .map(anthropic_block_text)
+        .filter(|text| !text.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+    let message = Message {
+        role: message_role(msg),
+        content,
+        unknown_parts: unknown_parts(&msg["content"], ANTHROPIC_CONTENT_TYPES),
+    };
+    let calls = blocks.iter().filter(is_call).map(|block| {
+        let name = block.get("name").and_then(Value::as_str);
+        let input = block.get("input").map(Value::to_string);
+        Message {
+            role: TOOL_USE_ROLE.to_string(),
+            content: format!(
+                "{}({})",
+                name.unwrap_or("unknown"),
+                input.as_deref().unwrap_or("{}")
+            ),
+            unknown_parts: vec![],
         }
+    });
+    std::iter::once(message).chain(calls).collect()
+}
+
+/// Text of an Anthropic content block other than a tool call. A tool
+/// result reads as its output alone, which is a string or text blocks.
+fn anthropic_block_text(block: &Value) -> String {
+    match block.get("type").and_then(Value::as_str) {
+        Some("tool_result") => block
+            .get("content")
+            .map(extract_text_from_value)
+            .unwrap_or_default(),
+        _ => extract_text_from_value(block),
     }
+}
 
-    Ok((model, messages, all_text))
+fn openai_message(msg: &Value) -> Message {
+    chat_message(msg, OPENAI_CONTENT_TYPES)
+}
+
+/// An item of an OpenAI Responses API request's `input`: a message, a tool
+/// call or its output, or the whole input when it's a single string
+fn openai_responses_item(item: &Value) -> Message {
+    let text = |key: &str| {
+        item.get(key)
+            .map(extract_text_from_value)
+            .unwrap_or_default()
+    };
+    let (role, content) = match item.get("type").and_then(Value::as_str) {
+        _ if item.is_string() => ("user".to_string(), extract_text_from_value(item)),
+        Some("function_call" | "custom_tool_call") => {
+            let name = item.get("name").and_then(Value::as_str);
+            // Arguments of a function call, free-form input of a custom tool
+            let input = item.get("arguments").or_else(|| item.get("input"));
+            (
+                TOOL_USE_ROLE.to_string(),
+                format!(
+                    "{}({})",
+                    name.unwrap_or("unknown"),
+                    input.and_then(Value::as_str).unwrap_or("{}")
+                ),
+            )
+        }
+        Some("function_call_output" | "custom_tool_call_output") => {
+            ("tool".to_string(), text("output"))
+        }
+        Some("reasoning") => ("reasoning".to_string(), text("summary")),
+        _ => return chat_message(item, OPENAI_RESPONSES_CONTENT_TYPES),
+    };
+    Message {
+        role,
+        content,
+        unknown_parts: vec![],
+    }
+}
+
+/// A message from an Anthropic or OpenAI chat request, whose content is a
+/// string or a list of typed blocks
+fn chat_message(msg: &Value, known_types: &[&str]) -> Message {
+    let role = message_role(msg);
+    let (content, unknown_parts) = if let Some(content_val) = msg.get("content") {
+        (
+            extract_text_from_value(content_val),
+            unknown_parts(content_val, known_types),
+        )
+    } else {
+        (String::new(), vec![])
+    };
+    Message {
+        role,
+        content,
+        unknown_parts,
+    }
+}
+
+fn message_role(msg: &Value) -> String {
+    msg.get("role")
+        .and_then(|v| v.as_str())
+        .unwrap_or("unknown")
+        .to_string()
+}
+
+/// A message from an Ollama chat request, whose content is a string,
+/// followed by the tool calls it makes
+fn ollama_messages(msg: &Value) -> Vec<Message> {
+    let message = Message {
+        role: message_role(msg),
+        content: msg
+            .get("content")
+            .map(extract_text_from_value)
+            .unwrap_or_default(),
+        unknown_parts: vec![],
+    };
+    let calls = msg
+        .get("tool_calls")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .map(|call| {
+            let function = call.get("function");
+            let name = function.and_then(|f| f.get("name")).and_then(Value::as_str);
+            // Ollama sends the arguments as an object, not a JSON string
+            let arguments = function
+                .and_then(|f| f.get("arguments"))
+                .map(Value::to_string);
+            Message {
+                role: TOOL_USE_ROLE.to_string(),
+                content: format!(
+                    "{}({})",
+                    name.unwrap_or("unknown"),
+                    arguments.as_deref().unwrap_or("{}")
+                ),
+                unknown_parts: vec![],
+            }
+        });
+    std::iter::once(message).chain(calls).collect()
+}
+
+/// An entry of a Gemini request's `contents`
+fn gemini_message(content: &Value) -> Message {
+    let role = content
+        .get("role")
+        .and_then(|v| v.as_str())
+        .unwrap_or("user")
+        .to_string();
+
+    // Gemini uses "parts" array
+    let text = if let Some(Value::Array(parts)) = content.get("parts") {
+        parts
+            .iter()
+            .filter_map(|part| part.get("text").and_then(|v| v.as_str()))
+            .collect::<Vec<_>>()
+            .join("\n")
+    } else {
+        extract_text_from_value(content)
+    };
+    Message {
+        role,
+        content: text,
+        unknown_parts: vec![],
+    }
 }
 
 /// Recursively extract all text from a JSON value
@@ -228,14 +1194,81 @@ pub fn extract_text_from_value(value: &Value) -> String {
     }
 }
 
-/// Detect provider from request path
+/// Detect provider from request path.
+/// When several providers match, the one with the longest matching pattern
+/// wins; among equally specific ones, those that are another provider's
+/// fallback lose, and remaining ties go to the first name alphabetically
 pub fn detect_provider(path: &str, providers: &std::collections::HashMap<String, crate::config::ProviderConfig>) -> Option<String> {
-    for (name, config) in providers {
-        if path.contains(&config.path_pattern) {
-            return Some(name.clone());
+    let is_fallback = |name: &str| {
+        providers
+            .values()
+            .any(|p| p.fallbacks.iter().any(|f| f == name))
+    };
+    providers
+        .iter()
+        .filter_map(|(name, config)| Some((name, config.path_match(path)?)))
+        .min_by_key(|(name, len)| (std::cmp::Reverse(*len), is_fallback(name), name.as_str()))
+        .map(|(name, _)| name.clone())
+}
+
+/// Names of the tools a request body defines, in the order they're sent
+pub fn tool_definition_names(body: &Value) -> Vec<&str> {
+    let defined = |key: &str| {
+        body.get(key)
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+    };
+    let tools = defined("tools").flat_map(|tool| {
+        match tool.get("functionDeclarations").and_then(Value::as_array) {
+            // Gemini groups its function declarations in one tool
+            Some(declarations) => declarations.iter().collect(),
+            // OpenAI wraps each in a `function`
+            None => vec![tool.get("function").unwrap_or(tool)],
         }
+    });
+    tools
+        .chain(defined("functions"))
+        .filter_map(|tool| tool.get("name").and_then(Value::as_str))
+        .collect()
+}
+
+/// Content block types only Anthropic's Messages API uses
+const ANTHROPIC_BLOCK_TYPES: [&str; 5] =
+    ["tool_use", "tool_result", "thinking", "redacted_thinking", "document"];
+
+/// Recognise a provider request format from the body alone, for paths no
+/// provider pattern matches. `None` if it fits no format, or several.
+pub fn detect_body_format(body: &Value) -> Option<&'static str> {
+    match body_formats(body)[..] {
+        [format] => Some(format),
+        _ => None,
     }
-    None
+}
+
+/// Every format `body` fits. Anthropic bodies also fit the looser OpenAI
+/// shape, so the OpenAI match is dropped in their favour.
+fn body_formats(body: &Value) -> Vec<&'static str> {
+    let mut formats = Vec::new();
+    if body.get("contents").is_some() || body.get("systemInstruction").is_some() {
+        formats.push("gemini");
+    }
+
+    let Some(messages) = body.get("messages").and_then(Value::as_array) else {
+        return formats;
+    };
+    let anthropic_blocks = messages
+        .iter()
+        .filter_map(|m| m.get("content").and_then(Value::as_array))
+        .flatten()
+        .filter_map(|block| block.get("type").and_then(Value::as_str))
+        .any(|kind| ANTHROPIC_BLOCK_TYPES.contains(&kind));
+    if body.get("system").is_some() || anthropic_blocks {
+        formats.push("anthropic");
+    } else if body.get("model").is_some() {
+        formats.push("openai");
+    }
+    formats
 }
 
 #[cfg(test)]
@@ -246,6 +1279,63 @@ mod tests {
     fn test_count_tokens() {
         let count = count_tokens("Hello, world!");
         assert!(count > 0);
+
+        // Long text is counted in chunks, and lines apart, to the same total
+        let lines = [
+            "fn main() {",
+            "    let x = 1;\n",
+            "} \n ",
+            "\t// café ☕\r",
+            "\n!?",
+            "",
+            "see ./",
+            "/usr/bin",
+        ];
+        let lines: Vec<&str> = lines.iter().cycle().take(40_000).copied().collect();
+        let text = lines.join("\n") + "\n";
+        assert!(chunk_len(&text).is_some());
+        for encoding in [Encoding::Cl100k, Encoding::O200k] {
+            let whole = encoding.bpe().encode_ordinary(&text).len();
+            assert_eq!(encoding.count(&text), whole);
+            assert_eq!(encoding.count_lines(lines.iter().copied()), whole);
+        }
+    }
+
+    #[test]
+    fn test_encoding_per_model() {
+        assert_eq!(Encoding::for_model("gpt-4o-2024-08-06"), Encoding::O200k);
+        assert_eq!(Encoding::for_model("openai/o3-mini"), Encoding::O200k);
+        assert_eq!(Encoding::for_model("gpt-4-turbo"), Encoding::Cl100k);
+        assert_eq!(Encoding::for_model("claude-sonnet-4-5"), Encoding::Cl100k);
+        assert_eq!(Encoding::for_model("unknown"), Encoding::Cl100k);
+
+        let text = "Die Katze schläft auf dem Sofa, und der Hund bellt draußen im Garten.";
+        let (cl100k, o200k) = (Encoding::Cl100k.count(text), Encoding::O200k.count(text));
+        assert_ne!(cl100k, o200k);
+        let body = |model: &str| {
+            let body = serde_json::json!({
+                "model": model,
+                "messages": [{"role": "user", "content": text}],
+            });
+            body.to_string()
+        };
+        let whole = ParseOptions::WHOLE;
+        let tokens = |body: String, options: &ParseOptions| {
+            let path = "/v1/chat/completions";
+            parse_request_with(body.as_bytes(), path, "openai", options)
+                .unwrap()
+                .tokens
+        };
+        // One line of text, plus its newline
+        let newline = |encoding: Encoding| encoding.count(&format!("{}\n", text));
+        assert_eq!(tokens(body("gpt-4o"), &whole), newline(Encoding::O200k));
+        assert_eq!(tokens(body("mystery"), &whole), newline(Encoding::Cl100k));
+        // A provider's configured tokenizer wins over the model's
+        let forced = ParseOptions {
+            encoding: Some(Encoding::Cl100k),
+            ..ParseOptions::WHOLE
+        };
+        assert_eq!(tokens(body("gpt-4o"), &forced), newline(Encoding::Cl100k));
     }
 
     #[test]
@@ -271,11 +1361,12 @@ mod tests {
             ]
         });
 
-        let (model, messages, _) = parse_anthropic_request(&body).unwrap();
-        assert_eq!(model, "claude-3-5-sonnet-20250514");
-        assert_eq!(messages.len(), 2); // system + user
-        assert_eq!(messages[0].role, "system");
-        assert_eq!(messages[1].role, "user");
+        let body = serde_json::to_vec(&body).unwrap();
+        let event = parse_request(&body, "/v1/messages", "anthropic").unwrap();
+        assert_eq!(event.model, "claude-3-5-sonnet-20250514");
+        assert_eq!(event.messages.len(), 2); // system + user
+        assert_eq!(event.messages[0].role, "system");
+        assert_eq!(event.messages[1].role, "user");
     }
 
     #[test]
@@ -288,8 +1379,583 @@ mod tests {
             ]
         });
 
-        let (model, messages, _) = parse_openai_request(&body).unwrap();
-        assert_eq!(model, "gpt-4");
-        assert_eq!(messages.len(), 2);
+        let body = serde_json::to_vec(&body).unwrap();
+        let event = parse_request(&body, "/v1/chat/completions", "openai").unwrap();
+        assert_eq!(event.model, "gpt-4");
+        assert_eq!(event.messages.len(), 2);
+    }
+
+    #[test]
+    fn test_gemini_model_from_path() {
+        let body = br#"{"contents": [{"role": "user", "parts": [{"text": "hi"}]}]}"#;
+        let cases = [
+            ("/v1beta/models/gemini-1.5-pro:generateContent", "gemini-1.5-pro"),
+            ("/v1/models/gemini-2.0-flash:streamGenerateContent?alt=sse", "gemini-2.0-flash"),
+            ("/v1beta/models/gemini-2.5-pro%3AgenerateContent", "gemini-2.5-pro"),
+            ("/v1beta/models/tuned%2Dmodel%2B1:generateContent?key=a:b", "tuned-model+1"),
+            ("/v1beta/models/gemini-1.5-pro:generateContent%zz", "gemini-1.5-pro"),
+            // Nothing in the path, so the default
+            ("/v1beta/generateContent", "gemini"),
+        ];
+        for (path, expected) in cases {
+            let event = parse_request(body, path, "gemini").unwrap();
+            assert_eq!(event.model, expected, "{}", path);
+        }
+
+        // The body's model only when the path has none
+        let named = br#"{"model": "gemini-pro", "contents": []}"#;
+        let event = parse_request(named, "/gateway/generateContent", "gemini").unwrap();
+        assert_eq!(event.model, "gemini-pro");
+        let path = "/v1/models/gemini-2.0-flash:generateContent";
+        let event = parse_request(named, path, "gemini").unwrap();
+        assert_eq!(event.model, "gemini-2.0-flash");
+        // Unparsable bodies still show the model
+        let event = minimal_event(b"{", path, "gemini");
+        assert_eq!(event.model, "gemini-2.0-flash");
+    }
+
+    #[test]
+    fn test_azure_deployment_from_path() {
+        let providers = crate::config::Config::default().providers;
+        let path = "/openai/deployments/gpt4o-prod/chat/completions?api-version=2024-10-21";
+        assert_eq!(detect_provider(path, &providers).as_deref(), Some("azure"));
+        // Plain OpenAI paths stay with OpenAI
+        for path in ["/v1/chat/completions", "/v1/responses"] {
+            assert_eq!(detect_provider(path, &providers).as_deref(), Some("openai"));
+        }
+
+        // Azure bodies usually leave the model out
+        let body = br#"{"messages": [{"role": "user", "content": "hi"}]}"#;
+        let event = parse_request(body, path, "openai").unwrap();
+        assert_eq!(event.model, "gpt4o-prod");
+        assert_eq!(event.tokens, count_tokens("hi\n"));
+
+        // Mapped to the model it deploys, which also picks the tokenizer
+        let options = ParseOptions {
+            deployment_models: BTreeMap::from([("gpt4o-prod".to_string(), "gpt-4o".to_string())]),
+            ..ParseOptions::WHOLE
+        };
+        let event = parse_request_with(body, path, "openai", &options).unwrap();
+        assert_eq!(event.model, "gpt-4o");
+        let path = "/openai/deployments/gpt4o%2Dprod/chat/completions";
+        let event = parse_request_with(body, path, "openai", &options).unwrap();
+        assert_eq!(event.model, "gpt-4o");
+
+        // Unparsable bodies still show the deployment
+        assert_eq!(minimal_event(b"{", path, "azure").model, "gpt4o-prod");
+    }
+
+    #[test]
+    fn test_parse_ollama_request() {
+        let providers = crate::config::Config::default().providers;
+        for path in ["/api/chat", "/api/generate"] {
+            assert_eq!(detect_provider(path, &providers).as_deref(), Some("ollama"));
+        }
+
+        let body = serde_json::json!({
+            "model": "llama3.1:8b",
+            "messages": [
+                {"role": "system", "content": "You are terse."},
+                {"role": "user", "content": "Weather in Paris?"},
+                {"role": "assistant", "content": "", "tool_calls": [
+                    {"function": {"name": "get_weather", "arguments": {"city": "Paris"}}}
+                ]},
+                {"role": "tool", "content": "22C and sunny"}
+            ],
+            "options": {"temperature": 0}
+        });
+        let body = serde_json::to_vec(&body).unwrap();
+        let event = parse_request(&body, "/api/chat", "ollama").unwrap();
+        assert_eq!(event.model, "llama3.1:8b");
+        let roles: Vec<_> = event.messages.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(
+            roles,
+            ["system", "user", "assistant", TOOL_USE_ROLE, "tool"]
+        );
+        assert_eq!(
+            event.messages[3].content,
+            r#"get_weather({"city":"Paris"})"#
+        );
+        assert!(event.composition.unwrap().tool_results > 0);
+        // Ollama streams unless asked not to
+        assert!(event.streaming);
+        assert!(schema_drift(&event).is_empty());
+
+        let body = br#"{"model": "llama3.1", "system": "Be brief.", "prompt": "Why is the sky blue?", "stream": false, "raw": true}"#;
+        let event = parse_request(body, "/api/generate", "ollama").unwrap();
+        let roles: Vec<_> = event.messages.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, ["system", "user"]);
+        assert_eq!(event.messages[1].content, "Why is the sky blue?");
+        assert!(event.tokens > 0);
+        assert!(!event.streaming);
+        assert!(schema_drift(&event).is_empty());
+    }
+
+    #[test]
+    fn test_detect_provider_prefers_longest_pattern() {
+        let mut providers = crate::config::Config::default().providers;
+        let anthropic = providers["anthropic"].clone();
+        let gateway = |patterns: &[&str]| crate::config::ProviderConfig {
+            path_patterns: patterns.iter().map(|p| p.to_string()).collect(),
+            ..anthropic.clone()
+        };
+        providers.insert("gateway".to_string(), gateway(&["/v1"]));
+        providers.insert(
+            "counter".to_string(),
+            gateway(&["/v1/messages/count_tokens"]),
+        );
+        let detect = |path: &str| detect_provider(path, &providers);
+
+        assert_eq!(detect("/v1/messages").as_deref(), Some("anthropic"));
+        assert_eq!(
+            detect("/v1/messages/count_tokens").as_deref(),
+            Some("counter")
+        );
+        assert_eq!(detect("/v1/chat/completions").as_deref(), Some("openai"));
+        // Only the catch-all matches these
+        assert_eq!(detect("/v1/models").as_deref(), Some("gateway"));
+        assert_eq!(detect("/v1/embeddings").as_deref(), Some("gateway"));
+        assert_eq!(detect("/healthz"), None);
+    }
+
+    #[test]
+    fn test_parse_openai_responses_request() {
+        let providers = crate::config::Config::default().providers;
+        assert_eq!(
+            detect_provider("/v1/responses", &providers).as_deref(),
+            Some("openai")
+        );
+
+        // A plain string input is one user turn
+        let body = serde_json::json!({
+            "model": "gpt-5",
+            "instructions": "You are a coding agent.",
+            "input": "List the files"
+        });
+        let body = serde_json::to_vec(&body).unwrap();
+        let event = parse_request(&body, "/v1/responses", "openai").unwrap();
+        assert_eq!(event.model, "gpt-5");
+        let roles: Vec<_> = event.messages.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, ["system", "user"]);
+        assert_eq!(event.messages[0].content, "You are a coding agent.");
+        assert_eq!(event.messages[1].content, "List the files");
+        assert!(schema_drift(&event).is_empty());
+
+        // Structured input: messages with content parts, and a tool round trip
+        let body = serde_json::json!({
+            "model": "gpt-5",
+            "instructions": "You are a coding agent.",
+            "input": [
+                {"role": "developer", "content": [{"type": "input_text", "text": "Be brief."}]},
+                {"type": "message", "role": "user", "content": [
+                    {"type": "input_text", "text": "What is in src?"}
+                ]},
+                {"type": "reasoning", "summary": [{"type": "summary_text", "text": "Look first."}]},
+                {"type": "function_call", "name": "shell", "arguments": "{\"command\":[\"ls\"]}", "call_id": "c1"},
+                {"type": "function_call_output", "call_id": "c1", "output": "main.rs"},
+                {"role": "assistant", "content": [{"type": "output_text", "text": "Just main.rs."}]}
+            ],
+            "tools": [{"type": "function", "name": "shell", "parameters": {}}],
+            "store": false
+        });
+        let body = serde_json::to_vec(&body).unwrap();
+        let event = parse_request(&body, "/v1/responses?beta=true", "openai").unwrap();
+        let roles: Vec<_> = event.messages.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(
+            roles.join(" "),
+            "system developer user reasoning tool_use tool assistant"
+        );
+        assert_eq!(event.messages[2].content, "What is in src?");
+        assert_eq!(event.messages[3].content, "Look first.");
+        assert_eq!(event.messages[4].tool_name(), Some("shell"));
+        assert_eq!(event.messages[5].content, "main.rs");
+        assert!(event.messages.iter().all(|m| m.unknown_parts.is_empty()));
+        assert!(schema_drift(&event).is_empty());
+        let composition = event.composition.unwrap();
+        assert!(composition.system > 0 && composition.tools > 0 && composition.tool_results > 0);
+
+        // Stored responses are looked up, not generated
+        assert!(matches!(
+            parse_request(b"{}", "/v1/responses/resp_1/input_items", "openai"),
+            Err(ParseError::UnsupportedEndpoint(_))
+        ));
+        assert!(matches!(
+            parse_request(b"{\"model\": \"gpt-5\"}", "/v1/responses", "openai"),
+            Err(ParseError::MissingMessages("input"))
+        ));
+    }
+
+    #[test]
+    fn test_anthropic_tool_blocks() {
+        let body = serde_json::to_vec(&fixture("claude_code_tool_loop.json")).unwrap();
+        let event = parse_request(&body, "/v1/messages", "anthropic").unwrap();
+        let messages: Vec<(&str, &str)> = event
+            .messages
+            .iter()
+            .map(|m| (m.role.as_str(), m.content.as_str()))
+            .collect();
+        let system = "You are Claude Code, Anthropic's official CLI for Claude.\n\
+            You are an interactive CLI tool that helps users with software engineering tasks.";
+        let reminder = "<system-reminder>\nAs you answer the user's questions, you can use \
+            the following context.\n</system-reminder>";
+        assert_eq!(
+            messages,
+            [
+                ("system", system),
+                ("user", &format!("{}\nthe parser test is failing, can you fix it?", reminder)),
+                ("assistant", "I'll run the tests and read the parser."),
+                (
+                    "tool_use",
+                    r#"Bash({"command":"cargo test parser","description":"Run parser tests"})"#
+                ),
+                ("tool_use", r#"Read({"file_path":"/repo/src/parser.rs"})"#),
+                (
+                    "user",
+                    "test parser::tests::test_wrap ... FAILED\n     \
+                     1\tfn wrap(text: &str) -> String {\n     2\t    text[..40].to_string()\n     3\t}"
+                ),
+                ("assistant", ""),
+                ("tool_use", r#"Bash({"command":"cargo test parser -- --nocapture"})"#),
+                ("user", "thread panicked: byte index 40 is not a char boundary"),
+            ]
+        );
+
+        // Tool arguments take up context like any other text
+        let texts: String = messages
+            .iter()
+            .filter(|(_, content)| !content.is_empty())
+            .map(|(_, content)| format!("{}\n", content))
+            .collect();
+        let tools = event.tool_definition_tokens() as usize;
+        assert_eq!(event.tokens, count_tokens(&texts) + tools);
+
+        let info = crate::event::RequestInfo::from(&event);
+        assert_eq!(info.detail.unwrap().tool_names(), ["Bash", "Read"]);
+    }
+
+    #[test]
+    fn test_streaming_flag() {
+        let chat = |stream: Value| {
+            let body = serde_json::json!({
+                "model": "claude-sonnet-4-5",
+                "stream": stream,
+                "messages": [{"role": "user", "content": "hi"}]
+            });
+            serde_json::to_vec(&body).unwrap()
+        };
+        let gemini = br#"{"contents": [{"role": "user", "parts": [{"text": "hi"}]}]}"#.to_vec();
+        let cases = [
+            (chat(true.into()), "/v1/messages", "anthropic", true),
+            (chat(false.into()), "/v1/messages", "anthropic", false),
+            (chat(Value::Null), "/v1/chat/completions", "openai", false),
+            (chat(true.into()), "/v1/chat/completions", "openai", true),
+            (gemini.clone(), "/v1beta/models/g:streamGenerateContent?alt=sse", "gemini", true),
+            (gemini, "/v1beta/models/g:generateContent?key=x", "gemini", false),
+        ];
+        for (body, path, provider, expected) in cases {
+            let event = parse_request(&body, path, provider).unwrap();
+            assert_eq!(event.streaming, expected, "{} {}", provider, path);
+        }
+    }
+
+    #[test]
+    fn test_tool_definition_names() {
+        let cases = [
+            (fixture("claude_code_tool_loop.json"), vec!["Bash", "Read"]),
+            (
+                serde_json::json!({
+                    "tools": [{"type": "function", "function": {"name": "search"}}],
+                    "functions": [{"name": "get_weather"}]
+                }),
+                vec!["search", "get_weather"],
+            ),
+            (
+                serde_json::json!({"tools": [
+                    {"functionDeclarations": [{"name": "lookup"}, {"name": "book"}]},
+                    {"googleSearch": {}}
+                ]}),
+                vec!["lookup", "book"],
+            ),
+            (serde_json::json!({"messages": []}), vec![]),
+        ];
+        for (body, expected) in cases {
+            assert_eq!(tool_definition_names(&body), expected, "{}", body);
+        }
+    }
+
+    #[test]
+    fn test_token_composition() {
+        let body = serde_json::json!({
+            "model": "claude-3",
+            "system": "You are a careful reviewer of Rust code.",
+            "tools": [{"name": "bash", "input_schema": {"type": "object"}}],
+            "messages": [
+                {"role": "user", "content": "List the files"},
+                {"role": "assistant", "content": [
+                    {"type": "tool_use", "id": "t1", "name": "bash", "input": {"cmd": "ls"}}
+                ]},
+                {"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "t1", "content": "main.rs lib.rs"}
+                ]}
+            ]
+        });
+        let body = serde_json::to_vec(&body).unwrap();
+        let event = parse_request(&body, "/v1/messages", "anthropic").unwrap();
+        let composition = event.composition();
+        let system = count_tokens("You are a careful reviewer of Rust code.");
+        assert_eq!(composition.system, system as u64);
+        assert_eq!(composition.tool_results, count_tokens("main.rs lib.rs") as u64);
+        let tools = r#"[{"input_schema":{"type":"object"},"name":"bash"}]"#;
+        assert_eq!(composition.tools, count_tokens(tools) as u64);
+        assert_eq!(composition.total(), event.tokens as u64);
+
+        // As are OpenAI's legacy function definitions
+        let body = serde_json::json!({
+            "model": "gpt-4",
+            "messages": [{"role": "user", "content": "What's the weather?"}],
+            "functions": [{"name": "get_weather", "parameters": {"type": "object"}}]
+        });
+        let body = serde_json::to_vec(&body).unwrap();
+        let event = parse_request(&body, "/v1/chat/completions", "openai").unwrap();
+        let functions = r#"[{"name":"get_weather","parameters":{"type":"object"}}]"#;
+        let functions = count_tokens(functions);
+        assert_eq!(event.tool_definition_tokens(), functions as u64);
+        let messages = count_tokens("What's the weather?\n");
+        assert_eq!(event.tokens, messages + functions);
+        assert!(schema_drift(&event).fields.is_empty());
+
+        // Unparsed bodies are all conversation
+        let event = minimal_event(b"not json", "/v1/messages", "anthropic");
+        assert_eq!(event.composition().conversation, event.tokens as u64);
+    }
+
+    #[test]
+    fn test_parse_error_variants() {
+        let oversized = vec![b' '; MAX_PARSE_BODY_BYTES + 1];
+        let cases: [(&[u8], &str, &str, &str); 7] = [
+            (b"\xff\xfe{}", "/v1/messages", "anthropic", "InvalidUtf8"),
+            (b"model=claude", "/v1/messages", "anthropic", "NotJson"),
+            (br#"{"model":"claude-3"}"#, "/v1/messages", "anthropic", "MissingMessages"),
+            (br#"{"model":"gpt-4"}"#, "/v1/chat/completions", "openai", "MissingMessages"),
+            (br#"{"generationConfig":{}}"#, "/v1beta/models/x:generateContent", "gemini", "MissingMessages"),
+            (br#"{"messages":[]}"#, "/v1/messages/count_tokens", "anthropic", "UnsupportedEndpoint"),
+            (&oversized, "/v1/messages", "anthropic", "OversizedBody"),
+        ];
+
+        for (body, path, provider, expected) in cases {
+            let err = parse_request(body, path, provider).unwrap_err();
+            assert_eq!(err.kind(), expected, "{} {}", provider, path);
+        }
+    }
+
+    fn fixture(name: &str) -> Value {
+        let path = format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+        serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_streamed_parse_matches_whole() {
+        let mut cases: Vec<(String, String, Vec<u8>)> = fixture("parse_requests.json")
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|case| {
+                let field = |name: &str| case[name].as_str().unwrap().to_string();
+                let body = serde_json::to_vec(&case["body"]).unwrap();
+                (field("provider"), field("path"), body)
+            })
+            .collect();
+        let anthropic = |body: &Value| {
+            let body = serde_json::to_vec(body).unwrap();
+            ("anthropic".to_string(), "/v1/messages".to_string(), body)
+        };
+        cases.push(anthropic(&fixture("conversation_anthropic.json")));
+        cases.push(anthropic(&fixture("claude_code_tool_loop.json")));
+        for case in fixture("language_corpus.json").as_array().unwrap() {
+            cases.push(anthropic(&case["body"]));
+        }
+        cases.push(anthropic(&serde_json::json!("not an object")));
+        let truncated = br#"{"model": "claude-sonnet-4-5", "messages": [{"role": "user""#;
+        cases.push(("anthropic".into(), "/v1/messages".into(), truncated.to_vec()));
+
+        let whole = ParseOptions::WHOLE;
+        let streamed = ParseOptions {
+            streaming_threshold: Some(0),
+            ..ParseOptions::WHOLE
+        };
+        for (provider, path, body) in &cases {
+            let label = String::from_utf8_lossy(body);
+            let parse = |options| parse_request_with(body, path, provider, options);
+            let (expected, actual) = match (parse(&whole), parse(&streamed)) {
+                (Ok(expected), Ok(actual)) => (expected, actual),
+                (Err(expected), Err(actual)) => {
+                    assert_eq!(expected.kind(), actual.kind(), "{}", label);
+                    continue;
+                }
+                (expected, actual) => panic!("{:?} != {:?} for {}", expected, actual, label),
+            };
+            assert_eq!(actual.model, expected.model, "{}", label);
+            assert_eq!(actual.tokens, expected.tokens, "{}", label);
+            assert_eq!(actual.composition, expected.composition, "{}", label);
+            assert_eq!(schema_drift(&actual), schema_drift(&expected), "{}", label);
+            let messages = |event: &RequestEvent| serde_json::to_value(&event.messages).unwrap();
+            assert_eq!(messages(&actual), messages(&expected), "{}", label);
+            assert_eq!(actual.raw_body, expected.raw_body, "{}", label);
+        }
+
+        // Long strings are cut in the kept body, but counted in full
+        let (provider, path, body) = &cases[0];
+        let options = ParseOptions {
+            streaming_threshold: Some(0),
+            max_kept_string: 24,
+            ..ParseOptions::WHOLE
+        };
+        let expected = parse_request(body, path, provider).unwrap();
+        let actual = parse_request_with(body, path, provider, &options).unwrap();
+        assert_eq!(actual.tokens, expected.tokens);
+        assert_eq!(
+            actual.raw_body["messages"][3]["content"],
+            "The slice cuts a multi-b… [51 bytes cut]"
+        );
+        assert_eq!(actual.raw_body["max_tokens"], 4096);
+        assert!(actual.messages[5].content.ends_with("café ☕ needs a boundary check."));
+    }
+
+    #[test]
+    fn test_streamed_parse_peak_memory() {
+        // About 10MB of files read back and forth, as a long session builds up
+        let file =
+            "    let total = items.iter().map(|item| item.price).sum::<f64>();\n".repeat(640);
+        let messages: Vec<Value> = ["user", "assistant"]
+            .iter()
+            .cycle()
+            .take(250)
+            .map(|role| serde_json::json!({"role": role, "content": file}))
+            .collect();
+        let body = serde_json::json!({"model": "claude-sonnet-4-5", "messages": messages});
+        let body = serde_json::to_vec(&body).unwrap();
+        assert!(body.len() > 10_000_000);
+
+        let parse = |threshold| {
+            let options = ParseOptions {
+                streaming_threshold: threshold,
+                ..ParseOptions::default()
+            };
+            crate::alloc_stats::peak_bytes(|| {
+                parse_request_with(&body, "/v1/messages", "anthropic", &options).unwrap()
+            })
+        };
+        // The tokenizer's tables load on first use
+        count_tokens("");
+        let whole = parse(None);
+        let streamed = parse(Some(1024));
+        assert!(
+            streamed < whole * 2 / 3,
+            "streamed peak {} bytes, whole {}",
+            streamed,
+            whole
+        );
+    }
+
+    #[test]
+    fn test_minimal_event_for_non_json() {
+        let event = minimal_event(b"hello there", "/v1/messages", "anthropic");
+        assert_eq!(event.model, "unknown");
+        assert!(event.tokens > 0);
+        assert!(event.raw_body.is_null());
+    }
+
+    #[test]
+    fn test_unknown_content_block_preserved() {
+        let body = br#"{
+            "model": "claude-sonnet-5",
+            "future_option": {"enabled": true},
+            "messages": [{
+                "role": "assistant",
+                "content": [
+                    {"type": "text", "text": "Let me check"},
+                    {"type": "quantum_tool_use", "id": "qt_1", "input": {"qubits": 3}}
+                ]
+            }]
+        }"#;
+        let event = parse_request(body, "/v1/messages", "anthropic").unwrap();
+
+        let parts = &event.messages[0].unknown_parts;
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].kind, "quantum_tool_use");
+        assert_eq!(parts[0].json["input"]["qubits"], 3);
+        assert_eq!(event.raw_body["messages"][0]["content"][1]["id"], "qt_1");
+
+        let drift = schema_drift(&event);
+        assert_eq!(drift.content_types, BTreeSet::from(["quantum_tool_use".to_string()]));
+        assert_eq!(drift.fields, BTreeSet::from(["future_option".to_string()]));
+
+        let known = br#"{"model":"claude-3","max_tokens":10,"messages":[{"role":"user","content":[{"type":"text","text":"hi"}]}]}"#;
+        let event = parse_request(known, "/v1/messages", "anthropic").unwrap();
+        assert!(schema_drift(&event).is_empty());
+    }
+
+    #[test]
+    fn test_detect_body_format() {
+        let detect = |body: Value| detect_body_format(&body);
+        assert_eq!(
+            detect(serde_json::json!({
+                "model": "gpt-4o",
+                "messages": [
+                    {"role": "system", "content": "Be brief"},
+                    {"role": "user", "content": "hi"}
+                ]
+            })),
+            Some("openai")
+        );
+        assert_eq!(
+            detect(serde_json::json!({
+                "model": "claude-sonnet-4-5",
+                "system": [{"type": "text", "text": "Be brief"}],
+                "messages": [{"role": "user", "content": [{"type": "text", "text": "hi"}]}]
+            })),
+            Some("anthropic")
+        );
+        // No system prompt, but tool blocks only Anthropic uses
+        assert_eq!(
+            detect(serde_json::json!({
+                "model": "claude-sonnet-4-5",
+                "messages": [{
+                    "role": "user",
+                    "content": [{"type": "tool_result", "tool_use_id": "t1"}]
+                }]
+            })),
+            Some("anthropic")
+        );
+        assert_eq!(
+            detect(serde_json::json!({
+                "systemInstruction": {"parts": [{"text": "Be brief"}]},
+                "contents": [{"role": "user", "parts": [{"text": "hi"}]}]
+            })),
+            Some("gemini")
+        );
+    }
+
+    #[test]
+    fn test_detect_body_format_ambiguous() {
+        // Fits both Gemini and OpenAI: refuse to guess
+        let mixed = serde_json::json!({
+            "model": "gpt-4o",
+            "messages": [{"role": "user", "content": "hi"}],
+            "contents": [{"role": "user", "parts": [{"text": "hi"}]}]
+        });
+        assert_eq!(body_formats(&mixed), ["gemini", "openai"]);
+        assert_eq!(detect_body_format(&mixed), None);
+
+        let both = serde_json::json!({
+            "system": "Be brief",
+            "messages": [{"role": "user", "content": "hi"}],
+            "systemInstruction": {"parts": [{"text": "Be brief"}]}
+        });
+        assert_eq!(detect_body_format(&both), None);
+
+        // Messages without a model or Anthropic markers fit nothing
+        assert_eq!(detect_body_format(&serde_json::json!({"messages": []})), None);
+        assert_eq!(detect_body_format(&serde_json::json!({"input": "hi"})), None);
     }
 }
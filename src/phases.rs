@@ -0,0 +1,424 @@
+//! Timing spans around each phase of a proxied request, for telling how
+//! much latency sherlock itself adds. Spans are at debug level, or info under
+//! `sherlock start --trace-requests`, which also installs `PhaseLayer` to
+//! total them up for a report on shutdown.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::field::{Field, Visit};
+use tracing::span::{Id, Record};
+use tracing::{Span, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::stats::{Histogram, Percentiles};
+
+/// Target of every phase span
+const TARGET: &str = "sherlock::phases";
+
+/// Field the measured time of a phase is recorded in
+const ELAPSED_FIELD: &str = "elapsed_us";
+
+/// Phase spans are at info rather than debug level
+static TRACE_REQUESTS: AtomicBool = AtomicBool::new(false);
+
+/// Raise phase spans to info level, for `--trace-requests`
+pub fn trace_requests(on: bool) {
+    TRACE_REQUESTS.store(on, Ordering::Relaxed);
+}
+
+/// A step of handling a proxied request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    /// Receiving the request body
+    ReadBody,
+    /// Matching the path, or the body's shape, to a provider
+    Detect,
+    /// Parsing the body and counting its tokens, before it is forwarded
+    Parse,
+    /// Connecting and sending upstream, until the response headers
+    Upstream,
+    /// From the response headers to the first byte of the body
+    FirstByte,
+    /// Relaying the body to the client, from its first byte to its end
+    Relay,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 6] = [
+        Phase::ReadBody,
+        Phase::Detect,
+        Phase::Parse,
+        Phase::Upstream,
+        Phase::FirstByte,
+        Phase::Relay,
+    ];
+
+    /// Name of the phase's span
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::ReadBody => "read_body",
+            Phase::Detect => "detect_provider",
+            Phase::Parse => "parse",
+            Phase::Upstream => "upstream",
+            Phase::FirstByte => "first_byte",
+            Phase::Relay => "relay",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|phase| phase.name() == name)
+    }
+
+    /// A span for the phase of request `id`. Span names are fixed where the
+    /// span is declared, hence one declaration per phase and level.
+    fn span(self, id: u64) -> Span {
+        macro_rules! span {
+            ($name:literal) => {
+                if TRACE_REQUESTS.load(Ordering::Relaxed) {
+                    tracing::info_span!(
+                        target: TARGET,
+                        $name,
+                        request_id = id,
+                        elapsed_us = tracing::field::Empty
+                    )
+                } else {
+                    tracing::debug_span!(
+                        target: TARGET,
+                        $name,
+                        request_id = id,
+                        elapsed_us = tracing::field::Empty
+                    )
+                }
+            };
+        }
+        match self {
+            Phase::ReadBody => span!("read_body"),
+            Phase::Detect => span!("detect_provider"),
+            Phase::Parse => span!("parse"),
+            Phase::Upstream => span!("upstream"),
+            Phase::FirstByte => span!("first_byte"),
+            Phase::Relay => span!("relay"),
+        }
+    }
+}
+
+/// Microseconds each phase of a request took, for the phases it reached
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RequestTimings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_body_us: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detect_us: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parse_us: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream_us: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_byte_us: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub relay_us: Option<u64>,
+}
+
+impl RequestTimings {
+    pub fn get(&self, phase: Phase) -> Option<u64> {
+        *self.field(phase)
+    }
+
+    fn field(&self, phase: Phase) -> &Option<u64> {
+        match phase {
+            Phase::ReadBody => &self.read_body_us,
+            Phase::Detect => &self.detect_us,
+            Phase::Parse => &self.parse_us,
+            Phase::Upstream => &self.upstream_us,
+            Phase::FirstByte => &self.first_byte_us,
+            Phase::Relay => &self.relay_us,
+        }
+    }
+
+    fn field_mut(&mut self, phase: Phase) -> &mut Option<u64> {
+        match phase {
+            Phase::ReadBody => &mut self.read_body_us,
+            Phase::Detect => &mut self.detect_us,
+            Phase::Parse => &mut self.parse_us,
+            Phase::Upstream => &mut self.upstream_us,
+            Phase::FirstByte => &mut self.first_byte_us,
+            Phase::Relay => &mut self.relay_us,
+        }
+    }
+}
+
+/// A phase under way: its span, and the time spent in it so far
+#[derive(Debug)]
+pub struct PhaseTimer {
+    phase: Phase,
+    span: Span,
+    elapsed: Duration,
+    /// Unset while paused
+    running: Option<Instant>,
+}
+
+impl PhaseTimer {
+    pub fn start(phase: Phase, id: u64) -> Self {
+        Self {
+            phase,
+            span: phase.span(id),
+            elapsed: Duration::ZERO,
+            running: Some(Instant::now()),
+        }
+    }
+
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    /// Run `f` inside the phase's span
+    pub fn in_scope<T>(&self, f: impl FnOnce() -> T) -> T {
+        self.span.in_scope(f)
+    }
+
+    /// Stop counting time toward the phase, e.g. while another one runs
+    pub fn pause(&mut self) {
+        if let Some(running) = self.running.take() {
+            self.elapsed += running.elapsed();
+        }
+    }
+
+    pub fn resume(&mut self) {
+        self.running.get_or_insert_with(Instant::now);
+    }
+
+    /// End the phase, recording the time spent in it on its span and in
+    /// `timings`
+    pub fn finish(mut self, timings: &mut RequestTimings) -> u64 {
+        self.pause();
+        let us = self.elapsed.as_micros() as u64;
+        self.span.record(ELAPSED_FIELD, us);
+        *timings.field_mut(self.phase) = Some(us);
+        us
+    }
+}
+
+/// Collects the time recorded on each phase span as it closes, by phase.
+/// Phases left unfinished, e.g. by a request refused early, don't count.
+#[derive(Debug, Clone, Default)]
+pub struct PhaseLayer {
+    histograms: Arc<Mutex<BTreeMap<Phase, Histogram>>>,
+}
+
+/// Time recorded on a phase span, kept in its extensions until it closes
+struct Elapsed(u64);
+
+impl PhaseLayer {
+    pub fn report(&self) -> PhaseReport {
+        let histograms = self.histograms.lock().unwrap();
+        PhaseReport {
+            phases: histograms
+                .iter()
+                .filter_map(|(phase, histogram)| {
+                    Some(PhaseSummary {
+                        phase: phase.name(),
+                        count: histogram.count(),
+                        percentiles: histogram.percentiles()?,
+                    })
+                })
+                .collect(),
+        }
+    }
+}
+
+impl<S> Layer<S> for PhaseLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        if span.metadata().target() != TARGET {
+            return;
+        }
+        let mut visitor = ElapsedVisitor(None);
+        values.record(&mut visitor);
+        if let Some(us) = visitor.0 {
+            span.extensions_mut().insert(Elapsed(us));
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(phase) = Phase::from_name(span.name()) else {
+            return;
+        };
+        if span.metadata().target() != TARGET {
+            return;
+        }
+        let Some(us) = span.extensions().get::<Elapsed>().map(|elapsed| elapsed.0) else {
+            return;
+        };
+        let mut histograms = self.histograms.lock().unwrap();
+        histograms.entry(phase).or_default().record(us);
+    }
+}
+
+struct ElapsedVisitor(Option<u64>);
+
+impl Visit for ElapsedVisitor {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if field.name() == ELAPSED_FIELD {
+            self.0 = Some(value);
+        }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn fmt::Debug) {}
+}
+
+/// Percentiles of each phase's time over the session, in microseconds
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PhaseReport {
+    /// In the order the phases happen
+    pub phases: Vec<PhaseSummary>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PhaseSummary {
+    pub phase: &'static str,
+    pub count: u64,
+    pub percentiles: Percentiles,
+}
+
+impl fmt::Display for PhaseReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.phases.is_empty() {
+            return writeln!(f, "No requests traced");
+        }
+        writeln!(f, "Request phases:")?;
+        writeln!(
+            f,
+            "  {:<16} {:>8} {:>9} {:>9} {:>9}",
+            "phase", "count", "p50", "p90", "p99"
+        )?;
+        for summary in &self.phases {
+            let Percentiles { p50, p90, p99 } = summary.percentiles;
+            writeln!(
+                f,
+                "  {:<16} {:>8} {:>9} {:>9} {:>9}",
+                summary.phase,
+                summary.count,
+                format_us(p50),
+                format_us(p90),
+                format_us(p99)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// e.g. "850µs", "12.3ms" or "2.41s"
+fn format_us(us: u64) -> String {
+    match us {
+        0..=999 => format!("{}µs", us),
+        1_000..=999_999 => format!("{:.1}ms", us as f64 / 1_000.0),
+        _ => format!("{:.2}s", us as f64 / 1_000_000.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    fn traced(f: impl FnOnce()) -> PhaseLayer {
+        let layer = PhaseLayer::default();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        tracing::subscriber::with_default(subscriber, f);
+        layer
+    }
+
+    #[test]
+    fn test_layer_totals_recorded_times_by_phase() {
+        let layer = traced(|| {
+            for (id, us) in (1..=100u64).map(|i| (i, i * 10)) {
+                let span = Phase::Parse.span(id);
+                span.record(ELAPSED_FIELD, us);
+            }
+            let span = Phase::Upstream.span(101);
+            span.record(ELAPSED_FIELD, 250_000u64);
+            // Unfinished phases don't count
+            let _unfinished = PhaseTimer::start(Phase::Relay, 102);
+            // Only phase spans count
+            let _other = tracing::info_span!("parse", elapsed_us = 5u64);
+        });
+        let report = layer.report();
+        let phases: Vec<(&str, u64)> = report
+            .phases
+            .iter()
+            .map(|summary| (summary.phase, summary.count))
+            .collect();
+        assert_eq!(phases, [("parse", 100), ("upstream", 1)]);
+        let parse = report.phases[0].percentiles;
+        // Within the histogram's few percent of 500, 900 and 990
+        assert!((480..=520).contains(&parse.p50), "{:?}", parse);
+        assert!((870..=930).contains(&parse.p90), "{:?}", parse);
+        assert!((950..=1000).contains(&parse.p99), "{:?}", parse);
+        assert_eq!(report.phases[1].percentiles.p50, 250_000);
+
+        let text = report.to_string();
+        assert!(text.starts_with("Request phases:\n"));
+        assert!(text.contains("upstream"));
+        assert!(text.contains("250.0ms"));
+        assert_eq!(
+            PhaseLayer::default().report().to_string(),
+            "No requests traced\n"
+        );
+    }
+
+    #[test]
+    fn test_timers_match_the_layer() {
+        let mut timings = RequestTimings::default();
+        let mut recorded = Vec::new();
+        let layer = traced(|| {
+            let read = PhaseTimer::start(Phase::ReadBody, 7);
+            std::thread::sleep(Duration::from_millis(2));
+            recorded.push(read.finish(&mut timings));
+
+            let mut detect = PhaseTimer::start(Phase::Detect, 7);
+            detect.pause();
+            // Time while paused doesn't count
+            std::thread::sleep(Duration::from_millis(20));
+            detect.resume();
+            recorded.push(detect.finish(&mut timings));
+        });
+        assert!(recorded[0] >= 2_000);
+        assert!(recorded[1] < 20_000);
+        assert_eq!(timings.get(Phase::ReadBody), Some(recorded[0]));
+        assert_eq!(timings.get(Phase::Detect), Some(recorded[1]));
+        assert_eq!(timings.get(Phase::Upstream), None);
+
+        // With one sample per phase, the percentiles are that sample
+        let report = layer.report();
+        assert_eq!(report.phases.len(), 2);
+        for (summary, us) in report.phases.iter().zip(&recorded) {
+            assert_eq!(summary.percentiles.p99, *us);
+            assert_eq!(summary.percentiles.p50, *us);
+        }
+    }
+
+    #[test]
+    fn test_span_names_match_phases() {
+        traced(|| {
+            for phase in Phase::ALL {
+                let span = phase.span(1);
+                assert_eq!(span.metadata().unwrap().name(), phase.name());
+                assert_eq!(Phase::from_name(phase.name()), Some(phase));
+            }
+        });
+    }
+}
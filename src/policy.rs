@@ -0,0 +1,452 @@
+use anyhow::{Context, Result};
+use regex::{Regex, RegexBuilder};
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use crate::config::{OutputCapAction, PolicyAction, PolicyConfig};
+use crate::event::{RequestEvent, TokenClamp};
+
+/// Compiled program size cap per pattern. The regex engine matches in linear
+/// time, so together with this a scan stays bounded on megabyte prompts.
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+const DFA_SIZE_LIMIT: usize = 2 << 20;
+
+/// Where each provider format keeps its output token limit: Anthropic and
+/// OpenAI chat at the top level, the OpenAI Responses API as
+/// `max_output_tokens`, Gemini nested in its generation config
+const OUTPUT_LIMIT_FIELDS: &[&[&str]] = &[
+    &["max_tokens"],
+    &["max_completion_tokens"],
+    &["max_output_tokens"],
+    &["generationConfig", "maxOutputTokens"],
+    &["generation_config", "max_output_tokens"],
+];
+
+/// Evaluates the configured `policy.scan_patterns` against outgoing messages
+#[derive(Debug, Default)]
+pub struct PolicyScanner {
+    patterns: Vec<CompiledPattern>,
+    max_output_tokens: Option<u32>,
+    output_action: OutputCapAction,
+}
+
+#[derive(Debug)]
+struct CompiledPattern {
+    name: String,
+    regex: Regex,
+    action: PolicyAction,
+}
+
+/// Which patterns matched a request. Only names and counts, never the matched text.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PolicyReport {
+    pub matches: BTreeMap<String, usize>,
+    /// Matched patterns whose action is `block`
+    pub blocked_by: Vec<String>,
+}
+
+/// Outcome of checking a body against `policy.max_output_tokens`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputCap {
+    /// No cap configured, or every limit is already within it
+    Within,
+    /// The body to forward instead, with every oversized limit lowered
+    Clamped { clamp: TokenClamp, body: Vec<u8> },
+    Blocked(TokenClamp),
+}
+
+impl PolicyScanner {
+    pub fn new(config: &PolicyConfig) -> Result<Self> {
+        let patterns = config
+            .scan_patterns
+            .iter()
+            .map(|pattern| {
+                let regex = RegexBuilder::new(&pattern.regex)
+                    .size_limit(REGEX_SIZE_LIMIT)
+                    .dfa_size_limit(DFA_SIZE_LIMIT)
+                    .build()
+                    .with_context(|| format!("Invalid policy pattern {:?}", pattern.name))?;
+                Ok(CompiledPattern {
+                    name: pattern.name.clone(),
+                    regex,
+                    action: pattern.action,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            patterns,
+            max_output_tokens: config.max_output_tokens,
+            output_action: config.max_output_tokens_action,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    pub fn scan(&self, event: &RequestEvent) -> PolicyReport {
+        let mut report = PolicyReport::default();
+        for pattern in &self.patterns {
+            let count: usize = event
+                .messages
+                .iter()
+                .map(|msg| pattern.regex.find_iter(&msg.content).count())
+                .sum();
+            if count == 0 {
+                continue;
+            }
+            *report.matches.entry(pattern.name.clone()).or_default() += count;
+            if pattern.action == PolicyAction::Block && !report.blocked_by.contains(&pattern.name)
+            {
+                report.blocked_by.push(pattern.name.clone());
+            }
+        }
+        report
+    }
+
+    /// Check a request body's output token limits against the cap. Bodies
+    /// already within it, or that aren't JSON, are left alone. Oversized
+    /// limits are rewritten where they stand, so the rest of the body goes
+    /// upstream byte for byte and a large one is never parsed whole.
+    pub fn cap_output_tokens(&self, body: &[u8]) -> OutputCap {
+        let Some(limit) = self.max_output_tokens.map(u64::from) else {
+            return OutputCap::Within;
+        };
+
+        let mut largest: Option<TokenClamp> = None;
+        let mut oversized = Vec::new();
+        for path in OUTPUT_LIMIT_FIELDS {
+            for span in value_spans(body, path) {
+                let requested = std::str::from_utf8(&body[span.clone()])
+                    .ok()
+                    .and_then(|n| n.parse::<u64>().ok());
+                let Some(requested) = requested.filter(|&n| n > limit) else {
+                    continue;
+                };
+                if largest.as_ref().is_none_or(|c| requested > c.requested) {
+                    largest = Some(TokenClamp {
+                        field: path.join("."),
+                        requested,
+                        limit,
+                    });
+                }
+                oversized.push(span);
+            }
+        }
+
+        match (largest, self.output_action) {
+            (None, _) => OutputCap::Within,
+            (Some(clamp), OutputCapAction::Block) => OutputCap::Blocked(clamp),
+            (Some(clamp), OutputCapAction::Clamp) => {
+                // From the end, so the spans still to go keep their offsets
+                oversized.sort_by_key(|span| std::cmp::Reverse(span.start));
+                let mut body = body.to_vec();
+                for span in oversized {
+                    body.splice(span, limit.to_string().into_bytes());
+                }
+                OutputCap::Clamped { clamp, body }
+            }
+        }
+    }
+}
+
+/// Byte ranges of the values at `path` in the JSON object `body`, every one
+/// when a key repeats. Empty when `body` isn't an object.
+fn value_spans(body: &[u8], path: &[&str]) -> Vec<Range<usize>> {
+    let Some((key, rest)) = path.split_first() else {
+        return vec![];
+    };
+    let mut spans = Vec::new();
+    let mut pos = skip_whitespace(body, 0);
+    if body.get(pos) != Some(&b'{') {
+        return spans;
+    }
+    pos = skip_whitespace(body, pos + 1);
+    if body.get(pos) == Some(&b'}') {
+        return spans;
+    }
+    loop {
+        let Some(key_end) = skip_value(body, pos).filter(|_| body[pos] == b'"') else {
+            return spans;
+        };
+        let name = serde_json::from_slice::<String>(&body[pos..key_end]);
+        pos = skip_whitespace(body, key_end);
+        if body.get(pos) != Some(&b':') {
+            return spans;
+        }
+        let start = skip_whitespace(body, pos + 1);
+        let Some(end) = skip_value(body, start) else {
+            return spans;
+        };
+        if name.is_ok_and(|name| name == *key) {
+            match rest.is_empty() {
+                true => spans.push(start..end),
+                false => spans.extend(
+                    value_spans(&body[start..end], rest)
+                        .into_iter()
+                        .map(|span| span.start + start..span.end + start),
+                ),
+            }
+        }
+        pos = skip_whitespace(body, end);
+        match body.get(pos) {
+            Some(b',') => pos = skip_whitespace(body, pos + 1),
+            _ => return spans,
+        }
+    }
+}
+
+fn skip_whitespace(body: &[u8], mut pos: usize) -> usize {
+    while body.get(pos).is_some_and(u8::is_ascii_whitespace) {
+        pos += 1;
+    }
+    pos
+}
+
+/// End of the JSON value starting at `pos`, found by matching brackets and
+/// quotes without parsing what's inside
+fn skip_value(body: &[u8], mut pos: usize) -> Option<usize> {
+    let mut depth = 0usize;
+    loop {
+        match *body.get(pos)? {
+            b'"' => {
+                pos += 1;
+                loop {
+                    match *body.get(pos)? {
+                        b'\\' => pos += 2,
+                        b'"' => break,
+                        _ => pos += 1,
+                    }
+                }
+                pos += 1;
+            }
+            b'{' | b'[' => {
+                depth += 1;
+                pos += 1;
+            }
+            b'}' | b']' => {
+                depth = depth.checked_sub(1)?;
+                pos += 1;
+            }
+            b',' | b':' if depth == 0 => return None,
+            _ if depth == 0 => {
+                // A number or literal runs to the next delimiter
+                let len = body[pos..]
+                    .iter()
+                    .position(|b| matches!(b, b',' | b'}' | b']') || b.is_ascii_whitespace())
+                    .unwrap_or(body.len() - pos);
+                return Some(pos + len);
+            }
+            _ => pos += 1,
+        }
+        if depth == 0 {
+            return Some(pos);
+        }
+    }
+}
+
+/// "name ×count" list for logs and the dashboard
+pub fn summarize(matches: &BTreeMap<String, usize>) -> String {
+    matches
+        .iter()
+        .map(|(name, count)| format!("{} ×{}", name, count))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ScanPattern;
+    use crate::event::Message;
+    use crate::parser::minimal_event;
+
+    fn scanner(patterns: &[(&str, &str, PolicyAction)]) -> PolicyScanner {
+        PolicyScanner::new(&PolicyConfig {
+            scan_patterns: patterns
+                .iter()
+                .map(|(name, regex, action)| ScanPattern {
+                    name: name.to_string(),
+                    regex: regex.to_string(),
+                    action: *action,
+                })
+                .collect(),
+            ..PolicyConfig::default()
+        })
+        .unwrap()
+    }
+
+    fn request(text: &str) -> RequestEvent {
+        let mut event = minimal_event(b"{}", "/v1/messages", "anthropic");
+        event.messages = vec![Message {
+            role: "user".to_string(),
+            content: text.to_string(),
+            unknown_parts: vec![],
+        }];
+        event
+    }
+
+    #[test]
+    fn test_scan_counts_per_pattern() {
+        let scanner = scanner(&[
+            ("confidential", r"(?i)\bconfidential\b", PolicyAction::Warn),
+            ("internal-host", r"\b[a-z0-9-]+\.corp\.example\.com\b", PolicyAction::Block),
+            ("unused", r"ACME Corp", PolicyAction::Block),
+        ]);
+        let report = scanner.scan(&request(
+            "CONFIDENTIAL\nconnect to db1.corp.example.com, not confidential? db2.corp.example.com",
+        ));
+
+        assert_eq!(
+            report.matches,
+            BTreeMap::from([
+                ("confidential".to_string(), 2),
+                ("internal-host".to_string(), 2),
+            ])
+        );
+        assert_eq!(report.blocked_by, vec!["internal-host".to_string()]);
+        assert_eq!(summarize(&report.matches), "confidential ×2, internal-host ×2");
+    }
+
+    #[test]
+    fn test_invalid_pattern_rejected() {
+        let config = PolicyConfig {
+            scan_patterns: vec![ScanPattern {
+                name: "broken".to_string(),
+                regex: "(unclosed".to_string(),
+                action: PolicyAction::Warn,
+            }],
+            ..PolicyConfig::default()
+        };
+        let err = PolicyScanner::new(&config).unwrap_err();
+        assert!(format!("{:#}", err).contains("broken"));
+
+        // Patterns that compile to huge programs are refused rather than slowing scans
+        let config = PolicyConfig {
+            scan_patterns: vec![ScanPattern {
+                name: "huge".to_string(),
+                regex: r"\w{1000}\w{1000}".to_string(),
+                action: PolicyAction::Warn,
+            }],
+            ..PolicyConfig::default()
+        };
+        assert!(PolicyScanner::new(&config).is_err());
+    }
+
+    #[test]
+    fn test_scan_megabyte_prompt_is_bounded() {
+        let scanner = scanner(&[("secret", r"(a|aa)+b", PolicyAction::Warn)]);
+        let event = request(&"a".repeat(1 << 20));
+
+        let start = std::time::Instant::now();
+        assert!(scanner.scan(&event).matches.is_empty());
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    fn capped(limit: u32, action: OutputCapAction) -> PolicyScanner {
+        PolicyScanner::new(&PolicyConfig {
+            max_output_tokens: Some(limit),
+            max_output_tokens_action: action,
+            ..PolicyConfig::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_clamp_output_tokens_per_provider() {
+        let scanner = capped(8192, OutputCapAction::Clamp);
+        let cases = [
+            // Anthropic messages
+            (
+                r#"{"model":"claude-3","max_tokens":64000,"messages":[]}"#,
+                "max_tokens",
+                serde_json::json!({"model":"claude-3","max_tokens":8192,"messages":[]}),
+            ),
+            // OpenAI chat, where both names may appear
+            (
+                r#"{"model":"gpt-4o","max_tokens":100,"max_completion_tokens":32000}"#,
+                "max_completion_tokens",
+                serde_json::json!({"model":"gpt-4o","max_tokens":100,"max_completion_tokens":8192}),
+            ),
+            // OpenAI responses
+            (
+                r#"{"model":"gpt-5","input":"hi","max_output_tokens":128000}"#,
+                "max_output_tokens",
+                serde_json::json!({"model":"gpt-5","input":"hi","max_output_tokens":8192}),
+            ),
+            // Gemini
+            (
+                r#"{"contents":[],"generationConfig":{"temperature":1,"maxOutputTokens":65536}}"#,
+                "generationConfig.maxOutputTokens",
+                serde_json::json!({
+                    "contents": [],
+                    "generationConfig": {"temperature": 1, "maxOutputTokens": 8192}
+                }),
+            ),
+        ];
+
+        for (body, field, expected) in cases {
+            let OutputCap::Clamped { clamp, body } = scanner.cap_output_tokens(body.as_bytes())
+            else {
+                panic!("{} not clamped", field);
+            };
+            assert_eq!(clamp.field, field);
+            assert_eq!(clamp.limit, 8192);
+            assert_eq!(
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_clamp_rewrites_only_the_limit() {
+        let scanner = capped(8192, OutputCapAction::Clamp);
+        let original = br#"{"stream": true, "model":"claude-3",
+  "messages": [{"role":"user","content":"say \"max_tokens\": 99999 {["}],
+  "max_tokens" : 64000, "metadata":{"max_tokens":90000}}"#;
+        let OutputCap::Clamped { clamp, body } = scanner.cap_output_tokens(original) else {
+            panic!("not clamped");
+        };
+        assert_eq!(clamp.requested, 64000);
+        // Key order, spacing and the look-alikes in strings and nested
+        // objects all stay as they were
+        let expected = String::from_utf8_lossy(original).replace("64000", "8192");
+        assert_eq!(String::from_utf8(body).unwrap(), expected);
+
+        // Every copy of a repeated key is lowered
+        let OutputCap::Clamped { body, .. } =
+            scanner.cap_output_tokens(br#"{"max_tokens":9000,"max_tokens":64000}"#)
+        else {
+            panic!("not clamped");
+        };
+        assert_eq!(body, br#"{"max_tokens":8192,"max_tokens":8192}"#);
+    }
+
+    #[test]
+    fn test_output_cap_within_and_block() {
+        let scanner = capped(8192, OutputCapAction::Clamp);
+        for body in [
+            r#"{"model":"claude-3","max_tokens":8192}"#,
+            r#"{"generationConfig":{"maxOutputTokens":1024}}"#,
+            r#"{"model":"gpt-4o"}"#,
+            "not json",
+        ] {
+            assert_eq!(scanner.cap_output_tokens(body.as_bytes()), OutputCap::Within);
+        }
+        assert_eq!(
+            PolicyScanner::default().cap_output_tokens(br#"{"max_tokens":64000}"#),
+            OutputCap::Within
+        );
+
+        let scanner = capped(8192, OutputCapAction::Block);
+        assert_eq!(
+            scanner.cap_output_tokens(br#"{"model":"claude-3","max_tokens":64000}"#),
+            OutputCap::Blocked(TokenClamp {
+                field: "max_tokens".to_string(),
+                requested: 64000,
+                limit: 8192,
+            })
+        );
+    }
+}
@@ -0,0 +1,277 @@
+use crate::aggregate::unprefixed;
+use crate::config::{ModelPrice, PricingConfig};
+use crate::context::context_limit;
+use crate::event::RequestEvent;
+
+/// Alternatives the detail view lists, cheapest first
+pub const ALTERNATIVES_SHOWN: usize = 3;
+
+/// The `pricing` config, ready for lookups: lowercase prefixes, longest first
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PriceTable {
+    prices: Vec<(String, ModelPrice)>,
+    compare_models: Vec<String>,
+}
+
+/// What a request would have cost on another model, at standard rates
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alternative {
+    pub model: String,
+    /// Unset for models without a price
+    pub cost_usd: Option<f64>,
+    /// Percent cheaper than the request's own model, negative when dearer;
+    /// unset when either has no price
+    pub savings_percent: Option<f64>,
+}
+
+impl PriceTable {
+    pub fn new(pricing: &PricingConfig) -> Self {
+        let mut prices: Vec<(String, ModelPrice)> = pricing
+            .models
+            .iter()
+            .map(|(prefix, price)| (prefix.to_ascii_lowercase(), price.clone()))
+            .collect();
+        prices.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then_with(|| a.0.cmp(&b.0)));
+        Self {
+            prices,
+            compare_models: pricing.compare_models.clone(),
+        }
+    }
+
+    /// Rates under the longest prefix of `model`, ignoring case and vendor
+    /// prefixes such as `models/`
+    pub fn price(&self, model: &str) -> Option<&ModelPrice> {
+        self.entry(model).map(|(_, price)| price)
+    }
+
+    /// The longest prefix of `model` with a price, and the price
+    fn entry(&self, model: &str) -> Option<&(String, ModelPrice)> {
+        let model = unprefixed(model).to_ascii_lowercase();
+        self.prices
+            .iter()
+            .find(|(prefix, _)| model.starts_with(prefix.as_str()))
+    }
+
+    /// Dollars for `input` and `output` tokens on `model` at its standard
+    /// rates, for requests not yet sent or not fully known. Unset for
+    /// unpriced models.
+    pub fn estimate(&self, model: &str, input: u64, output: u64) -> Option<f64> {
+        let price = self.price(model)?;
+        Some(
+            (input as f64 * price.input_per_mtok + output as f64 * price.output_per_mtok)
+                / 1_000_000.0,
+        )
+    }
+
+    /// The cheapest `ALTERNATIVES_SHOWN` of the `compare_models` for
+    /// `input` and `output` tokens, then those without a price. Leaves out
+    /// `model` itself, or whichever is priced under the same prefix, and
+    /// models whose context window is known to be too small.
+    pub fn alternatives(&self, model: &str, input: u64, output: u64) -> Vec<Alternative> {
+        let current = self.estimate(model, input, output);
+        let own = unprefixed(model).to_ascii_lowercase();
+        let own_entry = self.entry(model);
+        let is_own = |name: &str| {
+            unprefixed(name).eq_ignore_ascii_case(&own)
+                || own_entry.is_some_and(|own| self.entry(name) == Some(own))
+        };
+        let (mut priced, unpriced): (Vec<_>, Vec<_>) = self
+            .compare_models
+            .iter()
+            .filter(|name| !is_own(name))
+            .filter(|name| context_limit(name).is_none_or(|limit| limit >= input + output))
+            .map(|name| {
+                let cost_usd = self.estimate(name, input, output);
+                Alternative {
+                    model: name.clone(),
+                    cost_usd,
+                    savings_percent: match (current, cost_usd) {
+                        (Some(current), Some(cost)) if current > 0.0 => {
+                            Some((current - cost) / current * 100.0)
+                        }
+                        _ => None,
+                    },
+                }
+            })
+            .partition(|alternative| alternative.cost_usd.is_some());
+        priced.sort_by(|a, b| {
+            let cost = |alternative: &Alternative| alternative.cost_usd.unwrap_or_default();
+            cost(a).total_cmp(&cost(b))
+        });
+        priced.truncate(ALTERNATIVES_SHOWN);
+        priced.extend(unpriced);
+        priced
+    }
+
+    /// Dollars `event` cost at list price, going by the model and service
+    /// tier that served it. Gemini's own prompt count wins over sherlock's,
+    /// as it includes cached context, which is charged at the cached rate.
+    /// Unset for unpriced models and for requests the upstream rejected.
+    pub fn cost(&self, event: &RequestEvent) -> Option<f64> {
+        if event.response.is_some_and(|response| response.status >= 400) {
+            return None;
+        }
+        let price = self.price(event.served_model.as_deref().unwrap_or(&event.model))?;
+        let rates = price.rates(event.tier());
+        let (uncached, cached) = match event.gemini_usage {
+            Some(usage) if usage.prompt > 0 => (usage.uncached(), usage.cached),
+            _ => (event.tokens as u64, 0),
+        };
+        let cached_rate = price.cached_input_per_mtok.unwrap_or(rates.input_per_mtok);
+        let output = event.output.map_or(0, |output| output.tokens);
+        Some(
+            (uncached as f64 * rates.input_per_mtok
+                + cached as f64 * cached_rate
+                + output as f64 * rates.output_per_mtok)
+                / 1_000_000.0,
+        )
+    }
+}
+
+/// e.g. "$0.0042", "$1.27", or "-" when the cost isn't known
+pub fn format_cost(cost: Option<f64>) -> String {
+    match cost {
+        None => "-".to_string(),
+        Some(cost) if cost < 0.01 => format!("${:.4}", cost),
+        Some(cost) if cost < 1000.0 => format!("${:.2}", cost),
+        Some(cost) => format!("${:.0}", cost),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::event::{OutputTokens, ResponseInfo};
+
+    #[test]
+    fn test_longest_prefix_wins() {
+        let table = PriceTable::new(&Config::default().pricing);
+        let input = |model| table.price(model).map(|price| price.input_per_mtok);
+        assert_eq!(input("claude-3-5-sonnet-20241022"), Some(3.0));
+        assert_eq!(input("gpt-4o-2024-08-06"), Some(2.5));
+        assert_eq!(input("gpt-4o-mini-2024-07-18"), Some(0.15));
+        assert_eq!(input("models/Gemini-1.5-Pro-002"), Some(1.25));
+        assert_eq!(input("claude-opus-4-5-20251101"), Some(5.0));
+        assert_eq!(input("claude-opus-4-1-20250805"), Some(15.0));
+        assert_eq!(input("llama3:70b"), None);
+    }
+
+    #[test]
+    fn test_event_cost() {
+        let table = PriceTable::new(&Config::default().pricing);
+        let mut event = crate::parser::minimal_event(b"{}", "/v1/messages", "anthropic");
+        event.model = "claude-3-5-sonnet-latest".to_string();
+        event.tokens = 100_000;
+        event.output = Some(OutputTokens {
+            tokens: 2_000,
+            estimated: false,
+        });
+        assert_eq!(table.cost(&event), Some(0.33));
+        assert_eq!(format_cost(table.cost(&event)), "$0.33");
+
+        // Priced as the model that answered
+        event.served_model = Some("claude-3-5-haiku-20241022".to_string());
+        assert_eq!(format_cost(table.cost(&event)), "$0.09");
+        event.response = Some(ResponseInfo {
+            status: 429,
+            latency_ms: 40,
+        });
+        assert_eq!(table.cost(&event), None);
+        event.response = None;
+        event.served_model = Some("mystery-model".to_string());
+        assert_eq!(format_cost(table.cost(&event)), "-");
+        assert_eq!(format_cost(Some(0.00042)), "$0.0004");
+    }
+
+    #[test]
+    fn test_estimate() {
+        let table = PriceTable::new(&Config::default().pricing);
+        assert_eq!(
+            table.estimate("claude-3-5-sonnet-latest", 100_000, 2_000),
+            Some(0.33)
+        );
+        assert_eq!(table.estimate("gpt-4o-mini", 1_000_000, 0), Some(0.15));
+        assert_eq!(table.estimate("llama3:70b", 1_000, 1_000), None);
+    }
+
+    #[test]
+    fn test_alternatives() {
+        let mut pricing = Config::default().pricing;
+        pricing.compare_models = [
+            "claude-sonnet-4",
+            "gpt-4o-mini",
+            "in-house-7b",
+            "gemini-2.5-pro",
+            "gemini-2.5-flash",
+            "gpt-5-mini",
+            "claude-haiku-4-5",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect();
+        let table = PriceTable::new(&pricing);
+        let shown = |alternatives: Vec<Alternative>| {
+            alternatives
+                .into_iter()
+                .map(|a| {
+                    (
+                        a.model,
+                        format_cost(a.cost_usd),
+                        a.savings_percent.map(f64::round),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // $0.60 on Sonnet. The request's own model is left out, and so is
+        // gpt-4o-mini, whose window is too small for it; gemini-2.5-pro
+        // isn't among the cheapest three. Unpriced models come last.
+        let alternatives = table.alternatives("claude-sonnet-4-20250514", 200_000, 0);
+        let model = |name: &str, cost: &str, savings| (name.to_string(), cost.to_string(), savings);
+        assert_eq!(
+            shown(alternatives),
+            vec![
+                model("gpt-5-mini", "$0.05", Some(92.0)),
+                model("gemini-2.5-flash", "$0.06", Some(90.0)),
+                model("claude-haiku-4-5", "$0.20", Some(67.0)),
+                model("in-house-7b", "-", None),
+            ]
+        );
+
+        // No savings to speak of for a model without a price
+        let alternatives = table.alternatives("llama3:70b", 1_000, 1_000);
+        assert_eq!(alternatives.len(), 4);
+        assert!(alternatives.iter().all(|a| a.savings_percent.is_none()));
+
+        // A model whose name extends another's is still compared with it
+        let table = PriceTable::new(&Config::default().pricing);
+        let offers = |model, other: &str| {
+            let alternatives = table.alternatives(model, 1_000, 1_000);
+            alternatives.iter().any(|a| a.model == other)
+        };
+        assert!(offers("gpt-5-mini-2025-08-07", "gpt-5"));
+        assert!(!offers("gpt-5-mini-2025-08-07", "gpt-5-mini"));
+        assert!(offers("gpt-5-2025-08-07", "gpt-5-mini"));
+    }
+
+    #[test]
+    fn test_tier_rates() {
+        let table = PriceTable::new(&Config::default().pricing);
+        let mut event = crate::parser::minimal_event(b"{}", "/v1/chat/completions", "openai");
+        event.model = "gpt-5-2025-08-07".to_string();
+        event.tokens = 1_000_000;
+        assert_eq!(table.cost(&event), Some(1.25));
+        // The reported tier wins over the requested one
+        event.service_tier = Some("priority".to_string());
+        assert_eq!(table.cost(&event), Some(2.5));
+        event.served_tier = Some("flex".to_string());
+        assert_eq!(table.cost(&event), Some(0.625));
+        // Tiers without rates of their own pay standard ones
+        event.served_tier = Some("default".to_string());
+        assert_eq!(table.cost(&event), Some(1.25));
+        event.model = "claude-sonnet-4-5".to_string();
+        event.served_tier = Some("priority".to_string());
+        assert_eq!(table.cost(&event), Some(3.0));
+    }
+}
@@ -0,0 +1,396 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, TimeZone, Timelike, Utc};
+use serde::Serialize;
+use std::fmt;
+
+use crate::index::IndexEntry;
+use crate::pricing::{format_cost, PriceTable};
+
+/// Fraction of a typical day's usage that falls into each local hour
+#[derive(Debug, Clone, PartialEq)]
+pub struct HourlyProfile {
+    weights: [f64; 24],
+}
+
+/// End-of-day and end-of-month estimates for an accruing amount
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Projection {
+    pub today: f64,
+    pub month_to_date: f64,
+    pub month_end: f64,
+    /// Whether historical hour-of-day weights were used (otherwise linear)
+    pub weighted: bool,
+}
+
+impl HourlyProfile {
+    /// Every hour weighs the same, i.e. plain linear extrapolation
+    pub fn uniform() -> Self {
+        Self {
+            weights: [1.0 / 24.0; 24],
+        }
+    }
+
+    /// Build a profile from historical per-hour totals (summed over any
+    /// number of days). Returns `None` when there is no usable history.
+    pub fn from_hourly_totals(totals: &[f64; 24]) -> Option<Self> {
+        let mut weights = totals.map(|amount| amount.max(0.0));
+
+        let total: f64 = weights.iter().sum();
+        if total <= 0.0 {
+            return None;
+        }
+        for w in &mut weights {
+            *w /= total;
+        }
+        Some(Self { weights })
+    }
+
+    /// Share of a day's usage expected between two times on the same day
+    fn weight_between(&self, start: NaiveDateTime, end: NaiveDateTime) -> f64 {
+        let mut total = 0.0;
+        let mut cursor = start;
+        while cursor < end {
+            let hour_end = cursor
+                .date()
+                .and_hms_opt(cursor.hour(), 0, 0)
+                .expect("valid hour")
+                + Duration::hours(1);
+            let segment_end = hour_end.min(end);
+            let fraction = (segment_end - cursor).num_seconds() as f64 / 3600.0;
+            total += self.weights[cursor.hour() as usize] * fraction;
+            cursor = segment_end;
+        }
+        total
+    }
+}
+
+/// Project today's and this month's totals.
+///
+/// `observed_today` accrued between `observed_since` and `now`;
+/// `before_today` is the month's total up to local midnight.
+pub fn project(
+    observed_today: f64,
+    before_today: f64,
+    observed_since: NaiveDateTime,
+    now: NaiveDateTime,
+    profile: Option<&HourlyProfile>,
+) -> Projection {
+    let uniform = HourlyProfile::uniform();
+    let weighted = profile.is_some();
+    let profile = profile.unwrap_or(&uniform);
+
+    let midnight = now.date().and_hms_opt(0, 0, 0).expect("valid midnight");
+    let end_of_day = midnight + Duration::days(1);
+    let observed_since = observed_since.clamp(midnight, now);
+
+    let observed_weight = profile.weight_between(observed_since, now);
+    let remaining_weight = profile.weight_between(now, end_of_day);
+
+    let today = if observed_weight > f64::EPSILON {
+        observed_today + observed_today * remaining_weight / observed_weight
+    } else {
+        observed_today
+    };
+
+    let days_elapsed = now.day() as f64;
+    let daily_average = (before_today + today) / days_elapsed;
+
+    Projection {
+        today,
+        month_to_date: before_today + observed_today,
+        month_end: daily_average * days_in_month(now.date()) as f64,
+        weighted,
+    }
+}
+
+/// Accrues amounts per local day over a running session.
+///
+/// Completed days feed an hour-of-day profile, so projections switch from
+/// linear to weighted once the session has crossed midnight.
+#[derive(Debug, Clone)]
+pub struct SpendTracker {
+    started: NaiveDateTime,
+    day: NaiveDate,
+    today: f64,
+    month_before_today: f64,
+    today_hours: [f64; 24],
+    past_hours: [f64; 24],
+}
+
+impl SpendTracker {
+    pub fn new(now: NaiveDateTime) -> Self {
+        Self {
+            started: now,
+            day: now.date(),
+            today: 0.0,
+            month_before_today: 0.0,
+            today_hours: [0.0; 24],
+            past_hours: [0.0; 24],
+        }
+    }
+
+    /// A tracker that has seen `history`, in time order, since its first
+    /// entry
+    pub fn replay(history: &[(NaiveDateTime, f64)], now: NaiveDateTime) -> Self {
+        let started = history.first().map_or(now, |&(at, _)| at);
+        let mut tracker = Self::new(started);
+        for &(at, amount) in history {
+            tracker.record(at, amount);
+        }
+        tracker
+    }
+
+    pub fn record(&mut self, at: NaiveDateTime, amount: f64) {
+        self.roll_to(at.date());
+        self.today += amount;
+        self.today_hours[at.hour() as usize] += amount;
+    }
+
+    pub fn projection(&self, now: NaiveDateTime) -> Projection {
+        let mut current = self.clone();
+        current.roll_to(now.date());
+        let profile = HourlyProfile::from_hourly_totals(&current.past_hours);
+        project(
+            current.today,
+            current.month_before_today,
+            current.started,
+            now,
+            profile.as_ref(),
+        )
+    }
+
+    fn roll_to(&mut self, date: NaiveDate) {
+        if date <= self.day {
+            return;
+        }
+        if date.year() == self.day.year() && date.month() == self.day.month() {
+            self.month_before_today += self.today;
+        } else {
+            self.month_before_today = 0.0;
+        }
+        // Only a full day says anything about the hour-of-day shape
+        if self.started.date() < self.day {
+            for (past, today) in self.past_hours.iter_mut().zip(self.today_hours) {
+                *past += today;
+            }
+        }
+        self.today_hours = [0.0; 24];
+        self.today = 0.0;
+        self.day = date;
+    }
+}
+
+/// Dollars projected from the archive index, for `sherlock stats --projection`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CostProjection {
+    #[serde(flatten)]
+    pub projection: Projection,
+    /// Requests left out for want of a price
+    pub unpriced: usize,
+}
+
+impl CostProjection {
+    /// Project this month's cost from `entries`, in `tz`'s local days
+    pub fn build<Tz: TimeZone>(
+        entries: &[IndexEntry],
+        prices: &PriceTable,
+        tz: &Tz,
+        now: DateTime<Utc>,
+    ) -> Self {
+        let mut unpriced = 0;
+        let mut history: Vec<(NaiveDateTime, f64)> = entries
+            .iter()
+            .filter(|entry| !entry.failed())
+            .filter_map(|entry| {
+                let cost = entry.cost(prices);
+                unpriced += usize::from(cost.is_none());
+                Some((entry.timestamp.with_timezone(tz).naive_local(), cost?))
+            })
+            .collect();
+        history.sort_by_key(|&(at, _)| at);
+        let now = now.with_timezone(tz).naive_local();
+        Self {
+            projection: SpendTracker::replay(&history, now).projection(now),
+            unpriced,
+        }
+    }
+}
+
+impl fmt::Display for CostProjection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let p = &self.projection;
+        let model = if p.weighted { "hourly" } else { "linear" };
+        writeln!(f, "Spend (estimate, {}):", model)?;
+        writeln!(f, "  today          ~{}", format_cost(Some(p.today)))?;
+        writeln!(
+            f,
+            "  month to date   {}",
+            format_cost(Some(p.month_to_date))
+        )?;
+        writeln!(f, "  month end      ~{}", format_cost(Some(p.month_end)))?;
+        if self.unpriced > 0 {
+            writeln!(
+                f,
+                "  {} requests without a price aren't counted",
+                self.unpriced
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn days_in_month(date: NaiveDate) -> u32 {
+    let (year, month) = if date.month() == 12 {
+        (date.year() + 1, 1)
+    } else {
+        (date.year(), date.month() + 1)
+    };
+    NaiveDate::from_ymd_opt(year, month, 1)
+        .and_then(|first| first.pred_opt())
+        .map(|last| last.day())
+        .unwrap_or(30)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 6, day)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_linear_projection() {
+        // 100 spent between midnight and noon on June 15th
+        let p = project(100.0, 1400.0, at(15, 0, 0), at(15, 12, 0), None);
+        assert!(!p.weighted);
+        assert!((p.today - 200.0).abs() < 1e-9);
+        assert!((p.month_to_date - 1500.0).abs() < 1e-9);
+        // (1400 + 200) / 15 days * 30 days
+        assert!((p.month_end - 3200.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_session_started_mid_day() {
+        // Only observed the last two hours; linear rate of 10/hour
+        let p = project(20.0, 0.0, at(1, 10, 0), at(1, 12, 0), None);
+        assert!((p.today - 140.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_empty_history() {
+        assert_eq!(HourlyProfile::from_hourly_totals(&[0.0; 24]), None);
+        let p = project(0.0, 0.0, at(1, 0, 0), at(1, 0, 0), None);
+        assert_eq!(p.today, 0.0);
+        assert_eq!(p.month_end, 0.0);
+    }
+
+    #[test]
+    fn test_single_day_history_weights() {
+        // Yesterday all usage happened 9:00-17:00, evenly
+        let mut totals = [0.0; 24];
+        totals[9..17].fill(10.0);
+        let profile = HourlyProfile::from_hourly_totals(&totals).unwrap();
+
+        // By 13:00 today half of the working day has passed
+        let p = project(50.0, 0.0, at(15, 0, 0), at(15, 13, 0), Some(&profile));
+        assert!(p.weighted);
+        assert!((p.today - 100.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_tracker_rolls_over_midnight() {
+        let mut tracker = SpendTracker::new(at(14, 22, 0));
+        tracker.record(at(14, 23, 0), 30.0);
+        tracker.record(at(15, 1, 0), 5.0);
+
+        let p = tracker.projection(at(15, 2, 0));
+        assert!((p.month_to_date - 35.0).abs() < 1e-9);
+        // The partial first day doesn't count as history, so still linear:
+        // 5 over the first two hours of the day
+        assert!(!p.weighted);
+        assert!((p.today - 60.0).abs() < 1e-9);
+
+        // After a full day the hourly profile kicks in
+        tracker.record(at(15, 20, 0), 5.0);
+        let p = tracker.projection(at(16, 10, 0));
+        assert!(p.weighted);
+    }
+
+    #[test]
+    fn test_cost_projection_from_index() {
+        let entry = |day: u32, hour: u32, model: &str, cost_usd: Option<f64>| IndexEntry {
+            timestamp: at(day, hour, 0).and_utc(),
+            id: 0,
+            provider: "anthropic".to_string(),
+            model: Some(model.to_string()),
+            tokens: Some(100_000),
+            output_tokens: Some(0),
+            served_model: None,
+            service_tier: None,
+            requested_tier: None,
+            cost_usd,
+            unpriced: false,
+            no_context_limit: false,
+            status: Some(200),
+            latency_ms: None,
+            error: None,
+            response_ms: None,
+            throughput: None,
+            languages: Default::default(),
+            chaos: None,
+        };
+        let prices = PriceTable::new(&crate::config::Config::default().pricing);
+        let now = at(15, 12, 0).and_utc();
+
+        // Nothing archived yet
+        let empty = CostProjection::build(&[], &prices, &Utc, now);
+        assert_eq!(empty.projection.month_end, 0.0);
+        assert!(!empty.projection.weighted);
+
+        // A single day so far, one request priced now rather than recorded
+        let entries = [
+            entry(15, 6, "claude-3-5-sonnet", None),
+            entry(15, 9, "claude-3-5-sonnet", Some(0.5)),
+            entry(15, 10, "llama3:70b", None),
+        ];
+        let p = CostProjection::build(&entries, &prices, &Utc, now);
+        assert_eq!(p.unpriced, 1);
+        assert!((p.projection.month_to_date - 0.8).abs() < 1e-9);
+        // From 06:00 on, at 0.8 over 6 hours
+        assert!((p.projection.today - 2.4).abs() < 1e-9);
+        assert!(!p.projection.weighted);
+
+        // Earlier full days shape the hours, and the month runs on
+        let entries = [
+            entry(1, 9, "claude-3-5-sonnet", Some(1.0)),
+            entry(2, 9, "claude-3-5-sonnet", Some(2.0)),
+            entry(2, 18, "claude-3-5-sonnet", Some(2.0)),
+            entry(15, 9, "claude-3-5-sonnet", Some(1.0)),
+        ];
+        let p = CostProjection::build(&entries, &prices, &Utc, now);
+        assert!(p.projection.weighted);
+        assert!((p.projection.month_to_date - 6.0).abs() < 1e-9);
+        // Half of a day's usage comes after noon
+        assert!((p.projection.today - 2.0).abs() < 1e-9);
+        assert!(p.to_string().contains("  month to date   $6.00\n"));
+    }
+
+    #[test]
+    fn test_days_in_month() {
+        assert_eq!(
+            days_in_month(NaiveDate::from_ymd_opt(2024, 2, 10).unwrap()),
+            29
+        );
+        assert_eq!(
+            days_in_month(NaiveDate::from_ymd_opt(2023, 12, 31).unwrap()),
+            31
+        );
+        assert_eq!(
+            days_in_month(NaiveDate::from_ymd_opt(2024, 6, 1).unwrap()),
+            30
+        );
+    }
+}
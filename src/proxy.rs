@@ -1,87 +1,330 @@
 use anyhow::Result;
+use arc_swap::ArcSwap;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
 use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
 use http_body_util::{BodyExt, Full};
+use hyper::body::{Body, Frame};
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::{Method, Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 use tokio::net::TcpListener;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
+use tracing::Instrument;
 
-use crate::config::{ProviderConfig, ProxyConfig};
-use crate::event::RequestEvent;
-use crate::parser::{detect_provider, parse_request};
+use crate::chaos::{Chaos, EventCutter, Injected, CHAOS_HEADER};
+use crate::config::{PolicyConfig, ProviderConfig, ProxyConfig};
+use crate::context;
+use crate::event::{
+    Failover, InFlightRequest, Marker, ProxyEvent, RequestEvent, ResponseInfo, UploadProgress,
+    BLOCKED_PREFIX,
+};
+use crate::inspect;
+use crate::keys::{CapturedAuth, KeyFingerprinter};
+use crate::metrics::ProxyMetrics;
+use crate::parser::{
+    detect_body_format, detect_provider, minimal_event, parse_request, parse_request_with,
+    schema_drift, ParseError, ParseOptions, MAX_PARSE_BODY_BYTES,
+};
+use crate::phases::{Phase, PhaseTimer, RequestTimings};
+use crate::policy::{summarize, OutputCap, PolicyScanner};
+use crate::pricing::PriceTable;
+use crate::redact::Redactor;
+use crate::repo::RepoInfo;
+use crate::runtime::{RuntimeConfig, SharedRuntime};
+use crate::scrub::Scrubber;
+use crate::self_test;
+use crate::shaping::Shaper;
+use crate::sse::{AnthropicStreamTap, StreamedBlock};
+use crate::usage::{self, UsageTap};
+
+type ProxyBody = BoxBody<Bytes, std::io::Error>;
+
+/// Retry delays after a failed `accept()`
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// Source of per-process request ids
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Least time between two progress events for one request body upload
+const UPLOAD_PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Path prefix under which `sherlock claude` and friends pass session metadata
+const SESSION_PATH_PREFIX: &str = "/_sherlock/session/";
+
+/// Answered by the proxy itself: token counts for a body without sending it
+const ESTIMATE_PATH: &str = "/__sherlock/api/estimate";
+/// Answered by the proxy itself: adds a `sherlock mark` marker to the timeline
+pub const MARK_PATH: &str = "/__sherlock/api/mark";
+/// Answered by the proxy itself: the session totals `sherlock statusline` shows
+pub const STATUS_PATH: &str = "/__sherlock/api/status";
+
+/// Marks a request resent from the detail view; stripped before forwarding
+pub const MANUAL_HEADER: &str = "x-sherlock-manual";
+
+/// Metadata about the tool session a request came from. It travels as a
+/// path segment of the base URL handed to the tool, since the tool runs in a
+/// separate process from the proxy, and is stripped before forwarding.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionInfo {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repo: Option<RepoInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub overlay: Option<SessionOverlay>,
+}
+
+/// The project overlay a session's tool was launched under, with the parts
+/// of it the shared proxy applies to that session's requests
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionOverlay {
+    pub path: PathBuf,
+    /// Whether the session's requests are archived
+    pub archive: bool,
+    /// Content policy for the session's requests instead of the proxy's
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub policy: Option<PolicyConfig>,
+}
+
+impl SessionInfo {
+    /// Path to append to the proxy's base URL
+    pub fn to_path(&self) -> String {
+        let json = serde_json::to_vec(self).expect("session info serializes");
+        format!("{}{}", SESSION_PATH_PREFIX, URL_SAFE_NO_PAD.encode(json))
+    }
+
+    /// Split a session prefix off a request path, returning the decoded
+    /// session (if any) and the path to forward upstream
+    fn split_path(path: &str) -> (Option<Self>, &str) {
+        let Some(rest) = path.strip_prefix(SESSION_PATH_PREFIX) else {
+            return (None, path);
+        };
+        let (encoded, upstream_path) = match rest.find('/') {
+            Some(end) => rest.split_at(end),
+            None => (rest, "/"),
+        };
+        let session = URL_SAFE_NO_PAD
+            .decode(encoded)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok());
+        if session.is_none() {
+            tracing::warn!("Ignoring malformed session metadata in request path");
+        }
+        (session, upstream_path)
+    }
+}
+
+/// Tells a serving proxy to stop accepting connections and to close the
+/// open ones once the request each is serving has been answered
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle(Arc<watch::Sender<bool>>);
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.0.send_replace(true);
+    }
+}
+
+/// Resolves once `ShutdownHandle::shutdown` has been called
+async fn stop_requested(stopped: &mut watch::Receiver<bool>) {
+    let _ = stopped.wait_for(|stopped| *stopped).await;
+}
 
 /// HTTP proxy server that intercepts LLM API requests
 pub struct ProxyServer {
     config: ProxyConfig,
-    providers: Arc<HashMap<String, ProviderConfig>>,
-    client: reqwest::Client,
-    event_tx: mpsc::Sender<RequestEvent>,
+    runtime: SharedRuntime,
+    stop: Arc<watch::Sender<bool>>,
+    event_tx: mpsc::Sender<ProxyEvent>,
+    metrics: Arc<ProxyMetrics>,
+    keys: Arc<KeyFingerprinter>,
+    chaos: Option<Arc<Chaos>>,
+    scrubber: Option<Arc<Scrubber>>,
+    redactor: Arc<Redactor>,
 }
 
 impl ProxyServer {
     pub fn new(
         config: ProxyConfig,
         providers: HashMap<String, ProviderConfig>,
-        event_tx: mpsc::Sender<RequestEvent>,
-    ) -> Self {
-        let client = reqwest::Client::builder()
-            .pool_max_idle_per_host(10)
-            .build()
-            .expect("Failed to build HTTP client");
-
-        Self {
+        event_tx: mpsc::Sender<ProxyEvent>,
+        metrics: Arc<ProxyMetrics>,
+        keys: Arc<KeyFingerprinter>,
+        policy: Arc<PolicyScanner>,
+        prices: Arc<PriceTable>,
+    ) -> Result<Self> {
+        let runtime = RuntimeConfig::new(providers, policy, prices, &config)?;
+        metrics
+            .shaping()
+            .set_limits(config.max_upload_bytes_per_sec, config.max_download_bytes_per_sec);
+
+        Ok(Self {
             config,
-            providers: Arc::new(providers),
-            client,
+            runtime: Arc::new(ArcSwap::from_pointee(runtime)),
+            stop: Arc::new(watch::channel(false).0),
             event_tx,
-        }
+            metrics,
+            keys,
+            chaos: None,
+            scrubber: None,
+            redactor: Arc::default(),
+        })
     }
 
-    pub async fn run(self) -> Result<()> {
+    /// Inject `chaos`'s faults into the traffic. Unlike the rest of the
+    /// config, the rules stay as they are across reloads, so a seeded run
+    /// repeats.
+    pub fn with_chaos(mut self, chaos: Chaos) -> Self {
+        self.chaos = Some(Arc::new(chaos));
+        self
+    }
+
+    /// Pass every parsed request's messages through `scrubber` before the
+    /// event leaves the proxy. Like chaos rules, it stays across reloads.
+    pub fn with_scrubber(mut self, scrubber: Scrubber) -> Self {
+        self.scrubber = Some(Arc::new(scrubber));
+        self
+    }
+
+    /// Redact events with `redactor` instead of the default key patterns
+    pub fn with_redactor(mut self, redactor: Arc<Redactor>) -> Self {
+        self.redactor = redactor;
+        self
+    }
+
+    /// Handle for swapping in a new snapshot while the proxy runs
+    pub fn runtime(&self) -> SharedRuntime {
+        Arc::clone(&self.runtime)
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(Arc::clone(&self.stop))
+    }
+
+    /// Bind the configured address, ready for `serve`
+    pub async fn bind(&self) -> Result<TcpListener> {
         let addr = format!("{}:{}", self.config.bind_address, self.config.port);
         let listener = TcpListener::bind(&addr).await?;
 
         tracing::info!("Proxy server listening on {}", addr);
+        Ok(listener)
+    }
 
-        // Wrap shared state in Arc for cloning into tasks
-        let client = Arc::new(self.client);
-        let providers = self.providers;
+    /// Accept connections until shut down. Accept errors (e.g. EMFILE) are
+    /// retried with backoff instead of ending the proxy.
+    pub async fn serve(self, listener: TcpListener) {
+        let stop = self.stop;
+        let mut stopped = stop.subscribe();
+        let runtime = self.runtime;
         let event_tx = self.event_tx;
+        let metrics = self.metrics;
+        let keys = self.keys;
+        let chaos = self.chaos;
+        let scrubber = self.scrubber;
+        let redactor = self.redactor;
+        let max_connections = self.config.max_connections;
+        let mut backoff = ACCEPT_BACKOFF_MIN;
 
         loop {
-            let (stream, remote_addr) = listener.accept().await?;
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = stop_requested(&mut stopped) => {
+                    tracing::info!("Proxy no longer accepting connections");
+                    return;
+                }
+            };
+            let (stream, remote_addr) = match accepted {
+                Ok(accepted) => {
+                    backoff = ACCEPT_BACKOFF_MIN;
+                    accepted
+                }
+                Err(e) => {
+                    metrics.record_accept_error();
+                    tracing::warn!("Failed to accept connection, retrying in {:?}: {}", backoff, e);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                    continue;
+                }
+            };
             let io = TokioIo::new(stream);
 
             tracing::debug!("Accepted connection from {}", remote_addr);
 
+            let Some(guard) = metrics.open_connection(max_connections) else {
+                tracing::warn!(
+                    "Connection limit of {} reached, shedding {}",
+                    max_connections,
+                    remote_addr
+                );
+                tokio::spawn(async move {
+                    let service = service_fn(|_req| async {
+                        Ok::<_, hyper::Error>(
+                            Response::builder()
+                                .status(StatusCode::SERVICE_UNAVAILABLE)
+                                .header(hyper::header::CONNECTION, "close")
+                                .body(full("Too many connections"))
+                                .unwrap(),
+                        )
+                    });
+                    let _ = http1::Builder::new().serve_connection(io, service).await;
+                });
+                continue;
+            };
+
             // Clone for the spawned task
-            let client = Arc::clone(&client);
-            let providers = Arc::clone(&providers);
+            let runtime = Arc::clone(&runtime);
             let event_tx = event_tx.clone();
+            let metrics = Arc::clone(&metrics);
+            let keys = Arc::clone(&keys);
+            let chaos = chaos.clone();
+            let scrubber = scrubber.clone();
+            let redactor = Arc::clone(&redactor);
+            let mut stopped = stop.subscribe();
 
             tokio::spawn(async move {
-                let client = Arc::clone(&client);
-                let providers = Arc::clone(&providers);
-                let event_tx = event_tx.clone();
+                let _guard = guard;
 
                 let service = service_fn(move |req| {
-                    let client = Arc::clone(&client);
-                    let providers = Arc::clone(&providers);
+                    let runtime = Arc::clone(&runtime);
                     let event_tx = event_tx.clone();
+                    let metrics = Arc::clone(&metrics);
+                    let keys = Arc::clone(&keys);
+                    let chaos = chaos.clone();
+                    let scrubber = scrubber.clone();
+                    let redactor = Arc::clone(&redactor);
 
                     async move {
-                        handle_request(req, &client, &providers, event_tx).await
+                        // One snapshot for the whole request, even across a reload
+                        let runtime = runtime.load();
+                        let (chaos, scrubber) = (chaos.as_deref(), scrubber.as_deref());
+                        handle_request(
+                            req, &runtime, event_tx, &metrics, &keys, chaos, scrubber, &redactor,
+                        )
+                        .await
                     }
                 });
 
-                if let Err(e) = http1::Builder::new()
-                    .serve_connection(io, service)
-                    .await
-                {
+                let connection = http1::Builder::new().serve_connection(io, service);
+                tokio::pin!(connection);
+                let result = tokio::select! {
+                    result = connection.as_mut() => result,
+                    _ = stop_requested(&mut stopped) => {
+                        // Answer the request under way, then close instead of keeping alive
+                        connection.as_mut().graceful_shutdown();
+                        connection.await
+                    }
+                };
+                if let Err(e) = result {
                     tracing::error!("Connection error: {}", e);
                 }
             });
@@ -89,121 +332,1213 @@ impl ProxyServer {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn handle_request(
     req: Request<hyper::body::Incoming>,
-    client: &reqwest::Client,
-    providers: &HashMap<String, ProviderConfig>,
-    event_tx: mpsc::Sender<RequestEvent>,
-) -> Result<Response<Full<Bytes>>, hyper::Error> {
-    let method = req.method().clone();
-    let uri = req.uri().clone();
-    let headers = req.headers().clone();
+    runtime: &RuntimeConfig,
+    event_tx: mpsc::Sender<ProxyEvent>,
+    metrics: &ProxyMetrics,
+    keys: &KeyFingerprinter,
+    chaos: Option<&Chaos>,
+    scrubber: Option<&Scrubber>,
+    redactor: &Redactor,
+) -> Result<Response<ProxyBody>, hyper::Error> {
+    let RuntimeConfig {
+        providers,
+        clients,
+        policy,
+        prices,
+        shape_based_routing,
+        default_provider,
+        parsing,
+        capture_auth_headers,
+    } = runtime;
+    let (parts, body) = req.into_parts();
+    let (method, uri, mut headers) = (parts.method, parts.uri, parts.headers);
+    let manual = headers.remove(MANUAL_HEADER).is_some();
 
     let path = uri
         .path_and_query()
         .map(|pq| pq.as_str())
         .unwrap_or("/");
+    let (session, path) = SessionInfo::split_path(path);
 
     tracing::debug!("{} {}", method, path);
 
-    // Detect provider from path
-    let provider_name = detect_provider(path, providers);
-
-    if provider_name.is_none() {
-        tracing::warn!("Unknown provider for path: {}", path);
-        return Ok(Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .body(Full::new(Bytes::from("Unknown provider")))
-            .unwrap());
-    }
-
-    let provider_name = provider_name.unwrap();
-    let provider_config = providers.get(&provider_name).unwrap();
-
-    // Read body
-    let body_bytes = match req.collect().await {
-        Ok(collected) => collected.to_bytes(),
+    // Read body, reporting progress while a large one is still arriving.
+    // Sherlock's own endpoints only ever get small bodies.
+    let id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+    let mut timings = RequestTimings::default();
+    let mut detect = PhaseTimer::start(Phase::Detect, id);
+    let detected = detect.in_scope(|| detect_provider(path, providers));
+    detect.pause();
+    let (route, query) = path.split_once('?').unwrap_or((path, ""));
+    let api = [ESTIMATE_PATH, MARK_PATH, STATUS_PATH].contains(&route);
+    let mut upload = (!api).then(|| InFlightRequest {
+        id,
+        provider: detected.clone().unwrap_or_else(|| "unknown".to_string()),
+        model: None,
+        started_at: chrono::Utc::now(),
+        upload: Some(UploadProgress {
+            method: method.to_string(),
+            path: route.to_string(),
+            content_type: headers
+                .get(hyper::header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+            bytes: 0,
+        }),
+    });
+    let read = PhaseTimer::start(Phase::ReadBody, id);
+    let body = read_body(body, &mut upload, &event_tx)
+        .instrument(read.span().clone())
+        .await;
+    read.finish(&mut timings);
+    let mut body_bytes = match body {
+        Ok(bytes) => bytes,
         Err(e) => {
             tracing::error!("Failed to read request body: {}", e);
+            if upload.is_some() {
+                let error = format!("upload aborted: {}", e);
+                emit(
+                    &event_tx,
+                    ProxyEvent::Failed {
+                        id,
+                        error,
+                        event: None,
+                    },
+                );
+            }
             return Ok(Response::builder()
                 .status(StatusCode::BAD_REQUEST)
-                .body(Full::new(Bytes::from("Failed to read request body")))
+                .body(full("Failed to read request body"))
                 .unwrap());
         }
     };
 
-    // Parse request and emit event (non-blocking)
-    if !body_bytes.is_empty() {
-        match parse_request(&body_bytes, path, &provider_name) {
-            Ok(event) => {
-                if let Err(e) = event_tx.try_send(event) {
-                    tracing::warn!("Failed to send event: {}", e);
+    if route == ESTIMATE_PATH {
+        return Ok(estimate(
+            &method,
+            query,
+            &body_bytes,
+            providers,
+            prices,
+            &event_tx,
+        ));
+    }
+    if route == MARK_PATH {
+        let repo = session.as_ref().and_then(|s| s.repo.as_ref());
+        return Ok(mark(&method, &body_bytes, repo, &event_tx));
+    }
+    if route == STATUS_PATH {
+        return Ok(session_status(&method, metrics));
+    }
+
+    // Detect provider from path, falling back to the shape of the body. Requests
+    // recognised only by shape are labelled `unrouted:<format>` and forwarded
+    // when routing by shape is enabled and exactly one provider fits, or else
+    // to the default provider. Anything else goes to the default provider
+    // untouched.
+    detect.resume();
+    let matched = detected.is_some();
+    let (provider_name, format, target) = match detected {
+        Some(name) => {
+            let format = providers[&name].body_format(&name).to_string();
+            (name.clone(), format, Some(name))
+        }
+        None => {
+            let format = serde_json::from_slice::<serde_json::Value>(&body_bytes)
+                .ok()
+                .and_then(|body| detect_body_format(&body));
+            let Some(format) = format else {
+                let Some(default) = default_provider else {
+                    tracing::warn!("Unknown provider for path: {}", path);
+                    if upload.is_some() {
+                        let error = "unknown provider".to_string();
+                        emit(
+                            &event_tx,
+                            ProxyEvent::Failed {
+                                id,
+                                error,
+                                event: None,
+                            },
+                        );
+                    }
+                    return Ok(Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .body(full("Unknown provider"))
+                        .unwrap());
+                };
+                tracing::debug!(
+                    "No provider matches {}; passing it through to {}",
+                    path,
+                    default
+                );
+                if upload.is_some() {
+                    // Clears the upload's row without adding a request
+                    emit(&event_tx, ProxyEvent::Completed { id, event: None });
+                }
+                return Ok(pass_through(
+                    &clients[default],
+                    &providers[default],
+                    &method,
+                    &headers,
+                    path,
+                    &body_bytes,
+                    metrics.shaping(),
+                )
+                .await);
+            };
+            let target = shape_based_routing
+                .then(|| shape_route(providers, format))
+                .flatten()
+                .or_else(|| default_provider.clone());
+            tracing::info!(
+                "No provider matches {}; body looks like {}, {}",
+                path,
+                format,
+                match &target {
+                    Some(target) => format!("forwarding to {}", target),
+                    None => "not forwarding".to_string(),
                 }
+            );
+            (format!("unrouted:{}", format), format.to_string(), target)
+        }
+    };
+    detect.finish(&mut timings);
+
+    // Parse request; the full event is emitted once the response completes
+    let target_provider = target.as_ref().and_then(|name| providers.get(name));
+    let options = ParseOptions {
+        encoding: target_provider.and_then(|provider| provider.tokenizer),
+        deployment_models: target_provider
+            .map(|provider| provider.deployment_models.clone())
+            .unwrap_or_default(),
+        ..parsing.clone()
+    };
+    let parse = PhaseTimer::start(Phase::Parse, id);
+    let outcome = parse.in_scope(|| {
+        parse_body(
+            &method,
+            &body_bytes,
+            path,
+            &provider_name,
+            &format,
+            &options,
+            matched,
+        )
+    });
+    parse.finish(&mut timings);
+    let mut event = match outcome {
+        ParseOutcome::Skipped => {
+            tracing::debug!("Not parsing {} {}: no prompt in the body", method, path);
+            None
+        }
+        ParseOutcome::Parsed(mut event) => {
+            let drift = schema_drift(&event);
+            if !drift.is_empty() {
+                tracing::debug!("Schema drift from {}: {:?}", provider_name, drift);
+                metrics.record_schema_drift(&provider_name, &drift);
+            }
+            event.provider = provider_name.clone();
+            Some(*event)
+        }
+        ParseOutcome::Failed {
+            error,
+            level,
+            event,
+        } => {
+            metrics.record_parse_error(error.kind());
+            if level == tracing::Level::WARN {
+                tracing::warn!("Failed to parse request ({}): {}", error.kind(), error);
+            } else {
+                tracing::debug!("Not parsing {} ({}): {}", path, error.kind(), error);
+            }
+            event.map(|event| *event)
+        }
+    };
+
+    if let Some(event) = event.as_mut() {
+        event.api_version = api_version(&headers, uri.query());
+        event.key = keys.fingerprint_headers(&headers);
+        event.manual = manual;
+        if *capture_auth_headers {
+            event.auth = CapturedAuth::from_headers(&headers);
+        }
+        if let Some(session) = session.clone() {
+            event.repo = session.repo;
+            if let Some(overlay) = session.overlay {
+                event.overlay = Some(overlay.path);
+                event.unarchived = !overlay.archive;
             }
+        }
+        event.self_test = provider_name == self_test::PROVIDER;
+        event.context_overflow = context::check(event);
+        if let Some(overflow) = &event.context_overflow {
+            tracing::warn!(
+                "{} request to {} is over its context window: {}",
+                provider_name,
+                event.model,
+                overflow.describe()
+            );
+        }
+    }
+
+    // A session's overlay brings its own policy, checked when it was loaded
+    let session_policy = session
+        .and_then(|session| session.overlay?.policy)
+        .and_then(|policy| match PolicyScanner::new(&policy) {
+            Ok(scanner) => Some(scanner),
             Err(e) => {
-                tracing::warn!("Failed to parse request: {}", e);
+                tracing::warn!("Ignoring the session's overlay policy: {:#}", e);
+                None
             }
+        });
+    let policy = session_policy.as_ref().unwrap_or(policy);
+
+    // Content policy: report pattern names and counts, never the matched text
+    let mut blocked_by = Vec::new();
+    if let Some(event) = event.as_mut().filter(|_| !policy.is_empty()) {
+        let report = policy.scan(event);
+        if !report.matches.is_empty() {
+            tracing::warn!(
+                "Content policy matched in {} request: {}",
+                provider_name,
+                summarize(&report.matches)
+            );
+            metrics.record_policy_matches(&report.matches);
+            event.policy_matches = report.matches;
+            blocked_by = report.blocked_by;
         }
     }
 
-    // Forward to upstream
-    let upstream_url = format!("{}{}", provider_config.base_url, path);
+    // Output token cap: lower oversized limits, or refuse the request
+    let mut over_cap = None;
+    match policy.cap_output_tokens(&body_bytes) {
+        OutputCap::Within => {}
+        OutputCap::Clamped { clamp, body } => {
+            tracing::warn!(
+                "Clamped {} from {} to {} in {} request",
+                clamp.field,
+                clamp.requested,
+                clamp.limit,
+                provider_name
+            );
+            body_bytes = replace_body(&mut headers, body);
+            if let Some(event) = event.as_mut() {
+                event.output_clamp = Some(clamp);
+            }
+        }
+        OutputCap::Blocked(clamp) => {
+            tracing::warn!(
+                "Blocked {} request asking for {} {} (cap {})",
+                provider_name,
+                clamp.field,
+                clamp.requested,
+                clamp.limit
+            );
+            over_cap = Some(clamp);
+        }
+    }
 
-    let mut upstream_req = client.request(method_to_reqwest(&method), &upstream_url);
+    // Redacted and scrubbed after the policy scan, which must see what goes
+    // upstream. Keys never reach the scrubber.
+    if let Some(event) = event.as_mut() {
+        redactor.redact_event(event);
+        if let Some(scrubber) = scrubber {
+            scrubber.scrub(event).await;
+        }
+    }
 
-    // Copy headers, skipping hop-by-hop headers
-    for (name, value) in headers.iter() {
-        let name_str = name.as_str().to_lowercase();
-        if !is_hop_by_hop_header(&name_str) {
-            if let Ok(value_str) = value.to_str() {
-                upstream_req = upstream_req.header(name.as_str(), value_str);
+    if let Some(event) = event.as_mut() {
+        event.id = id;
+        event.timings = Some(timings);
+    }
+    emit(
+        &event_tx,
+        ProxyEvent::Started(InFlightRequest {
+            id,
+            provider: provider_name.clone(),
+            model: event.as_ref().map(|e| e.model.clone()),
+            started_at: chrono::Utc::now(),
+            upload: None,
+        }),
+    );
+
+    if !blocked_by.is_empty() {
+        let message = format!(
+            "Request blocked by sherlock content policy ({})",
+            blocked_by.join(", ")
+        );
+        emit(
+            &event_tx,
+            ProxyEvent::Failed {
+                id,
+                error: format!("{}{}", BLOCKED_PREFIX, blocked_by.join(", ")),
+                event: None,
+            },
+        );
+        return Ok(policy_error(&message));
+    }
+    if let Some(clamp) = over_cap {
+        let message = format!(
+            "Request blocked by sherlock output token cap ({} {} exceeds {})",
+            clamp.field, clamp.requested, clamp.limit
+        );
+        emit(
+            &event_tx,
+            ProxyEvent::Failed {
+                id,
+                error: format!("{}{} > {}", BLOCKED_PREFIX, clamp.field, clamp.limit),
+                event: None,
+            },
+        );
+        return Ok(policy_error(&message));
+    }
+    let Some(target) = target else {
+        emit(
+            &event_tx,
+            ProxyEvent::Completed {
+                id,
+                event: event.map(Box::new),
+            },
+        );
+        return Ok(Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(full(format!(
+                "Unknown provider (body looks like {}; enable proxy.shape_based_routing \
+                 or set proxy.default_provider to forward it)",
+                format
+            )))
+            .unwrap());
+    };
+
+    // Chaos mode: answer with an error instead, or hold the request first.
+    // Stream cuts are flagged once they happen.
+    let streaming = event.as_ref().is_some_and(|event| event.streaming);
+    let injected = chaos.and_then(|chaos| chaos.pick(&target, streaming));
+    if let Some(injected) = injected {
+        tracing::warn!("Chaos: {} for {} request {}", injected, target, id);
+        if let Some(event) = event.as_mut() {
+            if !matches!(injected, Injected::Truncate { .. }) {
+                event.chaos = Some(injected.to_string());
+            }
+        }
+    }
+    match injected {
+        Some(Injected::Status(status)) => {
+            if let Some(event) = event.as_mut() {
+                event.response = Some(ResponseInfo {
+                    status,
+                    latency_ms: 0,
+                });
             }
+            emit(
+                &event_tx,
+                ProxyEvent::Completed {
+                    id,
+                    event: event.map(Box::new),
+                },
+            );
+            return Ok(chaos_error(status));
         }
+        Some(Injected::Delay(delay)) => tokio::time::sleep(delay).await,
+        _ => {}
     }
 
-    // Set body (use Bytes directly to avoid copy)
-    upstream_req = upstream_req.body(body_bytes.clone());
+    // Requests that come without a key get the provider's configured one
+    if CapturedAuth::from_headers(&headers).is_empty() {
+        let injected = providers
+            .get(&target)
+            .and_then(|provider| provider.injected_auth(&target, |name| std::env::var(name).ok()));
+        if let Some((name, value)) = injected {
+            match hyper::header::HeaderValue::from_str(&value) {
+                Ok(value) => {
+                    headers.insert(name, value);
+                }
+                Err(_) => tracing::warn!("Not injecting {}'s key: not a valid header", target),
+            }
+        }
+    }
+
+    // Forward to upstream, falling back to other providers if configured
+    let forwarded_at = Instant::now();
+    let upstream = PhaseTimer::start(Phase::Upstream, id);
+    let (upstream_result, attempted) =
+        send_with_failover(
+            clients,
+            providers,
+            &target,
+            &method,
+            &headers,
+            path,
+            &body_bytes,
+            metrics.shaping(),
+        )
+        .instrument(upstream.span().clone())
+        .await;
+    upstream.finish(&mut timings);
+    let failover = (attempted.len() > 1).then(|| Failover {
+        served_by: attempted[attempted.len() - 1].clone(),
+        attempted,
+    });
 
-    // Send request
-    let upstream_resp = match upstream_req.send().await {
+    let upstream_resp = match upstream_result {
         Ok(resp) => resp,
         Err(e) => {
             tracing::error!("Upstream request failed: {}", e);
+            let error = match &failover {
+                Some(failover) => {
+                    format!("upstream error via {}: {}", failover.attempted.join(" → "), e)
+                }
+                None => format!("upstream error: {}", e),
+            };
+            if let Some(event) = event.as_mut() {
+                event.failover = failover;
+                event.timings = Some(timings);
+            }
+            let event = event.map(Box::new);
+            emit(&event_tx, ProxyEvent::Failed { id, error, event });
             return Ok(Response::builder()
                 .status(StatusCode::BAD_GATEWAY)
-                .body(Full::new(Bytes::from(format!("Upstream error: {}", e))))
+                .body(full(format!("Upstream error: {}", e)))
                 .unwrap());
         }
     };
 
     // Build response
     let status = upstream_resp.status();
+    if let Some(event) = event.as_mut() {
+        event.failover = failover;
+        event.response = Some(ResponseInfo {
+            status: status.as_u16(),
+            latency_ms: forwarded_at.elapsed().as_millis() as u64,
+        });
+    }
+
     let resp_headers = upstream_resp.headers().clone();
+    let mut response = response_head(&upstream_resp);
+    if let Some(injected) = injected {
+        response = response.header(CHAOS_HEADER, injected.to_string());
+    }
 
-    let resp_body = match upstream_resp.bytes().await {
-        Ok(bytes) => bytes,
+    // Observe Anthropic event streams without touching the relayed bytes
+    let is_event_stream = usage::is_event_stream(
+        event.as_ref().is_some_and(|event| event.streaming),
+        resp_headers
+            .get(hyper::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok()),
+    );
+    let tap = (is_event_stream && format == "anthropic").then(AnthropicStreamTap::new);
+    let usage = UsageTap::new(is_event_stream);
+    let cut_after = match injected {
+        Some(Injected::Truncate { after_events }) if is_event_stream => Some(after_events),
+        _ => None,
+    };
+
+    // Relay the body chunk by chunk as it arrives
+    let (body_tx, body_rx) = mpsc::channel(16);
+    let completion = Completion {
+        id,
+        event,
+        event_tx,
+        forwarded_at,
+        is_event_stream,
+        cut_after,
+        prices: Arc::clone(prices),
+        timings,
+        waiting: PhaseTimer::start(Phase::FirstByte, id),
+    };
+    tokio::spawn(relay_upstream(
+        upstream_resp,
+        body_tx,
+        tap,
+        usage,
+        completion,
+        Arc::clone(metrics.shaping()),
+    ));
+
+    Ok(response.body(RelayBody { rx: body_rx }.boxed()).unwrap())
+}
+
+/// What parsing a proxied request's body came to
+#[derive(Debug)]
+enum ParseOutcome {
+    /// No prompt to parse: the method carries none, or the body is blank or
+    /// `{}`, as health checks and preflights send
+    Skipped,
+    Parsed(Box<RequestEvent>),
+    /// `level` is what the error is worth logging at; `event` stands in for
+    /// a body that isn't JSON, so the request still shows up
+    Failed {
+        error: ParseError,
+        level: tracing::Level,
+        event: Option<Box<RequestEvent>>,
+    },
+}
+
+/// Parse a request body sent with `method` to `path`, `matched` when the
+/// path named a provider rather than the body's shape. Only a malformed
+/// body POSTed to a provider's path is worth a warning; anything else that
+/// fails to parse is noise from clients probing the proxy.
+fn parse_body(
+    method: &Method,
+    body: &[u8],
+    path: &str,
+    provider: &str,
+    format: &str,
+    options: &ParseOptions,
+    matched: bool,
+) -> ParseOutcome {
+    if matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) || is_empty_body(body) {
+        return ParseOutcome::Skipped;
+    }
+    match parse_request_with(body, path, format, options) {
+        Ok(event) => ParseOutcome::Parsed(Box::new(event)),
+        Err(error) => {
+            let level = if error.is_malformed() && matched && *method == Method::POST {
+                tracing::Level::WARN
+            } else {
+                tracing::Level::DEBUG
+            };
+            let event = error
+                .is_malformed()
+                .then(|| Box::new(minimal_event(body, path, provider)));
+            ParseOutcome::Failed {
+                error,
+                level,
+                event,
+            }
+        }
+    }
+}
+
+/// Blank, or an object with nothing in it
+fn is_empty_body(body: &[u8]) -> bool {
+    let body = body.trim_ascii();
+    let inside = body
+        .strip_prefix(b"{")
+        .and_then(|body| body.strip_suffix(b"}"))
+        .unwrap_or(body);
+    inside.iter().all(u8::is_ascii_whitespace)
+}
+
+/// `POST /__sherlock/api/estimate` body
+#[derive(Debug, Deserialize)]
+struct EstimateRequest {
+    provider: String,
+    /// The request body as it would be sent upstream
+    body: serde_json::Value,
+    /// Upstream path, for providers that put the model there; defaults to
+    /// the provider's main path
+    #[serde(default)]
+    path: Option<String>,
+}
+
+/// Parse a body the way the proxy would and report its tokens, forwarding
+/// nothing. With `?record=true` it is also recorded like proxied traffic.
+fn estimate(
+    method: &Method,
+    query: &str,
+    body: &[u8],
+    providers: &HashMap<String, ProviderConfig>,
+    prices: &PriceTable,
+    event_tx: &mpsc::Sender<ProxyEvent>,
+) -> Response<ProxyBody> {
+    if method != Method::POST {
+        return api_error(StatusCode::METHOD_NOT_ALLOWED, "use POST");
+    }
+    // The wrapper is a few bytes over the body it carries
+    if body.len() > MAX_PARSE_BODY_BYTES {
+        let e = ParseError::OversizedBody {
+            size: body.len(),
+            limit: MAX_PARSE_BODY_BYTES,
+        };
+        return api_error(StatusCode::PAYLOAD_TOO_LARGE, &e.to_string());
+    }
+    let request: EstimateRequest = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(e) => return api_error(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+    let Some(provider) = providers.get(&request.provider) else {
+        let message = format!("unknown provider {:?}", request.provider);
+        return api_error(StatusCode::BAD_REQUEST, &message);
+    };
+    let path = request.path.as_deref().unwrap_or(provider.main_path());
+    let raw = serde_json::to_vec(&request.body).expect("JSON values serialize");
+
+    let mut event = match parse_request(&raw, path, provider.body_format(&request.provider)) {
+        Ok(event) => event,
         Err(e) => {
-            tracing::error!("Failed to read upstream response: {}", e);
-            return Ok(Response::builder()
-                .status(StatusCode::BAD_GATEWAY)
-                .body(Full::new(Bytes::from("Failed to read upstream response")))
-                .unwrap());
+            let status = match e {
+                ParseError::OversizedBody { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+                _ => StatusCode::UNPROCESSABLE_ENTITY,
+            };
+            return api_error(status, &format!("{}: {}", e.kind(), e));
         }
     };
+    event.provider = request.provider;
+    let report = inspect::report(&event, prices);
 
-    let mut response = Response::builder().status(status.as_u16());
+    if query.split('&').any(|pair| pair == "record=true") {
+        let id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+        event.id = id;
+        emit(
+            event_tx,
+            ProxyEvent::Started(InFlightRequest {
+                id,
+                provider: event.provider.clone(),
+                model: Some(event.model.clone()),
+                started_at: event.timestamp,
+                upload: None,
+            }),
+        );
+        emit(
+            event_tx,
+            ProxyEvent::Completed {
+                id,
+                event: Some(Box::new(event)),
+            },
+        );
+    }
+
+    Response::builder()
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(full(serde_json::to_vec(&report).expect("report serializes")))
+        .unwrap()
+}
+
+/// `POST /__sherlock/api/mark` body
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MarkRequest {
+    pub label: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    /// Defaults to the repository of the session path the request came in on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session: Option<String>,
+}
 
-    // Copy response headers
-    for (name, value) in resp_headers.iter() {
+/// Timestamp a marker and hand it to the dashboard or recording, replying
+/// with the marker as recorded
+fn mark(
+    method: &Method,
+    body: &[u8],
+    repo: Option<&RepoInfo>,
+    event_tx: &mpsc::Sender<ProxyEvent>,
+) -> Response<ProxyBody> {
+    if method != Method::POST {
+        return api_error(StatusCode::METHOD_NOT_ALLOWED, "use POST");
+    }
+    let request: MarkRequest = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(e) => return api_error(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+    if request.label.trim().is_empty() {
+        return api_error(StatusCode::BAD_REQUEST, "empty label");
+    }
+    let marker = Marker {
+        label: request.label.trim().to_string(),
+        timestamp: chrono::Utc::now(),
+        tag: request.tag,
+        session: request.session.or_else(|| repo.map(RepoInfo::name)),
+    };
+    let reply = serde_json::to_vec(&marker).expect("markers serialize");
+    emit(event_tx, ProxyEvent::Marker(marker));
+    Response::builder()
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(full(reply))
+        .unwrap()
+}
+
+fn session_status(method: &Method, metrics: &ProxyMetrics) -> Response<ProxyBody> {
+    if method != Method::GET {
+        return api_error(StatusCode::METHOD_NOT_ALLOWED, "use GET");
+    }
+    let mut status = metrics.status();
+    status.parse_errors = metrics
+        .parse_errors()
+        .into_iter()
+        .map(|(kind, count)| (kind.to_string(), count))
+        .collect();
+    let reply = serde_json::to_vec(&status).expect("statuses serialize");
+    Response::builder()
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(full(reply))
+        .unwrap()
+}
+
+/// The answer to a request chaos mode fails with `status`
+fn chaos_error(status: u16) -> Response<ProxyBody> {
+    let message = format!("Injected by sherlock chaos mode: status {}", status);
+    let body = serde_json::json!({
+        "type": "error",
+        "error": {"type": "sherlock_chaos", "message": message},
+    });
+    let mut response = Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .header(CHAOS_HEADER, format!("status {}", status));
+    if matches!(status, 429 | 503) {
+        response = response.header(hyper::header::RETRY_AFTER, "1");
+    }
+    response.body(full(body.to_string())).unwrap()
+}
+
+fn api_error(status: StatusCode, message: &str) -> Response<ProxyBody> {
+    Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(full(serde_json::json!({ "error": message }).to_string()))
+        .unwrap()
+}
+
+/// The one provider speaking `format`, if exactly one does
+fn shape_route(providers: &HashMap<String, ProviderConfig>, format: &str) -> Option<String> {
+    let mut matching = providers
+        .iter()
+        .filter(|(name, provider)| provider.body_format(name) == format);
+    match (matching.next(), matching.next()) {
+        (Some((name, _)), None) => Some(name.clone()),
+        _ => None,
+    }
+}
+
+/// Send the request to `provider_name`, moving down its fallback chain on a
+/// connect error or one of its `failover_statuses`. Nothing has been relayed
+/// to the client yet, so retries are invisible to it. Returns the final
+/// response or error together with every provider tried.
+#[allow(clippy::too_many_arguments)]
+async fn send_with_failover(
+    clients: &HashMap<String, reqwest::Client>,
+    providers: &HashMap<String, ProviderConfig>,
+    provider_name: &str,
+    method: &Method,
+    headers: &hyper::HeaderMap,
+    path: &str,
+    body: &Bytes,
+    shaper: &Arc<Shaper>,
+) -> (reqwest::Result<reqwest::Response>, Vec<String>) {
+    let primary = &providers[provider_name];
+    let mut fallbacks = primary.fallbacks.iter();
+    let mut provider = primary;
+    let mut attempted = vec![provider_name.to_string()];
+
+    loop {
+        let client = &clients[&attempted[attempted.len() - 1]];
+        let result = send_upstream(client, provider, method, headers, path, body, shaper).await;
+        let reason = match &result {
+            Ok(resp) if primary.failover_statuses.contains(&resp.status().as_u16()) => {
+                format!("status {}", resp.status())
+            }
+            Err(e) if e.is_connect() => e.to_string(),
+            _ => return (result, attempted),
+        };
+        let Some((next, next_provider)) = fallbacks
+            .next()
+            .and_then(|name| providers.get_key_value(name))
+        else {
+            return (result, attempted);
+        };
+
+        tracing::warn!(
+            "{} failed ({}), failing over to {}",
+            attempted[attempted.len() - 1],
+            reason,
+            next
+        );
+        provider = next_provider;
+        attempted.push(next.clone());
+    }
+}
+
+/// Forward a request no provider claims to `provider` as it is, relaying the
+/// response without parsing or recording either
+async fn pass_through(
+    client: &reqwest::Client,
+    provider: &ProviderConfig,
+    method: &Method,
+    headers: &hyper::HeaderMap,
+    path: &str,
+    body: &Bytes,
+    shaper: &Arc<Shaper>,
+) -> Response<ProxyBody> {
+    let mut upstream =
+        match send_upstream(client, provider, method, headers, path, body, shaper).await {
+            Ok(resp) => resp,
+            Err(e) => {
+                tracing::debug!("Passing {} through failed: {}", path, e);
+                return Response::builder()
+                    .status(StatusCode::BAD_GATEWAY)
+                    .body(full(format!("Upstream error: {}", e)))
+                    .unwrap();
+            }
+        };
+    let response = response_head(&upstream);
+    let (body_tx, body_rx) = mpsc::channel(16);
+    let shaper = Arc::clone(shaper);
+    tokio::spawn(async move {
+        loop {
+            match upstream.chunk().await {
+                Ok(Some(chunk)) => {
+                    if !send_paced(&body_tx, chunk, &shaper).await {
+                        return;
+                    }
+                }
+                Ok(None) => return,
+                Err(e) => {
+                    let _ = body_tx.send(Err(std::io::Error::other(e))).await;
+                    return;
+                }
+            }
+        }
+    });
+    response.body(RelayBody { rx: body_rx }.boxed()).unwrap()
+}
+
+/// The client's response so far: the upstream's status and headers. The
+/// body is relayed as received, so a compressed one keeps its
+/// content-encoding.
+fn response_head(upstream: &reqwest::Response) -> hyper::http::response::Builder {
+    let mut response = Response::builder().status(upstream.status().as_u16());
+    for (name, value) in upstream.headers() {
         let name_str = name.as_str().to_lowercase();
-        if !is_hop_by_hop_header(&name_str) && name_str != "content-encoding" {
+        if !is_hop_by_hop_header(&name_str) {
             if let Ok(value_str) = value.to_str() {
                 response = response.header(name.as_str(), value_str);
             }
         }
     }
+    response
+}
 
-    Ok(response.body(Full::new(resp_body)).unwrap())
+async fn send_upstream(
+    client: &reqwest::Client,
+    provider: &ProviderConfig,
+    method: &Method,
+    headers: &hyper::HeaderMap,
+    path: &str,
+    body: &Bytes,
+    shaper: &Arc<Shaper>,
+) -> reqwest::Result<reqwest::Response> {
+    let upstream_url = format!("{}{}", provider.base_url, path);
+
+    let mut upstream_req = client.request(method_to_reqwest(method), &upstream_url);
+
+    // Copy headers, skipping hop-by-hop headers
+    for (name, value) in headers.iter() {
+        let name_str = name.as_str().to_lowercase();
+        if !is_hop_by_hop_header(&name_str) {
+            if let Ok(value_str) = value.to_str() {
+                upstream_req = upstream_req.header(name.as_str(), value_str);
+            }
+        }
+    }
+
+    // Set body (Bytes clones are cheap, no copy). The content-length header
+    // copied above still applies when the body is paced.
+    if shaper.upload.rate().is_none() {
+        shaper.upload.acquire(body.len()).await;
+        return upstream_req.body(body.clone()).send().await;
+    }
+    upstream_req.body(paced_body(body.clone(), Arc::clone(shaper))).send().await
+}
+
+/// Request body fed to upstream at the configured upload rate
+fn paced_body(body: Bytes, shaper: Arc<Shaper>) -> reqwest::Body {
+    let (tx, rx) = mpsc::channel(4);
+    tokio::spawn(async move {
+        for piece in shaper.upload.slices(body) {
+            shaper.upload.acquire(piece.len()).await;
+            if tx.send(Ok(piece)).await.is_err() {
+                return;
+            }
+        }
+    });
+    reqwest::Body::wrap(RelayBody { rx })
+}
+
+/// What to report once a relayed response is done
+struct Completion {
+    id: u64,
+    event: Option<RequestEvent>,
+    event_tx: mpsc::Sender<ProxyEvent>,
+    /// When the request went upstream, for its latency
+    forwarded_at: Instant,
+    /// Streams are timed to their first byte rather than their end
+    is_event_stream: bool,
+    /// Events chaos mode lets through before cutting the stream off
+    cut_after: Option<usize>,
+    /// Rates the request is priced at once its output is known
+    prices: Arc<PriceTable>,
+    /// Phases timed so far
+    timings: RequestTimings,
+    /// Times the wait for the first byte of the body
+    waiting: PhaseTimer,
+}
+
+/// Forward upstream chunks to the client body, feeding the taps a view of each
+async fn relay_upstream(
+    mut upstream: reqwest::Response,
+    body_tx: mpsc::Sender<std::io::Result<Bytes>>,
+    mut tap: Option<AnthropicStreamTap>,
+    mut usage: UsageTap,
+    completion: Completion,
+    shaper: Arc<Shaper>,
+) {
+    let mut client_aborted = false;
+    let mut first_byte = None;
+    let mut cutter = completion.cut_after.map(EventCutter::new);
+    let mut cut = false;
+    let mut timings = completion.timings;
+    let mut waiting = Some(completion.waiting);
+    let mut relaying = None;
+    loop {
+        // Watch for the client hanging up even while upstream is quiet, so a
+        // slow stream isn't kept open until its next chunk
+        let chunk = tokio::select! {
+            chunk = upstream.chunk() => chunk,
+            _ = body_tx.closed() => {
+                client_aborted = true;
+                break;
+            }
+        };
+        match chunk {
+            Ok(Some(mut chunk)) => {
+                first_byte.get_or_insert_with(Instant::now);
+                if let Some(waiting) = waiting.take() {
+                    waiting.finish(&mut timings);
+                    relaying = Some(PhaseTimer::start(Phase::Relay, completion.id));
+                }
+                let cut_at = cutter.as_mut().and_then(|cutter| cutter.cut(&chunk));
+                if let Some(cut_at) = cut_at {
+                    chunk.truncate(cut_at);
+                }
+                if let Some(tap) = tap.as_mut() {
+                    tap.observe(&chunk);
+                }
+                usage.observe(&chunk);
+                if !send_paced(&body_tx, chunk, &shaper).await {
+                    client_aborted = true;
+                    break;
+                }
+                if cut_at.is_some() {
+                    // End the response cleanly: an error body would have
+                    // hyper drop the connection before the events relayed so
+                    // far reach the client
+                    cut = true;
+                    break;
+                }
+            }
+            Ok(None) => break,
+            Err(e) => {
+                tracing::error!("Failed to read upstream response: {}", e);
+                let _ = body_tx.send(Err(std::io::Error::other(e))).await;
+                // The failure stands for the outcome in the index, so the
+                // archived request doesn't also count as answered
+                let event = completion.event.map(|mut event| {
+                    event.response = None;
+                    Box::new(event)
+                });
+                emit(
+                    &completion.event_tx,
+                    ProxyEvent::Failed {
+                        id: completion.id,
+                        error: "upstream response interrupted".to_string(),
+                        event,
+                    },
+                );
+                return;
+            }
+        }
+    }
+
+    // A body that never came is timed to its end
+    for timer in waiting.into_iter().chain(relaying) {
+        timer.finish(&mut timings);
+    }
+    let mut event = completion.event;
+    if let (Some(event), Some(tap)) = (event.as_mut(), tap.as_ref()) {
+        event.throughput = tap.throughput();
+    }
+    if let (Some(event), Some(after_events)) = (event.as_mut(), completion.cut_after) {
+        if cut {
+            event.chaos = Some(Injected::Truncate { after_events }.to_string());
+        }
+    }
+    if let Some(event) = event.as_mut() {
+        let rejected = event.response.is_some_and(|response| response.status >= 400);
+        if let Some(mut overflow) = usage.context_error().filter(|_| rejected) {
+            // Fill in what the provider's message left out from sherlock's own count
+            overflow.tokens = overflow.tokens.or(Some(context::counted_tokens(event)));
+            overflow.limit = overflow.limit.or_else(|| context::context_limit(&event.model));
+            event.context_overflow = Some(overflow);
+        }
+        let usage = usage.finish();
+        event.output = usage.output;
+        event.served_model = usage.model.filter(|served| *served != event.model);
+        event.served_tier = usage.service_tier;
+        event.response_text = usage.text;
+        event.gemini_usage = usage.gemini;
+        event.cost_usd = completion.prices.cost(event);
+        if let Some(served) = event.substituted_model() {
+            tracing::warn!("Requested {} but {} served {}", event.model, event.provider, served);
+        }
+        if let (Some(requested), Some(served)) = (event.tier_mismatch(), &event.served_tier) {
+            tracing::warn!(
+                "Requested the {} tier but {} served {}",
+                requested,
+                event.provider,
+                served
+            );
+        }
+        let done = match first_byte {
+            Some(first_byte) if completion.is_event_stream => first_byte,
+            _ => Instant::now(),
+        };
+        event.latency_ms = Some(done.duration_since(completion.forwarded_at).as_millis() as u64);
+        event.timings = Some(timings);
+    }
+    if client_aborted {
+        tracing::debug!("Client went away, dropping upstream response");
+        drop(upstream);
+        if let Some(event) = event.as_mut() {
+            event.client_aborted = true;
+        }
+    }
+
+    emit(
+        &completion.event_tx,
+        ProxyEvent::Completed {
+            id: completion.id,
+            event: event.map(Box::new),
+        },
+    );
+
+    if let Some(tap) = tap {
+        for block in tap.finish() {
+            if let StreamedBlock::ToolUse { id, name, input } = block {
+                match input {
+                    Ok(input) => tracing::debug!("Streamed tool call {} {}({})", id, name, input),
+                    Err(raw) => tracing::warn!(
+                        "Streamed tool call {} {} has incomplete input ({} bytes)",
+                        id,
+                        name,
+                        raw.len()
+                    ),
+                }
+            }
+        }
+    }
+}
+
+/// Relay `chunk` to the client at the configured download rate. False once
+/// the client has gone away.
+async fn send_paced(
+    body_tx: &mpsc::Sender<std::io::Result<Bytes>>,
+    chunk: Bytes,
+    shaper: &Shaper,
+) -> bool {
+    for piece in shaper.download.slices(chunk) {
+        shaper.download.acquire(piece.len()).await;
+        if body_tx.send(Ok(piece)).await.is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+/// Response body fed by `relay_upstream`
+struct RelayBody {
+    rx: mpsc::Receiver<std::io::Result<Bytes>>,
+}
+
+impl Body for RelayBody {
+    type Data = Bytes;
+    type Error = std::io::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Self::Error>>> {
+        self.rx
+            .poll_recv(cx)
+            .map(|chunk| chunk.map(|result| result.map(Frame::data)))
+    }
+}
+
+/// Read a whole request body. While `upload` is set and the body hasn't
+/// arrived in one piece, its progress is sent as `ProxyEvent::Uploading`,
+/// at most once per `UPLOAD_PROGRESS_INTERVAL`; `upload` is cleared if none
+/// was sent.
+async fn read_body(
+    mut body: hyper::body::Incoming,
+    upload: &mut Option<InFlightRequest>,
+    event_tx: &mpsc::Sender<ProxyEvent>,
+) -> Result<Bytes, hyper::Error> {
+    let mut bytes = Vec::new();
+    let mut reported: Option<Instant> = None;
+    while let Some(frame) = body.frame().await {
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(e) => {
+                if reported.is_none() {
+                    *upload = None;
+                }
+                return Err(e);
+            }
+        };
+        if let Ok(data) = frame.into_data() {
+            bytes.extend_from_slice(&data);
+        }
+        let Some(request) = upload.as_mut() else {
+            continue;
+        };
+        if body.is_end_stream()
+            || reported.is_some_and(|at| at.elapsed() < UPLOAD_PROGRESS_INTERVAL)
+        {
+            continue;
+        }
+        if let Some(progress) = request.upload.as_mut() {
+            progress.bytes = bytes.len() as u64;
+        }
+        emit(event_tx, ProxyEvent::Uploading(request.clone()));
+        reported = Some(Instant::now());
+    }
+    if reported.is_none() {
+        *upload = None;
+    }
+    Ok(Bytes::from(bytes))
+}
+
+/// Send a lifecycle event to the dashboard without blocking the request path
+fn emit(event_tx: &mpsc::Sender<ProxyEvent>, event: ProxyEvent) {
+    if let Err(e) = event_tx.try_send(event) {
+        tracing::warn!("Failed to send event: {}", e);
+    }
+}
+
+/// Swap in a rewritten request body, keeping Content-Length in step
+fn replace_body(headers: &mut hyper::HeaderMap, body: Vec<u8>) -> Bytes {
+    headers.insert(hyper::header::CONTENT_LENGTH, body.len().into());
+    Bytes::from(body)
+}
+
+/// Provider-style JSON error for a request the content policy or output token cap rejected
+fn policy_error(message: &str) -> Response<ProxyBody> {
+    let body = serde_json::json!({
+        "type": "error",
+        "error": {"type": "policy_error", "message": message},
+    });
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(full(body.to_string()))
+        .unwrap()
+}
+
+fn full(body: impl Into<Bytes>) -> ProxyBody {
+    Full::new(body.into()).map_err(|never| match never {}).boxed()
 }
 
 fn method_to_reqwest(method: &Method) -> reqwest::Method {
@@ -219,6 +1554,19 @@ fn method_to_reqwest(method: &Method) -> reqwest::Method {
     }
 }
 
+/// API version requested by the client, from headers or Azure's `api-version` query
+fn api_version(headers: &hyper::HeaderMap, query: Option<&str>) -> Option<String> {
+    for name in ["anthropic-version", "openai-beta"] {
+        if let Some(value) = headers.get(name).and_then(|v| v.to_str().ok()) {
+            return Some(value.to_string());
+        }
+    }
+    query?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("api-version="))
+        .map(str::to_string)
+}
+
 fn is_hop_by_hop_header(name: &str) -> bool {
     matches!(
         name,
@@ -233,3 +1581,1205 @@ fn is_hop_by_hop_header(name: &str) -> bool {
             | "host"
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::OutputTokens;
+
+    #[test]
+    fn test_parse_body_by_method_and_body() {
+        let options = ParseOptions::default();
+        let parse = |method: Method, body: &[u8], matched| match parse_body(
+            &method,
+            body,
+            "/v1/messages",
+            "anthropic",
+            "anthropic",
+            &options,
+            matched,
+        ) {
+            ParseOutcome::Skipped => "skipped".to_string(),
+            ParseOutcome::Parsed(event) => format!("event {}", event.model),
+            ParseOutcome::Failed {
+                error,
+                level,
+                event,
+            } => format!(
+                "{} {}{}",
+                level,
+                error.kind(),
+                if event.is_some() { " with event" } else { "" }
+            ),
+        };
+        let prompt =
+            br#"{"model": "claude-sonnet-4-5", "messages": [{"role": "user", "content": "hi"}]}"#;
+
+        // Health checks and preflights are never parsed, whatever they carry
+        for method in [Method::GET, Method::HEAD, Method::OPTIONS] {
+            for body in [&b""[..], b"{}", b"not json", prompt] {
+                assert_eq!(parse(method.clone(), body, true), "skipped");
+            }
+        }
+        // Bodies with nothing in them make no event, and log nothing above debug
+        for body in [&b""[..], b"  \r\n", b"{}", b"{ \n }"] {
+            assert_eq!(parse(Method::POST, body, true), "skipped");
+            assert_eq!(parse(Method::POST, body, false), "skipped");
+        }
+        assert_eq!(parse(Method::POST, prompt, true), "event claude-sonnet-4-5");
+
+        // Only a malformed body POSTed to a provider's path is a warning
+        assert_eq!(
+            parse(Method::POST, b"not json", true),
+            "WARN NotJson with event"
+        );
+        assert_eq!(
+            parse(Method::POST, b"\xff\xfe", true),
+            "WARN InvalidUtf8 with event"
+        );
+        assert_eq!(
+            parse(Method::POST, b"not json", false),
+            "DEBUG NotJson with event"
+        );
+        assert_eq!(
+            parse(Method::PUT, b"not json", true),
+            "DEBUG NotJson with event"
+        );
+        assert_eq!(
+            parse(Method::POST, br#"{"model": "claude-sonnet-4-5"}"#, true),
+            "DEBUG MissingMessages"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_relay_is_byte_for_byte() {
+        let fixture = include_str!("../tests/fixtures/anthropic_fine_grained_tools.sse");
+        let upstream = reqwest::Response::from(Response::new(fixture));
+
+        let (body_tx, body_rx) = mpsc::channel(16);
+        let (event_tx, mut event_rx) = mpsc::channel(4);
+        let completion = Completion {
+            id: 7,
+            event: Some(minimal_event(b"{}", "/v1/messages", "anthropic")),
+            event_tx,
+            forwarded_at: Instant::now(),
+            is_event_stream: true,
+            cut_after: None,
+            prices: Arc::default(),
+            timings: RequestTimings::default(),
+            waiting: PhaseTimer::start(Phase::FirstByte, 7),
+        };
+        relay_upstream(
+            upstream,
+            body_tx,
+            Some(AnthropicStreamTap::new()),
+            UsageTap::new(true),
+            completion,
+            Arc::default(),
+        )
+        .await;
+
+        let relayed = RelayBody { rx: body_rx }.collect().await.unwrap().to_bytes();
+        assert_eq!(relayed, Bytes::from(fixture));
+        match event_rx.recv().await {
+            // The usage of the final message_delta
+            Some(ProxyEvent::Completed {
+                id: 7,
+                event: Some(event),
+            }) => {
+                assert_eq!(
+                    event.output,
+                    Some(OutputTokens {
+                        tokens: 87,
+                        estimated: false
+                    })
+                );
+                assert!(event.latency_ms.is_some());
+                let timings = event.timings.unwrap();
+                assert!(timings.first_byte_us.is_some() && timings.relay_us.is_some());
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_abort_stops_upstream() {
+        use std::io::{Read, Write};
+
+        // Mock provider streaming one SSE chunk every 50ms for up to 5s,
+        // reporting how long it kept writing
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/v1/messages", listener.local_addr().unwrap());
+        let (stopped_tx, stopped_rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.read(&mut [0u8; 4096]);
+            let start = std::time::Instant::now();
+            let mut write = |data: &[u8]| stream.write_all(data).and_then(|_| stream.flush());
+            let mut result = write(
+                b"HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\n\
+                  transfer-encoding: chunked\r\n\r\n",
+            );
+            while result.is_ok() && start.elapsed() < Duration::from_secs(5) {
+                result = write(b"6\r\nping\n\n\r\n");
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            let _ = stopped_tx.send((result.is_err(), start.elapsed()));
+        });
+
+        let upstream = reqwest::Client::new().get(&url).send().await.unwrap();
+        let (body_tx, mut body_rx) = mpsc::channel(16);
+        let (event_tx, mut event_rx) = mpsc::channel(4);
+        let completion = Completion {
+            id: 3,
+            event: Some(minimal_event(b"{}", "/v1/messages", "anthropic")),
+            event_tx,
+            forwarded_at: Instant::now(),
+            is_event_stream: true,
+            cut_after: None,
+            prices: Arc::default(),
+            timings: RequestTimings::default(),
+            waiting: PhaseTimer::start(Phase::FirstByte, 7),
+        };
+        tokio::spawn(relay_upstream(
+            upstream,
+            body_tx,
+            None,
+            UsageTap::new(true),
+            completion,
+            Arc::default(),
+        ));
+
+        // Client reads part of the stream, then hangs up
+        for _ in 0..2 {
+            body_rx.recv().await.unwrap().unwrap();
+        }
+        drop(body_rx);
+
+        let event = tokio::time::timeout(Duration::from_secs(1), event_rx.recv())
+            .await
+            .expect("relay didn't notice the client leaving");
+        match event {
+            Some(ProxyEvent::Completed {
+                id: 3,
+                event: Some(event),
+            }) => assert!(event.client_aborted),
+            other => panic!("unexpected event: {:?}", other),
+        }
+
+        let (write_failed, elapsed) = tokio::task::spawn_blocking(move || stopped_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(write_failed, "upstream connection was never closed");
+        assert!(elapsed < Duration::from_secs(2), "upstream kept streaming for {:?}", elapsed);
+    }
+
+    /// Answer every connection with the same canned response, returning the base URL
+    fn mock_upstream(status: &'static str, body: &'static str) -> String {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                // Read the whole request so closing doesn't reset the connection
+                let mut request = Vec::new();
+                let mut buf = [0u8; 64 * 1024];
+                let mut expected = None;
+                loop {
+                    // Headers are only looked for until found, so large bodies read quickly
+                    let text = match expected {
+                        None => String::from_utf8_lossy(&request).to_lowercase(),
+                        Some(_) => String::new(),
+                    };
+                    if let Some(end) = text.find("\r\n\r\n") {
+                        let length: usize = text
+                            .lines()
+                            .find_map(|line| line.strip_prefix("content-length:"))
+                            .and_then(|value| value.trim().parse().ok())
+                            .unwrap_or(0);
+                        expected = Some(end + 4 + length);
+                    }
+                    if expected.is_some_and(|expected| request.len() >= expected) {
+                        break;
+                    }
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn test_failover_chain() {
+        let provider = |base_url: String, fallbacks: &[&str]| ProviderConfig {
+            host: "localhost".to_string(),
+            base_url,
+            env_vars: vec![],
+            path_patterns: vec!["/v1/messages".to_string()],
+            format: None,
+            fallbacks: fallbacks.iter().map(|f| f.to_string()).collect(),
+            failover_statuses: vec![529, 503],
+            tls: None,
+            tokenizer: None,
+            deployment_models: Default::default(),
+            api_key_env: None,
+        };
+        let closed = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        let providers = HashMap::from([
+            ("anthropic".to_string(), provider(closed, &["busy", "backup"])),
+            ("busy".to_string(), provider(mock_upstream("529 Overloaded", "{}"), &[])),
+            ("backup".to_string(), provider(mock_upstream("200 OK", "served"), &[])),
+            ("invalid".to_string(), provider(mock_upstream("400 Bad Request", "no"), &["backup"])),
+        ]);
+        let clients = providers
+            .keys()
+            .map(|name| (name.clone(), reqwest::Client::new()))
+            .collect();
+        let headers = hyper::HeaderMap::new();
+        let body = Bytes::from_static(b"{\"model\":\"claude-3\"}");
+        let shaper = Arc::default();
+        let send = |name: &'static str| {
+            send_with_failover(
+                &clients,
+                &providers,
+                name,
+                &Method::POST,
+                &headers,
+                "/v1/messages",
+                &body,
+                &shaper,
+            )
+        };
+
+        // Connect error, then an overloaded fallback, then success
+        let (result, attempted) = send("anthropic").await;
+        let resp = result.unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.text().await.unwrap(), "served");
+        assert_eq!(attempted, ["anthropic", "busy", "backup"]);
+
+        // Other statuses go straight back to the client
+        let (result, attempted) = send("invalid").await;
+        assert_eq!(result.unwrap().status(), 400);
+        assert_eq!(attempted, ["invalid"]);
+
+        // The last provider's failure is returned when the chain runs out
+        let (result, attempted) = send("busy").await;
+        assert_eq!(result.unwrap().status(), 529);
+        assert_eq!(attempted, ["busy"]);
+
+        // Providers that are only fallbacks don't take over primary traffic
+        assert_eq!(
+            detect_provider("/v1/messages", &providers).as_deref(),
+            Some("anthropic")
+        );
+    }
+
+    #[test]
+    fn test_shape_route() {
+        let provider = |format: Option<&str>| ProviderConfig {
+            host: "localhost".to_string(),
+            base_url: "http://localhost".to_string(),
+            env_vars: vec![],
+            path_patterns: vec!["/v1/chat/completions".to_string()],
+            format: format.map(str::to_string),
+            fallbacks: vec![],
+            failover_statuses: vec![],
+            tls: None,
+            tokenizer: None,
+            deployment_models: Default::default(),
+            api_key_env: None,
+        };
+        let mut providers = HashMap::from([
+            ("anthropic".to_string(), provider(None)),
+            ("openai".to_string(), provider(None)),
+        ]);
+        assert_eq!(shape_route(&providers, "openai").as_deref(), Some("openai"));
+        assert_eq!(shape_route(&providers, "gemini"), None);
+
+        // A second OpenAI-compatible gateway makes the format ambiguous
+        providers.insert("azure".to_string(), provider(Some("openai")));
+        assert_eq!(shape_route(&providers, "openai"), None);
+        assert_eq!(shape_route(&providers, "anthropic").as_deref(), Some("anthropic"));
+    }
+
+    #[tokio::test]
+    async fn test_clamped_body_content_length() {
+        use std::io::{Read, Write};
+
+        // Upstream that echoes back the raw request it received
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            while !request.ends_with(b"}") {
+                match stream.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => request.extend_from_slice(&buf[..n]),
+                }
+            }
+            let _ = write!(
+                stream,
+                "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                request.len()
+            );
+            let _ = stream.write_all(&request);
+        });
+
+        let original = br#"{"model":"claude-3","max_tokens":64000,"messages":[]}"#;
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert(hyper::header::CONTENT_LENGTH, original.len().into());
+        let scanner = PolicyScanner::new(&crate::config::PolicyConfig {
+            max_output_tokens: Some(8192),
+            ..Default::default()
+        })
+        .unwrap();
+        let OutputCap::Clamped { body, .. } = scanner.cap_output_tokens(original) else {
+            panic!("not clamped");
+        };
+        let body = replace_body(&mut headers, body);
+
+        let provider = ProviderConfig {
+            host: "localhost".to_string(),
+            base_url,
+            env_vars: vec![],
+            path_patterns: vec!["/v1/messages".to_string()],
+            format: None,
+            fallbacks: vec![],
+            failover_statuses: vec![],
+            tls: None,
+            tokenizer: None,
+            deployment_models: Default::default(),
+            api_key_env: None,
+        };
+        let client = reqwest::Client::new();
+        let resp = send_upstream(
+            &client,
+            &provider,
+            &Method::POST,
+            &headers,
+            "/v1/messages",
+            &body,
+            &Arc::default(),
+        )
+        .await
+        .unwrap();
+        let echoed = resp.text().await.unwrap().to_lowercase();
+
+        assert_eq!(echoed.matches("content-length:").count(), 1);
+        assert!(echoed.contains(&format!("content-length: {}\r\n", body.len())));
+        assert!(echoed.ends_with(r#"{"model":"claude-3","max_tokens":8192,"messages":[]}"#));
+    }
+
+    #[tokio::test]
+    async fn test_bandwidth_shaping() {
+        // 1 MiB each way at 1 MiB/s: a quarter second of burst, then paced
+        const SIZE: usize = 1024 * 1024;
+        let shaper = Arc::new(Shaper::default());
+        shaper.set_limits(Some(SIZE as u64), Some(SIZE as u64));
+        let provider = ProviderConfig {
+            host: "localhost".to_string(),
+            base_url: mock_upstream("200 OK", "x".repeat(SIZE).leak()),
+            env_vars: vec![],
+            path_patterns: vec!["/v1/messages".to_string()],
+            format: None,
+            fallbacks: vec![],
+            failover_statuses: vec![],
+            tls: None,
+            tokenizer: None,
+            deployment_models: Default::default(),
+            api_key_env: None,
+        };
+        let body = Bytes::from(vec![b'x'; SIZE]);
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert(hyper::header::CONTENT_LENGTH, SIZE.into());
+
+        let started = std::time::Instant::now();
+        let client = reqwest::Client::new();
+        let upstream =
+            send_upstream(&client, &provider, &Method::POST, &headers, "/", &body, &shaper)
+                .await
+                .unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(700), "{:?}", started.elapsed());
+        assert_eq!(shaper.upload.transferred(), SIZE as u64);
+
+        let started = std::time::Instant::now();
+        let (body_tx, body_rx) = mpsc::channel(16);
+        let (event_tx, _event_rx) = mpsc::channel(4);
+        let completion = Completion {
+            id: 1,
+            event: None,
+            event_tx,
+            forwarded_at: Instant::now(),
+            is_event_stream: false,
+            cut_after: None,
+            prices: Arc::default(),
+            timings: RequestTimings::default(),
+            waiting: PhaseTimer::start(Phase::FirstByte, 1),
+        };
+        tokio::spawn(relay_upstream(
+            upstream,
+            body_tx,
+            None,
+            UsageTap::new(false),
+            completion,
+            Arc::clone(&shaper),
+        ));
+        let relayed = RelayBody { rx: body_rx }.collect().await.unwrap().to_bytes();
+        assert_eq!(relayed.len(), SIZE);
+        assert!(started.elapsed() >= Duration::from_millis(700), "{:?}", started.elapsed());
+    }
+
+    #[tokio::test]
+    async fn test_context_length_rejection() {
+        let error = concat!(
+            r#"{"error":{"message":"This model's maximum context length is 128000 tokens. "#,
+            r#"However, your messages resulted in 131072 tokens.","#,
+            r#""type":"invalid_request_error","code":"context_length_exceeded"}}"#,
+        );
+        let provider = ProviderConfig {
+            host: "localhost".to_string(),
+            base_url: mock_upstream("400 Bad Request", error),
+            env_vars: vec![],
+            path_patterns: vec!["/v1/chat/completions".to_string()],
+            format: None,
+            fallbacks: vec![],
+            failover_statuses: vec![],
+            tls: None,
+            tokenizer: None,
+            deployment_models: Default::default(),
+            api_key_env: None,
+        };
+        let body = Bytes::from_static(br#"{"model":"gpt-4o","messages":[]}"#);
+        let mut event = parse_request(&body, "/v1/chat/completions", "openai").unwrap();
+        event.response = Some(ResponseInfo {
+            status: 400,
+            latency_ms: 5,
+        });
+        let client = reqwest::Client::new();
+        let headers = hyper::HeaderMap::new();
+        let shaper = Arc::default();
+        let upstream =
+            send_upstream(&client, &provider, &Method::POST, &headers, "/", &body, &shaper)
+                .await
+                .unwrap();
+
+        let (body_tx, body_rx) = mpsc::channel(16);
+        let (event_tx, mut event_rx) = mpsc::channel(4);
+        let completion = Completion {
+            id: 1,
+            event: Some(event),
+            event_tx,
+            forwarded_at: Instant::now(),
+            is_event_stream: false,
+            cut_after: None,
+            prices: Arc::default(),
+            timings: RequestTimings::default(),
+            waiting: PhaseTimer::start(Phase::FirstByte, 1),
+        };
+        tokio::spawn(relay_upstream(
+            upstream,
+            body_tx,
+            None,
+            UsageTap::new(false),
+            completion,
+            shaper,
+        ));
+        // The error still reaches the client untouched
+        let relayed = RelayBody { rx: body_rx }.collect().await.unwrap().to_bytes();
+        assert_eq!(relayed, error.as_bytes());
+        let Some(ProxyEvent::Completed { event: Some(event), .. }) = event_rx.recv().await else {
+            panic!("expected a completed event");
+        };
+        let overflow = event.context_overflow.unwrap();
+        assert!(overflow.rejected);
+        assert_eq!((overflow.tokens, overflow.limit), (Some(131_072), Some(128_000)));
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown() {
+        use std::io::{Read, Write};
+
+        // Provider that takes a while to answer, so the request is still
+        // under way when the proxy is told to stop
+        let upstream = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", upstream.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = upstream.accept().unwrap();
+            let _ = stream.read(&mut [0u8; 4096]);
+            std::thread::sleep(Duration::from_millis(300));
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\n{}");
+        });
+        let provider = ProviderConfig {
+            host: "localhost".to_string(),
+            base_url,
+            env_vars: vec![],
+            path_patterns: vec!["/v1/messages".to_string()],
+            format: None,
+            fallbacks: vec![],
+            failover_statuses: vec![],
+            tls: None,
+            tokenizer: None,
+            deployment_models: Default::default(),
+            api_key_env: None,
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/v1/messages", listener.local_addr().unwrap());
+        let key_dir =
+            std::env::temp_dir().join(format!("sherlock-shutdown-test-{}", std::process::id()));
+        let (event_tx, mut event_rx) = mpsc::channel(16);
+        let server = ProxyServer::new(
+            ProxyConfig::default(),
+            HashMap::from([("anthropic".to_string(), provider)]),
+            event_tx,
+            Arc::new(ProxyMetrics::default()),
+            Arc::new(KeyFingerprinter::load_or_create(&key_dir).unwrap()),
+            Arc::new(PolicyScanner::default()),
+            Arc::default(),
+        )
+        .unwrap();
+        let shutdown = server.shutdown_handle();
+        let serving = tokio::spawn(server.serve(listener));
+
+        let client = reqwest::Client::new();
+        let in_flight = tokio::spawn(
+            client
+                .post(&url)
+                .body(r#"{"model":"claude-3-5-sonnet-20241022","messages":[]}"#)
+                .send(),
+        );
+        assert!(matches!(event_rx.recv().await, Some(ProxyEvent::Started(_))));
+        shutdown.shutdown();
+        tokio::time::timeout(Duration::from_secs(1), serving)
+            .await
+            .expect("proxy kept accepting")
+            .unwrap();
+
+        // The request under way is still answered and recorded
+        let resp = in_flight.await.unwrap().unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.text().await.unwrap(), "{}");
+        assert!(matches!(
+            event_rx.recv().await,
+            Some(ProxyEvent::Completed { event: Some(_), .. })
+        ));
+        // New connections are refused
+        assert!(reqwest::Client::new().post(&url).body("{}").send().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_estimate_endpoint() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}{}", listener.local_addr().unwrap(), ESTIMATE_PATH);
+        let key_dir =
+            std::env::temp_dir().join(format!("sherlock-estimate-test-{}", std::process::id()));
+        let (event_tx, mut event_rx) = mpsc::channel(16);
+        let server = ProxyServer::new(
+            ProxyConfig::default(),
+            crate::config::Config::default().providers,
+            event_tx,
+            Arc::new(ProxyMetrics::default()),
+            Arc::new(KeyFingerprinter::load_or_create(&key_dir).unwrap()),
+            Arc::new(PolicyScanner::default()),
+            Arc::new(PriceTable::new(&crate::config::Config::default().pricing)),
+        )
+        .unwrap();
+        tokio::spawn(server.serve(listener));
+
+        let client = reqwest::Client::new();
+        let cases = [
+            (
+                "anthropic",
+                serde_json::json!({
+                    "model": "claude-3-5-sonnet-20241022",
+                    "system": "Be brief.",
+                    "messages": [{"role": "user", "content": [
+                        {"type": "text", "text": "What does this crate do?"},
+                        {"type": "hologram", "data": "..."}
+                    ]}]
+                }),
+                "claude-3-5-sonnet-20241022",
+                2,
+            ),
+            (
+                "openai",
+                serde_json::json!({
+                    "model": "gpt-4o",
+                    "messages": [
+                        {"role": "system", "content": "Be brief."},
+                        {"role": "user", "content": "What does this crate do?"},
+                        {"role": "assistant", "content": "It counts tokens."}
+                    ]
+                }),
+                "gpt-4o",
+                3,
+            ),
+            (
+                "gemini",
+                serde_json::json!({
+                    "contents": [{"role": "user", "parts": [{"text": "What does this crate do?"}]}]
+                }),
+                "gemini",
+                1,
+            ),
+        ];
+        for (provider, body, model, messages) in &cases {
+            let resp = client
+                .post(&url)
+                .json(&serde_json::json!({"provider": provider, "body": body}))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), 200, "{}", provider);
+            let report: serde_json::Value = resp.json().await.unwrap();
+            assert_eq!(report["model"], *model);
+            assert_eq!(report["messages"].as_array().unwrap().len(), *messages);
+            assert!(report["tokens"].as_u64().unwrap() > 0, "{}", report);
+            // Gemini's model isn't named in the body, so it has no price
+            assert_eq!(
+                report["input_cost_usd"].is_f64(),
+                *provider != "gemini",
+                "{}",
+                report
+            );
+        }
+        let report: serde_json::Value = client
+            .post(&url)
+            .json(&serde_json::json!({"provider": "anthropic", "body": cases[0].1}))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(
+            report["warnings"][0],
+            "unknown content block type \"hologram\" in message 2"
+        );
+        // Estimates alone are never recorded
+        assert!(event_rx.try_recv().is_err());
+
+        let resp = client
+            .post(format!("{}?record=true", url))
+            .json(&serde_json::json!({"provider": "openai", "body": cases[1].1}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        assert!(matches!(event_rx.recv().await, Some(ProxyEvent::Started(_))));
+        let Some(ProxyEvent::Completed { event: Some(event), .. }) = event_rx.recv().await else {
+            panic!("expected a recorded event");
+        };
+        assert_eq!((event.provider.as_str(), event.model.as_str()), ("openai", "gpt-4o"));
+
+        let resp = client
+            .post(&url)
+            .json(&serde_json::json!({"provider": "openai", "body": {"model": "gpt-4o"}}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 422);
+        let resp = client
+            .post(&url)
+            .json(&serde_json::json!({"provider": "mystery", "body": {}}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn test_mark_endpoint() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let key_dir =
+            std::env::temp_dir().join(format!("sherlock-mark-test-{}", std::process::id()));
+        let (event_tx, mut event_rx) = mpsc::channel(16);
+        let server = ProxyServer::new(
+            ProxyConfig::default(),
+            crate::config::Config::default().providers,
+            event_tx,
+            Arc::new(ProxyMetrics::default()),
+            Arc::new(KeyFingerprinter::load_or_create(&key_dir).unwrap()),
+            Arc::new(PolicyScanner::default()),
+            Arc::default(),
+        )
+        .unwrap();
+        tokio::spawn(server.serve(listener));
+
+        // Through a session path, the marker applies to that session's repository
+        let session = SessionInfo {
+            repo: Some(RepoInfo {
+                root: std::path::PathBuf::from("/src/sherlock"),
+                branch: None,
+                head: None,
+                dirty: None,
+            }),
+            overlay: None,
+        };
+        let client = reqwest::Client::new();
+        let resp = client
+            .post(format!("{}{}{}", base, session.to_path(), MARK_PATH))
+            .json(&serde_json::json!({"label": " started refactor ", "tag": "infra"}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        let replied: Marker = resp.json().await.unwrap();
+        let Some(ProxyEvent::Marker(marker)) = event_rx.recv().await else {
+            panic!("expected a marker");
+        };
+        assert_eq!(marker, replied);
+        assert_eq!(marker.describe(), "started refactor [infra] (sherlock)");
+
+        let url = format!("{}{}", base, MARK_PATH);
+        let resp = client
+            .post(&url)
+            .json(&serde_json::json!({"label": "ran tests", "session": "other"}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        let Some(ProxyEvent::Marker(marker)) = event_rx.recv().await else {
+            panic!("expected a marker");
+        };
+        assert_eq!(marker.session.as_deref(), Some("other"));
+
+        for resp in [
+            client.post(&url).json(&serde_json::json!({"label": " "})).send(),
+            client.post(&url).body("not json").send(),
+        ] {
+            assert_eq!(resp.await.unwrap().status(), 400);
+        }
+        assert_eq!(client.get(&url).send().await.unwrap().status(), 405);
+        assert!(event_rx.try_recv().is_err());
+        std::fs::remove_dir_all(&key_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_status_endpoint() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}{}", listener.local_addr().unwrap(), STATUS_PATH);
+        let key_dir =
+            std::env::temp_dir().join(format!("sherlock-status-test-{}", std::process::id()));
+        let (event_tx, mut event_rx) = mpsc::channel(16);
+        let metrics = Arc::new(ProxyMetrics::default());
+        let server = ProxyServer::new(
+            ProxyConfig::default(),
+            crate::config::Config::default().providers,
+            event_tx,
+            Arc::clone(&metrics),
+            Arc::new(KeyFingerprinter::load_or_create(&key_dir).unwrap()),
+            Arc::new(PolicyScanner::default()),
+            Arc::default(),
+        )
+        .unwrap();
+        tokio::spawn(server.serve(listener));
+
+        // Whatever the dashboard last published
+        let status = crate::statusline::Status {
+            requests: 3,
+            input_tokens: 141_000,
+            output_tokens: 600,
+            cost_usd: Some(3.2),
+            ..Default::default()
+        };
+        metrics.set_status(status.clone());
+        let client = reqwest::Client::new();
+        let resp = client.get(&url).send().await.unwrap();
+        assert_eq!(resp.status(), 200);
+        let served: crate::statusline::Status = resp.json().await.unwrap();
+        assert_eq!(served, status);
+
+        // Parse errors are counted by the proxy itself, so they're current
+        metrics.record_parse_error("NotJson");
+        metrics.record_parse_error("NotJson");
+        let served: crate::statusline::Status =
+            client.get(&url).send().await.unwrap().json().await.unwrap();
+        let expected = std::collections::BTreeMap::from([("NotJson".to_string(), 2)]);
+        assert_eq!(served.parse_errors, expected);
+
+        assert_eq!(client.post(&url).send().await.unwrap().status(), 405);
+        assert!(event_rx.try_recv().is_err());
+        std::fs::remove_dir_all(&key_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_slow_upload_progress() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        const SIZE: usize = 10 << 20;
+        const CHUNKS: usize = 40;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let key_dir =
+            std::env::temp_dir().join(format!("sherlock-upload-test-{}", std::process::id()));
+        let mut providers = crate::config::Config::default().providers;
+        providers.get_mut("anthropic").unwrap().base_url = mock_upstream("200 OK", "{}");
+        let (event_tx, mut event_rx) = mpsc::channel(64);
+        let server = ProxyServer::new(
+            ProxyConfig::default(),
+            providers,
+            event_tx,
+            Arc::new(ProxyMetrics::default()),
+            Arc::new(KeyFingerprinter::load_or_create(&key_dir).unwrap()),
+            Arc::new(PolicyScanner::default()),
+            Arc::default(),
+        )
+        .unwrap();
+        tokio::spawn(server.serve(listener));
+
+        // A token count request isn't parsed, so the body can be anything
+        let client = tokio::spawn(async move {
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let head = format!(
+                "POST /v1/messages/count_tokens HTTP/1.1\r\nhost: {}\r\n\
+                 content-type: application/octet-stream\r\ncontent-length: {}\r\n\
+                 connection: close\r\n\r\n",
+                addr, SIZE
+            );
+            stream.write_all(head.as_bytes()).await.unwrap();
+            let chunk = vec![b'x'; SIZE / CHUNKS];
+            for _ in 0..CHUNKS {
+                stream.write_all(&chunk).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(25)).await;
+            }
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).await.unwrap();
+            String::from_utf8_lossy(&response).into_owned()
+        });
+
+        let mut progress = Vec::new();
+        let id = loop {
+            match event_rx.recv().await.unwrap() {
+                ProxyEvent::Uploading(request) => {
+                    let upload = request.upload.unwrap();
+                    assert_eq!(upload.path, "/v1/messages/count_tokens");
+                    assert_eq!(upload.content_type.as_deref(), Some("application/octet-stream"));
+                    progress.push((request.id, upload.bytes));
+                }
+                ProxyEvent::Started(request) => break request.id,
+                other => panic!("unexpected {:?}", other),
+            }
+        };
+        // A few updates a second, each further along, all for the same request
+        assert!((2..=10).contains(&progress.len()), "{:?}", progress);
+        assert!(progress.iter().all(|(upload_id, _)| *upload_id == id));
+        assert!(progress.windows(2).all(|pair| pair[0].1 < pair[1].1));
+        assert!(progress.last().unwrap().1 < SIZE as u64);
+        assert!(matches!(
+            event_rx.recv().await,
+            Some(ProxyEvent::Completed { id: done, .. }) if done == id
+        ));
+        assert!(client.await.unwrap().starts_with("HTTP/1.1 200"));
+        std::fs::remove_dir_all(&key_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_session_overlay() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let key_dir =
+            std::env::temp_dir().join(format!("sherlock-overlay-test-{}", std::process::id()));
+        let (event_tx, mut event_rx) = mpsc::channel(16);
+        let server = ProxyServer::new(
+            ProxyConfig::default(),
+            crate::config::Config::default().providers,
+            event_tx,
+            Arc::new(ProxyMetrics::default()),
+            Arc::new(KeyFingerprinter::load_or_create(&key_dir).unwrap()),
+            Arc::new(PolicyScanner::default()),
+            Arc::default(),
+        )
+        .unwrap();
+        tokio::spawn(server.serve(listener));
+
+        let session = SessionInfo {
+            repo: None,
+            overlay: Some(SessionOverlay {
+                path: PathBuf::from("/src/client/.sherlock.json"),
+                archive: false,
+                policy: Some(PolicyConfig {
+                    scan_patterns: vec![crate::config::ScanPattern {
+                        name: "codename".to_string(),
+                        regex: "bluebird".to_string(),
+                        action: crate::config::PolicyAction::Block,
+                    }],
+                    ..Default::default()
+                }),
+            }),
+        };
+        let body = |text: &str| {
+            serde_json::json!({
+                "model": "claude-3",
+                "system": "You are helpful.",
+                "messages": [{"role": "user", "content": text}]
+            })
+        };
+        // Unrouted paths are answered by the proxy itself, so nothing goes upstream
+        let client = reqwest::Client::new();
+        let send = |session: Option<&SessionInfo>, text: &str| {
+            let prefix = session.map(SessionInfo::to_path).unwrap_or_default();
+            client
+                .post(format!("{}{}/llm/chat", base, prefix))
+                .json(&body(text))
+                .send()
+        };
+        let completed = |rx: &mut mpsc::Receiver<ProxyEvent>| {
+            let mut events = Vec::new();
+            while let Ok(event) = rx.try_recv() {
+                events.push(event);
+            }
+            events.into_iter().find_map(|event| match event {
+                ProxyEvent::Completed { event, .. } => event,
+                _ => None,
+            })
+        };
+
+        // The session's policy blocks what the proxy's own lets through
+        let resp = send(Some(&session), "ship bluebird").await.unwrap();
+        assert_eq!(resp.status(), 403);
+        let resp = send(None, "ship bluebird").await.unwrap();
+        assert_eq!(resp.status(), 400);
+        let event = completed(&mut event_rx).unwrap();
+        assert!(event.overlay.is_none() && !event.unarchived);
+
+        // The session's requests record the overlay and skip the archive
+        let resp = send(Some(&session), "hello").await.unwrap();
+        assert_eq!(resp.status(), 400);
+        let event = completed(&mut event_rx).unwrap();
+        assert_eq!(
+            event.overlay.as_deref(),
+            Some(std::path::Path::new("/src/client/.sherlock.json"))
+        );
+        assert!(event.unarchived);
+        std::fs::remove_dir_all(&key_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_manual_request() {
+        use std::io::{Read, Write};
+
+        // Upstream that echoes back the raw request it received
+        let upstream = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut providers = crate::config::Config::default().providers;
+        providers.get_mut("anthropic").unwrap().base_url =
+            format!("http://{}", upstream.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = upstream.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            while !request.ends_with(b"}") {
+                match stream.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => request.extend_from_slice(&buf[..n]),
+                }
+            }
+            let _ = write!(
+                stream,
+                "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                request.len()
+            );
+            let _ = stream.write_all(&request);
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let key_dir =
+            std::env::temp_dir().join(format!("sherlock-manual-test-{}", std::process::id()));
+        let (event_tx, mut event_rx) = mpsc::channel(16);
+        let server = ProxyServer::new(
+            ProxyConfig {
+                capture_auth_headers: true,
+                ..ProxyConfig::default()
+            },
+            providers,
+            event_tx,
+            Arc::new(ProxyMetrics::default()),
+            Arc::new(KeyFingerprinter::load_or_create(&key_dir).unwrap()),
+            Arc::new(PolicyScanner::default()),
+            Arc::default(),
+        )
+        .unwrap();
+        tokio::spawn(server.serve(listener));
+
+        let echoed = reqwest::Client::new()
+            .post(format!("{}/v1/messages", base))
+            .header(MANUAL_HEADER, "1")
+            .header("x-api-key", "sk-ant-SECRET")
+            .body(r#"{"model":"claude-3","messages":[{"role":"user","content":"hi"}]}"#)
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap()
+            .to_lowercase();
+        // The tag stays with sherlock; the key goes upstream as sent
+        assert!(!echoed.contains(MANUAL_HEADER));
+        assert!(echoed.contains("x-api-key: sk-ant-secret"));
+
+        let event = loop {
+            match event_rx.recv().await.unwrap() {
+                ProxyEvent::Completed { event, .. } => break event.unwrap(),
+                _ => continue,
+            }
+        };
+        assert!(event.manual);
+        let captured: Vec<_> = event.auth.headers().collect();
+        assert_eq!(captured, [("x-api-key", "sk-ant-SECRET")]);
+        let archived = serde_json::to_string(&event).unwrap();
+        assert!(archived.contains(r#""manual":true"#));
+        assert!(!archived.contains("SECRET"));
+        std::fs::remove_dir_all(&key_dir).unwrap();
+    }
+
+    #[test]
+    fn test_session_path_round_trip() {
+        let session = SessionInfo {
+            repo: Some(RepoInfo {
+                root: std::path::PathBuf::from("/src/sherlock"),
+                branch: Some("main".to_string()),
+                head: Some("1a2b3c4".to_string()),
+                dirty: Some(false),
+            }),
+            overlay: Some(SessionOverlay {
+                path: PathBuf::from("/src/sherlock/.sherlock.json"),
+                archive: false,
+                policy: None,
+            }),
+        };
+        let path = format!("{}/v1/messages?beta=true", session.to_path());
+        assert_eq!(
+            SessionInfo::split_path(&path),
+            (Some(session), "/v1/messages?beta=true")
+        );
+
+        // Malformed metadata is dropped but the request still goes through
+        assert_eq!(
+            SessionInfo::split_path("/_sherlock/session/%%%/v1/messages"),
+            (None, "/v1/messages")
+        );
+        assert_eq!(SessionInfo::split_path("/v1/messages"), (None, "/v1/messages"));
+    }
+
+    #[test]
+    fn test_api_version() {
+        let mut headers = hyper::HeaderMap::new();
+        assert_eq!(api_version(&headers, None), None);
+        assert_eq!(
+            api_version(&headers, Some("foo=1&api-version=2024-10-21")),
+            Some("2024-10-21".to_string())
+        );
+
+        headers.insert("anthropic-version", "2023-06-01".parse().unwrap());
+        assert_eq!(
+            api_version(&headers, Some("api-version=2024-10-21")),
+            Some("2023-06-01".to_string())
+        );
+    }
+
+    /// Runs the fd exhaustion scenario in a child process, since lowering
+    /// RLIMIT_NOFILE would break tests running in parallel
+    #[cfg(unix)]
+    #[test]
+    fn test_accept_recovers_from_fd_exhaustion() {
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["proxy::tests::fd_exhaustion_child", "--exact", "--ignored"])
+            .env("SHERLOCK_FD_EXHAUSTION_CHILD", "1")
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[cfg(unix)]
+    #[ignore = "run by test_accept_recovers_from_fd_exhaustion"]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn fd_exhaustion_child() {
+        use std::io::{Read, Write};
+
+        if std::env::var_os("SHERLOCK_FD_EXHAUSTION_CHILD").is_none() {
+            return;
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let key_dir = std::env::temp_dir().join(format!("sherlock-fd-test-{}", std::process::id()));
+        let metrics = Arc::new(ProxyMetrics::default());
+        let (event_tx, _event_rx) = mpsc::channel(16);
+        let server = ProxyServer::new(
+            ProxyConfig::default(),
+            HashMap::new(),
+            event_tx,
+            Arc::clone(&metrics),
+            Arc::new(KeyFingerprinter::load_or_create(&key_dir).unwrap()),
+            Arc::new(PolicyScanner::default()),
+            Arc::default(),
+        )
+        .unwrap();
+        tokio::spawn(server.serve(listener));
+
+        // Cap descriptors a little above what is open now, then use up the rest
+        let probe = std::fs::File::open("/dev/null").unwrap();
+        let in_use = std::os::fd::AsRawFd::as_raw_fd(&probe) as libc::rlim_t;
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        unsafe {
+            assert_eq!(libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit), 0);
+            limit.rlim_cur = in_use + 32;
+            assert_eq!(libc::setrlimit(libc::RLIMIT_NOFILE, &limit), 0);
+        }
+        let mut filler = vec![probe];
+        while let Ok(file) = std::fs::File::open("/dev/null") {
+            filler.push(file);
+        }
+
+        // Free exactly one descriptor for the client; the proxy has none left
+        filler.pop();
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        for _ in 0..100 {
+            if metrics.health().accept_errors > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(metrics.health().accept_errors > 0);
+        assert!(metrics.health().degraded);
+
+        drop(filler);
+        let response = tokio::task::spawn_blocking(move || {
+            client
+                .write_all(b"GET /unknown HTTP/1.1\r\nhost: x\r\nconnection: close\r\n\r\n")
+                .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        })
+        .await
+        .unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+        assert!(!metrics.health().degraded);
+        std::fs::remove_dir_all(&key_dir).unwrap();
+    }
+}
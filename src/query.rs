@@ -0,0 +1,962 @@
+use chrono::Local;
+use serde_json::{Map, Value};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::filter::Filter;
+use crate::index::IndexEntry;
+use crate::stats::Histogram;
+
+/// Fields a query can select, group or aggregate, for error messages
+const FIELDS: &str =
+    "provider, model, served_model, error, date, tokens, status, latency_ms or response_ms";
+const FUNCTIONS: &str = "count, sum, avg, min, max or p95";
+
+/// A field of an index entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Provider,
+    Model,
+    ServedModel,
+    Error,
+    /// Local day of the request, YYYY-MM-DD
+    Date,
+    Tokens,
+    Status,
+    LatencyMs,
+    ResponseMs,
+}
+
+impl Field {
+    const ALL: [Field; 9] = [
+        Field::Provider,
+        Field::Model,
+        Field::ServedModel,
+        Field::Error,
+        Field::Date,
+        Field::Tokens,
+        Field::Status,
+        Field::LatencyMs,
+        Field::ResponseMs,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Field::Provider => "provider",
+            Field::Model => "model",
+            Field::ServedModel => "served_model",
+            Field::Error => "error",
+            Field::Date => "date",
+            Field::Tokens => "tokens",
+            Field::Status => "status",
+            Field::LatencyMs => "latency_ms",
+            Field::ResponseMs => "response_ms",
+        }
+    }
+
+    fn is_numeric(self) -> bool {
+        matches!(
+            self,
+            Field::Tokens | Field::Status | Field::LatencyMs | Field::ResponseMs
+        )
+    }
+
+    fn value(self, entry: &IndexEntry) -> Datum {
+        let text = |text: Option<&String>| text.map_or(Datum::Null, |t| Datum::Text(t.clone()));
+        let number = |number: Option<u64>| number.map_or(Datum::Null, Datum::Number);
+        match self {
+            Field::Provider => Datum::Text(entry.provider.clone()),
+            Field::Model => text(entry.model.as_ref()),
+            Field::ServedModel => text(entry.served_model.as_ref()),
+            Field::Error => text(entry.error.as_ref()),
+            Field::Date => Datum::Text(
+                entry
+                    .timestamp
+                    .with_timezone(&Local)
+                    .format("%Y-%m-%d")
+                    .to_string(),
+            ),
+            Field::Tokens => number(entry.tokens),
+            Field::Status => number(entry.status.map(u64::from)),
+            Field::LatencyMs => number(entry.latency_ms),
+            Field::ResponseMs => number(entry.response_ms),
+        }
+    }
+}
+
+/// Aggregate functions over a group of entries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Function {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+    P95,
+}
+
+impl Function {
+    const ALL: [Function; 6] = [
+        Function::Count,
+        Function::Sum,
+        Function::Avg,
+        Function::Min,
+        Function::Max,
+        Function::P95,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Function::Count => "count",
+            Function::Sum => "sum",
+            Function::Avg => "avg",
+            Function::Min => "min",
+            Function::Max => "max",
+            Function::P95 => "p95",
+        }
+    }
+}
+
+/// One output column: a field as is, or an aggregate of one. `count()`
+/// counts entries, `count(field)` those where the field is set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    Field(Field),
+    Aggregate(Function, Option<Field>),
+}
+
+impl fmt::Display for Column {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Column::Field(field) => write!(f, "{}", field.name()),
+            Column::Aggregate(function, field) => {
+                write!(f, "{}({})", function.name(), field.map_or("", Field::name))
+            }
+        }
+    }
+}
+
+/// Why a query didn't parse: the option at fault and where in it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryError {
+    pub option: &'static str,
+    pub message: String,
+    /// Character offset in the option's value
+    pub start: usize,
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} at column {}",
+            self.option,
+            self.message,
+            self.start + 1
+        )
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+/// A parsed `sherlock query`: columns to select, fields to group by and
+/// how to order and cut the result
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    columns: Vec<Column>,
+    group_by: Vec<Field>,
+    order: Option<Order>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Order {
+    /// Index into the selected columns
+    column: usize,
+    descending: bool,
+}
+
+impl Query {
+    /// Parse `--select`, `--group-by` and `--order-by`, e.g. `model, sum(tokens)`,
+    /// `model` and `sum(tokens) desc`
+    pub fn parse(
+        select: &str,
+        group_by: Option<&str>,
+        order_by: Option<&str>,
+        limit: Option<usize>,
+    ) -> Result<Self, QueryError> {
+        let columns = Cursor::new(select, "--select").columns()?;
+        let group_by = match group_by {
+            Some(input) => Cursor::new(input, "--group-by").group_by()?,
+            None => Vec::new(),
+        };
+
+        let aggregated = columns
+            .iter()
+            .any(|(c, _)| matches!(c, Column::Aggregate(..)));
+        if aggregated {
+            for (column, start) in &columns {
+                if let Column::Field(field) = column {
+                    if !group_by.iter().any(|(g, _)| g == field) {
+                        return Err(QueryError {
+                            option: "--select",
+                            message: format!(
+                                "{} must be in --group-by to select it next to aggregates",
+                                field.name()
+                            ),
+                            start: *start,
+                        });
+                    }
+                }
+            }
+        } else if let Some((_, start)) = group_by.first() {
+            return Err(QueryError {
+                option: "--group-by",
+                message: "grouping needs an aggregate in --select, e.g. count()".to_string(),
+                start: *start,
+            });
+        }
+
+        let columns: Vec<Column> = columns.into_iter().map(|(column, _)| column).collect();
+        let order = match order_by {
+            Some(input) => Some(Cursor::new(input, "--order-by").order(&columns)?),
+            None => None,
+        };
+        Ok(Self {
+            columns,
+            group_by: group_by.into_iter().map(|(field, _)| field).collect(),
+            order,
+            limit,
+        })
+    }
+
+    fn aggregated(&self) -> bool {
+        self.columns
+            .iter()
+            .any(|c| matches!(c, Column::Aggregate(..)))
+    }
+
+    /// Evaluate over `entries` passing `filter`, keeping only one
+    /// accumulator per group and column rather than the entries
+    pub fn run(&self, entries: impl IntoIterator<Item = IndexEntry>, filter: &Filter) -> Results {
+        let entries = entries.into_iter().filter(|entry| filter.matches(entry));
+        let mut rows: Vec<Vec<Datum>> = if self.aggregated() {
+            self.aggregate(entries)
+        } else {
+            // Without an order the limit can stop reading early
+            let limit = match self.order {
+                None => self.limit.unwrap_or(usize::MAX),
+                Some(_) => usize::MAX,
+            };
+            entries
+                .take(limit)
+                .map(|entry| {
+                    self.columns
+                        .iter()
+                        .map(|column| match column {
+                            Column::Field(field) => field.value(&entry),
+                            Column::Aggregate(..) => Datum::Null,
+                        })
+                        .collect()
+                })
+                .collect()
+        };
+        if let Some(order) = self.order {
+            rows.sort_by(|a, b| {
+                let ordering = a[order.column].cmp(&b[order.column]);
+                if order.descending {
+                    ordering.reverse()
+                } else {
+                    ordering
+                }
+            });
+        }
+        if let Some(limit) = self.limit {
+            rows.truncate(limit);
+        }
+        Results {
+            columns: self.columns.iter().map(Column::to_string).collect(),
+            rows: rows
+                .into_iter()
+                .map(|row| row.into_iter().map(Value::from).collect())
+                .collect(),
+        }
+    }
+
+    fn aggregate(&self, entries: impl Iterator<Item = IndexEntry>) -> Vec<Vec<Datum>> {
+        let mut groups: BTreeMap<Vec<Datum>, Vec<Accumulator>> = BTreeMap::new();
+        let fresh = || vec![Accumulator::default(); self.columns.len()];
+        // Totals over nothing are still one row
+        if self.group_by.is_empty() {
+            groups.insert(Vec::new(), fresh());
+        }
+        for entry in entries {
+            let key = self
+                .group_by
+                .iter()
+                .map(|field| field.value(&entry))
+                .collect();
+            let accumulators = groups.entry(key).or_insert_with(fresh);
+            for (column, accumulator) in self.columns.iter().zip(accumulators) {
+                match column {
+                    Column::Aggregate(_, None) => accumulator.count += 1,
+                    Column::Aggregate(function, Some(field)) => {
+                        accumulator.record(*function, field.value(&entry))
+                    }
+                    Column::Field(_) => {}
+                }
+            }
+        }
+        groups
+            .into_iter()
+            .map(|(key, accumulators)| {
+                self.columns
+                    .iter()
+                    .zip(accumulators)
+                    .map(|(column, accumulator)| match column {
+                        Column::Field(field) => {
+                            let at = self.group_by.iter().position(|g| g == field);
+                            at.map_or(Datum::Null, |at| key[at].clone())
+                        }
+                        Column::Aggregate(function, _) => accumulator.result(*function),
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// A field's value in one entry, or an aggregate's. Sorts nulls first,
+/// then numbers, then text.
+#[derive(Debug, Clone, PartialEq)]
+enum Datum {
+    Null,
+    Number(u64),
+    Average(f64),
+    Text(String),
+}
+
+impl Datum {
+    fn rank(&self) -> u8 {
+        match self {
+            Datum::Null => 0,
+            Datum::Number(_) | Datum::Average(_) => 1,
+            Datum::Text(_) => 2,
+        }
+    }
+}
+
+impl Eq for Datum {}
+
+impl Ord for Datum {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Datum::Number(a), Datum::Number(b)) => a.cmp(b),
+            (Datum::Average(a), Datum::Average(b)) => a.total_cmp(b),
+            (Datum::Text(a), Datum::Text(b)) => a.cmp(b),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+impl PartialOrd for Datum {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl From<Datum> for Value {
+    fn from(datum: Datum) -> Self {
+        match datum {
+            Datum::Null => Value::Null,
+            Datum::Number(number) => Value::from(number),
+            Datum::Average(average) => Value::from((average * 10.0).round() / 10.0),
+            Datum::Text(text) => Value::String(text),
+        }
+    }
+}
+
+/// Running state for one aggregate column of one group
+#[derive(Debug, Clone, Default)]
+struct Accumulator {
+    /// Entries counted, or values seen for a field
+    count: u64,
+    sum: u64,
+    min: Option<u64>,
+    max: Option<u64>,
+    /// Only kept for p95
+    histogram: Option<Histogram>,
+}
+
+impl Accumulator {
+    fn record(&mut self, function: Function, value: Datum) {
+        let number = match value {
+            Datum::Null => return,
+            Datum::Number(number) => number,
+            // count(field) of a text field
+            _ => 0,
+        };
+        self.count += 1;
+        self.sum = self.sum.saturating_add(number);
+        self.min = Some(self.min.map_or(number, |min| min.min(number)));
+        self.max = Some(self.max.map_or(number, |max| max.max(number)));
+        if function == Function::P95 {
+            self.histogram
+                .get_or_insert_with(Histogram::new)
+                .record(number);
+        }
+    }
+
+    fn result(&self, function: Function) -> Datum {
+        let number = |n: Option<u64>| n.map_or(Datum::Null, Datum::Number);
+        match function {
+            Function::Count => Datum::Number(self.count),
+            Function::Sum => number((self.count > 0).then_some(self.sum)),
+            Function::Avg if self.count == 0 => Datum::Null,
+            Function::Avg => Datum::Average(self.sum as f64 / self.count as f64),
+            Function::Min => number(self.min),
+            Function::Max => number(self.max),
+            Function::P95 => number(self.histogram.as_ref().and_then(|h| h.quantile(0.95))),
+        }
+    }
+}
+
+/// Query output: a header and rows of JSON values, unset ones null
+#[derive(Debug, Clone, PartialEq)]
+pub struct Results {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+impl Results {
+    /// One object per row, keyed by column
+    pub fn to_json(&self) -> Value {
+        Value::Array(
+            self.rows
+                .iter()
+                .map(|row| {
+                    let object: Map<String, Value> = self
+                        .columns
+                        .iter()
+                        .cloned()
+                        .zip(row.iter().cloned())
+                        .collect();
+                    Value::Object(object)
+                })
+                .collect(),
+        )
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::new();
+        let mut line = |cells: Vec<String>| {
+            let cells: Vec<String> = cells.iter().map(|cell| csv_field(cell)).collect();
+            csv.push_str(&cells.join(","));
+            csv.push('\n');
+        };
+        line(self.columns.clone());
+        for row in &self.rows {
+            line(row.iter().map(cell).collect());
+        }
+        csv
+    }
+}
+
+/// Columns aligned, numbers to the right
+impl fmt::Display for Results {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cells: Vec<Vec<String>> = self
+            .rows
+            .iter()
+            .map(|row| row.iter().map(cell).collect())
+            .collect();
+        let widths: Vec<usize> = (0..self.columns.len())
+            .map(|i| {
+                let values = cells.iter().map(|row| row[i].chars().count());
+                values.fold(self.columns[i].chars().count(), usize::max)
+            })
+            .collect();
+        let numeric: Vec<bool> = (0..self.columns.len())
+            .map(|i| self.rows.iter().any(|row| row[i].is_number()))
+            .collect();
+        let write_row = |f: &mut fmt::Formatter<'_>, row: &[String]| {
+            let padded: Vec<String> = row
+                .iter()
+                .enumerate()
+                .map(|(i, text)| match numeric[i] {
+                    true => format!("{:>width$}", text, width = widths[i]),
+                    false => format!("{:<width$}", text, width = widths[i]),
+                })
+                .collect();
+            writeln!(f, "{}", padded.join("  ").trim_end())
+        };
+        write_row(f, &self.columns)?;
+        for row in &cells {
+            write_row(f, row)?;
+        }
+        Ok(())
+    }
+}
+
+fn cell(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+/// Reads one option's value, e.g. `model, sum(tokens)`, a character at a time
+struct Cursor {
+    chars: Vec<char>,
+    pos: usize,
+    option: &'static str,
+}
+
+impl Cursor {
+    fn new(input: &str, option: &'static str) -> Self {
+        Self {
+            chars: input.chars().collect(),
+            pos: 0,
+            option,
+        }
+    }
+
+    fn error(&self, start: usize, message: impl Into<String>) -> QueryError {
+        QueryError {
+            option: self.option,
+            message: message.into(),
+            start,
+        }
+    }
+
+    fn skip_space(&mut self) {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn at_end(&mut self) -> bool {
+        self.skip_space();
+        self.pos == self.chars.len()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_space();
+        let found = self.chars.get(self.pos) == Some(&c);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    /// A name of letters, digits and underscores, and where it starts
+    fn name(&mut self) -> Option<(String, usize)> {
+        self.skip_space();
+        let start = self.pos;
+        while self
+            .chars
+            .get(self.pos)
+            .is_some_and(|c| c.is_ascii_alphanumeric() || *c == '_')
+        {
+            self.pos += 1;
+        }
+        (self.pos > start).then(|| (self.chars[start..self.pos].iter().collect(), start))
+    }
+
+    /// What's at the cursor, for messages
+    fn found(&self) -> String {
+        match self.chars.get(self.pos) {
+            Some(c) => format!("\"{}\"", c),
+            None => "the end".to_string(),
+        }
+    }
+
+    fn field(&self, name: &str, start: usize) -> Result<Field, QueryError> {
+        let lower = name.to_ascii_lowercase();
+        if let Some(field) = Field::ALL.into_iter().find(|f| f.name() == lower) {
+            return Ok(field);
+        }
+        if let Some(function) = Function::ALL.into_iter().find(|f| f.name() == lower) {
+            return Err(self.error(
+                start,
+                format!(
+                    "{} needs parentheses, e.g. {}(tokens)",
+                    name,
+                    function.name()
+                ),
+            ));
+        }
+        Err(self.error(
+            start,
+            format!("unknown field \"{}\" (expected {})", name, FIELDS),
+        ))
+    }
+
+    /// A field or `function(field)`
+    fn column(&mut self) -> Result<(Column, usize), QueryError> {
+        let Some((name, start)) = self.name() else {
+            return Err(match self.at_end() {
+                true => self.error(self.pos, "expected a column at the end"),
+                false => self.error(
+                    self.pos,
+                    format!("expected a column, found {}", self.found()),
+                ),
+            });
+        };
+        if !self.eat('(') {
+            return Ok((Column::Field(self.field(&name, start)?), start));
+        }
+
+        let lower = name.to_ascii_lowercase();
+        let Some(function) = Function::ALL.into_iter().find(|f| f.name() == lower) else {
+            return Err(self.error(
+                start,
+                format!("unknown function \"{}\" (expected {})", name, FUNCTIONS),
+            ));
+        };
+        let argument = if self.eat('*') {
+            None
+        } else if let Some((argument, at)) = self.name() {
+            Some((self.field(&argument, at)?, at))
+        } else {
+            None
+        };
+        if !self.eat(')') {
+            return Err(self.error(
+                self.pos,
+                format!("expected ) after {}(, found {}", name, self.found()),
+            ));
+        }
+        match (function, argument) {
+            (Function::Count, argument) => {
+                Ok((Column::Aggregate(function, argument.map(|(f, _)| f)), start))
+            }
+            (_, None) => Err(self.error(
+                start,
+                format!(
+                    "{} needs a numeric field, e.g. {}(tokens)",
+                    name,
+                    function.name()
+                ),
+            )),
+            (_, Some((field, at))) if !field.is_numeric() => Err(self.error(
+                at,
+                format!(
+                    "{} needs a numeric field, and {} is text",
+                    name,
+                    field.name()
+                ),
+            )),
+            (_, Some((field, _))) => Ok((Column::Aggregate(function, Some(field)), start)),
+        }
+    }
+
+    /// Columns separated by commas
+    fn columns(&mut self) -> Result<Vec<(Column, usize)>, QueryError> {
+        let mut columns = vec![self.column()?];
+        while !self.at_end() {
+            if !self.eat(',') {
+                return Err(self.error(
+                    self.pos,
+                    format!("expected , between columns, found {}", self.found()),
+                ));
+            }
+            columns.push(self.column()?);
+        }
+        Ok(columns)
+    }
+
+    /// Fields separated by commas
+    fn group_by(&mut self) -> Result<Vec<(Field, usize)>, QueryError> {
+        self.columns()?
+            .into_iter()
+            .map(|(column, start)| match column {
+                Column::Field(field) => Ok((field, start)),
+                Column::Aggregate(..) => {
+                    Err(self.error(start, format!("can't group by {}, only by fields", column)))
+                }
+            })
+            .collect()
+    }
+
+    /// A selected column, then `asc` or `desc`
+    fn order(&mut self, selected: &[Column]) -> Result<Order, QueryError> {
+        let (column, start) = self.column()?;
+        let Some(index) = selected.iter().position(|c| *c == column) else {
+            return Err(self.error(
+                start,
+                format!(
+                    "{} isn't in --select, so results can't be ordered by it",
+                    column
+                ),
+            ));
+        };
+        let descending = match self.name() {
+            None => false,
+            Some((word, _)) if word.eq_ignore_ascii_case("asc") => false,
+            Some((word, _)) if word.eq_ignore_ascii_case("desc") => true,
+            Some((word, at)) => {
+                return Err(self.error(at, format!("expected asc or desc, found \"{}\"", word)))
+            }
+        };
+        if !self.at_end() {
+            return Err(self.error(self.pos, format!("unexpected {}", self.found())));
+        }
+        Ok(Order {
+            column: index,
+            descending,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    fn entry(provider: &str, model: &str, tokens: u64, latency_ms: u64) -> IndexEntry {
+        IndexEntry {
+            timestamp: Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap(),
+            id: 0,
+            provider: provider.to_string(),
+            model: Some(model.to_string()),
+            tokens: Some(tokens),
+            output_tokens: None,
+            served_model: None,
+            service_tier: None,
+            requested_tier: None,
+            cost_usd: None,
+            unpriced: false,
+            no_context_limit: false,
+            status: Some(200),
+            latency_ms: Some(latency_ms),
+            error: None,
+            response_ms: None,
+            throughput: None,
+            languages: Default::default(),
+            chaos: None,
+        }
+    }
+
+    fn entries() -> Vec<IndexEntry> {
+        vec![
+            entry("anthropic", "claude-3-5-sonnet", 1_000, 800),
+            entry("anthropic", "claude-3-5-haiku", 200, 300),
+            entry("anthropic", "claude-3-5-sonnet", 3_000, 1_200),
+            entry("openai", "gpt-4o", 500, 600),
+        ]
+    }
+
+    fn run(select: &str, group_by: Option<&str>, order_by: Option<&str>, filter: &str) -> Results {
+        Query::parse(select, group_by, order_by, None)
+            .unwrap()
+            .run(entries(), &Filter::parse(filter).unwrap())
+    }
+
+    #[test]
+    fn test_grouped_aggregates() {
+        let results = run(
+            "model, count(), sum(tokens), avg(latency_ms), max(latency_ms)",
+            Some("model"),
+            Some("sum(tokens) desc"),
+            "provider=anthropic",
+        );
+        assert_eq!(
+            results.columns,
+            [
+                "model",
+                "count()",
+                "sum(tokens)",
+                "avg(latency_ms)",
+                "max(latency_ms)"
+            ]
+        );
+        assert_eq!(
+            results.rows,
+            [
+                vec![
+                    Value::from("claude-3-5-sonnet"),
+                    Value::from(2),
+                    Value::from(4_000),
+                    Value::from(1_000.0),
+                    Value::from(1_200)
+                ],
+                vec![
+                    Value::from("claude-3-5-haiku"),
+                    Value::from(1),
+                    Value::from(200),
+                    Value::from(300.0),
+                    Value::from(300)
+                ],
+            ]
+        );
+        assert_eq!(
+            results.to_string(),
+            concat!(
+                "model              count()  sum(tokens)  avg(latency_ms)  max(latency_ms)\n",
+                "claude-3-5-sonnet        2         4000           1000.0             1200\n",
+                "claude-3-5-haiku         1          200            300.0              300\n",
+            )
+        );
+        assert_eq!(
+            results.to_json()[1],
+            serde_json::json!({"model": "claude-3-5-haiku", "count()": 1, "sum(tokens)": 200,
+                "avg(latency_ms)": 300.0, "max(latency_ms)": 300})
+        );
+
+        // Totals over everything, and over nothing. p95 is estimated to within 3%.
+        let totals = run("count(), sum(tokens), p95(latency_ms)", None, None, "");
+        assert_eq!(
+            totals.rows,
+            [vec![Value::from(4), Value::from(4_700), Value::from(1_183)]]
+        );
+        let none = run("count(*), sum(tokens)", None, None, "provider=gemini");
+        assert_eq!(none.rows, [vec![Value::from(0), Value::Null]]);
+    }
+
+    #[test]
+    fn test_plain_rows() {
+        let query = Query::parse("provider, model, tokens", None, None, Some(2)).unwrap();
+        let results = query.run(entries(), &Filter::parse("").unwrap());
+        assert_eq!(results.rows.len(), 2);
+        assert_eq!(
+            results.to_csv(),
+            concat!(
+                "provider,model,tokens\n",
+                "anthropic,claude-3-5-sonnet,1000\n",
+                "anthropic,claude-3-5-haiku,200\n",
+            )
+        );
+        let mut quoted = entry("openai", "gpt-4o", 1, 1);
+        quoted.error = Some("upstream error: \"timed out\", gave up".to_string());
+        let results = Query::parse("error", None, None, None)
+            .unwrap()
+            .run([quoted], &Filter::parse("").unwrap());
+        assert_eq!(
+            results.to_csv(),
+            "error\n\"upstream error: \"\"timed out\"\", gave up\"\n"
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let cases = [
+            (
+                ("", None, None),
+                "--select: expected a column at the end at column 1",
+            ),
+            (
+                ("model,", None, None),
+                "--select: expected a column at the end at column 7",
+            ),
+            (
+                ("model sum(tokens)", None, None),
+                "--select: expected , between columns, found \"s\" at column 7",
+            ),
+            (
+                ("@", None, None),
+                "--select: expected a column, found \"@\" at column 1",
+            ),
+            (
+                ("modle", None, None),
+                "--select: unknown field \"modle\" (expected {FIELDS}) at column 1",
+            ),
+            (
+                ("sum", None, None),
+                "--select: sum needs parentheses, e.g. sum(tokens) at column 1",
+            ),
+            (
+                ("median(tokens)", None, None),
+                "--select: unknown function \"median\" (expected {FUNCTIONS}) at column 1",
+            ),
+            (
+                ("sum(tokens", None, None),
+                "--select: expected ) after sum(, found the end at column 11",
+            ),
+            (
+                ("count(tokens, model)", None, None),
+                "--select: expected ) after count(, found \",\" at column 13",
+            ),
+            (
+                ("sum()", None, None),
+                "--select: sum needs a numeric field, e.g. sum(tokens) at column 1",
+            ),
+            (
+                ("avg(*)", None, None),
+                "--select: avg needs a numeric field, e.g. avg(tokens) at column 1",
+            ),
+            (
+                ("max(model)", None, None),
+                "--select: max needs a numeric field, and model is text at column 5",
+            ),
+            (
+                ("model, count()", None, None),
+                "--select: model must be in --group-by to select it next to aggregates at column 1",
+            ),
+            (
+                ("count(), model", Some("provider"), None),
+                concat!(
+                    "--select: model must be in --group-by to select it next to aggregates",
+                    " at column 10",
+                ),
+            ),
+            (
+                ("model", Some("model"), None),
+                "--group-by: grouping needs an aggregate in --select, e.g. count() at column 1",
+            ),
+            (
+                ("count()", Some("sum(tokens)"), None),
+                "--group-by: can't group by sum(tokens), only by fields at column 1",
+            ),
+            (
+                ("count()", Some("colour"), None),
+                "--group-by: unknown field \"colour\" (expected {FIELDS}) at column 1",
+            ),
+            (
+                ("count()", None, Some("sum(tokens)")),
+                concat!(
+                    "--order-by: sum(tokens) isn't in --select, so results can't be ordered by it",
+                    " at column 1",
+                ),
+            ),
+            (
+                ("count()", None, Some("count() down")),
+                "--order-by: expected asc or desc, found \"down\" at column 9",
+            ),
+            (
+                ("count()", None, Some("count() desc, model")),
+                "--order-by: unexpected \",\" at column 13",
+            ),
+        ];
+        for ((select, group_by, order_by), expected) in cases {
+            let expected = expected
+                .replace("{FIELDS}", FIELDS)
+                .replace("{FUNCTIONS}", FUNCTIONS);
+            let error = Query::parse(select, group_by, order_by, None).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                expected,
+                "{:?}",
+                (select, group_by, order_by)
+            );
+        }
+        // Names are case-insensitive and spacing is free
+        assert!(Query::parse(
+            " Model ,COUNT( * ) ",
+            Some("MODEL"),
+            Some("count(*) DESC"),
+            None
+        )
+        .is_ok());
+    }
+}
@@ -0,0 +1,412 @@
+//! Reads archived requests back as events, the inverse of the archive
+//! sinks: JSON request bodies by their file names, and `.jsonl` event
+//! files, such as a `sherlock record` bundle's `events.jsonl` copied into
+//! the archive. `sherlock stats --archive` totals what it reads, for
+//! archives the index doesn't cover.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, NaiveDate, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::archive::{archived_requests, load_archived_request, MARKERS_FILE};
+use crate::dashboard::format_number;
+use crate::event::RequestEvent;
+use crate::filter::{Filter, Subject};
+use crate::index::INDEX_FILE;
+
+/// Largest requests listed in `ArchiveStats`
+const LARGEST: usize = 10;
+
+/// A request read back from the archive
+#[derive(Debug, Clone)]
+pub struct ArchivedRequest {
+    pub event: RequestEvent,
+    /// File name it was read from, with the line for event files
+    pub source: String,
+}
+
+/// Every request in `dir`, JSON files newest first and then each event
+/// file in name order. Files and lines that don't read as a request come
+/// out as errors, so callers can skip them; failures and markers in event
+/// files are left out.
+pub fn read_archive(dir: &Path) -> Result<impl Iterator<Item = Result<ArchivedRequest>>> {
+    let requests = archived_requests(dir)
+        .with_context(|| format!("Failed to read the archive in {:?}", dir))?;
+    let mut event_files: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            let name = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or_default();
+            name.ends_with(".jsonl")
+                && !name.starts_with('.')
+                && name != INDEX_FILE
+                && name != MARKERS_FILE
+        })
+        .collect();
+    event_files.sort();
+
+    let bodies = requests.into_iter().map(|path| {
+        Ok(ArchivedRequest {
+            event: load_archived_request(&path)?,
+            source: file_name(&path),
+        })
+    });
+    let events = event_files
+        .into_iter()
+        .flat_map(|path| match read_events(&path) {
+            Ok(requests) => requests,
+            Err(e) => vec![Err(e)],
+        });
+    Ok(bodies.chain(events))
+}
+
+/// The requests on each line of an event file
+fn read_events(path: &Path) -> Result<Vec<Result<ArchivedRequest>>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let name = file_name(path);
+    Ok(content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .filter_map(|(number, line)| {
+            let source = format!("{}:{}", name, number + 1);
+            let value: Value = match serde_json::from_str(line) {
+                Ok(value) => value,
+                Err(e) => return Some(Err(anyhow::anyhow!("{} is not JSON: {}", source, e))),
+            };
+            if value["status"] == "failed" || value["status"] == "marker" {
+                return None;
+            }
+            Some(
+                serde_json::from_value(value)
+                    .map(|event| ArchivedRequest {
+                        event,
+                        source: source.clone(),
+                    })
+                    .with_context(|| format!("{} is not a request", source)),
+            )
+        })
+        .collect())
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Which requests `sherlock stats` counts
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StatsFilter {
+    /// From this local day on
+    pub since: Option<NaiveDate>,
+    pub provider: Option<String>,
+    /// `--filter` expression, as in the dashboard
+    pub expr: Option<Filter>,
+}
+
+impl StatsFilter {
+    pub fn matches(&self, timestamp: DateTime<Utc>, provider: &str) -> bool {
+        self.since.is_none_or(|since| local_day(timestamp) >= since)
+            && self
+                .provider
+                .as_deref()
+                .is_none_or(|wanted| wanted.eq_ignore_ascii_case(provider))
+    }
+
+    /// Whether `subject` is counted, going by the expression as well
+    pub fn admits<S: Subject + ?Sized>(&self, subject: &S) -> bool {
+        self.matches(subject.timestamp(), subject.provider())
+            && self.expr.as_ref().is_none_or(|expr| expr.matches(subject))
+    }
+}
+
+fn local_day(timestamp: DateTime<Utc>) -> NaiveDate {
+    timestamp.with_timezone(&Local).date_naive()
+}
+
+/// Requests and tokens counted under one name
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Totals {
+    pub requests: usize,
+    pub tokens: u64,
+}
+
+impl Totals {
+    fn add(&mut self, tokens: u64) {
+        self.requests += 1;
+        self.tokens += tokens;
+    }
+}
+
+/// One of the largest requests read
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LargeRequest {
+    pub timestamp: DateTime<Utc>,
+    pub provider: String,
+    pub model: String,
+    pub tokens: u64,
+    pub source: String,
+}
+
+/// Totals over the requests read from an archive, for `sherlock stats
+/// --archive`
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ArchiveStats {
+    pub directory: PathBuf,
+    pub requests: usize,
+    pub tokens: u64,
+    /// Files and lines that didn't read as a request
+    pub skipped: usize,
+    pub providers: BTreeMap<String, Totals>,
+    pub models: BTreeMap<String, Totals>,
+    /// Per local day
+    pub days: BTreeMap<NaiveDate, Totals>,
+    /// Most tokens first
+    pub largest: Vec<LargeRequest>,
+}
+
+impl ArchiveStats {
+    /// Count the requests in `dir` that `filter` lets through, one at a
+    /// time as they are read
+    pub fn read(dir: &Path, filter: &StatsFilter) -> Result<Self> {
+        let mut skipped = 0;
+        let requests = read_archive(dir)?.filter_map(|request| match request {
+            Ok(request) => Some(request),
+            Err(e) => {
+                tracing::warn!("Skipping {:#}", e);
+                skipped += 1;
+                None
+            }
+        });
+        let mut stats = Self::build(requests, filter);
+        stats.directory = dir.to_path_buf();
+        stats.skipped = skipped;
+        Ok(stats)
+    }
+
+    pub fn build(
+        requests: impl IntoIterator<Item = ArchivedRequest>,
+        filter: &StatsFilter,
+    ) -> Self {
+        let mut stats = Self::default();
+        for ArchivedRequest { event, source } in requests {
+            if !filter.admits(&event) {
+                continue;
+            }
+            let tokens = event.tokens as u64;
+            stats.requests += 1;
+            stats.tokens += tokens;
+            stats
+                .providers
+                .entry(event.provider.clone())
+                .or_default()
+                .add(tokens);
+            stats
+                .models
+                .entry(event.model.clone())
+                .or_default()
+                .add(tokens);
+            stats
+                .days
+                .entry(local_day(event.timestamp))
+                .or_default()
+                .add(tokens);
+            stats.largest.push(LargeRequest {
+                timestamp: event.timestamp,
+                provider: event.provider,
+                model: event.model,
+                tokens,
+                source,
+            });
+        }
+        stats
+            .largest
+            .sort_by(|a, b| b.tokens.cmp(&a.tokens).then(b.timestamp.cmp(&a.timestamp)));
+        stats.largest.truncate(LARGEST);
+        stats
+    }
+}
+
+impl fmt::Display for ArchiveStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Archive: {}", self.directory.display())?;
+        if self.skipped > 0 {
+            writeln!(f, "  Skipped {} unreadable files or lines", self.skipped)?;
+        }
+        if self.requests == 0 {
+            return writeln!(f, "No archived requests");
+        }
+        writeln!(
+            f,
+            "  {} requests, {} tokens",
+            format_number(self.requests as u64),
+            format_number(self.tokens)
+        )?;
+        let section = |f: &mut fmt::Formatter<'_>, title: &str, rows: Vec<(String, Totals)>| {
+            writeln!(f, "\n{}:", title)?;
+            let width = rows.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+            for (name, totals) in rows {
+                writeln!(
+                    f,
+                    "  {:<width$}  {:>8} requests  {:>12} tokens",
+                    name,
+                    format_number(totals.requests as u64),
+                    format_number(totals.tokens),
+                    width = width
+                )?;
+            }
+            Ok(())
+        };
+        let rows = |map: &BTreeMap<String, Totals>| {
+            map.iter()
+                .map(|(name, totals)| (name.clone(), *totals))
+                .collect::<Vec<_>>()
+        };
+        section(f, "By provider", rows(&self.providers))?;
+        section(f, "By model", rows(&self.models))?;
+        let days = self
+            .days
+            .iter()
+            .map(|(day, totals)| (day.to_string(), *totals))
+            .collect();
+        section(f, "By day", days)?;
+
+        writeln!(f, "\nLargest requests:")?;
+        for request in &self.largest {
+            writeln!(
+                f,
+                "  {:>12} tokens  {}  {} {}  {}",
+                format_number(request.tokens),
+                request
+                    .timestamp
+                    .with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M"),
+                request.provider,
+                request.model,
+                request.source
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    fn write_body(dir: &Path, name: &str, model: &str, text: &str) {
+        let body = json!({"model": model, "messages": [{"role": "user", "content": text}]});
+        std::fs::write(dir.join(name), body.to_string()).unwrap();
+    }
+
+    #[test]
+    fn test_reads_bodies_and_event_files() {
+        let dir = std::env::temp_dir().join(format!("sherlock-reader-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        write_body(
+            &dir,
+            "20250101_100000.000_00000001_anthropic.json",
+            "claude-sonnet-4-5",
+            "Fix the build",
+        );
+        write_body(
+            &dir,
+            "20250102_100000.000_00000002_anthropic.json",
+            "claude-sonnet-4-5",
+            &"Explain this module. ".repeat(200),
+        );
+        write_body(
+            &dir,
+            "20250103_100000.000_00000003_openai.json",
+            "gpt-4o",
+            "Write docs",
+        );
+        std::fs::write(dir.join("20250103_110000.000_00000004_openai.json"), "{").unwrap();
+        std::fs::copy(
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/import_events.jsonl"),
+            dir.join("events.jsonl"),
+        )
+        .unwrap();
+        // Neither holds requests
+        std::fs::write(dir.join(INDEX_FILE), "{\"index_version\":1}\n").unwrap();
+        std::fs::write(dir.join(MARKERS_FILE), "{}\n").unwrap();
+
+        let stats = ArchiveStats::read(&dir, &StatsFilter::default()).unwrap();
+        assert_eq!((stats.requests, stats.skipped), (4, 1));
+        assert_eq!(stats.providers["anthropic"].requests, 2);
+        assert_eq!(stats.providers["openai"].requests, 2);
+        assert_eq!(stats.models["gpt-4o"].requests, 2);
+        assert_eq!(
+            stats.tokens,
+            stats.providers.values().map(|t| t.tokens).sum::<u64>()
+        );
+        assert_eq!(stats.days.values().map(|t| t.requests).sum::<usize>(), 4);
+        assert_eq!(stats.largest.len(), 4);
+        assert_eq!(
+            stats.largest[0].source,
+            "20250102_100000.000_00000002_anthropic.json"
+        );
+        // The recording's request, 512 tokens, outweighs the short bodies
+        assert_eq!(stats.largest[1].source, "events.jsonl:1");
+        assert_eq!(stats.largest[1].tokens, 512);
+
+        let text = stats.to_string();
+        assert!(text.contains("  4 requests, "), "{}", text);
+        assert!(text.contains("Skipped 1 unreadable"), "{}", text);
+        assert!(text.contains("\nBy model:\n"), "{}", text);
+        assert!(text.contains("\nLargest requests:\n"), "{}", text);
+
+        let openai = StatsFilter {
+            since: None,
+            provider: Some("OpenAI".to_string()),
+            expr: None,
+        };
+        let stats = ArchiveStats::read(&dir, &openai).unwrap();
+        assert_eq!(stats.requests, 2);
+        assert_eq!(stats.providers.keys().collect::<Vec<_>>(), ["openai"]);
+
+        // The bodies are read, so text terms work here
+        let build = StatsFilter {
+            expr: Some(Filter::parse("model~sonnet \"the build\"").unwrap()),
+            ..Default::default()
+        };
+        let stats = ArchiveStats::read(&dir, &build).unwrap();
+        assert_eq!(stats.requests, 1);
+        assert_eq!(
+            stats.largest[0].source,
+            "20250101_100000.000_00000001_anthropic.json"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_filter_since_local_day() {
+        let noon = |day| {
+            Local
+                .with_ymd_and_hms(2025, 6, day, 12, 0, 0)
+                .unwrap()
+                .with_timezone(&Utc)
+        };
+        let filter = StatsFilter {
+            since: NaiveDate::from_ymd_opt(2025, 6, 2),
+            provider: None,
+            expr: None,
+        };
+        assert!(!filter.matches(noon(1), "anthropic"));
+        assert!(filter.matches(noon(2), "anthropic"));
+        assert!(filter.matches(noon(3), "gemini"));
+        assert!(StatsFilter::default().matches(noon(1), "openai"));
+    }
+}
@@ -0,0 +1,792 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+use crate::aggregate::{AggregateOptions, AggregateReport, UsageRecord};
+use crate::archive::{archive_writer, sanitize_component, ArchiveEntry};
+use crate::caching::{CacheSummary, PrefixTracker};
+use crate::config::Config;
+use crate::dashboard::format_number;
+use crate::event::{
+    InFlightRequest, Marker, ProxyEvent, RequestEvent, RequestFailure, TokenComposition,
+};
+use crate::export::{render_markdown, Conversation};
+use crate::keys::KeyFingerprinter;
+use crate::language::{self, LanguageMix};
+use crate::metrics::{ArchiveMetrics, ProxyMetrics};
+use crate::policy::PolicyScanner;
+use crate::pricing::PriceTable;
+use crate::proxy::ProxyServer;
+use crate::redact::Redactor;
+use crate::stats::{Histogram, Percentiles, SessionStats};
+use crate::watermark::Peak;
+
+/// Options for `sherlock record`
+pub struct RecordOptions {
+    /// Tag added to every captured request
+    pub name: String,
+    pub out: PathBuf,
+    /// Stop after this long; otherwise only Ctrl-C ends the recording
+    pub duration: Option<Duration>,
+    /// Write into a non-empty output directory
+    pub force: bool,
+    /// Write only `aggregates.json`, leaving out anything with message text
+    pub aggregates_only: Option<AggregateOptions>,
+}
+
+/// Everything captured during a recording, in completion order
+#[derive(Debug, Default)]
+struct Recording {
+    name: String,
+    entries: Vec<Entry>,
+    in_flight: HashMap<u64, InFlightRequest>,
+}
+
+/// A request, failure or marker as it goes into a bundle's `events.jsonl`
+#[derive(Debug)]
+pub(crate) enum Entry {
+    Completed(Box<RequestEvent>),
+    Failed {
+        request: InFlightRequest,
+        error: String,
+    },
+    Marker(Marker),
+}
+
+impl Entry {
+    /// Bundle order: timestamp, then request id, so concurrent requests come
+    /// out the same way whichever finished first
+    pub(crate) fn order_key(&self) -> (DateTime<Utc>, u64) {
+        match self {
+            Entry::Completed(event) => event.order_key(),
+            Entry::Failed { request, .. } => (request.started_at, request.id),
+            // Ahead of requests from the same millisecond
+            Entry::Marker(marker) => (marker.timestamp, 0),
+        }
+    }
+
+    /// The `events.jsonl` line for the `seq`th entry, which `replay::parse_events`
+    /// reads back. Failures and markers carry the recording name themselves.
+    pub(crate) fn line(&self, seq: usize, recording: Option<&str>) -> Result<serde_json::Value> {
+        let mut value = match self {
+            Entry::Completed(event) => {
+                let mut value = serde_json::to_value(event)?;
+                value["status"] = "completed".into();
+                return Ok(with_seq(value, seq));
+            }
+            Entry::Failed { request, error } => serde_json::json!({
+                "status": "failed",
+                "timestamp": request.started_at,
+                "provider": request.provider,
+                "model": request.model,
+                "error": error,
+            }),
+            Entry::Marker(marker) => {
+                let mut value = serde_json::to_value(marker)?;
+                value["status"] = "marker".into();
+                value
+            }
+        };
+        if let Some(recording) = recording {
+            value["recording"] = recording.into();
+        }
+        Ok(with_seq(value, seq))
+    }
+}
+
+fn with_seq(mut value: serde_json::Value, seq: usize) -> serde_json::Value {
+    value["seq"] = seq.into();
+    value
+}
+
+/// `stats.json` contents, also printed as the end-of-recording summary
+#[derive(Debug, Clone, Serialize)]
+pub struct RecordingStats {
+    pub name: String,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: DateTime<Utc>,
+    pub requests: usize,
+    pub failed: usize,
+    pub total_tokens: u64,
+    /// Tokens by bucket, whatever the dashboard gauge counts
+    pub composition: TokenComposition,
+    pub tokens: Option<Percentiles>,
+    pub by_provider: BTreeMap<String, Totals>,
+    pub by_model: BTreeMap<String, Totals>,
+    /// Keyed by repository name; requests without repo info are left out
+    pub by_repo: BTreeMap<String, Totals>,
+    /// Output tokens per second by model, from streamed responses long enough to measure
+    pub throughput: BTreeMap<String, Percentiles>,
+    /// Prompt caching opportunities across follow-up requests
+    pub cache: CacheSummary,
+    /// Approximate tokens per programming language, the rest under "prose"
+    pub by_language: LanguageMix,
+    /// The largest request, and its model's context window
+    pub peak: Option<Peak>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Totals {
+    pub requests: usize,
+    pub tokens: u64,
+    /// Dollars at list price; unpriced requests add nothing
+    pub cost_usd: f64,
+}
+
+/// Run `sherlock record`: proxy headlessly until the duration elapses or
+/// Ctrl-C, then write the bundle and print a summary
+pub async fn run_recording(config: Config, options: RecordOptions) -> Result<()> {
+    prepare_output(&options.out, options.force)?;
+
+    let addr = format!("{}:{}", config.proxy.bind_address, config.proxy.port);
+    let listener = TcpListener::bind(&addr).await?;
+    let keys_dir = dirs::home_dir()
+        .ok_or_else(|| anyhow::anyhow!("Could not determine home directory"))?
+        .join(".sherlock");
+    let keys = Arc::new(KeyFingerprinter::load_or_create(&keys_dir)?);
+
+    match options.duration {
+        Some(duration) => println!(
+            "Recording {:?} on {} for {:?} (Ctrl-C to stop early)",
+            options.name, addr, duration
+        ),
+        None => println!("Recording {:?} on {} (Ctrl-C to stop)", options.name, addr),
+    }
+
+    let duration = options.duration;
+    let shutdown = async move {
+        match duration {
+            Some(duration) => {
+                tokio::select! {
+                    _ = tokio::time::sleep(duration) => {}
+                    _ = tokio::signal::ctrl_c() => {}
+                }
+            }
+            None => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    };
+
+    let stats = record(&config, &options, listener, keys, shutdown).await?;
+    let peak = stats
+        .peak
+        .as_ref()
+        .map(|peak| format!(", {}", peak))
+        .unwrap_or_default();
+    println!(
+        "Recording {:?} complete: {} requests ({} failed), {} tokens{}. Bundle written to {}",
+        stats.name,
+        stats.requests,
+        stats.failed,
+        format_number(stats.total_tokens),
+        peak,
+        options.out.display()
+    );
+    Ok(())
+}
+
+/// Serve `listener` until `shutdown` resolves, then write the bundle
+async fn record(
+    config: &Config,
+    options: &RecordOptions,
+    listener: TcpListener,
+    keys: Arc<KeyFingerprinter>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<RecordingStats> {
+    let started_at = Utc::now();
+    let (event_tx, mut event_rx) = mpsc::channel::<ProxyEvent>(1000);
+    let (archive_tx, archive_rx) = mpsc::channel::<ArchiveEntry>(100);
+
+    let redactor = Arc::new(Redactor::new(&config.redaction)?);
+    let proxy = ProxyServer::new(
+        config.proxy.clone(),
+        config.providers.clone(),
+        event_tx,
+        Arc::new(ProxyMetrics::default()),
+        keys,
+        Arc::new(PolicyScanner::new(&config.policy)?),
+        Arc::new(PriceTable::new(&config.pricing)),
+    )?
+    .with_redactor(Arc::clone(&redactor));
+    let proxy_handle = tokio::spawn(proxy.serve(listener));
+
+    let archive_config = config.archive.clone();
+    let archive_handle = tokio::spawn(archive_writer(
+        archive_rx,
+        archive_config,
+        Arc::clone(&redactor),
+        Arc::new(ArchiveMetrics::default()),
+        None,
+    ));
+
+    let mut recording = Recording {
+        name: options.name.clone(),
+        ..Recording::default()
+    };
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => break,
+            Some(event) = event_rx.recv() => {
+                for entry in recording.handle_event(event) {
+                    let _ = archive_tx.send(entry).await;
+                }
+            }
+        }
+    }
+
+    // Stop accepting, then keep whatever already finished
+    proxy_handle.abort();
+    while let Ok(event) = event_rx.try_recv() {
+        for entry in recording.handle_event(event) {
+            let _ = archive_tx.send(entry).await;
+        }
+    }
+    drop(archive_tx);
+    if let Err(e) = archive_handle.await? {
+        tracing::error!("Archive writer error: {}", e);
+    }
+
+    let ended_at = Utc::now();
+    let stats = recording.stats(started_at, ended_at);
+    match options.aggregates_only {
+        Some(aggregate_options) => {
+            let report = recording.aggregates(started_at, ended_at, aggregate_options);
+            std::fs::write(
+                options.out.join("aggregates.json"),
+                serde_json::to_string_pretty(&report)?,
+            )?;
+        }
+        None => recording.write_bundle(&options.out, config, &stats)?,
+    }
+    Ok(stats)
+}
+
+/// Create the output directory, refusing one that already has files unless `force`
+fn prepare_output(out: &Path, force: bool) -> Result<()> {
+    if out.exists() {
+        let non_empty = std::fs::read_dir(out)
+            .with_context(|| format!("{} is not a directory", out.display()))?
+            .next()
+            .is_some();
+        if non_empty && !force {
+            anyhow::bail!(
+                "{} is not empty; pass --force to record into it anyway",
+                out.display()
+            );
+        }
+    }
+    std::fs::create_dir_all(out)
+        .with_context(|| format!("Failed to create {}", out.display()))
+}
+
+impl Recording {
+    /// Tag and keep a finished request or marker, returning what goes to the archive
+    fn handle_event(&mut self, event: ProxyEvent) -> Vec<ArchiveEntry> {
+        match event {
+            ProxyEvent::Uploading(request) | ProxyEvent::Started(request) => {
+                self.in_flight.insert(request.id, request);
+                vec![]
+            }
+            ProxyEvent::Completed { id, event } => {
+                self.in_flight.remove(&id);
+                let Some(mut event) = event else {
+                    return vec![];
+                };
+                event.recording = Some(self.name.clone());
+                self.entries.push(Entry::Completed(event.clone()));
+                vec![ArchiveEntry::Request(event)]
+            }
+            ProxyEvent::Failed { id, error, event } => {
+                let Some(request) = self.in_flight.remove(&id) else {
+                    return vec![];
+                };
+                let failure = RequestFailure::upstream(&request, &error, Utc::now());
+                self.entries.push(Entry::Failed { request, error });
+                let request = event.map(|mut event| {
+                    event.recording = Some(self.name.clone());
+                    ArchiveEntry::Request(event)
+                });
+                request
+                    .into_iter()
+                    .chain(failure.map(ArchiveEntry::Failure))
+                    .collect()
+            }
+            ProxyEvent::Marker(marker) => {
+                self.entries.push(Entry::Marker(marker.clone()));
+                vec![ArchiveEntry::Marker(marker)]
+            }
+            ProxyEvent::SelfTest(_) | ProxyEvent::UpdateAvailable(_) | ProxyEvent::Tool(_) => {
+                vec![]
+            }
+        }
+    }
+
+    fn stats(&self, started_at: DateTime<Utc>, ended_at: DateTime<Utc>) -> RecordingStats {
+        let mut stats = RecordingStats {
+            name: self.name.clone(),
+            started_at,
+            ended_at,
+            requests: 0,
+            failed: 0,
+            total_tokens: 0,
+            composition: TokenComposition::default(),
+            tokens: None,
+            by_provider: BTreeMap::new(),
+            by_model: BTreeMap::new(),
+            by_repo: BTreeMap::new(),
+            throughput: BTreeMap::new(),
+            cache: CacheSummary::default(),
+            by_language: LanguageMix::new(),
+            peak: None,
+        };
+        let mut histogram = Histogram::new();
+        let mut speeds = SessionStats::default();
+
+        for entry in &self.entries {
+            let event = match entry {
+                Entry::Completed(event) => event,
+                Entry::Failed { .. } => {
+                    stats.failed += 1;
+                    continue;
+                }
+                Entry::Marker(_) => continue,
+            };
+            stats.requests += 1;
+            stats.total_tokens += event.tokens as u64;
+            stats.composition += event.composition();
+            language::merge(&mut stats.by_language, &language::classify(event));
+            histogram.record(event.tokens as u64);
+            if let Some(throughput) = &event.throughput {
+                speeds.record_throughput(&event.model, throughput);
+            }
+            let repo = event
+                .repo
+                .as_ref()
+                .map(|repo| stats.by_repo.entry(repo.name()).or_default());
+            for totals in [
+                stats.by_provider.entry(event.provider.clone()).or_default(),
+                stats.by_model.entry(event.model.clone()).or_default(),
+            ]
+            .into_iter()
+            .chain(repo)
+            {
+                totals.requests += 1;
+                totals.tokens += event.tokens as u64;
+                totals.cost_usd += event.cost_usd.unwrap_or(0.0);
+            }
+        }
+
+        // Conversations are compared request by request, in order
+        let mut completed: Vec<&RequestEvent> = self
+            .entries
+            .iter()
+            .filter_map(|entry| match entry {
+                Entry::Completed(event) => Some(event.as_ref()),
+                _ => None,
+            })
+            .collect();
+        completed.sort_by_key(|event| event.order_key());
+        let mut prefixes = PrefixTracker::default();
+        for event in completed {
+            if let Some((_, reuse)) = prefixes.observe(event) {
+                stats.cache.record(&reuse);
+            }
+            // The first of equal peaks, in order, so the bundle is the same every time
+            if stats
+                .peak
+                .as_ref()
+                .is_none_or(|peak| event.tokens as u64 > peak.tokens)
+            {
+                stats.peak = Some(Peak::of(event));
+            }
+        }
+
+        stats.tokens = histogram.percentiles();
+        stats.throughput = speeds
+            .throughput
+            .iter()
+            .filter_map(|(model, hist)| Some((model.clone(), hist.percentiles()?)))
+            .collect();
+        stats
+    }
+
+    /// Content-free report for `--aggregates-only`, built from usage records
+    /// rather than the events themselves
+    fn aggregates(
+        &self,
+        started_at: DateTime<Utc>,
+        ended_at: DateTime<Utc>,
+        options: AggregateOptions,
+    ) -> AggregateReport {
+        let mut entries: Vec<&Entry> = self.entries.iter().collect();
+        entries.sort_by_key(|entry| entry.order_key());
+        let records: Vec<UsageRecord> = entries
+            .into_iter()
+            .filter_map(|entry| match entry {
+                Entry::Completed(event) => Some(UsageRecord::from(event.as_ref())),
+                Entry::Failed { request, .. } => Some(UsageRecord::from(request)),
+                Entry::Marker(_) => None,
+            })
+            .collect();
+        AggregateReport::build(&self.name, started_at, ended_at, &records, options)
+    }
+
+    /// Write `events.jsonl`, `conversations/*.md`, `stats.json` and `config.json`.
+    /// Everything is ordered by `Entry::order_key` and named by position, so
+    /// the same traffic always produces the same files.
+    fn write_bundle(&self, out: &Path, config: &Config, stats: &RecordingStats) -> Result<()> {
+        let conversations = out.join("conversations");
+        std::fs::create_dir_all(&conversations)?;
+
+        let mut entries: Vec<&Entry> = self.entries.iter().collect();
+        entries.sort_by_key(|entry| entry.order_key());
+
+        let mut events = String::new();
+        for (index, entry) in entries.into_iter().enumerate() {
+            let seq = index + 1;
+            if let Entry::Completed(event) = entry {
+                if !event.raw_body.is_null() {
+                    let mut conversation = Conversation::from_request_body(&event.raw_body);
+                    conversation.repo = event.repo.clone();
+                    conversation.cost_usd = event.cost_usd;
+                    let file = format!(
+                        "{:04}_{}_{}.md",
+                        seq,
+                        sanitize_component(&event.provider),
+                        sanitize_component(&event.model)
+                    );
+                    std::fs::write(conversations.join(file), render_markdown(&conversation))?;
+                }
+            }
+            let line = entry.line(seq, Some(&self.name))?;
+            events.push_str(&serde_json::to_string(&line)?);
+            events.push('\n');
+        }
+
+        std::fs::write(out.join("events.jsonl"), events)?;
+        std::fs::write(out.join("stats.json"), serde_json::to_string_pretty(stats)?)?;
+        std::fs::write(out.join("config.json"), serde_json::to_string_pretty(config)?)?;
+        Ok(())
+    }
+}
+
+/// Parse durations like `90s`, `15m`, `2h` or `1d`; a bare number is seconds
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+    let value: u64 = value
+        .parse()
+        .map_err(|_| format!("invalid duration {:?}", s))?;
+    let secs = match unit {
+        "" | "s" => value,
+        "m" => value * 60,
+        "h" => value * 60 * 60,
+        "d" => value * 24 * 60 * 60,
+        _ => return Err(format!("unknown duration unit {:?} (use s, m, h or d)", unit)),
+    };
+    Ok(Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ProviderConfig;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("sherlock-record-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// Mock provider answering every request with a fixed JSON body
+    fn mock_provider() -> String {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                loop {
+                    let text = String::from_utf8_lossy(&request).to_lowercase();
+                    if let Some(end) = text.find("\r\n\r\n") {
+                        let length: usize = text
+                            .lines()
+                            .find_map(|line| line.strip_prefix("content-length:"))
+                            .and_then(|value| value.trim().parse().ok())
+                            .unwrap_or(0);
+                        if request.len() >= end + 4 + length {
+                            break;
+                        }
+                    }
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let body = r#"{"type":"message","content":[{"type":"text","text":"hi"}]}"#;
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\n\
+                     content-length: {}\r\nconnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn test_record_bundle() {
+        let out = temp_dir("bundle");
+        let key_dir = temp_dir("bundle-keys");
+        let mut config = Config::default();
+        config.archive.enabled = false;
+        config.providers.insert(
+            "anthropic".to_string(),
+            ProviderConfig {
+                base_url: mock_provider(),
+                ..config.providers["anthropic"].clone()
+            },
+        );
+        let options = RecordOptions {
+            name: "experiment1".to_string(),
+            out: out.clone(),
+            duration: None,
+            force: false,
+            aggregates_only: None,
+        };
+        prepare_output(&out, false).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_url = format!("http://{}/v1/messages", listener.local_addr().unwrap());
+        let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+        let keys = Arc::new(KeyFingerprinter::load_or_create(&key_dir).unwrap());
+        let recorder = tokio::spawn(async move {
+            let shutdown = async {
+                let _ = stop_rx.await;
+            };
+            record(&config, &options, listener, keys, shutdown).await
+        });
+
+        let client = reqwest::Client::new();
+        for prompt in ["first prompt", "second prompt"] {
+            let body = serde_json::json!({
+                "model": "claude-3",
+                "messages": [{"role": "user", "content": prompt}]
+            });
+            let resp = client.post(&proxy_url).json(&body).send().await.unwrap();
+            assert_eq!(resp.status(), 200);
+            resp.bytes().await.unwrap();
+        }
+        // Completion events are emitted just after the body finishes relaying
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        stop_tx.send(()).unwrap();
+        let stats = recorder.await.unwrap().unwrap();
+
+        assert_eq!(stats.requests, 2);
+        assert_eq!(stats.by_model["claude-3"].requests, 2);
+
+        let events = std::fs::read_to_string(out.join("events.jsonl")).unwrap();
+        let events: Vec<serde_json::Value> = events
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["seq"], 1);
+        assert_eq!(events[1]["seq"], 2);
+        assert_eq!(events[1]["recording"], "experiment1");
+        assert_eq!(events[1]["messages"][0]["content"], "second prompt");
+
+        let conversation =
+            std::fs::read_to_string(out.join("conversations/0001_anthropic_claude-3.md")).unwrap();
+        assert!(conversation.contains("first prompt"));
+        let saved: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(out.join("stats.json")).unwrap())
+                .unwrap();
+        assert_eq!(saved["requests"], 2);
+        assert_eq!(saved["composition"]["conversation"], saved["total_tokens"]);
+        assert_eq!(saved["cache"]["missed_requests"], 0);
+        assert_eq!(saved["peak"]["tokens"], stats.peak.as_ref().unwrap().tokens);
+        assert_eq!(saved["peak"]["model"], "claude-3");
+        assert_eq!(saved["peak"]["limit"], 200_000);
+        assert!(out.join("config.json").exists());
+
+        // The bundle directory is now non-empty
+        assert!(prepare_output(&out, false).is_err());
+        prepare_output(&out, true).unwrap();
+
+        std::fs::remove_dir_all(&out).unwrap();
+        std::fs::remove_dir_all(&key_dir).unwrap();
+    }
+
+    /// 1,000 requests from 10 concurrent sessions, 25 to a millisecond,
+    /// completing in an order set by `seed`
+    fn concurrent_traffic(seed: u64) -> Recording {
+        let start = "2025-01-01T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let mut events = Vec::new();
+        for i in 0..1_000u64 {
+            let session = i % 10;
+            let started_at = start + chrono::Duration::milliseconds((i / 25) as i64);
+            let request = InFlightRequest {
+                id: i + 1,
+                provider: "anthropic".to_string(),
+                model: Some(format!("model-{}", session)),
+                started_at,
+                upload: None,
+            };
+            let event = if i % 97 == 0 {
+                ProxyEvent::Failed {
+                    id: i + 1,
+                    error: "upstream error".to_string(),
+                    event: None,
+                }
+            } else {
+                let content = format!("session {} turn {}", session, i / 10);
+                let body = serde_json::json!({
+                    "model": format!("model-{}", session),
+                    "messages": [{"role": "user", "content": content}]
+                });
+                let mut event = crate::parser::parse_request(
+                    body.to_string().as_bytes(),
+                    "/v1/messages",
+                    "anthropic",
+                )
+                .unwrap();
+                event.timestamp = started_at;
+                event.id = i + 1;
+                ProxyEvent::Completed {
+                    id: i + 1,
+                    event: Some(Box::new(event)),
+                }
+            };
+            events.push((request, event));
+        }
+
+        // Fisher-Yates with a fixed LCG so each seed is one reproducible interleaving
+        let mut state = seed;
+        for i in (1..events.len()).rev() {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            events.swap(i, (state >> 33) as usize % (i + 1));
+        }
+
+        let mut recording = Recording {
+            name: "concurrent".to_string(),
+            ..Recording::default()
+        };
+        for (request, event) in events {
+            recording.handle_event(ProxyEvent::Started(request));
+            recording.handle_event(event);
+        }
+        recording
+    }
+
+    fn read_tree(dir: &Path) -> BTreeMap<PathBuf, Vec<u8>> {
+        let mut files = BTreeMap::new();
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                files.extend(read_tree(&path));
+            } else {
+                let content = std::fs::read(&path).unwrap();
+                files.insert(path.strip_prefix(dir).unwrap_or(&path).to_path_buf(), content);
+            }
+        }
+        files
+    }
+
+    #[test]
+    fn test_bundle_is_deterministic_under_concurrency() {
+        let config = Config::default();
+        let start = "2025-01-01T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let end = start + chrono::Duration::minutes(1);
+
+        let mut bundles = Vec::new();
+        for seed in [1, 2] {
+            let recording = concurrent_traffic(seed);
+            let out = temp_dir(&format!("deterministic-{}", seed));
+            std::fs::create_dir_all(&out).unwrap();
+            let stats = recording.stats(start, end);
+            recording.write_bundle(&out, &config, &stats).unwrap();
+            bundles.push(read_tree(&out));
+            std::fs::remove_dir_all(&out).unwrap();
+        }
+
+        // events, stats and config, plus a conversation per completed request
+        assert_eq!(bundles[0].len(), 3 + 1_000 - 11);
+        for (path, content) in &bundles[0] {
+            assert!(bundles[1].get(path) == Some(content), "{} differs", path.display());
+        }
+        assert_eq!(bundles[0].len(), bundles[1].len());
+    }
+
+    #[test]
+    fn test_markers_in_bundle() {
+        let start = "2025-01-01T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let mut recording = concurrent_traffic(3);
+        // Arriving out of order, the later one first
+        for (label, ms) in [("ran tests", 30), ("started refactor", 10)] {
+            let marker = Marker {
+                label: label.to_string(),
+                timestamp: start + chrono::Duration::milliseconds(ms),
+                tag: Some("work".to_string()),
+                session: Some("sherlock".to_string()),
+            };
+            let archived = recording.handle_event(ProxyEvent::Marker(marker.clone()));
+            assert!(matches!(archived.as_slice(), [ArchiveEntry::Marker(m)] if *m == marker));
+        }
+
+        let out = temp_dir("markers");
+        std::fs::create_dir_all(&out).unwrap();
+        let stats = recording.stats(start, start + chrono::Duration::minutes(1));
+        assert_eq!((stats.requests, stats.failed), (1_000 - 11, 11));
+        recording
+            .write_bundle(&out, &Config::default(), &stats)
+            .unwrap();
+
+        let events = std::fs::read_to_string(out.join("events.jsonl")).unwrap();
+        let lines: Vec<serde_json::Value> = events
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let markers: Vec<&serde_json::Value> = lines
+            .iter()
+            .filter(|line| line["status"] == "marker")
+            .collect();
+        assert_eq!(markers.len(), 2);
+        // Each comes ahead of the requests from its millisecond, after all earlier ones
+        assert_eq!(markers[0]["label"], "started refactor");
+        assert_eq!(markers[0]["seq"], 251);
+        assert_eq!(markers[1]["label"], "ran tests");
+        assert_eq!(markers[1]["seq"], 752);
+        assert_eq!(markers[1]["session"], "sherlock");
+        assert_eq!(markers[1]["tag"], "work");
+        let marker: Marker = serde_json::from_value(markers[0].clone()).unwrap();
+        assert_eq!(marker.timestamp, start + chrono::Duration::milliseconds(10));
+        assert_eq!(lines[251]["timestamp"], markers[0]["timestamp"]);
+        std::fs::remove_dir_all(&out).unwrap();
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("15m"), Ok(Duration::from_secs(900)));
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert!(parse_duration("2w").is_err());
+        assert!(parse_duration("h").is_err());
+    }
+}
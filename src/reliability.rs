@@ -0,0 +1,329 @@
+use chrono::{DateTime, Local, TimeDelta, Timelike, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::config::SloConfig;
+use crate::event::{RequestEvent, RequestFailure};
+
+/// Fewest requests an hour of the day needs before it can be the worst one
+const MIN_HOUR_REQUESTS: usize = 5;
+
+/// How one forwarded request went
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sample {
+    pub timestamp: DateTime<Utc>,
+    pub provider: String,
+    /// HTTP status, or `None` when no complete response arrived
+    pub status: Option<u16>,
+    pub latency_ms: u64,
+}
+
+impl Sample {
+    /// Outcome of a completed request, if the proxy saw it answered and
+    /// chaos mode didn't interfere
+    pub fn of(event: &RequestEvent) -> Option<Self> {
+        if event.chaos.is_some() {
+            return None;
+        }
+        let response = event.response?;
+        Some(Self {
+            timestamp: event.timestamp,
+            provider: event.provider.clone(),
+            status: Some(response.status),
+            latency_ms: response.latency_ms,
+        })
+    }
+
+    /// `4xx`, `5xx` or `network` for a failed request
+    fn error_class(&self) -> Option<&'static str> {
+        match self.status {
+            None => Some("network"),
+            Some(status) if status >= 500 => Some("5xx"),
+            Some(status) if status >= 400 => Some("4xx"),
+            Some(_) => None,
+        }
+    }
+}
+
+impl From<&RequestFailure> for Sample {
+    fn from(failure: &RequestFailure) -> Self {
+        Self {
+            timestamp: failure.timestamp,
+            provider: failure.provider.clone(),
+            status: None,
+            latency_ms: failure.latency_ms,
+        }
+    }
+}
+
+/// Outcomes of one provider's requests over one window
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct WindowStats {
+    pub requests: usize,
+    /// Succeeded within the latency threshold
+    pub good: usize,
+    /// Succeeded, but slower than the threshold
+    pub slow: usize,
+    /// Failed requests by class: `4xx`, `5xx` or `network`
+    pub errors: BTreeMap<&'static str, usize>,
+}
+
+impl WindowStats {
+    fn record(&mut self, sample: &Sample, latency_ms: u64) {
+        self.requests += 1;
+        match sample.error_class() {
+            Some(class) => *self.errors.entry(class).or_default() += 1,
+            None if sample.latency_ms <= latency_ms => self.good += 1,
+            None => self.slow += 1,
+        }
+    }
+
+    /// Percentage of requests that were good
+    pub fn success_rate(&self) -> Option<f64> {
+        (self.requests > 0).then(|| self.good as f64 * 100.0 / self.requests as f64)
+    }
+
+    /// e.g. "5xx 3 · network 1 · slow 2", or "no errors"
+    pub fn problems(&self) -> String {
+        let mut parts: Vec<String> = self
+            .errors
+            .iter()
+            .map(|(class, count)| format!("{} {}", class, count))
+            .collect();
+        if self.slow > 0 {
+            parts.push(format!("slow {}", self.slow));
+        }
+        if parts.is_empty() {
+            "no errors".to_string()
+        } else {
+            parts.join(" · ")
+        }
+    }
+
+    /// e.g. "97.5% of 120"
+    pub fn describe(&self) -> String {
+        match self.success_rate() {
+            Some(rate) => format!("{:.1}% of {}", rate, self.requests),
+            None => "-".to_string(),
+        }
+    }
+}
+
+/// Hour of the day whose requests fared worst
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WorstHour {
+    /// Local hour, 0 to 23
+    pub hour: u32,
+    pub requests: usize,
+    pub success_rate: f64,
+}
+
+impl fmt::Display for WorstHour {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:00–{:02}:00 {:.1}% of {}",
+            self.hour,
+            (self.hour + 1) % 24,
+            self.success_rate,
+            self.requests
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ProviderReliability {
+    pub provider: String,
+    /// The last 24 hours
+    pub day: WindowStats,
+    /// The configured `slo.window`
+    pub window: WindowStats,
+    /// Over the window, ignoring hours with too few requests to judge
+    pub worst_hour: Option<WorstHour>,
+}
+
+/// Per provider share of requests answered successfully within the latency
+/// threshold, over the last day and the configured window
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReliabilityReport {
+    pub latency_ms: u64,
+    /// Label of the longer window, e.g. "7d"
+    pub window: String,
+    pub providers: Vec<ProviderReliability>,
+}
+
+impl ReliabilityReport {
+    pub fn build<'a>(
+        samples: impl IntoIterator<Item = &'a Sample>,
+        slo: &SloConfig,
+        now: DateTime<Utc>,
+    ) -> Self {
+        // Checked when the config loads
+        let window = slo.window().unwrap_or(TimeDelta::days(7));
+        let latency_ms = slo.latency_ms;
+        let mut providers: BTreeMap<&str, (WindowStats, WindowStats, Vec<WindowStats>)> =
+            BTreeMap::new();
+        for sample in samples {
+            let age = now - sample.timestamp;
+            if age > window.max(TimeDelta::days(1)) {
+                continue;
+            }
+            let (day, long, hours) = providers.entry(&sample.provider).or_default();
+            if age <= TimeDelta::days(1) {
+                day.record(sample, latency_ms);
+            }
+            if age <= window {
+                long.record(sample, latency_ms);
+                if hours.is_empty() {
+                    hours.resize(24, WindowStats::default());
+                }
+                let hour = sample.timestamp.with_timezone(&Local).hour();
+                hours[hour as usize].record(sample, latency_ms);
+            }
+        }
+
+        let providers = providers
+            .into_iter()
+            .map(|(provider, (day, window, hours))| ProviderReliability {
+                provider: provider.to_string(),
+                day,
+                window,
+                worst_hour: worst_hour(&hours),
+            })
+            .collect();
+        Self {
+            latency_ms,
+            window: slo.window.clone(),
+            providers,
+        }
+    }
+}
+
+/// Lowest success rate among hours with enough requests; busier hours win ties
+fn worst_hour(hours: &[WindowStats]) -> Option<WorstHour> {
+    hours
+        .iter()
+        .enumerate()
+        .filter(|(_, stats)| stats.requests >= MIN_HOUR_REQUESTS)
+        .filter_map(|(hour, stats)| {
+            Some(WorstHour {
+                hour: hour as u32,
+                requests: stats.requests,
+                success_rate: stats.success_rate()?,
+            })
+        })
+        .min_by(|a, b| {
+            a.success_rate
+                .total_cmp(&b.success_rate)
+                .then(b.requests.cmp(&a.requests))
+        })
+}
+
+impl fmt::Display for ReliabilityReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Requests answered successfully within {} ms, last 24h and {}",
+            self.latency_ms, self.window
+        )?;
+        if self.providers.is_empty() {
+            return writeln!(f, "\nNo requests with a recorded outcome yet");
+        }
+        for provider in &self.providers {
+            writeln!(f, "\n{}", provider.provider)?;
+            for (label, stats) in [
+                ("24h", &provider.day),
+                (self.window.as_str(), &provider.window),
+            ] {
+                writeln!(
+                    f,
+                    "  {:<5} {:<16} {}",
+                    label,
+                    stats.describe(),
+                    stats.problems()
+                )?;
+            }
+            if let Some(worst) = &provider.worst_hour {
+                writeln!(f, "  worst hour {}", worst)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(provider: &str, timestamp: DateTime<Utc>, status: Option<u16>, ms: u64) -> Sample {
+        Sample {
+            timestamp,
+            provider: provider.to_string(),
+            status,
+            latency_ms: ms,
+        }
+    }
+
+    #[test]
+    fn test_windows_and_error_classes() {
+        let now = Utc::now();
+        let day_ago = now - TimeDelta::hours(30);
+        let samples = [
+            sample("anthropic", now, Some(200), 800),
+            sample("anthropic", now, Some(200), 12_000),
+            sample("anthropic", now, Some(529), 300),
+            sample("anthropic", day_ago, Some(200), 900),
+            sample("anthropic", day_ago, None, 30_000),
+            sample("anthropic", now - TimeDelta::days(9), Some(200), 100),
+            sample("openai", now, Some(429), 50),
+        ];
+        let report = ReliabilityReport::build(&samples, &SloConfig::default(), now);
+
+        assert_eq!(report.providers.len(), 2);
+        let anthropic = &report.providers[0];
+        assert_eq!((anthropic.day.requests, anthropic.day.good), (3, 1));
+        assert_eq!(anthropic.day.problems(), "5xx 1 · slow 1");
+        assert_eq!((anthropic.window.requests, anthropic.window.good), (5, 2));
+        assert_eq!(anthropic.window.errors["network"], 1);
+        assert_eq!(anthropic.window.describe(), "40.0% of 5");
+        assert_eq!(anthropic.worst_hour, None);
+        let openai = &report.providers[1];
+        assert_eq!(openai.day.success_rate(), Some(0.0));
+        assert_eq!(openai.day.problems(), "4xx 1");
+    }
+
+    #[test]
+    fn test_worst_hour() {
+        let now = Utc::now();
+        let bad = now - TimeDelta::days(2);
+        let good = now - TimeDelta::hours(5);
+        let mut samples = Vec::new();
+        for i in 0..6 {
+            let status = if i < 3 { Some(500) } else { Some(200) };
+            samples.push(sample("anthropic", bad, status, 500));
+            samples.push(sample("anthropic", good, Some(200), 500));
+        }
+        // Too few to judge, however bad
+        samples.push(sample("anthropic", now - TimeDelta::hours(11), None, 500));
+        let slo = SloConfig {
+            window: "3d".to_string(),
+            ..SloConfig::default()
+        };
+        let report = ReliabilityReport::build(&samples, &slo, now);
+
+        let worst = report.providers[0].worst_hour.clone().unwrap();
+        let hour = bad.with_timezone(&Local).hour();
+        assert_eq!(
+            (worst.hour, worst.requests, worst.success_rate),
+            (hour, 6, 50.0)
+        );
+        let text = report.to_string();
+        assert!(
+            text.contains(&format!("  worst hour {:02}:00–", hour)),
+            "{}",
+            text
+        );
+        assert!(text.contains("  3d    69.2% of 13"), "{}", text);
+    }
+}
@@ -0,0 +1,445 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, NaiveDate, TimeDelta, Utc};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind};
+use ratatui::{backend::CrosstermBackend, Terminal};
+use serde_json::Value;
+use std::io::Stdout;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::archive::{archived_requests, load_archived_request, MARKERS_FILE};
+use crate::config::GoalsConfig;
+use crate::dashboard::{restore_terminal, setup_terminal, Dashboard};
+use crate::event::{InFlightRequest, Marker, ProxyEvent, RequestEvent};
+use crate::index::read_index;
+
+/// Playback speeds cycled with `x`
+const SPEEDS: [u32; 2] = [1, 10];
+
+/// Longest quiet stretch played out in full; playback skips to just before
+/// the next step instead of waiting
+const MAX_IDLE: TimeDelta = TimeDelta::seconds(5);
+
+/// One request, failure or marker of a replayed session, as the proxy events
+/// the dashboard saw for it
+#[derive(Debug, Clone)]
+pub struct Step {
+    pub at: DateTime<Utc>,
+    pub events: Vec<ProxyEvent>,
+}
+
+impl Step {
+    fn completed(event: RequestEvent) -> Self {
+        Self {
+            at: event.timestamp,
+            events: vec![ProxyEvent::Completed {
+                id: event.id,
+                event: Some(Box::new(event)),
+            }],
+        }
+    }
+
+    fn failed(request: InFlightRequest, error: String) -> Self {
+        let id = request.id;
+        Self {
+            at: request.started_at,
+            events: vec![
+                ProxyEvent::Started(request),
+                ProxyEvent::Failed {
+                    id,
+                    error,
+                    event: None,
+                },
+            ],
+        }
+    }
+
+    fn marker(marker: Marker) -> Self {
+        Self {
+            at: marker.timestamp,
+            events: vec![ProxyEvent::Marker(marker)],
+        }
+    }
+}
+
+/// Archived requests, failures and markers from the local calendar `date`,
+/// oldest first
+pub fn from_archive(dir: &Path, date: NaiveDate) -> Result<Vec<Step>> {
+    let on_date = |at: DateTime<Utc>| at.with_timezone(&Local).date_naive() == date;
+    // Archive names start with the UTC date, at most a day away from the local one
+    let utc_days: Vec<String> = [date.pred_opt(), Some(date), date.succ_opt()]
+        .into_iter()
+        .flatten()
+        .map(|day| day.format("%Y%m%d").to_string())
+        .collect();
+
+    let mut steps = Vec::new();
+    for path in archived_requests(dir)? {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if !utc_days.iter().any(|day| name.starts_with(day.as_str())) {
+            continue;
+        }
+        match load_archived_request(&path) {
+            Ok(event) if on_date(event.timestamp) => steps.push(Step::completed(event)),
+            Ok(_) => {}
+            Err(e) => tracing::warn!("Skipping {:?}: {:#}", path, e),
+        }
+    }
+    for entry in read_index(dir)? {
+        let Some(error) = entry.error else {
+            continue;
+        };
+        if on_date(entry.timestamp) {
+            let request = InFlightRequest {
+                id: entry.id,
+                provider: entry.provider,
+                model: entry.model,
+                started_at: entry.timestamp,
+                upload: None,
+            };
+            steps.push(Step::failed(request, error));
+        }
+    }
+    let markers = std::fs::read_to_string(dir.join(MARKERS_FILE)).unwrap_or_default();
+    for marker in markers
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Marker>(line).ok())
+    {
+        if on_date(marker.timestamp) {
+            steps.push(Step::marker(marker));
+        }
+    }
+    steps.sort_by_key(|step| step.at);
+    Ok(steps)
+}
+
+/// Every line of a `sherlock record` bundle's `events.jsonl`, in order
+pub fn from_recording(path: &Path) -> Result<Vec<Step>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    parse_events(&content, &path.display().to_string())
+}
+
+/// The lines of an `events.jsonl`, named `source` in errors
+pub fn parse_events(content: &str, source: &str) -> Result<Vec<Step>> {
+    let mut steps = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let value: Value = serde_json::from_str(line)
+            .with_context(|| format!("{}:{} is not JSON", source, number + 1))?;
+        let step = match value["status"].as_str() {
+            Some("failed") => {
+                let request = InFlightRequest {
+                    id: value["seq"].as_u64().unwrap_or_default(),
+                    provider: value["provider"].as_str().unwrap_or_default().to_string(),
+                    model: value["model"].as_str().map(str::to_string),
+                    started_at: serde_json::from_value(value["timestamp"].clone())?,
+                    upload: None,
+                };
+                let error = value["error"].as_str().unwrap_or_default().to_string();
+                Step::failed(request, error)
+            }
+            Some("marker") => Step::marker(serde_json::from_value(value)?),
+            _ => Step::completed(
+                serde_json::from_value(value)
+                    .with_context(|| format!("{}:{}", source, number + 1))?,
+            ),
+        };
+        steps.push(step);
+    }
+    Ok(steps)
+}
+
+/// Where a replay is in its session, and how it plays on
+#[derive(Debug)]
+pub struct Replay {
+    steps: Vec<Step>,
+    /// Steps applied to the dashboard
+    position: usize,
+    playing: bool,
+    speed: u32,
+    /// Session time playback has reached
+    clock: DateTime<Utc>,
+    goals: GoalsConfig,
+}
+
+impl Replay {
+    /// A paused replay of `steps`, marking `dashboard` as showing it
+    pub fn new(steps: Vec<Step>, goals: GoalsConfig, dashboard: &mut Dashboard) -> Self {
+        let clock = steps.first().map_or_else(Utc::now, |step| step.at);
+        let replay = Self {
+            steps,
+            position: 0,
+            playing: false,
+            speed: SPEEDS[0],
+            clock,
+            goals,
+        };
+        dashboard.set_replay_status(replay.status());
+        replay
+    }
+
+    /// Show the session as of the first `position` steps. Going back
+    /// rebuilds it from the start.
+    pub fn seek(&mut self, position: usize, dashboard: &mut Dashboard) {
+        let position = position.min(self.steps.len());
+        if position < self.position {
+            dashboard.reset_session(&self.goals);
+            self.position = 0;
+        }
+        for step in &self.steps[self.position..position] {
+            for event in &step.events {
+                dashboard.handle_event(event.clone());
+            }
+        }
+        self.position = position;
+        dashboard.set_replay_status(self.status());
+    }
+
+    /// Seek by hand, pausing the clock on the last step shown
+    fn step_to(&mut self, position: usize, dashboard: &mut Dashboard) {
+        self.seek(position, dashboard);
+        if let Some(last) = self.position.checked_sub(1) {
+            self.clock = self.steps[last].at;
+        }
+        dashboard.set_replay_status(self.status());
+    }
+
+    /// Apply a key press, returning true when the replay should end. Left
+    /// and Right step, Space plays and pauses, `x` changes speed; the rest
+    /// goes to the dashboard.
+    pub fn handle_key(&mut self, key: KeyEvent, dashboard: &mut Dashboard) -> bool {
+        if dashboard.captures_keys() {
+            return dashboard.handle_key(key);
+        }
+        match key.code {
+            KeyCode::Right => self.step_to(self.position + 1, dashboard),
+            KeyCode::Left => self.step_to(self.position.saturating_sub(1), dashboard),
+            KeyCode::Char(' ') => {
+                self.playing = !self.playing && self.position < self.steps.len();
+                dashboard.set_replay_status(self.status());
+            }
+            KeyCode::Char('x') => {
+                let next = SPEEDS
+                    .iter()
+                    .position(|s| *s == self.speed)
+                    .map_or(0, |i| i + 1);
+                self.speed = SPEEDS[next % SPEEDS.len()];
+                dashboard.set_replay_status(self.status());
+            }
+            _ => return dashboard.handle_key(key),
+        }
+        false
+    }
+
+    /// Move playback on by `elapsed` of wall time
+    pub fn tick(&mut self, elapsed: Duration, dashboard: &mut Dashboard) {
+        if !self.playing {
+            return;
+        }
+        let Some(next) = self.steps.get(self.position) else {
+            self.playing = false;
+            return dashboard.set_replay_status(self.status());
+        };
+        let played = TimeDelta::from_std(elapsed * self.speed).unwrap_or(MAX_IDLE);
+        self.clock = (self.clock + played).max(next.at - MAX_IDLE);
+        let due = self.steps[self.position..].partition_point(|step| step.at <= self.clock);
+        self.seek(self.position + due, dashboard);
+    }
+
+    /// e.g. "12/340 14:03:22 ▶ 10x"
+    fn status(&self) -> String {
+        format!(
+            "{}/{} {} {} {}x",
+            self.position,
+            self.steps.len(),
+            self.clock.format("%H:%M:%S"),
+            if self.playing { "▶" } else { "⏸" },
+            self.speed
+        )
+    }
+}
+
+/// Show `steps` in the dashboard until the user quits. No proxy runs and
+/// nothing is archived.
+pub fn run(mut dashboard: Dashboard, steps: Vec<Step>, goals: GoalsConfig, hz: u32) -> Result<()> {
+    let mut replay = Replay::new(steps, goals, &mut dashboard);
+    let mut terminal = setup_terminal()?;
+    let result = play(&mut terminal, &mut replay, &mut dashboard, hz);
+    restore_terminal(&mut terminal)?;
+    result
+}
+
+fn play(
+    terminal: &mut Terminal<CrosstermBackend<Stdout>>,
+    replay: &mut Replay,
+    dashboard: &mut Dashboard,
+    hz: u32,
+) -> Result<()> {
+    let tick_rate = Duration::from_millis(1000 / hz.max(1) as u64);
+    let mut last_tick = Instant::now();
+    loop {
+        terminal.draw(|f| dashboard.render(f))?;
+        if event::poll(tick_rate.saturating_sub(last_tick.elapsed()))? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press && replay.handle_key(key, dashboard) {
+                    return Ok(());
+                }
+            }
+        }
+        let elapsed = last_tick.elapsed();
+        if elapsed >= tick_rate {
+            last_tick = Instant::now();
+            dashboard.poll_search();
+            replay.tick(elapsed, dashboard);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::{archive_writer, ArchiveEntry};
+    use crate::config::{ArchiveConfig, DashboardConfig, SloConfig};
+    use crate::dashboard::format_number;
+    use crate::event::RequestFailure;
+    use crate::index::IndexSummary;
+    use crate::metrics::ArchiveMetrics;
+    use crate::models::ModelRegistry;
+    use crate::parser::parse_request;
+    use crossterm::event::KeyModifiers;
+    use ratatui::{backend::TestBackend, Terminal};
+    use std::sync::Arc;
+    use tokio::sync::mpsc;
+
+    fn dashboard() -> Dashboard {
+        Dashboard::new(
+            DashboardConfig::default(),
+            &GoalsConfig::default(),
+            Arc::default(),
+            Arc::default(),
+            ModelRegistry::default(),
+            SloConfig::default(),
+        )
+    }
+
+    fn screen(dashboard: &Dashboard) -> String {
+        let mut terminal = Terminal::new(TestBackend::new(120, 50)).unwrap();
+        terminal.draw(|f| dashboard.render(f)).unwrap();
+        let buffer = terminal.backend().buffer();
+        buffer.content().iter().map(|cell| cell.symbol()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_replayed_totals_match_archive_stats() {
+        let root = std::env::temp_dir().join(format!("sherlock-replay-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let config = ArchiveConfig {
+            directory: root.clone(),
+            ..ArchiveConfig::default()
+        };
+        let start = Local::now().date_naive().and_hms_opt(10, 0, 0).unwrap();
+        let start = start.and_local_timezone(Local).unwrap().with_timezone(&Utc);
+        let (tx, rx) = mpsc::channel(16);
+        for i in 0..4u64 {
+            let text = "word ".repeat(50 * (i as usize + 1));
+            let body = format!(
+                r#"{{"model":"claude-x","messages":[{{"role":"user","content":"{}"}}]}}"#,
+                text
+            );
+            let mut event = parse_request(body.as_bytes(), "/v1/messages", "anthropic").unwrap();
+            event.id = i;
+            event.timestamp = start + TimeDelta::minutes(i as i64);
+            tx.send(event.into()).await.unwrap();
+        }
+        let failure = RequestFailure {
+            timestamp: start + TimeDelta::seconds(90),
+            id: 9,
+            provider: "anthropic".to_string(),
+            model: None,
+            latency_ms: 30_000,
+            error: "upstream error: timed out".to_string(),
+        };
+        tx.send(ArchiveEntry::Failure(failure)).await.unwrap();
+        drop(tx);
+        archive_writer(
+            rx,
+            config,
+            Arc::default(),
+            Arc::new(ArchiveMetrics::default()),
+            None,
+        )
+        .await
+        .unwrap();
+
+        let steps = from_archive(&root, start.with_timezone(&Local).date_naive()).unwrap();
+        assert_eq!(steps.len(), 5);
+        let summary = IndexSummary::build(&read_index(&root).unwrap());
+        let totals = &summary.providers["anthropic"];
+
+        let mut dashboard = dashboard();
+        let mut replay = Replay::new(steps, GoalsConfig::default(), &mut dashboard);
+        replay.step_to(usize::MAX, &mut dashboard);
+        let end = screen(&dashboard);
+        assert!(
+            end.contains(&format!("{} / ", format_number(totals.tokens))),
+            "{}",
+            end
+        );
+        assert!(end.contains("REPLAY 5/5"));
+        assert_eq!(end.matches("upstream").count(), totals.failed, "{}", end);
+
+        // Stepping back and forth again lands on the same picture
+        let key = |code| KeyEvent::new(code, KeyModifiers::NONE);
+        for _ in 0..3 {
+            replay.handle_key(key(KeyCode::Left), &mut dashboard);
+        }
+        assert!(screen(&dashboard).contains("REPLAY 2/5"));
+        for _ in 0..3 {
+            replay.handle_key(key(KeyCode::Right), &mut dashboard);
+        }
+        assert_eq!(screen(&dashboard), end);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_playback_speed_and_idle_skip() {
+        let start = Utc::now();
+        let steps: Vec<Step> = [0, 2, 3, 600]
+            .iter()
+            .map(|secs| {
+                Step::marker(Marker {
+                    label: format!("at {}", secs),
+                    timestamp: start + TimeDelta::seconds(*secs),
+                    tag: None,
+                    session: None,
+                })
+            })
+            .collect();
+        let mut dashboard = dashboard();
+        let mut replay = Replay::new(steps, GoalsConfig::default(), &mut dashboard);
+        replay.seek(1, &mut dashboard);
+        let key = |c| KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE);
+
+        // Paused until Space
+        replay.tick(Duration::from_secs(5), &mut dashboard);
+        assert_eq!(replay.position, 1);
+        replay.handle_key(key(' '), &mut dashboard);
+        replay.tick(Duration::from_millis(2500), &mut dashboard);
+        assert_eq!(replay.position, 2);
+        replay.handle_key(key('x'), &mut dashboard);
+        assert_eq!(replay.speed, 10);
+        replay.tick(Duration::from_millis(100), &mut dashboard);
+        assert_eq!(replay.position, 3);
+
+        // Ten quiet minutes take moments, not a minute
+        replay.tick(Duration::from_millis(500), &mut dashboard);
+        assert_eq!(replay.position, 3);
+        replay.tick(Duration::from_millis(500), &mut dashboard);
+        assert_eq!(replay.position, 4);
+        replay.tick(Duration::from_millis(500), &mut dashboard);
+        assert!(!replay.playing);
+    }
+}
@@ -0,0 +1,213 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Git repository a tool was launched from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RepoInfo {
+    /// Working tree root (the worktree itself for linked worktrees)
+    pub root: PathBuf,
+    /// Checked-out branch; `None` on a detached HEAD
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
+    /// Short commit hash of HEAD; `None` before the first commit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub head: Option<String>,
+    /// Uncommitted changes to tracked files; `None` when git isn't on PATH
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dirty: Option<bool>,
+}
+
+/// Length of the abbreviated commit hash, matching git's default
+const SHORT_HASH_LEN: usize = 7;
+
+impl RepoInfo {
+    /// Find the repository containing `dir`. HEAD is read straight from the
+    /// git directory; the `git` binary is only used, when present, to resolve
+    /// refs sherlock can't read itself and to check for local changes.
+    pub fn detect(dir: &Path) -> Option<Self> {
+        let (root, git_dir) = find_git_dir(dir)?;
+        // Linked worktrees keep HEAD locally but share refs with the main repo
+        let common_dir = std::fs::read_to_string(git_dir.join("commondir"))
+            .map(|common| git_dir.join(common.trim()))
+            .unwrap_or_else(|_| git_dir.clone());
+
+        let (branch, head) = match std::fs::read_to_string(git_dir.join("HEAD")) {
+            Ok(head) => match head.trim().strip_prefix("ref: ") {
+                Some(reference) => (
+                    Some(
+                        reference
+                            .strip_prefix("refs/heads/")
+                            .unwrap_or(reference)
+                            .to_string(),
+                    ),
+                    resolve_ref(&common_dir, reference),
+                ),
+                None => (None, Some(head.trim().to_string())),
+            },
+            Err(_) => (git(&root, &["symbolic-ref", "--short", "-q", "HEAD"]), None),
+        };
+        let head = head
+            .or_else(|| git(&root, &["rev-parse", "HEAD"]))
+            .map(|hash| hash.chars().take(SHORT_HASH_LEN).collect());
+        let dirty = git(&root, &["status", "--porcelain", "--untracked-files=no"])
+            .map(|status| !status.is_empty());
+
+        Some(Self {
+            root,
+            branch,
+            head,
+            dirty,
+        })
+    }
+
+    /// Directory name of the repository, used for grouping
+    pub fn name(&self) -> String {
+        self.root
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| self.root.display().to_string())
+    }
+
+    /// e.g. "sherlock (main @ 1a2b3c4, dirty)"
+    pub fn label(&self) -> String {
+        let mut details = vec![match (&self.branch, &self.head) {
+            (Some(branch), Some(head)) => format!("{} @ {}", branch, head),
+            (Some(branch), None) => branch.clone(),
+            (None, Some(head)) => format!("detached @ {}", head),
+            (None, None) => "no commits".to_string(),
+        }];
+        if self.dirty == Some(true) {
+            details.push("dirty".to_string());
+        }
+        format!("{} ({})", self.name(), details.join(", "))
+    }
+}
+
+/// Walk up from `dir` to the first `.git` directory, or `.git` file as used
+/// by worktrees and submodules, returning the work tree root and git dir
+fn find_git_dir(dir: &Path) -> Option<(PathBuf, PathBuf)> {
+    for ancestor in dir.ancestors() {
+        let dot_git = ancestor.join(".git");
+        if dot_git.is_dir() {
+            return Some((ancestor.to_path_buf(), dot_git));
+        }
+        if let Ok(contents) = std::fs::read_to_string(&dot_git) {
+            let git_dir = contents.trim().strip_prefix("gitdir: ")?;
+            return Some((ancestor.to_path_buf(), ancestor.join(git_dir)));
+        }
+    }
+    None
+}
+
+/// Commit hash of a ref, from its loose file or `packed-refs`
+fn resolve_ref(common_dir: &Path, reference: &str) -> Option<String> {
+    if let Ok(hash) = std::fs::read_to_string(common_dir.join(reference)) {
+        return Some(hash.trim().to_string());
+    }
+    let packed = std::fs::read_to_string(common_dir.join("packed-refs")).ok()?;
+    packed.lines().find_map(|line| {
+        let (hash, name) = line.split_once(' ')?;
+        (name == reference).then(|| hash.to_string())
+    })
+}
+
+/// Trimmed stdout of a successful git command, or `None` if git is missing or fails
+fn git(root: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(root)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "1a2b3c4d5e6f708192a3b4c5d6e7f8091a2b3c4d";
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("sherlock-repo-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(path: PathBuf, contents: &str) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn test_detect_branch_from_subdirectory() {
+        let root = temp_dir("branch");
+        write(root.join(".git/HEAD"), "ref: refs/heads/feature/x\n");
+        write(
+            root.join(".git/refs/heads/feature/x"),
+            &format!("{}\n", HASH),
+        );
+        std::fs::create_dir_all(root.join("src/deep")).unwrap();
+
+        let repo = RepoInfo::detect(&root.join("src/deep")).unwrap();
+        assert_eq!(repo.root, root);
+        assert_eq!(repo.branch.as_deref(), Some("feature/x"));
+        assert_eq!(repo.head.as_deref(), Some("1a2b3c4"));
+
+        // Packed refs and detached HEADs
+        std::fs::remove_file(root.join(".git/refs/heads/feature/x")).unwrap();
+        write(
+            root.join(".git/packed-refs"),
+            &format!("# pack-refs with: peeled\n{} refs/heads/feature/x\n", HASH),
+        );
+        assert_eq!(
+            RepoInfo::detect(&root).unwrap().head.as_deref(),
+            Some("1a2b3c4")
+        );
+
+        write(root.join(".git/HEAD"), HASH);
+        let repo = RepoInfo::detect(&root).unwrap();
+        assert_eq!(repo.branch, None);
+        assert_eq!(repo.head.as_deref(), Some("1a2b3c4"));
+    }
+
+    #[test]
+    fn test_detect_worktree() {
+        let dir = temp_dir("worktree");
+        let main = dir.join("main");
+        write(main.join(".git/refs/heads/wip"), HASH);
+        write(main.join(".git/worktrees/wt/HEAD"), "ref: refs/heads/wip\n");
+        write(main.join(".git/worktrees/wt/commondir"), "../..\n");
+        write(
+            dir.join("wt/.git"),
+            &format!("gitdir: {}\n", main.join(".git/worktrees/wt").display()),
+        );
+
+        let repo = RepoInfo::detect(&dir.join("wt")).unwrap();
+        assert_eq!(repo.root, dir.join("wt"));
+        assert_eq!(repo.name(), "wt");
+        assert_eq!(repo.branch.as_deref(), Some("wip"));
+        assert_eq!(repo.head.as_deref(), Some("1a2b3c4"));
+    }
+
+    #[test]
+    fn test_label() {
+        let repo = RepoInfo {
+            root: PathBuf::from("/src/sherlock"),
+            branch: Some("main".to_string()),
+            head: Some("1a2b3c4".to_string()),
+            dirty: Some(true),
+        };
+        assert_eq!(repo.label(), "sherlock (main @ 1a2b3c4, dirty)");
+
+        let detached = RepoInfo {
+            branch: None,
+            dirty: None,
+            ..repo
+        };
+        assert_eq!(detached.label(), "sherlock (detached @ 1a2b3c4)");
+    }
+}
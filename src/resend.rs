@@ -0,0 +1,184 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Command;
+
+use crate::config::{ProviderConfig, ProxyConfig};
+use crate::keys::CapturedAuth;
+use crate::parser::parse_request;
+use crate::proxy::MANUAL_HEADER;
+
+/// `anthropic-version` sent when the original request's wasn't seen
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// A request from the detail view, to edit and send again
+#[derive(Debug, Clone, PartialEq)]
+pub struct Draft {
+    /// Provider name as configured
+    pub provider: String,
+    pub path: String,
+    /// The body, pretty-printed for editing
+    pub body: String,
+    /// `anthropic-version` and the like, see `RequestEvent::api_version`
+    pub api_version: Option<String>,
+    pub auth: CapturedAuth,
+}
+
+/// Sends edited requests back through the proxy, so they are shown,
+/// counted and archived like any other, tagged `[manual]`
+#[derive(Debug, Clone)]
+pub struct Resender {
+    proxy_url: String,
+    providers: HashMap<String, ProviderConfig>,
+    client: reqwest::Client,
+}
+
+impl Resender {
+    pub fn new(proxy: &ProxyConfig, providers: HashMap<String, ProviderConfig>) -> Self {
+        Self {
+            proxy_url: format!("http://{}:{}", proxy.bind_address, proxy.port),
+            providers,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// `edited` as a request to the proxy, once it parses as a request for
+    /// the draft's provider and there is a credential to send it with:
+    /// the captured one, or else the key the proxy injects for the provider
+    pub fn prepare(&self, draft: &Draft, edited: &str) -> Result<reqwest::RequestBuilder> {
+        self.prepare_with(draft, edited, |name| std::env::var(name).ok())
+    }
+
+    /// `prepare`, reading the environment with `var`
+    fn prepare_with(
+        &self,
+        draft: &Draft,
+        edited: &str,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<reqwest::RequestBuilder> {
+        let provider = self.providers.get(&draft.provider).with_context(|| {
+            format!("{} isn't a configured provider", draft.provider)
+        })?;
+        let format = provider.body_format(&draft.provider);
+        parse_request(edited.as_bytes(), &draft.path, format)
+            .with_context(|| format!("not a valid {} request", format))?;
+        if draft.auth.is_empty() && provider.injected_auth(&draft.provider, var).is_none() {
+            anyhow::bail!(
+                "no credentials for {}: turn on proxy.capture_auth_headers, or set \
+                 providers.{}.api_key_env",
+                draft.provider,
+                draft.provider
+            );
+        }
+
+        let mut request = self
+            .client
+            .post(format!("{}{}", self.proxy_url, draft.path))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(MANUAL_HEADER, "1")
+            .body(edited.to_string());
+        for (name, value) in draft.auth.headers() {
+            request = request.header(name, value);
+        }
+        if format == "anthropic" {
+            let version = draft.api_version.as_deref().unwrap_or(ANTHROPIC_VERSION);
+            request = request.header("anthropic-version", version);
+        }
+        Ok(request)
+    }
+}
+
+/// Open `body` in `editor`, e.g. `$EDITOR`, from a file in `dir` and wait
+/// for it to close, returning the saved text; `None` when it was emptied
+/// to cancel
+pub fn edit(body: &str, editor: &str, dir: &Path) -> Result<Option<String>> {
+    let path = dir.join(format!("sherlock-resend-{}.json", std::process::id()));
+    std::fs::write(&path, body).with_context(|| format!("Failed to write {:?}", path))?;
+    let result = run_editor(editor, &path).and_then(|()| Ok(std::fs::read_to_string(&path)?));
+    let _ = std::fs::remove_file(&path);
+    let edited = result?;
+    Ok((!edited.trim().is_empty()).then_some(edited))
+}
+
+fn run_editor(editor: &str, path: &Path) -> Result<()> {
+    // Editors are often set with arguments, e.g. "code --wait"
+    let mut words = editor.split_whitespace();
+    let program = words.next().context("EDITOR is empty")?;
+    let status = Command::new(program)
+        .args(words)
+        .arg(path)
+        .status()
+        .with_context(|| format!("Failed to start {}", editor))?;
+    if !status.success() {
+        anyhow::bail!("{} exited with {}", editor, status);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn draft(auth: CapturedAuth) -> Draft {
+        Draft {
+            provider: "anthropic".to_string(),
+            path: "/v1/messages".to_string(),
+            body: String::new(),
+            api_version: None,
+            auth,
+        }
+    }
+
+    #[test]
+    fn test_prepare() {
+        let config = Config::default();
+        let resender = Resender::new(&config.proxy, config.providers.clone());
+        let body = r#"{"model":"claude-sonnet-4-5","max_tokens":64,"messages":[{"role":"user","content":"hi"}]}"#;
+
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert("x-api-key", "sk-ant-test".parse().unwrap());
+        let request = resender
+            .prepare(&draft(CapturedAuth::from_headers(&headers)), body)
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(request.url().as_str(), "http://127.0.0.1:8080/v1/messages");
+        assert_eq!(request.headers()["x-api-key"], "sk-ant-test");
+        assert_eq!(request.headers()[MANUAL_HEADER], "1");
+        assert_eq!(request.headers()["anthropic-version"], ANTHROPIC_VERSION);
+
+        let error = resender
+            .prepare(&draft(CapturedAuth::from_headers(&headers)), "{not json")
+            .unwrap_err();
+        assert!(error.to_string().contains("not a valid anthropic request"));
+
+        // Without captured headers, only a key the proxy can inject will do
+        let error = resender.prepare(&draft(CapturedAuth::default()), body).unwrap_err();
+        assert!(error.to_string().contains("no credentials for anthropic"));
+        let mut providers = config.providers.clone();
+        providers.get_mut("anthropic").unwrap().api_key_env = Some("MY_KEY".to_string());
+        let resender = Resender::new(&config.proxy, providers);
+        let var = |name: &str| (name == "MY_KEY").then(|| "sk-ant-env".to_string());
+        let request = resender
+            .prepare_with(&draft(CapturedAuth::default()), body, var)
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(request.headers().get("x-api-key").is_none());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_edit() {
+        let dir = std::env::temp_dir().join(format!("sherlock-resend-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // `true` leaves the file as it was
+        assert_eq!(edit("{}\n", "true", &dir).unwrap().as_deref(), Some("{}\n"));
+        assert_eq!(edit("  \n", "true", &dir).unwrap(), None);
+        let error = edit("{}", "false", &dir).unwrap_err();
+        assert!(error.to_string().contains("false exited with"));
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
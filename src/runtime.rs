@@ -0,0 +1,261 @@
+use anyhow::Result;
+use arc_swap::ArcSwap;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::config::{expand_tilde, Config, ProviderConfig, ProxyConfig};
+use crate::parser::ParseOptions;
+use crate::policy::PolicyScanner;
+use crate::pricing::PriceTable;
+use crate::self_test;
+use crate::shaping::Shaper;
+use crate::tls::build_client;
+
+/// How often the config file is checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Everything the proxy reads on each request, shared with the config
+/// watcher, which swaps in a new snapshot when the file changes
+pub type SharedRuntime = Arc<ArcSwap<RuntimeConfig>>;
+
+/// Read-mostly state consulted on every proxied request. Requests hold one
+/// snapshot for their whole lifetime and never see a half-applied reload.
+#[derive(Debug)]
+pub struct RuntimeConfig {
+    pub providers: HashMap<String, ProviderConfig>,
+    /// One upstream client per provider, since TLS settings differ
+    pub clients: HashMap<String, reqwest::Client>,
+    pub policy: Arc<PolicyScanner>,
+    pub prices: Arc<PriceTable>,
+    pub shape_based_routing: bool,
+    /// Where requests no provider matches are passed through to
+    pub default_provider: Option<String>,
+    pub parsing: ParseOptions,
+    /// See `ProxyConfig::capture_auth_headers`
+    pub capture_auth_headers: bool,
+}
+
+impl RuntimeConfig {
+    pub fn new(
+        providers: HashMap<String, ProviderConfig>,
+        policy: Arc<PolicyScanner>,
+        prices: Arc<PriceTable>,
+        proxy: &ProxyConfig,
+    ) -> Result<Self> {
+        let clients = providers
+            .iter()
+            .map(|(name, provider)| Ok((name.clone(), build_client(name, provider.tls.as_ref())?)))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            providers,
+            clients,
+            policy,
+            prices,
+            shape_based_routing: proxy.shape_based_routing,
+            default_provider: proxy.default_provider.clone(),
+            parsing: ParseOptions::from(proxy),
+            capture_auth_headers: proxy.capture_auth_headers,
+        })
+    }
+
+    /// A snapshot for `config`. The self-test provider only lives in memory,
+    /// so it is carried over, and so are clients whose TLS settings are
+    /// unchanged, keeping their open connections.
+    pub fn rebuilt(&self, config: &Config) -> Result<Self> {
+        let mut providers = config.providers.clone();
+        if let Some(provider) = self.providers.get(self_test::PROVIDER) {
+            providers.insert(self_test::PROVIDER.to_string(), provider.clone());
+        }
+        let clients = providers
+            .iter()
+            .map(|(name, provider)| {
+                let previous = self.providers.get(name).map(|previous| &previous.tls);
+                match self.clients.get(name) {
+                    Some(client) if previous == Some(&provider.tls) => {
+                        Ok((name.clone(), client.clone()))
+                    }
+                    _ => Ok((name.clone(), build_client(name, provider.tls.as_ref())?)),
+                }
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            providers,
+            clients,
+            policy: Arc::new(PolicyScanner::new(&config.policy)?),
+            prices: Arc::new(PriceTable::new(&config.pricing)),
+            shape_based_routing: config.proxy.shape_based_routing,
+            default_provider: config.proxy.default_provider.clone(),
+            parsing: ParseOptions::from(&config.proxy),
+            capture_auth_headers: config.proxy.capture_auth_headers,
+        })
+    }
+}
+
+/// What re-reads the config file into a running proxy, on a change to the
+/// file or when asked with `Control::Reload`
+#[derive(Clone)]
+pub struct Reloader {
+    path: PathBuf,
+    runtime: SharedRuntime,
+    shaper: Arc<Shaper>,
+}
+
+impl Reloader {
+    pub fn new(path: &Path, runtime: SharedRuntime, shaper: Arc<Shaper>) -> Self {
+        Self {
+            path: expand_tilde(path),
+            runtime,
+            shaper,
+        }
+    }
+
+    /// Swap in a snapshot of the config file and its bandwidth limits, or
+    /// keep everything as it was if any of it is invalid
+    pub fn reload(&self) -> Result<()> {
+        reload(&self.path, &self.runtime, &self.shaper)?;
+        tracing::info!(
+            "Reloaded {:?}; bandwidth limits now upload {:?}, download {:?} bytes/s",
+            self.path,
+            self.shaper.upload.rate(),
+            self.shaper.download.rate()
+        );
+        Ok(())
+    }
+}
+
+/// Re-read the config file whenever it changes, swapping in a new runtime
+/// snapshot and bandwidth limits so they apply to a running proxy
+pub async fn watch_config(reloader: Reloader) {
+    let mut modified = modified_at(&reloader.path);
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    loop {
+        interval.tick().await;
+        let current = modified_at(&reloader.path);
+        if current == modified {
+            continue;
+        }
+        modified = current;
+        if let Err(e) = reloader.reload() {
+            tracing::warn!("Ignoring config change: {:#}", e);
+        }
+    }
+}
+
+/// Apply the config at `path`, or nothing at all if any of it is invalid
+fn reload(path: &Path, runtime: &ArcSwap<RuntimeConfig>, shaper: &Shaper) -> Result<()> {
+    let config = Config::load(path, false)?;
+    runtime.store(Arc::new(runtime.load().rebuilt(&config)?));
+    shaper.set_limits(
+        config.proxy.max_upload_bytes_per_sec,
+        config.proxy.max_download_bytes_per_sec,
+    );
+    Ok(())
+}
+
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc_stats::allocations;
+    use std::hint::black_box;
+
+    #[test]
+    fn test_request_path_allocations() {
+        let config = Config::default();
+        let runtime = RuntimeConfig::new(
+            config.providers.clone(),
+            Arc::default(),
+            Arc::default(),
+            &config.proxy,
+        );
+        let shared: SharedRuntime = Arc::new(ArcSwap::from_pointee(runtime.unwrap()));
+        let request = || {
+            let mut request = hyper::Request::new(());
+            let headers = request.headers_mut();
+            for (name, value) in [
+                ("content-type", "application/json"),
+                ("x-api-key", "sk-ant-REDACTED"),
+                ("anthropic-version", "2023-06-01"),
+                ("user-agent", "claude-cli/1.0.0 (external, cli)"),
+                ("accept", "application/json"),
+            ] {
+                headers.insert(name, value.parse().unwrap());
+            }
+            request
+        };
+
+        // What every request used to pay: the headers cloned, and the
+        // provider map, policy and clients each cloned as an Arc per layer
+        let previous = {
+            let request = request();
+            let providers = Arc::new(config.providers.clone());
+            let policy = Arc::new(PolicyScanner::default());
+            allocations(|| {
+                black_box(request.headers().clone());
+                let providers = black_box(Arc::clone(&providers));
+                black_box(Arc::clone(&policy));
+                black_box(providers.get("anthropic"));
+            })
+        };
+        // Now the headers are moved out and one snapshot serves the request.
+        // The first load on a thread registers it with arc-swap, so warm up.
+        drop(shared.load());
+        let current = {
+            let request = request();
+            allocations(|| {
+                let (parts, _) = request.into_parts();
+                black_box(parts.headers);
+                let runtime = shared.load();
+                black_box(runtime.providers.get("anthropic"));
+                black_box(runtime.clients.get("anthropic"));
+            })
+        };
+        assert!(previous > 0);
+        assert_eq!(current, 0, "request setup allocated {} times", current);
+    }
+
+    #[test]
+    fn test_reload_swaps_snapshot() {
+        let mut providers = Config::default().providers;
+        let self_test = providers["anthropic"].clone();
+        providers.insert(self_test::PROVIDER.to_string(), self_test);
+        let shared: SharedRuntime = Arc::new(ArcSwap::from_pointee(
+            RuntimeConfig::new(providers, Arc::default(), Arc::default(), &ProxyConfig::default())
+                .unwrap(),
+        ));
+        let held = shared.load_full();
+
+        let path =
+            std::env::temp_dir().join(format!("sherlock-runtime-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{"proxy": {"shape_based_routing": true},
+                "policy": {"scan_patterns": [{"name": "aws", "regex": "AKIA[0-9A-Z]{16}"}]}}"#,
+        )
+        .unwrap();
+        reload(&path, &shared, &Shaper::default()).unwrap();
+        let runtime = shared.load();
+        assert!(runtime.shape_based_routing);
+        assert!(!runtime.policy.is_empty());
+        assert!(runtime.providers.contains_key(self_test::PROVIDER));
+        assert_eq!(runtime.clients.len(), runtime.providers.len());
+        // Requests already under way keep the snapshot they started with
+        assert!(!held.shape_based_routing);
+
+        // An invalid pattern leaves everything as it was
+        std::fs::write(
+            &path,
+            r#"{"proxy": {"shape_based_routing": false},
+                "policy": {"scan_patterns": [{"name": "bad", "regex": "("}]}}"#,
+        )
+        .unwrap();
+        assert!(reload(&path, &shared, &Shaper::default()).is_err());
+        assert!(shared.load().shape_based_routing);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,312 @@
+//! An outside program run over each request's messages before the event
+//! leaves the proxy, so the dashboard and archive only see what it lets
+//! through. Upstream still gets the body exactly as the client sent it.
+//!
+//! The program reads one JSON array of messages per line on stdin and
+//! answers each with one line on stdout. Copies of it stay up between
+//! requests, up to `processes` of them, so a request pays for a round trip
+//! over a pipe rather than a process start.
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::Semaphore;
+
+use crate::config::{ScrubFailure, ScrubberConfig};
+use crate::event::{Message, RequestEvent};
+
+/// Top-level body fields that hold prompt text, in any request format
+const PROMPT_FIELDS: [&str; 7] = [
+    "messages",
+    "input",
+    "contents",
+    "prompt",
+    "system",
+    "instructions",
+    "systemInstruction",
+];
+
+/// The scrubber program and the copies of it waiting for work
+pub struct Scrubber {
+    config: ScrubberConfig,
+    idle: Mutex<Vec<Process>>,
+    slots: Semaphore,
+}
+
+impl Scrubber {
+    /// Start one copy of the program, so a command that can't run is
+    /// reported now rather than on every request
+    pub fn start(config: &ScrubberConfig) -> Result<Self> {
+        let first = Process::spawn(config)?;
+        Ok(Self {
+            config: config.clone(),
+            idle: Mutex::new(vec![first]),
+            slots: Semaphore::new(config.processes),
+        })
+    }
+
+    /// Replace `event`'s messages with the program's answer, and its raw
+    /// body's prompt fields with that answer too. When the program fails the
+    /// event keeps its messages, or loses their content, as `on_failure` says.
+    pub async fn scrub(&self, event: &mut RequestEvent) {
+        if !event.messages.is_empty() {
+            match self.run(&event.messages).await {
+                Ok(messages) => event.messages = messages,
+                Err(e) if self.config.on_failure == ScrubFailure::PassThrough => {
+                    tracing::warn!(
+                        "Scrubber failed, keeping the {} request's messages as they are: {:#}",
+                        event.provider,
+                        e
+                    );
+                    return;
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        "Scrubber failed, dropping the {} request's content: {:#}",
+                        event.provider,
+                        e
+                    );
+                    for message in &mut event.messages {
+                        message.content.clear();
+                        message.unknown_parts.clear();
+                    }
+                }
+            }
+        }
+        event.raw_body = scrubbed_body(&event.raw_body, &event.messages);
+    }
+
+    /// One round trip through a free copy of the program, all of it within
+    /// the timeout
+    async fn run(&self, messages: &[Message]) -> Result<Vec<Message>> {
+        let line = serde_json::to_vec(messages)?;
+        if line.len() > self.config.max_bytes {
+            anyhow::bail!(
+                "messages are {} bytes, over the {} byte limit",
+                line.len(),
+                self.config.max_bytes
+            );
+        }
+        let timeout = Duration::from_millis(self.config.timeout_ms);
+        tokio::time::timeout(timeout, self.exchange(&line))
+            .await
+            .with_context(|| format!("no answer within {}ms", self.config.timeout_ms))?
+    }
+
+    async fn exchange(&self, line: &[u8]) -> Result<Vec<Message>> {
+        let _slot = self.slots.acquire().await?;
+        let idle = self.idle.lock().unwrap().pop();
+        let mut process = match idle {
+            Some(process) => process,
+            None => Process::spawn(&self.config)?,
+        };
+        // A copy that fails or times out is dropped, which kills it
+        let answer = process.exchange(line, self.config.max_bytes).await?;
+        let messages = serde_json::from_slice(&answer)
+            .context("the answer is not a JSON array of messages")?;
+        self.idle.lock().unwrap().push(process);
+        Ok(messages)
+    }
+}
+
+/// One running copy of the program
+struct Process {
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl Process {
+    fn spawn(config: &ScrubberConfig) -> Result<Self> {
+        let mut child = Command::new(&config.command)
+            .args(&config.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Failed to run scrubber {:?}", config.command))?;
+        let stdin = child.stdin.take().context("scrubber has no stdin")?;
+        let stdout = child.stdout.take().context("scrubber has no stdout")?;
+        Ok(Self {
+            _child: child,
+            stdin,
+            stdout: BufReader::new(stdout),
+        })
+    }
+
+    /// Send `line` and read the answer line, without its newline, refusing
+    /// one longer than `limit` bytes
+    async fn exchange(&mut self, line: &[u8], limit: usize) -> Result<Vec<u8>> {
+        self.stdin.write_all(line).await?;
+        self.stdin.write_all(b"\n").await?;
+        self.stdin.flush().await?;
+
+        let mut answer = Vec::new();
+        (&mut self.stdout)
+            .take(limit as u64 + 1)
+            .read_until(b'\n', &mut answer)
+            .await?;
+        if answer.pop() != Some(b'\n') {
+            match answer.len() >= limit {
+                true => anyhow::bail!("the answer is over the {} byte limit", limit),
+                false => anyhow::bail!("the scrubber exited"),
+            }
+        }
+        Ok(answer)
+    }
+}
+
+/// `body` with its prompt fields swapped for `messages`, in sherlock's own
+/// `{role, content}` form; the rest, e.g. the model and tool definitions,
+/// stays as it was
+fn scrubbed_body(body: &Value, messages: &[Message]) -> Value {
+    let messages = serde_json::to_value(messages).unwrap_or_default();
+    let Value::Object(fields) = body else {
+        return serde_json::json!({ "messages": messages });
+    };
+    let mut fields = fields.clone();
+    for field in PROMPT_FIELDS {
+        fields.remove(field);
+    }
+    fields.insert("messages".to_string(), messages);
+    Value::Object(fields)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::parser::parse_request;
+    use std::path::{Path, PathBuf};
+
+    /// A scrubber script under a fresh directory, answering with `body`
+    fn script(name: &str, body: &str) -> (PathBuf, ScrubberConfig) {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir =
+            std::env::temp_dir().join(format!("sherlock-scrub-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("scrub.sh");
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let config = ScrubberConfig {
+            command: path.display().to_string(),
+            timeout_ms: 2000,
+            processes: 2,
+            ..ScrubberConfig::default()
+        };
+        (dir, config)
+    }
+
+    /// Uppercases every message's content, leaving the keys alone
+    const UPPERCASE: &str = r#"exec sed -u 's/"content":"\([^"]*\)"/"content":"\U\1"/g'"#;
+
+    fn event() -> RequestEvent {
+        let body = br#"{"model": "claude-sonnet-4-5", "max_tokens": 64, "system": "be brief",
+            "messages": [{"role": "user", "content": "my key is sk-123"}]}"#;
+        parse_request(body, "/v1/messages", "anthropic").unwrap()
+    }
+
+    fn contents(event: &RequestEvent) -> Vec<&str> {
+        event.messages.iter().map(|m| m.content.as_str()).collect()
+    }
+
+    fn cleanup(dir: &Path) {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_scrub_replaces_messages_and_body() {
+        let (dir, config) = script("upper", UPPERCASE);
+        let scrubber = Scrubber::start(&config).unwrap();
+
+        // Run more requests at once than there are processes, twice over
+        for _ in 0..2 {
+            let (mut a, mut b, mut c) = (event(), event(), event());
+            tokio::join!(
+                scrubber.scrub(&mut a),
+                scrubber.scrub(&mut b),
+                scrubber.scrub(&mut c)
+            );
+            for event in [&a, &b, &c] {
+                assert_eq!(contents(event), ["BE BRIEF", "MY KEY IS SK-123"]);
+                assert_eq!(event.raw_body["messages"][1]["content"], "MY KEY IS SK-123");
+                assert_eq!(event.raw_body["model"], "claude-sonnet-4-5");
+                assert_eq!(event.raw_body["max_tokens"], 64);
+                assert!(event.raw_body.get("system").is_none());
+            }
+        }
+        assert!(scrubber.idle.lock().unwrap().len() <= config.processes);
+        cleanup(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_failures_follow_the_policy() {
+        // Exits without answering
+        let (dir, mut config) = script("exit", "read line; exit 1");
+        config.on_failure = ScrubFailure::PassThrough;
+        let scrubber = Scrubber::start(&config).unwrap();
+        let mut passed = event();
+        scrubber.scrub(&mut passed).await;
+        assert_eq!(contents(&passed), ["be brief", "my key is sk-123"]);
+        assert_eq!(passed.raw_body, event().raw_body);
+
+        config.on_failure = ScrubFailure::DropContent;
+        let scrubber = Scrubber::start(&config).unwrap();
+        let mut dropped = event();
+        scrubber.scrub(&mut dropped).await;
+        assert_eq!(contents(&dropped), ["", ""]);
+        assert_eq!(dropped.messages[1].role, "user");
+        assert_eq!(dropped.tokens, event().tokens);
+        assert!(!dropped.raw_body.to_string().contains("sk-123"));
+        cleanup(&dir);
+
+        // Answers too slowly, or not with messages
+        for (name, body) in [
+            ("slow", "read line; sleep 5"),
+            ("garbage", "while read line; do echo nope; done"),
+        ] {
+            let (dir, mut config) = script(name, body);
+            config.timeout_ms = 200;
+            let scrubber = Scrubber::start(&config).unwrap();
+            let mut dropped = event();
+            scrubber.scrub(&mut dropped).await;
+            assert_eq!(contents(&dropped), ["", ""], "{}", name);
+            cleanup(&dir);
+        }
+
+        // Sent more than the limit, or answering with more
+        let limit = serde_json::to_vec(&event().messages).unwrap().len() + 1;
+        for (name, body) in [
+            ("large", UPPERCASE),
+            ("long", "while read line; do printf '%0500d\\n' 0; done"),
+        ] {
+            let (dir, mut config) = script(name, body);
+            config.max_bytes = limit;
+            let scrubber = Scrubber::start(&config).unwrap();
+            let mut dropped = event();
+            if name == "large" {
+                dropped.messages[1].content.push_str("!!");
+            }
+            scrubber.scrub(&mut dropped).await;
+            assert_eq!(contents(&dropped), ["", ""], "{}", name);
+            cleanup(&dir);
+        }
+    }
+
+    #[test]
+    fn test_missing_command_fails_at_start() {
+        let config = ScrubberConfig {
+            command: "/nonexistent/scrubber".to_string(),
+            ..ScrubberConfig::default()
+        };
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _guard = runtime.enter();
+        assert!(Scrubber::start(&config).is_err());
+    }
+}
@@ -0,0 +1,558 @@
+use chrono::{DateTime, Local, Utc};
+use serde::Serialize;
+use std::fmt;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, TryRecvError};
+use std::sync::Arc;
+
+use crate::event::{RequestDetail, RequestInfo};
+use crate::filter::Filter;
+use crate::reader::ArchivedRequest;
+
+/// Characters of context kept either side of a match in its snippet
+const SNIPPET_CONTEXT: usize = 40;
+
+/// What a dashboard search looks through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchScope {
+    /// User and system messages
+    Prompts,
+    /// Every message sent, and what came back
+    Everything,
+}
+
+impl SearchScope {
+    pub fn label(self) -> &'static str {
+        match self {
+            SearchScope::Prompts => "prompts",
+            SearchScope::Everything => "prompts + responses",
+        }
+    }
+}
+
+/// Which text of a request a match is in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Part {
+    /// Index into the request's messages
+    Message(usize),
+    Response,
+}
+
+/// The first match in one request
+#[derive(Debug, Clone)]
+pub struct SearchHit {
+    pub info: RequestInfo,
+    pub part: Part,
+    pub role: String,
+    /// Byte range of the match within its part's text
+    pub range: Range<usize>,
+    /// The match on one line with some context, and where it sits there
+    pub snippet: (String, Range<usize>),
+    /// Matches anywhere in the request
+    pub count: usize,
+}
+
+/// A search through retained requests, run on a background thread so a long
+/// session doesn't stall the dashboard. Hits arrive as they are found, and
+/// dropping the search stops the thread.
+pub struct Search {
+    pub query: String,
+    pub scope: SearchScope,
+    pub hits: Vec<SearchHit>,
+    pub selected: usize,
+    /// Unset once every request has been looked at
+    results: Option<mpsc::Receiver<SearchHit>>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Search {
+    /// Start looking for `query` in `rows`, reporting hits in their order
+    pub fn start(query: &str, scope: SearchScope, rows: Vec<RequestInfo>) -> Self {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let needle = query.to_lowercase();
+        let results = (!needle.trim().is_empty()).then(|| {
+            let (tx, rx) = mpsc::channel();
+            let cancelled = Arc::clone(&cancelled);
+            std::thread::spawn(move || {
+                for info in rows {
+                    if cancelled.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    let Some(hit) = search_request(info, &needle, scope) else {
+                        continue;
+                    };
+                    if tx.send(hit).is_err() {
+                        return;
+                    }
+                }
+            });
+            rx
+        });
+        Self {
+            query: query.to_string(),
+            scope,
+            hits: Vec::new(),
+            selected: 0,
+            results,
+            cancelled,
+        }
+    }
+
+    /// Take the hits found since the last call
+    pub fn poll(&mut self) {
+        let Some(results) = &self.results else {
+            return;
+        };
+        loop {
+            match results.try_recv() {
+                Ok(hit) => self.hits.push(hit),
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => {
+                    self.results = None;
+                    return;
+                }
+            }
+        }
+    }
+
+    pub fn finished(&self) -> bool {
+        self.results.is_none()
+    }
+
+    pub fn select_by(&mut self, delta: isize) {
+        let last = self.hits.len().saturating_sub(1);
+        self.selected = self
+            .selected
+            .min(last)
+            .saturating_add_signed(delta)
+            .min(last);
+    }
+
+    pub fn selected_hit(&self) -> Option<&SearchHit> {
+        self.hits
+            .get(self.selected.min(self.hits.len().saturating_sub(1)))
+    }
+}
+
+impl Drop for Search {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+/// The texts of `detail` that `scope` covers, with the role to show for each
+pub fn parts(
+    detail: &RequestDetail,
+    scope: SearchScope,
+) -> impl Iterator<Item = (Part, &str, &str)> {
+    let messages = detail
+        .messages
+        .iter()
+        .enumerate()
+        .filter(move |(_, message)| {
+            scope == SearchScope::Everything
+                || matches!(message.role.as_str(), "user" | "system" | "developer")
+        });
+    let response = detail
+        .response
+        .as_deref()
+        .filter(|_| scope == SearchScope::Everything);
+    messages
+        .map(|(i, message)| {
+            (
+                Part::Message(i),
+                message.role.as_str(),
+                message.content.as_str(),
+            )
+        })
+        .chain(response.map(|text| (Part::Response, "response", text)))
+}
+
+fn search_request(info: RequestInfo, needle: &str, scope: SearchScope) -> Option<SearchHit> {
+    let detail = info.detail.clone()?;
+    let mut first = None;
+    let mut count = 0;
+    for (part, role, text) in parts(&detail, scope) {
+        let found = find_all(text, needle);
+        if first.is_none() {
+            if let Some(range) = found.first() {
+                first = Some((part, role.to_string(), range.clone(), snippet(text, range)));
+            }
+        }
+        count += found.len();
+    }
+    let (part, role, range, snippet) = first?;
+    Some(SearchHit {
+        info,
+        part,
+        role,
+        range,
+        snippet,
+        count,
+    })
+}
+
+/// The first match in one archived request, for `sherlock search`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ArchiveHit {
+    pub timestamp: DateTime<Utc>,
+    pub provider: String,
+    pub model: String,
+    /// File name it was read from, with the line for event files
+    pub source: String,
+    pub role: String,
+    /// The match on one line with some context
+    pub snippet: String,
+    /// Matches anywhere in the request's messages
+    pub count: usize,
+}
+
+/// The first `limit` of `requests` that `filter` lets through with `text`
+/// in their messages, in the order given
+pub fn search_archive(
+    requests: impl IntoIterator<Item = ArchivedRequest>,
+    text: &str,
+    filter: &Filter,
+    limit: usize,
+) -> Vec<ArchiveHit> {
+    let needle = text.to_lowercase();
+    requests
+        .into_iter()
+        .filter(|request| filter.matches(&request.event))
+        .filter_map(|ArchivedRequest { event, source }| {
+            let mut first = None;
+            let mut count = 0;
+            for message in &event.messages {
+                let found = find_all(&message.content, &needle);
+                if first.is_none() {
+                    if let Some(range) = found.first() {
+                        first = Some((message.role.clone(), snippet(&message.content, range).0));
+                    }
+                }
+                count += found.len();
+            }
+            let (role, snippet) = first?;
+            Some(ArchiveHit {
+                timestamp: event.timestamp,
+                provider: event.provider,
+                model: event.model,
+                source,
+                role,
+                snippet,
+                count,
+            })
+        })
+        .take(limit)
+        .collect()
+}
+
+/// e.g. "2025-01-01 10:00  anthropic  claude-sonnet-4-5  (file.json, 2 matches)"
+/// with the snippet on the next line
+impl fmt::Display for ArchiveHit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{}  {}  {}  ({}, {} {})",
+            self.timestamp
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M"),
+            self.provider,
+            self.model,
+            self.source,
+            self.count,
+            if self.count == 1 { "match" } else { "matches" }
+        )?;
+        writeln!(f, "  {}: {}", self.role, self.snippet)
+    }
+}
+
+/// Byte ranges of `haystack` matching `needle` whatever their case, where
+/// `needle` is already lowercase
+pub fn find_all(haystack: &str, needle: &str) -> Vec<Range<usize>> {
+    if needle.is_empty() {
+        return Vec::new();
+    }
+    if haystack.is_ascii() {
+        let lowered = haystack.to_ascii_lowercase();
+        return lowered
+            .match_indices(needle)
+            .map(|(at, found)| at..at + found.len())
+            .collect();
+    }
+    // Lowercasing can change a character's length, so each lowered byte
+    // remembers where its character started in `haystack`
+    let mut lowered = String::with_capacity(haystack.len());
+    let mut origin = Vec::with_capacity(haystack.len() + 1);
+    for (i, c) in haystack.char_indices() {
+        for lower in c.to_lowercase() {
+            lowered.push(lower);
+            origin.resize(lowered.len(), i);
+        }
+    }
+    origin.push(haystack.len());
+    lowered
+        .match_indices(needle)
+        .map(|(at, found)| {
+            let start = origin[at];
+            // A match ending inside one character's lowercase form takes all of it
+            let first_char = haystack[start..].chars().next().map_or(0, char::len_utf8);
+            start..origin[at + found.len()].max(start + first_char)
+        })
+        .collect()
+}
+
+/// `range` of `text` with some context, whitespace folded onto one line,
+/// and where the match sits in it
+fn snippet(text: &str, range: &Range<usize>) -> (String, Range<usize>) {
+    let start = text[..range.start]
+        .char_indices()
+        .rev()
+        .nth(SNIPPET_CONTEXT - 1)
+        .map_or(0, |(i, _)| i);
+    let end = text[range.end..]
+        .char_indices()
+        .nth(SNIPPET_CONTEXT)
+        .map_or(text.len(), |(i, _)| range.end + i);
+    let mut snippet = String::from(if start > 0 { "…" } else { "" });
+    snippet.push_str(&one_line(text[start..range.start].trim_start()));
+    let found = snippet.len()..snippet.len() + one_line(&text[range.clone()]).len();
+    snippet.push_str(&one_line(&text[range.clone()]));
+    snippet.push_str(&one_line(text[range.end..end].trim_end()));
+    if end < text.len() {
+        snippet.push('…');
+    }
+    (snippet, found)
+}
+
+/// `s` with each run of whitespace made one space
+fn one_line(s: &str) -> String {
+    let mut line = String::with_capacity(s.len());
+    let mut blank = false;
+    for c in s.chars() {
+        if c.is_whitespace() {
+            if !blank {
+                line.push(' ');
+            }
+            blank = true;
+        } else {
+            line.push(c);
+            blank = false;
+        }
+    }
+    line
+}
+
+/// Where `matches` fall on wrapped `lines`, both as byte ranges of the same
+/// text: for each line, the ranges to highlight relative to its start. A
+/// match wrapped across lines is highlighted on each of them.
+pub fn line_highlights(lines: &[Range<usize>], matches: &[Range<usize>]) -> Vec<Vec<Range<usize>>> {
+    lines
+        .iter()
+        .map(|line| {
+            let first = matches.partition_point(|m| m.end <= line.start);
+            matches[first..]
+                .iter()
+                .take_while(|m| m.start < line.end)
+                .map(|m| m.start.max(line.start) - line.start..m.end.min(line.end) - line.start)
+                .collect()
+        })
+        .collect()
+}
+
+/// The wrapped line showing byte `offset`, or the next one when it falls on
+/// a break
+pub fn line_of(lines: &[Range<usize>], offset: usize) -> usize {
+    lines
+        .partition_point(|line| line.end <= offset)
+        .min(lines.len().saturating_sub(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_request;
+    use crate::text::wrap_ranges;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_find_all_ignores_case_in_multibyte_text() {
+        let text = "Rename ÉCOLE to école, then École";
+        let found = find_all(text, "école");
+        let words: Vec<&str> = found.iter().map(|range| &text[range.clone()]).collect();
+        assert_eq!(words, ["ÉCOLE", "école", "École"]);
+        assert_eq!(find_all("Fix the PARSER", "parser"), vec![8..14]);
+        // 'İ' lowercases to two characters; a match on part of it covers it all
+        let text = "İstanbul";
+        assert_eq!(&text[find_all(text, "i")[0].clone()], "İ");
+        assert_eq!(&text[find_all(text, "stan")[0].clone()], "stan");
+        assert!(find_all(text, "").is_empty());
+
+        let text = "first line\n\n  then   the model suggested renaming parse_body";
+        let at = text.find("suggested").unwrap();
+        let (snippet, found) = snippet(text, &(at..at + "suggested".len()));
+        assert_eq!(
+            snippet,
+            "first line then the model suggested renaming parse_body"
+        );
+        assert_eq!(&snippet[found], "suggested");
+    }
+
+    #[test]
+    fn test_highlights_on_wrapped_multibyte_lines() {
+        let text = "関数名を変更する: rename_parse を提案しました";
+        let lines = wrap_ranges(text, 12);
+        let shown: Vec<&str> = lines.iter().map(|line| &text[line.clone()]).collect();
+        assert_eq!(
+            shown,
+            [
+                "関数名を変更",
+                "する:",
+                "rename_parse",
+                "を提案しまし",
+                "た"
+            ]
+        );
+
+        // One match inside a line, one cut by a wrap, one over a whole line
+        let mut matches = ["変更", "しました", "rename_"]
+            .iter()
+            .flat_map(|word| find_all(text, word))
+            .collect::<Vec<_>>();
+        matches.sort_by_key(|m| m.start);
+        let highlights = line_highlights(&lines, &matches);
+        let marked: Vec<Vec<&str>> = highlights
+            .iter()
+            .zip(&shown)
+            .map(|(ranges, line)| ranges.iter().map(|range| &line[range.clone()]).collect())
+            .collect();
+        assert_eq!(
+            marked,
+            [
+                vec!["変更"],
+                vec![],
+                vec!["rename_"],
+                vec!["しまし"],
+                vec!["た"]
+            ]
+        );
+        assert_eq!(line_of(&lines, matches[1].start), 2);
+        assert_eq!(line_of(&lines, text.find("た").unwrap()), 4);
+    }
+
+    #[test]
+    fn test_search_archive() {
+        let request = |source: &str, model: &str, prompt: &str| {
+            let body = serde_json::json!({
+                "model": model,
+                "messages": [
+                    {"role": "system", "content": "You fix failing tests."},
+                    {"role": "user", "content": prompt}
+                ]
+            });
+            ArchivedRequest {
+                event: parse_request(body.to_string().as_bytes(), "/v1/messages", "anthropic")
+                    .unwrap(),
+                source: source.to_string(),
+            }
+        };
+        let requests = vec![
+            request(
+                "3.json",
+                "claude-sonnet-4-5",
+                "The Failing test is in parser.rs",
+            ),
+            request("2.json", "claude-haiku-4-5", "another failing test"),
+            request("1.json", "claude-sonnet-4-5", "write the docs"),
+        ];
+
+        let everything = Filter::parse("").unwrap();
+        let hits = search_archive(requests.clone(), "failing test", &everything, 10);
+        let sources: Vec<_> = hits.iter().map(|hit| hit.source.as_str()).collect();
+        assert_eq!(sources, ["3.json", "2.json", "1.json"]);
+        assert_eq!(hits[0].role, "system");
+        assert_eq!(hits[0].count, 2);
+
+        let sonnet = Filter::parse("model~sonnet \"parser.rs\"").unwrap();
+        let hits = search_archive(requests.clone(), "FAILING TEST", &sonnet, 10);
+        assert_eq!(hits.len(), 1);
+        let text = hits[0].to_string();
+        assert!(
+            text.ends_with(
+                "  anthropic  claude-sonnet-4-5  (3.json, 2 matches)\n  system: You fix failing tests.\n"
+            ),
+            "{}",
+            text
+        );
+
+        assert_eq!(
+            search_archive(requests.clone(), "test", &everything, 2).len(),
+            2
+        );
+        assert_eq!(search_archive(requests, "docs", &everything, 10).len(), 1);
+    }
+
+    #[test]
+    fn test_search_runs_in_background() {
+        let mut rows = Vec::new();
+        for (prompt, response) in [
+            (
+                "tidy up the parser",
+                "I suggest renaming parse_body to read_body.",
+            ),
+            ("now the tests", "Renaming done; the tests pass."),
+            ("anything else?", "No."),
+        ] {
+            let body = serde_json::json!({
+                "model": "claude-3",
+                "messages": [{"role": "user", "content": prompt}]
+            });
+            let mut event =
+                parse_request(body.to_string().as_bytes(), "/v1/messages", "anthropic").unwrap();
+            event.response_text = Some(response.to_string());
+            rows.push(RequestInfo::from(&event));
+        }
+        let finish = |mut search: Search| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while !search.finished() && Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(1));
+                search.poll();
+            }
+            assert!(search.finished());
+            search
+        };
+
+        let search = finish(Search::start(
+            "RENAMING",
+            SearchScope::Everything,
+            rows.clone(),
+        ));
+        let prompts: Vec<_> = search
+            .hits
+            .iter()
+            .map(|hit| hit.info.prompt.clone())
+            .collect();
+        assert_eq!(
+            prompts,
+            [
+                Some("tidy up the parser".into()),
+                Some("now the tests".into())
+            ]
+        );
+        assert_eq!(search.hits[0].part, Part::Response);
+        assert_eq!(search.hits[0].range, 10..18);
+        assert_eq!(search.hits[1].count, 1);
+
+        // Responses are only searched when asked
+        assert!(finish(Search::start(
+            "renaming",
+            SearchScope::Prompts,
+            rows.clone()
+        ))
+        .hits
+        .is_empty());
+        let search = finish(Search::start("tests", SearchScope::Prompts, rows));
+        assert_eq!(search.hits.len(), 1);
+        assert_eq!(search.hits[0].part, Part::Message(0));
+    }
+}
@@ -0,0 +1,218 @@
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+use crate::config::{ArchiveConfig, ProviderConfig};
+use crate::event::{ProxyEvent, RequestEvent};
+
+/// Provider the self-test request is routed to; it only exists while sherlock runs
+pub const PROVIDER: &str = "sherlock-self-test";
+const PATH: &str = "/_sherlock/self-test";
+const MODEL: &str = "sherlock-self-test";
+const REPLY: &str = r#"{"self_test":"ok"}"#;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Start a local upstream that answers one request with `REPLY`, returning
+/// the provider pointing at it
+pub async fn mock_provider() -> Result<ProviderConfig> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let base_url = format!("http://{}", listener.local_addr()?);
+    tokio::spawn(async move {
+        let Ok((mut stream, _)) = listener.accept().await else {
+            return;
+        };
+        let mut request = Vec::new();
+        let mut buf = [0u8; 4096];
+        while !request_complete(&request) {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => request.extend_from_slice(&buf[..n]),
+            }
+        }
+        // No Content-Length: the proxy then relays until the stream ends,
+        // which is only after it has emitted the request's event
+        let response = format!(
+            "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\nconnection: close\r\n\r\n{}",
+            REPLY
+        );
+        let _ = stream.write_all(response.as_bytes()).await;
+    });
+
+    Ok(ProviderConfig {
+        host: "127.0.0.1".to_string(),
+        base_url,
+        env_vars: vec![],
+        path_patterns: vec![PATH.to_string()],
+        format: None,
+        fallbacks: vec![],
+        failover_statuses: vec![],
+        tls: None,
+        tokenizer: None,
+        deployment_models: BTreeMap::new(),
+        api_key_env: None,
+    })
+}
+
+/// Headers and a body of the declared length have arrived
+fn request_complete(request: &[u8]) -> bool {
+    let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") else {
+        return false;
+    };
+    let headers = String::from_utf8_lossy(&request[..end]).to_ascii_lowercase();
+    let length = headers
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length:"))
+        .and_then(|value| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    request.len() >= end + 4 + length
+}
+
+/// Send a synthetic request through the proxy at `proxy_addr` and report the
+/// outcome on `event_tx`, where it follows the request's own event
+pub async fn run(proxy_addr: &str, archive: &ArchiveConfig, event_tx: mpsc::Sender<ProxyEvent>) {
+    let result = round_trip(proxy_addr, archive)
+        .await
+        .map_err(|e| format!("{:#}", e));
+    let _ = event_tx.send(ProxyEvent::SelfTest(result)).await;
+}
+
+async fn round_trip(proxy_addr: &str, archive: &ArchiveConfig) -> Result<()> {
+    if archive.enabled {
+        check_writable(&archive.directory).await.with_context(|| {
+            format!(
+                "archive directory {} not writable",
+                archive.directory.display()
+            )
+        })?;
+    }
+
+    let body = serde_json::json!({
+        "model": MODEL,
+        "messages": [{"role": "user", "content": "ping"}],
+    });
+    let response = reqwest::Client::new()
+        .post(format!("http://{}{}", proxy_addr, PATH))
+        .timeout(TIMEOUT)
+        .json(&body)
+        .send()
+        .await
+        .with_context(|| format!("proxy at {} did not answer", proxy_addr))?;
+    let status = response.status();
+    let reply = response.text().await.context("response relay broke off")?;
+    if !status.is_success() {
+        anyhow::bail!("proxy answered {}: {}", status, reply.trim());
+    }
+    if reply != REPLY {
+        anyhow::bail!("response relay changed the body to {:?}", reply);
+    }
+    Ok(())
+}
+
+async fn check_writable(dir: &Path) -> Result<()> {
+    tokio::fs::create_dir_all(dir).await?;
+    let probe = dir.join(".sherlock-self-test");
+    tokio::fs::write(&probe, b"ok").await?;
+    tokio::fs::remove_file(&probe).await?;
+    Ok(())
+}
+
+/// Whether `event` is the self-test request, detected and parsed as sent
+pub fn is_expected(event: &RequestEvent) -> bool {
+    event.self_test && event.model == MODEL
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ProxyConfig;
+    use crate::keys::KeyFingerprinter;
+    use crate::metrics::ProxyMetrics;
+    use crate::policy::PolicyScanner;
+    use crate::proxy::ProxyServer;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    /// Serve a proxy whose only provider is the mock, returning its address
+    async fn start_proxy(event_tx: mpsc::Sender<ProxyEvent>, dir: &Path) -> String {
+        let providers = HashMap::from([(PROVIDER.to_string(), mock_provider().await.unwrap())]);
+        let proxy = ProxyServer::new(
+            ProxyConfig::default(),
+            providers,
+            event_tx,
+            Arc::new(ProxyMetrics::default()),
+            Arc::new(KeyFingerprinter::load_or_create(dir).unwrap()),
+            Arc::new(PolicyScanner::new(&Default::default()).unwrap()),
+            Arc::default(),
+        )
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(proxy.serve(listener));
+        addr
+    }
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "sherlock-self-test-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_round_trip_emits_flagged_event_first() {
+        let dir = temp_dir("pass");
+        let (event_tx, mut event_rx) = mpsc::channel(16);
+        let addr = start_proxy(event_tx.clone(), &dir).await;
+        let archive = ArchiveConfig {
+            directory: dir.join("archive"),
+            ..ArchiveConfig::default()
+        };
+
+        run(&addr, &archive, event_tx).await;
+        let mut events = Vec::new();
+        while let Some(event) = event_rx.recv().await {
+            let done = matches!(event, ProxyEvent::SelfTest(_));
+            events.push(event);
+            if done {
+                break;
+            }
+        }
+
+        assert!(matches!(&events[0], ProxyEvent::Started(r) if r.provider == PROVIDER));
+        assert!(
+            matches!(&events[1], ProxyEvent::Completed { event: Some(e), .. } if is_expected(e))
+        );
+        assert!(matches!(&events[2], ProxyEvent::SelfTest(Ok(()))));
+        // The probe file doesn't stay behind
+        assert_eq!(std::fs::read_dir(&archive.directory).unwrap().count(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unwritable_archive_reported() {
+        let dir = temp_dir("archive");
+        let blocker = dir.join("not-a-dir");
+        std::fs::write(&blocker, "").unwrap();
+        let archive = ArchiveConfig {
+            directory: blocker.join("archive"),
+            ..ArchiveConfig::default()
+        };
+
+        let (event_tx, mut event_rx) = mpsc::channel(16);
+        run("127.0.0.1:9", &archive, event_tx).await;
+        let Some(ProxyEvent::SelfTest(Err(error))) = event_rx.recv().await else {
+            panic!("expected a failed self-test");
+        };
+        assert!(error.starts_with("archive directory"), "{}", error);
+        assert!(error.contains("not writable"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
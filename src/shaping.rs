@@ -0,0 +1,214 @@
+use bytes::Bytes;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Largest piece sent at once while a limit applies, so a big body goes out
+/// as a steady trickle instead of in bursts
+const MAX_SLICE: usize = 16 * 1024;
+/// Traffic allowed through without waiting, as a fraction of a second at the
+/// configured rate, so requests under the limit see no added latency
+const BURST_SECS: f64 = 0.25;
+
+/// A bytes-per-second limit shared by every connection through the proxy
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    /// 0 means unlimited
+    rate: AtomicU64,
+    bucket: Mutex<Bucket>,
+    /// Bytes passed through, limited or not
+    transferred: AtomicU64,
+    window: Mutex<Window>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Negative while senders are waiting off a debt
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Default for Bucket {
+    fn default() -> Self {
+        Self {
+            tokens: 0.0,
+            refilled: Instant::now(),
+        }
+    }
+}
+
+/// Throughput measured over the last second or so, for the dashboard
+#[derive(Debug)]
+struct Window {
+    started: Instant,
+    transferred: u64,
+    rate: u64,
+}
+
+impl Default for Window {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            transferred: 0,
+            rate: 0,
+        }
+    }
+}
+
+impl RateLimiter {
+    /// Change the limit; `None` or 0 lifts it. Takes effect for the next
+    /// piece of every transfer already in progress.
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let rate = rate.unwrap_or(0);
+        if self.rate.swap(rate, Ordering::Relaxed) != rate {
+            *self.bucket.lock().unwrap() = Bucket {
+                tokens: rate as f64 * BURST_SECS,
+                refilled: Instant::now(),
+            };
+        }
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        Some(self.rate.load(Ordering::Relaxed)).filter(|rate| *rate > 0)
+    }
+
+    /// Wait until `bytes` more may be sent. Returns at once when unlimited or
+    /// when the burst allowance covers them.
+    pub async fn acquire(&self, bytes: usize) {
+        self.transferred.fetch_add(bytes as u64, Ordering::Relaxed);
+        let Some(rate) = self.rate() else {
+            return;
+        };
+        let rate = rate as f64;
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.refilled).as_secs_f64() * rate;
+            bucket.tokens = (bucket.tokens + refill).min(rate * BURST_SECS);
+            bucket.refilled = now;
+            // Taking on debt keeps concurrent senders in line: each one waits
+            // for everything queued ahead of it
+            bucket.tokens -= bytes as f64;
+            (bucket.tokens < 0.0).then(|| Duration::from_secs_f64(-bucket.tokens / rate))
+        };
+        if let Some(wait) = wait {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// `chunk` cut into pieces small enough to pace smoothly; whole when
+    /// unlimited
+    pub fn slices(&self, chunk: Bytes) -> Vec<Bytes> {
+        let Some(rate) = self.rate() else {
+            return vec![chunk];
+        };
+        // Keep pieces to a tenth of a second at low rates
+        let size = MAX_SLICE.min((rate / 10).max(1) as usize);
+        (0..chunk.len())
+            .step_by(size)
+            .map(|start| chunk.slice(start..chunk.len().min(start + size)))
+            .collect()
+    }
+
+    pub fn transferred(&self) -> u64 {
+        self.transferred.load(Ordering::Relaxed)
+    }
+
+    /// Bytes per second over roughly the last second
+    pub fn throughput(&self) -> u64 {
+        let mut window = self.window.lock().unwrap();
+        let elapsed = window.started.elapsed();
+        if elapsed >= Duration::from_secs(1) {
+            let transferred = self.transferred();
+            window.rate =
+                ((transferred - window.transferred) as f64 / elapsed.as_secs_f64()) as u64;
+            window.started = Instant::now();
+            window.transferred = transferred;
+        }
+        window.rate
+    }
+}
+
+/// Upload and download limits from `proxy.max_*_bytes_per_sec`
+#[derive(Debug, Default)]
+pub struct Shaper {
+    /// Request bodies sent upstream
+    pub upload: RateLimiter,
+    /// Response bodies relayed back to clients
+    pub download: RateLimiter,
+}
+
+impl Shaper {
+    pub fn set_limits(&self, upload: Option<u64>, download: Option<u64>) {
+        self.upload.set_rate(upload);
+        self.download.set_rate(download);
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.upload.rate().is_some() || self.download.rate().is_some()
+    }
+
+    /// Header text while shaping, e.g. "↑ 48 KB/s of 64 KB/s  ↓ 1.2 MB/s"
+    pub fn status(&self) -> Option<String> {
+        if !self.is_active() {
+            return None;
+        }
+        let describe = |arrow: &str, limiter: &RateLimiter| {
+            let current = format_rate(limiter.throughput());
+            match limiter.rate() {
+                Some(rate) => format!("{} {} of {}", arrow, current, format_rate(rate)),
+                None => format!("{} {}", arrow, current),
+            }
+        };
+        Some(format!(
+            "{}  {}",
+            describe("↑", &self.upload),
+            describe("↓", &self.download)
+        ))
+    }
+}
+
+/// e.g. "512 B/s", "64 KB/s", "1.2 MB/s"
+pub fn format_rate(bytes_per_sec: u64) -> String {
+    match bytes_per_sec {
+        0..=1023 => format!("{} B/s", bytes_per_sec),
+        1024..=1_048_575 => format!("{} KB/s", bytes_per_sec / 1024),
+        _ => format!("{:.1} MB/s", bytes_per_sec as f64 / 1_048_576.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_no_delay_under_limit() {
+        let limiter = RateLimiter::default();
+        limiter.set_rate(Some(1024 * 1024));
+        let started = Instant::now();
+        for _ in 0..8 {
+            limiter.acquire(16 * 1024).await;
+        }
+        assert!(started.elapsed() < Duration::from_millis(50));
+        assert_eq!(limiter.transferred(), 128 * 1024);
+    }
+
+    #[tokio::test]
+    async fn test_paced_over_limit() {
+        let limiter = RateLimiter::default();
+        limiter.set_rate(Some(100_000));
+        let started = Instant::now();
+        for piece in limiter.slices(Bytes::from(vec![0u8; 75_000])) {
+            assert!(piece.len() <= 10_000);
+            limiter.acquire(piece.len()).await;
+        }
+        // 25 KB of burst, the remaining 50 KB at 100 KB/s
+        assert!(started.elapsed() >= Duration::from_millis(450));
+
+        // Lifting the limit takes effect at once
+        limiter.set_rate(None);
+        let started = Instant::now();
+        limiter.acquire(1_000_000).await;
+        assert!(started.elapsed() < Duration::from_millis(50));
+    }
+}
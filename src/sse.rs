@@ -0,0 +1,392 @@
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use crate::event::Throughput;
+use crate::parser::count_tokens;
+
+/// A single server-sent event
+#[derive(Debug, Clone, PartialEq)]
+pub struct SseEvent {
+    pub event: Option<String>,
+    pub data: String,
+}
+
+/// Incremental SSE framing parser.
+///
+/// Chunks may split lines (or UTF-8 sequences) anywhere; incomplete input
+/// is buffered until the rest arrives.
+#[derive(Debug, Default)]
+pub struct SseParser {
+    buffer: Vec<u8>,
+    event: Option<String>,
+    data: Vec<String>,
+}
+
+impl SseParser {
+    /// Feed a chunk of the stream, returning every event it completed
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut events = Vec::new();
+        while let Some(pos) = self.buffer.iter().position(|&b| b == b'\n') {
+            let mut line: Vec<u8> = self.buffer.drain(..=pos).collect();
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            let line = String::from_utf8_lossy(&line);
+
+            if line.is_empty() {
+                if let Some(event) = self.dispatch() {
+                    events.push(event);
+                }
+                continue;
+            }
+            if line.starts_with(':') {
+                continue;
+            }
+
+            let (field, value) = match line.split_once(':') {
+                Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+                None => (line.as_ref(), ""),
+            };
+            match field {
+                "event" => self.event = Some(value.to_string()),
+                "data" => self.data.push(value.to_string()),
+                _ => {}
+            }
+        }
+
+        events
+    }
+
+    fn dispatch(&mut self) -> Option<SseEvent> {
+        let event = self.event.take();
+        if self.data.is_empty() {
+            return None;
+        }
+        let data = std::mem::take(&mut self.data).join("\n");
+        Some(SseEvent { event, data })
+    }
+}
+
+/// A content block rebuilt from an Anthropic Messages stream
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamedBlock {
+    Text(String),
+    ToolUse {
+        id: String,
+        name: String,
+        /// Concatenated `input_json_delta` fragments parsed as JSON.
+        /// `Err` keeps the raw text when the fragments don't form valid JSON
+        /// (e.g. generation stopped mid-argument).
+        input: Result<Value, String>,
+    },
+}
+
+#[derive(Debug)]
+enum PartialBlock {
+    Text(String),
+    ToolUse {
+        id: String,
+        name: String,
+        initial: Value,
+        partial_json: String,
+    },
+    Other,
+}
+
+/// Accumulates Anthropic `content_block_*` events into complete blocks.
+///
+/// Blocks are tracked by their `index`, so interleaved deltas for parallel
+/// blocks are reassembled independently. Output tokens are counted one delta
+/// at a time, so the text is never re-tokenized as it grows.
+#[derive(Debug, Default)]
+pub struct AnthropicStreamAccumulator {
+    blocks: BTreeMap<u64, PartialBlock>,
+    output_tokens: u64,
+}
+
+impl AnthropicStreamAccumulator {
+    /// Apply one event, returning how many output tokens its delta carried
+    pub fn push(&mut self, event: &SseEvent) -> u64 {
+        let Ok(data) = serde_json::from_str::<Value>(&event.data) else {
+            return 0;
+        };
+        let Some(index) = data.get("index").and_then(Value::as_u64) else {
+            return 0;
+        };
+
+        match data.get("type").and_then(Value::as_str) {
+            Some("content_block_start") => {
+                let block = data.get("content_block").cloned().unwrap_or(Value::Null);
+                let partial = match block.get("type").and_then(Value::as_str) {
+                    Some("text") => PartialBlock::Text(
+                        block
+                            .get("text")
+                            .and_then(Value::as_str)
+                            .unwrap_or_default()
+                            .to_string(),
+                    ),
+                    Some("tool_use") => PartialBlock::ToolUse {
+                        id: string_field(&block, "id"),
+                        name: string_field(&block, "name"),
+                        initial: block.get("input").cloned().unwrap_or(Value::Null),
+                        partial_json: String::new(),
+                    },
+                    _ => PartialBlock::Other,
+                };
+                self.blocks.insert(index, partial);
+            }
+            Some("content_block_delta") => {
+                let Some(delta) = data.get("delta") else {
+                    return 0;
+                };
+                let tokens = ["text", "partial_json", "thinking"]
+                    .iter()
+                    .filter_map(|field| delta.get(field).and_then(Value::as_str))
+                    .map(|text| count_tokens(text) as u64)
+                    .sum::<u64>();
+                self.output_tokens += tokens;
+                match (
+                    self.blocks.get_mut(&index),
+                    delta.get("type").and_then(Value::as_str),
+                ) {
+                    (Some(PartialBlock::Text(text)), Some("text_delta")) => {
+                        text.push_str(
+                            delta
+                                .get("text")
+                                .and_then(Value::as_str)
+                                .unwrap_or_default(),
+                        );
+                    }
+                    (
+                        Some(PartialBlock::ToolUse { partial_json, .. }),
+                        Some("input_json_delta"),
+                    ) => {
+                        partial_json.push_str(
+                            delta
+                                .get("partial_json")
+                                .and_then(Value::as_str)
+                                .unwrap_or_default(),
+                        );
+                    }
+                    _ => {}
+                }
+                return tokens;
+            }
+            _ => {}
+        }
+        0
+    }
+
+    /// Output tokens seen so far
+    pub fn output_tokens(&self) -> u64 {
+        self.output_tokens
+    }
+
+    /// Finish accumulation, returning the blocks in index order
+    pub fn finish(self) -> Vec<StreamedBlock> {
+        self.blocks
+            .into_values()
+            .filter_map(|block| match block {
+                PartialBlock::Text(text) => Some(StreamedBlock::Text(text)),
+                PartialBlock::ToolUse {
+                    id,
+                    name,
+                    initial,
+                    partial_json,
+                } => {
+                    let input = if partial_json.trim().is_empty() {
+                        Ok(initial)
+                    } else {
+                        serde_json::from_str(&partial_json).map_err(|_| partial_json)
+                    };
+                    Some(StreamedBlock::ToolUse { id, name, input })
+                }
+                PartialBlock::Other => None,
+            })
+            .collect()
+    }
+}
+
+/// Read-only observer of a relayed Anthropic SSE stream.
+///
+/// The relay forwards the original chunks untouched; the tap only looks at them.
+#[derive(Debug, Default)]
+pub struct AnthropicStreamTap {
+    parser: SseParser,
+    accumulator: AnthropicStreamAccumulator,
+    /// When the first and latest chunks carrying output tokens arrived
+    first_content: Option<Instant>,
+    last_content: Option<Instant>,
+}
+
+impl AnthropicStreamTap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn observe(&mut self, chunk: &[u8]) {
+        self.observe_at(chunk, Instant::now());
+    }
+
+    fn observe_at(&mut self, chunk: &[u8], now: Instant) {
+        for event in self.parser.feed(chunk) {
+            if self.accumulator.push(&event) > 0 {
+                self.first_content.get_or_insert(now);
+                self.last_content = Some(now);
+            }
+        }
+    }
+
+    /// Output speed so far, once any content has streamed
+    pub fn throughput(&self) -> Option<Throughput> {
+        let duration = self.last_content?.duration_since(self.first_content?);
+        Some(Throughput {
+            output_tokens: self.accumulator.output_tokens(),
+            duration_ms: duration.as_millis() as u64,
+        })
+    }
+
+    pub fn finish(self) -> Vec<StreamedBlock> {
+        self.accumulator.finish()
+    }
+}
+
+fn string_field(value: &Value, key: &str) -> String {
+    value
+        .get(key)
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FINE_GRAINED: &str = include_str!("../tests/fixtures/anthropic_fine_grained_tools.sse");
+    const PARALLEL: &str = include_str!("../tests/fixtures/anthropic_parallel_tools.sse");
+
+    fn accumulate_in_chunks(stream: &str, chunk_size: usize) -> Vec<StreamedBlock> {
+        let mut tap = AnthropicStreamTap::new();
+        for chunk in stream.as_bytes().chunks(chunk_size) {
+            tap.observe(chunk);
+        }
+        tap.finish()
+    }
+
+    #[test]
+    fn test_sse_parser_split_lines() {
+        let mut parser = SseParser::default();
+        assert!(parser.feed(b"event: ping\r\nda").is_empty());
+        let events = parser.feed(b"ta: {\"a\":1}\r\n\r\n: comment\n\ndata: x\n");
+        assert_eq!(
+            events,
+            vec![SseEvent {
+                event: Some("ping".to_string()),
+                data: "{\"a\":1}".to_string()
+            }]
+        );
+        assert_eq!(parser.feed(b"data: y\n\n")[0].data, "x\ny");
+    }
+
+    #[test]
+    fn test_fine_grained_tool_input_reassembled() {
+        // Every chunking, down to single bytes, must produce the same result
+        for chunk_size in [1, 3, 17, 64, FINE_GRAINED.len()] {
+            let blocks = accumulate_in_chunks(FINE_GRAINED, chunk_size);
+            assert_eq!(blocks.len(), 2);
+            assert_eq!(
+                blocks[0],
+                StreamedBlock::Text("I'll update the configuration file.".to_string())
+            );
+            match &blocks[1] {
+                StreamedBlock::ToolUse { id, name, input } => {
+                    assert_eq!(id, "toolu_01D7FLrfh4GYq7yT1ULFeyMV");
+                    assert_eq!(name, "write_file");
+                    let input = input.as_ref().expect("tool input should parse");
+                    assert_eq!(input["path"], "src/config.rs");
+                    assert_eq!(
+                        input["content"],
+                        "fn main() {\n    println!(\"héllo\");\n}\n"
+                    );
+                }
+                other => panic!("expected tool_use, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parallel_blocks_by_index() {
+        let blocks = accumulate_in_chunks(PARALLEL, 7);
+        let inputs: Vec<_> = blocks
+            .iter()
+            .filter_map(|b| match b {
+                StreamedBlock::ToolUse { name, input, .. } => {
+                    Some((name.as_str(), input.clone().unwrap()))
+                }
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(inputs.len(), 2);
+        assert_eq!(inputs[0].0, "read_file");
+        assert_eq!(inputs[0].1, serde_json::json!({"path": "Cargo.toml"}));
+        assert_eq!(inputs[1].0, "list_dir");
+        assert_eq!(
+            inputs[1].1,
+            serde_json::json!({"path": "src", "recursive": true})
+        );
+    }
+
+    #[test]
+    fn test_truncated_tool_input_kept_raw() {
+        let stream = concat!(
+            "event: content_block_start\n",
+            "data: {\"type\":\"content_block_start\",\"index\":0,\"content_block\":{\"type\":\"tool_use\",\"id\":\"t\",\"name\":\"edit\",\"input\":{}}}\n\n",
+            "event: content_block_delta\n",
+            "data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"input_json_delta\",\"partial_json\":\"{\\\"path\\\": \\\"a\"}}\n\n",
+        );
+        let blocks = accumulate_in_chunks(stream, 5);
+        match &blocks[0] {
+            StreamedBlock::ToolUse { input, .. } => {
+                assert_eq!(input, &Err("{\"path\": \"a".to_string()));
+            }
+            other => panic!("expected tool_use, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_throughput_counted_per_delta() {
+        let start = Instant::now();
+        let mut tap = AnthropicStreamTap::new();
+        let chunks: Vec<&[u8]> = FINE_GRAINED.as_bytes().chunks(64).collect();
+        for (i, chunk) in chunks.iter().enumerate() {
+            tap.observe_at(chunk, start + std::time::Duration::from_millis(10 * i as u64));
+        }
+
+        let throughput = tap.throughput().unwrap();
+        assert!(throughput.duration_ms > 0);
+        assert!(throughput.duration_ms < 10 * chunks.len() as u64);
+        // Counting delta by delta stays close to tokenizing the finished output
+        let text: String = tap
+            .finish()
+            .iter()
+            .map(|block| match block {
+                StreamedBlock::Text(text) => text.clone(),
+                StreamedBlock::ToolUse { input, .. } => input.as_ref().unwrap().to_string(),
+            })
+            .collect();
+        let whole = count_tokens(&text) as f64;
+        let counted = throughput.output_tokens as f64;
+        assert!((counted - whole).abs() / whole < 0.5, "{} vs {}", counted, whole);
+
+        // No content yet, no measurement
+        let mut empty = AnthropicStreamTap::new();
+        empty.observe(b"event: ping\ndata: {\"type\":\"ping\"}\n\n");
+        assert_eq!(empty.throughput(), None);
+    }
+}
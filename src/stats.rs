@@ -0,0 +1,610 @@
+use serde::{Serialize, Serializer};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use crate::dashboard::format_number;
+use crate::event::{RequestEvent, Throughput};
+use crate::index::IndexEntry;
+use crate::keys::KeyFingerprint;
+use crate::reliability::Sample;
+use crate::repo::RepoInfo;
+
+/// Number of linear sub-buckets per power of two. 16 sub-buckets keep the
+/// relative error of a quantile estimate under ~3%.
+const SUB_BUCKETS: u64 = 16;
+const SUB_BUCKET_BITS: u32 = 4;
+/// Enough buckets to cover the full u64 range
+const BUCKET_COUNT: usize = (SUB_BUCKETS as usize) * (64 - SUB_BUCKET_BITS as usize + 1);
+
+/// Widest bar of a `--histogram` chart, in characters
+const CHART_WIDTH: u64 = 40;
+
+/// Fixed-bucket log-linear histogram for streaming quantile estimates.
+///
+/// Memory stays constant no matter how many values are recorded, so it is
+/// safe to keep one per metric for the lifetime of a session.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+    min: u64,
+    max: u64,
+}
+
+/// p50/p90/p99 summary of a histogram
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Percentiles {
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Self {
+            counts: vec![0; BUCKET_COUNT],
+            total: 0,
+            min: u64::MAX,
+            max: 0,
+        }
+    }
+
+    /// Record a single value
+    pub fn record(&mut self, value: u64) {
+        self.counts[bucket_index(value)] += 1;
+        self.total += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    /// Values recorded
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    /// Estimate the value at quantile `q` (0.0..=1.0)
+    pub fn quantile(&self, q: f64) -> Option<u64> {
+        if self.total == 0 {
+            return None;
+        }
+
+        let rank = ((q.clamp(0.0, 1.0) * self.total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let (low, high) = bucket_bounds(index);
+                let mid = low + (high - low) / 2;
+                return Some(mid.clamp(self.min, self.max));
+            }
+        }
+
+        Some(self.max)
+    }
+
+    pub fn percentiles(&self) -> Option<Percentiles> {
+        Some(Percentiles {
+            p50: self.quantile(0.50)?,
+            p90: self.quantile(0.90)?,
+            p99: self.quantile(0.99)?,
+        })
+    }
+
+    /// Counts per power of two, as `(low, high, count)` with inclusive
+    /// bounds, from the lowest value recorded to the highest
+    pub fn coarse_buckets(&self) -> Vec<(u64, u64, u64)> {
+        if self.total == 0 {
+            return Vec::new();
+        }
+        let range = |value: u64| match value {
+            0 => (0, 0),
+            _ => {
+                let low = 1u64 << (63 - value.leading_zeros());
+                (low, low.saturating_sub(1).saturating_add(low))
+            }
+        };
+        let mut buckets: Vec<(u64, u64, u64)> = Vec::new();
+        let (mut low, mut high) = range(self.min);
+        loop {
+            buckets.push((low, high, 0));
+            if high >= self.max {
+                break;
+            }
+            (low, high) = range(high + 1);
+        }
+        for (index, &count) in self.counts.iter().enumerate().filter(|(_, &c)| c > 0) {
+            let low = bucket_bounds(index).0;
+            if let Some(bucket) = buckets
+                .iter_mut()
+                .find(|(l, h, _)| (*l..=*h).contains(&low))
+            {
+                bucket.2 += count;
+            }
+        }
+        buckets
+    }
+}
+
+fn serialize_percentiles<S: Serializer>(
+    hist: &Histogram,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    hist.percentiles().serialize(serializer)
+}
+
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKETS {
+        return value as usize;
+    }
+    let exp = 63 - value.leading_zeros();
+    let shift = exp - SUB_BUCKET_BITS;
+    let sub = (value >> shift) & (SUB_BUCKETS - 1);
+    (SUB_BUCKETS + (shift as u64) * SUB_BUCKETS + sub) as usize
+}
+
+/// Inclusive value range covered by a bucket
+fn bucket_bounds(index: usize) -> (u64, u64) {
+    let index = index as u64;
+    if index < SUB_BUCKETS {
+        return (index, index);
+    }
+    let shift = (index - SUB_BUCKETS) / SUB_BUCKETS;
+    let sub = (index - SUB_BUCKETS) % SUB_BUCKETS;
+    let low = (SUB_BUCKETS + sub) << shift;
+    let high = low + ((1u64 << shift) - 1);
+    (low, high)
+}
+
+/// Distribution statistics accumulated over a session
+#[derive(Debug, Clone, Default)]
+pub struct SessionStats {
+    /// Input token count per request
+    pub request_tokens: Histogram,
+    /// The same, grouped by API key fingerprint
+    pub by_key: BTreeMap<String, KeyStats>,
+    /// The same, grouped by the name of the git repository the tool ran in
+    pub by_repo: BTreeMap<String, Histogram>,
+    /// Output tokens per second of streamed responses, by model
+    pub throughput: BTreeMap<String, Histogram>,
+    /// Milliseconds to the first byte of requests that asked for a stream
+    pub streaming_latency: Histogram,
+    /// Milliseconds to the whole response of those that didn't
+    pub sync_latency: Histogram,
+    /// Tokens each finished response generated
+    pub output_tokens: Histogram,
+    /// How each forwarded request went, for the reliability panel
+    pub outcomes: Vec<Sample>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct KeyStats {
+    /// Display label of the key, e.g. "…a1b2"
+    pub label: String,
+    pub request_tokens: Histogram,
+}
+
+impl SessionStats {
+    pub fn record_request(
+        &mut self,
+        tokens: usize,
+        key: Option<&KeyFingerprint>,
+        repo: Option<&RepoInfo>,
+    ) {
+        self.request_tokens.record(tokens as u64);
+        if let Some(repo) = repo {
+            self.by_repo.entry(repo.name()).or_default().record(tokens as u64);
+        }
+        if let Some(key) = key {
+            let stats = self.by_key.entry(key.fingerprint.clone()).or_default();
+            stats.label = key.label();
+            stats.request_tokens.record(tokens as u64);
+        }
+    }
+
+    pub fn record_outcome(&mut self, sample: Sample) {
+        self.outcomes.push(sample);
+    }
+
+    /// Streamed and whole responses are timed to different points, so they
+    /// are kept apart
+    pub fn record_latency(&mut self, streaming: bool, latency_ms: u64) {
+        if streaming {
+            self.streaming_latency.record(latency_ms);
+        } else {
+            self.sync_latency.record(latency_ms);
+        }
+    }
+
+    /// Count a response's output speed, unless it was too short to mean much
+    pub fn record_throughput(&mut self, model: &str, throughput: &Throughput) {
+        if !throughput.is_significant() {
+            return;
+        }
+        if let Some(rate) = throughput.tokens_per_sec() {
+            self.throughput
+                .entry(model.to_string())
+                .or_default()
+                .record(rate.round() as u64);
+        }
+    }
+}
+
+/// Percentiles over the archive index, for `sherlock stats`; serialized as
+/// p50/p90/p99, or null without samples
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Distribution {
+    /// Input tokens per request
+    #[serde(serialize_with = "serialize_percentiles")]
+    pub request_tokens: Histogram,
+    /// Tokens per finished response
+    #[serde(serialize_with = "serialize_percentiles")]
+    pub output_tokens: Histogram,
+    /// Milliseconds until the response finished, or its first byte for
+    /// event streams, as in the Latency column
+    #[serde(serialize_with = "serialize_percentiles")]
+    pub latency_ms: Histogram,
+}
+
+impl Distribution {
+    fn rows(&self) -> [(&'static str, &Histogram); 3] {
+        [
+            ("tokens", &self.request_tokens),
+            ("output tokens", &self.output_tokens),
+            ("latency ms", &self.latency_ms),
+        ]
+    }
+
+    /// An ASCII bar per power of two of each histogram, for `--histogram`
+    pub fn chart(&self) -> String {
+        let mut chart = String::new();
+        for (label, hist) in self.rows() {
+            let buckets = hist.coarse_buckets();
+            let Some(most) = buckets.iter().map(|&(_, _, count)| count).max() else {
+                continue;
+            };
+            chart.push_str(&format!("{}:\n", label));
+            for (low, high, count) in buckets {
+                let bar = "█".repeat((count * CHART_WIDTH).div_ceil(most) as usize);
+                chart.push_str(&format!(
+                    "  {:>11} - {:<11} {:<width$} {}\n",
+                    format_number(low),
+                    format_number(high),
+                    bar,
+                    count,
+                    width = CHART_WIDTH as usize
+                ));
+            }
+        }
+        chart
+    }
+}
+
+/// p50/p90/p99 table of the histograms that have samples
+impl fmt::Display for Distribution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rows: Vec<_> = self
+            .rows()
+            .into_iter()
+            .filter_map(|(label, hist)| Some((label, hist.percentiles()?)))
+            .collect();
+        if rows.is_empty() {
+            return Ok(());
+        }
+        writeln!(f, "  {:<14} {:>10} {:>10} {:>10}", "", "p50", "p90", "p99")?;
+        for (label, p) in rows {
+            writeln!(
+                f,
+                "  {:<14} {:>10} {:>10} {:>10}",
+                label,
+                format_number(p.p50),
+                format_number(p.p90),
+                format_number(p.p99)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Output tokens per second by model over the archive index, for
+/// `sherlock stats --throughput`
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ThroughputReport {
+    pub models: BTreeMap<String, ModelThroughput>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ModelThroughput {
+    /// Responses long enough to count
+    pub responses: u64,
+    #[serde(flatten)]
+    pub tokens_per_sec: Percentiles,
+}
+
+impl ThroughputReport {
+    /// Measured like the Distribution panel, leaving out short responses
+    pub fn build(entries: &[IndexEntry]) -> Self {
+        let mut stats = SessionStats::default();
+        for entry in entries {
+            if let (Some(model), Some(throughput)) = (&entry.model, &entry.throughput) {
+                stats.record_throughput(model, throughput);
+            }
+        }
+        let models = stats
+            .throughput
+            .into_iter()
+            .filter_map(|(model, hist)| {
+                let measured = ModelThroughput {
+                    responses: hist.count(),
+                    tokens_per_sec: hist.percentiles()?,
+                };
+                Some((model, measured))
+            })
+            .collect();
+        Self { models }
+    }
+}
+
+impl fmt::Display for ThroughputReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.models.is_empty() {
+            return writeln!(f, "No measured streamed responses yet");
+        }
+        writeln!(
+            f,
+            "Output tokens per second\n  {:<32} {:>9} {:>6} {:>6} {:>6}",
+            "model", "responses", "p50", "p90", "p99"
+        )?;
+        let mut models: Vec<_> = self.models.iter().collect();
+        models.sort_by_key(|(_, measured)| Reverse(measured.tokens_per_sec.p50));
+        for (model, measured) in models {
+            let p = measured.tokens_per_sec;
+            writeln!(
+                f,
+                "  {:<32} {:>9} {:>6} {:>6} {:>6}",
+                model, measured.responses, p.p50, p.p90, p.p99
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Requests and tokens of one model, or of all of a provider's models
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ModelStats {
+    pub requests: usize,
+    pub tokens: u64,
+    pub output_tokens: u64,
+}
+
+impl ModelStats {
+    pub fn record(&mut self, event: &RequestEvent) {
+        self.requests += 1;
+        self.tokens += event.tokens as u64;
+        self.output_tokens += event.output.map_or(0, |output| output.tokens);
+    }
+}
+
+/// `stats` with the most tokens first, then the most requests
+pub fn ranked(stats: &HashMap<String, ModelStats>) -> Vec<(&str, ModelStats)> {
+    let mut ranked: Vec<(&str, ModelStats)> = stats
+        .iter()
+        .map(|(name, stats)| (name.as_str(), *stats))
+        .collect();
+    ranked.sort_by(|(a_name, a), (b_name, b)| {
+        (b.tokens, b.requests)
+            .cmp(&(a.tokens, a.requests))
+            .then_with(|| a_name.cmp(b_name))
+    });
+    ranked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(estimate: u64, exact: u64) {
+        let error = (estimate as f64 - exact as f64).abs() / exact as f64;
+        assert!(
+            error <= 0.04,
+            "estimate {} too far from {} ({:.2}%)",
+            estimate,
+            exact,
+            error * 100.0
+        );
+    }
+
+    #[test]
+    fn test_bucket_bounds_cover_index() {
+        for value in [0, 1, 15, 16, 17, 100, 1_000, 65_535, 1 << 40, u64::MAX] {
+            let (low, high) = bucket_bounds(bucket_index(value));
+            assert!(
+                low <= value && value <= high,
+                "{} not in [{}, {}]",
+                value,
+                low,
+                high
+            );
+        }
+    }
+
+    #[test]
+    fn test_uniform_distribution() {
+        let mut hist = Histogram::new();
+        for v in 1..=100_000u64 {
+            hist.record(v);
+        }
+
+        let p = hist.percentiles().unwrap();
+        assert_close(p.p50, 50_000);
+        assert_close(p.p90, 90_000);
+        assert_close(p.p99, 99_000);
+    }
+
+    #[test]
+    fn test_skewed_distribution() {
+        // 99% small requests, 1% huge ones
+        let mut hist = Histogram::new();
+        for i in 0..10_000u64 {
+            hist.record(if i % 100 == 0 { 150_000 } else { 2_000 + i % 7 });
+        }
+
+        let p = hist.percentiles().unwrap();
+        assert_close(p.p50, 2_003);
+        assert_close(p.p90, 2_003);
+        assert_close(p.p99, 2_006);
+        assert_close(hist.quantile(1.0).unwrap(), 150_000);
+    }
+
+    #[test]
+    fn test_grouped_by_key() {
+        let key = |fingerprint: &str, last4: &str| KeyFingerprint {
+            fingerprint: fingerprint.to_string(),
+            last4: last4.to_string(),
+        };
+        let mut stats = SessionStats::default();
+        stats.record_request(10, Some(&key("aa", "1111")), None);
+        stats.record_request(14, Some(&key("bb", "2222")), None);
+        stats.record_request(10, Some(&key("aa", "1111")), None);
+        stats.record_request(5, None, None);
+
+        assert_eq!(stats.request_tokens.quantile(1.0), Some(14));
+        assert_eq!(stats.by_key.len(), 2);
+        assert_eq!(stats.by_key["aa"].label, "…1111");
+        assert_eq!(stats.by_key["aa"].request_tokens.quantile(1.0), Some(10));
+        assert_eq!(stats.by_key["bb"].request_tokens.quantile(0.5), Some(14));
+    }
+
+    #[test]
+    fn test_empty_and_single() {
+        let mut hist = Histogram::new();
+        assert_eq!(hist.percentiles(), None);
+
+        hist.record(42);
+        assert_eq!(
+            hist.percentiles(),
+            Some(Percentiles {
+                p50: 42,
+                p90: 42,
+                p99: 42
+            })
+        );
+    }
+
+    #[test]
+    fn test_coarse_buckets_and_chart() {
+        let mut hist = Histogram::new();
+        for value in [3, 5, 6, 7, 100, 100] {
+            hist.record(value);
+        }
+        assert_eq!(
+            hist.coarse_buckets(),
+            [
+                (2, 3, 1),
+                (4, 7, 3),
+                (8, 15, 0),
+                (16, 31, 0),
+                (32, 63, 0),
+                (64, 127, 2)
+            ]
+        );
+        assert!(Histogram::new().coarse_buckets().is_empty());
+
+        let mut zero = Histogram::new();
+        zero.record(0);
+        zero.record(u64::MAX);
+        let buckets = zero.coarse_buckets();
+        assert_eq!(buckets.len(), 65);
+        assert_eq!(buckets[0], (0, 0, 1));
+        assert_eq!(buckets[64], (1 << 63, u64::MAX, 1));
+
+        let distribution = Distribution {
+            output_tokens: hist,
+            ..Distribution::default()
+        };
+        let chart = distribution.chart();
+        let lines: Vec<&str> = chart.lines().collect();
+        assert_eq!(lines.len(), 7);
+        assert_eq!(lines[0], "output tokens:");
+        assert_eq!(
+            lines[2],
+            format!("  {:>11} - {:<11} {} 3", 4, 7, "█".repeat(40))
+        );
+        assert!(lines[3].ends_with(" 0"));
+        assert_eq!(lines[6].matches('█').count(), 27);
+        assert!(lines[6].ends_with(" 2"));
+    }
+
+    #[test]
+    fn test_throughput_skips_short_responses() {
+        let mut stats = SessionStats::default();
+        let sample = |output_tokens, duration_ms| Throughput {
+            output_tokens,
+            duration_ms,
+        };
+        stats.record_throughput("claude", &sample(500, 10_000));
+        stats.record_throughput("claude", &sample(960, 10_000));
+        stats.record_throughput("claude", &sample(3, 10));
+        stats.record_throughput("claude", &sample(2_000, 100));
+        stats.record_throughput("gpt-4o", &sample(10, 5_000));
+
+        assert_eq!(stats.throughput["claude"].quantile(0.5), Some(50));
+        assert_eq!(stats.throughput["claude"].quantile(1.0), Some(96));
+        assert!(!stats.throughput.contains_key("gpt-4o"));
+    }
+
+    #[test]
+    fn test_throughput_report() {
+        let mut event = crate::parser::minimal_event(b"hi", "/v1/messages", "anthropic");
+        let mut entry = |model: &str, output_tokens, duration_ms| {
+            event.model = model.to_string();
+            event.throughput = Some(Throughput {
+                output_tokens,
+                duration_ms,
+            });
+            IndexEntry::from(&event)
+        };
+        let entries = [
+            entry("claude-haiku", 1_000, 5_000),
+            entry("claude-haiku", 1_200, 5_000),
+            entry("claude-haiku", 10, 100),
+            entry("claude-opus", 800, 20_000),
+            entry("gpt-4o", 5, 5_000),
+        ];
+
+        let report = ThroughputReport::build(&entries);
+        assert_eq!(report.models.len(), 2);
+        assert_eq!(report.models["claude-haiku"].responses, 2);
+        assert_close(report.models["claude-haiku"].tokens_per_sec.p50, 200);
+        let text = report.to_string();
+        let rows: Vec<&str> = text.lines().skip(2).collect();
+        assert!(rows[0].starts_with("  claude-haiku "), "{}", text);
+        assert!(rows[1].starts_with("  claude-opus "));
+        assert!(rows[1].ends_with("40     40     40"));
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["models"]["claude-opus"]["p50"], 40);
+        assert_eq!(
+            ThroughputReport::build(&[]).to_string(),
+            "No measured streamed responses yet\n"
+        );
+    }
+
+    #[test]
+    fn test_latency_split_by_streaming() {
+        let mut stats = SessionStats::default();
+        stats.record_latency(true, 400);
+        stats.record_latency(false, 9_000);
+        stats.record_latency(false, 11_000);
+
+        assert_eq!(stats.streaming_latency.quantile(1.0), Some(400));
+        assert_eq!(stats.sync_latency.quantile(0.0), Some(9_000));
+        assert_eq!(stats.sync_latency.quantile(1.0), Some(11_000));
+    }
+}
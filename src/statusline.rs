@@ -0,0 +1,262 @@
+//! A one-line summary of what sherlock has counted, for the terminal title
+//! (`dashboard.set_terminal_title`) and for `sherlock statusline`, which
+//! status bars such as tmux's `status-right` can run.
+
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use crate::index::IndexEntry;
+use crate::metrics::ArchiveSnapshot;
+use crate::pricing::format_cost;
+
+/// Least time between two terminal title updates
+const TITLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What the totals cover
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// The running instance's session
+    #[default]
+    Session,
+    /// Today's requests in the archive index, with no instance running
+    Today,
+}
+
+/// Requests, tokens and spend, as the running instance serves them on
+/// `/__sherlock/api/status`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Status {
+    #[serde(default)]
+    pub scope: Scope,
+    pub requests: usize,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    /// Dollars spent on priced requests; unset until one completes, and
+    /// always for `Scope::Today`, as the index keeps no prices
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cost_usd: Option<f64>,
+    /// Request bodies sherlock couldn't parse, by kind of failure
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub parse_errors: BTreeMap<String, u64>,
+    /// How the archive writer is keeping up, for `Scope::Session`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archive: Option<ArchiveSnapshot>,
+}
+
+impl Status {
+    /// Today's totals from the archive index; `entries` may cover any span
+    pub fn today(entries: impl IntoIterator<Item = IndexEntry>, today: NaiveDate) -> Self {
+        let mut status = Self {
+            scope: Scope::Today,
+            ..Self::default()
+        };
+        for entry in entries {
+            if entry.timestamp.with_timezone(&chrono::Local).date_naive() != today {
+                continue;
+            }
+            status.requests += 1;
+            status.input_tokens += entry.tokens.unwrap_or(0);
+            status.output_tokens += entry.output_tokens.unwrap_or(0);
+        }
+        status
+    }
+
+    /// e.g. "sherlock · 142k tok · $3.20", or "sherlock today · 12k tok"
+    pub fn line(&self) -> String {
+        let mut line = match self.scope {
+            Scope::Session => "sherlock".to_string(),
+            Scope::Today => "sherlock today".to_string(),
+        };
+        if self.requests == 0 {
+            line.push_str(" · idle");
+            return line;
+        }
+        let tokens = self.input_tokens + self.output_tokens;
+        line.push_str(&format!(" · {} tok", compact(tokens)));
+        if self.cost_usd.is_some() {
+            line.push_str(&format!(" · {}", format_cost(self.cost_usd)));
+        }
+        line
+    }
+}
+
+/// Token counts short enough for a status bar, e.g. 950, 38k, 1.4M
+pub fn compact(tokens: u64) -> String {
+    match tokens {
+        0..=999 => tokens.to_string(),
+        1_000..=999_499 => format!("{}k", (tokens + 500) / 1000),
+        _ => format!("{:.1}M", tokens as f64 / 1_000_000.0),
+    }
+}
+
+/// Whether a terminal described by `$TERM` takes escape sequences
+pub fn supports_title(term: Option<&str>) -> bool {
+    !matches!(term, None | Some("") | Some("dumb"))
+}
+
+/// Keeps the terminal title on the latest status line, at most once per
+/// `TITLE_INTERVAL`, putting the previous title back when done
+#[derive(Debug, Default)]
+pub struct TerminalTitle {
+    shown: Option<String>,
+    updated: Option<Instant>,
+}
+
+impl TerminalTitle {
+    /// Save the current title on the terminal's title stack (xterm's
+    /// `CSI 22 ; 2 t`), for `restore`
+    pub fn save(out: &mut impl Write) -> io::Result<Self> {
+        out.write_all(b"\x1b[22;2t")?;
+        out.flush()?;
+        Ok(Self::default())
+    }
+
+    /// Show `title` with OSC 2, unless it is already shown or the last
+    /// update was too recent
+    pub fn update(&mut self, out: &mut impl Write, title: &str, now: Instant) -> io::Result<()> {
+        if self.shown.as_deref() == Some(title) {
+            return Ok(());
+        }
+        if self
+            .updated
+            .is_some_and(|updated| now.duration_since(updated) < TITLE_INTERVAL)
+        {
+            return Ok(());
+        }
+        // Control characters would end the sequence early
+        let title: String = title.chars().filter(|c| !c.is_control()).collect();
+        write!(out, "\x1b]2;{}\x07", title)?;
+        out.flush()?;
+        self.shown = Some(title);
+        self.updated = Some(now);
+        Ok(())
+    }
+
+    /// Put back the title `save` saved
+    pub fn restore(self, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(b"\x1b[23;2t")?;
+        out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Local, TimeZone};
+
+    fn session(requests: usize, tokens: u64, cost_usd: Option<f64>) -> Status {
+        Status {
+            requests,
+            input_tokens: tokens,
+            output_tokens: 0,
+            cost_usd,
+            ..Status::default()
+        }
+    }
+
+    #[test]
+    fn test_line() {
+        assert_eq!(session(0, 0, None).line(), "sherlock · idle");
+        assert_eq!(session(1, 950, None).line(), "sherlock · 950 tok");
+        let status = session(12, 141_600, Some(3.2));
+        assert_eq!(status.line(), "sherlock · 142k tok · $3.20");
+        assert_eq!(
+            session(3, 999_499, Some(0.004)).line(),
+            "sherlock · 999k tok · $0.0040"
+        );
+        assert_eq!(
+            session(40, 1_420_000, Some(12.5)).line(),
+            "sherlock · 1.4M tok · $12.50"
+        );
+
+        let mut today = session(5, 12_000, None);
+        today.scope = Scope::Today;
+        today.output_tokens = 400;
+        assert_eq!(today.line(), "sherlock today · 12k tok");
+    }
+
+    #[test]
+    fn test_today_from_index() {
+        let entry = |day: u32, tokens, output_tokens| IndexEntry {
+            timestamp: Local
+                .with_ymd_and_hms(2026, 3, day, 12, 0, 0)
+                .unwrap()
+                .with_timezone(&chrono::Utc),
+            id: 1,
+            provider: "anthropic".to_string(),
+            model: None,
+            tokens,
+            output_tokens,
+            served_model: None,
+            service_tier: None,
+            requested_tier: None,
+            cost_usd: None,
+            unpriced: false,
+            no_context_limit: false,
+            status: None,
+            latency_ms: None,
+            error: None,
+            response_ms: None,
+            throughput: None,
+            languages: Default::default(),
+            chaos: None,
+        };
+        let entries = vec![
+            entry(1, Some(5_000), Some(300)),
+            entry(2, Some(2_000), Some(800)),
+            entry(2, None, None),
+        ];
+        let status = Status::today(entries, NaiveDate::from_ymd_opt(2026, 3, 2).unwrap());
+        assert_eq!(status.scope, Scope::Today);
+        assert_eq!(status.requests, 2);
+        assert_eq!(status.input_tokens, 2_000);
+        assert_eq!(status.output_tokens, 800);
+        assert_eq!(status.line(), "sherlock today · 3k tok");
+    }
+
+    #[test]
+    fn test_status_round_trips() {
+        let mut status = session(2, 300, Some(0.5));
+        status.archive = Some(ArchiveSnapshot {
+            backlog: 3,
+            last_error: Some("disk full".to_string()),
+            ..ArchiveSnapshot::default()
+        });
+        let json = serde_json::to_string(&status).unwrap();
+        assert_eq!(serde_json::from_str::<Status>(&json).unwrap(), status);
+    }
+
+    #[test]
+    fn test_terminal_title_is_rate_limited() {
+        let mut out = Vec::new();
+        let mut title = TerminalTitle::save(&mut out).unwrap();
+        let start = Instant::now();
+        title.update(&mut out, "sherlock · idle", start).unwrap();
+        // Unchanged, then too soon
+        title
+            .update(&mut out, "sherlock · idle", start + TITLE_INTERVAL)
+            .unwrap();
+        title.update(&mut out, "sherlock · 1k tok", start).unwrap();
+        title
+            .update(&mut out, "sherlock · 2k\x07 tok", start + TITLE_INTERVAL)
+            .unwrap();
+        title.restore(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\x1b[22;2t\x1b]2;sherlock · idle\x07\x1b]2;sherlock · 2k tok\x07\x1b[23;2t"
+        );
+    }
+
+    #[test]
+    fn test_supports_title() {
+        assert!(supports_title(Some("xterm-256color")));
+        assert!(supports_title(Some("screen")));
+        assert!(!supports_title(Some("dumb")));
+        assert!(!supports_title(Some("")));
+        assert!(!supports_title(None));
+    }
+}
@@ -0,0 +1,199 @@
+use std::ops::Range;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+/// Terminal columns taken by `s`
+pub fn display_width(s: &str) -> usize {
+    s.width()
+}
+
+/// Cut `s` to at most `max_width` columns, ending in "..." when shortened
+pub fn truncate(s: &str, max_width: usize) -> String {
+    if s.width() <= max_width {
+        return s.to_string();
+    }
+    let (head, _) = take_width(s.graphemes(true), max_width.saturating_sub(3));
+    format!("{}...", head.concat())
+}
+
+/// Cut `s` to at most `max_width` columns by replacing its middle with "…",
+/// so both the prefix and the distinguishing suffix of ids like
+/// `anthropic/claude-3.5-sonnet:beta` stay visible
+pub fn truncate_middle(s: &str, max_width: usize) -> String {
+    if s.width() <= max_width {
+        return s.to_string();
+    }
+    if max_width == 0 {
+        return String::new();
+    }
+
+    let keep = max_width - 1;
+    let (head, head_width) = take_width(s.graphemes(true), keep - keep / 2);
+    let (mut tail, _) = take_width(s.graphemes(true).rev(), keep - head_width);
+    tail.reverse();
+    format!("{}…{}", head.concat(), tail.concat())
+}
+
+/// `s` broken into lines of at most `width` columns, after a space where
+/// possible and mid-word otherwise, as the byte range of each line. Newlines
+/// in `s` always break; the spaces and line endings broken at are left out.
+pub fn wrap_ranges(s: &str, width: usize) -> Vec<Range<usize>> {
+    let width = width.max(1);
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    for paragraph in s.split('\n') {
+        let base = offset;
+        offset += paragraph.len() + 1;
+        let paragraph = paragraph.trim_end_matches('\r');
+        // Where the current line starts and ends so far, and its width
+        let (mut start, mut end, mut line_width) = (0, 0, 0);
+        // End and width of the current line up to its last space
+        let mut space = None;
+        // Spaces at a break aren't carried onto the next line
+        let mut at_break = false;
+        let trimmed = |start: usize, end: usize| {
+            base + start..base + start + paragraph[start..end].trim_end().len()
+        };
+        for (i, grapheme) in paragraph.grapheme_indices(true) {
+            let grapheme_width = grapheme.width();
+            let blank = grapheme.trim().is_empty();
+            if blank && at_break {
+                (start, end) = (i + grapheme.len(), i + grapheme.len());
+                continue;
+            }
+            at_break = false;
+            if line_width + grapheme_width > width && end > start {
+                if blank {
+                    lines.push(trimmed(start, end));
+                    (start, end) = (i + grapheme.len(), i + grapheme.len());
+                    (line_width, space, at_break) = (0, None, true);
+                    continue;
+                }
+                match space.take() {
+                    Some((at, at_width)) => {
+                        lines.push(trimmed(start, at));
+                        start = at;
+                        line_width -= at_width;
+                    }
+                    None => {
+                        lines.push(base + start..base + end);
+                        (start, line_width) = (end, 0);
+                    }
+                }
+            }
+            end = i + grapheme.len();
+            line_width += grapheme_width;
+            if blank {
+                space = Some((end, line_width));
+            }
+        }
+        lines.push(base + start..base + end);
+    }
+    lines
+}
+
+/// Leading graphemes that fit in `budget` columns, and the columns they use
+fn take_width<'a>(
+    graphemes: impl Iterator<Item = &'a str>,
+    budget: usize,
+) -> (Vec<&'a str>, usize) {
+    let mut taken = Vec::new();
+    let mut used = 0;
+    for grapheme in graphemes {
+        let width = grapheme.width();
+        if used + width > budget {
+            break;
+        }
+        used += width;
+        taken.push(grapheme);
+    }
+    (taken, used)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate("hello", 10), "hello");
+        assert_eq!(truncate("hello world", 8), "hello...");
+        assert_eq!(truncate("日本語のモデル", 9), "日本語...");
+    }
+
+    #[test]
+    fn test_truncate_middle_slugs() {
+        let cases = [
+            ("claude-3-5-sonnet", 30, "claude-3-5-sonnet"),
+            (
+                "anthropic/claude-3.5-sonnet:beta",
+                20,
+                "anthropic/…nnet:beta",
+            ),
+            (
+                "anthropic/claude-3.5-sonnet:beta",
+                32,
+                "anthropic/claude-3.5-sonnet:beta",
+            ),
+            (
+                "meta-llama/llama-3.1-405b-instruct:free",
+                24,
+                "meta-llama/l…struct:free",
+            ),
+            (
+                "contoso-gpt-4o-2024-08-06-eastus-prod",
+                21,
+                "contoso-gp…astus-prod",
+            ),
+            ("gpt-4o", 1, "…"),
+            ("gpt-4o", 0, ""),
+        ];
+        for (model, width, expected) in cases {
+            let truncated = truncate_middle(model, width);
+            assert_eq!(truncated, expected, "{} at {}", model, width);
+            assert!(display_width(&truncated) <= width);
+        }
+    }
+
+    fn wrap(s: &str, width: usize) -> Vec<&str> {
+        wrap_ranges(s, width).into_iter().map(|range| &s[range]).collect()
+    }
+
+    #[test]
+    fn test_wrap() {
+        assert_eq!(wrap("the quick brown fox", 10), ["the quick", "brown fox"]);
+        assert_eq!(wrap("the quick  brown", 9), ["the quick", "brown"]);
+        assert_eq!(wrap("abcdefghij", 4), ["abcd", "efgh", "ij"]);
+        assert_eq!(
+            wrap("see https://example.com/a/b", 8),
+            ["see", "https://", "example.", "com/a/b"]
+        );
+        assert_eq!(wrap("a\r\n\nb", 5), ["a", "", "b"]);
+        assert_eq!(wrap("日本語のモデル", 5), ["日本", "語の", "モデ", "ル"]);
+        assert_eq!(wrap("", 5), [""]);
+    }
+
+    #[test]
+    fn test_wrap_ranges_multibyte() {
+        let s = "naïve café\r\n日本語の関数名  を変更";
+        let ranges = wrap_ranges(s, 6);
+        let lines: Vec<&str> = ranges.iter().map(|range| &s[range.clone()]).collect();
+        assert_eq!(lines, ["naïve", "café", "日本語", "の関数", "名", "を変更"]);
+        assert_eq!(ranges[1], 7..12);
+        assert_eq!(ranges[2].start, "naïve café\r\n".len());
+        // The two spaces after 名 are where it broke
+        assert_eq!(ranges[5].start, ranges[4].end + 2);
+    }
+
+    #[test]
+    fn test_truncate_middle_wide_and_combined() {
+        // Double-width characters never get split across the budget
+        let truncated = truncate_middle("通义千问-大模型-最新版", 9);
+        assert_eq!(truncated, "通义…新版");
+        assert!(display_width(&truncated) <= 9);
+
+        // Combining accents stay attached to their base letter
+        let model = "mode\u{301}le-spe\u{301}cial-version";
+        assert_eq!(truncate_middle(model, 9), "mode\u{301}…sion");
+    }
+}
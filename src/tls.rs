@@ -0,0 +1,177 @@
+use anyhow::{Context, Result};
+use std::path::Path;
+
+use crate::config::{expand_tilde, TlsConfig};
+
+/// Build the upstream client for one provider, applying its TLS settings
+pub fn build_client(name: &str, tls: Option<&TlsConfig>) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder().pool_max_idle_per_host(10);
+
+    if let Some(tls) = tls {
+        if let Some(path) = &tls.ca_bundle {
+            for cert in load_ca_bundle(path)? {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        if let Some(identity) = load_identity(tls)? {
+            builder = builder.identity(identity);
+        }
+        if tls.insecure_skip_verify {
+            tracing::warn!(
+                "!!! TLS certificate verification is DISABLED for provider {} \
+                 (insecure_skip_verify); upstream traffic can be intercepted !!!",
+                name
+            );
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+    }
+
+    builder
+        .build()
+        .with_context(|| format!("Failed to build HTTP client for provider {}", name))
+}
+
+/// Check that every configured file exists and parses as PEM
+pub fn validate(tls: &TlsConfig) -> Result<()> {
+    if let Some(path) = &tls.ca_bundle {
+        load_ca_bundle(path)?;
+    }
+    load_identity(tls)?;
+    Ok(())
+}
+
+fn load_ca_bundle(path: &Path) -> Result<Vec<reqwest::Certificate>> {
+    let pem = read_pem(path)?;
+    let certs = reqwest::Certificate::from_pem_bundle(&pem)
+        .with_context(|| format!("{:?} is not a valid PEM certificate bundle", path))?;
+    if certs.is_empty() {
+        anyhow::bail!("{:?} contains no certificates", path);
+    }
+    Ok(certs)
+}
+
+/// Client certificate and key, which must be configured together
+fn load_identity(tls: &TlsConfig) -> Result<Option<reqwest::Identity>> {
+    let (cert, key) = match (&tls.client_cert, &tls.client_key) {
+        (None, None) => return Ok(None),
+        (Some(cert), Some(key)) => (cert, key),
+        _ => anyhow::bail!("client_cert and client_key must be set together"),
+    };
+    let mut pem = read_pem(cert)?;
+    pem.push(b'\n');
+    pem.extend(read_pem(key)?);
+    let identity = reqwest::Identity::from_pem(&pem).with_context(|| {
+        format!("{:?} and {:?} are not a valid PEM certificate and key", cert, key)
+    })?;
+    Ok(Some(identity))
+}
+
+fn read_pem(path: &Path) -> Result<Vec<u8>> {
+    let path = expand_tilde(path);
+    let pem = std::fs::read(&path).with_context(|| format!("Failed to read {:?}", path))?;
+    if !pem.windows(11).any(|w| w == b"-----BEGIN ") {
+        anyhow::bail!("{:?} is not PEM encoded", path);
+    }
+    Ok(pem)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use tokio_rustls::rustls;
+    use tokio_rustls::rustls::pki_types::pem::PemObject;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+    fn fixture(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tls").join(name)
+    }
+
+    /// HTTPS server presenting the fixture certificate signed by `ca.pem`
+    async fn tls_server() -> String {
+        let certs = CertificateDer::pem_file_iter(fixture("server.pem"))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let key = PrivateKeyDer::from_pem_file(fixture("server.key")).unwrap();
+        let config = rustls::ServerConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(stream) = acceptor.accept(stream).await else {
+                        return;
+                    };
+                    let service = hyper::service::service_fn(|_req| async {
+                        Ok::<_, hyper::Error>(hyper::Response::new(http_body_util::Full::new(
+                            bytes::Bytes::from_static(b"ok"),
+                        )))
+                    });
+                    let _ = hyper::server::conn::http1::Builder::new()
+                        .serve_connection(hyper_util::rt::TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+        format!("https://localhost:{}", port)
+    }
+
+    #[tokio::test]
+    async fn test_custom_ca_bundle() {
+        let url = tls_server().await;
+
+        let tls = TlsConfig {
+            ca_bundle: Some(fixture("ca.pem")),
+            ..TlsConfig::default()
+        };
+        let client = build_client("corp", Some(&tls)).unwrap();
+        let resp = client.get(&url).send().await.unwrap();
+        assert_eq!(resp.text().await.unwrap(), "ok");
+
+        // The default trust store doesn't know the private CA
+        let client = build_client("corp", None).unwrap();
+        let err = client.get(&url).send().await.unwrap_err();
+        assert!(err.is_connect(), "{:?}", err);
+    }
+
+    #[test]
+    fn test_validate() {
+        let valid = TlsConfig {
+            ca_bundle: Some(fixture("ca.pem")),
+            client_cert: Some(fixture("client.pem")),
+            client_key: Some(fixture("client.key")),
+            insecure_skip_verify: false,
+        };
+        validate(&valid).unwrap();
+
+        let missing = TlsConfig {
+            ca_bundle: Some(fixture("missing.pem")),
+            ..TlsConfig::default()
+        };
+        assert!(validate(&missing).is_err());
+
+        let not_pem = TlsConfig {
+            ca_bundle: Some(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml")),
+            ..TlsConfig::default()
+        };
+        assert!(validate(&not_pem).is_err());
+
+        let cert_only = TlsConfig {
+            client_cert: Some(fixture("client.pem")),
+            ..TlsConfig::default()
+        };
+        assert!(validate(&cert_only).is_err());
+    }
+}
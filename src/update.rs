@@ -0,0 +1,243 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::Duration;
+
+/// Version of this build
+pub const CURRENT: &str = env!("CARGO_PKG_VERSION");
+
+pub const CACHE_FILE: &str = "update_check.json";
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What `update_check.json` remembers between runs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct CachedCheck {
+    checked_at: DateTime<Utc>,
+    /// Latest release seen; kept from an earlier check when a fetch fails
+    latest: Option<String>,
+}
+
+/// Newest release when it's newer than this build. Fetches `url` at most
+/// once a day and otherwise answers from `dir/update_check.json`; a failed
+/// fetch is logged and never an error.
+pub async fn check(dir: &Path, url: &str) -> Option<String> {
+    check_version(dir, url, CURRENT, Utc::now()).await
+}
+
+async fn check_version(dir: &Path, url: &str, current: &str, now: DateTime<Utc>) -> Option<String> {
+    let path = dir.join(CACHE_FILE);
+    let cached = read_cache(&path);
+    let latest = match cached {
+        Some(cached) if now - cached.checked_at < TimeDelta::days(1) => cached.latest,
+        previous => {
+            let latest = match fetch_latest(url).await {
+                Ok(latest) => Some(latest),
+                Err(e) => {
+                    tracing::debug!("Update check failed: {:#}", e);
+                    previous.and_then(|cached| cached.latest)
+                }
+            };
+            let cached = CachedCheck {
+                checked_at: now,
+                latest: latest.clone(),
+            };
+            if let Err(e) = write_cache(dir, &cached) {
+                tracing::debug!("Failed to save update check: {:#}", e);
+            }
+            latest
+        }
+    };
+    latest.filter(|latest| is_newer(latest, current))
+}
+
+/// `sherlock --version` text, e.g. "0.4.2 — 0.6.0 available", from the
+/// last update check only so it never waits on the network
+pub fn version_line(dir: Option<&Path>) -> String {
+    let latest = dir
+        .and_then(|dir| read_cache(&dir.join(CACHE_FILE)))
+        .and_then(|cached| cached.latest)
+        .filter(|latest| is_newer(latest, CURRENT));
+    match latest {
+        Some(latest) => format!("{} — {} available", CURRENT, latest),
+        None => CURRENT.to_string(),
+    }
+}
+
+async fn fetch_latest(url: &str) -> Result<String> {
+    let body = reqwest::Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .user_agent(format!("sherlock/{}", CURRENT))
+        .build()?
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    parse_release(&body).with_context(|| format!("No version in response from {}", url))
+}
+
+/// Version from a plain text body like `v0.6.0`, or a JSON one with a
+/// `tag_name` (GitHub releases) or `version` field
+fn parse_release(body: &str) -> Option<String> {
+    let body = body.trim();
+    let version = if body.starts_with('{') {
+        let json: serde_json::Value = serde_json::from_str(body).ok()?;
+        ["tag_name", "version"]
+            .iter()
+            .find_map(|field| json.get(field)?.as_str())?
+            .trim()
+            .to_string()
+    } else {
+        body.lines().next()?.trim().to_string()
+    };
+    let version = version.strip_prefix('v').unwrap_or(&version);
+    parse_version(version).map(|_| version.to_string())
+}
+
+/// Numeric components of `1.2.3`, ignoring a `-beta` or `+build` suffix
+fn parse_version(version: &str) -> Option<[u64; 3]> {
+    let version = version.strip_prefix('v').unwrap_or(version);
+    let core = version.split(['-', '+']).next()?;
+    let mut parts = [0; 3];
+    for (i, part) in core.split('.').enumerate() {
+        *parts.get_mut(i)? = part.parse().ok()?;
+    }
+    Some(parts)
+}
+
+fn is_newer(latest: &str, current: &str) -> bool {
+    match (parse_version(latest), parse_version(current)) {
+        (Some(latest), Some(current)) => latest > current,
+        _ => false,
+    }
+}
+
+fn read_cache(path: &Path) -> Option<CachedCheck> {
+    serde_json::from_str(&std::fs::read_to_string(path).ok()?).ok()
+}
+
+fn write_cache(dir: &Path, cached: &CachedCheck) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+    std::fs::write(dir.join(CACHE_FILE), serde_json::to_string(cached)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("sherlock-update-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// Release endpoint answering every request with `body`, counting hits
+    fn mock_release(body: &'static str) -> (String, Arc<AtomicUsize>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/latest", listener.local_addr().unwrap());
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&hits);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let _ = stream.read(&mut [0u8; 4096]);
+                counter.fetch_add(1, Ordering::SeqCst);
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+            }
+        });
+        (url, hits)
+    }
+
+    #[tokio::test]
+    async fn test_checks_at_most_daily() {
+        let dir = temp_dir("daily");
+        let (url, hits) = mock_release(r#"{"tag_name": "v0.6.0", "name": "Sherlock 0.6"}"#);
+        let now = Utc::now();
+
+        let latest = check_version(&dir, &url, "0.4.2", now).await;
+        assert_eq!(latest.as_deref(), Some("0.6.0"));
+        // Answered from the cache within the day, fetched again after it
+        let later = now + TimeDelta::hours(23);
+        assert_eq!(check_version(&dir, &url, "0.4.2", later).await, latest);
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        let tomorrow = now + TimeDelta::hours(25);
+        assert_eq!(check_version(&dir, &url, "0.4.2", tomorrow).await, latest);
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+        // Up to date builds get no notice
+        assert_eq!(check_version(&dir, &url, "0.6.0", tomorrow).await, None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_network_failure() {
+        let dir = temp_dir("offline");
+        let (url, _) = mock_release("0.6.0\n");
+        let now = Utc::now();
+        assert_eq!(
+            check_version(&dir, &url, "0.4.2", now).await.as_deref(),
+            Some("0.6.0")
+        );
+
+        // Nothing listens on the port a dropped listener had
+        let closed = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}/latest", listener.local_addr().unwrap())
+        };
+        let tomorrow = now + TimeDelta::days(2);
+        assert_eq!(
+            check_version(&dir, &closed, "0.4.2", tomorrow)
+                .await
+                .as_deref(),
+            Some("0.6.0")
+        );
+        let cached = read_cache(&dir.join(CACHE_FILE)).unwrap();
+        assert_eq!(cached.checked_at, tomorrow);
+
+        let fresh = temp_dir("offline-fresh");
+        assert_eq!(check_version(&fresh, &closed, "0.4.2", now).await, None);
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_dir_all(&fresh).unwrap();
+    }
+
+    #[test]
+    fn test_versions() {
+        assert_eq!(parse_release("v0.6.0\n").as_deref(), Some("0.6.0"));
+        assert_eq!(
+            parse_release(r#"{"version": "1.2"}"#).as_deref(),
+            Some("1.2")
+        );
+        assert_eq!(parse_release("<html>rate limited</html>"), None);
+        assert!(is_newer("0.10.0", "0.9.3"));
+        assert!(is_newer("1.0.0", "0.9.9-beta"));
+        assert!(!is_newer("0.4.2", "0.4.2"));
+        assert!(!is_newer("0.4.1", "0.4.2"));
+        assert!(!is_newer("garbage", "0.4.2"));
+
+        let dir = temp_dir("version-line");
+        assert_eq!(version_line(Some(&dir)), CURRENT);
+        let cached = CachedCheck {
+            checked_at: Utc::now(),
+            latest: Some("999.0.0".to_string()),
+        };
+        write_cache(&dir, &cached).unwrap();
+        assert_eq!(
+            version_line(Some(&dir)),
+            format!("{} — 999.0.0 available", CURRENT)
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
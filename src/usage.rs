@@ -0,0 +1,381 @@
+use serde_json::Value;
+
+use crate::context::{self, ContextOverflow};
+use crate::event::{GeminiUsage, OutputTokens};
+use crate::parser::{count_tokens, MAX_PARSE_BODY_BYTES};
+use crate::sse::SseParser;
+
+/// Read-only observer of a relayed response that works out how many tokens
+/// the upstream generated, and which model it says generated them.
+///
+/// The provider's own `usage` block wins: Anthropic `output_tokens`, OpenAI
+/// `completion_tokens` and Gemini `usageMetadata`. Streams report it
+/// cumulatively, so the latest report counts. Without one, the response text
+/// is tokenized instead.
+#[derive(Debug)]
+pub struct UsageTap {
+    /// Set for event streams; JSON bodies are buffered and parsed at the end
+    parser: Option<SseParser>,
+    body: Vec<u8>,
+    /// Bytes seen past what is kept, after which the body isn't parsed
+    overflowed: bool,
+    reported: Option<u64>,
+    text: String,
+    model: Option<String>,
+    service_tier: Option<String>,
+    gemini: Option<GeminiUsage>,
+}
+
+/// What a finished response reported about itself
+#[derive(Debug, Default, PartialEq)]
+pub struct ResponseUsage {
+    /// Unset when the response carried neither usage nor text (error bodies,
+    /// unparseable or oversized responses)
+    pub output: Option<OutputTokens>,
+    /// The response's own `model` (`modelVersion` for Gemini)
+    pub model: Option<String>,
+    /// Service tier the response says it was processed on
+    pub service_tier: Option<String>,
+    /// Generated text, tool call arguments included, for searching responses
+    pub text: Option<String>,
+    /// Gemini's `usageMetadata`, every count in it
+    pub gemini: Option<GeminiUsage>,
+}
+
+/// Whether a response is read as an event stream. Its content type says
+/// what the upstream sent, so a stream request answered with a JSON error is
+/// read as JSON; the request's `stream` flag only decides when the upstream
+/// labels the body as neither.
+pub fn is_event_stream(requested: bool, content_type: Option<&str>) -> bool {
+    let content_type = content_type.map(str::to_ascii_lowercase);
+    match content_type.as_deref() {
+        Some(ct) if ct.starts_with("text/event-stream") => true,
+        Some(ct) if ct.contains("json") => false,
+        _ => requested,
+    }
+}
+
+impl UsageTap {
+    pub fn new(is_event_stream: bool) -> Self {
+        Self {
+            parser: is_event_stream.then(SseParser::default),
+            body: Vec::new(),
+            overflowed: false,
+            reported: None,
+            text: String::new(),
+            model: None,
+            service_tier: None,
+            gemini: None,
+        }
+    }
+
+    pub fn observe(&mut self, chunk: &[u8]) {
+        match self.parser.as_mut() {
+            Some(parser) => {
+                for event in parser.feed(chunk) {
+                    if let Ok(data) = serde_json::from_str::<Value>(&event.data) {
+                        self.apply(&data);
+                    }
+                }
+            }
+            None if self.body.len() + chunk.len() > MAX_PARSE_BODY_BYTES => {
+                self.overflowed = true;
+                self.body = Vec::new();
+            }
+            None if !self.overflowed => self.body.extend_from_slice(chunk),
+            None => {}
+        }
+    }
+
+    /// The provider's context length error, for JSON error bodies, which
+    /// are buffered here anyway
+    pub fn context_error(&self) -> Option<ContextOverflow> {
+        if self.parser.is_some() {
+            return None;
+        }
+        context::parse_error(&serde_json::from_slice(&self.body).ok()?)
+    }
+
+    /// Output tokens and model of the whole response
+    pub fn finish(mut self) -> ResponseUsage {
+        if self.parser.is_none() {
+            let body = std::mem::take(&mut self.body);
+            match serde_json::from_slice::<Value>(&body) {
+                // Gemini's non-SSE stream is one array of chunks
+                Ok(Value::Array(chunks)) => chunks.iter().for_each(|chunk| self.apply(chunk)),
+                Ok(value) => self.apply(&value),
+                Err(_) => return ResponseUsage::default(),
+            }
+        }
+        let output = match self.reported {
+            Some(tokens) => Some(OutputTokens {
+                tokens,
+                estimated: false,
+            }),
+            None if self.text.is_empty() => None,
+            None => Some(OutputTokens {
+                tokens: count_tokens(&self.text) as u64,
+                estimated: true,
+            }),
+        };
+        ResponseUsage {
+            output,
+            model: self.model,
+            service_tier: self.service_tier,
+            text: (!self.text.is_empty()).then_some(self.text),
+            gemini: self.gemini,
+        }
+    }
+
+    /// Take usage and text from one response body or stream event
+    fn apply(&mut self, value: &Value) {
+        if self.model.is_none() {
+            self.model = served_model(value);
+        }
+        if self.service_tier.is_none() {
+            self.service_tier = served_tier(value);
+        }
+        if let Some(tokens) = reported_tokens(value) {
+            self.reported = Some(tokens);
+        }
+        if let Some(metadata) = value.get("usageMetadata") {
+            let usage = self.gemini.get_or_insert_with(GeminiUsage::default);
+            merge_gemini_usage(usage, metadata);
+        }
+        if self.text.len() < MAX_PARSE_BODY_BYTES {
+            response_text(value, &mut self.text);
+        }
+    }
+}
+
+/// Output tokens in a provider's usage block, if `value` has one
+fn reported_tokens(value: &Value) -> Option<u64> {
+    // Anthropic repeats the usage of `message_start` in the final `message_delta`
+    let usage = value
+        .get("usage")
+        .or_else(|| value.get("message").and_then(|message| message.get("usage")));
+    if let Some(tokens) = usage.and_then(|usage| {
+        ["output_tokens", "completion_tokens"]
+            .iter()
+            .find_map(|field| usage.get(field).and_then(Value::as_u64))
+    }) {
+        return Some(tokens);
+    }
+    let metadata = value.get("usageMetadata")?;
+    let candidates = metadata.get("candidatesTokenCount").and_then(Value::as_u64);
+    let thoughts = metadata.get("thoughtsTokenCount").and_then(Value::as_u64);
+    candidates
+        .or(thoughts)
+        .map(|_| candidates.unwrap_or(0) + thoughts.unwrap_or(0))
+}
+
+/// Raise each count in `usage` to the one `metadata` reports, if larger
+fn merge_gemini_usage(usage: &mut GeminiUsage, metadata: &Value) {
+    let counts = [
+        ("promptTokenCount", &mut usage.prompt),
+        ("cachedContentTokenCount", &mut usage.cached),
+        ("candidatesTokenCount", &mut usage.candidates),
+        ("thoughtsTokenCount", &mut usage.thoughts),
+        ("toolUsePromptTokenCount", &mut usage.tool_use_prompt),
+        ("totalTokenCount", &mut usage.total),
+    ];
+    for (field, count) in counts {
+        if let Some(reported) = metadata.get(field).and_then(Value::as_u64) {
+            *count = (*count).max(reported);
+        }
+    }
+}
+
+/// Model named by a response body or stream event; Anthropic streams name it
+/// in `message_start` only
+fn served_model(value: &Value) -> Option<String> {
+    ["/model", "/message/model", "/modelVersion"]
+        .iter()
+        .find_map(|pointer| value.pointer(pointer).and_then(Value::as_str))
+        .filter(|model| !model.is_empty())
+        .map(str::to_string)
+}
+
+/// Service tier reported by a response body or stream event: in Anthropic's
+/// usage block, at the top level for OpenAI
+fn served_tier(value: &Value) -> Option<String> {
+    let pointers = ["/usage/service_tier", "/message/usage/service_tier", "/service_tier"];
+    pointers
+        .iter()
+        .find_map(|pointer| value.pointer(pointer).and_then(Value::as_str))
+        .filter(|tier| !tier.is_empty())
+        .map(str::to_string)
+}
+
+/// Append the generated text in a response body or stream event to `out`
+fn response_text(value: &Value, out: &mut String) {
+    let mut push = |text: Option<&str>| {
+        if let Some(text) = text.filter(|text| !text.is_empty()) {
+            out.push_str(text);
+            out.push('\n');
+        }
+    };
+    // Anthropic messages and their stream deltas
+    if let Some(blocks) = value.get("content").and_then(Value::as_array) {
+        for block in blocks {
+            for field in ["text", "thinking"] {
+                push(block.get(field).and_then(Value::as_str));
+            }
+            if let Some(input) = block.get("input") {
+                push(Some(&input.to_string()));
+            }
+        }
+    }
+    if let Some(delta) = value.get("delta") {
+        for field in ["text", "partial_json", "thinking"] {
+            push(delta.get(field).and_then(Value::as_str));
+        }
+    }
+    // OpenAI chat completions, whole or streamed
+    for choice in value.get("choices").and_then(Value::as_array).into_iter().flatten() {
+        let Some(message) = choice.get("message").or_else(|| choice.get("delta")) else {
+            continue;
+        };
+        push(message.get("content").and_then(Value::as_str));
+        for call in message.get("tool_calls").and_then(Value::as_array).into_iter().flatten() {
+            push(call.pointer("/function/arguments").and_then(Value::as_str));
+        }
+    }
+    // Gemini candidates
+    for candidate in value.get("candidates").and_then(Value::as_array).into_iter().flatten() {
+        let parts = candidate.pointer("/content/parts").and_then(Value::as_array);
+        for part in parts.into_iter().flatten() {
+            push(part.get("text").and_then(Value::as_str));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn finish(is_event_stream: bool, body: &str, chunk_size: usize) -> ResponseUsage {
+        let mut tap = UsageTap::new(is_event_stream);
+        for chunk in body.as_bytes().chunks(chunk_size) {
+            tap.observe(chunk);
+        }
+        tap.finish()
+    }
+
+    fn observe(is_event_stream: bool, body: &str, chunk_size: usize) -> Option<OutputTokens> {
+        finish(is_event_stream, body, chunk_size).output
+    }
+
+    fn reported(tokens: u64) -> Option<OutputTokens> {
+        Some(OutputTokens {
+            tokens,
+            estimated: false,
+        })
+    }
+
+    #[test]
+    fn test_stream_flag_and_content_type() {
+        let cases = [
+            (true, Some("text/event-stream; charset=utf-8"), true),
+            (false, Some("text/event-stream"), true),
+            (true, Some("application/json"), false),
+            (true, Some("Application/JSON; charset=utf-8"), false),
+            (true, None, true),
+            (true, Some("text/plain"), true),
+            (false, None, false),
+        ];
+        for (requested, content_type, expected) in cases {
+            assert_eq!(
+                is_event_stream(requested, content_type),
+                expected,
+                "{} {:?}",
+                requested,
+                content_type
+            );
+        }
+
+        // A stream was asked for, but the upstream rejected it with JSON
+        let error = r#"{"type":"error","error":{"type":"invalid_request_error",
+            "message":"prompt is too long: 212345 tokens > 200000 maximum"}}"#;
+        let mut tap = UsageTap::new(is_event_stream(true, Some("application/json")));
+        tap.observe(error.as_bytes());
+        let overflow = tap.context_error().unwrap();
+        assert_eq!((overflow.tokens, overflow.limit), (Some(212345), Some(200000)));
+        assert_eq!(tap.finish(), ResponseUsage::default());
+
+        // An unlabeled stream is still read as one when it was asked for
+        let stream = "event: message_delta\n\
+            data: {\"type\":\"message_delta\",\"usage\":{\"output_tokens\":5}}\n\n";
+        assert_eq!(observe(is_event_stream(true, None), stream, 8), reported(5));
+    }
+
+    #[test]
+    fn test_reported_usage_per_provider() {
+        let anthropic = r#"{"model":"claude-3-5-sonnet-20241022",
+            "content":[{"type":"text","text":"Hi"}],
+            "usage":{"input_tokens":12,"output_tokens":7}}"#;
+        let openai = r#"{"model":"gpt-4o-2024-08-06","choices":[{"message":{"content":"Hi"}}],
+            "usage":{"prompt_tokens":12,"completion_tokens":9}}"#;
+        let gemini = r#"[{"candidates":[{"content":{"parts":[{"text":"Hi"}]}}],
+            "usageMetadata":{"promptTokenCount":12,"candidatesTokenCount":4},
+            "modelVersion":"gemini-1.5-pro-002"},
+            {"candidates":[{"content":{"parts":[{"text":" there"}]}}],
+            "usageMetadata":{"candidatesTokenCount":6,"thoughtsTokenCount":20}}]"#;
+        assert_eq!(observe(false, anthropic, 5), reported(7));
+        assert_eq!(observe(false, openai, 1000), reported(9));
+        assert_eq!(observe(false, gemini, 3), reported(26));
+        // Each usageMetadata count is the largest any chunk reported
+        let usage = finish(false, gemini, 3).gemini.unwrap();
+        assert_eq!(
+            (usage.prompt, usage.candidates, usage.thoughts),
+            (12, 6, 20)
+        );
+        for (body, model) in [
+            (anthropic, "claude-3-5-sonnet-20241022"),
+            (openai, "gpt-4o-2024-08-06"),
+            (gemini, "gemini-1.5-pro-002"),
+        ] {
+            assert_eq!(finish(false, body, 64).model.as_deref(), Some(model));
+        }
+
+        // The final message_delta carries the total
+        let stream = concat!(
+            "event: message_start\n",
+            "data: {\"type\":\"message_start\",\"message\":",
+            "{\"model\":\"claude-3-5-haiku-20241022\",",
+            "\"usage\":{\"output_tokens\":1}}}\n\n",
+            "event: content_block_delta\n",
+            "data: {\"type\":\"content_block_delta\",\"index\":0,",
+            "\"delta\":{\"type\":\"text_delta\",\"text\":\"Hello\"}}\n\n",
+            "event: message_delta\n",
+            "data: {\"type\":\"message_delta\",\"usage\":{\"output_tokens\":42}}\n\n",
+        );
+        for chunk_size in [1, 7, stream.len()] {
+            let usage = finish(true, stream, chunk_size);
+            assert_eq!(usage.output, reported(42));
+            assert_eq!(usage.model.as_deref(), Some("claude-3-5-haiku-20241022"));
+            assert_eq!(usage.text.as_deref(), Some("Hello\n"));
+        }
+    }
+
+    #[test]
+    fn test_counted_without_usage() {
+        // OpenAI streams only report usage when the client asks for it
+        let stream = concat!(
+            "data: {\"choices\":[{\"delta\":{\"content\":\"The quick brown fox \"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"jumps over the lazy dog\"}}]}\n\n",
+            "data: [DONE]\n\n",
+        );
+        let output = observe(true, stream, 10).unwrap();
+        assert!(output.estimated);
+        assert_eq!(
+            output.tokens,
+            count_tokens("The quick brown fox \njumps over the lazy dog\n") as u64
+        );
+
+        // Error bodies and responses that aren't JSON count nothing
+        let error = r#"{"type":"error","error":{"type":"overloaded_error","message":"x"}}"#;
+        assert_eq!(observe(false, error, 8), None);
+        assert_eq!(observe(false, "<html>bad gateway</html>", 8), None);
+    }
+}
@@ -0,0 +1,269 @@
+//! High-water marks of context use: the largest single request per
+//! conversation, over the session and over the dashboard's lifetime. The
+//! gauge shows where a session stands now; these show how close it came.
+
+use serde::Serialize;
+use std::fmt;
+
+use crate::caching::{conversation, RecentConversations};
+use crate::context::context_limit;
+use crate::event::RequestEvent;
+use crate::statusline::compact;
+
+/// The largest request seen, and the context window of its model
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Peak {
+    pub tokens: u64,
+    pub model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u64>,
+}
+
+impl Peak {
+    pub fn of(event: &RequestEvent) -> Self {
+        Self::new(event.tokens as u64, &event.model)
+    }
+
+    pub fn new(tokens: u64, model: &str) -> Self {
+        Self {
+            tokens,
+            model: model.to_string(),
+            limit: context_limit(model),
+        }
+    }
+
+    /// Percent of the context window taken, when the model's window is known
+    pub fn percent(&self) -> Option<f64> {
+        Some(self.tokens as f64 / self.limit? as f64 * 100.0)
+    }
+}
+
+/// e.g. "peak 187k / 200k", or "peak 187k" for a model of unknown window
+impl fmt::Display for Peak {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "peak {}", compact(self.tokens))?;
+        if let Some(limit) = self.limit {
+            write!(f, " / {}", compact(limit))?;
+        }
+        Ok(())
+    }
+}
+
+/// A conversation's new peak reaching an alert threshold
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeakAlert {
+    /// The conversation's opening user message, shortened
+    pub conversation: String,
+    pub peak: Peak,
+    /// Percent of the context window crossed
+    pub threshold: u8,
+}
+
+/// e.g. "\"fix the parser\" reached 90% of claude-sonnet-4-5's context (peak 187k / 200k)"
+impl fmt::Display for PeakAlert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "\"{}\" reached {}% of {}'s context ({})",
+            self.conversation, self.threshold, self.peak.model, self.peak
+        )
+    }
+}
+
+/// Peaks per conversation and per session, and the lifetime peak that
+/// outlasts `reset_session`
+#[derive(Debug, Default)]
+pub struct Watermarks {
+    /// Percents of the context window that raise an alert, lowest first
+    thresholds: Vec<u8>,
+    conversations: RecentConversations<ConversationPeak>,
+    session: Option<Peak>,
+    lifetime: Option<Peak>,
+}
+
+#[derive(Debug)]
+struct ConversationPeak {
+    label: String,
+    peak: Peak,
+    /// Highest threshold already alerted on, 0 for none
+    alerted: u8,
+}
+
+impl Watermarks {
+    pub fn new(thresholds: &[u8]) -> Self {
+        let mut thresholds = thresholds.to_vec();
+        thresholds.sort_unstable();
+        thresholds.dedup();
+        Self {
+            thresholds,
+            ..Self::default()
+        }
+    }
+
+    /// Record `event`'s size, returning an alert when it is a new peak for
+    /// its conversation that crosses a threshold not yet alerted on. A jump
+    /// over several thresholds alerts once, on the highest.
+    pub fn observe(&mut self, event: &RequestEvent) -> Option<PeakAlert> {
+        if event.imported || event.self_test {
+            return None;
+        }
+        let peak = Peak::of(event);
+        raise(&mut self.session, &peak);
+        raise(&mut self.lifetime, &peak);
+
+        let (key, label) = conversation(event)?;
+        let entry = self
+            .conversations
+            .get_or_insert_with(key, || ConversationPeak {
+                label,
+                peak: Peak::new(0, &event.model),
+                alerted: 0,
+            });
+        let mut alert = None;
+        if peak.tokens > entry.peak.tokens {
+            entry.peak = peak;
+            if let Some(threshold) = crossed(&self.thresholds, &entry.peak) {
+                if threshold > entry.alerted {
+                    entry.alerted = threshold;
+                    alert = Some(PeakAlert {
+                        conversation: entry.label.clone(),
+                        peak: entry.peak.clone(),
+                        threshold,
+                    });
+                }
+            }
+        }
+        alert
+    }
+
+    /// Start a new session, keeping the lifetime peak
+    pub fn reset_session(&mut self) {
+        self.conversations.clear();
+        self.session = None;
+    }
+
+    pub fn session(&self) -> Option<&Peak> {
+        self.session.as_ref()
+    }
+
+    pub fn lifetime(&self) -> Option<&Peak> {
+        self.lifetime.as_ref()
+    }
+
+    /// Peak of the conversation `event` belongs to, if it was seen
+    pub fn conversation(&self, event: &RequestEvent) -> Option<&Peak> {
+        let (key, _) = conversation(event)?;
+        self.conversations.get(key).map(|c| &c.peak)
+    }
+}
+
+/// Keep whichever of `peak` and `other` is larger, the earlier on a tie
+fn raise(peak: &mut Option<Peak>, other: &Peak) {
+    if peak.as_ref().is_none_or(|peak| other.tokens > peak.tokens) {
+        *peak = Some(other.clone());
+    }
+}
+
+/// The highest of `thresholds` that `peak` reaches, counting one exactly at it
+fn crossed(thresholds: &[u8], peak: &Peak) -> Option<u8> {
+    let limit = peak.limit?;
+    thresholds
+        .iter()
+        .rev()
+        .find(|threshold| peak.tokens * 100 >= limit * **threshold as u64)
+        .copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_request;
+
+    /// A request opening with `first`, padded to `tokens` tokens
+    fn event(model: &str, first: &str, tokens: usize) -> RequestEvent {
+        let body = serde_json::json!({
+            "model": model,
+            "messages": [{"role": "user", "content": first}]
+        });
+        let mut event =
+            parse_request(body.to_string().as_bytes(), "/v1/messages", "anthropic").unwrap();
+        event.tokens = tokens;
+        event
+    }
+
+    #[test]
+    fn test_thresholds_alert_once_per_conversation() {
+        let mut marks = Watermarks::new(&[90, 80]);
+        let model = "claude-sonnet-4-5";
+
+        assert_eq!(marks.observe(&event(model, "fix it", 150_000)), None);
+        // Exactly at the boundary counts
+        let alert = marks.observe(&event(model, "fix it", 160_000)).unwrap();
+        assert_eq!(alert.threshold, 80);
+        assert_eq!(
+            alert.to_string(),
+            "\"fix it\" reached 80% of claude-sonnet-4-5's context (peak 160k / 200k)"
+        );
+        // Shrinking and growing back under the peak is no new peak
+        assert_eq!(marks.observe(&event(model, "fix it", 40_000)), None);
+        assert_eq!(marks.observe(&event(model, "fix it", 160_000)), None);
+        assert_eq!(marks.observe(&event(model, "fix it", 170_000)), None);
+        // One short of the next threshold, then on it
+        assert_eq!(marks.observe(&event(model, "fix it", 179_999)), None);
+        let alert = marks.observe(&event(model, "fix it", 180_000)).unwrap();
+        assert_eq!(alert.threshold, 90);
+        assert_eq!(marks.observe(&event(model, "fix it", 199_000)), None);
+
+        // A jump past both alerts once, on the higher
+        let alert = marks.observe(&event(model, "other", 185_000)).unwrap();
+        assert_eq!(
+            (alert.conversation.as_str(), alert.threshold),
+            ("other", 90)
+        );
+        assert_eq!(marks.observe(&event(model, "other", 190_000)), None);
+    }
+
+    #[test]
+    fn test_conversations_with_different_limits() {
+        let mut marks = Watermarks::new(&[50]);
+        let small = event("gpt-4-0613", "small", 4_096);
+        let large = event("gpt-4o", "large", 100_000);
+        let unknown = event("llama3", "local", 900_000);
+
+        let alert = marks.observe(&small).unwrap();
+        assert_eq!(alert.peak.limit, Some(8_192));
+        assert_eq!(alert.peak.percent(), Some(50.0));
+        let alert = marks.observe(&large).unwrap();
+        assert_eq!(alert.peak.to_string(), "peak 100k / 128k");
+        // No window to measure against, so no alert, though it sets the peak
+        assert_eq!(marks.observe(&unknown), None);
+
+        assert_eq!(marks.conversation(&small).unwrap().tokens, 4_096);
+        assert_eq!(marks.conversation(&large).unwrap().tokens, 100_000);
+        assert_eq!(marks.session().unwrap().to_string(), "peak 900k");
+        assert_eq!(marks.session(), marks.lifetime());
+    }
+
+    #[test]
+    fn test_reset_keeps_lifetime_peak() {
+        let mut marks = Watermarks::new(&[80]);
+        let model = "claude-sonnet-4-5";
+        assert!(marks.observe(&event(model, "before", 190_000)).is_some());
+
+        marks.reset_session();
+        assert_eq!(marks.session(), None);
+        assert_eq!(marks.lifetime().unwrap().tokens, 190_000);
+        assert_eq!(marks.conversation(&event(model, "before", 0)), None);
+
+        // The conversation starts over, alerts included
+        assert!(marks.observe(&event(model, "before", 170_000)).is_some());
+        assert_eq!(marks.session().unwrap().tokens, 170_000);
+        assert_eq!(marks.lifetime().unwrap().tokens, 190_000);
+
+        // Imported history is no part of the session
+        let mut imported = event(model, "old", 199_000);
+        imported.imported = true;
+        assert_eq!(marks.observe(&imported), None);
+        assert_eq!(marks.session().unwrap().tokens, 170_000);
+    }
+}
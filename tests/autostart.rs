@@ -0,0 +1,101 @@
+//! Tools `sherlock start` launches from the `autostart` config
+#![cfg(unix)]
+
+mod common;
+
+use std::path::Path;
+use std::process::ExitStatus;
+
+use sherlock::config::AutostartTool;
+
+use common::Instance;
+
+impl Instance {
+    fn tool_log(&self, tag: &str) -> String {
+        let path = self.dir.join(format!("home/.sherlock/logs/{}.log", tag));
+        std::fs::read_to_string(path).unwrap_or_default()
+    }
+
+    /// The pid the sleeping tool wrote, once it has
+    async fn tool_pid(&self) -> libc::pid_t {
+        let path = self.dir.join("pid");
+        self.wait_for(|| std::fs::read_to_string(&path).is_ok_and(|pid| pid.ends_with('\n')))
+            .await;
+        std::fs::read_to_string(&path)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap()
+    }
+
+    fn quit(&mut self) -> ExitStatus {
+        let pid = self.child.id() as libc::pid_t;
+        assert_eq!(unsafe { libc::kill(pid, libc::SIGTERM) }, 0);
+        self.child.wait().unwrap()
+    }
+}
+
+/// A dummy tool: `sh -c script`, run in `dir`
+fn script(tag: &str, dir: &Path, script: &str) -> AutostartTool {
+    AutostartTool {
+        provider: "anthropic".to_string(),
+        command: "sh".to_string(),
+        args: vec!["-c".to_string(), script.to_string()],
+        cwd: Some(dir.to_path_buf()),
+        tag: Some(tag.to_string()),
+        restarts: 0,
+    }
+}
+
+/// Prints its base URL, leaves its pid in `pid`, then waits to be stopped
+const SLEEPER: &str = r#"echo "base url $ANTHROPIC_BASE_URL"; echo $$ > pid; exec sleep 60"#;
+
+fn alive(pid: libc::pid_t) -> bool {
+    unsafe { libc::kill(pid, 0) == 0 }
+}
+
+#[tokio::test]
+async fn test_autostarted_tools_are_supervised_and_stopped() {
+    let mut instance = Instance::start("autostart-stopped", |config, dir| {
+        let mut crasher = script("crasher", dir, "echo crashing; exit 3");
+        crasher.restarts = 1;
+        let mut missing = script("missing", dir, "");
+        missing.command = "sherlock-no-such-tool".to_string();
+        config.autostart = vec![script("sleeper", dir, SLEEPER), crasher, missing];
+    })
+    .await;
+
+    // Pointed at the proxy, with its output in its own log
+    let pid = instance.tool_pid().await;
+    instance.logged("Started sleeper", 1).await;
+    let url = format!("base url http://127.0.0.1:{}\n", instance.port);
+    assert_eq!(instance.tool_log("sleeper"), url);
+
+    // Failures are restarted as many times as asked, then left alone
+    instance.logged("Restarting crasher (1 of 1)", 1).await;
+    instance.logged("crasher exited 3", 2).await;
+    assert_eq!(instance.tool_log("crasher"), "crashing\ncrashing\n");
+    instance.logged("Couldn't start missing", 1).await;
+
+    // Quitting stops the ones still running
+    assert!(alive(pid));
+    let status = instance.quit();
+    assert!(status.success(), "{:?}", status);
+    instance.logged("Stopped sleeper", 1).await;
+    assert!(!alive(pid));
+}
+
+#[tokio::test]
+async fn test_autostarted_tools_can_outlive_sherlock() {
+    let mut instance = Instance::start("autostart-outlive", |config, dir| {
+        config.autostart = vec![script("sleeper", dir, SLEEPER)];
+        config.autostart_kill_on_exit = false;
+    })
+    .await;
+
+    let pid = instance.tool_pid().await;
+    let status = instance.quit();
+    assert!(status.success(), "{:?}", status);
+    assert!(alive(pid));
+    unsafe { libc::kill(pid, libc::SIGKILL) };
+}
@@ -0,0 +1,297 @@
+//! Helpers shared by the integration tests: a scripted mock upstream and a
+//! headless `sherlock start` to point at it
+#![allow(dead_code)]
+
+use bytes::Bytes;
+use hyper::body::{Body, Frame, Incoming};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{HeaderMap, Request, Response};
+use hyper_util::rt::TokioIo;
+use std::convert::Infallible;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+use http_body_util::BodyExt;
+use sherlock::config::Config;
+
+/// Picks the mock upstream's reply; the proxy passes it on like any header
+pub const SCRIPT_HEADER: &str = "x-mock-script";
+
+pub const MESSAGE: &str = r#"{"id":"msg_1","type":"message","model":"claude-sonnet-4-5-20250929",
+"content":[{"type":"text","text":"Hello there"}],"usage":{"input_tokens":12,"output_tokens":3}}"#;
+
+pub const STREAM: &[&str] = &[
+    "event: message_start\ndata: {\"type\":\"message_start\",\"message\":{\"model\":\
+     \"claude-sonnet-4-5-20250929\",\"usage\":{\"input_tokens\":12,\"output_tokens\":1}}}\n\n",
+    "event: content_block_delta\ndata: {\"type\":\"content_block_delta\",\"index\":0,\
+     \"delta\":{\"type\":\"text_delta\",\"text\":\"Hello\"}}\n\n",
+    "event: content_block_delta\ndata: {\"type\":\"content_block_delta\",\"index\":0,\
+     \"delta\":{\"type\":\"text_delta\",\"text\":\" there\"}}\n\n",
+    "event: message_delta\ndata: {\"type\":\"message_delta\",\"usage\":{\"output_tokens\":7}}\n\n",
+    "event: message_stop\ndata: {\"type\":\"message_stop\"}\n\n",
+];
+
+/// A request as the mock upstream received it
+#[derive(Debug, Clone)]
+pub struct Received {
+    pub path: String,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+/// What the mock upstream sends back: chunks written `pause` apart
+struct Reply {
+    status: u16,
+    headers: Vec<(&'static str, &'static str)>,
+    chunks: Vec<Bytes>,
+    pause: Duration,
+}
+
+impl Reply {
+    fn json(status: u16, body: &'static str) -> Self {
+        Self {
+            status,
+            headers: vec![("content-type", "application/json")],
+            chunks: vec![Bytes::from_static(body.as_bytes())],
+            pause: Duration::ZERO,
+        }
+    }
+
+    fn stream(pause: Duration) -> Self {
+        Self {
+            status: 200,
+            headers: vec![("content-type", "text/event-stream")],
+            chunks: STREAM
+                .iter()
+                .map(|chunk| Bytes::from_static(chunk.as_bytes()))
+                .collect(),
+            pause,
+        }
+    }
+
+    /// Replies by the script named in the request
+    fn scripted(headers: &HeaderMap) -> Self {
+        let script = headers.get(SCRIPT_HEADER).and_then(|v| v.to_str().ok());
+        match script.unwrap_or("message") {
+            "stream" => Reply::stream(Duration::ZERO),
+            "slow-stream" => Reply::stream(Duration::from_millis(150)),
+            "unlabeled-stream" => Reply {
+                headers: vec![],
+                ..Reply::stream(Duration::ZERO)
+            },
+            "gzip" => Reply {
+                headers: vec![
+                    ("content-type", "application/json"),
+                    ("content-encoding", "gzip"),
+                ],
+                chunks: vec![Bytes::from(gzip(MESSAGE.as_bytes()))],
+                ..Reply::json(200, "")
+            },
+            "rate-limited" => Reply {
+                headers: vec![("content-type", "application/json"), ("retry-after", "20")],
+                ..Reply::json(
+                    429,
+                    r#"{"type":"error","error":{"type":"rate_limit_error","message":"slow down"}}"#,
+                )
+            },
+            _ => Reply {
+                headers: vec![
+                    ("content-type", "application/json"),
+                    ("request-id", "req_e2e"),
+                ],
+                ..Reply::json(200, MESSAGE)
+            },
+        }
+    }
+}
+
+/// Response body fed from a channel, so chunks can be spaced out in time
+struct ChannelBody(mpsc::Receiver<Bytes>);
+
+impl Body for ChannelBody {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+        self.0
+            .poll_recv(cx)
+            .map(|chunk| chunk.map(|chunk| Ok(Frame::data(chunk))))
+    }
+}
+
+/// Upstream answering every request by its script, keeping what it received
+pub async fn mock_upstream() -> (String, Arc<Mutex<Vec<Received>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let log = Arc::clone(&received);
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let log = Arc::clone(&log);
+            let service = service_fn(move |req: Request<Incoming>| {
+                let log = Arc::clone(&log);
+                async move {
+                    let (parts, body) = req.into_parts();
+                    let body = body.collect().await.unwrap().to_bytes();
+                    let reply = Reply::scripted(&parts.headers);
+                    log.lock().unwrap().push(Received {
+                        path: parts.uri.to_string(),
+                        headers: parts.headers,
+                        body,
+                    });
+                    let (tx, rx) = mpsc::channel(4);
+                    tokio::spawn(async move {
+                        for chunk in reply.chunks {
+                            tokio::time::sleep(reply.pause).await;
+                            if tx.send(chunk).await.is_err() {
+                                return;
+                            }
+                        }
+                    });
+                    let mut response = Response::builder().status(reply.status);
+                    for (name, value) in reply.headers {
+                        response = response.header(name, value);
+                    }
+                    Ok::<_, Infallible>(response.body(ChannelBody(rx)).unwrap())
+                }
+            });
+            tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+        }
+    });
+    (base_url, received)
+}
+
+/// A gzip member holding `data` in stored (uncompressed) deflate blocks
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+    let mut blocks = data.chunks(u16::MAX as usize).peekable();
+    while let Some(block) = blocks.next() {
+        out.push(u8::from(blocks.peek().is_none()));
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    let crc = data.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| {
+            (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg())
+        })
+    });
+    out.extend_from_slice(&(!crc).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+/// Every file under `dir`: name and content
+pub fn walk(dir: &Path, files: &mut Vec<(String, String)>) {
+    for entry in std::fs::read_dir(dir).into_iter().flatten().flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            walk(&path, files);
+        } else {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            files.push((name, std::fs::read_to_string(&path).unwrap()));
+        }
+    }
+}
+
+/// A headless instance with its own home, config and log
+pub struct Instance {
+    pub child: Child,
+    pub dir: PathBuf,
+    pub config_path: PathBuf,
+    pub config: Config,
+    pub port: u16,
+}
+
+impl Instance {
+    /// Start `sherlock start --headless` in a fresh directory named after
+    /// `name`, with `configure` applied to its config, once it's listening
+    pub async fn start(name: &str, configure: impl FnOnce(&mut Config, &Path)) -> Self {
+        let dir = std::env::temp_dir().join(format!("sherlock-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("home")).unwrap();
+
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut config = Config::default();
+        config.proxy.port = port;
+        config.proxy.self_test = false;
+        config.update_check = false;
+        config.archive.directory = dir.join("prompts");
+        configure(&mut config, &dir);
+        let config_path = dir.join("config.json");
+        std::fs::write(&config_path, serde_json::to_string_pretty(&config).unwrap()).unwrap();
+
+        let log = std::fs::File::create(dir.join("sherlock.log")).unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_sherlock"))
+            .arg("--config")
+            .arg(&config_path)
+            .args(["--no-repo-info", "start", "--headless"])
+            .env("HOME", dir.join("home"))
+            .env("RUST_LOG", "sherlock=info")
+            .stdin(Stdio::null())
+            .stdout(log.try_clone().unwrap())
+            .stderr(log)
+            .spawn()
+            .unwrap();
+
+        let instance = Self {
+            child,
+            dir,
+            config_path,
+            config,
+            port,
+        };
+        instance
+            .wait_for(|| std::net::TcpStream::connect(("127.0.0.1", port)).is_ok())
+            .await;
+        instance
+    }
+
+    pub fn base_url(&self) -> String {
+        format!("http://127.0.0.1:{}", self.port)
+    }
+
+    /// Wait for the log to gain its `count`th line containing `text`
+    pub async fn logged(&self, text: &str, count: usize) {
+        let path = self.dir.join("sherlock.log");
+        self.wait_for(|| {
+            let log = std::fs::read_to_string(&path).unwrap_or_default();
+            log.matches(text).count() >= count
+        })
+        .await;
+    }
+
+    pub async fn wait_for(&self, mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !done() {
+            if Instant::now() > deadline {
+                let log = std::fs::read_to_string(self.dir.join("sherlock.log"));
+                panic!("timed out; log:\n{}", log.unwrap_or_default());
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
@@ -0,0 +1,677 @@
+mod common;
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+use sherlock::archive::{archive_writer, ArchiveEntry};
+use sherlock::chaos::{Chaos, Injected, CHAOS_HEADER};
+use sherlock::config::{ChaosConfig, ChaosFault, ChaosRule, Config, ScrubberConfig};
+use sherlock::event::{ProxyEvent, RequestEvent};
+use sherlock::keys::KeyFingerprinter;
+use sherlock::metrics::{ArchiveMetrics, ProxyMetrics};
+use sherlock::parser::count_tokens;
+use sherlock::phases::Phase;
+use sherlock::policy::PolicyScanner;
+use sherlock::pricing::PriceTable;
+use sherlock::proxy::ProxyServer;
+use sherlock::redact::Redactor;
+use sherlock::scrub::Scrubber;
+
+use common::{gzip, mock_upstream, walk, MESSAGE, SCRIPT_HEADER, STREAM};
+
+/// A proxy on a free port, forwarding the `anthropic` provider to
+/// `upstream`, with its events collected and archived under a fresh
+/// directory. Chaos rules in the config apply, as under `--enable-chaos`.
+struct Harness {
+    base_url: String,
+    client: reqwest::Client,
+    events: mpsc::Receiver<ProxyEvent>,
+    archive_tx: Option<mpsc::Sender<ArchiveEntry>>,
+    archive: tokio::task::JoinHandle<anyhow::Result<()>>,
+    dir: PathBuf,
+}
+
+impl Harness {
+    async fn start(name: &str, upstream: &str) -> Self {
+        Self::start_with(name, upstream, |_| {}).await
+    }
+
+    /// A harness whose config `configure` changes first
+    async fn start_with(name: &str, upstream: &str, configure: impl FnOnce(&mut Config)) -> Self {
+        let dir =
+            std::env::temp_dir().join(format!("sherlock-e2e-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut config = Config::default();
+        config.proxy.port = 0;
+        for name in ["anthropic", "azure"] {
+            config.providers.get_mut(name).unwrap().base_url = upstream.to_string();
+        }
+        config.archive.directory = dir.join("prompts");
+        configure(&mut config);
+
+        let (event_tx, events) = mpsc::channel(64);
+        let redactor = Arc::new(Redactor::new(&config.redaction).unwrap());
+        let proxy = ProxyServer::new(
+            config.proxy.clone(),
+            config.providers.clone(),
+            event_tx,
+            Arc::new(ProxyMetrics::default()),
+            Arc::new(KeyFingerprinter::load_or_create(&dir).unwrap()),
+            Arc::new(PolicyScanner::new(&config.policy).unwrap()),
+            Arc::new(PriceTable::new(&config.pricing)),
+        )
+        .unwrap()
+        .with_redactor(Arc::clone(&redactor));
+        let proxy = match config.chaos.is_empty() {
+            true => proxy,
+            false => proxy.with_chaos(Chaos::new(&config.chaos).unwrap()),
+        };
+        let proxy = match &config.scrubber {
+            Some(scrubber) => proxy.with_scrubber(Scrubber::start(scrubber).unwrap()),
+            None => proxy,
+        };
+        let listener = proxy.bind().await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(proxy.serve(listener));
+
+        let (archive_tx, archive_rx) = mpsc::channel(16);
+        let archive = tokio::spawn(archive_writer(
+            archive_rx,
+            config.archive.clone(),
+            redactor,
+            Arc::new(ArchiveMetrics::default()),
+            None,
+        ));
+        Self {
+            base_url,
+            client: reqwest::Client::new(),
+            events,
+            archive_tx: Some(archive_tx),
+            archive,
+            dir,
+        }
+    }
+
+    fn post(&self, path: &str, body: &str) -> reqwest::RequestBuilder {
+        self.client
+            .post(format!("{}{}", self.base_url, path))
+            .header("content-type", "application/json")
+            .body(body.to_string())
+    }
+
+    /// The next request to finish, passed on to the archive like the
+    /// dashboard does; `Err` with the error if it failed instead
+    async fn finished(&mut self) -> Result<RequestEvent, String> {
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(5), self.events.recv())
+                .await
+                .expect("no event from the proxy")
+                .expect("proxy event channel closed");
+            match event {
+                ProxyEvent::Completed { event, .. } => {
+                    let event = *event.expect("request wasn't parsed");
+                    let archive_tx = self.archive_tx.as_ref().unwrap();
+                    archive_tx.send(event.clone().into()).await.unwrap();
+                    return Ok(event);
+                }
+                ProxyEvent::Failed { error, event, .. } => {
+                    if let Some(event) = event {
+                        let archive_tx = self.archive_tx.as_ref().unwrap();
+                        archive_tx.send((*event).into()).await.unwrap();
+                    }
+                    return Err(error);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Let the archive writer finish, then read the files it wrote: name
+    /// and content, by name
+    async fn archived(mut self) -> Vec<(String, String)> {
+        drop(self.archive_tx.take());
+        self.archive.await.unwrap().unwrap();
+        let mut files = Vec::new();
+        walk(&self.dir.join("prompts"), &mut files);
+        files.sort();
+        let _ = std::fs::remove_dir_all(&self.dir);
+        files
+    }
+}
+
+fn request_body(stream: bool) -> String {
+    serde_json::json!({
+        "model": "claude-sonnet-4-5",
+        "max_tokens": 256,
+        "stream": stream,
+        "system": "You are terse.",
+        "messages": [{"role": "user", "content": "Say hello to the integration tests"}],
+    })
+    .to_string()
+}
+
+#[tokio::test]
+async fn test_forwards_and_archives_a_request() {
+    let (upstream, received) = mock_upstream().await;
+    let mut harness = Harness::start("forward", &upstream).await;
+    let body = request_body(false);
+
+    let resp = harness
+        .post("/v1/messages", &body)
+        .header("x-api-key", "sk-ant-e2e-secret")
+        .header("anthropic-version", "2023-06-01")
+        .header("anthropic-beta", "tools-2024-04-04")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["request-id"], "req_e2e");
+    assert_eq!(resp.text().await.unwrap(), MESSAGE);
+
+    // The upstream gets the request as sent, bar the hop-by-hop headers
+    let upstream_saw = received.lock().unwrap()[0].clone();
+    assert_eq!(upstream_saw.path, "/v1/messages");
+    assert_eq!(upstream_saw.body, body.as_bytes());
+    for (name, value) in [
+        ("x-api-key", "sk-ant-e2e-secret"),
+        ("anthropic-version", "2023-06-01"),
+        ("anthropic-beta", "tools-2024-04-04"),
+        ("content-type", "application/json"),
+    ] {
+        assert_eq!(upstream_saw.headers[name], value, "{}", name);
+    }
+    assert_eq!(
+        upstream_saw.headers["host"],
+        upstream.trim_start_matches("http://")
+    );
+
+    // Tokens are the system prompt and the message, a line each
+    let event = harness.finished().await.unwrap();
+    assert_eq!(event.provider, "anthropic");
+    assert!(!event.streaming);
+    assert_eq!(event.model, "claude-sonnet-4-5");
+    let expected = count_tokens("You are terse.\nSay hello to the integration tests\n");
+    assert_eq!(event.tokens, expected);
+    assert_eq!(event.output.map(|output| output.tokens), Some(3));
+    assert_eq!(event.response.map(|response| response.status), Some(200));
+    assert_eq!(
+        event.served_model.as_deref(),
+        Some("claude-sonnet-4-5-20250929")
+    );
+    assert_eq!(event.api_version.as_deref(), Some("2023-06-01"));
+    assert!(event.key.is_some());
+    // Every phase of the request was timed
+    let timings = event.timings.unwrap();
+    for phase in Phase::ALL {
+        assert!(timings.get(phase).is_some(), "{:?}", phase);
+    }
+
+    let files = harness.archived().await;
+    let file = |suffix: &str| {
+        let found = files.iter().find(|(name, _)| name.ends_with(suffix));
+        found
+            .map(|(_, content)| content.as_str())
+            .unwrap_or_else(|| {
+                panic!(
+                    "no {} among {:?}",
+                    suffix,
+                    files.iter().map(|f| &f.0).collect::<Vec<_>>()
+                )
+            })
+    };
+    let archived: serde_json::Value = serde_json::from_str(file("_anthropic.json")).unwrap();
+    assert_eq!(
+        archived,
+        serde_json::from_str::<serde_json::Value>(&body).unwrap()
+    );
+    assert!(file("index.jsonl").contains("\"provider\":\"anthropic\""));
+    let markdown = file("_anthropic.md");
+    assert!(markdown.contains("Say hello to the integration tests"));
+    assert!(!markdown.contains("sk-ant-e2e-secret"));
+}
+
+#[tokio::test]
+async fn test_forwards_azure_deployments() {
+    let (upstream, received) = mock_upstream().await;
+    let mut harness = Harness::start("azure", &upstream).await;
+    // Azure leaves the model out of the body; the deployment names it
+    let body = serde_json::json!({
+        "messages": [{"role": "user", "content": "Say hello to Azure"}],
+        "max_tokens": 256,
+    })
+    .to_string();
+    let path = "/openai/deployments/gpt4o-prod/chat/completions?api-version=2024-10-21";
+
+    let resp = harness
+        .post(path, &body)
+        .header("api-key", "azure-e2e-secret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+
+    // Path, query and the `api-key` header all reach the upstream as sent
+    let upstream_saw = received.lock().unwrap()[0].clone();
+    assert_eq!(upstream_saw.path, path);
+    assert_eq!(upstream_saw.headers["api-key"], "azure-e2e-secret");
+
+    let event = harness.finished().await.unwrap();
+    assert_eq!(event.provider, "azure");
+    assert_eq!(event.model, "gpt4o-prod");
+    assert_eq!(event.api_version.as_deref(), Some("2024-10-21"));
+    assert!(event.key.is_some());
+    assert_eq!(event.tokens, count_tokens("Say hello to Azure\n"));
+    harness.archived().await;
+}
+
+#[tokio::test]
+async fn test_relays_streams_as_they_arrive() {
+    let (upstream, _) = mock_upstream().await;
+    let mut harness = Harness::start("stream", &upstream).await;
+
+    let resp = harness
+        .post("/v1/messages", &request_body(true))
+        .header(SCRIPT_HEADER, "stream")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.headers()["content-type"], "text/event-stream");
+    assert_eq!(resp.text().await.unwrap(), STREAM.concat());
+    let event = harness.finished().await.unwrap();
+    assert!(event.streaming);
+    assert_eq!(event.output.map(|output| output.tokens), Some(7));
+    assert!(event.throughput.is_some());
+
+    // Without a content type, the request's `stream` flag says how to read it
+    let resp = harness
+        .post("/v1/messages", &request_body(true))
+        .header(SCRIPT_HEADER, "unlabeled-stream")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.text().await.unwrap(), STREAM.concat());
+    let event = harness.finished().await.unwrap();
+    assert_eq!(event.output.map(|output| output.tokens), Some(7));
+
+    // Each event reaches the client before the upstream has sent the next
+    let started = Instant::now();
+    let mut resp = harness
+        .post("/v1/messages", &request_body(true))
+        .header(SCRIPT_HEADER, "slow-stream")
+        .send()
+        .await
+        .unwrap();
+    let mut arrivals = Vec::new();
+    let mut relayed = Vec::new();
+    while let Some(chunk) = resp.chunk().await.unwrap() {
+        arrivals.push(started.elapsed());
+        relayed.extend_from_slice(&chunk);
+    }
+    assert_eq!(relayed, STREAM.concat().as_bytes());
+    assert!(arrivals.len() >= STREAM.len(), "{:?}", arrivals);
+    assert!(arrivals[0] < Duration::from_millis(300), "{:?}", arrivals);
+    assert!(
+        arrivals[arrivals.len() - 1] >= Duration::from_millis(700),
+        "{:?}",
+        arrivals
+    );
+    let event = harness.finished().await.unwrap();
+    assert_eq!(event.output.map(|output| output.tokens), Some(7));
+}
+
+#[tokio::test]
+async fn test_relays_upstream_errors_and_encodings() {
+    let (upstream, _) = mock_upstream().await;
+    let mut harness = Harness::start("statuses", &upstream).await;
+
+    // Rate limits go back to the client untouched, and aren't priced
+    let resp = harness
+        .post("/v1/messages", &request_body(false))
+        .header(SCRIPT_HEADER, "rate-limited")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 429);
+    assert_eq!(resp.headers()["retry-after"], "20");
+    assert!(resp.text().await.unwrap().contains("rate_limit_error"));
+    let event = harness.finished().await.unwrap();
+    assert_eq!(event.response.map(|response| response.status), Some(429));
+    assert_eq!(event.cost_usd, None);
+
+    // Compressed bodies are relayed byte for byte, still labeled as such
+    let resp = harness
+        .post("/v1/messages", &request_body(false))
+        .header(SCRIPT_HEADER, "gzip")
+        .header("accept-encoding", "gzip")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-encoding"], "gzip");
+    assert_eq!(resp.bytes().await.unwrap(), gzip(MESSAGE.as_bytes()));
+    assert!(harness.finished().await.is_ok());
+}
+
+#[tokio::test]
+async fn test_unreachable_upstream_is_a_bad_gateway() {
+    let closed = {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}", listener.local_addr().unwrap())
+    };
+    let mut harness = Harness::start("unreachable", &closed).await;
+
+    let resp = harness
+        .post("/v1/messages", &request_body(false))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 502);
+    assert!(resp.text().await.unwrap().starts_with("Upstream error"));
+    let error = harness.finished().await.unwrap_err();
+    assert!(error.starts_with("upstream error"), "{}", error);
+    // The request is archived all the same, without a response
+    let files = harness.archived().await;
+    let markdown = files
+        .iter()
+        .find(|(name, _)| name.ends_with("_anthropic.md"));
+    assert!(markdown.is_some_and(|(_, content)| content.contains("Say hello")));
+}
+
+#[tokio::test]
+async fn test_unknown_paths_are_not_forwarded() {
+    let (upstream, received) = mock_upstream().await;
+    let mut harness = Harness::start("unknown", &upstream).await;
+
+    let resp = harness
+        .post("/v2/unheard-of", "plain text")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 400);
+    assert_eq!(resp.text().await.unwrap(), "Unknown provider");
+    // A body shaped like a known API is still shown, but not forwarded
+    // unless routing by shape is on
+    let resp = harness
+        .post("/v2/unheard-of", &request_body(false))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 400);
+    assert!(resp
+        .text()
+        .await
+        .unwrap()
+        .contains("body looks like anthropic"));
+    let event = harness.finished().await.unwrap();
+    assert_eq!(event.provider, "unrouted:anthropic");
+    assert_eq!(event.response, None);
+    assert!(received.lock().unwrap().is_empty());
+    harness.archived().await;
+}
+
+#[tokio::test]
+async fn test_unknown_paths_pass_through_to_default_provider() {
+    let (upstream, received) = mock_upstream().await;
+    let mut harness = Harness::start_with("default", &upstream, |config| {
+        config.proxy.default_provider = Some("anthropic".to_string());
+    })
+    .await;
+
+    // Passed through as sent, with nothing recorded
+    let resp = harness
+        .client
+        .get(format!("{}/v1/models?limit=5", harness.base_url))
+        .header("x-api-key", "sk-ant-test")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.text().await.unwrap(), MESSAGE);
+    {
+        let received = received.lock().unwrap();
+        assert_eq!(received[0].path, "/v1/models?limit=5");
+        assert_eq!(received[0].headers["x-api-key"], "sk-ant-test");
+    }
+    assert!(harness.events.try_recv().is_err());
+
+    // A body shaped like a known API is still recorded on its way there
+    let resp = harness
+        .post("/v2/unheard-of", &request_body(false))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let event = harness.finished().await.unwrap();
+    assert_eq!(event.provider, "unrouted:anthropic");
+    assert_eq!(event.response.map(|response| response.status), Some(200));
+    assert_eq!(received.lock().unwrap()[1].path, "/v2/unheard-of");
+    harness.archived().await;
+}
+
+#[tokio::test]
+async fn test_requests_without_prompts_make_no_events() {
+    let (upstream, received) = mock_upstream().await;
+    let mut harness = Harness::start("no-prompt", &upstream).await;
+
+    let url = format!("{}/v1/messages", harness.base_url);
+    let requests = [
+        harness.client.request(reqwest::Method::OPTIONS, &url),
+        harness.client.head(&url),
+        harness.post("/v1/messages", ""),
+        harness.post("/v1/messages", " \n"),
+        harness.post("/v1/messages", "{}"),
+    ];
+    for request in requests {
+        let resp = request.send().await.unwrap();
+        assert_eq!(resp.status(), 200);
+        // Forwarded and finished, with nothing parsed to show or archive
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(5), harness.events.recv());
+            match event.await.unwrap().unwrap() {
+                ProxyEvent::Completed { event, .. } => {
+                    assert!(event.is_none());
+                    break;
+                }
+                ProxyEvent::Failed { error, .. } => panic!("{}", error),
+                _ => {}
+            }
+        }
+    }
+    assert_eq!(received.lock().unwrap().len(), 5);
+
+    harness
+        .post("/v1/messages", &request_body(false))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(harness.finished().await.unwrap().model, "claude-sonnet-4-5");
+    let json = harness
+        .archived()
+        .await
+        .into_iter()
+        .filter(|(name, _)| name.ends_with(".json"))
+        .count();
+    assert_eq!(json, 1);
+}
+
+fn chaos(rules: Vec<(f64, ChaosFault)>) -> ChaosConfig {
+    ChaosConfig {
+        seed: Some(7),
+        rules: rules
+            .into_iter()
+            .map(|(percent, fault)| ChaosRule {
+                provider: Some("anthropic".to_string()),
+                percent,
+                fault,
+            })
+            .collect(),
+    }
+}
+
+#[tokio::test]
+async fn test_chaos_faults_follow_the_seed() {
+    const REQUESTS: usize = 40;
+    let (upstream, received) = mock_upstream().await;
+    let config = chaos(vec![(25.0, ChaosFault::Status { status: 429 })]);
+    // The same seed picks the same requests again
+    let expected = Chaos::new(&config).unwrap();
+    let expected: Vec<bool> = (0..REQUESTS)
+        .map(|_| expected.pick("anthropic", false) == Some(Injected::Status(429)))
+        .collect();
+    let mut harness = Harness::start_with("chaos", &upstream, |c| c.chaos = config).await;
+
+    let mut faulted = Vec::new();
+    for _ in 0..REQUESTS {
+        let resp = harness
+            .post("/v1/messages", &request_body(false))
+            .send()
+            .await
+            .unwrap();
+        let injected = resp.status() == 429;
+        if injected {
+            assert_eq!(resp.headers()[CHAOS_HEADER], "status 429");
+            assert_eq!(resp.headers()["retry-after"], "1");
+            assert!(resp.text().await.unwrap().contains("sherlock_chaos"));
+        } else {
+            assert_eq!(resp.status(), 200);
+            assert!(resp.headers().get(CHAOS_HEADER).is_none());
+        }
+        let event = harness.finished().await.unwrap();
+        assert_eq!(event.chaos.is_some(), injected);
+        faulted.push(injected);
+    }
+    assert_eq!(faulted, expected);
+    let count = faulted.iter().filter(|&&injected| injected).count();
+    assert!((5..=15).contains(&count), "{} of {}", count, REQUESTS);
+    // Faulted requests never reach the upstream
+    assert_eq!(received.lock().unwrap().len(), REQUESTS - count);
+
+    let files = harness.archived().await;
+    let flagged = files
+        .iter()
+        .filter(|(name, content)| {
+            name.ends_with(".md") && content.contains("**Chaos:** status 429")
+        })
+        .count();
+    assert_eq!(flagged, count);
+}
+
+#[tokio::test]
+async fn test_chaos_delays_and_cuts_streams() {
+    let (upstream, received) = mock_upstream().await;
+    let config = chaos(vec![
+        (100.0, ChaosFault::Truncate { after_events: 2 }),
+        (
+            100.0,
+            ChaosFault::Latency {
+                min_ms: 300,
+                max_ms: 300,
+            },
+        ),
+    ]);
+    let mut harness = Harness::start_with("chaos-stream", &upstream, |c| c.chaos = config).await;
+
+    // Streams end after their first two events, with no message_stop
+    let resp = harness
+        .post("/v1/messages", &request_body(true))
+        .header(SCRIPT_HEADER, "stream")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.headers()[CHAOS_HEADER], "stream cut after 2 events");
+    assert_eq!(resp.text().await.unwrap(), STREAM[..2].concat());
+    let event = harness.finished().await.unwrap();
+    assert_eq!(event.chaos.as_deref(), Some("stream cut after 2 events"));
+
+    // Requests that don't stream are held instead
+    let started = Instant::now();
+    let resp = harness
+        .post("/v1/messages", &request_body(false))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()[CHAOS_HEADER], "delayed 0.3s");
+    assert_eq!(resp.text().await.unwrap(), MESSAGE);
+    assert!(started.elapsed() >= Duration::from_millis(300));
+    let event = harness.finished().await.unwrap();
+    assert_eq!(event.chaos.as_deref(), Some("delayed 0.3s"));
+    assert_eq!(received.lock().unwrap().len(), 2);
+    harness.archived().await;
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_scrubber_rewrites_only_what_is_kept() {
+    use std::os::unix::fs::PermissionsExt;
+
+    // Uppercases every message's content, leaving the keys alone
+    let dir =
+        std::env::temp_dir().join(format!("sherlock-e2e-scrub-script-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let script = dir.join("scrub.sh");
+    std::fs::write(
+        &script,
+        "#!/bin/sh\nexec sed -u 's/\"content\":\"\\([^\"]*\\)\"/\"content\":\"\\U\\1\"/g'\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let (upstream, received) = mock_upstream().await;
+    let mut harness = Harness::start_with("scrubber", &upstream, |c| {
+        c.scrubber = Some(ScrubberConfig {
+            command: script.display().to_string(),
+            ..ScrubberConfig::default()
+        })
+    })
+    .await;
+    let body = request_body(false);
+    let resp = harness.post("/v1/messages", &body).send().await.unwrap();
+    assert_eq!(resp.status(), 200);
+
+    // Upstream gets the body untouched; the event and archive get the answer
+    assert_eq!(received.lock().unwrap()[0].body, body.as_bytes());
+    let event = harness.finished().await.unwrap();
+    let contents: Vec<_> = event.messages.iter().map(|m| m.content.as_str()).collect();
+    assert_eq!(
+        contents,
+        ["YOU ARE TERSE.", "SAY HELLO TO THE INTEGRATION TESTS"]
+    );
+    let files = harness.archived().await;
+    let (_, archived) = files
+        .iter()
+        .find(|(name, _)| name.ends_with("_anthropic.json"))
+        .unwrap();
+    assert!(archived.contains("SAY HELLO TO THE INTEGRATION TESTS"));
+    assert!(!archived.contains("Say hello"), "{}", archived);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_keys_are_redacted_from_events_and_archive() {
+    let (upstream, received) = mock_upstream().await;
+    let mut harness = Harness::start("redact", &upstream).await;
+    let key = "sk-ant-REDACTED";
+    let body = serde_json::json!({
+        "model": "claude-sonnet-4-5",
+        "max_tokens": 1024,
+        "metadata": {"user_id": format!("ci:{}", key)},
+        "messages": [{"role": "user", "content": format!("Why does {} fail?", key)}],
+    })
+    .to_string();
+    let resp = harness.post("/v1/messages", &body).send().await.unwrap();
+    assert_eq!(resp.status(), 200);
+
+    // The key still goes upstream, but nowhere else
+    assert_eq!(received.lock().unwrap()[0].body, body.as_bytes());
+    let event = harness.finished().await.unwrap();
+    assert_eq!(event.messages[0].content, "Why does [REDACTED:40] fail?");
+    assert_eq!(event.raw_body["metadata"]["user_id"], "ci:[REDACTED:40]");
+    for (name, content) in harness.archived().await {
+        assert!(!content.contains(key), "{}: {}", name, content);
+    }
+}
@@ -0,0 +1,104 @@
+//! Signals sent to a running `sherlock start --headless`
+#![cfg(unix)]
+
+mod common;
+
+use sherlock::config::{PolicyAction, ScanPattern, SinkConfig};
+
+use common::{mock_upstream, walk, Instance};
+
+impl Instance {
+    fn signal(&self, signal: libc::c_int) {
+        let pid = self.child.id() as libc::pid_t;
+        assert_eq!(unsafe { libc::kill(pid, signal) }, 0);
+    }
+
+    async fn post(&self, text: &str) -> reqwest::StatusCode {
+        let body = serde_json::json!({
+            "model": "claude-sonnet-4-5",
+            "max_tokens": 256,
+            "messages": [{"role": "user", "content": text}],
+        });
+        let response = reqwest::Client::new()
+            .post(format!("{}/v1/messages", self.base_url()))
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        let status = response.status();
+        response.bytes().await.unwrap();
+        status
+    }
+
+    /// Archived requests, leaving out the index
+    fn archived(&self) -> usize {
+        let mut files = Vec::new();
+        walk(&self.config.archive.directory, &mut files);
+        files
+            .iter()
+            .filter(|(name, _)| name.ends_with(".json"))
+            .count()
+    }
+}
+
+#[tokio::test]
+async fn test_signals_control_a_running_instance() {
+    let (upstream, _) = mock_upstream().await;
+    let mut instance = Instance::start("signals", |config, _| {
+        config.providers.get_mut("anthropic").unwrap().base_url = upstream;
+        config.archive.sinks = vec![SinkConfig::Json];
+    })
+    .await;
+
+    // Requests are done once logged, which can be just after the reply
+    assert_eq!(instance.post("first").await, 200);
+    instance.wait_for(|| instance.archived() == 1).await;
+
+    // SIGUSR2 pauses archiving, and again resumes it
+    instance.signal(libc::SIGUSR2);
+    instance.logged("Archiving paused", 1).await;
+    assert_eq!(instance.post("while paused").await, 200);
+    instance.logged(", 200 in ", 2).await;
+    instance.signal(libc::SIGUSR2);
+    instance.logged("Archiving resumed", 1).await;
+    assert_eq!(instance.post("resumed").await, 200);
+    instance.wait_for(|| instance.archived() == 2).await;
+    instance.logged(", 200 in ", 3).await;
+
+    // SIGUSR1 flushes the archive and logs the session so far
+    instance.signal(libc::SIGUSR1);
+    instance.logged("Flushed archive", 1).await;
+    instance.logged("Session stats: 3 requests", 1).await;
+
+    // SIGHUP reloads; the file keeps its mtime so only the signal notices
+    let modified = std::fs::metadata(&instance.config_path)
+        .unwrap()
+        .modified()
+        .unwrap();
+    let mut config = instance.config.clone();
+    config.policy.scan_patterns.push(ScanPattern {
+        name: "launch-code".to_string(),
+        regex: "swordfish".to_string(),
+        action: PolicyAction::Block,
+    });
+    std::fs::write(
+        &instance.config_path,
+        serde_json::to_string_pretty(&config).unwrap(),
+    )
+    .unwrap();
+    std::fs::File::options()
+        .write(true)
+        .open(&instance.config_path)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+    assert_eq!(instance.post("swordfish").await, 200);
+    instance.signal(libc::SIGHUP);
+    instance.logged("Reloaded", 1).await;
+    assert_eq!(instance.post("swordfish").await, 403);
+
+    // Still quits cleanly on SIGTERM
+    instance.signal(libc::SIGTERM);
+    let status = instance.child.wait().unwrap();
+    assert!(status.success(), "{:?}", status);
+}